@@ -48,6 +48,16 @@ pub fn set_opt_map_size(x: u32) -> u32 {
     if x <= 1 || x > OPT_MAX_MAPSIZE { 0 } else { (x - 1) << 1 }
 }
 
+// Versioned forkserver protocol (AFL++ 4.21 and later)
+pub const NEW_VERSION_MAGIC: u32 = 0x41464c00;
+pub const NEW_VERSION_MAX: u32 = 1;
+pub const NEW_OPT_MAPSIZE: u32 = 0x00000001;
+pub const NEW_OPT_SHDMEM_FUZZ: u32 = 0x00000002;
+
+/// Environment variable used to select the versioned forkserver protocol. Older versions of AFL++
+/// do not understand the versioned handshake, so the legacy protocol is used by default.
+pub const NEW_FORKSERVER_ENV_VAR: &str = "ICICLE_AFL_NEW_FORKSERVER";
+
 pub struct Comms {
     rx: std::fs::File,
     tx: std::fs::File,
@@ -92,6 +102,10 @@ impl Comms {
 
     /// Send configuration information to AFL and check that AFL responds correctly.
     pub fn setup(&mut self, config: &FuzzConfig) -> anyhow::Result<()> {
+        if std::env::var_os(NEW_FORKSERVER_ENV_VAR).is_some() {
+            return self.setup_versioned(config.shared_mem_inputs);
+        }
+
         let mut status = OPT_ENABLED;
         status |= set_opt_map_size(shared_mem::MAP_SIZE as u32) | OPT_MAPSIZE;
 
@@ -117,6 +131,31 @@ impl Comms {
         Ok(())
     }
 
+    /// Performs the versioned handshake: the forkserver sends its version, AFL responds with the
+    /// inverted version, then the forkserver sends its options (followed by any option specific
+    /// data) and finally repeats the version to mark the end of the handshake.
+    fn setup_versioned(&mut self, shared_mem_inputs: bool) -> anyhow::Result<()> {
+        let version = NEW_VERSION_MAGIC + NEW_VERSION_MAX;
+        self.write(version).context("failed to send forkserver version to AFL")?;
+
+        let response = self.read().context("failed to get version response")?;
+        let expected = version ^ 0xffffffff;
+        anyhow::ensure!(
+            response == expected,
+            "Unexpected response from AFL++ during forkserver setup: {response:#x} (expected: {expected:#x})",
+        );
+
+        let mut options = NEW_OPT_MAPSIZE;
+        if shared_mem_inputs {
+            options |= NEW_OPT_SHDMEM_FUZZ;
+        }
+        self.write(options).context("failed to send options to AFL")?;
+        self.write(shared_mem::MAP_SIZE as u32).context("failed to send map size to AFL")?;
+        self.write(version).context("failed to complete forkserver handshake")?;
+
+        Ok(())
+    }
+
     /// Notifies AFL that we are starting the next execution of the next fuzz case.
     pub fn start_fuzz_case(
         &mut self,
@@ -168,3 +207,55 @@ impl<'a> std::fmt::Display for BytesDisplay<'a> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_comms(name: &str, responses: &[u32]) -> (Comms, std::path::PathBuf) {
+        let dir = std::env::temp_dir();
+        let rx_path = dir.join(format!("icicle-afl-{name}-rx-{}", std::process::id()));
+        let tx_path = dir.join(format!("icicle-afl-{name}-tx-{}", std::process::id()));
+        let bytes: Vec<u8> = responses.iter().flat_map(|x| x.to_le_bytes()).collect();
+        std::fs::write(&rx_path, bytes).unwrap();
+
+        let rx = std::fs::File::open(&rx_path).unwrap();
+        let tx = std::fs::File::create(&tx_path).unwrap();
+        std::fs::remove_file(&rx_path).unwrap();
+        (Comms { rx, tx, killable_process: u32::MAX }, tx_path)
+    }
+
+    fn sent(tx_path: &std::path::Path) -> Vec<u32> {
+        let bytes = std::fs::read(tx_path).unwrap();
+        std::fs::remove_file(tx_path).unwrap();
+        bytes.chunks_exact(4).map(|x| u32::from_le_bytes(x.try_into().unwrap())).collect()
+    }
+
+    #[test]
+    fn versioned_handshake() {
+        let version = NEW_VERSION_MAGIC + NEW_VERSION_MAX;
+        let (mut comms, tx_path) = test_comms("handshake", &[version ^ 0xffffffff]);
+        comms.setup_versioned(true).unwrap();
+        drop(comms);
+
+        let options = NEW_OPT_MAPSIZE | NEW_OPT_SHDMEM_FUZZ;
+        assert_eq!(sent(&tx_path), [version, options, shared_mem::MAP_SIZE as u32, version]);
+    }
+
+    #[test]
+    fn versioned_handshake_rejects_bad_response() {
+        let version = NEW_VERSION_MAGIC + NEW_VERSION_MAX;
+        let (mut comms, tx_path) = test_comms("bad-response", &[version]);
+        assert!(comms.setup_versioned(false).is_err());
+        drop(comms);
+
+        // The handshake stops after the version is sent.
+        assert_eq!(sent(&tx_path), [version]);
+
+        // AFL closing the pipe before responding is also an error.
+        let (mut comms, tx_path) = test_comms("closed", &[]);
+        assert!(comms.setup_versioned(false).is_err());
+        drop(comms);
+        sent(&tx_path);
+    }
+}
@@ -1,6 +1,8 @@
 use icicle_fuzzing::{
+    block_ids::BlockIds,
     cmplog::CmpMap,
     coverage::{AFLHitCountsBuilder, BlockCoverageBuilder},
+    feedback::FeedbackChannel,
     CoverageMode,
 };
 use icicle_vm::cpu::lifter::Block;
@@ -12,15 +14,15 @@ pub struct Instrumentation {
     cov: (*mut u8, u32),
     cmplog_map: Option<&'static std::cell::UnsafeCell<CmpMap>>,
     path_tracer: Option<icicle_fuzzing::trace::PathTracerRef>,
+    block_ids: Option<BlockIds>,
+    /// The maximum value of every entry of the coverage map across all executions (only tracked
+    /// when checkpoints are enabled).
+    accumulated_coverage: Option<Vec<u8>>,
 }
 
 impl Instrumentation {
     pub fn clear(&mut self, vm: &mut icicle_vm::Vm) {
-        // Reset coverage to default state, note we set a single value in the coverage map, because
-        // AFL++ checks the map to determine whether the backend is working.
-        let cov_slice = unsafe { std::slice::from_raw_parts_mut(self.cov.0, self.cov.1 as usize) };
-        cov_slice.fill(0);
-        cov_slice[0] = 1;
+        self.discard_coverage();
 
         if self.cmplog_map.is_some() {
             // AFL++ takes care of clearing the cmplog_map after each input
@@ -31,12 +33,71 @@ impl Instrumentation {
         }
     }
 
+    /// Resets the coverage map to its default state. This is also used to hide the coverage of
+    /// executions that reached unreachable code, so AFL++ never considers them interesting.
+    pub fn discard_coverage(&mut self) {
+        // Note we set a single value in the coverage map, because AFL++ checks the map to determine
+        // whether the backend is working.
+        let cov_slice = unsafe { std::slice::from_raw_parts_mut(self.cov.0, self.cov.1 as usize) };
+        cov_slice.fill(0);
+        cov_slice[0] = 1;
+    }
+
+    /// Merges the coverage map of the current execution into the accumulated coverage map.
+    pub fn accumulate_coverage(&mut self) {
+        let Some(acc) = self.accumulated_coverage.as_mut()
+        else {
+            return;
+        };
+        let cov_slice = unsafe { std::slice::from_raw_parts(self.cov.0, self.cov.1 as usize) };
+        for (acc, value) in acc.iter_mut().zip(cov_slice) {
+            *acc = (*acc).max(*value);
+        }
+    }
+
+    /// The coverage accumulated across all executions, see [Self::accumulate_coverage].
+    pub fn accumulated_coverage(&self) -> &[u8] {
+        self.accumulated_coverage.as_deref().unwrap_or(&[])
+    }
+
+    /// Restores coverage accumulated by a previous session (e.g. from a checkpoint).
+    pub fn restore_accumulated_coverage(&mut self, coverage: &[u8]) {
+        if let Some(acc) = self.accumulated_coverage.as_mut() {
+            for (acc, value) in acc.iter_mut().zip(coverage) {
+                *acc = (*acc).max(*value);
+            }
+        }
+    }
+
     pub fn save_cmplog_map(&mut self, path: &std::path::Path) -> std::io::Result<()> {
         if let Some(map) = self.cmplog_map {
             unsafe { map.get().as_ref().unwrap() }.save(path)?;
         }
         Ok(())
     }
+
+    /// Registers modules loaded since the VM was instrumented, so that blocks in them are assigned
+    /// stable IDs.
+    pub fn update_modules(&mut self, vm: &icicle_vm::Vm) {
+        if let Some(ids) = self.block_ids.as_ref() {
+            ids.add_linux_modules(vm);
+        }
+    }
+
+    /// The block ID mapping used by the coverage instrumentation (if block IDs are enabled).
+    pub fn block_ids(&self) -> Option<&BlockIds> {
+        self.block_ids.as_ref()
+    }
+
+    /// Saves the block ID mapping to `path` if any new blocks were discovered. Unless `force` is
+    /// set, saves are rate limited (see [BlockIds::maybe_save]).
+    pub fn save_block_ids(&mut self, path: &std::path::Path, force: bool) -> anyhow::Result<()> {
+        match self.block_ids.as_ref() {
+            Some(ids) if force && ids.is_dirty() => ids.save(path),
+            Some(ids) if !force => ids.maybe_save(path),
+            _ => Ok(()),
+        }
+    }
 }
 
 pub fn instrument_vm(
@@ -53,24 +114,43 @@ pub fn instrument_vm(
 
     let (start_addr, end_addr) = config.get_instrumentation_range(vm).unwrap_or((0, u64::MAX));
 
+    let block_ids = match config.block_ids_path.as_ref() {
+        Some(path) => {
+            let ids = BlockIds::load(path)?;
+            ids.add_linux_modules(vm);
+            Some(ids)
+        }
+        None => None,
+    };
+
     let filter = move |block: &Block| start_addr <= block.start && block.start <= end_addr;
     let cov_map = match config.coverage_mode {
-        CoverageMode::Blocks => BlockCoverageBuilder::new()
-            .filter(filter)
-            .enable_context(config.context_bits != 0)
-            .finish(vm, afl_area_ptr, afl_map_size as u32),
+        CoverageMode::Blocks => {
+            let mut builder = BlockCoverageBuilder::new()
+                .filter(filter)
+                .enable_context(config.context_bits != 0);
+            if let Some(ids) = block_ids.clone() {
+                builder = builder.block_ids(ids);
+            }
+            builder.finish(vm, afl_area_ptr, afl_map_size as u32)
+        }
         CoverageMode::BlockCounts => anyhow::bail!("Block counts not implemented"),
         CoverageMode::Edges => anyhow::bail!("Edge-only coverage not implemented"),
-        CoverageMode::EdgeCounts => AFLHitCountsBuilder::new()
-            .filter(filter)
-            .with_context(config.context_bits)
-            .finish(vm, afl_area_ptr, afl_map_size as u32),
+        CoverageMode::EdgeCounts => {
+            let mut builder =
+                AFLHitCountsBuilder::new().filter(filter).with_context(config.context_bits);
+            if let Some(ids) = block_ids.clone() {
+                builder = builder.block_ids(ids);
+            }
+            builder.finish(vm, afl_area_ptr, afl_map_size as u32)
+        }
     };
 
     if let Some(map) = cmplog_map {
         icicle_fuzzing::cmplog::CmpLogBuilder::new()
             .filter(move |block| start_addr <= block.start && block.start <= end_addr)
             .instrument_calls(!config.no_cmplog_return)
+            .instrument_all_cmps(config.cmplog_all_cmps)
             .finish(vm, map);
     }
 
@@ -81,6 +161,18 @@ pub fn instrument_vm(
             .finish(vm, cov_map);
     }
 
+    for (id, spec) in config.feedback.iter().enumerate() {
+        let channel = FeedbackChannel::new(vm, cov_map, id as u32, spec.bucketing);
+        spec.register(vm, channel)?;
+    }
+
+    if !config.unreachable.is_empty() {
+        let unreachable = icicle_vm::unreachable::attach(vm);
+        for &(start, len) in &config.unreachable {
+            unreachable.add_range(start, len);
+        }
+    }
+
     let mut tracer = None;
     if config.track_path {
         tracer = Some(icicle_fuzzing::trace::add_path_tracer(vm)?);
@@ -90,6 +182,11 @@ pub fn instrument_vm(
         cov: (afl_area_ptr, afl_map_size as u32),
         cmplog_map,
         path_tracer: tracer,
+        block_ids,
+        accumulated_coverage: config
+            .checkpoint_dir
+            .is_some()
+            .then(|| vec![0; afl_map_size as usize]),
     })
 }
 
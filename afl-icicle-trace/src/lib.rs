@@ -6,6 +6,7 @@ use std::{
 
 use anyhow::Context;
 use icicle_fuzzing::{
+    checkpoint::{CampaignStats, Checkpoint},
     utils::{get_afl_exit_code, BlockCoverageTracker},
     FuzzConfig, FuzzTarget,
 };
@@ -101,6 +102,13 @@ where
             "exit_code": metadata.exit_code,
             "key": key,
             "call_stack": metadata.call_stack_string,
+            "stack_hash": format!("{:016x}", metadata.triage.stack_hash),
+            "bucket": metadata.triage.bucket.name(),
+            "severity": format!("{:?}", metadata.triage.severity()),
+            "process": metadata.process,
+            "sanitizer_report": metadata.sanitizer_report,
+            "hang_report": metadata.hang_report,
+            "environment": metadata.environment,
         }));
     }
     write!(writer, "{}", serde_json::json!(output))
@@ -126,6 +134,7 @@ pub fn run_without_parent<T: FuzzTarget>(
         _ => usize::MAX,
     };
 
+    let core_dump_dir = config.core_dump_dir.clone();
     let input = read_input(config)?;
     let truncated_input = &input[..max_input_size.min(input.len())];
     if input.len() != truncated_input.len() {
@@ -151,6 +160,10 @@ pub fn run_without_parent<T: FuzzTarget>(
     let exit = target.run(&mut vm)?;
     eprintln!("\n[icicle] exited with: {:?}", exit);
 
+    if let Some(dir) = core_dump_dir.as_ref() {
+        write_core_dump(&mut vm, exit, dir);
+    }
+
     if std::env::var_os("ICICLE_SAVE_DISASM").is_some() {
         std::fs::write("disasm.asm", icicle_vm::debug::dump_disasm(&vm)?.as_bytes())?;
     }
@@ -163,6 +176,20 @@ pub fn run_without_parent<T: FuzzTarget>(
             writeln!(output, "{addr:#x},{icount}").unwrap();
         }
     }
+    if std::env::var_os("ICICLE_SAVE_SOURCE_TRACE").is_some() {
+        let output = std::io::BufWriter::new(std::fs::File::create("trace.src.txt")?);
+        let blocks = tracer.get_last_blocks(&mut vm);
+        icicle_vm::source_trace::annotate_trace(&mut vm, blocks, output)?;
+    }
+    if let Ok(format) = std::env::var("ICICLE_SAVE_COVERAGE") {
+        let format: icicle_vm::coverage_export::CoverageFormat = format.parse()?;
+        let mut exporter = icicle_vm::coverage_export::CoverageExporter::from_env(&vm);
+        let blocks = tracer.get_last_blocks(&mut vm);
+        exporter.set_calls(icicle_vm::coverage_export::call_trace(&vm, blocks));
+        let path = format!("coverage.{}", format.extension());
+        exporter.save(&vm, format, path.as_ref())?;
+        exporter.write_call_trace(std::io::BufWriter::new(std::fs::File::create("calls.txt")?))?;
+    }
     if let Some((path, cmp_map)) = cmplog_trace {
         unsafe { (*cmp_map.get()).save(path.as_ref())? };
     }
@@ -210,6 +237,25 @@ pub fn run_without_parent<T: FuzzTarget>(
     Ok(())
 }
 
+/// Writes a core file to `dir` if the VM exited because of a memory error.
+fn write_core_dump(vm: &mut icicle_vm::Vm, exit: VmExit, dir: &Path) {
+    let VmExit::UnhandledException((code, _)) = exit
+    else {
+        return;
+    };
+    if !code.is_memory_error()
+        || matches!(code, ExceptionCode::ReadWatch | ExceptionCode::WriteWatch)
+    {
+        return;
+    }
+
+    let path = dir.join(format!("core_pc_{:0x}", vm.cpu.read_pc()));
+    match icicle_vm::core_dump::write_core_dump(vm, code, &path) {
+        Ok(()) => tracing::info!("core file written to: {}", path.display()),
+        Err(e) => tracing::error!("failed to write core file to {}: {e:?}", path.display()),
+    }
+}
+
 fn read_input(config: FuzzConfig) -> anyhow::Result<Vec<u8>> {
     let input = match config.icicle_args.get(1) {
         Some(path) => std::fs::read(path).with_context(|| format!("Failed to read: {path}"))?,
@@ -258,8 +304,32 @@ fn run_afl<T: FuzzTarget>(
 
     let mut coverage_tracker = BlockCoverageTracker::new();
 
+    let checkpoint = match config.checkpoint_dir.as_ref() {
+        Some(dir) => Checkpoint::load(dir)?,
+        None => None,
+    };
+
+    if let (Some(checkpoint), Some(ids)) = (checkpoint.as_ref(), instrumentation.block_ids()) {
+        checkpoint.restore_block_ids(ids)?;
+    }
+
     target.initialize_vm(&config, &mut vm)?;
+    let mut stats = CampaignStats::default();
+    let mut crashes = icicle_fuzzing::log::CrashLogger::default();
+    if let Some(checkpoint) = checkpoint {
+        tracing::info!("resuming from checkpoint after {} execs", checkpoint.stats.total_execs);
+        checkpoint.restore(&mut vm)?;
+        for addr in &checkpoint.seen_blocks {
+            coverage_tracker.add(*addr, 0);
+        }
+        instrumentation.restore_accumulated_coverage(&checkpoint.coverage);
+        crashes.add_known(checkpoint.crashes);
+        stats = checkpoint.stats;
+        stats.crashes = crashes.len() as u64;
+    }
+    instrumentation.update_modules(&vm);
     let snapshot = vm.snapshot();
+    let mut last_checkpoint = std::time::Instant::now();
 
     if config.enable_dry_run {
         // Perform a dry-run so the first execution we report to AFL isn't really slow. Note:
@@ -281,8 +351,12 @@ fn run_afl<T: FuzzTarget>(
     afl.setup(&config)?;
     coverage_tracker.add_new(&vm.code, 0);
 
+    let mut symbol_cache = match config.symbol_cache_path.as_ref() {
+        Some(path) => Some(icicle_fuzzing::symbol_cache::SymbolCache::open(path)?),
+        None => None,
+    };
+
     // The main fuzzing loop
-    let mut crashes = icicle_fuzzing::log::CrashLogger::default();
     while afl.is_alive() {
         instrumentation.clear(&mut vm);
 
@@ -318,15 +392,36 @@ fn run_afl<T: FuzzTarget>(
             }
         }
 
+        if let VmExit::Unreachable(addr) = exit {
+            tracing::debug!("input reached unreachable code at {addr:#x}");
+            instrumentation.discard_coverage();
+        }
+        instrumentation.accumulate_coverage();
+
         let afl_exit_kind = get_afl_exit_code(&vm, exit);
         if afl_exit_kind != 0 && crashes.check_crash(&mut vm, exit) {
-            let backtrace = icicle_vm::debug::backtrace(&mut vm);
+            let backtrace = match symbol_cache.as_mut() {
+                Some(cache) => {
+                    cache.add_linux_modules(&mut vm);
+                    let backtrace = cache.backtrace(&mut vm);
+                    if let Err(e) = cache.flush() {
+                        tracing::error!("error saving symbol cache: {e:?}");
+                    }
+                    backtrace
+                }
+                None => icicle_vm::debug::backtrace(&mut vm),
+            };
             tracing::info!("New crash ({:0x?}): \n{}", exit, backtrace);
+            stats.crashes = crashes.len() as u64;
 
             if config.save_crashes {
                 let pc = vm.cpu.read_pc();
                 std::fs::write(&format!("crash_pc_{:0x}.bin", pc), input).unwrap()
             }
+
+            if let Some(dir) = config.core_dump_dir.as_ref() {
+                write_core_dump(&mut vm, exit, dir);
+            }
         }
 
         if let Some(path) = config.cmplog_path.as_ref() {
@@ -341,12 +436,39 @@ fn run_afl<T: FuzzTarget>(
         if let Some(logger) = stats_logger.as_mut() {
             logger.log_exec(input.len())
         }
+        stats.total_execs += 1;
+        stats.total_input_bytes += input.len() as u64;
 
         coverage_tracker.add_new(&vm.code, 0);
         if let Err(e) = coverage_tracker.maybe_save("cur_coverage.txt".as_ref()) {
             tracing::error!("error saving coverage file: {e:?}");
         }
 
+        if let Some(path) = config.block_ids_path.as_ref() {
+            if let Err(e) = instrumentation.save_block_ids(path, false) {
+                tracing::error!("error saving block IDs: {e:?}");
+            }
+        }
+
+        if let Some(dir) = config.checkpoint_dir.as_ref() {
+            if last_checkpoint.elapsed() >= config.checkpoint_interval {
+                stats.run_time += last_checkpoint.elapsed();
+                last_checkpoint = std::time::Instant::now();
+
+                // The checkpoint must contain the state at the snapshot point, not the state at the
+                // end of the last execution.
+                vm.restore(&snapshot);
+                let mut checkpoint = Checkpoint::capture(&mut vm, instrumentation.block_ids())?;
+                checkpoint.seen_blocks = coverage_tracker.seen.keys().copied().collect();
+                checkpoint.coverage = instrumentation.accumulated_coverage().to_vec();
+                checkpoint.crashes = crashes.keys().cloned().collect();
+                checkpoint.stats = stats.clone();
+                if let Err(e) = checkpoint.save(dir) {
+                    tracing::error!("error saving checkpoint: {e:?}");
+                }
+            }
+        }
+
         // Check if we should recompile here, note we do this before the next iteration so that AFL
         // doesn't count the recompilation time as part of the fuzzing time.
         if vm.should_recompile() {
@@ -354,6 +476,10 @@ fn run_afl<T: FuzzTarget>(
         }
     }
 
+    if let Some(path) = config.block_ids_path.as_ref() {
+        instrumentation.save_block_ids(path, true)?;
+    }
+
     Ok(())
 }
 
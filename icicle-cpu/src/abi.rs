@@ -0,0 +1,375 @@
+//! Helpers for configuring the initial state of the CPU according to the ABI of the target
+//! architecture.
+//!
+//! Environments typically need to allocate a stack, place arguments in the locations expected by
+//! the ABI, and arrange for the guest to return to a known address when the entry point (or a
+//! function being called) returns. [Cpu::init_abi_state] handles these details for all supported
+//! architectures, and [Cpu::read_return_value] / [Cpu::write_return_value] (and their 64-bit
+//! variants) access function return values without per-architecture conditionals.
+
+use target_lexicon::Architecture;
+
+use crate::{
+    Cpu,
+    mem::{AllocLayout, Mapping, MemError, MemResult, perm},
+};
+
+const PAGE_SIZE: u64 = 0x1000;
+
+/// The arguments to pass to the guest.
+#[derive(Clone, Copy, Debug)]
+pub enum AbiArgs<'a> {
+    /// No arguments are passed.
+    None,
+
+    /// Integer arguments passed according to the default calling convention, as for a function
+    /// call. A return address pointing to [AbiState::return_addr] is set up.
+    Call(&'a [u64]),
+
+    /// Arguments passed on the stack in the layout used for the entry point of a process:
+    /// `argc`, followed by the NULL terminated `argv` and `envp` arrays, and an empty auxiliary
+    /// vector. The strings are stored at the top of the stack.
+    Process { argv: &'a [&'a [u8]], envp: &'a [&'a [u8]] },
+}
+
+/// Describes the state created by [Cpu::init_abi_state].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AbiState {
+    /// The address of the guard page below the stack.
+    pub guard_page: u64,
+
+    /// The lowest address of the stack.
+    pub stack_limit: u64,
+
+    /// The address immediately after the end of the stack.
+    pub stack_base: u64,
+
+    /// The initial value of the stack pointer.
+    pub sp: u64,
+
+    /// A (non-executable) address that the guest returns to when the called function returns.
+    /// Executing this address causes an `ExecViolation` exception that environments can check
+    /// for.
+    pub return_addr: u64,
+}
+
+/// Gets the required alignment of the stack pointer at function entry.
+fn stack_alignment(arch: Architecture, ptr_size: u64) -> u64 {
+    match arch {
+        Architecture::X86_64
+        | Architecture::X86_32(_)
+        | Architecture::Aarch64(_)
+        | Architecture::Riscv32(_)
+        | Architecture::Riscv64(_)
+        | Architecture::Powerpc
+        | Architecture::Powerpc64
+        | Architecture::Powerpc64le
+        | Architecture::XTensa => 16,
+        Architecture::Arm(_) | Architecture::Mips32(_) => 8,
+        _ => ptr_size.max(2),
+    }
+}
+
+/// Gets the name of the register used for the return address, or `None` if the return address is
+/// pushed onto the stack.
+fn link_register(arch: Architecture) -> Option<&'static str> {
+    match arch {
+        Architecture::Arm(_) => Some("lr"),
+        Architecture::Aarch64(_) => Some("x30"),
+        Architecture::Mips32(_) | Architecture::Riscv32(_) | Architecture::Riscv64(_) => Some("ra"),
+        Architecture::Powerpc | Architecture::Powerpc64 | Architecture::Powerpc64le => Some("LR"),
+        Architecture::XTensa => Some("a0"),
+        _ => None,
+    }
+}
+
+/// Gets the names of the registers used for function return values. The second register is used
+/// for the upper half of double-width values (e.g. 64-bit values on 32-bit targets).
+fn return_registers(arch: Architecture) -> Option<(&'static str, Option<&'static str>)> {
+    Some(match arch {
+        Architecture::X86_64 => ("RAX", None),
+        Architecture::X86_32(_) => ("EAX", Some("EDX")),
+        Architecture::Aarch64(_) => ("x0", None),
+        Architecture::Arm(_) => ("r0", Some("r1")),
+        Architecture::Riscv64(_) => ("a0", None),
+        Architecture::Riscv32(_) => ("a0", Some("a1")),
+        Architecture::Mips32(_) => ("v0", Some("v1")),
+        Architecture::Powerpc => ("r3", Some("r4")),
+        Architecture::Powerpc64 | Architecture::Powerpc64le => ("r3", None),
+        Architecture::Msp430 => ("R12", Some("R13")),
+        Architecture::XTensa => ("a2", Some("a3")),
+        _ => return None,
+    })
+}
+
+/// Gets the offset (relative to the stack pointer after any return address has been pushed) of
+/// the first argument passed on the stack.
+fn stack_args_offset(arch: Architecture, ptr_size: u64) -> u64 {
+    match arch {
+        // The caller reserves space for the 4 register arguments.
+        Architecture::Mips32(_) => 16,
+        Architecture::X86_64 | Architecture::X86_32(_) | Architecture::Msp430 => ptr_size,
+        Architecture::M68k => ptr_size,
+        // The back chain and LR save word are stored below the parameter save area.
+        Architecture::Powerpc => 2 * ptr_size,
+        _ => 0,
+    }
+}
+
+impl Cpu {
+    /// Reads the integer return value of a function according to the default calling convention.
+    /// Returns `None` if the return register is unknown for the current architecture.
+    pub fn read_return_value(&mut self) -> Option<u64> {
+        let (name, _) = return_registers(self.arch.triple.architecture)?;
+        let var = self.arch.sleigh.get_varnode(name)?;
+        Some(self.read_reg(var))
+    }
+
+    /// Writes the integer return value of a function according to the default calling convention.
+    /// Returns `None` if the return register is unknown for the current architecture.
+    pub fn write_return_value(&mut self, value: u64) -> Option<()> {
+        let (name, _) = return_registers(self.arch.triple.architecture)?;
+        let var = self.arch.sleigh.get_varnode(name)?;
+        self.write_reg(var, value);
+        Some(())
+    }
+
+    /// Reads a 64-bit return value, combining the register pair used on 32-bit targets.
+    ///
+    /// Note: on big-endian targets that return values in a register pair (e.g. ARM EABI, MIPS o32,
+    /// PowerPC) the first register contains the most significant half.
+    pub fn read_return_value_u64(&mut self) -> Option<u64> {
+        let (lo, hi) = self.return_value_pair()?;
+        let Some(hi) = hi
+        else {
+            return Some(self.read_reg(lo));
+        };
+        Some((self.read_reg(lo) & 0xffff_ffff) | (self.read_reg(hi) << 32))
+    }
+
+    /// Writes a 64-bit return value, splitting it across the register pair used on 32-bit
+    /// targets. See [Cpu::read_return_value_u64].
+    pub fn write_return_value_u64(&mut self, value: u64) -> Option<()> {
+        let (lo, hi) = self.return_value_pair()?;
+        match hi {
+            Some(hi) => {
+                self.write_reg(lo, value & 0xffff_ffff);
+                self.write_reg(hi, value >> 32);
+            }
+            None => self.write_reg(lo, value),
+        }
+        Some(())
+    }
+
+    /// Gets the registers containing the low and (for 32-bit targets) high halves of a 64-bit
+    /// return value.
+    fn return_value_pair(&self) -> Option<(pcode::VarNode, Option<pcode::VarNode>)> {
+        let (first, second) = return_registers(self.arch.triple.architecture)?;
+        let first = self.arch.sleigh.get_varnode(first)?;
+        let Some(second) = second.filter(|_| self.arch.reg_pc.size < 8)
+        else {
+            return Some((first, None));
+        };
+        let second = self.arch.sleigh.get_varnode(second)?;
+
+        // x86 always uses EDX:EAX, other targets order the pair according to the endianness.
+        let first_is_high = self.arch.sleigh.big_endian
+            && !matches!(self.arch.triple.architecture, Architecture::X86_32(_));
+        Some(match first_is_high {
+            true => (second, Some(first)),
+            false => (first, Some(second)),
+        })
+    }
+
+    /// Reads the `n`th integer argument at the entry of a function according to the default calling
+    /// convention. Arguments that cannot be read are returned as zero.
+    pub fn read_arg(&mut self, n: usize) -> u64 {
+        match self.arg_location(n) {
+            ArgLocation::Reg(var) => self.read_reg(var),
+            ArgLocation::Stack(addr) => match self.arch.reg_pc.size {
+                8 => self.mem.read_u64(addr, perm::NONE).unwrap_or(0),
+                _ => self.mem.read_u32(addr, perm::NONE).map_or(0, |x| x as u64),
+            },
+        }
+    }
+
+    /// Writes the `n`th integer argument at the entry of a function according to the default
+    /// calling convention. Returns `None` if the argument could not be written.
+    pub fn write_arg(&mut self, n: usize, value: u64) -> Option<()> {
+        match self.arg_location(n) {
+            ArgLocation::Reg(var) => self.write_reg(var, value),
+            ArgLocation::Stack(addr) => match self.arch.reg_pc.size {
+                8 => self.mem.write_u64(addr, value, perm::NONE).ok()?,
+                _ => self.mem.write_u32(addr, value as u32, perm::NONE).ok()?,
+            },
+        }
+        Some(())
+    }
+
+    /// Reads the return address at the entry of a function, either from the link register or from
+    /// the top of the stack for targets that push the return address.
+    pub fn read_return_addr(&mut self) -> Option<u64> {
+        match link_register(self.arch.triple.architecture) {
+            Some(name) => self.arch.sleigh.get_varnode(name).map(|var| self.read_reg(var)),
+            None => {
+                let sp = self.read_reg(self.arch.reg_sp);
+                match self.arch.reg_sp.size {
+                    8 => self.mem.read_u64(sp, perm::READ).ok(),
+                    _ => self.mem.read_u32(sp, perm::READ).ok().map(|x| x as u64),
+                }
+            }
+        }
+    }
+
+    /// Gets the location of the `n`th integer argument at the entry of a function.
+    fn arg_location(&mut self, n: usize) -> ArgLocation {
+        let int_regs = &self.arch.calling_cov.integers;
+        if let Some(var) = int_regs.get(n) {
+            return ArgLocation::Reg(*var);
+        }
+        let ptr_size = self.arch.reg_pc.size as u64;
+        let offset = stack_args_offset(self.arch.triple.architecture, ptr_size)
+            + (n - int_regs.len()) as u64 * ptr_size;
+        ArgLocation::Stack(self.read_reg(self.arch.reg_sp).wrapping_add(offset))
+    }
+
+    /// Allocates a stack of `stack_size` bytes (with a guard page below it), writes `args`, then
+    /// sets the stack pointer and return address according to the ABI of the current architecture.
+    ///
+    /// Note: this should be called after `arch.on_boot` since booting resets the registers.
+    pub fn init_abi_state(&mut self, stack_size: u64, args: AbiArgs) -> MemResult<AbiState> {
+        let arch = self.arch.triple.architecture;
+        let ptr_size = self.arch.reg_pc.size as u64;
+        let big_endian = self.arch.sleigh.big_endian;
+        let stack_size = crate::mem::align_up(stack_size.max(PAGE_SIZE), PAGE_SIZE);
+
+        let rw = Mapping { perm: perm::READ | perm::WRITE | perm::INIT, value: 0 };
+        let layout = AllocLayout { addr: None, size: stack_size + PAGE_SIZE, align: PAGE_SIZE };
+        let guard_page = self.mem.alloc_memory(layout, rw)?;
+        self.mem.update_perm(guard_page, PAGE_SIZE, perm::NONE)?;
+        let stack_limit = guard_page + PAGE_SIZE;
+        let stack_base = stack_limit + stack_size;
+
+        // A non-executable page used as the return address of the entry point.
+        let layout = AllocLayout { addr: None, size: PAGE_SIZE, align: PAGE_SIZE };
+        let return_addr = self.mem.alloc_memory(layout, Mapping { perm: perm::READ, value: 0 })?;
+
+        let mut writer = StackWriter { cpu: self, sp: stack_base, ptr_size, big_endian };
+        let align = stack_alignment(arch, ptr_size);
+
+        match args {
+            AbiArgs::None | AbiArgs::Call(_) => {
+                let args = match args {
+                    AbiArgs::Call(args) => args,
+                    _ => &[],
+                };
+                let int_regs = writer.cpu.arch.calling_cov.integers.clone();
+                let (reg_args, stack_args) = args.split_at(args.len().min(int_regs.len()));
+                for (var, value) in int_regs.iter().zip(reg_args) {
+                    writer.cpu.write_reg(*var, *value);
+                }
+
+                // Reserve space for the stack arguments, then align the stack so it is aligned at
+                // the point the call instruction would have executed.
+                let link = link_register(arch);
+                let ret_size = if link.is_none() { ptr_size } else { 0 };
+                // Targets without a link register (e.g. AVR) may not reserve any space above the
+                // return address.
+                let offset = stack_args_offset(arch, ptr_size).saturating_sub(ret_size);
+                let args_size = offset + stack_args.len() as u64 * ptr_size;
+                writer.sp = (writer.sp - args_size) & !(align - 1);
+                for (i, value) in stack_args.iter().enumerate() {
+                    writer.write_ptr(writer.sp + offset + i as u64 * ptr_size, *value)?;
+                }
+
+                match link {
+                    Some(name) => writer.set_link_reg(name, return_addr)?,
+                    None => writer.push_ptr(return_addr)?,
+                }
+
+                // Match the shadow stack entry a call instruction would have pushed, so returning
+                // from the function is not reported as a shadow stack violation.
+                if writer.cpu.enable_shadow_stack {
+                    writer.cpu.push_shadow_stack(return_addr);
+                }
+            }
+            AbiArgs::Process { argv, envp } => {
+                let argv_ptrs: Vec<u64> =
+                    argv.iter().map(|x| writer.push_str(x)).collect::<MemResult<_>>()?;
+                let envp_ptrs: Vec<u64> =
+                    envp.iter().map(|x| writer.push_str(x)).collect::<MemResult<_>>()?;
+
+                // argc, argv[], NULL, envp[], NULL, AT_NULL (key and value)
+                let count = 1 + argv_ptrs.len() + 1 + envp_ptrs.len() + 1 + 2;
+                writer.sp = (writer.sp - count as u64 * ptr_size) & !(align - 1);
+
+                let mut addr = writer.sp;
+                let values = std::iter::once(argv.len() as u64)
+                    .chain(argv_ptrs)
+                    .chain([0])
+                    .chain(envp_ptrs)
+                    .chain([0, 0, 0]);
+                for value in values {
+                    writer.write_ptr(addr, value)?;
+                    addr += ptr_size;
+                }
+
+                // Processes exit with a system call instead of returning, but set the link register
+                // anyway so returning from the entry point is detected.
+                if let Some(name) = link_register(arch) {
+                    writer.set_link_reg(name, return_addr)?;
+                }
+            }
+        }
+
+        let sp = writer.sp;
+        self.write_reg(self.arch.reg_sp, sp);
+        Ok(AbiState { guard_page, stack_limit, stack_base, sp, return_addr })
+    }
+}
+
+/// The location of an integer argument at the entry of a function.
+enum ArgLocation {
+    Reg(pcode::VarNode),
+    Stack(u64),
+}
+
+struct StackWriter<'a> {
+    cpu: &'a mut Cpu,
+    sp: u64,
+    ptr_size: u64,
+    big_endian: bool,
+}
+
+impl StackWriter<'_> {
+    fn write_ptr(&mut self, addr: u64, value: u64) -> MemResult<()> {
+        let bytes = match self.big_endian {
+            true => value.to_be_bytes(),
+            false => value.to_le_bytes(),
+        };
+        let bytes = match self.big_endian {
+            true => &bytes[8 - self.ptr_size as usize..],
+            false => &bytes[..self.ptr_size as usize],
+        };
+        self.cpu.mem.write_bytes(addr, bytes, perm::NONE)
+    }
+
+    fn set_link_reg(&mut self, name: &str, value: u64) -> MemResult<()> {
+        let var = self.cpu.arch.sleigh.get_varnode(name).ok_or(MemError::UnmappedRegister)?;
+        self.cpu.write_reg(var, value);
+        Ok(())
+    }
+
+    fn push_ptr(&mut self, value: u64) -> MemResult<()> {
+        self.sp -= self.ptr_size;
+        self.write_ptr(self.sp, value)
+    }
+
+    /// Pushes a NUL terminated copy of `value` onto the stack, returning its address.
+    fn push_str(&mut self, value: &[u8]) -> MemResult<u64> {
+        self.sp -= value.len() as u64 + 1;
+        self.cpu.mem.write_bytes(self.sp, value, perm::NONE)?;
+        self.cpu.mem.write_bytes(self.sp + value.len() as u64, &[0], perm::NONE)?;
+        Ok(self.sp)
+    }
+}
@@ -1,3 +1,5 @@
+use ahash::AHashMap as HashMap;
+
 pub struct Config {
     pub triple: target_lexicon::Triple,
     pub enable_jit: bool,
@@ -7,6 +9,78 @@ pub struct Config {
     pub track_uninitialized: bool,
     pub optimize_instructions: bool,
     pub optimize_block: bool,
+    pub recover_jump_tables: bool,
+
+    /// The maximum number of p-code operations in each block, see
+    /// [crate::lifter::Settings::max_pcode_ops_per_block].
+    pub max_pcode_ops_per_block: usize,
+
+    /// The maximum amount of time to spend lifting each block, see
+    /// [crate::lifter::Settings::max_lift_time].
+    pub max_lift_time: Option<std::time::Duration>,
+
+    pub smc_policy: SmcPolicy,
+
+    /// Whether the virtual clock should advance by an approximate number of cycles for each
+    /// instruction instead of the instruction count (for architectures with a cost table, see
+    /// [crate::lifter::timing::CostTable::for_arch]).
+    pub cycle_timing: bool,
+
+    /// Whether compiled SLEIGH specifications should be cached in the user's cache directory and
+    /// reused when building later VMs for the same architecture (enabled by default).
+    pub cache_sleigh: bool,
+
+    /// Whether textual output produced by the guest should be collected in the VM's guest log (see
+    /// `icicle_vm::guest_log`).
+    pub enable_guest_log: bool,
+
+    /// The initial seed used for all entropy sources visible to the guest.
+    pub entropy_seed: u64,
+
+    /// Configures the values reported by x86 `cpuid`, `rdtsc` and `rdmsr` instructions.
+    pub x86: X86Config,
+}
+
+/// Configures the values reported by x86 `cpuid`, `rdtsc` and `rdmsr` instructions, see
+/// [crate::exec::helpers::x86::X86Model].
+#[derive(Clone, Debug)]
+pub struct X86Config {
+    /// The vendor string reported by `cpuid` leaf 0.
+    pub vendor: [u8; 12],
+
+    /// Overrides the `[eax, ebx, ecx, edx]` values reported by `cpuid` for a `(leaf, subleaf)`
+    /// pair. A subleaf of `None` applies to every subleaf of the leaf.
+    pub cpuid: HashMap<(u32, Option<u32>), [u32; 4]>,
+
+    /// The initial values of model specific registers that are not backed by a register in the
+    /// SLEIGH specification.
+    pub msrs: HashMap<u32, u64>,
+
+    /// The amount the time stamp counter advances by for every tick of the virtual clock (1 by
+    /// default). If zero, the time stamp counter only changes when it is written to by the guest.
+    pub tsc_scale: u64,
+}
+
+impl Default for X86Config {
+    fn default() -> Self {
+        Self { vendor: *b"GenuineIntel", cpuid: HashMap::new(), msrs: HashMap::new(), tsc_scale: 1 }
+    }
+}
+
+/// Controls how modifications to code that has already been translated are handled.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SmcPolicy {
+    /// Exit with `SelfModifyingCode` whenever translated code is modified. Instruction cache
+    /// invalidation operations executed by the guest are ignored.
+    #[default]
+    Exit,
+
+    /// Allow translated code to be modified, and treat instruction cache invalidation operations
+    /// executed by the guest as hints to invalidate any translated code in the referenced range.
+    ///
+    /// Note: code that is modified without a corresponding invalidation will continue to execute
+    /// the stale translation (matching hardware with non-coherent instruction caches).
+    InvalidateOnHint,
 }
 
 impl Config {
@@ -29,6 +103,15 @@ impl Default for Config {
             track_uninitialized: false,
             optimize_instructions: true,
             optimize_block: true,
+            recover_jump_tables: true,
+            max_pcode_ops_per_block: 0x4000,
+            max_lift_time: None,
+            smc_policy: SmcPolicy::Exit,
+            cycle_timing: false,
+            cache_sleigh: true,
+            enable_guest_log: false,
+            entropy_seed: 0,
+            x86: X86Config::default(),
         }
     }
 }
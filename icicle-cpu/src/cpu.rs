@@ -11,6 +11,7 @@ use crate::{
     lifter::{BlockExit, Target},
     regs::{RegValue, Regs, ValueSource},
     trace::{self, Trace},
+    utils::Drbg,
     ExceptionCode, InstHook, InternalError, VarSource,
 };
 
@@ -266,6 +267,10 @@ pub struct Cpu {
     pub icount: u64,
     pub fuel: Fuel,
 
+    /// A register containing the extra cycles executed beyond one per instruction, when cycle
+    /// timing is enabled (see [crate::lifter::timing]).
+    pub extra_cycles: Option<pcode::VarNode>,
+
     pub exception: Exception,
     pub pending_exception: Option<Exception>,
     pub block_id: u64,
@@ -276,6 +281,14 @@ pub struct Cpu {
 
     pub trace: Trace,
 
+    /// The source of random values for instructions like `RDRAND`. This is shared with any
+    /// entropy sources managed by the environment.
+    pub entropy: Drbg,
+
+    /// The model used for x86 `cpuid`, `rdtsc` and `rdmsr` instructions. A model with the
+    /// default configuration is created the first time it is used if this is not set.
+    pub x86: Option<Box<crate::exec::helpers::x86::X86Model>>,
+
     /// Handlers perform special operations when reading / writing to registers. Currently we
     /// simply check each handler sequentially, since we expect very few handlers and this allows
     /// us to avoid code bloat.
@@ -300,17 +313,21 @@ impl Cpu {
         let pc_offset = Regs::var_offset(arch.reg_pc);
         let pc_mask = pcode::mask(arch.reg_pc.size as u64 * 8);
 
+        let mut mem = Mmu::new();
+        mem.big_endian = arch.sleigh.big_endian;
+
         Box::new(Cpu {
             regs: Regs::new(),
             args: [0; 8],
             shadow_stack: ShadowStack::new(),
             enable_shadow_stack: false,
 
-            mem: Mmu::new(),
+            mem,
             jit_ctx: JitContext::default(),
 
             icount: 0,
             fuel: Fuel::default(),
+            extra_cycles: None,
 
             exception: Exception::default(),
             pending_exception: None,
@@ -321,6 +338,8 @@ impl Cpu {
             arch,
 
             trace: Trace::default(),
+            entropy: Drbg::default(),
+            x86: None,
             reg_handlers: UnsafeCell::new(vec![]),
 
             pc_offset,
@@ -339,6 +358,7 @@ impl Cpu {
 
         self.icount = 0;
         self.fuel = Fuel::default();
+        self.entropy.reset();
 
         self.exception.code = ExceptionCode::None as u32;
         self.exception.value = 0;
@@ -390,6 +410,26 @@ impl Cpu {
         self.icount + self.fuel.start - self.fuel.remaining
     }
 
+    /// Gets the current value of the virtual clock. This is the same as the instruction count,
+    /// unless cycle timing is enabled.
+    #[inline]
+    pub fn clock(&self) -> u64 {
+        match self.extra_cycles {
+            Some(var) => {
+                let extra = ValueSource::read_var::<u64>(&self.regs, var);
+                self.icount().wrapping_add(extra)
+            }
+            None => self.icount(),
+        }
+    }
+
+    /// Converts a deadline for the virtual clock to the (lower bound of the) instruction count the
+    /// deadline will be reached at.
+    #[inline]
+    pub fn clock_to_icount(&self, deadline: u64) -> u64 {
+        self.icount().saturating_add(deadline.saturating_sub(self.clock()))
+    }
+
     /// Translate a SLEIGH register offset to an Icicle varnode.
     pub fn var_for_offset(&self, offset: u32, size: u8) -> Option<pcode::VarNode> {
         let (reg, reg_offset) = self.arch.sleigh.map_sleigh_reg(offset, size)?;
@@ -729,6 +769,7 @@ impl<'a> PcodeExecutor for UncheckedExecutor<'a> {
     }
 }
 
+#[derive(Clone)]
 pub struct CpuSnapshot {
     pub regs: Regs,
     pub args: [u128; 8],
@@ -738,6 +779,7 @@ pub struct CpuSnapshot {
     pub icount: u64,
     pub block_id: u64,
     pub block_offset: u64,
+    pub entropy: u64,
 }
 
 impl Cpu {
@@ -751,6 +793,7 @@ impl Cpu {
             icount: self.icount,
             block_id: self.block_id,
             block_offset: self.block_offset,
+            entropy: self.entropy.state(),
         })
     }
 
@@ -765,6 +808,7 @@ impl Cpu {
         self.pending_exception = snapshot.pending_exception;
         self.icount = snapshot.icount;
         self.fuel = Fuel::default();
+        self.entropy.set_state(snapshot.entropy);
 
         // @fixme: Check if we can avoiding needing to save/restore these values.
         self.block_id = snapshot.block_id;
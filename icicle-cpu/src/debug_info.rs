@@ -2,8 +2,28 @@ use std::{collections::BTreeMap, rc::Rc};
 
 use object::{Object, ObjectKind, ObjectSection, ObjectSymbol};
 
+use crate::Cpu;
+
+mod frame;
+mod variables;
+
+pub use self::{
+    frame::{dwarf_register_name, dwarf_stack_pointer, Frame},
+    variables::{
+        FrameBase, FrameLayout, FrameObject, Member, ScopedVariable, Type, TypeKind, Variable,
+        VariableKind, VariableLocation,
+    },
+};
+
 pub type Addr2LineCtx = addr2line::Context<gimli::EndianRcSlice<gimli::RunTimeEndian>>;
 
+/// A compilation unit along with the base address and call frame information of its object.
+type UnitInfo<'a> = (
+    gimli::UnitRef<'a, gimli::EndianRcSlice<gimli::RunTimeEndian>>,
+    u64,
+    Option<&'a frame::CallFrameInfo>,
+);
+
 #[derive(Clone, Default)]
 pub struct DebugInfo {
     /// Symbol table representing a mapping from an address to the name and size of a symbol.
@@ -28,15 +48,35 @@ pub struct DebugInfo {
     /// The program entrypoint
     pub entry_ptr: u64,
 
+    /// Shared libraries that were loaded and linked by the ELF loader (see
+    /// [crate::elf::ElfLoader::library_paths]).
+    pub modules: Vec<LoadedModule>,
+
     /// Context object for symbolizing addresses.
     // @fixme: this should read data directly from the MMU instead of maintaining a copy of the
     // loaded file
     ctx: BTreeMap<u64, std::rc::Rc<Addr2LineCtx>>,
 
+    /// Call frame information for each loaded object, keyed by relocation offset.
+    frames: BTreeMap<u64, std::rc::Rc<frame::CallFrameInfo>>,
+
     /// The offset the original binary was relocated by.
     relocation_offset: u64,
 }
 
+/// A shared library that was loaded alongside the main binary.
+#[derive(Clone, Debug)]
+pub struct LoadedModule {
+    /// The path the library was loaded from.
+    pub path: Vec<u8>,
+
+    /// The address the library was loaded at.
+    pub base: u64,
+
+    /// The size of the memory region occupied by the library.
+    pub len: u64,
+}
+
 impl DebugInfo {
     /// Load symbols and debug information about the loaded library.
     // @todo: consider doing this on-demand.
@@ -62,6 +102,8 @@ impl DebugInfo {
             }
             Err(e) => tracing::warn!("failed to get DWARF debug context: {e}"),
         }
+        let cfi = frame::CallFrameInfo::new(&object, endian);
+        self.frames.insert(relocation_offset, std::rc::Rc::new(cfi));
 
         tracing::info!("Loaded object file with architecture {:?}", object.architecture());
         let is_arm = matches!(object.architecture(), object::Architecture::Arm);
@@ -117,7 +159,20 @@ impl DebugInfo {
         }
 
         // Otherwise fallback to trying to find the closest symbol
-        self.get_symbol(addr)
+        if let Some(info) = self.get_symbol(addr) {
+            return Some(info);
+        }
+
+        let module = self.module_containing(addr)?;
+        Some(SourceLocation {
+            library_name_and_offset: Some((module.path.clone(), module.base)),
+            ..SourceLocation::default()
+        })
+    }
+
+    /// Finds the shared library loaded at `addr`.
+    pub fn module_containing(&self, addr: u64) -> Option<&LoadedModule> {
+        self.modules.iter().find(|module| (module.base..module.base + module.len).contains(&addr))
     }
 
     fn get_symbol(&self, addr: u64) -> Option<SourceLocation> {
@@ -180,6 +235,110 @@ impl DebugInfo {
         Some(output)
     }
 
+    /// Resolves a source-level variable visible in `frame`.
+    ///
+    /// `path` is the name of a local, parameter or global variable optionally followed by member
+    /// accesses (`a.b`, `p->b`), array indexing (`a[1]`) and dereferences (`*p`).
+    ///
+    /// Since `DebugInfo` is cheap to clone, a copy can be moved into a hook to check source-level
+    /// state while executing, e.g.: `debug_info.read_variable(cpu, &Frame::current(cpu), "x")`.
+    pub fn read_variable(
+        &self,
+        cpu: &mut Cpu,
+        frame: &Frame,
+        path: &str,
+    ) -> Result<Variable, String> {
+        let (unit, library_base, cfi) = self.find_unit(frame.lookup_pc())?;
+        variables::read_variable(unit, library_base, cfi, cpu, frame, path)
+    }
+
+    /// Finds every parameter and local variable that is in scope at the PC of `frame`, starting
+    /// with the innermost scope. Variables shadowed by an inner scope are not included.
+    ///
+    /// The location of each variable is evaluated against the register state of `frame`, and
+    /// variables that are not available at the PC (e.g. because they were optimized out) are
+    /// still included with the reason as the error of [ScopedVariable::location].
+    pub fn variables(&self, cpu: &mut Cpu, frame: &Frame) -> Result<Vec<ScopedVariable>, String> {
+        let (unit, library_base, cfi) = self.find_unit(frame.lookup_pc())?;
+        variables::scope_variables(unit, library_base, cfi, cpu, frame)
+    }
+
+    /// Finds the layout of the stack frame of the function containing `pc` (see [FrameLayout]).
+    /// Returns `None` if `pc` is not inside of a function with debug info.
+    pub fn frame_layout(&self, pc: u64) -> Result<Option<FrameLayout>, String> {
+        let (unit, library_base, _) = self.find_unit(pc)?;
+        variables::frame_layout(unit, library_base, pc.wrapping_sub(library_base))
+    }
+
+    /// Gets the DWARF register number and offset used to compute the canonical frame address at
+    /// `pc` from the call frame information.
+    pub fn cfa_rule(&self, pc: u64) -> Option<(u16, i64)> {
+        let library_base = self
+            .frames
+            .range(..=pc)
+            .last()
+            .map_or(self.relocation_offset, |(library_base, _)| *library_base);
+        self.frames.get(&library_base)?.cfa_rule(pc.wrapping_sub(library_base))
+    }
+
+    /// Finds the compilation unit containing `pc`, along with the base address and call frame
+    /// information of the object that contains it.
+    fn find_unit(
+        &self,
+        pc: u64,
+    ) -> Result<UnitInfo<'_>, String> {
+        let library_base = self
+            .ctx
+            .range(..=pc)
+            .last()
+            .map_or(self.relocation_offset, |(library_base, _)| *library_base);
+
+        let ctx = self.ctx.get(&library_base).ok_or("no debug info available")?;
+        let local_pc = pc.wrapping_sub(library_base);
+        let unit = ctx
+            .find_dwarf_and_unit(local_pc)
+            .skip_all_loads()
+            .ok_or_else(|| format!("no debug info for {pc:#x}"))?;
+        let cfi = self.frames.get(&library_base).map(|x| &**x);
+        Ok((unit, library_base, cfi))
+    }
+
+    /// Computes the register state of the caller of `frame` using the call frame information
+    /// (`.eh_frame` or `.debug_frame`) of the object containing it. Returns `None` if the frame
+    /// could not be unwound or `frame` is the outermost frame.
+    pub fn unwind(&self, cpu: &mut Cpu, frame: &Frame) -> Option<Frame> {
+        let pc = frame.lookup_pc();
+        let library_base = self
+            .frames
+            .range(..=pc)
+            .last()
+            .map_or(self.relocation_offset, |(library_base, _)| *library_base);
+        let cfi = self.frames.get(&library_base)?;
+        cfi.unwind(pc.wrapping_sub(library_base), cpu, frame)
+    }
+
+    /// Unwinds the call stack from the current state of `cpu`, returning at most `max_frames`
+    /// frames starting with the innermost frame.
+    ///
+    /// Unlike the shadow stack, this only depends on the register state and the call frame
+    /// information, so it works for code that uses `setjmp`/`longjmp` or switches stacks manually.
+    pub fn backtrace(&self, cpu: &mut Cpu, max_frames: usize) -> Vec<Frame> {
+        let mut frames = vec![Frame::current(cpu)];
+        while frames.len() < max_frames {
+            let frame = frames.last().unwrap();
+            let Some(caller) = self.unwind(cpu, frame)
+            else {
+                break;
+            };
+            // Avoid looping forever on invalid CFI that does not unwind anything.
+            if caller.pc == frame.pc && caller.regs == frame.regs {
+                break;
+            }
+            frames.push(caller);
+        }
+        frames
+    }
+
     /// Return an iterator over all symbols found in the debug info.
     pub fn debug_symbols_iter(&self) {
         todo!()
@@ -212,6 +371,12 @@ impl DebugInfo {
     }
 }
 
+/// Returns the build ID (the `NT_GNU_BUILD_ID` note) of an object file, if it has one.
+pub fn build_id(data: &[u8]) -> Option<Vec<u8>> {
+    let object = object::read::File::parse(data).ok()?;
+    Some(object.build_id().ok()??.to_vec())
+}
+
 fn dwarf_ctx(
     object: &object::File,
     endian: gimli::RunTimeEndian,
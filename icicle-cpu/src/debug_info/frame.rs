@@ -0,0 +1,255 @@
+//! Register state of stack frames and call frame information (CFI) used for evaluating DWARF
+//! expressions that refer to the canonical frame address and for unwinding the call stack.
+
+use std::{collections::HashMap, rc::Rc};
+
+use gimli::UnwindSection;
+use object::{Object, ObjectSection};
+use target_lexicon::Architecture;
+
+use crate::{mem::perm, Cpu};
+
+type Reader = gimli::EndianRcSlice<gimli::RunTimeEndian>;
+
+/// The register state of a stack frame.
+#[derive(Clone, Debug, Default)]
+pub struct Frame {
+    /// The address of the instruction being executed in the frame.
+    pub pc: u64,
+
+    /// Registers (keyed by DWARF register number) with values that differ from the current state
+    /// of the CPU. Empty for the innermost frame.
+    pub regs: HashMap<u16, u64>,
+
+    /// The number of frames between this frame and the innermost frame.
+    pub depth: usize,
+}
+
+impl Frame {
+    /// Returns the innermost frame, corresponding to the current state of the CPU.
+    pub fn current(cpu: &mut Cpu) -> Self {
+        Self { pc: cpu.read_pc(), regs: HashMap::new(), depth: 0 }
+    }
+
+    /// The address used for finding the debug info of the frame. For outer frames `pc` is the
+    /// return address, which may be past the end of the calling function (e.g. after a call to a
+    /// `noreturn` function), so the address of the last byte of the call instruction is used
+    /// instead.
+    pub fn lookup_pc(&self) -> u64 {
+        match self.depth {
+            0 => self.pc,
+            _ => self.pc.wrapping_sub(1),
+        }
+    }
+
+    /// Reads the value of the register with DWARF register number `reg` in this frame.
+    pub fn read_register(&self, cpu: &mut Cpu, reg: u16) -> Option<u64> {
+        if let Some(value) = self.regs.get(&reg) {
+            return Some(*value);
+        }
+        let name = dwarf_register_name(cpu.arch.triple.architecture, reg)?;
+        let var = cpu.arch.sleigh.get_varnode(name)?;
+        Some(cpu.read_reg(var))
+    }
+}
+
+/// Gets the name of the SLEIGH register assigned DWARF register number `reg` on `arch`.
+pub fn dwarf_register_name(arch: Architecture, reg: u16) -> Option<&'static str> {
+    const X86_64: &[&str] = &[
+        "RAX", "RDX", "RCX", "RBX", "RSI", "RDI", "RBP", "RSP", "R8", "R9", "R10", "R11", "R12",
+        "R13", "R14", "R15", "RIP",
+    ];
+    const X86: &[&str] = &["EAX", "ECX", "EDX", "EBX", "ESP", "EBP", "ESI", "EDI", "EIP"];
+    const AARCH64: &[&str] = &[
+        "x0", "x1", "x2", "x3", "x4", "x5", "x6", "x7", "x8", "x9", "x10", "x11", "x12", "x13",
+        "x14", "x15", "x16", "x17", "x18", "x19", "x20", "x21", "x22", "x23", "x24", "x25", "x26",
+        "x27", "x28", "x29", "x30", "sp",
+    ];
+    const ARM: &[&str] = &[
+        "r0", "r1", "r2", "r3", "r4", "r5", "r6", "r7", "r8", "r9", "r10", "r11", "r12", "sp",
+        "lr", "pc",
+    ];
+    const RISCV: &[&str] = &[
+        "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3",
+        "a4", "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11",
+        "t3", "t4", "t5", "t6",
+    ];
+    const MIPS: &[&str] = &[
+        "zero", "at", "v0", "v1", "a0", "a1", "a2", "a3", "t0", "t1", "t2", "t3", "t4", "t5",
+        "t6", "t7", "s0", "s1", "s2", "s3", "s4", "s5", "s6", "s7", "t8", "t9", "k0", "k1", "gp",
+        "sp", "s8", "ra",
+    ];
+
+    let names = match arch {
+        Architecture::X86_64 => X86_64,
+        Architecture::X86_32(_) => X86,
+        Architecture::Aarch64(_) => AARCH64,
+        Architecture::Arm(_) => ARM,
+        Architecture::Riscv32(_) | Architecture::Riscv64(_) => RISCV,
+        Architecture::Mips32(_) => MIPS,
+        _ => return None,
+    };
+    names.get(reg as usize).copied()
+}
+
+/// Gets the DWARF register number of the stack pointer on `arch`.
+pub fn dwarf_stack_pointer(arch: Architecture) -> Option<u16> {
+    Some(match arch {
+        Architecture::X86_64 => 7,
+        Architecture::X86_32(_) => 4,
+        Architecture::Aarch64(_) => 31,
+        Architecture::Arm(_) => 13,
+        Architecture::Riscv32(_) | Architecture::Riscv64(_) => 2,
+        Architecture::Mips32(_) => 29,
+        _ => return None,
+    })
+}
+
+/// The call frame information of a single object file.
+pub(crate) struct CallFrameInfo {
+    eh_frame: Option<gimli::EhFrame<Reader>>,
+    debug_frame: Option<gimli::DebugFrame<Reader>>,
+    bases: gimli::BaseAddresses,
+}
+
+impl CallFrameInfo {
+    pub fn new(object: &object::File, endian: gimli::RunTimeEndian) -> Self {
+        let load = |name: &str| {
+            let section = object.section_by_name(name)?;
+            let data: Rc<[u8]> = section.uncompressed_data().ok()?.into();
+            Some((gimli::EndianRcSlice::new(data, endian), section.address()))
+        };
+
+        let mut bases = gimli::BaseAddresses::default();
+        if let Some(text) = object.section_by_name(".text") {
+            bases = bases.set_text(text.address());
+        }
+        if let Some(hdr) = object.section_by_name(".eh_frame_hdr") {
+            bases = bases.set_eh_frame_hdr(hdr.address());
+        }
+
+        let address_size = if object.is_64() { 8 } else { 4 };
+        let eh_frame = load(".eh_frame").map(|(data, addr)| {
+            bases = bases.clone().set_eh_frame(addr);
+            let mut eh_frame = gimli::EhFrame::from(data);
+            eh_frame.set_address_size(address_size);
+            eh_frame
+        });
+        let debug_frame = load(".debug_frame").map(|(data, _)| {
+            let mut debug_frame = gimli::DebugFrame::from(data);
+            debug_frame.set_address_size(address_size);
+            debug_frame
+        });
+
+        Self { eh_frame, debug_frame, bases }
+    }
+
+    /// Computes the canonical frame address (CFA) of the frame executing `pc` (relative to the
+    /// address the object was linked at). Returns `None` if there is no CFI covering `pc` or the
+    /// CFA rule is not supported.
+    pub fn cfa(
+        &self,
+        pc: u64,
+        read_register: &mut dyn FnMut(gimli::Register) -> Option<u64>,
+    ) -> Option<u64> {
+        self.with_row(pc, |row, _| eval_cfa_rule(row.cfa(), read_register))
+    }
+
+    /// Gets the DWARF register number and offset used to compute the CFA at `pc` (relative to the
+    /// address the object was linked at). Returns `None` if there is no CFI covering `pc` or the
+    /// CFA is computed with an expression.
+    pub fn cfa_rule(&self, pc: u64) -> Option<(u16, i64)> {
+        self.with_row(pc, |row, _| match row.cfa() {
+            gimli::CfaRule::RegisterAndOffset { register, offset } => Some((register.0, *offset)),
+            gimli::CfaRule::Expression(_) => None,
+        })
+    }
+
+    /// Computes the register state of the caller of `frame`, using the CFI covering `pc` (relative
+    /// to the address the object was linked at). Returns `None` if there is no CFI covering `pc`,
+    /// the rules are not supported, or `frame` is the outermost frame.
+    pub fn unwind(&self, pc: u64, cpu: &mut Cpu, frame: &Frame) -> Option<Frame> {
+        let arch = cpu.arch.triple.architecture;
+        self.with_row(pc, |row, return_address| {
+            let cfa = eval_cfa_rule(row.cfa(), &mut |reg| frame.read_register(cpu, reg.0))?;
+
+            // Registers without a rule keep the value they had in `frame`.
+            let mut caller = Frame { pc: 0, regs: frame.regs.clone(), depth: frame.depth + 1 };
+            for (reg, rule) in row.registers() {
+                let value = match rule {
+                    gimli::RegisterRule::Undefined if *reg == return_address => return None,
+                    gimli::RegisterRule::Undefined | gimli::RegisterRule::SameValue => continue,
+                    gimli::RegisterRule::Offset(offset) => {
+                        read_pointer(cpu, cfa.wrapping_add_signed(*offset))?
+                    }
+                    gimli::RegisterRule::ValOffset(offset) => cfa.wrapping_add_signed(*offset),
+                    gimli::RegisterRule::Register(other) => frame.read_register(cpu, other.0)?,
+                    gimli::RegisterRule::Constant(value) => *value,
+                    // @todo: support register rules that use DWARF expressions.
+                    _ => continue,
+                };
+                caller.regs.insert(reg.0, value);
+            }
+            caller.regs.insert(dwarf_stack_pointer(arch)?, cfa);
+
+            caller.pc = caller.read_register(cpu, return_address.0)?;
+            if matches!(arch, Architecture::Arm(_)) {
+                // Clear thumb bit
+                caller.pc &= !1;
+            }
+            (caller.pc != 0).then_some(caller)
+        })
+    }
+
+    /// Calls `f` with the row of the unwind table for `pc` and the return address register of the
+    /// CIE, preferring `.eh_frame` over `.debug_frame`.
+    fn with_row<T>(
+        &self,
+        pc: u64,
+        f: impl FnOnce(&gimli::UnwindTableRow<usize>, gimli::Register) -> Option<T>,
+    ) -> Option<T> {
+        let mut ctx = gimli::UnwindContext::new();
+        if let Some(eh_frame) = &self.eh_frame {
+            let cie = gimli::EhFrame::cie_from_offset;
+            if let Ok(fde) = eh_frame.fde_for_address(&self.bases, pc, cie) {
+                if let Ok(row) = fde.unwind_info_for_address(eh_frame, &self.bases, &mut ctx, pc) {
+                    return f(row, fde.cie().return_address_register());
+                }
+            }
+        }
+        if let Some(debug_frame) = &self.debug_frame {
+            let cie = gimli::DebugFrame::cie_from_offset;
+            if let Ok(fde) = debug_frame.fde_for_address(&self.bases, pc, cie) {
+                let row = fde.unwind_info_for_address(debug_frame, &self.bases, &mut ctx, pc);
+                if let Ok(row) = row {
+                    return f(row, fde.cie().return_address_register());
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Reads a pointer sized value from guest memory at `addr`.
+fn read_pointer(cpu: &mut Cpu, addr: u64) -> Option<u64> {
+    let size = cpu.arch.reg_pc.size as usize;
+    let mut buf = [0; 8];
+    cpu.mem.read_bytes(addr, &mut buf[..size], perm::READ).ok()?;
+    Some(match cpu.arch.sleigh.big_endian {
+        true => buf[..size].iter().fold(0, |acc, x| (acc << 8) | *x as u64),
+        false => u64::from_le_bytes(buf),
+    })
+}
+
+fn eval_cfa_rule(
+    rule: &gimli::CfaRule<usize>,
+    read_register: &mut dyn FnMut(gimli::Register) -> Option<u64>,
+) -> Option<u64> {
+    match rule {
+        gimli::CfaRule::RegisterAndOffset { register, offset } => {
+            Some(read_register(*register)?.wrapping_add_signed(*offset))
+        }
+        // @todo: support CFA expressions.
+        gimli::CfaRule::Expression(_) => None,
+    }
+}
@@ -0,0 +1,1294 @@
+//! Resolution of source-level variables and their types from DWARF debugging information entries.
+
+use std::collections::HashSet;
+
+use gimli::{AttributeValue, Reader as _, UnitOffset};
+
+use crate::{
+    debug_info::{frame::CallFrameInfo, Frame},
+    mem::perm,
+    Cpu,
+};
+
+type R = gimli::EndianRcSlice<gimli::RunTimeEndian>;
+type UnitRef<'a> = gimli::UnitRef<'a, R>;
+type Scope = (UnitOffset, gimli::DwTag);
+
+/// The maximum number of pointers to follow when resolving the target of a pointer type (avoids
+/// infinite recursion on self-referential types).
+const MAX_POINTER_DEPTH: usize = 4;
+
+/// The maximum number of array elements to include when displaying a variable.
+const MAX_DISPLAY_ELEMENTS: u64 = 16;
+
+/// The type of a source-level variable. Typedefs and type qualifiers (e.g. `const`) are resolved
+/// to their underlying type.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Type {
+    /// The name of the type (if it has one).
+    pub name: Option<String>,
+
+    /// The size of the type in bytes.
+    pub size: u64,
+
+    pub kind: TypeKind,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TypeKind {
+    /// An integer, floating point or boolean type with a `DW_ATE_*` encoding.
+    Base { encoding: gimli::DwAte },
+
+    /// A pointer (or reference) to a value of the target type. The target is `None` for `void`
+    /// pointers or if the target was nested too deeply to resolve.
+    Pointer(Option<Box<Type>>),
+
+    /// A struct, class or union type.
+    Struct(Vec<Member>),
+
+    /// An array of elements with a known (or unknown) count.
+    Array { element: Box<Type>, count: Option<u64> },
+
+    /// An enumeration with a list of `(name, value)` pairs.
+    Enum(Vec<(String, i64)>),
+
+    /// A type that is not supported.
+    Unknown,
+}
+
+/// A member of a struct, class or union type.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Member {
+    pub name: String,
+
+    /// The offset of the member from the start of the parent type.
+    pub offset: u64,
+
+    pub ty: Type,
+}
+
+/// The location where the value of a variable is stored.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum VariableLocation {
+    /// The variable is stored in memory at the given address.
+    Address(u64),
+
+    /// The variable is stored in a register (with the given DWARF register number).
+    Register { reg: u16, value: u64 },
+
+    /// The variable is not stored anywhere, but it has a known value.
+    Value(Vec<u8>),
+}
+
+/// A source-level variable resolved from debug info.
+#[derive(Clone, Debug)]
+pub struct Variable {
+    /// The path used to access the variable (e.g. `a.b`).
+    pub name: String,
+
+    pub ty: Type,
+
+    pub location: VariableLocation,
+}
+
+/// Whether a variable in scope is a parameter of the enclosing function or a local variable.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VariableKind {
+    Parameter,
+    Local,
+}
+
+/// A variable that is in scope at the PC of a frame (see
+/// [DebugInfo::variables](crate::debug_info::DebugInfo::variables)).
+#[derive(Clone, Debug)]
+pub struct ScopedVariable {
+    pub name: String,
+
+    pub kind: VariableKind,
+
+    pub ty: Type,
+
+    /// The location of the variable at the PC of the frame, or the reason the variable is not
+    /// available (e.g. it was optimized out).
+    pub location: Result<VariableLocation, String>,
+}
+
+/// How the frame base (`DW_AT_frame_base`) of a function is computed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrameBase {
+    /// The value of a register (with the given DWARF register number) plus a constant offset.
+    Register { reg: u16, offset: i64 },
+
+    /// The canonical frame address computed from the call frame information.
+    Cfa,
+}
+
+/// A variable that is stored at a fixed offset from the frame base of a function.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FrameObject {
+    pub name: String,
+
+    /// The offset of the variable from the frame base.
+    pub offset: i64,
+
+    /// The size of the variable in bytes.
+    pub size: u64,
+}
+
+/// The variables stored in the stack frame of a function.
+#[derive(Clone, Debug)]
+pub struct FrameLayout {
+    pub function: Option<String>,
+
+    /// The (relocated) address of the start of the function.
+    pub start: u64,
+
+    pub frame_base: FrameBase,
+
+    /// The variables stored in the frame (including variables of nested scopes and inlined
+    /// functions) ordered by offset.
+    pub objects: Vec<FrameObject>,
+}
+
+impl FrameLayout {
+    /// Finds the object that contains the byte at `offset` from the frame base.
+    pub fn object_at(&self, offset: i64) -> Option<&FrameObject> {
+        self.objects
+            .iter()
+            .find(|x| offset >= x.offset && offset < x.offset.saturating_add(x.size as i64))
+    }
+}
+
+impl ScopedVariable {
+    /// Gets the variable for reading its value, fails if the variable is not available.
+    pub fn variable(&self) -> Result<Variable, String> {
+        let location = self.location.clone().map_err(|e| format!("`{}`: {e}", self.name))?;
+        Ok(Variable { name: self.name.clone(), ty: self.ty.clone(), location })
+    }
+}
+
+impl Variable {
+    /// Reads the bytes that make up the value of the variable.
+    pub fn read_bytes(&self, cpu: &mut Cpu) -> Result<Vec<u8>, String> {
+        let size = self.ty.size as usize;
+        match &self.location {
+            VariableLocation::Address(addr) => {
+                let mut buf = vec![0; size];
+                cpu.mem
+                    .read_bytes_large(*addr, &mut buf, perm::READ)
+                    .map_err(|e| format!("failed to read `{}` at {addr:#x}: {e}", self.name))?;
+                Ok(buf)
+            }
+            VariableLocation::Register { value, .. } => {
+                if size > 8 {
+                    return Err(format!("`{}` is too large for a register", self.name));
+                }
+                Ok(u64_to_bytes(*value, size, cpu.arch.sleigh.big_endian))
+            }
+            VariableLocation::Value(bytes) => Ok(bytes.clone()),
+        }
+    }
+
+    /// Reads the value of the variable as an integer. Only supported for variables that are at
+    /// most 8 bytes in size.
+    pub fn read_u64(&self, cpu: &mut Cpu) -> Result<u64, String> {
+        if self.ty.size > 8 {
+            return Err(format!("`{}` is too large to read as an integer", self.name));
+        }
+        let bytes = self.read_bytes(cpu)?;
+        Ok(bytes_to_u64(&bytes, cpu.arch.sleigh.big_endian))
+    }
+
+    /// Returns the member of a struct variable named `name`.
+    pub fn member(&self, name: &str) -> Result<Variable, String> {
+        let TypeKind::Struct(members) = &self.ty.kind
+        else {
+            return Err(format!("`{}` is not a struct", self.name));
+        };
+        let member = members
+            .iter()
+            .find(|member| member.name == name)
+            .ok_or_else(|| format!("`{}` has no member named `{name}`", self.name))?;
+        let location = self.sub_location(member.offset, member.ty.size)?;
+        Ok(Variable { name: format!("{}.{name}", self.name), ty: member.ty.clone(), location })
+    }
+
+    /// Dereferences a pointer variable.
+    pub fn deref(&self, cpu: &mut Cpu) -> Result<Variable, String> {
+        self.pointer_element(cpu, 0).map(|mut var| {
+            var.name = format!("*{}", self.name);
+            var
+        })
+    }
+
+    /// Returns the element at `index` of an array or pointer variable.
+    pub fn index(&self, cpu: &mut Cpu, index: u64) -> Result<Variable, String> {
+        let mut var = match &self.ty.kind {
+            TypeKind::Array { element, count } => {
+                if count.is_some_and(|count| index >= count) {
+                    return Err(format!("index {index} is out of bounds for `{}`", self.name));
+                }
+                let offset = index
+                    .checked_mul(element.size)
+                    .ok_or_else(|| format!("index {index} is out of bounds for `{}`", self.name))?;
+                let location = self.sub_location(offset, element.size)?;
+                Variable { name: String::new(), ty: (**element).clone(), location }
+            }
+            _ => self.pointer_element(cpu, index)?,
+        };
+        var.name = format!("{}[{index}]", self.name);
+        Ok(var)
+    }
+
+    fn pointer_element(&self, cpu: &mut Cpu, index: u64) -> Result<Variable, String> {
+        let TypeKind::Pointer(target) = &self.ty.kind
+        else {
+            return Err(format!("`{}` is not a pointer", self.name));
+        };
+        let target = target
+            .as_deref()
+            .ok_or_else(|| format!("cannot dereference `{}`: unknown target type", self.name))?;
+        let addr = self.read_u64(cpu)?.wrapping_add(index.wrapping_mul(target.size));
+        Ok(Variable {
+            name: String::new(),
+            ty: target.clone(),
+            location: VariableLocation::Address(addr),
+        })
+    }
+
+    fn sub_location(&self, offset: u64, size: u64) -> Result<VariableLocation, String> {
+        match &self.location {
+            VariableLocation::Address(addr) => {
+                Ok(VariableLocation::Address(addr.wrapping_add(offset)))
+            }
+            VariableLocation::Value(bytes) => offset
+                .checked_add(size)
+                .and_then(|end| {
+                    bytes.get(usize::try_from(offset).ok()?..usize::try_from(end).ok()?)
+                })
+                .map(|bytes| VariableLocation::Value(bytes.to_vec()))
+                .ok_or_else(|| format!("`{}` has an incomplete value", self.name)),
+            VariableLocation::Register { .. } => {
+                Err(format!("accessing part of register variable `{}` is not supported", self.name))
+            }
+        }
+    }
+
+    /// Formats the value of the variable in a C-like syntax.
+    pub fn display(&self, cpu: &mut Cpu) -> Result<String, String> {
+        self.display_with_depth(cpu, 0)
+    }
+
+    fn display_with_depth(&self, cpu: &mut Cpu, depth: usize) -> Result<String, String> {
+        let size = self.ty.size;
+        Ok(match &self.ty.kind {
+            TypeKind::Base { encoding } if size <= 8 => {
+                let value = self.read_u64(cpu)?;
+                match *encoding {
+                    gimli::DW_ATE_float if size == 4 => f32::from_bits(value as u32).to_string(),
+                    gimli::DW_ATE_float if size == 8 => f64::from_bits(value).to_string(),
+                    gimli::DW_ATE_boolean => (value != 0).to_string(),
+                    gimli::DW_ATE_signed | gimli::DW_ATE_signed_char => {
+                        let shift = 64 - 8 * size.max(1);
+                        (((value << shift) as i64) >> shift).to_string()
+                    }
+                    _ => value.to_string(),
+                }
+            }
+            TypeKind::Pointer(_) => format!("{:#x}", self.read_u64(cpu)?),
+            TypeKind::Enum(variants) => {
+                let value = self.read_u64(cpu)? as i64;
+                match variants.iter().find(|(_, x)| *x == value) {
+                    Some((name, _)) => name.clone(),
+                    None => value.to_string(),
+                }
+            }
+            TypeKind::Struct(_) if depth >= 2 => "{...}".into(),
+            TypeKind::Struct(members) => {
+                let mut fields = vec![];
+                for member in members {
+                    let value = self.member(&member.name)?.display_with_depth(cpu, depth + 1)?;
+                    fields.push(format!("{} = {value}", member.name));
+                }
+                format!("{{ {} }}", fields.join(", "))
+            }
+            TypeKind::Array { count, .. } => {
+                let count = count.unwrap_or(0);
+                let mut elements = vec![];
+                for i in 0..count.min(MAX_DISPLAY_ELEMENTS) {
+                    elements.push(self.index(cpu, i)?.display_with_depth(cpu, depth + 1)?);
+                }
+                if count > MAX_DISPLAY_ELEMENTS {
+                    elements.push("...".into());
+                }
+                format!("[{}]", elements.join(", "))
+            }
+            TypeKind::Base { .. } | TypeKind::Unknown => {
+                let bytes = self.read_bytes(cpu)?;
+                bytes.iter().map(|x| format!("{x:02x}")).collect()
+            }
+        })
+    }
+}
+
+/// A step in a variable access path.
+enum Access<'a> {
+    Member(&'a str),
+    Deref,
+    Index(u64),
+}
+
+/// Parses a C-like access path, e.g. `*a.b->c[1]`, into the name of the root variable and the list
+/// of accesses to apply to it.
+fn parse_path(path: &str) -> Result<(&str, Vec<Access<'_>>), String> {
+    fn ident(s: &str) -> (&str, &str) {
+        let end = s.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).unwrap_or(s.len());
+        s.split_at(end)
+    }
+
+    let path = path.trim();
+    let derefs = path.len() - path.trim_start_matches('*').len();
+    let (name, mut rest) = ident(&path[derefs..]);
+    if name.is_empty() {
+        return Err(format!("invalid variable path: {path}"));
+    }
+
+    let mut accesses = vec![];
+    while !rest.is_empty() {
+        if let Some(tail) = rest.strip_prefix('.') {
+            let (member, tail) = ident(tail);
+            accesses.push(Access::Member(member));
+            rest = tail;
+        }
+        else if let Some(tail) = rest.strip_prefix("->") {
+            let (member, tail) = ident(tail);
+            accesses.extend([Access::Deref, Access::Member(member)]);
+            rest = tail;
+        }
+        else if let Some(tail) = rest.strip_prefix('[') {
+            let (index, tail) =
+                tail.split_once(']').ok_or_else(|| format!("invalid variable path: {path}"))?;
+            let index = crate::utils::parse_u64_with_prefix(index.trim())
+                .ok_or_else(|| format!("invalid array index: {index}"))?;
+            accesses.push(Access::Index(index));
+            rest = tail;
+        }
+        else {
+            return Err(format!("invalid variable path: {path}"));
+        }
+    }
+    accesses.extend((0..derefs).map(|_| Access::Deref));
+
+    Ok((name, accesses))
+}
+
+/// Resolves the variable accessed by `path` in the scope of `frame`.
+pub(crate) fn read_variable(
+    unit: UnitRef,
+    base: u64,
+    cfi: Option<&CallFrameInfo>,
+    cpu: &mut Cpu,
+    frame: &Frame,
+    path: &str,
+) -> Result<Variable, String> {
+    let (name, accesses) = parse_path(path)?;
+
+    let ctx = Evaluator::new(unit, base, cfi, frame);
+    let mut var = ctx
+        .find_variable(cpu, name)?
+        .ok_or_else(|| format!("no variable named `{name}` at {:#x}", frame.pc))?;
+
+    for access in accesses {
+        var = match access {
+            Access::Member(name) => var.member(name)?,
+            Access::Deref => var.deref(cpu)?,
+            Access::Index(index) => var.index(cpu, index)?,
+        };
+    }
+
+    Ok(var)
+}
+
+/// Finds the parameters and local variables in the scope of `frame`.
+pub(crate) fn scope_variables(
+    unit: UnitRef,
+    base: u64,
+    cfi: Option<&CallFrameInfo>,
+    cpu: &mut Cpu,
+    frame: &Frame,
+) -> Result<Vec<ScopedVariable>, String> {
+    Evaluator::new(unit, base, cfi, frame).scope_variables(cpu)
+}
+
+/// Finds the layout of the stack frame of the function containing `pc` (relative to the address
+/// the object was linked at). Returns `None` if `pc` is not inside of a function.
+///
+/// Only variables located with a single `DW_OP_fbreg` operation at every PC in the function are
+/// included, variables with location lists are skipped.
+pub(crate) fn frame_layout(
+    unit: UnitRef,
+    base: u64,
+    pc: u64,
+) -> Result<Option<FrameLayout>, String> {
+    let mut scopes = vec![];
+    let mut tree = unit.entries_tree(None).map_err(dwarf_err)?;
+    find_scopes(unit, tree.root().map_err(dwarf_err)?, pc, &mut scopes).map_err(dwarf_err)?;
+    let Some((subprogram, _)) =
+        scopes.iter().rev().find(|(_, tag)| *tag == gimli::DW_TAG_subprogram)
+    else {
+        return Ok(None);
+    };
+
+    let entry = unit.entry(*subprogram).map_err(dwarf_err)?;
+    let function = attr_string(unit, &entry, gimli::DW_AT_name).map_err(dwarf_err)?;
+    let mut ranges = unit.dwarf.die_ranges(unit.unit, &entry).map_err(dwarf_err)?;
+    let start = ranges.next().map_err(dwarf_err)?.map_or(pc, |range| range.begin);
+
+    let frame_base = match entry.attr_value(gimli::DW_AT_frame_base).map_err(dwarf_err)? {
+        Some(AttributeValue::Exprloc(expr)) => match single_op(unit, expr).map_err(dwarf_err)? {
+            Some(gimli::Operation::Register { register }) => {
+                FrameBase::Register { reg: register.0, offset: 0 }
+            }
+            Some(gimli::Operation::RegisterOffset { register, offset, .. }) => {
+                FrameBase::Register { reg: register.0, offset }
+            }
+            Some(gimli::Operation::CallFrameCFA) => FrameBase::Cfa,
+            _ => return Err("unsupported frame base".into()),
+        },
+        _ => return Err("function has no frame base".into()),
+    };
+
+    let mut objects = vec![];
+    let mut tree = unit.entries_tree(Some(*subprogram)).map_err(dwarf_err)?;
+    find_frame_objects(unit, tree.root().map_err(dwarf_err)?, &mut objects).map_err(dwarf_err)?;
+    objects.sort_by_key(|x| x.offset);
+
+    Ok(Some(FrameLayout { function, start: start.wrapping_add(base), frame_base, objects }))
+}
+
+/// Finds the variables within the scope of `node` that are stored at a fixed offset from the frame
+/// base. Nested functions are skipped since they have their own frame.
+fn find_frame_objects(
+    unit: UnitRef,
+    node: gimli::EntriesTreeNode<R>,
+    objects: &mut Vec<FrameObject>,
+) -> gimli::Result<()> {
+    let mut children = node.children();
+    while let Some(child) = children.next()? {
+        let entry = child.entry();
+        match entry.tag() {
+            gimli::DW_TAG_variable | gimli::DW_TAG_formal_parameter => {
+                let Some(AttributeValue::Exprloc(expr)) = entry.attr_value(gimli::DW_AT_location)?
+                else {
+                    continue;
+                };
+                let Some(gimli::Operation::FrameOffset { offset }) = single_op(unit, expr)?
+                else {
+                    continue;
+                };
+                let size = match attr_with_origin(unit, entry, gimli::DW_AT_type)? {
+                    Some(AttributeValue::UnitRef(ty)) => parse_type(unit, ty, 0)?.size,
+                    _ => 0,
+                };
+                if size == 0 {
+                    continue;
+                }
+                let name = attr_string(unit, entry, gimli::DW_AT_name)?.unwrap_or_default();
+                objects.push(FrameObject { name, offset, size });
+            }
+            gimli::DW_TAG_lexical_block | gimli::DW_TAG_inlined_subroutine => {
+                find_frame_objects(unit, child, objects)?;
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Returns the operation in `expr` if it consists of exactly one operation.
+fn single_op(
+    unit: UnitRef,
+    expr: gimli::Expression<R>,
+) -> gimli::Result<Option<gimli::Operation<R>>> {
+    let mut ops = expr.operations(unit.encoding());
+    let Some(op) = ops.next()?
+    else {
+        return Ok(None);
+    };
+    Ok(ops.next()?.is_none().then_some(op))
+}
+
+struct Evaluator<'a> {
+    unit: UnitRef<'a>,
+    /// The offset that the object containing `unit` was relocated by.
+    base: u64,
+    /// The program counter relative to the object's link address.
+    pc: u64,
+    cfi: Option<&'a CallFrameInfo>,
+    frame: &'a Frame,
+}
+
+impl<'a> Evaluator<'a> {
+    fn new(unit: UnitRef<'a>, base: u64, cfi: Option<&'a CallFrameInfo>, frame: &'a Frame) -> Self {
+        Self { unit, base, pc: frame.lookup_pc().wrapping_sub(base), cfi, frame }
+    }
+
+    /// Finds the chain of scopes that contain the PC (see [find_scopes]) and the concrete
+    /// subprogram that defines the frame base.
+    fn scopes(&self) -> Result<(Vec<Scope>, Option<UnitOffset>), String> {
+        let mut scopes = vec![];
+        let mut tree = self.unit.entries_tree(None).map_err(dwarf_err)?;
+        find_scopes(self.unit, tree.root().map_err(dwarf_err)?, self.pc, &mut scopes)
+            .map_err(dwarf_err)?;
+
+        let subprogram =
+            scopes.iter().rev().find(|(_, tag)| *tag == gimli::DW_TAG_subprogram).map(|x| x.0);
+        Ok((scopes, subprogram))
+    }
+
+    fn find_variable(&self, cpu: &mut Cpu, name: &str) -> Result<Option<Variable>, String> {
+        let unit = self.unit;
+        let (scopes, subprogram) = self.scopes()?;
+
+        // Search from the innermost scope outwards, stopping at the function boundary, then fall
+        // back to variables at the compilation unit level.
+        let mut found = None;
+        for (offset, tag) in scopes.iter().rev() {
+            found = find_child_named(unit, Some(*offset), name).map_err(dwarf_err)?;
+            let is_function =
+                matches!(*tag, gimli::DW_TAG_subprogram | gimli::DW_TAG_inlined_subroutine);
+            if found.is_some() || is_function {
+                break;
+            }
+        }
+        if found.is_none() {
+            found = find_child_named(unit, None, name).map_err(dwarf_err)?;
+        }
+        let Some(offset) = found
+        else {
+            return Ok(None);
+        };
+
+        let entry = unit.entry(offset).map_err(dwarf_err)?;
+        let ty = self.variable_type(&entry)?;
+        let location = self.variable_location(cpu, &entry, subprogram, &ty)?;
+        Ok(Some(Variable { name: name.to_owned(), ty, location }))
+    }
+
+    /// Finds every parameter and local variable visible at the PC, from the innermost scope
+    /// outwards (in declaration order within each scope). Variables that are shadowed by a
+    /// variable with the same name in an inner scope are skipped.
+    fn scope_variables(&self, cpu: &mut Cpu) -> Result<Vec<ScopedVariable>, String> {
+        let unit = self.unit;
+        let (scopes, subprogram) = self.scopes()?;
+
+        let mut seen = HashSet::new();
+        let mut variables = vec![];
+        for (offset, tag) in scopes.iter().rev() {
+            for (offset, name) in named_variables(unit, Some(*offset)).map_err(dwarf_err)? {
+                if !seen.insert(name.clone()) {
+                    continue;
+                }
+                let entry = unit.entry(offset).map_err(dwarf_err)?;
+                let kind = match entry.tag() {
+                    gimli::DW_TAG_formal_parameter => VariableKind::Parameter,
+                    _ => VariableKind::Local,
+                };
+                let ty = self.variable_type(&entry)?;
+                let location = self.variable_location(cpu, &entry, subprogram, &ty);
+                variables.push(ScopedVariable { name, kind, ty, location });
+            }
+            if matches!(*tag, gimli::DW_TAG_subprogram | gimli::DW_TAG_inlined_subroutine) {
+                break;
+            }
+        }
+        Ok(variables)
+    }
+
+    fn variable_type(&self, entry: &gimli::DebuggingInformationEntry<R>) -> Result<Type, String> {
+        match attr_with_origin(self.unit, entry, gimli::DW_AT_type).map_err(dwarf_err)? {
+            Some(AttributeValue::UnitRef(offset)) => parse_type(self.unit, offset, 0),
+            _ => Ok(Type { name: None, size: 0, kind: TypeKind::Unknown }),
+        }
+        .map_err(dwarf_err)
+    }
+
+    fn variable_location(
+        &self,
+        cpu: &mut Cpu,
+        entry: &gimli::DebuggingInformationEntry<R>,
+        subprogram: Option<UnitOffset>,
+        ty: &Type,
+    ) -> Result<VariableLocation, String> {
+        let big_endian = cpu.arch.sleigh.big_endian;
+
+        if let Some(value) = entry.attr_value(gimli::DW_AT_const_value).map_err(dwarf_err)? {
+            return match value {
+                AttributeValue::Block(data) => {
+                    Ok(VariableLocation::Value(data.to_slice().map_err(dwarf_err)?.to_vec()))
+                }
+                value => {
+                    let value = value
+                        .sdata_value()
+                        .map(|x| x as u64)
+                        .or_else(|| value.udata_value())
+                        .ok_or("unsupported constant value")?;
+                    Ok(VariableLocation::Value(u64_to_bytes(value, ty.size as usize, big_endian)))
+                }
+            };
+        }
+
+        let Some(location) = entry.attr_value(gimli::DW_AT_location).map_err(dwarf_err)?
+        else {
+            return Err("variable is optimized out".into());
+        };
+        let Some(expr) = self.find_location_expr(location).map_err(dwarf_err)?
+        else {
+            return Err(format!("variable is not available at {:#x}", self.frame.pc));
+        };
+
+        let pieces = self.evaluate(cpu, expr, subprogram)?;
+        match pieces.as_slice() {
+            [piece] => match &piece.location {
+                gimli::Location::Address { address } => Ok(VariableLocation::Address(*address)),
+                gimli::Location::Register { register } => {
+                    let value = self.read_register(cpu, *register)?;
+                    Ok(VariableLocation::Register { reg: register.0, value })
+                }
+                gimli::Location::Value { value } => {
+                    let value = value.to_u64(u64::MAX).map_err(dwarf_err)?;
+                    Ok(VariableLocation::Value(u64_to_bytes(value, ty.size as usize, big_endian)))
+                }
+                gimli::Location::Bytes { value } => {
+                    Ok(VariableLocation::Value(value.to_slice().map_err(dwarf_err)?.to_vec()))
+                }
+                gimli::Location::Empty => Err("variable is optimized out".into()),
+                other => Err(format!("unsupported variable location: {other:?}")),
+            },
+            [] => Err("variable is optimized out".into()),
+            _ => Err("variables split across multiple locations are not supported".into()),
+        }
+    }
+
+    /// Finds the location expression that applies to the current PC.
+    fn find_location_expr(
+        &self,
+        location: AttributeValue<R>,
+    ) -> gimli::Result<Option<gimli::Expression<R>>> {
+        if let AttributeValue::Exprloc(expr) = location {
+            return Ok(Some(expr));
+        }
+        let Some(mut locations) = self.unit.dwarf.attr_locations(self.unit.unit, location)?
+        else {
+            return Ok(None);
+        };
+        while let Some(entry) = locations.next()? {
+            if (entry.range.begin..entry.range.end).contains(&self.pc) {
+                return Ok(Some(entry.data));
+            }
+        }
+        Ok(None)
+    }
+
+    fn evaluate(
+        &self,
+        cpu: &mut Cpu,
+        expr: gimli::Expression<R>,
+        subprogram: Option<UnitOffset>,
+    ) -> Result<Vec<gimli::Piece<R>>, String> {
+        let big_endian = cpu.arch.sleigh.big_endian;
+
+        let mut eval = expr.evaluation(self.unit.encoding());
+        let mut result = eval.evaluate().map_err(dwarf_err)?;
+        loop {
+            result = match result {
+                gimli::EvaluationResult::Complete => return Ok(eval.result()),
+                gimli::EvaluationResult::RequiresMemory { address, size, .. } => {
+                    let mut buf = [0; 8];
+                    let buf = &mut buf[..(size as usize).min(8)];
+                    cpu.mem
+                        .read_bytes(address, buf, perm::READ)
+                        .map_err(|e| format!("failed to read memory at {address:#x}: {e}"))?;
+                    let value = gimli::Value::Generic(bytes_to_u64(buf, big_endian));
+                    eval.resume_with_memory(value)
+                }
+                gimli::EvaluationResult::RequiresRegister { register, .. } => {
+                    let value = self.read_register(cpu, register)?;
+                    eval.resume_with_register(gimli::Value::Generic(value))
+                }
+                gimli::EvaluationResult::RequiresFrameBase => {
+                    let frame_base = self.frame_base(cpu, subprogram)?;
+                    eval.resume_with_frame_base(frame_base)
+                }
+                gimli::EvaluationResult::RequiresCallFrameCfa => {
+                    let cfa = self.cfa(cpu)?;
+                    eval.resume_with_call_frame_cfa(cfa)
+                }
+                gimli::EvaluationResult::RequiresRelocatedAddress(addr) => {
+                    eval.resume_with_relocated_address(addr.wrapping_add(self.base))
+                }
+                gimli::EvaluationResult::RequiresIndexedAddress { index, relocate } => {
+                    let addr = self.unit.dwarf.address(self.unit.unit, index).map_err(dwarf_err)?;
+                    let base = if relocate { self.base } else { 0 };
+                    eval.resume_with_indexed_address(addr.wrapping_add(base))
+                }
+                other => return Err(format!("unsupported DWARF expression: {other:?}")),
+            }
+            .map_err(dwarf_err)?;
+        }
+    }
+
+    /// Computes the value of the `DW_AT_frame_base` attribute of `subprogram`.
+    fn frame_base(&self, cpu: &mut Cpu, subprogram: Option<UnitOffset>) -> Result<u64, String> {
+        let subprogram = subprogram.ok_or("frame base required outside of a function")?;
+        let entry = self.unit.entry(subprogram).map_err(dwarf_err)?;
+        let Some(AttributeValue::Exprloc(expr)) =
+            entry.attr_value(gimli::DW_AT_frame_base).map_err(dwarf_err)?
+        else {
+            return Err("function has no frame base".into());
+        };
+
+        let pieces = self.evaluate(cpu, expr, None)?;
+        match pieces.first().map(|piece| &piece.location) {
+            Some(gimli::Location::Register { register }) => self.read_register(cpu, *register),
+            Some(gimli::Location::Address { address }) => Ok(*address),
+            other => Err(format!("unsupported frame base: {other:?}")),
+        }
+    }
+
+    /// Computes the canonical frame address of the current frame using call frame information.
+    fn cfa(&self, cpu: &mut Cpu) -> Result<u64, String> {
+        let cfi = self.cfi.ok_or("no call frame information available")?;
+        let frame = self.frame;
+        cfi.cfa(self.pc, &mut |reg| frame.read_register(cpu, reg.0))
+            .ok_or_else(|| format!("failed to compute CFA at {:#x}", self.frame.pc))
+    }
+
+    fn read_register(&self, cpu: &mut Cpu, register: gimli::Register) -> Result<u64, String> {
+        self.frame
+            .read_register(cpu, register.0)
+            .ok_or_else(|| format!("unsupported DWARF register: {}", register.0))
+    }
+}
+
+/// Finds the chain of scopes (functions, inlined functions and lexical blocks) that contain `pc`,
+/// ordered from outermost to innermost.
+fn find_scopes(
+    unit: UnitRef,
+    node: gimli::EntriesTreeNode<R>,
+    pc: u64,
+    scopes: &mut Vec<Scope>,
+) -> gimli::Result<()> {
+    let mut children = node.children();
+    while let Some(child) = children.next()? {
+        let entry = child.entry();
+        match entry.tag() {
+            gimli::DW_TAG_subprogram
+            | gimli::DW_TAG_inlined_subroutine
+            | gimli::DW_TAG_lexical_block
+                if die_contains(unit, entry, pc)? =>
+            {
+                scopes.push((entry.offset(), entry.tag()));
+                return find_scopes(unit, child, pc, scopes);
+            }
+            gimli::DW_TAG_namespace => {
+                let len = scopes.len();
+                find_scopes(unit, child, pc, scopes)?;
+                if scopes.len() != len {
+                    return Ok(());
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+fn die_contains(
+    unit: UnitRef,
+    entry: &gimli::DebuggingInformationEntry<R>,
+    pc: u64,
+) -> gimli::Result<bool> {
+    let mut ranges = unit.dwarf.die_ranges(unit.unit, entry)?;
+    while let Some(range) = ranges.next()? {
+        if (range.begin..range.end).contains(&pc) {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Finds a variable or parameter defined directly within the scope at `offset` (or the root of the
+/// unit if `None`) named `name`.
+fn find_child_named(
+    unit: UnitRef,
+    offset: Option<UnitOffset>,
+    name: &str,
+) -> gimli::Result<Option<UnitOffset>> {
+    let variables = named_variables(unit, offset)?;
+    Ok(variables.into_iter().find(|(_, x)| x == name).map(|(offset, _)| offset))
+}
+
+/// Gets the offset and name of every variable or parameter defined directly within the scope at
+/// `offset` (or the root of the unit if `None`).
+fn named_variables(
+    unit: UnitRef,
+    offset: Option<UnitOffset>,
+) -> gimli::Result<Vec<(UnitOffset, String)>> {
+    let mut variables = vec![];
+    let mut tree = unit.entries_tree(offset)?;
+    let mut children = tree.root()?.children();
+    while let Some(child) = children.next()? {
+        let entry = child.entry();
+        if !matches!(entry.tag(), gimli::DW_TAG_variable | gimli::DW_TAG_formal_parameter) {
+            continue;
+        }
+        // Skip declarations, the definition will refer to them with `DW_AT_specification`.
+        if let Some(AttributeValue::Flag(true)) = entry.attr_value(gimli::DW_AT_declaration)? {
+            continue;
+        }
+        if let Some(name) = attr_string(unit, entry, gimli::DW_AT_name)? {
+            variables.push((entry.offset(), name));
+        }
+    }
+    Ok(variables)
+}
+
+/// Gets the value of `attr` from `entry`, or the entry it was derived from (for inlined functions
+/// and out-of-line definitions).
+fn attr_with_origin(
+    unit: UnitRef,
+    entry: &gimli::DebuggingInformationEntry<R>,
+    attr: gimli::DwAt,
+) -> gimli::Result<Option<AttributeValue<R>>> {
+    if let Some(value) = entry.attr_value(attr)? {
+        return Ok(Some(value));
+    }
+    for origin in [gimli::DW_AT_abstract_origin, gimli::DW_AT_specification] {
+        if let Some(AttributeValue::UnitRef(offset)) = entry.attr_value(origin)? {
+            return attr_with_origin(unit, &unit.entry(offset)?, attr);
+        }
+    }
+    Ok(None)
+}
+
+fn attr_string(
+    unit: UnitRef,
+    entry: &gimli::DebuggingInformationEntry<R>,
+    attr: gimli::DwAt,
+) -> gimli::Result<Option<String>> {
+    let Some(value) = attr_with_origin(unit, entry, attr)?
+    else {
+        return Ok(None);
+    };
+    let string = unit.dwarf.attr_string(unit.unit, value)?;
+    Ok(Some(string.to_string_lossy()?.into_owned()))
+}
+
+fn parse_type(unit: UnitRef, offset: UnitOffset, depth: usize) -> gimli::Result<Type> {
+    let entry = unit.entry(offset)?;
+    let name = attr_string(unit, &entry, gimli::DW_AT_name)?;
+    let size = entry.attr_value(gimli::DW_AT_byte_size)?.and_then(|x| x.udata_value());
+    let inner = match entry.attr_value(gimli::DW_AT_type)? {
+        Some(AttributeValue::UnitRef(offset)) => Some(offset),
+        _ => None,
+    };
+
+    let kind = match entry.tag() {
+        gimli::DW_TAG_typedef
+        | gimli::DW_TAG_const_type
+        | gimli::DW_TAG_volatile_type
+        | gimli::DW_TAG_restrict_type
+        | gimli::DW_TAG_atomic_type => {
+            let mut ty = match inner {
+                Some(inner) => parse_type(unit, inner, depth)?,
+                None => Type { name: None, size: 0, kind: TypeKind::Unknown },
+            };
+            if entry.tag() == gimli::DW_TAG_typedef {
+                ty.name = name;
+            }
+            return Ok(ty);
+        }
+        gimli::DW_TAG_base_type => {
+            let encoding = match entry.attr_value(gimli::DW_AT_encoding)? {
+                Some(AttributeValue::Encoding(encoding)) => encoding,
+                _ => gimli::DW_ATE_unsigned,
+            };
+            TypeKind::Base { encoding }
+        }
+        gimli::DW_TAG_pointer_type
+        | gimli::DW_TAG_reference_type
+        | gimli::DW_TAG_rvalue_reference_type => {
+            let target = match inner {
+                Some(inner) if depth < MAX_POINTER_DEPTH => {
+                    Some(Box::new(parse_type(unit, inner, depth + 1)?))
+                }
+                _ => None,
+            };
+            let ptr_size = unit.encoding().address_size as u64;
+            let kind = TypeKind::Pointer(target);
+            return Ok(Type { name, size: size.unwrap_or(ptr_size), kind });
+        }
+        gimli::DW_TAG_structure_type | gimli::DW_TAG_class_type | gimli::DW_TAG_union_type => {
+            let mut members = vec![];
+            let mut tree = unit.entries_tree(Some(offset))?;
+            let mut children = tree.root()?.children();
+            while let Some(child) = children.next()? {
+                let member = child.entry();
+                if member.tag() != gimli::DW_TAG_member {
+                    continue;
+                }
+                let ty = match member.attr_value(gimli::DW_AT_type)? {
+                    Some(AttributeValue::UnitRef(offset)) => parse_type(unit, offset, depth)?,
+                    _ => continue,
+                };
+                members.push(Member {
+                    name: attr_string(unit, member, gimli::DW_AT_name)?.unwrap_or_default(),
+                    offset: member_offset(unit, member)?,
+                    ty,
+                });
+            }
+            TypeKind::Struct(members)
+        }
+        gimli::DW_TAG_array_type => {
+            let element = match inner {
+                Some(inner) => parse_type(unit, inner, depth)?,
+                None => Type { name: None, size: 0, kind: TypeKind::Unknown },
+            };
+
+            // @todo: support multi-dimensional arrays.
+            let mut count = None;
+            let mut tree = unit.entries_tree(Some(offset))?;
+            let mut children = tree.root()?.children();
+            while let Some(child) = children.next()? {
+                let subrange = child.entry();
+                if subrange.tag() != gimli::DW_TAG_subrange_type {
+                    continue;
+                }
+                count = match subrange.attr_value(gimli::DW_AT_count)? {
+                    Some(value) => value.udata_value(),
+                    None => subrange
+                        .attr_value(gimli::DW_AT_upper_bound)?
+                        .and_then(|x| x.udata_value())
+                        .and_then(|x| x.checked_add(1)),
+                };
+                break;
+            }
+
+            let size = size.unwrap_or_else(|| count.unwrap_or(0).saturating_mul(element.size));
+            let kind = TypeKind::Array { element: Box::new(element), count };
+            return Ok(Type { name, size, kind });
+        }
+        gimli::DW_TAG_enumeration_type => {
+            let mut variants = vec![];
+            let mut tree = unit.entries_tree(Some(offset))?;
+            let mut children = tree.root()?.children();
+            while let Some(child) = children.next()? {
+                let variant = child.entry();
+                if variant.tag() != gimli::DW_TAG_enumerator {
+                    continue;
+                }
+                let name = attr_string(unit, variant, gimli::DW_AT_name)?.unwrap_or_default();
+                let value = variant.attr_value(gimli::DW_AT_const_value)?.and_then(|x| {
+                    x.sdata_value().or_else(|| x.udata_value().map(|x| x as i64))
+                });
+                variants.push((name, value.unwrap_or(0)));
+            }
+            TypeKind::Enum(variants)
+        }
+        _ => TypeKind::Unknown,
+    };
+
+    Ok(Type { name, size: size.unwrap_or(0), kind })
+}
+
+/// Gets the offset of a struct member from the `DW_AT_data_member_location` attribute.
+fn member_offset(
+    unit: UnitRef,
+    entry: &gimli::DebuggingInformationEntry<R>,
+) -> gimli::Result<u64> {
+    match entry.attr_value(gimli::DW_AT_data_member_location)? {
+        Some(AttributeValue::Exprloc(expr)) => {
+            let mut eval = expr.evaluation(unit.encoding());
+            eval.set_initial_value(0);
+            if let gimli::EvaluationResult::Complete = eval.evaluate()? {
+                if let Some(gimli::Location::Address { address }) =
+                    eval.result().into_iter().next().map(|piece| piece.location)
+                {
+                    return Ok(address);
+                }
+            }
+            Ok(0)
+        }
+        Some(value) => Ok(value.udata_value().unwrap_or(0)),
+        // Union members have no location.
+        None => Ok(0),
+    }
+}
+
+fn dwarf_err(err: gimli::Error) -> String {
+    format!("error reading DWARF: {err}")
+}
+
+fn bytes_to_u64(bytes: &[u8], big_endian: bool) -> u64 {
+    let mut buf = [0; 8];
+    let len = bytes.len().min(8);
+    if big_endian {
+        buf[8 - len..].copy_from_slice(&bytes[..len]);
+        u64::from_be_bytes(buf)
+    }
+    else {
+        buf[..len].copy_from_slice(&bytes[..len]);
+        u64::from_le_bytes(buf)
+    }
+}
+
+fn u64_to_bytes(value: u64, size: usize, big_endian: bool) -> Vec<u8> {
+    let size = size.min(8);
+    if big_endian {
+        value.to_be_bytes()[8 - size..].to_vec()
+    }
+    else {
+        value.to_le_bytes()[..size].to_vec()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use gimli::write::{self, Address, AttributeValue, DwarfUnit, EndianVec, Expression};
+
+    use super::{
+        parse_path, read_variable, scope_variables, Access, TypeKind, VariableKind,
+        VariableLocation, R,
+    };
+    use crate::{
+        debug_info::Frame,
+        mem::{perm, Mapping},
+        Arch, Cpu,
+    };
+
+    #[test]
+    fn variable_paths() {
+        let (name, accesses) = parse_path("*a.b->c[0x2]").unwrap();
+        assert_eq!(name, "a");
+        let accesses: Vec<_> = accesses
+            .iter()
+            .map(|x| match x {
+                Access::Member(name) => format!(".{name}"),
+                Access::Deref => "*".into(),
+                Access::Index(index) => format!("[{index}]"),
+            })
+            .collect();
+        assert_eq!(accesses, [".b", "*", ".c", "[2]", "*"]);
+
+        assert!(parse_path("").is_err());
+        assert!(parse_path("a[1").is_err());
+        assert!(parse_path("a+b").is_err());
+    }
+
+    /// Writes the DWARF sections for `dwarf` and loads them for reading.
+    fn load(dwarf: &mut DwarfUnit) -> gimli::Dwarf<R> {
+        let mut sections = write::Sections::new(EndianVec::new(gimli::LittleEndian));
+        dwarf.write(&mut sections).unwrap();
+        let mut data = HashMap::new();
+        sections
+            .for_each(|id, section| -> write::Result<()> {
+                data.insert(id, section.slice().to_vec());
+                Ok(())
+            })
+            .unwrap();
+
+        gimli::Dwarf::load(|id| -> gimli::Result<R> {
+            let section = data.get(&id).cloned().unwrap_or_default();
+            Ok(gimli::EndianRcSlice::new(section.into(), gimli::RunTimeEndian::Little))
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn scoped_variables() {
+        let encoding =
+            gimli::Encoding { format: gimli::Format::Dwarf32, version: 4, address_size: 8 };
+        let mut dwarf = DwarfUnit::new(encoding);
+        let root = dwarf.unit.root();
+        let name = |x: &str| AttributeValue::String(x.as_bytes().to_vec());
+        let set_range = |dwarf: &mut DwarfUnit, id, start, len| {
+            let entry = dwarf.unit.get_mut(id);
+            entry.set(gimli::DW_AT_low_pc, AttributeValue::Address(Address::Constant(start)));
+            entry.set(gimli::DW_AT_high_pc, AttributeValue::Udata(len));
+        };
+        set_range(&mut dwarf, root, 0x1000, 0x100);
+
+        let int = dwarf.unit.add(root, gimli::DW_TAG_base_type);
+        let entry = dwarf.unit.get_mut(int);
+        entry.set(gimli::DW_AT_name, name("int"));
+        entry.set(gimli::DW_AT_byte_size, AttributeValue::Udata(4));
+        entry.set(gimli::DW_AT_encoding, AttributeValue::Encoding(gimli::DW_ATE_signed));
+
+        let add_variable = |dwarf: &mut DwarfUnit, parent, tag, var: &str, value| {
+            let id = dwarf.unit.add(parent, tag);
+            let entry = dwarf.unit.get_mut(id);
+            entry.set(gimli::DW_AT_name, name(var));
+            entry.set(gimli::DW_AT_type, AttributeValue::UnitRef(int));
+            if let Some((attr, value)) = value {
+                entry.set(attr, value);
+            }
+        };
+
+        let func = dwarf.unit.add(root, gimli::DW_TAG_subprogram);
+        dwarf.unit.get_mut(func).set(gimli::DW_AT_name, name("func"));
+        set_range(&mut dwarf, func, 0x1000, 0x40);
+
+        let mut addr = Expression::new();
+        addr.op_addr(Address::Constant(0x5000));
+        let location = Some((gimli::DW_AT_location, AttributeValue::Exprloc(addr)));
+        add_variable(&mut dwarf, func, gimli::DW_TAG_formal_parameter, "x", location);
+        let value = Some((gimli::DW_AT_const_value, AttributeValue::Sdata(7)));
+        add_variable(&mut dwarf, func, gimli::DW_TAG_variable, "y", value);
+        add_variable(&mut dwarf, func, gimli::DW_TAG_variable, "z", None);
+
+        let block = dwarf.unit.add(func, gimli::DW_TAG_lexical_block);
+        set_range(&mut dwarf, block, 0x1010, 0x10);
+        let value = Some((gimli::DW_AT_const_value, AttributeValue::Sdata(9)));
+        add_variable(&mut dwarf, block, gimli::DW_TAG_variable, "y", value);
+
+        let dwarf = load(&mut dwarf);
+        let header = dwarf.units().next().unwrap().unwrap();
+        let unit = dwarf.unit(header).unwrap();
+        let unit = gimli::UnitRef::new(&dwarf, &unit);
+
+        let mut cpu = Cpu::new_boxed(Arch::none());
+        let mut variables_at = |pc: u64| {
+            cpu.write_pc(pc);
+            let frame = Frame::current(&mut cpu);
+            scope_variables(unit, 0, None, &mut cpu, &frame).unwrap()
+        };
+
+        let vars = variables_at(0x1000);
+        let names: Vec<_> = vars.iter().map(|x| x.name.as_str()).collect();
+        assert_eq!(names, ["x", "y", "z"]);
+        assert_eq!(vars[0].kind, VariableKind::Parameter);
+        assert!(matches!(vars[0].location, Ok(VariableLocation::Address(0x5000))));
+        assert_eq!(vars[1].kind, VariableKind::Local);
+        assert!(matches!(&vars[1].location, Ok(VariableLocation::Value(x)) if x == &[7, 0, 0, 0]));
+        assert_eq!(vars[1].ty.size, 4);
+        assert!(vars[2].location.is_err());
+        assert!(vars[2].variable().is_err());
+
+        // The `y` in the lexical block shadows the outer `y`, and inner scopes are listed first.
+        let vars = variables_at(0x1014);
+        let names: Vec<_> = vars.iter().map(|x| x.name.as_str()).collect();
+        assert_eq!(names, ["y", "x", "z"]);
+        assert!(matches!(&vars[0].location, Ok(VariableLocation::Value(x)) if x == &[9, 0, 0, 0]));
+
+        assert!(variables_at(0x2000).is_empty());
+    }
+
+    #[test]
+    fn struct_members_and_array_elements() {
+        let encoding =
+            gimli::Encoding { format: gimli::Format::Dwarf32, version: 4, address_size: 8 };
+        let mut dwarf = DwarfUnit::new(encoding);
+        let root = dwarf.unit.root();
+        let name = |x: &str| AttributeValue::String(x.as_bytes().to_vec());
+        let entry = dwarf.unit.get_mut(root);
+        entry.set(gimli::DW_AT_low_pc, AttributeValue::Address(Address::Constant(0x1000)));
+        entry.set(gimli::DW_AT_high_pc, AttributeValue::Udata(0x100));
+
+        let int = dwarf.unit.add(root, gimli::DW_TAG_base_type);
+        let entry = dwarf.unit.get_mut(int);
+        entry.set(gimli::DW_AT_name, name("int"));
+        entry.set(gimli::DW_AT_byte_size, AttributeValue::Udata(4));
+        entry.set(gimli::DW_AT_encoding, AttributeValue::Encoding(gimli::DW_ATE_signed));
+
+        // struct point { int x; int y; int z; }, with the offset of `z` given as an expression.
+        let point = dwarf.unit.add(root, gimli::DW_TAG_structure_type);
+        let entry = dwarf.unit.get_mut(point);
+        entry.set(gimli::DW_AT_name, name("point"));
+        entry.set(gimli::DW_AT_byte_size, AttributeValue::Udata(12));
+        let mut z_offset = Expression::new();
+        z_offset.op_plus_uconst(8);
+        let offsets = [
+            ("x", AttributeValue::Udata(0)),
+            ("y", AttributeValue::Udata(4)),
+            ("z", AttributeValue::Exprloc(z_offset)),
+        ];
+        for (member, offset) in offsets {
+            let id = dwarf.unit.add(point, gimli::DW_TAG_member);
+            let entry = dwarf.unit.get_mut(id);
+            entry.set(gimli::DW_AT_name, name(member));
+            entry.set(gimli::DW_AT_type, AttributeValue::UnitRef(int));
+            entry.set(gimli::DW_AT_data_member_location, offset);
+        }
+
+        // int[3] and int[]
+        let add_array = |dwarf: &mut DwarfUnit, count: Option<u64>| {
+            let array = dwarf.unit.add(root, gimli::DW_TAG_array_type);
+            dwarf.unit.get_mut(array).set(gimli::DW_AT_type, AttributeValue::UnitRef(int));
+            let subrange = dwarf.unit.add(array, gimli::DW_TAG_subrange_type);
+            if let Some(count) = count {
+                dwarf.unit.get_mut(subrange).set(gimli::DW_AT_count, AttributeValue::Udata(count));
+            }
+            array
+        };
+        let array = add_array(&mut dwarf, Some(3));
+        let unbounded = add_array(&mut dwarf, None);
+
+        let func = dwarf.unit.add(root, gimli::DW_TAG_subprogram);
+        let entry = dwarf.unit.get_mut(func);
+        entry.set(gimli::DW_AT_name, name("func"));
+        entry.set(gimli::DW_AT_low_pc, AttributeValue::Address(Address::Constant(0x1000)));
+        entry.set(gimli::DW_AT_high_pc, AttributeValue::Udata(0x40));
+        let variables = [("p", point, 0x5000), ("arr", array, 0x5010), ("tail", unbounded, 0x5020)];
+        for (var, ty, addr) in variables {
+            let id = dwarf.unit.add(func, gimli::DW_TAG_variable);
+            let entry = dwarf.unit.get_mut(id);
+            entry.set(gimli::DW_AT_name, name(var));
+            entry.set(gimli::DW_AT_type, AttributeValue::UnitRef(ty));
+            let mut location = Expression::new();
+            location.op_addr(Address::Constant(addr));
+            entry.set(gimli::DW_AT_location, AttributeValue::Exprloc(location));
+        }
+
+        let dwarf = load(&mut dwarf);
+        let header = dwarf.units().next().unwrap().unwrap();
+        let unit = dwarf.unit(header).unwrap();
+        let unit = gimli::UnitRef::new(&dwarf, &unit);
+
+        let mut cpu = Cpu::new_boxed(Arch::none());
+        let mapping = Mapping { perm: perm::READ | perm::WRITE, value: 0 };
+        cpu.mem.map_memory_len(0x5000, 0x1000, mapping);
+        for (i, value) in [1_u32, 2, 3].into_iter().enumerate() {
+            cpu.mem.write_u32(0x5000 + 4 * i as u64, value, perm::NONE).unwrap();
+            cpu.mem.write_u32(0x5010 + 4 * i as u64, value * 10, perm::NONE).unwrap();
+        }
+        cpu.write_pc(0x1010);
+        let frame = Frame::current(&mut cpu);
+        let mut read = |path: &str| read_variable(unit, 0, None, &mut cpu, &frame, path);
+
+        let p = read("p").unwrap();
+        assert_eq!(p.ty.size, 12);
+        let TypeKind::Struct(members) = &p.ty.kind
+        else {
+            panic!("unexpected type: {:?}", p.ty);
+        };
+        let offsets: Vec<_> = members.iter().map(|x| (x.name.as_str(), x.offset)).collect();
+        assert_eq!(offsets, [("x", 0), ("y", 4), ("z", 8)]);
+        assert_eq!(read("p.z").unwrap().location, VariableLocation::Address(0x5008));
+        assert!(read("p.w").is_err());
+
+        let arr = read("arr").unwrap();
+        assert_eq!(arr.ty.size, 12);
+        assert_eq!(read("arr[2]").unwrap().location, VariableLocation::Address(0x5018));
+        assert!(read("arr[3]").is_err());
+
+        // Indices computed from guest data must not overflow.
+        assert!(read(&format!("tail[{}]", u64::MAX)).is_err());
+
+        assert_eq!(p.member("y").unwrap().read_u64(&mut cpu).unwrap(), 2);
+        assert_eq!(p.display(&mut cpu).unwrap(), "{ x = 1, y = 2, z = 3 }");
+        assert_eq!(arr.display(&mut cpu).unwrap(), "[10, 20, 30]");
+    }
+}
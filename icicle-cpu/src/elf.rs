@@ -1,10 +1,16 @@
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+
 use crate::{
-    debug_info::DebugInfo,
-    mem::{perm, AllocLayout, Mapping},
+    debug_info::{DebugInfo, LoadedModule},
+    mem::{perm, AllocLayout, Mapping, MemResult},
     utils, Cpu,
 };
 
-use object::{elf, read::elf::ProgramHeader, Endianness, FileKind};
+use object::{
+    elf,
+    read::elf::{Dyn, FileHeader, ProgramHeader, Rel, Rela, SectionHeader, Sym, SymbolTable},
+    Endian, Endianness, FileKind, SymbolIndex,
+};
 use tracing::{info, warn};
 
 #[derive(Debug, Clone, Default)]
@@ -26,6 +32,9 @@ pub struct ElfMetadata {
 
     /// The number of entries in the program header.
     pub phdr_num: u64,
+
+    /// The initialization image for thread-local storage (from the `PT_TLS` header).
+    pub tls: Option<TlsImage>,
 }
 
 #[derive(Clone)]
@@ -33,6 +42,128 @@ pub struct LoadedElf {
     pub binary: ElfMetadata,
     pub interpreter: Option<ElfMetadata>,
     pub debug_info: DebugInfo,
+
+    /// The layout of the static TLS area, if the thread pointer should be initialized by the
+    /// environment. This is `None` if the binary is started by an interpreter (which sets up TLS
+    /// itself).
+    pub tls: Option<TlsLayout>,
+}
+
+/// The initialization image for the thread-local storage of a single module.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TlsImage {
+    /// The address of the initialization image (after relocation).
+    pub addr: u64,
+
+    /// The number of bytes copied from the initialization image.
+    pub file_size: u64,
+
+    /// The total size of the TLS block. Bytes after `file_size` are zero initialized.
+    pub mem_size: u64,
+
+    /// The required alignment of the TLS block.
+    pub align: u64,
+}
+
+/// A module's TLS block within the static TLS area.
+#[derive(Debug, Clone, Copy)]
+pub struct TlsModule {
+    pub image: TlsImage,
+
+    /// The offset of the TLS block relative to the thread pointer.
+    pub tp_offset: i64,
+}
+
+/// The size reserved for the thread control block on targets where the thread pointer points to
+/// the TCB and the TLS blocks are placed below it (variant II). This is large enough for the
+/// fields that libc accesses at fixed offsets from the thread pointer (e.g. the stack guard).
+const TCB_SIZE: u64 = 0x400;
+
+/// The layout of the static TLS area that is allocated for each thread.
+#[derive(Debug, Clone)]
+pub struct TlsLayout {
+    /// The TLS blocks of every module with thread-local storage, indexed by `module ID - 1`.
+    pub modules: Vec<TlsModule>,
+
+    /// The total size of the TLS area.
+    pub size: u64,
+
+    /// The required alignment of the TLS area.
+    pub align: u64,
+
+    /// The offset of the thread pointer from the start of the TLS area.
+    pub tp_offset: u64,
+
+    /// The size of a pointer on the target, or `None` if the TCB does not start with a pointer to
+    /// itself.
+    self_ptr_size: Option<u64>,
+}
+
+impl TlsLayout {
+    /// Computes the layout of the static TLS area for `images` (in module ID order) using the TLS
+    /// ABI of `machine`. Returns `None` if the TLS ABI of the target is not supported.
+    pub fn new(machine: u16, ptr_size: u64, images: &[TlsImage]) -> Option<Self> {
+        let align = images.iter().map(|x| x.align).fold(ptr_size, u64::max);
+        let mut modules = Vec::with_capacity(images.len());
+        match machine {
+            // Variant II: the thread pointer points to the TCB, and TLS blocks are placed below it.
+            elf::EM_X86_64 | elf::EM_386 => {
+                let mut offset = 0;
+                for image in images {
+                    offset = utils::align_up(offset + image.mem_size, image.align.max(1));
+                    modules.push(TlsModule { image: *image, tp_offset: -(offset as i64) });
+                }
+                let tp_offset = utils::align_up(offset, align);
+                Some(Self {
+                    modules,
+                    size: tp_offset + TCB_SIZE,
+                    align,
+                    tp_offset,
+                    self_ptr_size: Some(ptr_size),
+                })
+            }
+
+            // Variant I: TLS blocks are placed after a TCB of two pointers at the thread pointer.
+            // RISC-V uses the same layout but without a TCB.
+            elf::EM_AARCH64 | elf::EM_ARM | elf::EM_RISCV => {
+                let mut offset = if machine == elf::EM_RISCV { 0 } else { 2 * ptr_size };
+                for image in images {
+                    let start = utils::align_up(offset, image.align.max(1));
+                    modules.push(TlsModule { image: *image, tp_offset: start as i64 });
+                    offset = start + image.mem_size;
+                }
+                let size = offset.max(1);
+                Some(Self { modules, size, align, tp_offset: 0, self_ptr_size: None })
+            }
+
+            _ => None,
+        }
+    }
+
+    /// Initializes a copy of the TLS area at `base`, returning the value of the thread pointer.
+    /// `base` must be aligned to [TlsLayout::align] with at least [TlsLayout::size] bytes mapped.
+    pub fn init(&self, cpu: &mut Cpu, base: u64) -> MemResult<u64> {
+        cpu.mem.fill_mem(base, self.size, 0)?;
+        let tp = base + self.tp_offset;
+
+        let mut buf = vec![];
+        for module in &self.modules {
+            buf.resize(module.image.file_size as usize, 0);
+            cpu.mem.read_bytes(module.image.addr, &mut buf, perm::NONE)?;
+            cpu.mem.write_bytes(tp.wrapping_add_signed(module.tp_offset), &buf, perm::NONE)?;
+        }
+
+        if let Some(size) = self.self_ptr_size {
+            // The TCB starts with a pointer to itself (`tcbhead_t::tcb` in glibc), with another
+            // copy after the DTV pointer (`tcbhead_t::self`). Variant II is only used on
+            // little-endian targets.
+            let bytes = &tp.to_le_bytes()[..size as usize];
+            cpu.mem.write_bytes(tp, bytes, perm::NONE)?;
+            cpu.mem.write_bytes(tp + 2 * size, bytes, perm::NONE)?;
+        }
+
+        Ok(tp)
+    }
 }
 
 pub trait ElfLoader {
@@ -47,6 +178,27 @@ pub trait ElfLoader {
     // binaries expect the physical address, but ARM ELF binaries expect the virtual address.
     const LOAD_AT_PHYSICAL_ADDRESS: bool = false;
 
+    /// Directories that are searched for the shared libraries listed in the `DT_NEEDED` entries of
+    /// a dynamically linked binary.
+    ///
+    /// If empty (the default), dependencies are left for the binary's interpreter to load.
+    /// Otherwise the loader maps every dependency itself and resolves the relocations required to
+    /// start executing at the entry point of the binary, without running the interpreter.
+    ///
+    /// Dependencies that need code to run before the entry point (constructors and indirect
+    /// functions) can only be linked by the interpreter, so binaries using them are started using
+    /// the interpreter instead.
+    fn library_paths(&self) -> &[Vec<u8>] {
+        &[]
+    }
+
+    /// Symbols provided by the environment, used to resolve references that are not defined by any
+    /// of the shared libraries mapped by the loader (e.g. functions normally implemented by the
+    /// dynamic linker, such as `__tls_get_addr`).
+    fn builtin_symbols(&mut self, _cpu: &mut Cpu) -> Vec<(Vec<u8>, u64)> {
+        vec![]
+    }
+
     fn read_file(&mut self, path: &[u8]) -> Result<Vec<u8>, String> {
         let path = std::str::from_utf8(path)
             .map_err(|e| format!("@fixme: only utf-8 paths are supported: {e}"))?;
@@ -54,8 +206,6 @@ pub trait ElfLoader {
     }
 
     fn load_elf(&mut self, cpu: &mut Cpu, path: &[u8]) -> Result<LoadedElf, String> {
-        use object::read::elf::FileHeader;
-
         tracing::info!("Loading ELF file from: {}", path.escape_ascii());
 
         let file = self.read_file(path)?;
@@ -80,15 +230,80 @@ pub trait ElfLoader {
     }
 }
 
+fn parse_error(e: object::Error) -> String {
+    format!("error parsing elf: {}", e)
+}
+
 fn load_elf<H, L>(loader: &mut L, cpu: &mut Cpu, data: &[u8], elf: &H) -> Result<LoadedElf, String>
 where
-    H: object::read::elf::FileHeader,
+    H: FileHeader,
     L: ElfLoader + ?Sized,
 {
-    fn parse_error(e: object::Error) -> String {
-        format!("error parsing elf: {}", e)
+    let (binary, interpreter_path) = map_image::<H, L>(cpu, data, elf)?;
+
+    if !loader.library_paths().is_empty() {
+        let needed = needed_libraries(elf, data)?;
+        if !needed.is_empty() {
+            let libraries = find_libraries(loader, elf, needed)?;
+            match requires_dynamic_linker(elf, data, &libraries)? {
+                None => {
+                    let mut debug_info = DebugInfo::default();
+                    let main = (data, &binary);
+                    let tls = link_libraries(loader, cpu, main, elf, libraries, &mut debug_info)?;
+                    return Ok(LoadedElf { binary, interpreter: None, debug_info, tls });
+                }
+                Some(reason) if interpreter_path.is_some() => {
+                    warn!("{reason}, starting the binary using the interpreter instead");
+                }
+                Some(reason) => return Err(reason),
+            }
+        }
     }
 
+    // Statically linked binaries expect the thread pointer to be initialized by the environment.
+    let tls = match (interpreter_path, binary.tls) {
+        (None, Some(image)) => static_tls_layout(elf, &[image])?,
+        _ => None,
+    };
+
+    let interpreter = interpreter_path.map(|path| loader.load_elf(cpu, path)).transpose()?;
+    let (interpreter, mut debug_info) = match interpreter {
+        Some(entry) => (Some(entry.binary), entry.debug_info),
+        None => (None, DebugInfo::default()),
+    };
+
+    if let Some(path) = interpreter_path {
+        debug_info.dynamic_linker = path.to_vec();
+    }
+
+    Ok(LoadedElf { binary, interpreter, debug_info, tls })
+}
+
+/// Computes the static TLS layout for `images` based on the target of `elf`.
+fn static_tls_layout<H: FileHeader>(
+    elf: &H,
+    images: &[TlsImage],
+) -> Result<Option<TlsLayout>, String> {
+    let endian = elf.endian().map_err(parse_error)?;
+    let ptr_size = if elf.is_type_64() { 8 } else { 4 };
+    let layout = TlsLayout::new(elf.e_machine(endian), ptr_size, images);
+    if layout.is_none() {
+        warn!("thread-local storage is not supported for e_machine={}", elf.e_machine(endian));
+    }
+    Ok(layout)
+}
+
+/// Maps the loadable segments of an ELF file into memory, returning the metadata of the loaded
+/// image and the path to the interpreter requested by the file (if any).
+fn map_image<'a, H, L>(
+    cpu: &mut Cpu,
+    data: &'a [u8],
+    elf: &H,
+) -> Result<(ElfMetadata, Option<&'a [u8]>), String>
+where
+    H: FileHeader,
+    L: ElfLoader + ?Sized,
+{
     let endian = elf.endian().map_err(parse_error)?;
     let program_headers = elf.program_headers(endian, data).map_err(parse_error)?;
 
@@ -114,6 +329,7 @@ where
 
     let mut phdr_ptr = None;
     let mut interpreter_path = None;
+    let mut tls = None;
     for header in program_headers {
         // Convert object permissions to our internal permissions
         let permission = get_permission(endian, header);
@@ -222,8 +438,17 @@ where
                 }
             }
 
+            elf::PT_TLS => {
+                tls = Some(TlsImage {
+                    addr: header.p_vaddr(endian).into() + relocation_offset,
+                    file_size: header.p_filesz(endian).into(),
+                    mem_size: header.p_memsz(endian).into(),
+                    align: header.p_align(endian).into(),
+                });
+            }
+
             // These headers are either handled elsewhere, or not used
-            elf::PT_TLS | elf::PT_DYNAMIC | elf::PT_NOTE | elf::PT_NULL => {}
+            elf::PT_DYNAMIC | elf::PT_NOTE | elf::PT_NULL => {}
 
             // These headers we may (or may not) care about but haven't been implemented yet so
             // print a warning message
@@ -241,19 +466,663 @@ where
         phdr_ptr: phdr_ptr
             .map_or(base_addr + elf.e_phoff(endian).into(), |addr| addr + relocation_offset),
         phdr_num: elf.e_phnum(endian) as u64,
+        tls,
     };
 
-    let interpreter = interpreter_path.map(|path| loader.load_elf(cpu, path)).transpose()?;
-    let (interpreter, mut debug_info) = match interpreter {
-        Some(entry) => (Some(entry.binary), entry.debug_info),
-        None => (None, DebugInfo::default()),
+    Ok((binary, interpreter_path))
+}
+
+/// Returns whether an ELF file has constructors (`DT_INIT` or `DT_INIT_ARRAY` entries) that must
+/// be run before the entry point of the program.
+fn has_constructors<H: FileHeader>(elf: &H, data: &[u8]) -> Result<bool, String> {
+    let endian = elf.endian().map_err(parse_error)?;
+    let sections = elf.sections(endian, data).map_err(parse_error)?;
+
+    for section in sections.iter() {
+        let Some((entries, _)) = section.dynamic(endian, data).map_err(parse_error)?
+        else {
+            continue;
+        };
+        let has_init = entries.iter().any(|entry| match entry.tag32(endian) {
+            Some(elf::DT_INIT) => true,
+            Some(elf::DT_INIT_ARRAYSZ) => entry.val32(endian) != Some(0),
+            _ => false,
+        });
+        if has_init {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+/// Returns the names of the shared libraries listed in the `DT_NEEDED` entries of an ELF file.
+fn needed_libraries<H: FileHeader>(elf: &H, data: &[u8]) -> Result<Vec<Vec<u8>>, String> {
+    let endian = elf.endian().map_err(parse_error)?;
+    let sections = elf.sections(endian, data).map_err(parse_error)?;
+
+    let mut needed = vec![];
+    for section in sections.iter() {
+        let Some((entries, link)) = section.dynamic(endian, data).map_err(parse_error)?
+        else {
+            continue;
+        };
+        let strings = sections.strings(endian, data, link).map_err(parse_error)?;
+        for entry in entries.iter().filter(|entry| entry.tag32(endian) == Some(elf::DT_NEEDED)) {
+            let name = entry
+                .val32(endian)
+                .and_then(|offset| strings.get(offset).ok())
+                .ok_or_else(|| "invalid DT_NEEDED entry".to_string())?;
+            needed.push(name.to_vec());
+        }
+    }
+
+    Ok(needed)
+}
+
+/// A shared library found in the library paths, stored as the path it was found at and the content
+/// of the file.
+type LibraryFile = (Vec<u8>, Vec<u8>);
+
+/// Finds the shared libraries (and their dependencies) required by the main binary, in the order
+/// they should be loaded.
+fn find_libraries<H, L>(
+    loader: &mut L,
+    elf: &H,
+    needed: Vec<Vec<u8>>,
+) -> Result<Vec<LibraryFile>, String>
+where
+    H: FileHeader,
+    L: ElfLoader + ?Sized,
+{
+    let endian = elf.endian().map_err(parse_error)?;
+    let search_paths = loader.library_paths().to_vec();
+
+    let mut libraries = vec![];
+    let mut seen = HashSet::new();
+    let mut queue = VecDeque::from(needed);
+    while let Some(name) = queue.pop_front() {
+        if !seen.insert(name.clone()) {
+            continue;
+        }
+
+        let (path, data) = find_library(loader, &search_paths, &name)?;
+        let lib = H::parse(&data[..]).map_err(parse_error)?;
+        if lib.e_machine(endian) != elf.e_machine(endian) {
+            return Err(format!("{} was built for a different architecture", path.escape_ascii()));
+        }
+        queue.extend(needed_libraries(lib, &data)?);
+        libraries.push((path, data));
+    }
+
+    Ok(libraries)
+}
+
+/// Checks whether the main binary and `libraries` can be linked by the loader, returning the reason
+/// if they depend on code that is normally run by the dynamic linker before the entry point.
+fn requires_dynamic_linker<H: FileHeader>(
+    elf: &H,
+    data: &[u8],
+    libraries: &[LibraryFile],
+) -> Result<Option<String>, String> {
+    let endian = elf.endian().map_err(parse_error)?;
+    let machine = elf.e_machine(endian);
+    if machine == elf::EM_MIPS {
+        // MIPS resolves most symbols using the GOT described by `DT_MIPS_*` entries instead of
+        // relocations, which is not implemented.
+        return Ok(Some("linking shared libraries is not supported for MIPS binaries".into()));
+    }
+
+    let objects: Vec<(&[u8], &[u8])> = std::iter::once((&b"main binary"[..], data))
+        .chain(libraries.iter().map(|(path, data)| (&path[..], &data[..])))
+        .collect();
+
+    // The constructors of the main binary are run by its own startup code.
+    for &(path, data) in &objects[1..] {
+        if has_constructors(H::parse(data).map_err(parse_error)?, data)? {
+            return Ok(Some(format!(
+                "{}: shared libraries with constructors (DT_INIT/DT_INIT_ARRAY) are not supported",
+                path.escape_ascii()
+            )));
+        }
+    }
+
+    let mut scope = Vec::with_capacity(objects.len());
+    for &(_, data) in &objects {
+        scope.push(exported_symbols(H::parse(data).map_err(parse_error)?, data, 0)?);
+    }
+
+    // Indirect functions are resolved by calling a resolver function in the guest.
+    for &(path, data) in &objects {
+        let object = H::parse(data).map_err(parse_error)?;
+        let sections = object.sections(endian, data).map_err(parse_error)?;
+        let symbols = sections.symbols(endian, data, elf::SHT_DYNSYM).map_err(parse_error)?;
+        for (r_offset, r_type, r_sym, _) in dynamic_relocations(object, data)? {
+            let kind = reloc_kind(machine, r_type);
+            if kind == Some(RelocKind::IRelative) {
+                return Ok(Some(format!(
+                    "{}: IRELATIVE relocation at {r_offset:#0x} is not supported",
+                    path.escape_ascii()
+                )));
+            }
+            if kind.is_none() || r_sym == 0 {
+                continue;
+            }
+
+            let sym = symbols.symbol(SymbolIndex(r_sym as usize)).map_err(parse_error)?;
+            let name = sym.name(endian, symbols.strings()).map_err(parse_error)?;
+            let local_ifunc = sym.st_type() == elf::STT_GNU_IFUNC;
+            let skip = usize::from(kind == Some(RelocKind::Copy));
+            let ifunc = match sym.st_bind() {
+                elf::STB_LOCAL => local_ifunc,
+                _ => match scope.iter().skip(skip).find_map(|x| x.get(name)) {
+                    Some(symbol) => symbol.ifunc,
+                    None => local_ifunc && !sym.is_undefined(endian),
+                },
+            };
+            if ifunc {
+                return Ok(Some(format!(
+                    "{}: symbol `{}` is an indirect function (STT_GNU_IFUNC), which is not \
+                     supported",
+                    path.escape_ascii(),
+                    name.escape_ascii()
+                )));
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+/// A shared library mapped by the loader.
+struct SharedLibrary {
+    data: Vec<u8>,
+    offset: u64,
+    tls: Option<TlsImage>,
+}
+
+/// Maps the shared `libraries` required by the main binary (see [find_libraries]), then applies
+/// the dynamic relocations of every loaded object. `main` is the file data and the metadata of the
+/// main binary.
+///
+/// Returns the layout of the static TLS area, which contains the TLS blocks of every loaded object.
+fn link_libraries<H, L>(
+    loader: &mut L,
+    cpu: &mut Cpu,
+    main: (&[u8], &ElfMetadata),
+    elf: &H,
+    libraries: Vec<LibraryFile>,
+    debug_info: &mut DebugInfo,
+) -> Result<Option<TlsLayout>, String>
+where
+    H: FileHeader,
+    L: ElfLoader + ?Sized,
+{
+    let mut loaded = Vec::with_capacity(libraries.len());
+    for (path, data) in libraries {
+        let lib = H::parse(&data[..]).map_err(parse_error)?;
+        let (metadata, _) = map_image::<H, L>(cpu, &data, lib)?;
+        info!("Loaded {} at {:#0x}", path.escape_ascii(), metadata.base_ptr);
+
+        debug_info.add_file(&data, metadata.offset)?;
+        debug_info.modules.push(LoadedModule {
+            path,
+            base: metadata.base_ptr,
+            len: metadata.length,
+        });
+        loaded.push(SharedLibrary { data, offset: metadata.offset, tls: metadata.tls });
+    }
+
+    // The main binary is always first in the lookup scope, followed by the libraries in the order
+    // they were loaded.
+    let (main_data, main_metadata) = main;
+    let objects: Vec<(&[u8], u64, Option<TlsImage>)> =
+        std::iter::once((main_data, main_metadata.offset, main_metadata.tls))
+            .chain(loaded.iter().map(|lib| (&lib.data[..], lib.offset, lib.tls)))
+            .collect();
+
+    // Module IDs are assigned to objects with a TLS block in load order, starting from 1.
+    let mut tls_images = vec![];
+    let mut tls_modules = Vec::with_capacity(objects.len());
+    for &(_, _, tls) in &objects {
+        tls_modules.push(tls.map(|image| {
+            tls_images.push(image);
+            tls_images.len() as u64
+        }));
+    }
+    let tls = match tls_images.is_empty() {
+        true => None,
+        false => static_tls_layout(elf, &tls_images)?,
     };
 
-    if let Some(path) = interpreter_path {
-        debug_info.dynamic_linker = path.to_vec();
+    let mut scope = Vec::with_capacity(objects.len() + 1);
+    for &(data, offset, _) in &objects {
+        scope.push(exported_symbols(H::parse(data).map_err(parse_error)?, data, offset)?);
+    }
+    let builtins = loader.builtin_symbols(cpu);
+    scope.push(
+        builtins
+            .into_iter()
+            .map(|(name, addr)| (name, ScopeSymbol { addr, size: 0, ifunc: false }))
+            .collect(),
+    );
+
+    // Dependencies are relocated before the objects that depend on them, so copy relocations in
+    // the main binary see the relocated data.
+    let mut unsupported = BTreeMap::new();
+    for (i, &(data, offset, _)) in objects.iter().enumerate().rev() {
+        let elf = H::parse(data).map_err(parse_error)?;
+        let object = Object { index: i, offset, tls: &tls_modules, tls_layout: tls.as_ref() };
+        relocate(cpu, elf, data, object, &scope, &mut unsupported)?;
+    }
+    for (r_type, count) in unsupported {
+        warn!("{count} relocations of unsupported type {r_type} were ignored");
+    }
+
+    Ok(tls)
+}
+
+/// Information about the object being relocated.
+struct Object<'a> {
+    /// The index of the object in the global scope.
+    index: usize,
+
+    /// The offset the object was relocated by.
+    offset: u64,
+
+    /// The TLS module ID of each object in the global scope.
+    tls: &'a [Option<u64>],
+
+    /// The layout of the static TLS area.
+    tls_layout: Option<&'a TlsLayout>,
+}
+
+impl Object<'_> {
+    /// Returns the TLS module ID and the offset (relative to the thread pointer) of the TLS block
+    /// of the object at `index` in the global scope.
+    fn tls_block(&self, index: usize) -> Result<(u64, i64), String> {
+        let block = self.tls.get(index).copied().flatten().and_then(|id| {
+            let module = self.tls_layout?.modules.get(id as usize - 1)?;
+            Some((id, module.tp_offset))
+        });
+        block.ok_or_else(|| "TLS relocation references an object without a TLS block".into())
+    }
+}
+
+/// Searches the library paths for a shared library, returning the path the library was found at
+/// and the content of the file.
+fn find_library<L: ElfLoader + ?Sized>(
+    loader: &mut L,
+    search_paths: &[Vec<u8>],
+    name: &[u8],
+) -> Result<(Vec<u8>, Vec<u8>), String> {
+    if name.contains(&b'/') {
+        return Ok((name.to_vec(), loader.read_file(name)?));
     }
 
-    Ok(LoadedElf { binary, interpreter, debug_info })
+    for dir in search_paths {
+        let mut path = dir.clone();
+        if !path.ends_with(b"/") {
+            path.push(b'/');
+        }
+        path.extend_from_slice(name);
+        if let Ok(data) = loader.read_file(&path) {
+            return Ok((path, data));
+        }
+    }
+
+    Err(format!("failed to find shared library: {}", name.escape_ascii()))
+}
+
+/// A symbol exported by an object.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct ScopeSymbol {
+    /// The address of the symbol, or the offset within the object's TLS block for TLS symbols.
+    addr: u64,
+
+    /// The size of the symbol.
+    size: u64,
+
+    /// Whether the symbol is an indirect function (`STT_GNU_IFUNC`), where `addr` is the address
+    /// of the resolver rather than the function.
+    ifunc: bool,
+}
+
+/// A mapping from the name of each symbol exported by an object to the symbol.
+type SymbolScope = HashMap<Vec<u8>, ScopeSymbol>;
+
+fn exported_symbols<H: FileHeader>(
+    elf: &H,
+    data: &[u8],
+    offset: u64,
+) -> Result<SymbolScope, String> {
+    let endian = elf.endian().map_err(parse_error)?;
+    let sections = elf.sections(endian, data).map_err(parse_error)?;
+    let symbols = sections.symbols(endian, data, elf::SHT_DYNSYM).map_err(parse_error)?;
+
+    let mut exports = HashMap::new();
+    for sym in symbols.iter() {
+        if sym.is_undefined(endian)
+            || !matches!(sym.st_bind(), elf::STB_GLOBAL | elf::STB_WEAK | elf::STB_GNU_UNIQUE)
+        {
+            continue;
+        }
+        let Ok(name) = sym.name(endian, symbols.strings())
+        else {
+            continue;
+        };
+        let symbol = ScopeSymbol {
+            addr: symbol_value(endian, sym, offset),
+            size: sym.st_size(endian).into(),
+            ifunc: sym.st_type() == elf::STT_GNU_IFUNC,
+        };
+        exports.entry(name.to_vec()).or_insert(symbol);
+    }
+
+    Ok(exports)
+}
+
+/// Returns the value of `sym` after the object has been relocated by `offset`. TLS symbols are
+/// offsets within the TLS block so they are not relocated.
+fn symbol_value<S: Sym>(endian: S::Endian, sym: &S, offset: u64) -> u64 {
+    let value: u64 = sym.st_value(endian).into();
+    match sym.st_type() {
+        elf::STT_TLS => value,
+        _ => value + offset,
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum RelocKind {
+    /// The relocation has no effect.
+    None,
+
+    /// The relocation offset of the object plus the addend.
+    Relative,
+
+    /// The address of the symbol (used for GOT and PLT entries).
+    Symbol,
+
+    /// The address of the symbol plus the addend.
+    Absolute,
+
+    /// Copies the initial value of the symbol from the library that defines it.
+    Copy,
+
+    /// The module ID of the object that defines the TLS symbol.
+    TlsModule,
+
+    /// The offset of the TLS symbol within its module's TLS block plus the addend.
+    TlsOffset,
+
+    /// The offset of the TLS symbol relative to the thread pointer plus the addend.
+    TlsTpOffset,
+
+    /// The result of calling the resolver at the relocation offset of the object plus the addend.
+    IRelative,
+}
+
+fn reloc_kind(machine: u16, r_type: u32) -> Option<RelocKind> {
+    Some(match (machine, r_type) {
+        (_, 0) => RelocKind::None,
+
+        (elf::EM_X86_64, elf::R_X86_64_RELATIVE) => RelocKind::Relative,
+        (elf::EM_X86_64, elf::R_X86_64_GLOB_DAT | elf::R_X86_64_JUMP_SLOT) => RelocKind::Symbol,
+        (elf::EM_X86_64, elf::R_X86_64_64) => RelocKind::Absolute,
+        (elf::EM_X86_64, elf::R_X86_64_COPY) => RelocKind::Copy,
+        (elf::EM_X86_64, elf::R_X86_64_DTPMOD64) => RelocKind::TlsModule,
+        (elf::EM_X86_64, elf::R_X86_64_DTPOFF64) => RelocKind::TlsOffset,
+        (elf::EM_X86_64, elf::R_X86_64_TPOFF64) => RelocKind::TlsTpOffset,
+        (elf::EM_X86_64, elf::R_X86_64_IRELATIVE) => RelocKind::IRelative,
+
+        (elf::EM_386, elf::R_386_RELATIVE) => RelocKind::Relative,
+        (elf::EM_386, elf::R_386_GLOB_DAT | elf::R_386_JMP_SLOT) => RelocKind::Symbol,
+        (elf::EM_386, elf::R_386_32) => RelocKind::Absolute,
+        (elf::EM_386, elf::R_386_COPY) => RelocKind::Copy,
+        (elf::EM_386, elf::R_386_TLS_DTPMOD32) => RelocKind::TlsModule,
+        (elf::EM_386, elf::R_386_TLS_DTPOFF32) => RelocKind::TlsOffset,
+        (elf::EM_386, elf::R_386_TLS_TPOFF) => RelocKind::TlsTpOffset,
+        (elf::EM_386, elf::R_386_IRELATIVE) => RelocKind::IRelative,
+
+        (elf::EM_AARCH64, elf::R_AARCH64_RELATIVE) => RelocKind::Relative,
+        (elf::EM_AARCH64, elf::R_AARCH64_GLOB_DAT | elf::R_AARCH64_JUMP_SLOT) => RelocKind::Symbol,
+        (elf::EM_AARCH64, elf::R_AARCH64_ABS64) => RelocKind::Absolute,
+        (elf::EM_AARCH64, elf::R_AARCH64_COPY) => RelocKind::Copy,
+        (elf::EM_AARCH64, elf::R_AARCH64_TLS_DTPMOD) => RelocKind::TlsModule,
+        (elf::EM_AARCH64, elf::R_AARCH64_TLS_DTPREL) => RelocKind::TlsOffset,
+        (elf::EM_AARCH64, elf::R_AARCH64_TLS_TPREL) => RelocKind::TlsTpOffset,
+        (elf::EM_AARCH64, elf::R_AARCH64_IRELATIVE) => RelocKind::IRelative,
+
+        (elf::EM_ARM, elf::R_ARM_RELATIVE) => RelocKind::Relative,
+        (elf::EM_ARM, elf::R_ARM_GLOB_DAT | elf::R_ARM_JUMP_SLOT) => RelocKind::Symbol,
+        (elf::EM_ARM, elf::R_ARM_ABS32) => RelocKind::Absolute,
+        (elf::EM_ARM, elf::R_ARM_COPY) => RelocKind::Copy,
+        (elf::EM_ARM, elf::R_ARM_TLS_DTPMOD32) => RelocKind::TlsModule,
+        (elf::EM_ARM, elf::R_ARM_TLS_DTPOFF32) => RelocKind::TlsOffset,
+        (elf::EM_ARM, elf::R_ARM_TLS_TPOFF32) => RelocKind::TlsTpOffset,
+        (elf::EM_ARM, elf::R_ARM_IRELATIVE) => RelocKind::IRelative,
+
+        (elf::EM_RISCV, elf::R_RISCV_RELATIVE) => RelocKind::Relative,
+        (elf::EM_RISCV, elf::R_RISCV_JUMP_SLOT) => RelocKind::Symbol,
+        (elf::EM_RISCV, elf::R_RISCV_32 | elf::R_RISCV_64) => RelocKind::Absolute,
+        (elf::EM_RISCV, elf::R_RISCV_COPY) => RelocKind::Copy,
+        (elf::EM_RISCV, elf::R_RISCV_TLS_DTPMOD32 | elf::R_RISCV_TLS_DTPMOD64) => {
+            RelocKind::TlsModule
+        }
+        (elf::EM_RISCV, elf::R_RISCV_TLS_DTPREL32 | elf::R_RISCV_TLS_DTPREL64) => {
+            RelocKind::TlsOffset
+        }
+        (elf::EM_RISCV, elf::R_RISCV_TLS_TPREL32 | elf::R_RISCV_TLS_TPREL64) => {
+            RelocKind::TlsTpOffset
+        }
+        (elf::EM_RISCV, elf::R_RISCV_IRELATIVE) => RelocKind::IRelative,
+
+        // @todo: support TLS descriptors.
+        _ => return None,
+    })
+}
+
+/// A relocation applied at load time: (offset, type, symbol, explicit addend).
+type DynamicRelocation = (u64, u32, u32, Option<i64>);
+
+/// Returns every relocation of an object that is applied at load time.
+fn dynamic_relocations<H: FileHeader>(
+    elf: &H,
+    data: &[u8],
+) -> Result<Vec<DynamicRelocation>, String> {
+    let endian = elf.endian().map_err(parse_error)?;
+    let sections = elf.sections(endian, data).map_err(parse_error)?;
+    let is_mips64el = elf.is_mips64el(endian);
+
+    let mut relocations = vec![];
+    for section in sections.iter() {
+        let flags: u64 = section.sh_flags(endian).into();
+        if flags & u64::from(elf::SHF_ALLOC) == 0 {
+            continue;
+        }
+        if let Some((relocs, _)) = section.rel(endian, data).map_err(parse_error)? {
+            relocations.extend(relocs.iter().map(|rel| {
+                (rel.r_offset(endian).into(), rel.r_type(endian), rel.r_sym(endian), None)
+            }));
+        }
+        if let Some((relocs, _)) = section.rela(endian, data).map_err(parse_error)? {
+            relocations.extend(relocs.iter().map(|rela| {
+                (
+                    rela.r_offset(endian).into(),
+                    rela.r_type(endian, is_mips64el),
+                    rela.r_sym(endian, is_mips64el),
+                    Some(rela.r_addend(endian).into()),
+                )
+            }));
+        }
+    }
+
+    Ok(relocations)
+}
+
+/// Applies the dynamic relocations of a single object, resolving symbols using the global `scope`.
+fn relocate<H: FileHeader>(
+    cpu: &mut Cpu,
+    elf: &H,
+    data: &[u8],
+    object: Object,
+    scope: &[SymbolScope],
+    unsupported: &mut BTreeMap<u32, usize>,
+) -> Result<(), String> {
+    let endian = elf.endian().map_err(parse_error)?;
+    let sections = elf.sections(endian, data).map_err(parse_error)?;
+    let symbols = sections.symbols(endian, data, elf::SHT_DYNSYM).map_err(parse_error)?;
+    let machine = elf.e_machine(endian);
+    let relocations = dynamic_relocations(elf, data)?;
+
+    let size = if elf.is_type_64() { 8 } else { 4 };
+    let word = Word { size, big_endian: endian.is_big_endian() };
+    let offset = object.offset;
+    for (r_offset, r_type, r_sym, addend) in relocations {
+        let addr = r_offset + offset;
+        let Some(kind) = reloc_kind(machine, r_type)
+        else {
+            *unsupported.entry(r_type).or_default() += 1;
+            continue;
+        };
+
+        // Relocations without an explicit addend use the value stored at the target, except for
+        // GOT and PLT entries which are always replaced.
+        let addend = match addend {
+            Some(addend) => addend as u64,
+            None if kind == RelocKind::Symbol => 0,
+            None => word.read(cpu, addr)?,
+        };
+
+        let resolve =
+            |skip_main| resolve_symbol(endian, &symbols, r_sym, &object, scope, skip_main);
+        let value = match kind {
+            RelocKind::None => continue,
+            RelocKind::IRelative => {
+                // Resolving the target requires executing the resolver in the guest.
+                return Err(format!(
+                    "unsupported IRELATIVE relocation at {addr:#0x} (indirect functions require \
+                     the dynamic linker)"
+                ));
+            }
+            RelocKind::Relative => offset.wrapping_add(addend),
+            RelocKind::Symbol | RelocKind::Absolute => {
+                let (sym_addr, _, _) = resolve(false)?;
+                sym_addr.wrapping_add(addend)
+            }
+            RelocKind::TlsModule => {
+                let (_, _, index) = resolve(false)?;
+                object.tls_block(index)?.0
+            }
+            RelocKind::TlsOffset => {
+                let (value, _, _) = resolve(false)?;
+                value.wrapping_add(addend)
+            }
+            RelocKind::TlsTpOffset => {
+                let (value, _, index) = resolve(false)?;
+                let (_, tp_offset) = object.tls_block(index)?;
+                value.wrapping_add_signed(tp_offset).wrapping_add(addend)
+            }
+            RelocKind::Copy => {
+                let (src, size, _) = resolve(true)?;
+                let mut buf = vec![0; size as usize];
+                cpu.mem
+                    .read_bytes(src, &mut buf, perm::NONE)
+                    .and_then(|_| cpu.mem.write_bytes(addr, &buf, perm::NONE))
+                    .map_err(|e| format!("failed to apply copy relocation at {addr:#0x}: {e}"))?;
+                continue;
+            }
+        };
+        word.write(cpu, addr, value)?;
+    }
+
+    Ok(())
+}
+
+/// Resolves the address and size of a symbol referenced by a relocation, and the index of the
+/// object that defines it. Global symbols are looked up in the global scope first (skipping the
+/// main binary if `skip_main` is set), allowing definitions to be interposed by objects earlier in
+/// the scope.
+fn resolve_symbol<H: FileHeader>(
+    endian: H::Endian,
+    symbols: &SymbolTable<'_, H>,
+    index: u32,
+    object: &Object,
+    scope: &[SymbolScope],
+    skip_main: bool,
+) -> Result<(u64, u64, usize), String> {
+    // A relocation without a symbol refers to the object itself (e.g. for local-dynamic TLS).
+    if index == 0 {
+        return Ok((0, 0, object.index));
+    }
+
+    let sym = symbols.symbol(SymbolIndex(index as usize)).map_err(parse_error)?;
+    let name = sym.name(endian, symbols.strings()).map_err(parse_error)?;
+    let ifunc_error = || {
+        // The address of the function is only known after calling the resolver in the guest.
+        format!(
+            "symbol `{}` is an indirect function (STT_GNU_IFUNC), which requires the dynamic \
+             linker",
+            name.escape_ascii()
+        )
+    };
+
+    let value = symbol_value(endian, sym, object.offset);
+    let local = (value, sym.st_size(endian).into(), object.index);
+    let local_ifunc = sym.st_type() == elf::STT_GNU_IFUNC;
+    if sym.st_bind() == elf::STB_LOCAL {
+        return if local_ifunc { Err(ifunc_error()) } else { Ok(local) };
+    }
+
+    let skip = if skip_main { 1 } else { 0 };
+    let found = scope.iter().enumerate().skip(skip).find_map(|(i, x)| Some((i, x.get(name)?)));
+    if let Some((i, symbol)) = found {
+        if symbol.ifunc {
+            return Err(ifunc_error());
+        }
+        return Ok((symbol.addr, symbol.size, i));
+    }
+
+    if !sym.is_undefined(endian) {
+        return if local_ifunc { Err(ifunc_error()) } else { Ok(local) };
+    }
+    if sym.st_bind() != elf::STB_WEAK {
+        warn!("undefined symbol: {}", name.escape_ascii());
+    }
+    Ok((0, 0, object.index))
+}
+
+/// The size and byte order of a pointer sized value in the target.
+#[derive(Copy, Clone)]
+struct Word {
+    size: usize,
+    big_endian: bool,
+}
+
+impl Word {
+    fn read(self, cpu: &mut Cpu, addr: u64) -> Result<u64, String> {
+        let mut buf = [0; 8];
+        let buf = &mut buf[..self.size];
+        cpu.mem
+            .read_bytes(addr, buf, perm::NONE)
+            .map_err(|e| format!("failed to read relocation target at {addr:#0x}: {e}"))?;
+        let value = match self.big_endian {
+            true => buf.iter().fold(0, |acc, &b| (acc << 8) | b as u64),
+            false => buf.iter().rev().fold(0, |acc, &b| (acc << 8) | b as u64),
+        };
+        Ok(value)
+    }
+
+    fn write(self, cpu: &mut Cpu, addr: u64, value: u64) -> Result<(), String> {
+        let (be, le) = (value.to_be_bytes(), value.to_le_bytes());
+        let bytes = match self.big_endian {
+            true => &be[8 - self.size..],
+            false => &le[..self.size],
+        };
+        cpu.mem
+            .write_bytes(addr, bytes, perm::NONE)
+            .map_err(|e| format!("failed to write relocation target at {addr:#0x}: {e}"))
+    }
 }
 
 // Retrives the base address and layout requirements of the ELF when it is loaded into memory.
@@ -323,3 +1192,127 @@ fn get_permission<H: ProgramHeader>(endian: H::Endian, header: &H) -> u8 {
     perm |= if (flags & elf::PF_X) == 0 { perm::NONE } else { perm::EXEC };
     perm
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn tls_layout() {
+        let main = TlsImage { addr: 0x1000, file_size: 0x8, mem_size: 0x14, align: 0x8 };
+        let lib = TlsImage { addr: 0x2000, file_size: 0x10, mem_size: 0x20, align: 0x20 };
+
+        // Variant II: blocks are placed below the thread pointer in module order.
+        let layout = TlsLayout::new(elf::EM_X86_64, 8, &[main, lib]).unwrap();
+        assert_eq!(layout.modules[0].tp_offset, -0x18);
+        assert_eq!(layout.modules[1].tp_offset, -0x40);
+        assert_eq!(layout.tp_offset, 0x40);
+        assert_eq!(layout.align, 0x20);
+
+        // Variant I: blocks are placed after the TCB.
+        let layout = TlsLayout::new(elf::EM_AARCH64, 8, &[main, lib]).unwrap();
+        assert_eq!(layout.modules[0].tp_offset, 0x10);
+        assert_eq!(layout.modules[1].tp_offset, 0x40);
+        assert_eq!(layout.tp_offset, 0);
+        assert_eq!(layout.size, 0x60);
+
+        assert!(TlsLayout::new(elf::EM_MIPS, 4, &[main]).is_none());
+    }
+
+    /// Builds a shared library exporting the symbols `(name, st_type)`, with the given entries in
+    /// its dynamic section.
+    fn build_library(symbols: &[(&[u8], u8)], dynamic: &[(u32, u64)]) -> Vec<u8> {
+        use object::write::elf::{FileHeader, Sym, Writer};
+
+        let mut data = vec![];
+        let mut w = Writer::new(Endianness::Little, true, &mut data);
+        w.reserve_file_header();
+
+        w.reserve_null_section_index();
+        w.reserve_dynsym_section_index();
+        w.reserve_dynstr_section_index();
+        w.reserve_dynamic_section_index();
+        w.reserve_shstrtab_section_index();
+
+        let names: Vec<_> = symbols.iter().map(|(name, _)| w.add_dynamic_string(name)).collect();
+        w.reserve_null_dynamic_symbol_index();
+        for _ in &names {
+            w.reserve_dynamic_symbol_index();
+        }
+        w.reserve_dynsym();
+        w.reserve_dynstr();
+        w.reserve_dynamic(dynamic.len() + 1);
+        w.reserve_shstrtab();
+        w.reserve_section_headers();
+
+        w.write_file_header(&FileHeader {
+            os_abi: 0,
+            abi_version: 0,
+            e_type: elf::ET_DYN,
+            e_machine: elf::EM_X86_64,
+            e_entry: 0,
+            e_flags: 0,
+        })
+        .unwrap();
+        w.write_null_dynamic_symbol();
+        for (i, (name, (_, st_type))) in names.iter().zip(symbols).enumerate() {
+            w.write_dynamic_symbol(&Sym {
+                name: Some(*name),
+                section: None,
+                st_info: (elf::STB_GLOBAL << 4) | st_type,
+                st_other: 0,
+                st_shndx: elf::SHN_ABS,
+                st_value: 0x1000 + i as u64 * 0x10,
+                st_size: 0x10,
+            });
+        }
+        w.write_dynstr();
+        w.write_align_dynamic();
+        for &(tag, value) in dynamic {
+            w.write_dynamic(tag, value);
+        }
+        w.write_dynamic(elf::DT_NULL, 0);
+        w.write_shstrtab();
+
+        w.write_null_section_header();
+        w.write_dynsym_section_header(0, 1);
+        w.write_dynstr_section_header(0);
+        w.write_dynamic_section_header(0);
+        w.write_shstrtab_section_header();
+        data
+    }
+
+    #[test]
+    fn library_constructors() {
+        let parse = |data: &[u8]| {
+            let header = elf::FileHeader64::<Endianness>::parse(data).unwrap();
+            has_constructors(header, data).unwrap()
+        };
+        assert!(!parse(&build_library(&[], &[(elf::DT_INIT_ARRAYSZ, 0)])));
+        assert!(parse(&build_library(&[], &[(elf::DT_INIT, 0x1000)])));
+        let init_array = [(elf::DT_INIT_ARRAY, 0x2000), (elf::DT_INIT_ARRAYSZ, 8)];
+        assert!(parse(&build_library(&[], &init_array)));
+    }
+
+    #[test]
+    fn libraries_with_constructors_require_dynamic_linker() {
+        let main = build_library(&[], &[]);
+        let header = elf::FileHeader64::<Endianness>::parse(&main[..]).unwrap();
+        let check = |lib: Vec<u8>| {
+            requires_dynamic_linker(header, &main, &[(b"libfoo.so".to_vec(), lib)]).unwrap()
+        };
+        assert_eq!(check(build_library(&[(b"puts", elf::STT_FUNC)], &[])), None);
+        assert!(check(build_library(&[], &[(elf::DT_INIT, 0x1000)])).is_some());
+    }
+
+    #[test]
+    fn indirect_functions_are_marked() {
+        let data = build_library(&[(b"memcpy", elf::STT_GNU_IFUNC), (b"puts", elf::STT_FUNC)], &[]);
+        let header = elf::FileHeader64::<Endianness>::parse(&data[..]).unwrap();
+        let scope = exported_symbols(header, &data, 0x10000).unwrap();
+
+        assert_eq!(scope[&b"memcpy"[..]], ScopeSymbol { addr: 0x11000, size: 0x10, ifunc: true });
+        assert_eq!(scope[&b"puts"[..]], ScopeSymbol { addr: 0x11010, size: 0x10, ifunc: false });
+        assert!(reloc_kind(elf::EM_X86_64, elf::R_X86_64_IRELATIVE) == Some(RelocKind::IRelative));
+    }
+}
@@ -27,14 +27,6 @@ fn set_isa_mode(_cpu: &mut Cpu, _: VarNode, _: [Value; 2]) {
     // called explicitly.
 }
 
-fn enable_interrupts(_cpu: &mut Cpu, _: VarNode, _: [Value; 2]) {
-    // @todo
-}
-
-fn disable_interrupts(_cpu: &mut Cpu, _: VarNode, _: [Value; 2]) {
-    // @todo
-}
-
 fn count_leading_zeros(cpu: &mut Cpu, dst: VarNode, args: [Value; 2]) {
     let input = args[0];
     let result = match args[0].size() {
@@ -253,14 +245,41 @@ fn saturating_sub(cpu: &mut Cpu, dst: pcode::VarNode, args: [Value; 2]) {
 }
 
 pub mod x86 {
+    use ahash::AHashMap as HashMap;
+
     use super::*;
+    use crate::X86Config;
+
+    /// The name of the register used to store the `IA32_KERNEL_GS_BASE` MSR. Only present for
+    /// targets that can run in kernel mode.
+    pub const KERNEL_GS_BASE: &str = "KERNEL_GS_BASE";
 
     pub const HELPERS: &[(&str, PcodeOpHelper)] = &[
         ("rdtsc", rdtsc),
-        ("cpuid_basic_info", cpuid_basic_info),
-        ("cpuid_Version_info", cpuid_version_info),
-        ("cpuid_Extended_Feature_Enumeration_info", cpuid_extended_feature_enumeration_info),
+        ("rdrand", random_value),
+        ("rdseed", random_value),
+        ("rdrandIsValid", random_is_valid),
+        ("rdseedIsValid", random_is_valid),
+        ("rdmsr", rdmsr),
+        ("wrmsr", wrmsr),
         ("cpuid", cpuid),
+        ("cpuid_basic_info", cpuid),
+        ("cpuid_Version_info", cpuid),
+        ("cpuid_cache_tlb_info", cpuid),
+        ("cpuid_serial_info", cpuid),
+        ("cpuid_Deterministic_Cache_Parameters_info", cpuid),
+        ("cpuid_MONITOR_MWAIT_Features_info", cpuid),
+        ("cpuid_Thermal_Power_Management_info", cpuid),
+        ("cpuid_Extended_Feature_Enumeration_info", cpuid),
+        ("cpuid_Direct_Cache_Access_info", cpuid),
+        ("cpuid_Architectural_Performance_Monitoring_info", cpuid),
+        ("cpuid_Extended_Topology_info", cpuid),
+        ("cpuid_Processor_Extended_States_info", cpuid),
+        ("cpuid_Quality_of_Service_info", cpuid),
+        ("cpuid_brand_part1_info", cpuid),
+        ("cpuid_brand_part2_info", cpuid),
+        ("cpuid_brand_part3_info", cpuid),
+        ("swapgs", swapgs),
         ("movmskpd", movmskpd),
         ("pinsrw", pinsrw), // Note: implemented in SLEIGH in Ghidra 10.3.
         ("pshuflw", pshuflw),
@@ -279,140 +298,336 @@ pub mod x86 {
         ("fscale", fscale),
     ];
 
+    /// Exchanges the GS base with the kernel GS base. This is a privileged instruction, so it is
+    /// invalid on targets without a kernel GS base (i.e. user-mode targets).
+    fn swapgs(cpu: &mut Cpu, _: VarNode, _: [Value; 2]) {
+        let sleigh = &cpu.arch.sleigh;
+        let (Some(gs_base), Some(kernel_gs_base)) =
+            (sleigh.get_varnode("GS_OFFSET"), sleigh.get_varnode(KERNEL_GS_BASE))
+        else {
+            cpu.exception.code = ExceptionCode::InvalidInstruction as u32;
+            return;
+        };
+        let gs = cpu.read_reg(gs_base);
+        let kernel_gs = cpu.read_reg(kernel_gs_base);
+        cpu.write_reg(gs_base, kernel_gs);
+        cpu.write_reg(kernel_gs_base, gs);
+    }
+
+    fn random_value(cpu: &mut Cpu, dst: VarNode, _: [Value; 2]) {
+        let value = cpu.entropy.next_u64();
+        cpu.write_trunc(dst, value);
+    }
+
+    fn random_is_valid(cpu: &mut Cpu, dst: VarNode, _: [Value; 2]) {
+        cpu.write_trunc(dst, 1_u64);
+    }
+
+    /// Calls `f` with the x86 model of the CPU, creating a model with the default configuration if
+    /// the CPU does not have one.
+    fn with_model<R>(cpu: &mut Cpu, f: impl FnOnce(&mut X86Model, &mut Cpu) -> R) -> R {
+        let mut model = cpu.x86.take().unwrap_or_default();
+        let result = f(&mut model, cpu);
+        cpu.x86 = Some(model);
+        result
+    }
+
     fn rdtsc(cpu: &mut Cpu, dst: VarNode, _: [Value; 2]) {
-        cpu.write_var(dst, 0_u64);
+        let tsc = with_model(cpu, |model, cpu| model.tsc(cpu));
+        cpu.write_trunc(dst, tsc);
     }
 
-    // Basic processor information
-    fn cpuid_basic_info(cpu: &mut Cpu, dst: VarNode, _: [Value; 2]) {
-        if dst.size != 16 {
-            tracing::warn!(
-                "Using unpatched SLEIGH specification, CPUID instruction will behave incorrectly"
-            );
-            return;
-        }
-        tracing::debug!("cpuid(BASIC_INFO)");
-        if true {
-            // Pretend to be an Intel CPU
-            cpu.write_var(dst.slice(0, 4), 0_u32);
-            cpu.write_var(dst.slice(4, 4), u32::from_le_bytes(*b"Genu"));
-            cpu.write_var(dst.slice(8, 4), u32::from_le_bytes(*b"ineI"));
-            cpu.write_var(dst.slice(12, 4), u32::from_le_bytes(*b"ntel"));
+    fn rdmsr(cpu: &mut Cpu, dst: VarNode, args: [Value; 2]) {
+        let msr: u32 = cpu.read(args[0]);
+        tracing::debug!("rdmsr({msr:#0x})");
+        match with_model(cpu, |model, cpu| model.rdmsr(cpu, msr)) {
+            Some(value) => cpu.write_trunc(dst, value),
+            None => {
+                tracing::warn!("Unknown MSR: {msr:#0x}");
+                cpu.exception.code = ExceptionCode::UnknownMsr as u32;
+                cpu.exception.value = msr as u64;
+            }
         }
-        else {
-            cpu.write_var(dst.slice(0, 4), 0_u32);
-            cpu.write_var(dst.slice(4, 4), u32::from_le_bytes(*b"Icic"));
-            cpu.write_var(dst.slice(8, 4), u32::from_le_bytes(*b"leCo"));
-            cpu.write_var(dst.slice(12, 4), u32::from_le_bytes(*b"reVm"));
+    }
+
+    fn wrmsr(cpu: &mut Cpu, _: VarNode, args: [Value; 2]) {
+        let msr: u32 = cpu.read(args[0]);
+        let value: u64 = cpu.read_dynamic(args[1]).zxt();
+        tracing::debug!("wrmsr({msr:#0x}, {value:#0x})");
+        if !with_model(cpu, |model, cpu| model.wrmsr(cpu, msr, value)) {
+            tracing::warn!("Unknown MSR: {msr:#0x}");
+            cpu.exception.code = ExceptionCode::UnknownMsr as u32;
+            cpu.exception.value = msr as u64;
         }
     }
 
-    // Processor info and feature bits
-    fn cpuid_version_info(cpu: &mut Cpu, dst: VarNode, _: [Value; 2]) {
+    /// Handles every variant of the `cpuid` operation. Note: the patched SLEIGH specification
+    /// uses a different operation for each leaf, but the leaf is always passed as the first
+    /// argument.
+    fn cpuid(cpu: &mut Cpu, dst: VarNode, args: [Value; 2]) {
         if dst.size != 16 {
             tracing::warn!(
                 "Using unpatched SLEIGH specification, CPUID instruction will behave incorrectly"
             );
             return;
         }
-        tracing::debug!("cpuid(VERSION_INFO)");
-        // Copied from `Coffee Lake` microarchitecture
-        let extended_family = 0x0;
-        let family = 0x6;
-        let extended_model = 0x9;
-        let model = 0xe;
+        let leaf: u32 = cpu.read(args[0]);
+        let subleaf: u32 = cpu.read(args[1]);
+        tracing::debug!("cpuid({leaf:#0x}, {subleaf:#0x})");
+        match with_model(cpu, |model, cpu| model.cpuid(cpu, leaf, subleaf)) {
+            Some([eax, ebx, ecx, edx]) => {
+                cpu.write_var(dst.slice(0, 4), eax);
+                cpu.write_var(dst.slice(4, 4), ebx);
+                cpu.write_var(dst.slice(8, 4), edx);
+                cpu.write_var(dst.slice(12, 4), ecx);
+            }
+            None => {
+                tracing::warn!("Unknown CPUID index: {leaf:0x}");
+                cpu.exception.code = ExceptionCode::UnknownCpuID as u32;
+                cpu.exception.value = leaf as u64;
+            }
+        }
+    }
 
-        let eax: u32 =
-            (extended_family << 20) | (extended_model << 16) | (family << 8) | (model << 4);
+    /// Handles `cpuid` leaves that are not handled by the model, see [X86Model::add_cpuid_handler].
+    pub trait CpuidHandler {
+        /// Returns the `[eax, ebx, ecx, edx]` values for `leaf` and `subleaf`, or `None` if the
+        /// leaf is not handled.
+        fn cpuid(&mut self, cpu: &mut Cpu, leaf: u32, subleaf: u32) -> Option<[u32; 4]>;
+    }
 
-        use cpuid::FeatureInformationEcx as Feature;
+    impl<F> CpuidHandler for F
+    where
+        F: FnMut(&mut Cpu, u32, u32) -> Option<[u32; 4]>,
+    {
+        fn cpuid(&mut self, cpu: &mut Cpu, leaf: u32, subleaf: u32) -> Option<[u32; 4]> {
+            self(cpu, leaf, subleaf)
+        }
+    }
 
-        let ecx: u32 = (Feature::sse3
-            | Feature::tm2
-            | Feature::pdcm
-            | Feature::popcnt
-            | Feature::tsc_deadline
-            | Feature::aesni
-            | Feature::xsave)
-            .bits();
+    /// Handles model specific registers that are not handled by the model, see
+    /// [X86Model::add_msr_handler].
+    pub trait MsrHandler {
+        /// Reads the value of `msr`, or returns `None` if the MSR is not handled.
+        fn rdmsr(&mut self, cpu: &mut Cpu, msr: u32) -> Option<u64>;
 
-        cpu.write_var(dst.slice(0, 4), eax);
-        cpu.write_var(dst.slice(4, 4), 0_u32);
-        cpu.write_var(dst.slice(8, 4), ecx);
-        cpu.write_var(dst.slice(12, 4), 0_u32);
+        /// Writes `value` to `msr`, returning `false` if the MSR is not handled.
+        fn wrmsr(&mut self, cpu: &mut Cpu, msr: u32, value: u64) -> bool;
     }
 
-    // Return structured extended feature enumeration info leaf
-    fn cpuid_extended_feature_enumeration_info(cpu: &mut Cpu, dst: VarNode, args: [Value; 2]) {
-        if dst.size != 16 {
-            tracing::warn!(
-                "Using unpatched SLEIGH specification, CPUID instruction will behave incorrectly"
-            );
-            return;
+    /// Numbers of the model specific registers handled by [X86Model].
+    pub mod msr {
+        pub const IA32_TIME_STAMP_COUNTER: u32 = 0x10;
+        pub const IA32_FS_BASE: u32 = 0xc000_0100;
+        pub const IA32_GS_BASE: u32 = 0xc000_0101;
+        pub const IA32_KERNEL_GS_BASE: u32 = 0xc000_0102;
+    }
+
+    /// Models the values returned by `cpuid`, `rdtsc` and `rdmsr` instructions.
+    ///
+    /// `cpuid` leaves are resolved by checking (in order): the overrides in [X86Config::cpuid],
+    /// the default leaves (see [X86Model::default_cpuid]) then any registered [CpuidHandler].
+    ///
+    /// MSRs are resolved by checking (in order): the FS, GS and kernel GS base MSRs (which are
+    /// backed by registers), the time stamp counter, MSRs configured in [X86Config::msrs] (or
+    /// written by the guest), then any registered [MsrHandler].
+    ///
+    /// Note: the values of MSRs that are not backed by a register are not part of CPU snapshots.
+    pub struct X86Model {
+        pub config: X86Config,
+
+        /// The current value of MSRs that are not backed by a register.
+        msrs: HashMap<u32, u64>,
+
+        /// The offset added to the time stamp counter, adjusted when the guest writes to the time
+        /// stamp counter.
+        tsc_offset: u64,
+
+        cpuid_handlers: Vec<Box<dyn CpuidHandler>>,
+        msr_handlers: Vec<Box<dyn MsrHandler>>,
+    }
+
+    impl Default for X86Model {
+        fn default() -> Self {
+            Self::new(X86Config::default())
         }
-        let count: u32 = cpu.read(args[1]);
-        tracing::debug!("cpuid(EXTENDED_FEATURE_ENUMERATION_INFO, {:#0x})", count);
+    }
 
-        match count {
-            // Returns extended feature flags in EBX, ECX, and EDX
-            0x0 => {
-                cpu.write_var(dst.slice(0, 4), u32::MAX);
-                cpu.write_var(dst.slice(4, 4), cpuid::EXTENDED_FEATURES_EBX);
-                cpu.write_var(dst.slice(8, 4), cpuid::EXTENDED_FEATURES_EDX);
-                cpu.write_var(dst.slice(12, 4), cpuid::EXTENDED_FEATURES_ECX);
+    impl X86Model {
+        pub fn new(config: X86Config) -> Self {
+            Self {
+                msrs: config.msrs.clone(),
+                config,
+                tsc_offset: 0,
+                cpuid_handlers: vec![],
+                msr_handlers: vec![],
             }
+        }
+
+        /// Registers a handler for `cpuid` leaves that are not handled by the model.
+        pub fn add_cpuid_handler(&mut self, handler: Box<dyn CpuidHandler>) {
+            self.cpuid_handlers.push(handler);
+        }
+
+        /// Registers a handler for MSRs that are not handled by the model.
+        pub fn add_msr_handler(&mut self, handler: Box<dyn MsrHandler>) {
+            self.msr_handlers.push(handler);
+        }
+
+        /// Gets the current value of the time stamp counter.
+        pub fn tsc(&self, cpu: &Cpu) -> u64 {
+            cpu.clock().wrapping_mul(self.config.tsc_scale).wrapping_add(self.tsc_offset)
+        }
 
-            // Returns extended feature flags in EAX
-            0x1 => {
-                // We don't support AVX-512 BFLOAT16 operations
-                cpu.write_var(dst.slice(0, 4), 0_u32);
-                cpu.write_var(dst.slice(4, 4), 0_u32);
-                cpu.write_var(dst.slice(8, 4), 0_u32);
-                cpu.write_var(dst.slice(12, 4), 0_u32);
+        /// Gets the `[eax, ebx, ecx, edx]` values reported by `cpuid` for `leaf` and `subleaf`.
+        pub fn cpuid(&mut self, cpu: &mut Cpu, leaf: u32, subleaf: u32) -> Option<[u32; 4]> {
+            let config = &self.config.cpuid;
+            let value = config.get(&(leaf, Some(subleaf))).or_else(|| config.get(&(leaf, None)));
+            if let Some(value) = value {
+                return Some(*value);
             }
-            _ => {
-                cpu.write_var(dst.slice(0, 4), 0_u32);
-                cpu.write_var(dst.slice(4, 4), 0_u32);
-                cpu.write_var(dst.slice(8, 4), 0_u32);
-                cpu.write_var(dst.slice(12, 4), 0_u32);
+            if let Some(value) = self.default_cpuid(leaf, subleaf) {
+                return Some(value);
             }
+            let handled =
+                self.cpuid_handlers.iter_mut().find_map(|handler| handler.cpuid(cpu, leaf, subleaf));
+            if handled.is_some() {
+                return handled;
+            }
+
+            // Guests (e.g. glibc) query the cache and topology leaves below the highest reported
+            // leaf without checking the feature bits first, so leaves that are reported but not
+            // otherwise handled return zeros (i.e. no information) instead of failing.
+            self.is_reported_leaf(leaf).then_some([0; 4])
         }
-    }
 
-    fn cpuid(cpu: &mut Cpu, dst: VarNode, args: [Value; 2]) {
-        if dst.size != 16 {
-            tracing::warn!(
-                "Using unpatched SLEIGH specification, CPUID instruction will behave incorrectly"
-            );
-            return;
+        /// The default values for the leaves commonly queried by user-mode programs.
+        ///
+        /// Leaf 0 and leaf 0x8000_0000 report the highest basic and extended leaf that is either
+        /// handled here or overridden in [X86Config::cpuid]. Leaves served by a [CpuidHandler]
+        /// beyond these should also override leaf 0 or leaf 0x8000_0000 so the guest queries them.
+        ///
+        /// Note: reported leaves without a default value (e.g. the cache parameters in leaf 4) are
+        /// not handled here, [X86Model::cpuid] returns zeros for them if no handler does.
+        pub fn default_cpuid(&self, leaf: u32, subleaf: u32) -> Option<[u32; 4]> {
+            Some(match leaf {
+                // Basic processor information
+                0x0 => {
+                    let vendor = |i: usize| {
+                        u32::from_le_bytes(self.config.vendor[i * 4..][..4].try_into().unwrap())
+                    };
+                    [self.max_leaf(0x0, 0x7), vendor(0), vendor(2), vendor(1)]
+                }
+
+                // Processor info and feature bits
+                0x1 => {
+                    // Copied from `Coffee Lake` microarchitecture
+                    let extended_family = 0x0;
+                    let family = 0x6;
+                    let extended_model = 0x9;
+                    let model = 0xe;
+
+                    let eax: u32 = (extended_family << 20)
+                        | (extended_model << 16)
+                        | (family << 8)
+                        | (model << 4);
+
+                    use cpuid::FeatureInformationEcx as Feature;
+
+                    let features: u32 = (Feature::sse3
+                        | Feature::tm2
+                        | Feature::pdcm
+                        | Feature::popcnt
+                        | Feature::tsc_deadline
+                        | Feature::aesni
+                        | Feature::xsave)
+                        .bits();
+
+                    [eax, 0, features, 0]
+                }
+
+                // Structured extended feature enumeration
+                0x7 => match subleaf {
+                    // Returns extended feature flags in EBX, ECX, and EDX
+                    0x0 => [
+                        u32::MAX,
+                        cpuid::EXTENDED_FEATURES_EBX,
+                        cpuid::EXTENDED_FEATURES_ECX,
+                        cpuid::EXTENDED_FEATURES_EDX,
+                    ],
+                    // We don't support AVX-512 BFLOAT16 operations
+                    _ => [0; 4],
+                },
+
+                // Hypervisor
+                0x4000_0000 => [0; 4],
+
+                // Get Highest Extended Function Implemented
+                0x8000_0000 => [self.max_leaf(0x8000_0000, 0x8000_0000), 0, 0, 0],
+
+                _ => return None,
+            })
+        }
+
+        /// Returns the highest leaf in the range starting at `base` that is overridden in the
+        /// configuration, or `default` if it is higher.
+        fn max_leaf(&self, base: u32, default: u32) -> u32 {
+            let leaves = self.config.cpuid.keys().map(|(leaf, _)| *leaf);
+            leaves.filter(|leaf| (base..base + 0x1000_0000).contains(leaf)).fold(default, u32::max)
+        }
+
+        /// Returns whether `leaf` is at or below the highest basic or extended leaf reported by
+        /// leaf 0 or leaf 0x8000_0000.
+        fn is_reported_leaf(&self, leaf: u32) -> bool {
+            let (base, default) =
+                if leaf >= 0x8000_0000 { (0x8000_0000, 0x8000_0000) } else { (0x0, 0x7) };
+            leaf <= self.max_leaf(base, default)
         }
-        let index: u32 = cpu.read(args[0]);
-        let count: u32 = cpu.read(args[1]);
-        tracing::debug!("cpuid({:#0x}, {:#0x})", index, count);
-        match index {
-            // Hypervisor
-            0x4000_0000 => {
-                cpu.write_var(dst.slice(0, 4), 0_u32);
-                cpu.write_var(dst.slice(4, 4), 0_u32);
-                cpu.write_var(dst.slice(8, 4), 0_u32);
-                cpu.write_var(dst.slice(12, 4), 0_u32);
+
+        /// Reads the value of `msr`, or returns `None` if the MSR is unknown.
+        pub fn rdmsr(&mut self, cpu: &mut Cpu, msr: u32) -> Option<u64> {
+            if let Some(var) = msr_register(cpu, msr) {
+                return Some(cpu.read_reg(var));
+            }
+            if msr == msr::IA32_TIME_STAMP_COUNTER {
+                return Some(self.tsc(cpu));
+            }
+            if let Some(value) = self.msrs.get(&msr) {
+                return Some(*value);
             }
+            self.msr_handlers.iter_mut().find_map(|handler| handler.rdmsr(cpu, msr))
+        }
 
-            // Get Highest Extended Function Implemented
-            0x8000_0000 => {
-                cpu.write_var(dst.slice(0, 4), 0_u32);
-                cpu.write_var(dst.slice(4, 4), 0_u32);
-                cpu.write_var(dst.slice(8, 4), 0_u32);
-                cpu.write_var(dst.slice(12, 4), 0_u32);
+        /// Writes `value` to `msr`, returning `false` if the MSR is unknown.
+        pub fn wrmsr(&mut self, cpu: &mut Cpu, msr: u32, value: u64) -> bool {
+            if let Some(var) = msr_register(cpu, msr) {
+                cpu.write_reg(var, value);
+                return true;
             }
-            unknown => {
-                tracing::warn!("Unknown CPUID index: {:0x}", unknown);
-                cpu.exception.code = ExceptionCode::UnknownCpuID as u32;
-                cpu.exception.value = unknown as u64;
+            if msr == msr::IA32_TIME_STAMP_COUNTER {
+                self.tsc_offset = 0;
+                self.tsc_offset = value.wrapping_sub(self.tsc(cpu));
+                return true;
+            }
+            if let Some(entry) = self.msrs.get_mut(&msr) {
+                *entry = value;
+                return true;
             }
+            self.msr_handlers.iter_mut().any(|handler| handler.wrmsr(cpu, msr, value))
         }
     }
 
+    /// Gets the register that backs `msr`, if any.
+    fn msr_register(cpu: &Cpu, msr: u32) -> Option<VarNode> {
+        let name = match msr {
+            msr::IA32_FS_BASE => "FS_OFFSET",
+            msr::IA32_GS_BASE => "GS_OFFSET",
+            msr::IA32_KERNEL_GS_BASE => KERNEL_GS_BASE,
+            _ => return None,
+        };
+        cpu.arch.sleigh.get_varnode(name)
+    }
+
     /// Extract Packed Double-Precision Floating-Point Sign Mask
     fn movmskpd(cpu: &mut Cpu, dst: VarNode, args: [Value; 2]) {
         let src = cpu.read::<u128>(args[1]);
@@ -713,9 +928,21 @@ pub mod aarch64 {
 pub mod arm {
     use super::*;
 
+    /// The names of the registers used to store the Cortex-M exception mask registers and the
+    /// current exception number. Only present for bare-metal Thumb targets.
+    pub const PRIMASK: &str = "PRIMASK";
+    pub const BASEPRI: &str = "BASEPRI";
+    pub const IPSR: &str = "IPSR";
+
     pub const HELPERS: &[(&str, PcodeOpHelper)] = &[
         ("enableIRQinterrupts", enable_interrupts),
         ("disableIRQinterrupts", disable_interrupts),
+        ("isIRQinterruptsEnabled", is_interrupts_enabled),
+        ("getBasePriority", get_base_priority),
+        ("setBasePriority", set_base_priority),
+        ("getCurrentExceptionNumber", get_current_exception_number),
+        ("getMainStackPointer", get_main_stack_pointer),
+        ("setMainStackPointer", set_main_stack_pointer),
         // NEON
         ("VectorCompareEqual", vector_compare_equal),
         ("VectorPairwiseAdd", vector_pairwise_add),
@@ -725,6 +952,58 @@ pub mod arm {
         ("VectorCopyNarrow", vector_copy_narrow),
     ];
 
+    fn write_system_reg(cpu: &mut Cpu, name: &str, value: u64) {
+        if let Some(reg) = cpu.arch.sleigh.get_varnode(name) {
+            cpu.write_reg(reg, value);
+        }
+    }
+
+    fn read_system_reg(cpu: &mut Cpu, name: &str) -> u64 {
+        match cpu.arch.sleigh.get_varnode(name) {
+            Some(reg) => cpu.read_reg(reg),
+            None => 0,
+        }
+    }
+
+    fn enable_interrupts(cpu: &mut Cpu, _: VarNode, _: [Value; 2]) {
+        write_system_reg(cpu, PRIMASK, 0);
+    }
+
+    fn disable_interrupts(cpu: &mut Cpu, _: VarNode, _: [Value; 2]) {
+        write_system_reg(cpu, PRIMASK, 1);
+    }
+
+    fn is_interrupts_enabled(cpu: &mut Cpu, dst: VarNode, _: [Value; 2]) {
+        let enabled = read_system_reg(cpu, PRIMASK) & 1 == 0;
+        cpu.write_trunc(dst, enabled as u64);
+    }
+
+    fn get_base_priority(cpu: &mut Cpu, dst: VarNode, _: [Value; 2]) {
+        let value = read_system_reg(cpu, BASEPRI);
+        cpu.write_trunc(dst, value);
+    }
+
+    fn set_base_priority(cpu: &mut Cpu, _: VarNode, args: [Value; 2]) {
+        let value: u64 = cpu.read_dynamic(args[0]).zxt();
+        write_system_reg(cpu, BASEPRI, value & 0xff);
+    }
+
+    fn get_current_exception_number(cpu: &mut Cpu, dst: VarNode, _: [Value; 2]) {
+        let value = read_system_reg(cpu, IPSR);
+        cpu.write_trunc(dst, value);
+    }
+
+    // Only the main stack is modelled, so it is always the active stack.
+    fn get_main_stack_pointer(cpu: &mut Cpu, dst: VarNode, _: [Value; 2]) {
+        let sp = cpu.read_reg(cpu.arch.reg_sp);
+        cpu.write_trunc(dst, sp);
+    }
+
+    fn set_main_stack_pointer(cpu: &mut Cpu, _: VarNode, args: [Value; 2]) {
+        let value: u64 = cpu.read_dynamic(args[0]).zxt();
+        cpu.write_reg(cpu.arch.reg_sp, value);
+    }
+
     fn vector_compare_equal(cpu: &mut Cpu, dst: VarNode, args: [Value; 2]) {
         let esize = cpu.args[0] as u8;
         for i in 0..(dst.size / esize) {
@@ -11,6 +11,13 @@ pub enum VmExit {
     /// The VM exited because it reached a breakpoint.
     Breakpoint,
 
+    /// The VM exited because the loop with the given header address exceeded its iteration limit.
+    LoopLimit(u64),
+
+    /// The VM exited because it reached an instruction at the given address that was marked as
+    /// unreachable.
+    Unreachable(u64),
+
     /// The VM exited because the interrupt flag was set.
     Interrupted,
 
@@ -45,6 +52,8 @@ impl std::fmt::Debug for VmExit {
             Self::Running => write!(f, "Running"),
             Self::InstructionLimit => write!(f, "InstructionLimit"),
             Self::Breakpoint => write!(f, "Breakpoint"),
+            Self::LoopLimit(header) => write!(f, "LoopLimit(header={header:#0x})"),
+            Self::Unreachable(addr) => write!(f, "Unreachable(addr={addr:#0x})"),
             Self::Interrupted => write!(f, "Interrupt"),
             Self::Halt => write!(f, "Halt"),
             Self::Killed => write!(f, "Killed"),
@@ -1,3 +1,4 @@
+pub mod abi;
 pub mod cpu;
 pub mod debug_info;
 pub mod elf;
@@ -18,7 +19,7 @@ use ahash::{AHashMap as HashMap, AHashSet as HashSet};
 use crate::debug_info::{DebugInfo, SourceLocation};
 
 pub use crate::{
-    config::Config,
+    config::{Config, SmcPolicy, X86Config},
     cpu::{Arch, Cpu, CpuSnapshot, Exception, RegHandler, ShadowStack, ShadowStackEntry},
     exit::VmExit,
     lifter::BlockGroup,
@@ -38,11 +39,43 @@ pub struct BlockKey {
 /// Keeps track of all the code in the program that the emulator has discovered.
 #[derive(Default)]
 pub struct BlockTable {
+    /// The active translation for each block key. Modified using [BlockTable::insert] and
+    /// [BlockTable::retain] so changes can be rolled back by [BlockTable::restore].
     pub map: HashMap<BlockKey, BlockGroup>,
     pub blocks: Vec<lifter::Block>,
     pub disasm: HashMap<u64, String>,
     pub breakpoints: HashSet<u64>,
     pub modified: HashSet<usize>,
+
+    /// The targets of jump tables used by the exits of blocks, see [lifter::jump_table].
+    pub jump_table_targets: Vec<(lifter::BlockId, u64)>,
+
+    /// Incremented every time the code cache is flushed, block IDs from previous generations are
+    /// no longer valid.
+    pub generation: u64,
+
+    /// The modifications made to `map` since the code cache was last flushed (oldest first), used
+    /// to roll back the active translations in [BlockTable::restore].
+    journal: Vec<JournalEntry>,
+
+    /// The number of entries that have been discarded from the start of `journal`.
+    journal_base: usize,
+}
+
+/// The maximum number of modifications kept in the journal of a [BlockTable]. Restoring snapshots
+/// older than the journal only keeps translations that are still valid.
+const MAX_JOURNAL_LEN: usize = 0x10000;
+
+/// A modification to the active translations in a [BlockTable].
+#[derive(Copy, Clone)]
+struct JournalEntry {
+    key: BlockKey,
+
+    /// The group that was active for `key` before the modification.
+    prev: Option<BlockGroup>,
+
+    /// Whether the modification invalidated the group, preventing it from being reactivated.
+    invalidated: bool,
 }
 
 impl BlockTable {
@@ -51,6 +84,120 @@ impl BlockTable {
         self.blocks.clear();
         self.disasm.clear();
         self.modified.clear();
+        self.jump_table_targets.clear();
+        self.journal.clear();
+        self.journal_base = 0;
+        self.generation += 1;
+    }
+
+    /// Returns the (block, target) pairs of the jump tables recovered for the blocks in `group`.
+    pub fn group_jump_table_targets(&self, group: &BlockGroup) -> &[(lifter::BlockId, u64)] {
+        &self.jump_table_targets[group.jump_table_targets.0..group.jump_table_targets.1]
+    }
+
+    /// Activates `group` as the translation of the code at `key`.
+    pub fn insert(&mut self, key: BlockKey, group: BlockGroup) {
+        let prev = self.map.insert(key, group);
+        self.record(JournalEntry { key, prev, invalidated: false });
+    }
+
+    /// Invalidates every active translation where `keep` returns false, returning the groups that
+    /// were removed. Invalidated translations are never reactivated by [BlockTable::restore].
+    pub fn retain(&mut self, keep: impl FnMut(&BlockGroup) -> bool) -> Vec<BlockGroup> {
+        self.remove_where(keep, true)
+    }
+
+    fn remove_where(
+        &mut self,
+        mut keep: impl FnMut(&BlockGroup) -> bool,
+        invalidated: bool,
+    ) -> Vec<BlockGroup> {
+        let mut removed = vec![];
+        self.map.retain(|key, group| {
+            if keep(group) {
+                return true;
+            }
+            removed.push((*key, *group));
+            false
+        });
+        for &(key, group) in &removed {
+            self.record(JournalEntry { key, prev: Some(group), invalidated });
+        }
+        removed.into_iter().map(|(_, group)| group).collect()
+    }
+
+    fn record(&mut self, entry: JournalEntry) {
+        self.journal.push(entry);
+        if self.journal.len() > MAX_JOURNAL_LEN {
+            let discarded = self.journal.len() / 2;
+            self.journal.drain(..discarded);
+            self.journal_base += discarded;
+        }
+    }
+
+    /// Captures the set of active translations so that they can be reactivated by
+    /// [BlockTable::restore].
+    pub fn snapshot(&self) -> BlockTableSnapshot {
+        BlockTableSnapshot {
+            journal_len: self.journal_base + self.journal.len(),
+            generation: self.generation,
+        }
+    }
+
+    /// Rolls back the set of active translations to `snapshot`.
+    ///
+    /// Only the entries that were modified after the snapshot are updated. Translations created
+    /// after the snapshot are kept only if `is_valid` returns true, i.e. the code they were lifted
+    /// from is unchanged in the restored state. Translations that were active at the time of the
+    /// snapshot are reactivated, unless they were invalidated after it. Returns the groups that
+    /// were removed.
+    pub fn restore(
+        &mut self,
+        snapshot: &BlockTableSnapshot,
+        mut is_valid: impl FnMut(&BlockGroup) -> bool,
+    ) -> Vec<BlockGroup> {
+        // If the code cache was flushed after the snapshot, then the block IDs in the snapshot
+        // refer to blocks that no longer exist. If the journal no longer covers the snapshot, then
+        // the translations that were active at the time of the snapshot are unknown.
+        let start = snapshot
+            .journal_len
+            .checked_sub(self.journal_base)
+            .filter(|start| snapshot.generation == self.generation && *start <= self.journal.len());
+        let Some(start) = start
+        else {
+            return self.remove_where(is_valid, false);
+        };
+
+        // The group that was active for each modified key at the time of the snapshot, and
+        // whether the key was invalidated since.
+        let mut original: HashMap<BlockKey, (Option<BlockGroup>, bool)> = HashMap::new();
+        for entry in &self.journal[start..] {
+            let (_, invalidated) = original.entry(entry.key).or_insert((entry.prev, false));
+            *invalidated |= entry.invalidated;
+        }
+
+        let mut removed = vec![];
+        for (key, (prev, invalidated)) in original {
+            let current = self.map.get(&key).copied();
+            if current == prev {
+                continue;
+            }
+            let new = match current {
+                Some(group) if is_valid(&group) => continue,
+                _ => prev.filter(|_| !invalidated),
+            };
+            if new == current {
+                continue;
+            }
+            removed.extend(current);
+            match new {
+                Some(group) => self.map.insert(key, group),
+                None => self.map.remove(&key),
+            };
+            self.record(JournalEntry { key, prev: current, invalidated: false });
+        }
+
+        removed
     }
 
     pub fn get_info(&self, key: BlockKey) -> Option<BlockInfoRef<'_>> {
@@ -59,6 +206,13 @@ impl BlockTable {
     }
 }
 
+/// The position in the journal of a [BlockTable] at the time of a snapshot.
+#[derive(Clone, Default)]
+pub struct BlockTableSnapshot {
+    journal_len: usize,
+    generation: u64,
+}
+
 pub struct BlockInfoRef<'a> {
     group: BlockGroup,
     code: &'a BlockTable,
@@ -79,6 +233,62 @@ impl<'a> BlockInfoRef<'a> {
     pub fn blocks(&self) -> impl Iterator<Item = (usize, &'a lifter::Block)> + 'a {
         self.group.range().map(|i| (i, &self.code.blocks[i]))
     }
+
+    /// Return the group the info refers to.
+    pub fn group(&self) -> BlockGroup {
+        self.group
+    }
+
+    /// Return statistics about the code generated for the group.
+    pub fn stats(&self) -> BlockGroupStats {
+        let mut stats = BlockGroupStats {
+            guest_bytes: self.group.end - self.group.start,
+            blocks: self.group.range().len(),
+            ..BlockGroupStats::default()
+        };
+        for (_, block) in self.blocks() {
+            stats.guest_instructions += block.num_instructions as usize;
+            stats.breakpoints += block.breakpoints as usize;
+            for inst in &block.pcode.instructions {
+                match inst.op {
+                    pcode::Op::InstructionMarker => {}
+                    pcode::Op::Hook(_) | pcode::Op::HookIf(_) => stats.hooks += 1,
+                    _ => stats.pcode_ops += 1,
+                }
+            }
+        }
+        stats
+    }
+}
+
+/// Statistics about the code generated for a [BlockGroup], used for finding blocks that are
+/// expensive to translate or execute.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BlockGroupStats {
+    /// The number of guest instructions in the group.
+    pub guest_instructions: usize,
+
+    /// The number of bytes of guest code covered by the group.
+    pub guest_bytes: u64,
+
+    /// The number of blocks the group was split into by the lifter.
+    pub blocks: usize,
+
+    /// The number of p-code operations (excluding instruction markers and hooks).
+    pub pcode_ops: usize,
+
+    /// The number of hooks inserted into the group by instrumentation.
+    pub hooks: usize,
+
+    /// The number of breakpoints in the group.
+    pub breakpoints: usize,
+
+    /// The size of the host code generated by the JIT for the group, or `None` if the group has not
+    /// been compiled.
+    ///
+    /// Note: groups that are compiled together (e.g. after recompilation) report the size of the
+    /// combined code.
+    pub host_code_bytes: Option<u32>,
 }
 
 pub trait Environment {
@@ -93,6 +303,16 @@ pub trait Environment {
         u64::MAX
     }
 
+    /// Raises the target specific interrupt `interrupt`. The environment is responsible for
+    /// delivering the interrupt to the guest (e.g. by redirecting execution with
+    /// [ExceptionCode::ExternalAddr], or by marking it as pending until it is unmasked).
+    ///
+    /// Returns `false` if the environment does not support the interrupt.
+    fn raise_interrupt(&mut self, cpu: &mut Cpu, interrupt: u64) -> bool {
+        let _ = (cpu, interrupt);
+        false
+    }
+
     /// Get a direct reference to the debug info loaded for the current environment
     fn debug_info(&self) -> Option<&DebugInfo> {
         None
@@ -120,6 +340,19 @@ pub trait Environment {
 
     /// Restores the environment to the state of the snapshot.
     fn restore(&mut self, snapshot: &Box<dyn Any>);
+
+    /// Serializes the current state of the environment so it can be restored in another process by
+    /// [Environment::load_state]. Returns an error describing why the state cannot be saved if the
+    /// environment does not support this.
+    fn save_state(&mut self) -> Result<Vec<u8>, String> {
+        Err("environment does not support saving state".into())
+    }
+
+    /// Restores the environment to a state serialized by [Environment::save_state].
+    fn load_state(&mut self, data: &[u8]) -> Result<(), String> {
+        let _ = data;
+        Err("environment does not support loading saved state".into())
+    }
 }
 
 pub trait EnvironmentAny: Environment {
@@ -148,6 +381,12 @@ impl Environment for () {
         Box::new(())
     }
     fn restore(&mut self, _: &Box<dyn Any>) {}
+    fn save_state(&mut self) -> Result<Vec<u8>, String> {
+        Ok(vec![])
+    }
+    fn load_state(&mut self, _: &[u8]) -> Result<(), String> {
+        Ok(())
+    }
 }
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
@@ -159,10 +398,14 @@ pub enum ExceptionCode {
     Halt = 0x0002,
     Sleep = 0x0003,
     SoftwareBreakpoint = 0x0004,
+    LoopLimit = 0x0005,
+    Unreachable = 0x0006,
 
     Syscall = 0x0101,
     CpuStateChanged = 0x0102,
     DivisionException = 0x0103,
+    InvalidateICache = 0x0104,
+    AddressSpaceReplaced = 0x0105,
 
     ReadUnmapped = 0x0201,
     ReadPerm = 0x0202,
@@ -192,9 +435,14 @@ pub enum ExceptionCode {
     ShadowStackInvalid = 0x1008,
     InvalidTarget = 0x1009,
     UnimplementedOp = 0x100a,
+    LiftLimitExceeded = 0x100b,
+    UnknownMsr = 0x100c,
 
     ExternalAddr = 0x2001,
     Environment = 0x2002,
+    UnimplementedApi = 0x2003,
+    SanitizerError = 0x2004,
+    StackBufferOverflow = 0x2005,
 
     JitError = 0x3001,
     InternalError = 0x3002,
@@ -212,10 +460,14 @@ impl ExceptionCode {
             0x0002 => Self::Halt,
             0x0003 => Self::Sleep,
             0x0004 => Self::SoftwareBreakpoint,
+            0x0005 => Self::LoopLimit,
+            0x0006 => Self::Unreachable,
 
             0x0101 => Self::Syscall,
             0x0102 => Self::CpuStateChanged,
             0x0103 => Self::DivisionException,
+            0x0104 => Self::InvalidateICache,
+            0x0105 => Self::AddressSpaceReplaced,
 
             0x0201 => Self::ReadUnmapped,
             0x0202 => Self::ReadPerm,
@@ -245,9 +497,14 @@ impl ExceptionCode {
             0x1008 => Self::ShadowStackInvalid,
             0x1009 => Self::InvalidTarget,
             0x100a => Self::UnimplementedOp,
+            0x100b => Self::LiftLimitExceeded,
+            0x100c => Self::UnknownMsr,
 
             0x2001 => Self::ExternalAddr,
             0x2002 => Self::Environment,
+            0x2003 => Self::UnimplementedApi,
+            0x2004 => Self::SanitizerError,
+            0x2005 => Self::StackBufferOverflow,
 
             0x3001 => Self::JitError,
             0x3002 => Self::InternalError,
@@ -345,6 +602,7 @@ impl From<DecodeError> for ExceptionCode {
             DecodeError::BadAlignment => ExceptionCode::ExecUnaligned,
             DecodeError::DisassemblyChanged => ExceptionCode::SelfModifyingCode,
             DecodeError::UnimplementedOp => ExceptionCode::UnimplementedOp,
+            DecodeError::LimitExceeded { .. } => ExceptionCode::LiftLimitExceeded,
         }
     }
 }
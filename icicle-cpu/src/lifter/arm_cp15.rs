@@ -0,0 +1,482 @@
+//! Modelling of the ARMv7-A system control coprocessor (CP15).
+//!
+//! SLEIGH lifts `mcr`/`mrc` instructions to generic `coprocessor_moveto`/`coprocessor_movefromRt`
+//! operations, or to named operations (e.g. `coproc_movefrom_Main_ID`) for commonly used CP15
+//! registers. Accesses to CP15 are resolved at lift time and replaced with reads and writes of
+//! custom registers, constant ID register values, or removed entirely (e.g. cache and TLB
+//! maintenance operations which have no effect under emulation).
+
+use ahash::AHashMap as HashMap;
+use pcode::{Inputs, Value, VarNode};
+
+use crate::{
+    Arch, Cpu,
+    lifter::{self, BlockState, PcodeOpInjector},
+};
+
+/// The coprocessor number of the system control coprocessor.
+const CP15: u64 = 15;
+
+/// Identifies a CP15 register by `(CRn, opc1, CRm, opc2)`.
+pub type RegId = (u8, u8, u8, u8);
+
+/// Read-only identification registers (values are based on a Cortex-A9).
+pub const ID_REGS: &[(&str, RegId, u32)] = &[
+    ("MIDR", (0, 0, 0, 0), 0x413f_c090),
+    ("CTR", (0, 0, 0, 1), 0x8333_8003),
+    ("TCMTR", (0, 0, 0, 2), 0x0000_0000),
+    ("TLBTR", (0, 0, 0, 3), 0x0000_0000),
+    ("MPIDR", (0, 0, 0, 5), 0x8000_0000),
+    ("REVIDR", (0, 0, 0, 6), 0x0000_0000),
+    ("ID_PFR0", (0, 0, 1, 0), 0x0000_1231),
+    ("ID_PFR1", (0, 0, 1, 1), 0x0000_0011),
+    ("ID_DFR0", (0, 0, 1, 2), 0x0001_0444),
+    ("ID_AFR0", (0, 0, 1, 3), 0x0000_0000),
+    ("ID_MMFR0", (0, 0, 1, 4), 0x0010_0103),
+    ("ID_MMFR1", (0, 0, 1, 5), 0x2000_0000),
+    ("ID_MMFR2", (0, 0, 1, 6), 0x0123_0000),
+    ("ID_MMFR3", (0, 0, 1, 7), 0x0010_2111),
+    ("ID_ISAR0", (0, 0, 2, 0), 0x0010_1111),
+    ("ID_ISAR1", (0, 0, 2, 1), 0x1311_2111),
+    ("ID_ISAR2", (0, 0, 2, 2), 0x2123_2041),
+    ("ID_ISAR3", (0, 0, 2, 3), 0x1111_2131),
+    ("ID_ISAR4", (0, 0, 2, 4), 0x0001_1142),
+    ("ID_ISAR5", (0, 0, 2, 5), 0x0000_0000),
+    ("CCSIDR", (0, 1, 0, 0), 0xe00f_e019),
+    ("CLIDR", (0, 1, 0, 1), 0x0a20_0023),
+    ("AIDR", (0, 1, 0, 7), 0x0000_0000),
+];
+
+/// Registers that are backed by storage.
+pub const SYSTEM_REGS: &[(&str, RegId)] = &[
+    ("CSSELR", (0, 2, 0, 0)),
+    ("SCTLR", (1, 0, 0, 0)),
+    ("ACTLR", (1, 0, 0, 1)),
+    ("CPACR", (1, 0, 0, 2)),
+    ("TTBR0", (2, 0, 0, 0)),
+    ("TTBR1", (2, 0, 0, 1)),
+    ("TTBCR", (2, 0, 0, 2)),
+    ("DACR", (3, 0, 0, 0)),
+    ("DFSR", (5, 0, 0, 0)),
+    ("IFSR", (5, 0, 0, 1)),
+    ("DFAR", (6, 0, 0, 0)),
+    ("IFAR", (6, 0, 0, 2)),
+    ("PAR", (7, 0, 4, 0)),
+    ("PRRR", (10, 0, 2, 0)),
+    ("NMRR", (10, 0, 2, 1)),
+    ("VBAR", (12, 0, 0, 0)),
+    ("MVBAR", (12, 0, 0, 1)),
+    ("CONTEXTIDR", (13, 0, 0, 1)),
+    ("TPIDRURW", (13, 0, 0, 2)),
+    ("TPIDRURO", (13, 0, 0, 3)),
+    ("TPIDRPRW", (13, 0, 0, 4)),
+];
+
+/// Instruction cache invalidation operations that apply to the entire cache (ICIALLUIS, ICIALLU).
+const ICACHE_INVALIDATE_ALL_OPS: &[RegId] = &[(7, 0, 1, 0), (7, 0, 5, 0)];
+
+/// Instruction cache invalidation by virtual address (ICIMVAU).
+const ICACHE_INVALIDATE_MVA: RegId = (7, 0, 5, 1);
+
+/// Address translation operations (ATS1CPR, ATS1CPW, ATS1CUR, ATS1CUW).
+const ADDRESS_TRANSLATION_OPS: &[RegId] = &[(7, 0, 8, 0), (7, 0, 8, 1), (7, 0, 8, 2), (7, 0, 8, 3)];
+
+/// The named operations used by SLEIGH for CP15 accesses (as both `coproc_moveto_{name}` and
+/// `coproc_movefrom_{name}`), and the leading fields of the `(CRn, opc1, CRm, opc2)` encoding they
+/// match. Any remaining fields are passed as arguments to the operation in reverse order.
+const NAMED_OPS: &[(&str, &[u8])] = &[
+    ("Main_ID", &[0, 0, 0, 0]),
+    ("Cache_Type", &[0, 0, 0, 1]),
+    ("TCM_Status", &[0, 0, 0, 2]),
+    ("TLB_Type", &[0, 0, 0, 3]),
+    ("Control", &[1, 0, 0, 0]),
+    ("Auxiliary_Control", &[1, 0, 0, 1]),
+    ("Coprocessor_Access_Control", &[1, 0, 0, 2]),
+    ("Secure_Configuration", &[1, 0, 1, 0]),
+    ("Secure_Debug_Enable", &[1, 0, 1, 1]),
+    ("NonSecure_Access_Control", &[1, 0, 1, 2]),
+    ("Translation_table_base_0", &[2, 0, 0, 0]),
+    ("Translation_table_base_1", &[2, 0, 0, 1]),
+    ("Translation_table_control", &[2, 0, 0, 2]),
+    ("Domain_Access_Control", &[3, 0, 0, 0]),
+    ("Fault_Address", &[6, 0, 0, 1]),
+    ("Wait_for_interrupt", &[7, 0, 0, 4]),
+    ("Invalidate_Entire_Instruction", &[7, 0, 5, 0]),
+    ("Invalidate_Instruction_Cache_by_MVA", &[7, 0, 5, 1]),
+    ("Flush_Prefetch_Buffer", &[7, 0, 5, 4]),
+    ("Invalidate_Entire_Data_cache", &[7, 0, 6, 0]),
+    ("Invalidate_Entire_Data_by_MVA", &[7, 0, 6, 1]),
+    ("Invalidate_Entire_Data_by_Index", &[7, 0, 6, 2]),
+    ("Clean_Entire_Data_Cache", &[7, 0, 10, 0]),
+    ("Clean_Data_Cache_by_MVA", &[7, 0, 10, 1]),
+    ("Clean_Data_Cache_by_Index", &[7, 0, 10, 2]),
+    ("Data_Synchronization", &[7, 0, 10, 4]),
+    ("Data_Memory_Barrier", &[7, 0, 10, 5]),
+    ("Invalidate_Entire_Data_Cache", &[7, 0, 14, 0]),
+    ("Invalidate_Data_Cache_by_MVA", &[7, 0, 14, 1]),
+    ("Invalidate_unified_TLB_unlocked", &[8, 0, 7, 0]),
+    ("Invalidate_unified_TLB_by_MVA", &[8, 0, 7, 1]),
+    ("Invalidate_unified_TLB_by_ASID_match", &[8, 0, 7, 2]),
+    ("FCSE_PID", &[13, 0, 0, 0]),
+    ("Context_ID", &[13, 0, 0, 1]),
+    ("User_RW_Thread_and_Process_ID", &[13, 0, 0, 2]),
+    ("User_R_Thread_and_Process_ID", &[13, 0, 0, 3]),
+    ("Privileged_only_Thread_and_Process_ID", &[13, 0, 0, 4]),
+    ("Peripherial_Port_Memory_Remap", &[15, 0, 2, 4]),
+    ("Feature_Identification", &[0, 0, 1]),
+    ("ISA_Feature_Identification", &[0, 0, 2]),
+    ("Peripheral_Port_Memory_Remap", &[0, 2, 4]),
+    ("Control_registers", &[1, 0, 0]),
+    ("Security_world_control", &[1, 0, 1]),
+    ("Translation_table", &[2, 0, 0]),
+    ("Instruction_cache", &[7, 0, 5]),
+    ("Data_cache_operations", &[7, 0, 10]),
+    ("Identification_registers", &[0, 0]),
+    ("Peripheral_System", &[15]),
+];
+
+/// Named operations that SLEIGH only defines for reads.
+const NAMED_READ_OPS: &[(&str, &[u8])] = &[
+    ("Data_Fault_Status", &[5, 0, 0, 0]),
+    ("Instruction_Fault_Status", &[5, 0, 0, 1]),
+    ("Instruction_Fault_Address", &[6, 0, 0, 2]),
+];
+
+/// Named operations that SLEIGH only defines for writes. (`coproc_moveto_Instruction_Fault` is used
+/// for both IFSR and IFAR, so is not included).
+const NAMED_WRITE_OPS: &[(&str, &[u8])] = &[("Data_Fault_Status", &[5, 0, 0, 1])];
+
+#[derive(Clone, Copy, Debug)]
+enum Cp15Reg {
+    /// A read-only register with a constant value.
+    Const(u32),
+
+    /// A register backed by a varnode.
+    Var(VarNode),
+
+    /// An address translation operation, the result of the translation is written to `PAR`.
+    AddressTranslation(VarNode),
+
+    /// Instruction cache invalidation, either by address or of the entire cache.
+    InvalidateICache { all: bool },
+
+    /// An access with no observable effect (e.g. cache/TLB maintenance, barriers and performance
+    /// monitors).
+    Ignored,
+}
+
+#[derive(Clone)]
+struct Cp15 {
+    regs: HashMap<RegId, Cp15Reg>,
+
+    /// The varnodes for `cr0`-`cr15` used by SLEIGH for the `CRn` and `CRm` operands.
+    cr: Vec<VarNode>,
+}
+
+impl Cp15 {
+    fn new(cpu: &mut Cpu) -> Option<Self> {
+        let cr = (0..16)
+            .map(|i| cpu.arch.sleigh.get_varnode(&format!("cr{i}")))
+            .collect::<Option<Vec<_>>>()?;
+
+        let mut regs = HashMap::new();
+        for &(_, id, value) in ID_REGS {
+            regs.insert(id, Cp15Reg::Const(value));
+        }
+        for &(name, id) in SYSTEM_REGS {
+            let var = match cpu.arch.sleigh.get_varnode(name) {
+                Some(var) => var,
+                None => cpu.arch.sleigh.add_custom_reg(name, 4)?,
+            };
+            regs.insert(id, Cp15Reg::Var(var));
+        }
+        let Some(&Cp15Reg::Var(par)) = regs.get(&(7, 0, 4, 0))
+        else {
+            return None;
+        };
+        for id in ADDRESS_TRANSLATION_OPS {
+            regs.insert(*id, Cp15Reg::AddressTranslation(par));
+        }
+        if lifter::pcodeops::reports_icache_invalidation(cpu) {
+            for id in ICACHE_INVALIDATE_ALL_OPS {
+                regs.insert(*id, Cp15Reg::InvalidateICache { all: true });
+            }
+            regs.insert(ICACHE_INVALIDATE_MVA, Cp15Reg::InvalidateICache { all: false });
+        }
+
+        Some(Self { regs, cr })
+    }
+
+    fn lookup(&self, id: RegId) -> Cp15Reg {
+        match self.regs.get(&id) {
+            Some(reg) => *reg,
+            // c7: cache maintenance and barriers, c8: TLB maintenance, c9: performance monitors.
+            None if matches!(id.0, 7..=9) => Cp15Reg::Ignored,
+            None => {
+                tracing::warn!("unknown CP15 register: {id:?} (treating as RAZ/WI)");
+                Cp15Reg::Ignored
+            }
+        }
+    }
+
+    fn cr_index(&self, value: Value) -> Option<u8> {
+        let Value::Var(var) = value
+        else {
+            return None;
+        };
+        self.cr.iter().position(|cr| cr.id == var.id).map(|i| i as u8)
+    }
+
+    /// Resolves the register operands of a generic coprocessor operation. Returns `None` if the
+    /// operation does not target CP15.
+    fn resolve(
+        &self,
+        state: &BlockState,
+        inputs: Inputs,
+        opc2: Value,
+        crn: Value,
+        crm: Value,
+    ) -> Option<RegId> {
+        let [cpn, opc1] = inputs.get();
+        if resolve_const(&state.pcode, cpn)? != CP15 {
+            return None;
+        }
+        let opc1 = resolve_const(&state.pcode, opc1)? as u8;
+        let opc2 = resolve_const(&state.pcode, opc2)? as u8;
+        Some((self.cr_index(crn)?, opc1, self.cr_index(crm)?, opc2))
+    }
+
+    /// Resolves the register accessed by a named operation matching the leading `fields` of the
+    /// encoding, where `args` are the values of the remaining fields in reverse order.
+    fn resolve_named(state: &BlockState, fields: &[u8], args: &[Value]) -> Option<RegId> {
+        let mut id = [0; 4];
+        id[..fields.len()].copy_from_slice(fields);
+        for (field, arg) in id[fields.len()..].iter_mut().rev().zip(args) {
+            *field = resolve_const(&state.pcode, *arg)? as u8;
+        }
+        Some((id[0], id[1], id[2], id[3]))
+    }
+
+    /// Handles: `coprocessor_moveto(cpn, opc1, opc2, Rt, CRn, CRm)`
+    ///
+    /// Returns `None` if the operation was not handled, otherwise returns whether the operation
+    /// ends the current block.
+    fn move_to(&self, arch: &Arch, state: &mut BlockState, inputs: Inputs) -> Option<bool> {
+        let [opc2, rt, crn, crm] = get_args(&state.pcode)?;
+        let id = self.resolve(state, inputs, opc2, crn, crm)?;
+        Some(self.write(arch, state, id, rt))
+    }
+
+    /// Handles: `coproc_moveto_{name}(Rt, ...)`
+    fn named_move_to(
+        &self,
+        arch: &Arch,
+        state: &mut BlockState,
+        fields: &[u8],
+        args: &[Value],
+    ) -> Option<bool> {
+        let (&rt, args) = args.split_first()?;
+        let id = Self::resolve_named(state, fields, args)?;
+        Some(self.write(arch, state, id, rt))
+    }
+
+    /// Writes `rt` to the register identified by `id`, returning whether the write ends the
+    /// current block.
+    fn write(&self, arch: &Arch, state: &mut BlockState, id: RegId, rt: Value) -> bool {
+        match self.lookup(id) {
+            Cp15Reg::Var(var) => state.pcode.push(var.copy_from(rt)),
+            Cp15Reg::AddressTranslation(par) => {
+                // There is no MMU, so all addresses translate to themselves.
+                state.pcode.push((par, pcode::Op::IntAnd, rt, 0xffff_f000_u32));
+            }
+            Cp15Reg::InvalidateICache { all } => {
+                let addr = (!all).then_some(rt);
+                return lifter::pcodeops::gen_icache_invalidate(arch, addr, state);
+            }
+            Cp15Reg::Const(_) => tracing::debug!("ignoring write to read-only register: {id:?}"),
+            Cp15Reg::Ignored => {}
+        }
+        false
+    }
+
+    /// Handles: `Rt = coprocessor_movefromRt(cpn, opc1, opc2, CRn, CRm)`
+    fn move_from(&self, state: &mut BlockState, inputs: Inputs, dst: VarNode) -> Option<bool> {
+        let [opc2, crn, crm] = get_args(&state.pcode)?;
+        let id = self.resolve(state, inputs, opc2, crn, crm)?;
+        self.read(state, id, dst);
+        Some(false)
+    }
+
+    /// Handles: `Rt = coproc_movefrom_{name}(...)`
+    fn named_move_from(
+        &self,
+        state: &mut BlockState,
+        fields: &[u8],
+        args: &[Value],
+        dst: VarNode,
+    ) -> Option<bool> {
+        let id = Self::resolve_named(state, fields, args)?;
+        self.read(state, id, dst);
+        Some(false)
+    }
+
+    /// Reads the register identified by `id` into `dst`.
+    fn read(&self, state: &mut BlockState, id: RegId, dst: VarNode) {
+        match self.lookup(id) {
+            Cp15Reg::Var(var) => state.pcode.push(dst.copy_from(var)),
+            Cp15Reg::Const(value) => state.pcode.push(dst.copy_from(value)),
+            Cp15Reg::AddressTranslation(_)
+            | Cp15Reg::InvalidateICache { .. }
+            | Cp15Reg::Ignored => state.pcode.push(dst.copy_from(Value::Const(0, dst.size))),
+        }
+    }
+}
+
+struct Cp15Injector {
+    cp15: Cp15,
+    is_read: bool,
+}
+
+impl PcodeOpInjector for Cp15Injector {
+    fn inject_ops(
+        &mut self,
+        arch: &Arch,
+        id: pcode::PcodeOpId,
+        inputs: Inputs,
+        output: VarNode,
+        state: &mut BlockState,
+    ) -> bool {
+        let result = match self.is_read {
+            true => self.cp15.move_from(state, inputs, output),
+            false => self.cp15.move_to(arch, state, inputs),
+        };
+        match result {
+            Some(block_exit) => block_exit,
+            None => {
+                // Not a CP15 access (or an encoding we were unable to decode), so keep the
+                // original operation.
+                state.pcode.push((output, pcode::Op::PcodeOp(id), inputs));
+                false
+            }
+        }
+    }
+}
+
+/// An injector for a named CP15 operation.
+struct NamedCp15Injector {
+    cp15: Cp15,
+    is_read: bool,
+    /// The leading fields of the encoding matched by the operation.
+    fields: &'static [u8],
+}
+
+impl PcodeOpInjector for NamedCp15Injector {
+    fn inject_ops(
+        &mut self,
+        arch: &Arch,
+        id: pcode::PcodeOpId,
+        inputs: Inputs,
+        output: VarNode,
+        state: &mut BlockState,
+    ) -> bool {
+        // The value written (for writes) followed by the remaining fields of the encoding.
+        let num_args = usize::from(!self.is_read) + (4 - self.fields.len());
+        let result = get_op_args(&state.pcode, inputs, num_args).and_then(|args| {
+            match self.is_read {
+                true => self.cp15.named_move_from(state, self.fields, &args, output),
+                false => self.cp15.named_move_to(arch, state, self.fields, &args),
+            }
+        });
+        match result {
+            Some(block_exit) => block_exit,
+            None => {
+                state.pcode.push((output, pcode::Op::PcodeOp(id), inputs));
+                false
+            }
+        }
+    }
+}
+
+pub fn get_injectors(
+    cpu: &mut Cpu,
+    injectors: &mut HashMap<pcode::PcodeOpId, Box<dyn PcodeOpInjector>>,
+) {
+    let move_to = cpu.arch.sleigh.get_userop("coprocessor_moveto");
+    let move_from = cpu.arch.sleigh.get_userop("coprocessor_movefromRt");
+    if move_to.is_none() && move_from.is_none() {
+        return;
+    }
+
+    let Some(cp15) = Cp15::new(cpu)
+    else {
+        tracing::warn!("SLEIGH specification is missing coprocessor registers, CP15 not modelled");
+        return;
+    };
+
+    if let Some(id) = move_to {
+        injectors.insert(id, Box::new(Cp15Injector { cp15: cp15.clone(), is_read: false }));
+    }
+    if let Some(id) = move_from {
+        injectors.insert(id, Box::new(Cp15Injector { cp15: cp15.clone(), is_read: true }));
+    }
+
+    let named_ops = NAMED_OPS
+        .iter()
+        .flat_map(|&(name, fields)| [(name, fields, false), (name, fields, true)])
+        .chain(NAMED_READ_OPS.iter().map(|&(name, fields)| (name, fields, true)))
+        .chain(NAMED_WRITE_OPS.iter().map(|&(name, fields)| (name, fields, false)));
+    for (name, fields, is_read) in named_ops {
+        let prefix = if is_read { "coproc_movefrom" } else { "coproc_moveto" };
+        if let Some(id) = cpu.arch.sleigh.get_userop(&format!("{prefix}_{name}")) {
+            let injector = NamedCp15Injector { cp15: cp15.clone(), is_read, fields };
+            injectors.insert(id, Box::new(injector));
+        }
+    }
+}
+
+/// Finds the values of the most recent `Arg` operations in `block`.
+fn get_args<const N: usize>(block: &pcode::Block) -> Option<[Value; N]> {
+    let mut args = [None; N];
+    for inst in block.instructions.iter().rev() {
+        if let pcode::Op::Arg(i) = inst.op {
+            if let Some(slot @ None) = args.get_mut(i as usize) {
+                *slot = Some(inst.inputs.first());
+            }
+        }
+        if args.iter().all(Option::is_some) {
+            break;
+        }
+    }
+    let mut out = [Value::Const(0, 0); N];
+    for (out, arg) in out.iter_mut().zip(args) {
+        *out = arg?;
+    }
+    Some(out)
+}
+
+/// Gets the first `n` arguments of an operation. The first two arguments are the inputs of the
+/// operation, and any additional arguments are passed using `Arg` operations.
+fn get_op_args(block: &pcode::Block, inputs: Inputs, n: usize) -> Option<Vec<Value>> {
+    let mut args = vec![inputs.first(), inputs.second()];
+    args.truncate(n);
+    match n.saturating_sub(2) {
+        0 => {}
+        1 => args.extend(get_args::<1>(block)?),
+        _ => args.extend(get_args::<2>(block)?),
+    }
+    Some(args)
+}
+
+/// Resolves `value` to a constant, either directly or by finding the most recent copy of a
+/// constant to it in `block`.
+fn resolve_const(block: &pcode::Block, value: Value) -> Option<u64> {
+    let var = match value {
+        Value::Const(x, _) => return Some(x),
+        Value::Var(var) => var,
+    };
+    let inst = block.instructions.iter().rev().find(|inst| inst.output == var)?;
+    match (inst.op, inst.inputs.first()) {
+        (pcode::Op::Copy, Value::Const(x, _)) => Some(x),
+        _ => None,
+    }
+}
@@ -0,0 +1,208 @@
+//! Recovery of the targets of indirect jumps through jump tables (e.g. generated for `switch`
+//! statements).
+//!
+//! For each block that exits with an indirect jump, the p-code of the block is evaluated backwards
+//! from the jump target to find a value of the form:
+//!
+//! ```text
+//! ext(load(base + index * entry_size)) * scale + offset
+//! ```
+//!
+//! where `index` is unknown, and `base`, `scale` and `offset` are constants. This covers tables of
+//! absolute addresses (e.g. `jmp [table + rax*8]`), tables of offsets relative to a base address
+//! (e.g. `movsxd rax, [rdx + rax*4]; add rax, rdx; jmp rax`), and scaled offset tables (e.g. ARM
+//! `tbb`/`tbh`).
+//!
+//! The bound of the index is usually checked in a different block, so entries are read from the
+//! table until one of them does not point to executable memory. As a result, data following the
+//! table may occasionally be reported as additional targets. Tables are only read from regular
+//! memory, to avoid triggering the side effects of memory mapped IO.
+
+use crate::{
+    BlockTable,
+    lifter::{Block, BlockExit, BlockId, InstructionSource, Target},
+};
+
+/// The maximum number of entries to read from a single jump table.
+const MAX_ENTRIES: u64 = 512;
+
+/// The maximum number of operations to follow when evaluating the jump target.
+const MAX_DEPTH: usize = 16;
+
+/// The maximum number of targets kept in [BlockTable::jump_table_targets]. Targets are recovered
+/// again whenever a group is restored from the translation cache, so once this is reached no more
+/// targets are recovered until the code cache is flushed.
+const MAX_TOTAL_TARGETS: usize = 0x10_0000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Expr {
+    Const(u64),
+
+    /// `index * scale + offset` for an unknown `index`.
+    Index { scale: u64, offset: u64 },
+
+    /// `ext(load(base + index * size)) * scale + offset`.
+    Table { base: u64, size: u8, signed: bool, scale: u64, offset: u64 },
+}
+
+impl Expr {
+    fn add(self, value: u64) -> Self {
+        match self {
+            Self::Const(x) => Self::Const(x.wrapping_add(value)),
+            Self::Index { scale, offset } => {
+                Self::Index { scale, offset: offset.wrapping_add(value) }
+            }
+            Self::Table { base, size, signed, scale, offset } => {
+                Self::Table { base, size, signed, scale, offset: offset.wrapping_add(value) }
+            }
+        }
+    }
+
+    fn mul(self, value: u64) -> Self {
+        match self {
+            Self::Const(x) => Self::Const(x.wrapping_mul(value)),
+            Self::Index { scale, offset } => Self::Index {
+                scale: scale.wrapping_mul(value),
+                offset: offset.wrapping_mul(value),
+            },
+            Self::Table { base, size, signed, scale, offset } => Self::Table {
+                base,
+                size,
+                signed,
+                scale: scale.wrapping_mul(value),
+                offset: offset.wrapping_mul(value),
+            },
+        }
+    }
+}
+
+/// Recovers jump table targets for all blocks in `blocks`, adding them to
+/// [BlockTable::jump_table_targets]. Returns the range of the targets that were added.
+pub fn recover<S>(src: &mut S, code: &mut BlockTable, blocks: (BlockId, BlockId)) -> (usize, usize)
+where
+    S: InstructionSource,
+{
+    let start = code.jump_table_targets.len();
+    for id in blocks.0..blocks.1 {
+        if code.jump_table_targets.len() >= MAX_TOTAL_TARGETS {
+            tracing::debug!("jump table target limit reached, skipping recovery");
+            break;
+        }
+        let block = &code.blocks[id];
+        for target in recover_block_targets(src, block) {
+            code.jump_table_targets.push((id, target));
+        }
+    }
+    (start, code.jump_table_targets.len())
+}
+
+/// Returns the targets of the jump table used by the exit of `block`, or an empty list if the
+/// block does not exit using a jump table.
+pub fn recover_block_targets<S>(src: &mut S, block: &Block) -> Vec<u64>
+where
+    S: InstructionSource,
+{
+    let BlockExit::Jump { target: Target::External(pcode::Value::Var(var)) } = block.exit
+    else {
+        return vec![];
+    };
+    let Some(Expr::Table { base, size, signed, scale, offset }) =
+        eval_var(&block.pcode.instructions, var, MAX_DEPTH)
+    else {
+        return vec![];
+    };
+    if !matches!(size, 1 | 2 | 4 | 8) {
+        return vec![];
+    }
+    // Tables on word addressed targets (e.g. AVR) are read from a different address space to the
+    // one used for code, which is not modelled here.
+    if src.arch().sleigh.word_size() != 1 {
+        return vec![];
+    }
+
+    let big_endian = src.arch().sleigh.big_endian;
+    let mut targets = vec![];
+    for i in 0..MAX_ENTRIES {
+        let mut buf = [0; 8];
+        if !src.read_data(base.wrapping_add(i * size as u64), &mut buf[..size as usize]) {
+            break;
+        }
+        let mut entry = match big_endian {
+            true => u64::from_be_bytes(buf) >> (64 - size as u64 * 8),
+            false => u64::from_le_bytes(buf),
+        };
+        if signed {
+            entry = pcode::sxt64(entry, size as u64 * 8);
+        }
+
+        let target = entry.wrapping_mul(scale).wrapping_add(offset);
+        let target = target & pcode::mask(var.size as u64 * 8);
+        if !src.ensure_exec(target, 1) {
+            break;
+        }
+        if !targets.contains(&target) {
+            targets.push(target);
+        }
+    }
+    targets
+}
+
+/// Evaluates the value of `var` at the end of `instructions`.
+fn eval_var(
+    instructions: &[pcode::Instruction],
+    var: pcode::VarNode,
+    depth: usize,
+) -> Option<Expr> {
+    let Some(pos) = instructions.iter().rposition(|inst| inst.output.id == var.id)
+    else {
+        // The value was defined before the block.
+        return Some(Expr::Index { scale: 1, offset: 0 });
+    };
+    let inst = instructions[pos];
+    if inst.output != var || depth == 0 {
+        return Some(Expr::Index { scale: 1, offset: 0 });
+    }
+
+    let defs = &instructions[..pos];
+    let eval = |value: pcode::Value| match value {
+        pcode::Value::Const(x, _) => Some(Expr::Const(x)),
+        pcode::Value::Var(var) => eval_var(defs, var, depth - 1),
+    };
+    let [a, b] = inst.inputs.get();
+
+    Some(match inst.op {
+        pcode::Op::Copy | pcode::Op::ZeroExtend => eval(a)?,
+        pcode::Op::SignExtend => match eval(a)? {
+            Expr::Table { base, size, scale: 1, offset: 0, .. } => {
+                Expr::Table { base, size, signed: true, scale: 1, offset: 0 }
+            }
+            Expr::Table { .. } => return None,
+            expr => expr,
+        },
+        pcode::Op::IntAdd => match (eval(a)?, eval(b)?) {
+            (expr, Expr::Const(x)) | (Expr::Const(x), expr) => expr.add(x),
+            _ => return None,
+        },
+        pcode::Op::IntSub => match (eval(a)?, eval(b)?) {
+            (expr, Expr::Const(x)) => expr.add(x.wrapping_neg()),
+            _ => return None,
+        },
+        pcode::Op::IntMul => match (eval(a)?, eval(b)?) {
+            (expr, Expr::Const(x)) | (Expr::Const(x), expr) => expr.mul(x),
+            _ => return None,
+        },
+        pcode::Op::IntLeft => match (eval(a)?, eval(b)?) {
+            (expr, Expr::Const(x)) if x < 64 => expr.mul(1 << x),
+            _ => return None,
+        },
+        pcode::Op::Load(_) => match eval(a)? {
+            Expr::Index { scale, offset } if scale == inst.output.size as u64 && offset != 0 => {
+                let size = inst.output.size;
+                Expr::Table { base: offset, size, signed: false, scale: 1, offset: 0 }
+            }
+            _ => Expr::Index { scale: 1, offset: 0 },
+        },
+        // Any other operation produces a value we can't reason about, which could be the index.
+        _ => Expr::Index { scale: 1, offset: 0 },
+    })
+}
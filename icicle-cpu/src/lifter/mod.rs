@@ -1,6 +1,10 @@
+pub mod arm_cp15;
+pub mod jump_table;
 pub mod msp430;
 pub mod optimize;
 pub mod pcodeops;
+pub mod riscv;
+pub mod timing;
 
 use ahash::AHashMap as HashMap;
 
@@ -14,6 +18,10 @@ pub trait InstructionSource {
     fn arch(&self) -> &Arch;
     fn read_bytes(&mut self, vaddr: u64, buf: &mut [u8]);
     fn ensure_exec(&mut self, vaddr: u64, size: usize) -> bool;
+
+    /// Reads data (e.g. a jump table) at `vaddr` without side effects, returning `false` if the
+    /// memory is not regular memory (e.g. it is unmapped or memory mapped IO).
+    fn read_data(&mut self, vaddr: u64, buf: &mut [u8]) -> bool;
 }
 
 impl InstructionSource for crate::Cpu {
@@ -28,6 +36,11 @@ impl InstructionSource for crate::Cpu {
     fn ensure_exec(&mut self, vaddr: u64, len: usize) -> bool {
         self.mem.ensure_executable(vaddr, len as u64)
     }
+
+    fn read_data(&mut self, vaddr: u64, buf: &mut [u8]) -> bool {
+        self.mem.is_regular_region(vaddr, buf.len() as u64)
+            && self.mem.read_bytes(vaddr, buf, icicle_mem::perm::NONE).is_ok()
+    }
 }
 
 pub struct InstructionLifter {
@@ -55,6 +68,19 @@ pub enum DecodeError {
     DisassemblyChanged,
     UnimplementedOp,
     LifterError(sleigh_runtime::LifterError),
+
+    /// Lifting the block starting at `addr` exceeded one of the limits configured in [Settings].
+    LimitExceeded { addr: u64, limit: LiftLimit },
+}
+
+/// A limit on the amount of work done by the lifter for a single block.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum LiftLimit {
+    /// The instruction generates more p-code operations than [Settings::max_pcode_ops_per_block].
+    PcodeOps,
+
+    /// Lifting the instruction took longer than [Settings::max_lift_time].
+    Time,
 }
 
 impl From<sleigh_runtime::LifterError> for DecodeError {
@@ -141,8 +167,13 @@ impl InstructionLifter {
     where
         S: InstructionSource,
     {
+        // For word addressed code (e.g. AVR) `vaddr` is in words, so convert it to the address of
+        // the first byte of the instruction.
+        let word_size = src.arch().sleigh.word_size();
+        let byte_addr = vaddr * word_size;
+
         let alignment_mask = !(src.arch().sleigh.alignment as u64 - 1);
-        if vaddr & alignment_mask != vaddr {
+        if byte_addr & alignment_mask != byte_addr {
             return Err(DecodeError::BadAlignment);
         }
 
@@ -153,14 +184,14 @@ impl InstructionLifter {
         // Read the instruction at `vaddr` into `buf`, we ignore errors here because we want to be
         // able to handle instructions that occur on permission boundaries, and we don't currently
         // know the length of the instruction.
-        src.read_bytes(vaddr, &mut buf);
+        src.read_bytes(byte_addr, &mut buf);
 
         self.decoder.set_inst(vaddr, &buf);
 
         if self.decoder.decode_into(&src.arch().sleigh, &mut self.decoded).is_none() {
             // If the decoding fails we need to check if it failed because the memory is not
             // executable, or because the instruction is actually invalid.
-            return if !src.ensure_exec(vaddr, 1) {
+            return if !src.ensure_exec(byte_addr, 1) {
                 Err(DecodeError::NonExecutableMemory)
             }
             else {
@@ -169,8 +200,8 @@ impl InstructionLifter {
         }
 
         // Now that we know the length of the instruction, ensure the region is executable.
-        let len = self.decoded.num_bytes() as usize;
-        let is_executable = len <= buf.len() && src.ensure_exec(vaddr, len);
+        let len = (self.decoded.num_bytes() * word_size) as usize;
+        let is_executable = len <= buf.len() && src.ensure_exec(byte_addr, len);
         if !is_executable {
             return Err(DecodeError::NonExecutableMemory);
         }
@@ -511,7 +542,7 @@ const UNKNOWN_BLOCK: usize = 0xbadbadbadbad;
 const NEXT_ADDR_LABEL: u16 = u16::MAX;
 
 /// Represents a group of blocks that are connected with internal jumps.
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct BlockGroup {
     /// The range of blocks that this entry covers.
     pub blocks: (BlockId, BlockId),
@@ -521,6 +552,9 @@ pub struct BlockGroup {
 
     /// The ending address of the group.
     pub end: u64,
+
+    /// The range of entries in [BlockTable::jump_table_targets] recovered for blocks in this group.
+    pub jump_table_targets: (usize, usize),
 }
 
 impl BlockGroup {
@@ -603,11 +637,33 @@ pub struct Settings {
 
     /// Whether to perform block level optimizations.
     pub optimize_block: bool,
+
+    /// Whether to recover the targets of indirect jumps through jump tables, see [jump_table].
+    pub recover_jump_tables: bool,
+
+    /// The maximum number of p-code operations to include in each block. Blocks are ended before
+    /// any instruction that would exceed the limit, unless it is the first instruction in the
+    /// block, in which case lifting fails with [DecodeError::LimitExceeded].
+    pub max_pcode_ops_per_block: usize,
+
+    /// The maximum amount of time to spend lifting each block. Checked after each instruction is
+    /// lifted, with the same behaviour as [Settings::max_pcode_ops_per_block].
+    ///
+    /// Note: this makes block boundaries (and whether lifting fails) depend on host timing, so
+    /// lifting is no longer deterministic across runs when this limit is set.
+    pub max_lift_time: Option<std::time::Duration>,
 }
 
 impl Default for Settings {
     fn default() -> Self {
-        Self { max_instructions_per_block: 128, optimize: true, optimize_block: true }
+        Self {
+            max_instructions_per_block: 128,
+            optimize: true,
+            optimize_block: true,
+            recover_jump_tables: true,
+            max_pcode_ops_per_block: 0x4000,
+            max_lift_time: None,
+        }
     }
 }
 
@@ -640,13 +696,26 @@ impl<'a, 'b, S> Context<'a, 'b, S> {
 /// currently used for working around bugs / missing functionality in sleigh specs).
 pub type PcodePatcher = Box<dyn FnMut(&mut pcode::Block) + 'static>;
 
+/// Represents a function that is called for every newly lifted group of blocks, before block level
+/// optimizations are performed and before the code is compiled. Callbacks may insert, modify, or
+/// remove p-code operations in any block that is part of the group.
+pub type LiftCallback = Box<dyn FnMut(&BlockGroup, &mut BlockTable) + 'static>;
+
 pub struct BlockLifter {
     pub settings: Settings,
     pub instruction_lifter: InstructionLifter,
     pub op_injectors: HashMap<u16, Box<dyn PcodeOpInjector>>,
     pub patchers: Vec<PcodePatcher>,
+    pub lift_callbacks: Vec<LiftCallback>,
     current: BlockState,
     optimizer: Optimizer,
+
+    /// The number of p-code operations lifted for the current block.
+    block_pcode_ops: usize,
+
+    /// The time that lifting started for the current block (only tracked if there is a time
+    /// limit).
+    block_start_time: Option<std::time::Instant>,
 }
 
 impl BlockLifter {
@@ -658,6 +727,9 @@ impl BlockLifter {
             current: Default::default(),
             optimizer: Optimizer::new(),
             patchers: vec![],
+            lift_callbacks: vec![],
+            block_pcode_ops: 0,
+            block_start_time: None,
         }
     }
 
@@ -678,6 +750,8 @@ impl BlockLifter {
         // Lift all instructions that are part of the same block, or until we reach a maximum number
         // of instructions.
         let mut icount = 0;
+        self.block_pcode_ops = 0;
+        self.block_start_time = self.settings.max_lift_time.map(|_| std::time::Instant::now());
         self.current.start = ctx.vaddr;
         self.current.entry = Some(ctx.vaddr);
         let exit_target = loop {
@@ -690,6 +764,10 @@ impl BlockLifter {
                     next_addr
                 }
                 BlockResult::Exit(addr) => break Target::External(addr.into()),
+                BlockResult::Invalid(DecodeError::LimitExceeded { .. }) if icount > 0 => {
+                    // The instruction will be lifted again as the start of a new block.
+                    break Target::External(ctx.vaddr.into());
+                }
                 BlockResult::Invalid(e) => {
                     // We can't return here directly because there might be previous instructions
                     // in the block before the invalid instruction and we still want to be able to
@@ -711,12 +789,6 @@ impl BlockLifter {
             }
         }
 
-        if self.settings.optimize_block {
-            for block in &mut ctx.code.blocks[group_start..] {
-                self.optimizer.const_prop(&mut block.pcode);
-            }
-        }
-
         // This occurs when the block starts with an invalid instruction there is no need to
         // generate a block at all, and we can immediately raise an exception.
         // This was added to improve the performance of fuzzing programs that can easily jump to an
@@ -729,11 +801,30 @@ impl BlockLifter {
             };
         }
 
-        Ok(BlockGroup {
+        let no_targets = ctx.code.jump_table_targets.len();
+        let mut group = BlockGroup {
             blocks: (group_start, group_end),
             start: ctx.code.blocks[group_start].start,
             end: self.current.next,
-        })
+            jump_table_targets: (no_targets, no_targets),
+        };
+
+        for callback in &mut self.lift_callbacks {
+            callback(&group, ctx.code);
+        }
+
+        if self.settings.optimize_block {
+            for block in &mut ctx.code.blocks[group_start..] {
+                self.optimizer.const_prop(&mut block.pcode);
+            }
+        }
+
+        // Jump targets are easier to recognise after constant propagation.
+        if self.settings.recover_jump_tables {
+            group.jump_table_targets = jump_table::recover(ctx.src, ctx.code, group.blocks);
+        }
+
+        Ok(group)
     }
 
     /// Lifts the next instruction from `ctx` and adds the pcode operations the current block.
@@ -747,6 +838,11 @@ impl BlockLifter {
             Ok(addr) => addr,
             Err(e) => return BlockResult::Invalid(e),
         };
+        if let Err(e) = self.check_limits(ctx.vaddr) {
+            // Exclude the instruction from the current block.
+            self.current.next = ctx.vaddr;
+            return BlockResult::Invalid(e);
+        }
 
         let mut label_to_next = false;
         let mut block_exit = false;
@@ -877,6 +973,24 @@ impl BlockLifter {
         }
     }
 
+    /// Checks whether adding the most recently lifted instruction (at `addr`) to the current block
+    /// would exceed any of the configured limits.
+    fn check_limits(&mut self, addr: u64) -> Result<(), DecodeError> {
+        let ops = self.instruction_lifter.lifted.instructions.len();
+        if self.block_pcode_ops + ops > self.settings.max_pcode_ops_per_block {
+            tracing::debug!("{addr:#x}: block exceeds p-code limit ({ops} ops)");
+            return Err(DecodeError::LimitExceeded { addr, limit: LiftLimit::PcodeOps });
+        }
+        if let (Some(start), Some(max)) = (self.block_start_time, self.settings.max_lift_time) {
+            if start.elapsed() > max {
+                tracing::debug!("{addr:#x}: block exceeds lifting time limit");
+                return Err(DecodeError::LimitExceeded { addr, limit: LiftLimit::Time });
+            }
+        }
+        self.block_pcode_ops += ops;
+        Ok(())
+    }
+
     fn lift_next_inst<S>(&mut self, ctx: &mut Context<S>) -> Result<u64, DecodeError>
     where
         S: InstructionSource,
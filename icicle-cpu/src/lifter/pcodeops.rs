@@ -92,6 +92,42 @@ fn syscall(
     gen_exception(arch, inputs, state, ExceptionCode::Syscall)
 }
 
+/// The value passed with [ExceptionCode::InvalidateICache] when the entire instruction cache should
+/// be invalidated.
+pub const INVALIDATE_ALL: u64 = u64::MAX;
+
+/// Generates an [ExceptionCode::InvalidateICache] exception for the cache line containing `addr`
+/// (or the entire cache if `addr` is `None`).
+pub(crate) fn gen_icache_invalidate(
+    arch: &Arch,
+    addr: Option<pcode::Value>,
+    state: &mut BlockState,
+) -> bool {
+    let r = arch.reg_next_pc;
+    state.pcode.push((r, pcode::Op::Copy, pcode::Value::Const(state.next, r.size)));
+    let value = addr.unwrap_or(pcode::Value::Const(INVALIDATE_ALL, 8));
+    state.pcode.push((pcode::Op::Exception, (ExceptionCode::InvalidateICache as u32, value)));
+    true
+}
+
+/// Returns whether instruction cache invalidation operations executed by the guest need to be
+/// reported to the VM, i.e. when modifications to translated code are not detected by the MMU (see
+/// [crate::SmcPolicy]). Otherwise the operations are ignored, so they do not end the current block.
+pub(crate) fn reports_icache_invalidation(cpu: &Cpu) -> bool {
+    !cpu.mem.detect_self_modifying_code
+}
+
+fn icache_invalidate(
+    arch: &Arch,
+    _: pcode::PcodeOpId,
+    inputs: pcode::Inputs,
+    _: pcode::VarNode,
+    state: &mut BlockState,
+) -> bool {
+    let addr = Some(inputs.first()).filter(|x| !x.is_invalid());
+    gen_icache_invalidate(arch, addr, state)
+}
+
 fn ignored_hint(
     _: &Arch,
     _: pcode::PcodeOpId,
@@ -127,6 +163,18 @@ pub const BARRIER_OPS: &[&str] = &[
 /// Names for pcodeops that act as hints.
 pub const HINT_OPS: &[&str] = &["prefetch", "Hint_Prefetch", "HintPreloadData"];
 
+/// Names for pcodeops that invalidate the instruction cache (either for a single address, or the
+/// entire cache if no address is provided).
+pub const ICACHE_INVALIDATE_OPS: &[&str] = &[
+    "IC_IVAU",
+    "IC_IALLU",
+    "IC_IALLUIS",
+    "InstructionCacheInvalidate",
+    "fence.i",
+    "fence_i",
+    "synci",
+];
+
 /// Names for pcodeops that indicate invailid instructions.
 pub const INVALID_INSTRUCTION_OPS: &[&str] = &["invalidInstructionException", "software_udf"];
 
@@ -156,6 +204,14 @@ pub fn get_injectors(
         injectors.insert(id, Box::new(ignored_hint));
     }
 
+    let report_icache_invalidation = reports_icache_invalidation(cpu);
+    for id in ICACHE_INVALIDATE_OPS.iter().filter_map(|name| cpu.arch.sleigh.get_userop(name)) {
+        match report_icache_invalidation {
+            true => injectors.insert(id, Box::new(icache_invalidate)),
+            false => injectors.insert(id, Box::new(ignored_hint)),
+        };
+    }
+
     for id in INVALID_INSTRUCTION_OPS.iter().filter_map(|name| cpu.arch.sleigh.get_userop(name)) {
         injectors.insert(id, Box::new(invalid_instruction));
     }
@@ -225,6 +281,8 @@ pub mod arm {
             injectors.insert(id, Box::new(sleep));
         }
 
+        crate::lifter::arm_cp15::get_injectors(cpu, injectors);
+
         let ex_addr = match cpu.arch.sleigh.get_varnode("exclusive_addr") {
             Some(var) => var,
             None => cpu.arch.sleigh.add_custom_reg("exclusive_addr", 4).unwrap(),
@@ -0,0 +1,139 @@
+//! Modelling of RISC-V control and status registers (CSRs).
+//!
+//! SLEIGH treats CSRs as ordinary registers, so reading a counter always returns the last value
+//! written to it, and the floating-point CSRs are independent of each other. The lifted code of
+//! each instruction is patched so that:
+//!
+//! - Reads of the counter CSRs (`cycle`, `time`, `instret`, their machine-mode versions, and the
+//!   upper halves used by RV32) return the current instruction count, or for `cycle` and `time`,
+//!   the current value of the virtual clock (see [Cpu::clock]).
+//! - `fcsr` is kept in sync with the `fflags` and `frm` fields it contains.
+
+use pcode::{Op, Value, VarNode};
+
+use crate::{Cpu, ValueSource, lifter::BlockLifter};
+
+/// The counter CSRs, along with the amount the counter is shifted by to get their value, and
+/// whether they count clock cycles (instead of retired instructions).
+const COUNTERS: &[(&str, u8, bool)] = &[
+    ("cycle", 0, true),
+    ("time", 0, true),
+    ("instret", 0, false),
+    ("mcycle", 0, true),
+    ("minstret", 0, false),
+    ("cycleh", 32, true),
+    ("timeh", 32, true),
+    ("instreth", 32, false),
+    ("mcycleh", 32, true),
+    ("minstreth", 32, false),
+];
+
+/// The bits of `fcsr` that contain `fflags`.
+const FFLAGS_MASK: u64 = 0x1f;
+
+/// The offset of `frm` within `fcsr`.
+const FRM_SHIFT: u64 = 5;
+
+/// The bits of `frm`.
+const FRM_MASK: u64 = 0x7;
+
+#[derive(Clone, Copy)]
+struct FloatCsrs {
+    fflags: VarNode,
+    frm: VarNode,
+    fcsr: VarNode,
+}
+
+pub fn csr_patch(cpu: &mut Cpu, lifter: &mut BlockLifter) {
+    let read_counter = cpu.arch.sleigh.register_user_op(Some("read_counter"));
+    cpu.set_helper(read_counter, read_counter_helper);
+
+    let counters: Vec<(VarNode, u8, bool)> = COUNTERS
+        .iter()
+        .filter_map(|&(name, shift, cycles)| {
+            Some((cpu.arch.sleigh.get_varnode(name)?, shift, cycles))
+        })
+        .collect();
+
+    let get = |name: &str| cpu.arch.sleigh.get_varnode(name);
+    let float_csrs = match (get("fflags"), get("frm"), get("fcsr")) {
+        (Some(fflags), Some(frm), Some(fcsr)) => Some(FloatCsrs { fflags, frm, fcsr }),
+        _ => None,
+    };
+
+    lifter.patchers.push(Box::new(move |block: &mut pcode::Block| {
+        let mut counter_reads = vec![];
+        let mut fcsr_written = false;
+        let mut fields_written = false;
+        for inst in &block.instructions {
+            for input in inst.inputs.get() {
+                let Value::Var(var) = input
+                else {
+                    continue;
+                };
+                let entry = counters.iter().find(|(counter, ..)| counter.id == var.id);
+                if let Some(&entry) = entry.filter(|entry| !counter_reads.contains(*entry)) {
+                    counter_reads.push(entry);
+                }
+            }
+            if let Some(csrs) = float_csrs {
+                fcsr_written |= inst.output.id == csrs.fcsr.id;
+                fields_written |=
+                    inst.output.id == csrs.fflags.id || inst.output.id == csrs.frm.id;
+            }
+        }
+
+        // Update the counters at the start of the instruction.
+        let start = block
+            .instructions
+            .iter()
+            .position(|x| x.op == Op::InstructionMarker)
+            .map_or(0, |pos| pos + 1);
+        for (counter, shift, cycles) in counter_reads {
+            let read = (counter, Op::PcodeOp(read_counter), (shift, cycles as u8));
+            block.instructions.insert(start, read.into());
+        }
+
+        // Synchronize the floating-point CSRs at the end of the instruction (but before any
+        // branch).
+        let Some(csrs) = float_csrs
+        else {
+            return;
+        };
+        if !fcsr_written && !fields_written {
+            return;
+        }
+        let branch = match block.instructions.last().map(|x| x.op) {
+            Some(Op::Branch(_) | Op::PcodeBranch(_)) => block.instructions.pop(),
+            _ => None,
+        };
+        let FloatCsrs { fflags, frm, fcsr } = csrs;
+        let c = |value: u64| Value::Const(value, fcsr.size);
+        if fcsr_written {
+            block.push((fflags, Op::IntAnd, (fcsr, c(FFLAGS_MASK))));
+            block.push((frm, Op::IntRight, (fcsr, c(FRM_SHIFT))));
+            block.push((frm, Op::IntAnd, (frm, c(FRM_MASK))));
+        }
+        else {
+            let tmp = block.alloc_tmp(fcsr.size);
+            block.push((tmp, Op::IntAnd, (frm, c(FRM_MASK))));
+            block.push((tmp, Op::IntLeft, (tmp, c(FRM_SHIFT))));
+            block.push((fcsr, Op::IntAnd, (fflags, c(FFLAGS_MASK))));
+            block.push((fcsr, Op::IntOr, (fcsr, tmp)));
+        }
+        if let Some(branch) = branch {
+            block.push(branch);
+        }
+    }));
+}
+
+fn read_counter_helper(cpu: &mut Cpu, dst: VarNode, args: [Value; 2]) {
+    let shift = args[0].as_u64();
+    let count = match args[1].as_u64() {
+        0 => cpu.icount(),
+        _ => cpu.clock(),
+    };
+    // The instruction reading the counter has already been counted, but has not retired yet.
+    let value = count.saturating_sub(1) >> shift;
+    cpu.write_trunc(dst, value);
+}
@@ -0,0 +1,118 @@
+//! Approximate cycle timing.
+//!
+//! By default the virtual clock advances by one for each instruction executed. When a [CostTable]
+//! is enabled, the lifted code of each instruction is patched to add the number of cycles the
+//! instruction takes beyond the first to a custom register, so [Cpu::clock] returns an approximate
+//! cycle count. The instruction count (and therefore fuel) is unaffected.
+//!
+//! The cost of each instruction is estimated from the p-code operations it performs, so it does
+//! not model pipeline hazards, wait states, or caches.
+
+use pcode::{Op, Value};
+
+use crate::{Cpu, lifter::BlockLifter};
+
+/// The number of extra cycles used by each kind of operation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CostTable {
+    /// Extra cycles for each load from memory.
+    pub load: u8,
+
+    /// Extra cycles for each store to memory.
+    pub store: u8,
+
+    /// Extra cycles for integer multiplication.
+    pub mul: u8,
+
+    /// Extra cycles for integer division and remainder.
+    pub div: u8,
+
+    /// Extra cycles for floating-point arithmetic and conversions.
+    pub float: u8,
+
+    /// Extra cycles for floating-point division and square root.
+    pub float_div: u8,
+
+    /// Extra cycles when a branch is taken (i.e. for refilling the pipeline).
+    pub branch_taken: u8,
+}
+
+impl CostTable {
+    /// Approximate timings for Cortex-M3/M4 cores.
+    pub const CORTEX_M: Self =
+        Self { load: 1, store: 0, mul: 0, div: 6, float: 0, float_div: 13, branch_taken: 2 };
+
+    /// Approximate timings for a simple in-order RISC-V core without branch prediction.
+    pub const RISCV: Self =
+        Self { load: 1, store: 0, mul: 2, div: 33, float: 2, float_div: 19, branch_taken: 2 };
+
+    /// Returns the default cost table for `arch` (if any).
+    pub fn for_arch(arch: target_lexicon::Architecture) -> Option<Self> {
+        use target_lexicon::Architecture;
+        match arch {
+            Architecture::Arm(arm) if arm.is_thumb() => Some(Self::CORTEX_M),
+            Architecture::Riscv32(_) | Architecture::Riscv64(_) => Some(Self::RISCV),
+            _ => None,
+        }
+    }
+
+    /// Returns the extra cycles used by `op`, excluding the cost of taken branches.
+    fn op_cost(&self, op: Op) -> u64 {
+        let cost = match op {
+            Op::Load(pcode::RAM_SPACE) => self.load,
+            Op::Store(pcode::RAM_SPACE) => self.store,
+            Op::IntMul => self.mul,
+            Op::IntDiv | Op::IntSignedDiv | Op::IntRem | Op::IntSignedRem => self.div,
+            Op::FloatDiv | Op::FloatSqrt => self.float_div,
+            Op::FloatAdd
+            | Op::FloatSub
+            | Op::FloatMul
+            | Op::IntToFloat
+            | Op::UintToFloat
+            | Op::FloatToFloat
+            | Op::FloatToInt => self.float,
+            _ => 0,
+        };
+        cost as u64
+    }
+}
+
+/// Patches the lifter to track the extra cycles used by each instruction according to `table`.
+pub fn cycle_patch(cpu: &mut Cpu, lifter: &mut BlockLifter, table: CostTable) {
+    let extra_cycles = cpu.arch.sleigh.add_custom_reg("extra_cycles", 8).unwrap();
+    cpu.extra_cycles = Some(extra_cycles);
+
+    lifter.patchers.push(Box::new(move |block: &mut pcode::Block| {
+        let mut cost: u64 = block.instructions.iter().map(|inst| table.op_cost(inst.op)).sum();
+
+        // Account for taken branches before the branch is executed.
+        let branch = match block.instructions.last().copied() {
+            Some(inst) if matches!(inst.op, Op::Branch(_)) => Some(inst),
+            _ => None,
+        };
+        if let Some(branch) = branch {
+            let cond = branch.inputs.first();
+            if cond.const_eq(1) {
+                cost += table.branch_taken as u64;
+            }
+            else if !cond.is_const() && table.branch_taken != 0 {
+                block.instructions.pop();
+                let tmp = block.alloc_tmp(8);
+                block.push((tmp, Op::ZeroExtend, cond));
+                block.push((tmp, Op::IntMul, (tmp, Value::Const(table.branch_taken as u64, 8))));
+                block.push((extra_cycles, Op::IntAdd, (extra_cycles, tmp)));
+                block.push(branch);
+            }
+        }
+
+        if cost != 0 {
+            let start = block
+                .instructions
+                .iter()
+                .position(|x| x.op == Op::InstructionMarker)
+                .map_or(0, |pos| pos + 1);
+            let add = (extra_cycles, Op::IntAdd, (extra_cycles, Value::Const(cost, 8)));
+            block.instructions.insert(start, add.into());
+        }
+    }));
+}
@@ -11,9 +11,38 @@ use object::{
         IMAGE_REL_BASED_DIR64, IMAGE_REL_BASED_HIGH, IMAGE_REL_BASED_HIGHLOW, IMAGE_REL_BASED_LOW,
         IMAGE_SIZEOF_FILE_HEADER,
     },
-    read::pe::{DataDirectories, ImageOptionalHeader},
+    read::pe::{DataDirectories, ImageOptionalHeader, ImageThunkData, Import},
 };
 
+/// The size of each generated stub for unresolved imports.
+const IMPORT_STUB_SIZE: u64 = 0x10;
+
+/// The maximum number of arguments to report for calls to unimplemented APIs.
+const MAX_REPORTED_ARGS: usize = 6;
+
+/// Maps API set contract names (e.g. `api-ms-win-core-synch-l1-2-0.dll`) to the DLL that
+/// implements them. Entries are matched by prefix, in order.
+const API_SET_MAPPING: &[(&str, &str)] = &[
+    ("api-ms-win-crt-", "ucrtbase.dll"),
+    ("api-ms-win-core-", "kernelbase.dll"),
+    ("api-ms-win-security-", "advapi32.dll"),
+    ("api-ms-win-eventing-", "advapi32.dll"),
+    ("api-ms-win-service-", "advapi32.dll"),
+    ("api-ms-win-ntuser-", "user32.dll"),
+    ("api-ms-win-rtcore-ntuser-", "user32.dll"),
+    ("api-ms-win-gdi-", "gdi32.dll"),
+    ("api-ms-win-shell-", "shell32.dll"),
+    ("api-ms-win-mm-", "winmm.dll"),
+    ("ext-ms-win-", "kernelbase.dll"),
+];
+
+/// Resolves an API set contract name to the DLL implementing it, returning `None` if `name` is
+/// not an API set.
+pub fn resolve_api_set(name: &str) -> Option<&'static str> {
+    let name = name.to_ascii_lowercase();
+    API_SET_MAPPING.iter().find(|(prefix, _)| name.starts_with(prefix)).map(|(_, dll)| *dll)
+}
+
 enum RelocationError {
     Access(icicle_mem::MemError),
     Unsupported(u16),
@@ -47,15 +76,160 @@ pub struct PeMetadata {
 
     /// The total length of the file.
     pub length: u64,
+
+    /// The address and size of the exception directory (i.e. the function table used for
+    /// unwinding on x86-64), if present.
+    pub exception_directory: Option<(u64, u64)>,
+}
+
+/// A stub generated for an import that could not be resolved by the loader.
+#[derive(Debug, Clone)]
+pub struct ImportStub {
+    /// The name of the DLL the import was resolved to (after API set resolution).
+    pub dll: String,
+
+    /// The name of the imported function (or `#ordinal` for imports by ordinal).
+    pub name: String,
+
+    /// The address of the stub.
+    pub addr: u64,
+}
+
+/// Stubs for all unresolved imports of a PE file.
+///
+/// Stubs are mapped as non-executable memory, so any call to an unresolved import exits with an
+/// `ExecViolation` at the address of the stub which can then be looked up with
+/// [ImportStubs::describe_call].
+#[derive(Debug, Clone, Default)]
+pub struct ImportStubs {
+    /// The base address of the stub region.
+    pub base: u64,
+
+    /// The size of pointers (in bytes) for the target.
+    pub ptr_size: u64,
+
+    pub stubs: Vec<ImportStub>,
+}
+
+impl ImportStubs {
+    /// Gets the stub at `addr`.
+    pub fn get(&self, addr: u64) -> Option<&ImportStub> {
+        let offset = addr.checked_sub(self.base)?;
+        self.stubs.get((offset / IMPORT_STUB_SIZE) as usize)
+    }
+
+    /// Gets the stub that is called by a call to `addr`, which is either the stub itself or an
+    /// import thunk in the image (i.e. `jmp [iat_entry]`) that jumps to the stub.
+    pub fn resolve(&self, cpu: &mut Cpu, addr: u64) -> Option<&ImportStub> {
+        if let Some(stub) = self.get(addr) {
+            return Some(stub);
+        }
+        let mut inst = [0; 6];
+        cpu.mem.read_bytes(addr, &mut inst, perm::NONE).ok()?;
+        if inst[..2] != [0xff, 0x25] {
+            return None;
+        }
+        let disp = i32::from_le_bytes(inst[2..].try_into().unwrap());
+        let iat_entry = match self.ptr_size {
+            // x86-64 uses RIP relative addressing, x86 uses absolute addresses.
+            8 => addr.wrapping_add(6).wrapping_add(disp as i64 as u64),
+            _ => disp as u32 as u64,
+        };
+        let target = match self.ptr_size {
+            8 => cpu.mem.read_u64(iat_entry, perm::NONE).ok()?,
+            _ => cpu.mem.read_u32(iat_entry, perm::NONE).ok()? as u64,
+        };
+        self.get(target)
+    }
+
+    /// If `addr` is the address of a stub, returns a description of the call (including the
+    /// arguments according to the target's calling convention).
+    pub fn describe_call(&self, cpu: &mut Cpu, addr: u64) -> Option<UnimplementedApiCall> {
+        let stub = self.get(addr)?;
+
+        let sp = cpu.read_reg(cpu.arch.reg_sp);
+        let read_ptr = |cpu: &mut Cpu, addr: u64| match self.ptr_size {
+            8 => cpu.mem.read_u64(addr, perm::NONE).ok(),
+            _ => cpu.mem.read_u32(addr, perm::NONE).ok().map(|x| x as u64),
+        };
+        let return_addr = read_ptr(cpu, sp).unwrap_or(0);
+
+        let mut args = vec![];
+        if self.ptr_size == 8 {
+            // Microsoft x64: the first four arguments are passed in registers, with the remaining
+            // arguments after the 32 byte shadow space on the stack.
+            for name in ["RCX", "RDX", "R8", "R9"] {
+                let var = cpu.arch.sleigh.get_varnode(name)?;
+                args.push(cpu.read_reg(var));
+            }
+            for i in 0..(MAX_REPORTED_ARGS - args.len()) as u64 {
+                match read_ptr(cpu, sp + 0x28 + i * 8) {
+                    Some(value) => args.push(value),
+                    None => break,
+                }
+            }
+        }
+        else {
+            // stdcall/cdecl: all arguments are passed on the stack.
+            for i in 0..MAX_REPORTED_ARGS as u64 {
+                match read_ptr(cpu, sp + 4 + i * 4) {
+                    Some(value) => args.push(value),
+                    None => break,
+                }
+            }
+        }
+
+        Some(UnimplementedApiCall {
+            dll: stub.dll.clone(),
+            name: stub.name.clone(),
+            args,
+            return_addr,
+        })
+    }
+}
+
+/// Details about a call to an unimplemented API.
+#[derive(Debug, Clone)]
+pub struct UnimplementedApiCall {
+    pub dll: String,
+    pub name: String,
+
+    /// The values of the (potential) arguments passed to the function. The number of arguments
+    /// the function actually takes is unknown.
+    pub args: Vec<u64>,
+
+    pub return_addr: u64,
+}
+
+impl std::fmt::Display for UnimplementedApiCall {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "unimplemented API called: {}!{}(", self.dll, self.name)?;
+        for (i, arg) in self.args.iter().enumerate() {
+            if i != 0 {
+                f.write_str(", ")?;
+            }
+            write!(f, "{arg:#x}")?;
+        }
+        write!(f, ") from {:#x}", self.return_addr)
+    }
 }
 
 #[derive(Clone)]
 pub struct LoadedPe {
     pub binary: PeMetadata,
     pub debug_info: DebugInfo,
+
+    /// Stubs generated for any imports that were not resolved by [PeLoader::resolve_import].
+    pub import_stubs: ImportStubs,
 }
 
 pub trait PeLoader {
+    /// Resolves the address of `name` exported by `dll`. Imports that are not resolved are
+    /// replaced by stubs that exit when called.
+    fn resolve_import(&mut self, _cpu: &mut Cpu, _dll: &str, _name: &str) -> Option<u64> {
+        None
+    }
+
     fn load_pe32(&mut self, cpu: &mut Cpu, data: &[u8]) -> Result<LoadedPe, String> {
         use object::read::pe::ImageNtHeaders;
         tracing::info!("Loading 32-bit PE file");
@@ -67,7 +241,7 @@ pub trait PeLoader {
         let (nt_headers, data_directories) = ImageNtHeaders32::parse(data, &mut offset)
             .map_err(|e| format!("Unable to parse Nt Headers for x86 binary: {e:?}"))?;
         let sections = nt_headers.sections(data, offset).unwrap();
-        load_pe(cpu, data, dos_header, nt_headers, &sections, &data_directories)
+        load_pe(self, cpu, data, dos_header, nt_headers, &sections, &data_directories)
     }
 
     fn load_pe64(&mut self, cpu: &mut Cpu, data: &[u8]) -> Result<LoadedPe, String> {
@@ -80,11 +254,12 @@ pub trait PeLoader {
         let (nt_headers, data_directories) = ImageNtHeaders64::parse(data, &mut offset)
             .map_err(|e| format!("Unable to parse Nt Headers for x64 binary: {e:?}"))?;
         let sections = nt_headers.sections(data, offset).unwrap();
-        load_pe(cpu, data, dos_header, nt_headers, &sections, &data_directories)
+        load_pe(self, cpu, data, dos_header, nt_headers, &sections, &data_directories)
     }
 }
 
-fn load_pe<H>(
+fn load_pe<L, H>(
+    loader: &mut L,
     cpu: &mut Cpu,
     data: &[u8],
     dos_header: &ImageDosHeader,
@@ -93,6 +268,7 @@ fn load_pe<H>(
     data_directories: &DataDirectories<'_>,
 ) -> Result<LoadedPe, String>
 where
+    L: PeLoader + ?Sized,
     H: object::read::pe::ImageNtHeaders,
 {
     let sec_alignment = nt_headers.optional_header().section_alignment() as u64;
@@ -175,17 +351,134 @@ where
         }
     }
 
+    // resolve imports
+    let ptr_size = if nt_headers.is_type_64() { 8 } else { 4 };
+    let import_stubs = resolve_imports::<L, H>(
+        loader,
+        cpu,
+        data,
+        sections,
+        data_directories,
+        base_addr,
+        ptr_size,
+    )?;
+
+    let exception_directory = data_directories
+        .get(pe::IMAGE_DIRECTORY_ENTRY_EXCEPTION)
+        .map(|dir| {
+            let addr = dir.virtual_address.get(object::LittleEndian) as u64;
+            (base_addr + addr, dir.size.get(object::LittleEndian) as u64)
+        })
+        .filter(|(_, size)| *size != 0);
+
     // create result object
     let binary = PeMetadata {
         relocation_offset,
         entry_ptr: nt_headers.optional_header().address_of_entry_point() as u64 + base_addr,
         length: layout.size,
         base_ptr: base_addr,
+        exception_directory,
     };
 
     let mut debug_info = DebugInfo::default();
     debug_info.add_file(data, relocation_offset)?;
-    Ok(LoadedPe { binary, debug_info })
+    Ok(LoadedPe { binary, debug_info, import_stubs })
+}
+
+/// Resolves all imports of the PE file, generating stubs for any imports that could not be
+/// resolved.
+fn resolve_imports<L, H>(
+    loader: &mut L,
+    cpu: &mut Cpu,
+    data: &[u8],
+    sections: &SectionTable<'_>,
+    data_directories: &DataDirectories<'_>,
+    base_addr: u64,
+    ptr_size: u64,
+) -> Result<ImportStubs, String>
+where
+    L: PeLoader + ?Sized,
+    H: object::read::pe::ImageNtHeaders,
+{
+    let mut stubs = ImportStubs { base: 0, ptr_size, stubs: vec![] };
+
+    let Some(import_table) = data_directories
+        .import_table(data, sections)
+        .map_err(|e| format!("Failed to read import table: {e}"))?
+    else {
+        return Ok(stubs);
+    };
+
+    // Collect the list of unresolved imports and the IAT entries that reference them.
+    let mut unresolved = vec![];
+    let mut descriptors =
+        import_table.descriptors().map_err(|e| format!("Failed to read imports: {e}"))?;
+    while let Some(desc) = descriptors.next().map_err(|e| format!("Failed to read imports: {e}"))?
+    {
+        let dll = import_table
+            .name(desc.name.get(object::LittleEndian))
+            .map_err(|e| format!("Failed to read import name: {e}"))?;
+        let dll = String::from_utf8_lossy(dll).into_owned();
+        let dll = resolve_api_set(&dll).map_or(dll, |x| x.to_owned());
+
+        let first_thunk = desc.first_thunk.get(object::LittleEndian);
+        let lookup_thunk = match desc.original_first_thunk.get(object::LittleEndian) {
+            0 => first_thunk,
+            x => x,
+        };
+        let mut thunks = import_table
+            .thunks(lookup_thunk)
+            .map_err(|e| format!("Failed to read import thunks for {dll}: {e}"))?;
+
+        let mut iat_entry = base_addr + first_thunk as u64;
+        while let Some(thunk) =
+            thunks.next::<H>().map_err(|e| format!("Failed to read import thunk: {e}"))?
+        {
+            let name = match import_table.import::<H>(thunk) {
+                Ok(Import::Name(_, name)) => String::from_utf8_lossy(name).into_owned(),
+                Ok(Import::Ordinal(ordinal)) => format!("#{ordinal}"),
+                Err(e) => {
+                    tracing::warn!("Failed to read import {:#x}: {e}", thunk.raw());
+                    format!("<invalid:{:#x}>", thunk.raw())
+                }
+            };
+
+            match loader.resolve_import(cpu, &dll, &name) {
+                Some(addr) => write_ptr(cpu, iat_entry, addr, ptr_size)?,
+                None => unresolved.push((iat_entry, dll.clone(), name)),
+            }
+            iat_entry += ptr_size;
+        }
+    }
+
+    if unresolved.is_empty() {
+        return Ok(stubs);
+    }
+
+    // Map a non-executable region for the stubs, so calls to any stub will exit.
+    let size = unresolved.len() as u64 * IMPORT_STUB_SIZE;
+    let layout = AllocLayout { addr: None, size, align: 0x1000 };
+    stubs.base = cpu
+        .mem
+        .alloc_memory(layout, Mapping { perm: perm::MAP | perm::READ, value: 0xcc })
+        .map_err(|e| format!("Failed to allocate memory for import stubs: {e:?}"))?;
+
+    for (i, (iat_entry, dll, name)) in unresolved.into_iter().enumerate() {
+        let addr = stubs.base + i as u64 * IMPORT_STUB_SIZE;
+        tracing::debug!("Unresolved import: {dll}!{name} (stub={addr:#x})");
+        write_ptr(cpu, iat_entry, addr, ptr_size)?;
+        stubs.stubs.push(ImportStub { dll, name, addr });
+    }
+
+    Ok(stubs)
+}
+
+fn write_ptr(cpu: &mut Cpu, addr: u64, value: u64, ptr_size: u64) -> Result<(), String> {
+    let result = match ptr_size {
+        8 => cpu.mem.write_u64(addr, value, perm::NONE),
+        _ => cpu.mem.write_u32(addr, value as u32, perm::NONE),
+    };
+    result.map_err(|e| format!("Failed to write import address at {addr:#x}: {e:?}"))
 }
 
 fn handle_relocation(
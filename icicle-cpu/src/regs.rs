@@ -148,6 +148,16 @@ impl Regs {
         let end = REG_OFFSET as usize + valid * 16;
         self.0[..end].copy_from_slice(&other.0[..end]);
     }
+
+    /// Returns the bytes used for storing the first `valid` registers.
+    pub fn valid_bytes(&self, valid: usize) -> &[u8] {
+        &self.0[..REG_OFFSET as usize + valid * 16]
+    }
+
+    /// Returns the bytes used for storing the first `valid` registers for modification.
+    pub fn valid_bytes_mut(&mut self, valid: usize) -> &mut [u8] {
+        &mut self.0[..REG_OFFSET as usize + valid * 16]
+    }
 }
 
 impl Default for Regs {
@@ -40,6 +40,74 @@ impl XorShiftRng {
     }
 }
 
+/// A deterministic random bit generator used for all entropy sources visible to the guest (e.g.
+/// `getrandom`, `/dev/urandom`, `RDRAND`).
+///
+/// Cloning a `Drbg` produces a handle to the same generator, so reseeding any handle affects every
+/// entropy source that shares it.
+#[derive(Clone, Default)]
+pub struct Drbg {
+    inner: std::rc::Rc<DrbgState>,
+}
+
+#[derive(Default)]
+struct DrbgState {
+    seed: std::cell::Cell<u64>,
+    state: std::cell::Cell<u64>,
+}
+
+impl Drbg {
+    pub fn new(seed: u64) -> Self {
+        let drbg = Self::default();
+        drbg.reseed(seed);
+        drbg
+    }
+
+    /// Resets the generator (and all handles that share it) to the initial state for `seed`.
+    /// `seed` is kept as the seed used by [Drbg::reset].
+    pub fn reseed(&self, seed: u64) {
+        self.inner.seed.set(seed);
+        self.inner.state.set(seed);
+    }
+
+    /// Gets the most recently configured seed.
+    pub fn seed(&self) -> u64 {
+        self.inner.seed.get()
+    }
+
+    /// Resets the generator back to the initial state for the most recently configured seed.
+    pub fn reset(&self) {
+        self.inner.state.set(self.inner.seed.get());
+    }
+
+    /// Gets the internal state of the generator, so that it can be later restored with
+    /// [Drbg::set_state].
+    pub fn state(&self) -> u64 {
+        self.inner.state.get()
+    }
+
+    /// Restores a state previously obtained from [Drbg::state] (without changing the seed).
+    pub fn set_state(&self, state: u64) {
+        self.inner.state.set(state);
+    }
+
+    pub fn next_u64(&self) -> u64 {
+        // SplitMix64, chosen because every seed (including zero) produces a good sequence.
+        let state = self.inner.state.get().wrapping_add(0x9e37_79b9_7f4a_7c15);
+        self.inner.state.set(state);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    pub fn fill_bytes(&self, buf: &mut [u8]) {
+        for chunk in buf.chunks_mut(8) {
+            chunk.copy_from_slice(&self.next_u64().to_le_bytes()[..chunk.len()]);
+        }
+    }
+}
+
 pub struct UdpWriter {
     socket: Option<std::net::UdpSocket>,
 }
@@ -219,4 +287,14 @@ impl crate::lifter::InstructionSource for BasicInstructionSource {
     fn ensure_exec(&mut self, vaddr: u64, size: usize) -> bool {
         self.get_mem_region(vaddr, size).map_or(false, |x| x.len() == size)
     }
+
+    fn read_data(&mut self, vaddr: u64, buf: &mut [u8]) -> bool {
+        match self.get_mem_region(vaddr, buf.len()) {
+            Some(data) if data.len() == buf.len() => {
+                buf.copy_from_slice(data);
+                true
+            }
+            _ => false,
+        }
+    }
 }
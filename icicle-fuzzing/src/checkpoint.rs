@@ -0,0 +1,363 @@
+//! Checkpoints of the state of a fuzzing campaign.
+//!
+//! A checkpoint contains everything needed to resume a long running campaign without repeating
+//! the warm-up work done before it was saved: the state of the harness at the snapshot point (see
+//! [icicle_vm::snapshot_file]), the accumulated coverage, the stable block ID mapping (see
+//! [BlockIds]), the PC and key of the crashes that have been found, and the statistics of the
+//! campaign.
+//!
+//! Checkpoints are saved to a directory with the following layout:
+//!
+//! ```text
+//! CURRENT              the name of the latest complete checkpoint
+//! checkpoint-<N>/
+//!   manifest.ron       statistics and metadata
+//!   state.bin          the state of the VM
+//!   coverage.bin       the accumulated coverage map
+//!   block_ids.ron      the block ID mapping (if block IDs are enabled)
+//! ```
+//!
+//! Each checkpoint is written to a new subdirectory which is only made current (by atomically
+//! replacing `CURRENT`) after all of its files have been flushed to disk, so a crash or a reboot
+//! of the host while saving leaves the previous checkpoint intact.
+//!
+//! If the environment of the VM does not support saving its state (e.g. the Linux environment),
+//! only the CPU and memory state is saved, and the environment state is recreated by running the
+//! setup code of the harness again before the checkpoint is restored.
+
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::Context;
+use icicle_vm::Vm;
+
+use crate::block_ids::BlockIds;
+
+/// The version of the checkpoint format.
+const VERSION: u32 = 3;
+
+/// The name of the file that identifies the latest complete checkpoint.
+const CURRENT: &str = "CURRENT";
+
+const CHECKPOINT_PREFIX: &str = "checkpoint-";
+
+/// Statistics of a fuzzing campaign that are preserved across restarts.
+#[derive(Clone, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct CampaignStats {
+    /// The total number of executions.
+    pub total_execs: u64,
+
+    /// The total size of all inputs that were executed.
+    pub total_input_bytes: u64,
+
+    /// The number of unique crashes found.
+    pub crashes: u64,
+
+    /// The total time spent fuzzing (excluding the time the campaign was stopped).
+    pub run_time: Duration,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Manifest {
+    version: u32,
+    stats: CampaignStats,
+    /// The start address of every block discovered by the campaign.
+    seen_blocks: Vec<u64>,
+    /// The PC and crash key (see [crate::gen_crash_key]) of the unique crashes found by the campaign.
+    crashes: Vec<(u64, String)>,
+    has_block_ids: bool,
+}
+
+/// The state of a fuzzing campaign.
+#[derive(Clone, Debug, Default)]
+pub struct Checkpoint {
+    /// The state of the VM at the snapshot point of the harness (see [Vm::save_state]).
+    pub state: Vec<u8>,
+
+    /// The coverage accumulated across all executions (e.g. AFL's virgin map).
+    pub coverage: Vec<u8>,
+
+    /// The start address of every block discovered by the campaign.
+    pub seen_blocks: Vec<u64>,
+
+    /// The serialized block ID mapping (see [BlockIds::serialize]).
+    pub block_ids: Option<String>,
+
+    /// The PC and crash key (see [crate::gen_crash_key]) of the unique crashes found by the
+    /// campaign.
+    pub crashes: Vec<(u64, String)>,
+
+    pub stats: CampaignStats,
+}
+
+impl Checkpoint {
+    /// Captures the current state of `vm`, which should be at the snapshot point of the harness
+    /// (i.e. before any input has been executed).
+    pub fn capture(vm: &mut Vm, block_ids: Option<&BlockIds>) -> anyhow::Result<Self> {
+        let state = match vm.save_state() {
+            Ok(state) => state,
+            Err(e) => {
+                // The snapshot point is reached deterministically by the setup code of the harness,
+                // so the state of the environment is recreated before the checkpoint is restored.
+                tracing::debug!("{e:#}, only saving CPU and memory state");
+                vm.save_state_without_env().context("failed to save VM state")?
+            }
+        };
+        let block_ids = block_ids.map(|ids| ids.serialize()).transpose()?;
+        Ok(Self { state, block_ids, ..Self::default() })
+    }
+
+    /// Restores the block ID mapping saved in the checkpoint. This must be called before any
+    /// instrumented code is lifted, otherwise blocks that have already been lifted keep their
+    /// previous IDs.
+    pub fn restore_block_ids(&self, block_ids: &BlockIds) -> anyhow::Result<()> {
+        if let Some(data) = self.block_ids.as_ref() {
+            block_ids.parse(data).context("failed to load block IDs")?;
+        }
+        Ok(())
+    }
+
+    /// Restores the state of `vm`. `vm` must be built and set up the same way as the VM the
+    /// checkpoint was captured from.
+    pub fn restore(&self, vm: &mut Vm) -> anyhow::Result<()> {
+        vm.load_state(&self.state).context("failed to load VM state")
+    }
+
+    /// Atomically saves the checkpoint to `dir`, replacing any previous checkpoint.
+    pub fn save(&self, dir: &Path) -> anyhow::Result<()> {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("failed to create: {}", dir.display()))?;
+
+        let generation = current_checkpoint(dir)?.map_or(0, |(generation, _)| generation + 1);
+        let name = format!("{CHECKPOINT_PREFIX}{generation}");
+        let tmp_path = dir.join(format!("{name}.tmp"));
+        let path = dir.join(&name);
+
+        // Remove any leftovers from a save that was interrupted.
+        for stale in [&tmp_path, &path] {
+            if stale.exists() {
+                std::fs::remove_dir_all(stale)
+                    .with_context(|| format!("failed to remove: {}", stale.display()))?;
+            }
+        }
+        std::fs::create_dir(&tmp_path)
+            .with_context(|| format!("failed to create: {}", tmp_path.display()))?;
+
+        let manifest = Manifest {
+            version: VERSION,
+            stats: self.stats.clone(),
+            seen_blocks: self.seen_blocks.clone(),
+            crashes: self.crashes.clone(),
+            has_block_ids: self.block_ids.is_some(),
+        };
+        let manifest = ron::ser::to_string_pretty(&manifest, ron::ser::PrettyConfig::default())?;
+        write_synced(&tmp_path.join("manifest.ron"), manifest.as_bytes())?;
+        write_synced(&tmp_path.join("state.bin"), &self.state)?;
+        write_synced(&tmp_path.join("coverage.bin"), &self.coverage)?;
+        if let Some(ids) = self.block_ids.as_ref() {
+            write_synced(&tmp_path.join("block_ids.ron"), ids.as_bytes())?;
+        }
+        sync_dir(&tmp_path)?;
+
+        std::fs::rename(&tmp_path, &path)
+            .with_context(|| format!("failed to rename: {}", tmp_path.display()))?;
+        sync_dir(dir)?;
+
+        let current_tmp = dir.join(format!("{CURRENT}.tmp"));
+        write_synced(&current_tmp, name.as_bytes())?;
+        std::fs::rename(&current_tmp, dir.join(CURRENT))
+            .with_context(|| format!("failed to update: {}", dir.join(CURRENT).display()))?;
+        sync_dir(dir)?;
+
+        // The new checkpoint is now current, so older checkpoints are no longer needed.
+        for entry in std::fs::read_dir(dir)?.flatten() {
+            let entry_name = entry.file_name();
+            let entry_name = entry_name.to_string_lossy();
+            if entry_name.starts_with(CHECKPOINT_PREFIX) && entry_name != name {
+                if let Err(e) = std::fs::remove_dir_all(entry.path()) {
+                    tracing::warn!("failed to remove old checkpoint {entry_name}: {e}");
+                }
+            }
+        }
+
+        tracing::info!("saved checkpoint to: {}", path.display());
+        Ok(())
+    }
+
+    /// Loads the latest checkpoint saved to `dir`, returning `None` if there is no checkpoint.
+    pub fn load(dir: &Path) -> anyhow::Result<Option<Self>> {
+        let Some((_, path)) = current_checkpoint(dir)?
+        else {
+            return Ok(None);
+        };
+
+        let read = |name: &str| {
+            let path = path.join(name);
+            std::fs::read(&path).with_context(|| format!("failed to read: {}", path.display()))
+        };
+
+        let manifest: Manifest = ron::de::from_bytes(&read("manifest.ron")?)
+            .with_context(|| format!("invalid checkpoint manifest in: {}", path.display()))?;
+        if manifest.version != VERSION {
+            anyhow::bail!(
+                "unsupported checkpoint version {} (expected {VERSION}) in: {}",
+                manifest.version,
+                path.display()
+            );
+        }
+
+        let block_ids = match manifest.has_block_ids {
+            true => Some(String::from_utf8(read("block_ids.ron")?)?),
+            false => None,
+        };
+
+        Ok(Some(Self {
+            state: read("state.bin")?,
+            coverage: read("coverage.bin")?,
+            seen_blocks: manifest.seen_blocks,
+            block_ids,
+            crashes: manifest.crashes,
+            stats: manifest.stats,
+        }))
+    }
+}
+
+/// Gets the generation and path of the current checkpoint in `dir`.
+fn current_checkpoint(dir: &Path) -> anyhow::Result<Option<(u64, PathBuf)>> {
+    let current = dir.join(CURRENT);
+    let name = match std::fs::read_to_string(&current) {
+        Ok(name) => name,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("failed to read: {}", current.display())),
+    };
+    let name = name.trim();
+    let generation = name
+        .strip_prefix(CHECKPOINT_PREFIX)
+        .and_then(|x| x.parse().ok())
+        .with_context(|| format!("invalid checkpoint name in {}: {name}", current.display()))?;
+    Ok(Some((generation, dir.join(name))))
+}
+
+/// Writes `data` to `path` and flushes it to disk.
+fn write_synced(path: &Path, data: &[u8]) -> anyhow::Result<()> {
+    let mut file = std::fs::File::create(path)
+        .with_context(|| format!("failed to create: {}", path.display()))?;
+    file.write_all(data).with_context(|| format!("failed to write: {}", path.display()))?;
+    file.sync_all().with_context(|| format!("failed to sync: {}", path.display()))
+}
+
+/// Flushes the entries of the directory at `path` to disk.
+fn sync_dir(path: &Path) -> anyhow::Result<()> {
+    // Directories can only be synced on Unix-like platforms.
+    #[cfg(unix)]
+    std::fs::File::open(path)
+        .and_then(|dir| dir.sync_all())
+        .with_context(|| format!("failed to sync: {}", path.display()))?;
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::any::Any;
+
+    use icicle_vm::{
+        VmExit,
+        cpu::{
+            Config, Cpu, Environment,
+            mem::{Mapping, perm},
+        },
+    };
+
+    use super::*;
+
+    /// An environment that does not support saving its state (like the Linux environment).
+    struct NoSaveEnv;
+
+    impl Environment for NoSaveEnv {
+        fn load(&mut self, _: &mut Cpu, _: &[u8]) -> Result<(), String> {
+            Ok(())
+        }
+        fn handle_exception(&mut self, _: &mut Cpu) -> Option<VmExit> {
+            None
+        }
+        fn snapshot(&mut self) -> Box<dyn Any> {
+            Box::new(())
+        }
+        fn restore(&mut self, _: &Box<dyn Any>) {}
+    }
+
+    fn setup_vm() -> Vm {
+        static CODE: &[u8] = &[0x48, 0xff, 0xc0]; // inc rax
+        let mut vm = icicle_vm::build(&Config::from_target_triple("x86_64-none")).unwrap();
+        vm.set_env(NoSaveEnv);
+        let code = Mapping { perm: perm::READ | perm::EXEC, value: 0 };
+        vm.cpu.mem.map_memory_len(0x1000, 0x1000, code);
+        vm.cpu.mem.write_bytes(0x1000, CODE, perm::NONE).unwrap();
+        vm.cpu.mem.map_memory_len(0x4000, 0x1000, Mapping { perm: perm::READ, value: 0 });
+        vm.cpu.write_pc(0x1000);
+        vm
+    }
+
+    #[test]
+    fn checkpoint_and_resume_vm() {
+        let dir = std::env::temp_dir().join(format!("icicle-checkpoint-{}", std::process::id()));
+        assert!(Checkpoint::load(&dir).unwrap().is_none());
+
+        let ids = BlockIds::new();
+        ids.add_module("/bin/target", 0x1000, 0x2000);
+        ids.get_or_insert(0x1010);
+
+        // Run the harness up to the snapshot point.
+        let mut vm = setup_vm();
+        assert_eq!(vm.step(1), VmExit::InstructionLimit);
+        vm.cpu.mem.write_u64(0x4000, 0x1234, perm::NONE).unwrap();
+
+        let mut checkpoint = Checkpoint::capture(&mut vm, Some(&ids)).unwrap();
+        checkpoint.coverage = vec![0, 1, 0, 4];
+        checkpoint.seen_blocks = vec![0x1000];
+        checkpoint.crashes = vec![(0x1000, "0x1000_halt".into())];
+        checkpoint.stats =
+            CampaignStats { total_execs: 10, crashes: 1, ..CampaignStats::default() };
+        checkpoint.save(&dir).unwrap();
+
+        checkpoint.stats.total_execs = 20;
+        checkpoint.save(&dir).unwrap();
+
+        // Resume in a new VM that has been set up the same way.
+        let loaded = Checkpoint::load(&dir).unwrap().unwrap();
+        assert_eq!(loaded.stats.total_execs, 20);
+        assert_eq!(loaded.coverage, checkpoint.coverage);
+        assert_eq!(loaded.seen_blocks, [0x1000]);
+        assert_eq!(loaded.crashes, checkpoint.crashes);
+
+        let restored = BlockIds::new();
+        loaded.restore_block_ids(&restored).unwrap();
+        assert_eq!(restored.len(), 1);
+
+        let mut new_vm = setup_vm();
+        loaded.restore(&mut new_vm).unwrap();
+        assert_eq!(new_vm.cpu.read_pc(), 0x1003);
+        assert_eq!(new_vm.cpu.mem.read_u64(0x4000, perm::NONE).unwrap(), 0x1234);
+        let rax = new_vm.cpu.arch.sleigh.get_varnode("RAX").unwrap();
+        assert_eq!(new_vm.cpu.read_reg(rax), 1);
+
+        new_vm.cpu.write_pc(0x1000);
+        assert_eq!(new_vm.step(1), VmExit::InstructionLimit);
+        assert_eq!(new_vm.cpu.read_reg(rax), 2);
+
+        // Only the latest checkpoint is kept.
+        let checkpoints = std::fs::read_dir(&dir)
+            .unwrap()
+            .flatten()
+            .filter(|x| x.file_name().to_string_lossy().starts_with(CHECKPOINT_PREFIX))
+            .count();
+        assert_eq!(checkpoints, 1);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,303 @@
+//! Triage of crashing executions.
+//!
+//! Produces a normalized backtrace for the crash, a stable hash of the innermost frames that can
+//! be used as a deduplication key, and a rough classification of how severe the crash is likely
+//! to be.
+
+use icicle_vm::{
+    cpu::{debug_info::SourceLocation, ExceptionCode},
+    Vm, VmExit,
+};
+
+use crate::CrashKind;
+
+/// The default number of frames used when computing the stack hash.
+pub const DEFAULT_HASH_FRAMES: usize = 5;
+
+/// Addresses below this value are treated as null pointer dereferences.
+const NULL_PAGE_SIZE: u64 = 0x1000;
+
+/// The granularity that code is loaded at, used to normalize addresses that are not part of a known
+/// module.
+const PAGE_SIZE: u64 = 0x1000;
+
+/// A coarse grouping of crashes based on the kind of fault.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum CrashBucket {
+    /// Execution was redirected to an invalid address, e.g. via a corrupted return address or
+    /// function pointer.
+    ControlFlowHijack,
+
+    /// A write to a wild (non-null) address, e.g. a write-what-where condition.
+    WildWrite,
+
+    /// A read from a wild (non-null) address.
+    WildRead,
+
+    /// A memory error detected by a sanitizer in the guest.
+    Sanitizer,
+
+    /// A read, write or jump via a null (or near-null) pointer.
+    NullDeref,
+
+    /// A timeout or resource exhaustion.
+    ResourceExhaustion,
+
+    /// The program aborted, or was stopped by the environment.
+    Abort,
+
+    /// The execution did not crash.
+    NotCrash,
+
+    /// A crash that could not be classified (e.g. an unsupported instruction).
+    Unknown,
+}
+
+impl CrashBucket {
+    /// Classifies the exit of a VM.
+    pub fn classify(exit: VmExit) -> Self {
+        match exit {
+            VmExit::UnhandledException((ExceptionCode::ShadowStackInvalid, _)) => {
+                Self::ControlFlowHijack
+            }
+            VmExit::UnhandledException((
+                ExceptionCode::InvalidTarget | ExceptionCode::ExecViolation,
+                addr,
+            )) => match addr < NULL_PAGE_SIZE {
+                true => Self::NullDeref,
+                false => Self::ControlFlowHijack,
+            },
+            _ => match CrashKind::from(exit) {
+                CrashKind::Halt | CrashKind::Unreachable(_) => Self::NotCrash,
+                CrashKind::Hang | CrashKind::OutOfMemory => Self::ResourceExhaustion,
+                CrashKind::Killed | CrashKind::Custom(_) => Self::Abort,
+                CrashKind::Sanitizer(_) => Self::Sanitizer,
+                CrashKind::ReadViolation(addr) if addr < NULL_PAGE_SIZE => Self::NullDeref,
+                CrashKind::WriteViolation(addr) if addr < NULL_PAGE_SIZE => Self::NullDeref,
+                CrashKind::ReadViolation(_) => Self::WildRead,
+                CrashKind::WriteViolation(_) => Self::WildWrite,
+                CrashKind::ExecViolation | CrashKind::Unknown => Self::Unknown,
+            },
+        }
+    }
+
+    /// Returns the likely severity of crashes in this bucket.
+    pub fn severity(&self) -> Severity {
+        match self {
+            Self::ControlFlowHijack | Self::WildWrite => Severity::Critical,
+            Self::WildRead | Self::Sanitizer => Severity::High,
+            Self::NullDeref | Self::Unknown => Severity::Medium,
+            Self::ResourceExhaustion | Self::Abort => Severity::Low,
+            Self::NotCrash => Severity::None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::ControlFlowHijack => "control_flow_hijack",
+            Self::WildWrite => "wild_write",
+            Self::WildRead => "wild_read",
+            Self::Sanitizer => "sanitizer",
+            Self::NullDeref => "null_deref",
+            Self::ResourceExhaustion => "resource_exhaustion",
+            Self::Abort => "abort",
+            Self::NotCrash => "not_crash",
+            Self::Unknown => "unknown",
+        }
+    }
+}
+
+impl std::fmt::Display for CrashBucket {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Severity {
+    None,
+    Low,
+    Medium,
+    High,
+    Critical,
+}
+
+/// A frame of a normalized backtrace.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NormalizedFrame {
+    /// The address of the frame (for frames other than the innermost one, this is the address of
+    /// the call instruction).
+    pub addr: u64,
+
+    /// A representation of `addr` that is independent of where code was loaded, either
+    /// `function+offset`, `module+offset` or `<unknown>+offset` (the offset within the page) if
+    /// neither is known.
+    pub label: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct CrashTriage {
+    /// The exit that was triaged.
+    pub exit: VmExit,
+
+    pub bucket: CrashBucket,
+
+    /// The frames of the backtrace at the crash, innermost first.
+    pub frames: Vec<NormalizedFrame>,
+
+    /// A hash of the innermost frames of the backtrace.
+    pub stack_hash: u64,
+}
+
+impl CrashTriage {
+    pub fn severity(&self) -> Severity {
+        self.bucket.severity()
+    }
+
+    /// A key that is stable across executions (and different load addresses) that crash in the
+    /// same way.
+    pub fn key(&self) -> String {
+        format!("{:016x}_{}", self.stack_hash, self.bucket)
+    }
+}
+
+impl std::fmt::Display for CrashTriage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{:?} ({}, severity: {:?})", self.exit, self.bucket, self.severity())?;
+        writeln!(f, "stack hash: {:016x}", self.stack_hash)?;
+        for frame in &self.frames {
+            writeln!(f, "  {:#012x}: {}", frame.addr, frame.label)?;
+        }
+        Ok(())
+    }
+}
+
+/// Triages a VM that exited with `exit`, hashing the innermost `hash_frames` frames of the
+/// backtrace.
+///
+/// See [normalized_backtrace] for how the backtrace is obtained.
+pub fn triage(vm: &mut Vm, exit: VmExit, hash_frames: usize) -> CrashTriage {
+    let bucket = CrashBucket::classify(exit);
+    let frames = normalized_backtrace(vm);
+
+    // When control flow was redirected, the innermost frame is at an invalid address that often
+    // varies between inputs, so only the callers are used to deduplicate the crash.
+    let is_invalid_jump = matches!(
+        exit,
+        VmExit::UnhandledException((ExceptionCode::InvalidTarget | ExceptionCode::ExecViolation, _))
+    );
+    let skip = match bucket == CrashBucket::ControlFlowHijack || is_invalid_jump {
+        true => 1,
+        false => 0,
+    };
+
+    let mut hashed = String::new();
+    for frame in frames.iter().skip(skip).take(hash_frames) {
+        hashed.push_str(&frame.label);
+        hashed.push('\n');
+    }
+    let stack_hash = icicle_vm::translation_cache::hash_bytes(hashed.as_bytes());
+
+    CrashTriage { exit, bucket, frames, stack_hash }
+}
+
+/// Returns the backtrace of the current state of the VM with each frame normalized relative to
+/// the function or module that contains it.
+///
+/// The backtrace is obtained from the shadow stack if it is enabled, otherwise the stack is
+/// unwound using call frame information (see [Vm::backtrace]).
+pub fn normalized_backtrace(vm: &mut Vm) -> Vec<NormalizedFrame> {
+    // Use the address of the call instruction instead of the return address for all frames
+    // except the innermost one.
+    let addrs: Vec<u64> = match vm.cpu.enable_shadow_stack {
+        true => vm
+            .get_callstack()
+            .into_iter()
+            .rev()
+            .enumerate()
+            .map(|(i, addr)| if i == 0 { addr } else { addr.wrapping_sub(1) })
+            .collect(),
+        false => vm.backtrace().iter().map(|x| x.frame.lookup_pc()).collect(),
+    };
+
+    let mut frames = vec![];
+    for addr in addrs {
+        let location = vm.env.symbolize_addr(&mut vm.cpu, addr);
+        frames.push(NormalizedFrame { addr, label: frame_label(location, addr) });
+    }
+    frames
+}
+
+/// Gets a label for `addr` that does not depend on where the code was loaded.
+fn frame_label(location: Option<SourceLocation>, addr: u64) -> String {
+    let location = location.unwrap_or_default();
+    match (location.function, location.symbol_with_offset, location.library_name_and_offset) {
+        (Some((name, start)), ..) => format!("{name}+{:#x}", addr.wrapping_sub(start)),
+        (None, Some((name, offset)), _) => format!("{name}+{offset:#x}"),
+        (None, None, Some((path, base))) => {
+            let path = String::from_utf8_lossy(&path);
+            let name = path.rsplit('/').next().unwrap_or(&path);
+            format!("{name}+{:#x}", addr.wrapping_sub(base))
+        }
+        // Code is loaded at page granularity, so the offset within the page is independent of the
+        // load address.
+        (None, None, None) => format!("<unknown>+{:#x}", addr & (PAGE_SIZE - 1)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classify_exits() {
+        let exception = |code, addr| VmExit::UnhandledException((code, addr));
+
+        let bucket = CrashBucket::classify(exception(ExceptionCode::WriteUnmapped, 0x4141_4141));
+        assert_eq!(bucket, CrashBucket::WildWrite);
+        assert_eq!(bucket.severity(), Severity::Critical);
+
+        let bucket = CrashBucket::classify(exception(ExceptionCode::ReadUnmapped, 0x8));
+        assert_eq!(bucket, CrashBucket::NullDeref);
+
+        let bucket = CrashBucket::classify(exception(ExceptionCode::ReadPerm, 0x10_0000));
+        assert_eq!(bucket, CrashBucket::WildRead);
+
+        let bucket = CrashBucket::classify(exception(ExceptionCode::ExecViolation, 0));
+        assert_eq!(bucket, CrashBucket::NullDeref);
+
+        let bucket = CrashBucket::classify(exception(ExceptionCode::ShadowStackInvalid, 0));
+        assert_eq!(bucket, CrashBucket::ControlFlowHijack);
+
+        assert_eq!(CrashBucket::classify(VmExit::Halt), CrashBucket::NotCrash);
+        assert_eq!(CrashBucket::classify(VmExit::InstructionLimit).severity(), Severity::Low);
+        assert!(Severity::Critical > Severity::High);
+    }
+
+    #[test]
+    fn stack_hash_is_independent_of_load_address() {
+        use icicle_vm::cpu::mem::{perm, Mapping};
+
+        let crash_at = |base: u64| {
+            let config = icicle_vm::cpu::Config::from_target_triple("x86_64-none");
+            let mut vm = icicle_vm::build(&config).unwrap();
+            let mapping = Mapping { perm: perm::READ | perm::EXEC, value: 0 };
+            vm.cpu.mem.map_memory_len(base, 0x1000, mapping);
+            // nop
+            // mov rax, qword ptr [0x1000000]
+            let code = [0x90, 0x48, 0x8b, 0x04, 0x25, 0x00, 0x00, 0x00, 0x01];
+            vm.cpu.mem.write_bytes(base, &code, perm::NONE).unwrap();
+            vm.cpu.write_pc(base);
+            let exit = vm.run();
+            triage(&mut vm, exit, DEFAULT_HASH_FRAMES)
+        };
+
+        let a = crash_at(0x10_0000);
+        let b = crash_at(0x23_0000);
+        assert_eq!(a.bucket, CrashBucket::WildRead);
+        assert_eq!(a.frames[0].addr, 0x10_0001);
+        assert_eq!(a.frames[0].label, "<unknown>+0x1");
+        assert_eq!(a.stack_hash, b.stack_hash);
+        assert_eq!(a.key(), b.key());
+    }
+}
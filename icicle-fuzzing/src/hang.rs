@@ -0,0 +1,133 @@
+//! Triage of executions that exit due to a timeout.
+//!
+//! A timeout on its own says very little about why the target got stuck. After a hang, the VM is
+//! single-stepped for a short window to find the code the target is spending its time in, and the
+//! environment is checked to see whether the target is waiting for more input than it was given.
+
+use std::collections::HashMap;
+
+use icicle_vm::{linux::PauseReason, Vm, VmExit};
+
+use crate::Runnable;
+
+/// The default number of instructions to sample after a hang.
+pub const DEFAULT_WINDOW: u64 = 0x4000;
+
+/// The maximum number of blocks included in a report.
+const MAX_HOT_BLOCKS: usize = 5;
+
+#[derive(Clone, Debug)]
+pub struct HotBlock {
+    /// The start address of the block.
+    pub addr: u64,
+
+    /// The number of instructions executed in the block during the sampling window.
+    pub icount: u64,
+
+    /// The symbolized location of the block (if known).
+    pub location: Option<String>,
+}
+
+#[derive(Clone, Debug)]
+pub struct HangReport {
+    /// The exit that was triaged.
+    pub exit: VmExit,
+
+    /// The symbolized backtrace at the time of the exit.
+    pub backtrace: String,
+
+    /// The blocks executed most frequently after the exit, ordered by the number of instructions
+    /// executed in each block.
+    pub hot_blocks: Vec<HotBlock>,
+
+    /// The number of instructions that were sampled.
+    pub sampled: u64,
+
+    /// If the target appears to be blocked waiting for input, a description of what it is
+    /// waiting for.
+    pub blocked_on_input: Option<String>,
+}
+
+impl std::fmt::Display for HangReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{:?}", self.exit)?;
+        if let Some(reason) = self.blocked_on_input.as_ref() {
+            writeln!(f, "blocked on input: {reason}")?;
+        }
+        writeln!(f, "hot blocks ({} instructions sampled):", self.sampled)?;
+        for block in &self.hot_blocks {
+            let percent = 100.0 * block.icount as f64 / self.sampled.max(1) as f64;
+            write!(f, "  {:#012x}: {percent:5.1}%", block.addr)?;
+            if let Some(location) = block.location.as_ref() {
+                write!(f, " {location}")?;
+            }
+            writeln!(f)?;
+        }
+        write!(f, "callstack:\n{}", self.backtrace)
+    }
+}
+
+/// Triages a VM that exited with `exit` due to a timeout, by sampling the next `window`
+/// instructions executed by the target.
+///
+/// Note: this continues execution of the VM, so any other information about the exit should be
+/// captured before calling this function.
+pub fn triage<T: Runnable>(
+    target: &mut T,
+    vm: &mut Vm,
+    exit: VmExit,
+    window: u64,
+) -> HangReport {
+    let backtrace = icicle_vm::debug::backtrace(vm);
+    let eof_reads = target.input_eof_reads(vm);
+
+    let mut counts = HashMap::new();
+    let mut sampled = 0;
+    while sampled < window {
+        let block = vm.get_current_block().and_then(|(id, _)| vm.code.blocks.get(id as usize));
+        if let Some(block) = block {
+            *counts.entry(block.start).or_insert(0) += 1;
+        }
+        match vm.step(1) {
+            VmExit::Running | VmExit::InstructionLimit => sampled += 1,
+            _ => break,
+        }
+    }
+
+    let mut hot_blocks: Vec<_> = counts.into_iter().collect();
+    hot_blocks.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    let hot_blocks = hot_blocks
+        .into_iter()
+        .take(MAX_HOT_BLOCKS)
+        .map(|(addr, icount)| HotBlock {
+            addr,
+            icount,
+            location: vm.env.symbolize_addr(&mut vm.cpu, addr).map(|x| x.to_string()),
+        })
+        .collect();
+
+    let blocked_on_input = match (eof_reads, target.input_eof_reads(vm)) {
+        (Some(before), Some(after)) if after > before => {
+            Some(format!("read past the end of the input {} times", after - before))
+        }
+        _ => waiting_processes(vm),
+    };
+
+    HangReport { exit, backtrace, hot_blocks, sampled, blocked_on_input }
+}
+
+/// Describes the Linux processes that are suspended waiting for a file to become ready.
+fn waiting_processes(vm: &Vm) -> Option<String> {
+    let kernel = vm.env_ref::<icicle_vm::linux::Kernel>()?;
+    let pids: Vec<_> = kernel
+        .process_manager
+        .parked
+        .iter()
+        .filter(|x| matches!(x.pause_reason, PauseReason::WaitFile))
+        .map(|x| x.process.pid.to_string())
+        .collect();
+    if pids.is_empty() {
+        return None;
+    }
+    Some(format!("waiting for a file to become ready (pid: {})", pids.join(", ")))
+}
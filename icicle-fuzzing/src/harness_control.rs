@@ -0,0 +1,239 @@
+//! A small block of MMIO registers that allows code running in the guest (e.g. shims injected into
+//! the target) to cooperate with the fuzzer explicitly.
+//!
+//! ```text
+//! regs + 0x00: u64 (read)  number of input bytes that have not been consumed
+//! regs + 0x08:     (read)  consumes the next bytes of the input (one for each byte of the access).
+//!                          Bytes past the end of the input read as zero.
+//! regs + 0x10:     (write) "next input" doorbell: stops executing the current input
+//! regs + 0x18: u64 (write) reports a custom feedback value to the fuzzer (see
+//!                          [HarnessControl::set_feedback_channel])
+//! ```
+//!
+//! The block is only present if the harness maps it with [HarnessControl::map]. Values are
+//! accessed in the endianness of the target. Input bytes are consumed using an
+//! [icicle_vm::mmio_input::InputMmio] peripheral, so reads are tracked the same way as reads from
+//! fuzzed peripherals.
+
+use std::any::Any;
+
+use icicle_vm::{
+    cpu::mem::{IoHandler, MemError, MemResult, MmioHandler},
+    mmio_input::InputMmio,
+    Vm, VmExit,
+};
+
+use crate::{feedback::FeedbackChannel, Runnable};
+
+/// The size of the register block.
+pub const REGS_SIZE: u64 = 0x20;
+
+const REMAINING_OFFSET: u64 = 0x00;
+const READ_OFFSET: u64 = 0x08;
+const NEXT_INPUT_OFFSET: u64 = 0x10;
+const FEEDBACK_OFFSET: u64 = 0x18;
+
+/// The maximum number of feedback values kept for a single input, additional values are dropped.
+pub const MAX_FEEDBACK: usize = 0x1000;
+
+/// The peripheral that implements the control registers.
+#[derive(Default)]
+pub struct ControlRegisters {
+    base: u64,
+    input: InputMmio,
+
+    /// The number of reads that were (at least partially) past the end of the input.
+    pub eof_reads: u64,
+
+    /// Whether the guest rang the "next input" doorbell.
+    pub next_input_requested: bool,
+
+    /// Feedback values reported by the guest for the current input (at most [MAX_FEEDBACK]).
+    pub feedback: Vec<u64>,
+}
+
+impl ControlRegisters {
+    /// Replaces the current input, and resets all per-input state.
+    pub fn set_input(&mut self, input: &[u8]) {
+        self.input.set_input(input);
+        self.eof_reads = 0;
+        self.next_input_requested = false;
+        self.feedback.clear();
+    }
+
+    /// The number of input bytes that have not been consumed by the guest.
+    pub fn remaining(&self) -> usize {
+        self.input.remaining()
+    }
+
+    fn take(&mut self, addr: u64, size: usize) -> u64 {
+        if self.input.remaining() < size {
+            self.eof_reads += 1;
+        }
+        self.input.take_padded(addr, size)
+    }
+}
+
+impl MmioHandler for ControlRegisters {
+    fn load(&mut self, addr: u64, size: u8) -> MemResult<u64> {
+        match addr.wrapping_sub(self.base) {
+            REMAINING_OFFSET => Ok(self.remaining() as u64),
+            READ_OFFSET => Ok(self.take(addr, size as usize)),
+            _ => Ok(0),
+        }
+    }
+
+    fn store(&mut self, addr: u64, _size: u8, value: u64) -> MemResult<()> {
+        match addr.wrapping_sub(self.base) {
+            NEXT_INPUT_OFFSET => {
+                // Stop execution at the store, the exit is reported to the fuzzer by
+                // [HarnessControl::run].
+                self.next_input_requested = true;
+                Err(MemError::WriteWatch)
+            }
+            FEEDBACK_OFFSET => {
+                if self.feedback.len() < MAX_FEEDBACK {
+                    self.feedback.push(value);
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn snapshot(&mut self) -> Box<dyn Any> {
+        let input = self.input.snapshot();
+        Box::new((input, self.eof_reads, self.next_input_requested, self.feedback.clone()))
+    }
+
+    fn restore(&mut self, snapshot: &Box<dyn Any>) {
+        let (input, eof_reads, next_input_requested, feedback) =
+            snapshot.downcast_ref::<(Box<dyn Any>, u64, bool, Vec<u64>)>().unwrap();
+        self.input.restore(input);
+        self.eof_reads = *eof_reads;
+        self.next_input_requested = *next_input_requested;
+        self.feedback.clone_from(feedback);
+    }
+}
+
+/// A handle to the control registers mapped into a VM.
+pub struct HarnessControl {
+    id: IoHandler,
+
+    /// If set, feedback values reported by the guest are merged into the coverage map.
+    feedback_channel: Option<FeedbackChannel>,
+}
+
+impl HarnessControl {
+    /// Maps the control registers at `addr`.
+    pub fn map(vm: &mut Vm, addr: u64) -> anyhow::Result<Self> {
+        let regs = ControlRegisters { base: addr, ..ControlRegisters::default() };
+        let Some(id) = vm.cpu.mem.map_mmio(addr, REGS_SIZE, regs)
+        else {
+            anyhow::bail!("failed to map harness control registers at {addr:#x}");
+        };
+        Ok(Self { id, feedback_channel: None })
+    }
+
+    /// Merges feedback values reported by the guest into the coverage map using `channel`.
+    ///
+    /// Values are merged when execution stops, after which they are no longer returned by
+    /// [HarnessControl::take_feedback].
+    pub fn set_feedback_channel(&mut self, channel: FeedbackChannel) {
+        self.feedback_channel = Some(channel);
+    }
+
+    /// Gets the state of the control registers.
+    pub fn regs<'a>(&self, vm: &'a mut Vm) -> &'a mut ControlRegisters {
+        vm.cpu.mem.get_mmio_handler_mut(self.id).unwrap()
+    }
+
+    /// Removes the feedback values reported by the guest since the last call.
+    pub fn take_feedback(&self, vm: &mut Vm) -> Vec<u64> {
+        std::mem::take(&mut self.regs(vm).feedback)
+    }
+}
+
+impl Runnable for HarnessControl {
+    fn set_input(&mut self, vm: &mut Vm, input: &[u8]) -> anyhow::Result<()> {
+        self.regs(vm).set_input(input);
+        Ok(())
+    }
+
+    fn modify_input(&mut self, vm: &mut Vm, offset: u64, input: &[u8]) -> anyhow::Result<()> {
+        let current = self.regs(vm).input.input_mut();
+        let len = current.len();
+        let Some(dst) = current.get_mut(offset as usize..offset as usize + input.len())
+        else {
+            anyhow::bail!("modification at {offset:#x} exceeds input length ({len:#x})");
+        };
+        dst.copy_from_slice(input);
+        Ok(())
+    }
+
+    fn get_input_cursor(&mut self, vm: &mut Vm) -> u64 {
+        self.regs(vm).input.consumed() as u64
+    }
+
+    fn input_eof_reads(&mut self, vm: &mut Vm) -> Option<u64> {
+        Some(self.regs(vm).eof_reads)
+    }
+
+    /// Runs the current input, treating a request for the next input as a normal exit.
+    fn run(&mut self, vm: &mut Vm) -> anyhow::Result<VmExit> {
+        let exit = vm.run();
+        if let Some(channel) = self.feedback_channel {
+            for value in self.take_feedback(vm) {
+                channel.report(&mut vm.cpu, value);
+            }
+        }
+        if self.regs(vm).next_input_requested {
+            return Ok(VmExit::Halt);
+        }
+        Ok(exit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use icicle_vm::cpu::mem::perm;
+
+    use super::*;
+
+    #[test]
+    fn guest_controls_input_and_feedback() {
+        let config = icicle_vm::cpu::Config::from_target_triple("x86_64-none");
+        let mut vm = icicle_vm::build(&config).unwrap();
+        let mut control = HarnessControl::map(&mut vm, 0x2000).unwrap();
+
+        control.set_input(&mut vm, b"abc").unwrap();
+        assert_eq!(vm.cpu.mem.read_u64(0x2000, perm::READ).unwrap(), 3);
+        assert_eq!(vm.cpu.mem.read_u16(0x2008, perm::READ).unwrap(), u16::from_le_bytes(*b"ab"));
+        assert_eq!(vm.cpu.mem.read_u64(0x2000, perm::READ).unwrap(), 1);
+
+        // Reads past the end of the input are padded with zeroes.
+        assert_eq!(vm.cpu.mem.read_u16(0x2008, perm::READ).unwrap(), b'c' as u16);
+        assert_eq!(vm.cpu.mem.read_u64(0x2000, perm::READ).unwrap(), 0);
+        assert_eq!(control.input_eof_reads(&mut vm), Some(1));
+        assert_eq!(control.get_input_cursor(&mut vm), 3);
+
+        vm.cpu.mem.write_u64(0x2018, 0x1234, perm::WRITE).unwrap();
+        vm.cpu.mem.write_u64(0x2018, 0x5678, perm::WRITE).unwrap();
+        assert_eq!(control.take_feedback(&mut vm), [0x1234, 0x5678]);
+
+        // Feedback values past the limit are dropped.
+        for i in 0..MAX_FEEDBACK as u64 + 10 {
+            vm.cpu.mem.write_u64(0x2018, i, perm::WRITE).unwrap();
+        }
+        let feedback = control.take_feedback(&mut vm);
+        assert_eq!(feedback.len(), MAX_FEEDBACK);
+        assert_eq!(feedback.last(), Some(&(MAX_FEEDBACK as u64 - 1)));
+
+        assert!(vm.cpu.mem.write_u64(0x2010, 1, perm::WRITE).is_err());
+        assert!(control.regs(&mut vm).next_input_requested);
+
+        control.set_input(&mut vm, b"x").unwrap();
+        assert!(!control.regs(&mut vm).next_input_requested);
+        assert_eq!(vm.cpu.mem.read_u64(0x2000, perm::READ).unwrap(), 1);
+    }
+}
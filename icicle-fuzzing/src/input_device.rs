@@ -0,0 +1,165 @@
+//! A paravirtual input device that exposes the fuzz input directly in guest memory.
+//!
+//! The device consists of two regions:
+//!
+//! - A read-only data region containing the current input. Bytes past the end of the input read as
+//!   zero.
+//! - A read-only register block describing the input:
+//!
+//! ```text
+//! regs + 0x00: u64 length of the current input
+//! regs + 0x08: u64 capacity of the data region
+//! regs + 0x10: u64 address of the data region
+//! ```
+//!
+//! Both regions are regular memory (not I/O memory), so guests (or shims injected into the target)
+//! can read the input without any exits to the emulator. Registers are stored in the endianness of
+//! the target.
+//!
+//! Note: since the device is backed by regular memory, its contents are saved and restored as part
+//! of VM snapshots. When restoring a snapshot before each input, [InputDevice::set_input] should be
+//! called after the snapshot is restored.
+
+use icicle_vm::{
+    cpu::mem::{perm, Mapping},
+    Vm,
+};
+
+use crate::Runnable;
+
+/// The size of the register block.
+const REGS_SIZE: u64 = 0x1000;
+
+const LEN_OFFSET: u64 = 0x0;
+const CAPACITY_OFFSET: u64 = 0x8;
+const DATA_ADDR_OFFSET: u64 = 0x10;
+
+pub struct InputDevice {
+    data_addr: u64,
+    capacity: u64,
+    regs_addr: u64,
+}
+
+impl InputDevice {
+    /// Maps an input device with space for `capacity` bytes of input at `data_addr`, and the
+    /// registers describing the input at `regs_addr`.
+    pub fn map(vm: &mut Vm, data_addr: u64, capacity: u64, regs_addr: u64) -> anyhow::Result<Self> {
+        let mapping = Mapping { perm: perm::READ | perm::INIT, value: 0 };
+        if capacity == 0 || !vm.cpu.mem.map_memory_len(data_addr, capacity, mapping) {
+            anyhow::bail!("failed to map input data at {data_addr:#x} (capacity={capacity:#x})");
+        }
+        if !vm.cpu.mem.map_memory_len(regs_addr, REGS_SIZE, mapping) {
+            anyhow::bail!("failed to map input registers at {regs_addr:#x}");
+        }
+
+        let device = Self { data_addr, capacity, regs_addr };
+        device.write_reg(vm, CAPACITY_OFFSET, capacity)?;
+        device.write_reg(vm, DATA_ADDR_OFFSET, data_addr)?;
+        device.write_reg(vm, LEN_OFFSET, 0)?;
+        Ok(device)
+    }
+
+    pub fn data_addr(&self) -> u64 {
+        self.data_addr
+    }
+
+    pub fn regs_addr(&self) -> u64 {
+        self.regs_addr
+    }
+
+    pub fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    fn write_reg(&self, vm: &mut Vm, offset: u64, value: u64) -> anyhow::Result<()> {
+        let bytes = match vm.cpu.arch.sleigh.big_endian {
+            true => value.to_be_bytes(),
+            false => value.to_le_bytes(),
+        };
+        vm.cpu
+            .mem
+            .write_bytes(self.regs_addr + offset, &bytes, perm::NONE)
+            .map_err(|e| anyhow::format_err!("failed to write input register: {e:?}"))
+    }
+
+    fn read_reg(&self, vm: &mut Vm, offset: u64) -> anyhow::Result<u64> {
+        let mut bytes = [0; 8];
+        vm.cpu
+            .mem
+            .read_bytes(self.regs_addr + offset, &mut bytes, perm::NONE)
+            .map_err(|e| anyhow::format_err!("failed to read input register: {e:?}"))?;
+        Ok(match vm.cpu.arch.sleigh.big_endian {
+            true => u64::from_be_bytes(bytes),
+            false => u64::from_le_bytes(bytes),
+        })
+    }
+
+    /// Gets the length of the input currently exposed to the guest.
+    pub fn input_len(&self, vm: &mut Vm) -> anyhow::Result<u64> {
+        self.read_reg(vm, LEN_OFFSET)
+    }
+
+    /// Copies `input` into the data region and updates the length register. Inputs larger than the
+    /// capacity of the device are truncated.
+    pub fn set_input(&self, vm: &mut Vm, input: &[u8]) -> anyhow::Result<()> {
+        let input = &input[..input.len().min(self.capacity as usize)];
+
+        // Clear any bytes from the previous input, so reads past the end of the new input return
+        // zero. The previous length is read from memory, since it may have been changed by
+        // restoring a snapshot.
+        let prev_len = self.input_len(vm)?.min(self.capacity);
+        if prev_len > input.len() as u64 {
+            let zeroes = vec![0; (prev_len - input.len() as u64) as usize];
+            self.write_data(vm, input.len() as u64, &zeroes)?;
+        }
+
+        self.write_data(vm, 0, input)?;
+        self.write_reg(vm, LEN_OFFSET, input.len() as u64)
+    }
+
+    fn write_data(&self, vm: &mut Vm, offset: u64, data: &[u8]) -> anyhow::Result<()> {
+        vm.cpu
+            .mem
+            .write_bytes(self.data_addr + offset, data, perm::NONE)
+            .map_err(|e| anyhow::format_err!("failed to write input data: {e:?}"))
+    }
+}
+
+impl Runnable for InputDevice {
+    fn set_input(&mut self, vm: &mut Vm, input: &[u8]) -> anyhow::Result<()> {
+        InputDevice::set_input(self, vm, input)
+    }
+
+    fn modify_input(&mut self, vm: &mut Vm, offset: u64, input: &[u8]) -> anyhow::Result<()> {
+        let len = self.input_len(vm)?;
+        if offset.checked_add(input.len() as u64).filter(|end| *end <= len).is_none() {
+            anyhow::bail!("modification at {offset:#x} exceeds input length ({len:#x})");
+        }
+        self.write_data(vm, offset, input)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shorter_input_clears_previous_bytes() {
+        let config = icicle_vm::cpu::Config::from_target_triple("x86_64-none");
+        let mut vm = icicle_vm::build(&config).unwrap();
+        let device = InputDevice::map(&mut vm, 0x10_0000, 0x1000, 0x20_0000).unwrap();
+        assert_eq!(device.read_reg(&mut vm, CAPACITY_OFFSET).unwrap(), 0x1000);
+        assert_eq!(device.read_reg(&mut vm, DATA_ADDR_OFFSET).unwrap(), 0x10_0000);
+
+        device.set_input(&mut vm, b"hello world").unwrap();
+        device.set_input(&mut vm, b"abc").unwrap();
+        assert_eq!(device.input_len(&mut vm).unwrap(), 3);
+
+        let mut buf = [0xff; 8];
+        vm.cpu.mem.read_bytes(0x10_0000, &mut buf, perm::READ).unwrap();
+        assert_eq!(&buf, b"abc\0\0\0\0\0");
+
+        // Guests cannot modify the input.
+        assert!(vm.cpu.mem.write_bytes(0x10_0000, b"x", perm::WRITE).is_err());
+    }
+}
@@ -0,0 +1,249 @@
+//! Stable identifiers for blocks, used to keep coverage maps comparable across runs.
+//!
+//! By default, coverage instrumentation derives the index of a block in the coverage map from the
+//! address of the block, so the index changes whenever the module containing the block is loaded
+//! at a different address. With [BlockIds], each block is instead identified by the module it
+//! belongs to and its offset within that module, and is assigned an ID the first time it is seen.
+//! The mapping can be saved to a sidecar file and loaded on the next run, so a block keeps the same
+//! coverage index after it is relifted or after the campaign is restarted.
+
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, HashMap},
+    path::Path,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use icicle_vm::Vm;
+
+/// The minimum time between saves performed by [BlockIds::maybe_save].
+const SAVE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The location of a block, independent of the address the module containing it was loaded at.
+#[derive(
+    Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, serde::Serialize, serde::Deserialize,
+)]
+pub struct StableBlock {
+    /// The name of the module containing the block, empty if the block is not part of any known
+    /// module.
+    pub module: String,
+
+    /// The offset of the block from the start of the module, or the address of the block if the
+    /// module is unknown.
+    pub offset: u64,
+}
+
+#[derive(Default)]
+struct BlockIdState {
+    /// Regions of memory that belong to a known module, keyed by start address: (end, name).
+    regions: BTreeMap<u64, (u64, String)>,
+
+    /// The lowest address each module is mapped at.
+    bases: HashMap<String, u64>,
+
+    ids: BTreeMap<StableBlock, u32>,
+    next: u32,
+
+    /// Whether new IDs have been assigned since the mapping was last saved.
+    dirty: bool,
+
+    /// The time the mapping was last saved.
+    last_save: Option<Instant>,
+}
+
+impl BlockIdState {
+    fn locate(&self, addr: u64) -> StableBlock {
+        match self.regions.range(..=addr).next_back() {
+            Some((_, (end, name))) if addr < *end => {
+                let base = self.bases.get(name).copied().unwrap_or(0);
+                StableBlock { module: name.clone(), offset: addr - base }
+            }
+            _ => StableBlock { module: String::new(), offset: addr },
+        }
+    }
+}
+
+/// A shared handle to a block ID mapping. Cloning the handle produces a handle to the same mapping.
+#[derive(Clone, Default)]
+pub struct BlockIds {
+    state: Rc<RefCell<BlockIdState>>,
+}
+
+impl BlockIds {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Loads a mapping previously saved with [BlockIds::save]. If `path` does not exist an empty
+    /// mapping is returned.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let ids = Self::new();
+        let data = match std::fs::read_to_string(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(ids),
+            Err(e) => {
+                return Err(e).with_context(|| format!("failed to read: {}", path.display()));
+            }
+        };
+
+        ids.parse(&data)
+            .with_context(|| format!("error parsing block IDs from: {}", path.display()))?;
+        tracing::debug!("loaded {} block IDs from: {}", ids.len(), path.display());
+
+        Ok(ids)
+    }
+
+    /// Saves the mapping to `path`. The file is replaced atomically so a concurrent reader never
+    /// observes a partially written mapping.
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let data = self.serialize()?;
+
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, data).with_context(|| format!("failed to write: {}", tmp.display()))?;
+        std::fs::rename(&tmp, path)
+            .with_context(|| format!("failed to write: {}", path.display()))?;
+
+        let mut state = self.state.borrow_mut();
+        state.dirty = false;
+        state.last_save = Some(Instant::now());
+        Ok(())
+    }
+
+    /// Saves the mapping to `path` if new IDs have been assigned and enough time has passed since
+    /// the last save, avoiding a write for every execution that discovers a new block.
+    pub fn maybe_save(&self, path: &Path) -> anyhow::Result<()> {
+        let state = self.state.borrow();
+        let due = state.last_save.is_none_or(|time| time.elapsed() >= SAVE_INTERVAL);
+        if !state.dirty || !due {
+            return Ok(());
+        }
+        drop(state);
+        self.save(path)
+    }
+
+    /// Serializes the mapping in the format used by [BlockIds::save]. Unlike `save` this does not
+    /// mark the mapping as saved.
+    pub fn serialize(&self) -> anyhow::Result<String> {
+        let state = self.state.borrow();
+        Ok(ron::ser::to_string_pretty(&state.ids, ron::ser::PrettyConfig::default())?)
+    }
+
+    /// Replaces the mapping with one serialized by [BlockIds::serialize].
+    pub fn parse(&self, data: &str) -> anyhow::Result<()> {
+        let entries: BTreeMap<StableBlock, u32> = ron::from_str(data)?;
+        let mut state = self.state.borrow_mut();
+        state.next = entries.values().max().map_or(0, |x| x + 1);
+        state.ids = entries;
+        Ok(())
+    }
+
+    /// Returns whether new IDs have been assigned since the mapping was last saved.
+    pub fn is_dirty(&self) -> bool {
+        self.state.borrow().dirty
+    }
+
+    /// Registers the memory in `start..end` as part of the module `name`. A module can consist of
+    /// multiple regions, offsets are relative to the lowest address the module is mapped at.
+    pub fn add_module(&self, name: &str, start: u64, end: u64) {
+        let mut state = self.state.borrow_mut();
+        state.regions.insert(start, (end, name.to_owned()));
+        let base = state.bases.entry(name.to_owned()).or_insert(start);
+        *base = (*base).min(start);
+    }
+
+    /// Registers all files currently mapped by the active Linux process as modules. Does nothing
+    /// if the VM is not running a Linux environment.
+    ///
+    /// Note: blocks are located at the time they are lifted, so this should be called before
+    /// executing code from modules that have been loaded since the last call.
+    pub fn add_linux_modules(&self, vm: &Vm) {
+        let Some(kernel) = vm.env_ref::<icicle_vm::linux::Kernel>()
+        else {
+            return;
+        };
+        for (start, entry) in &kernel.process.mapping {
+            self.add_module(&String::from_utf8_lossy(&entry.path), *start, entry.end);
+        }
+    }
+
+    /// Gets the stable location of the block at `addr`.
+    pub fn locate(&self, addr: u64) -> StableBlock {
+        self.state.borrow().locate(addr)
+    }
+
+    /// Gets the ID of the block at `addr`, if one has been assigned.
+    pub fn get(&self, addr: u64) -> Option<u32> {
+        let state = self.state.borrow();
+        state.ids.get(&state.locate(addr)).copied()
+    }
+
+    /// Gets the ID of the block at `addr`, assigning a new ID if the block has not been seen
+    /// before.
+    pub fn get_or_insert(&self, addr: u64) -> u32 {
+        let mut state = self.state.borrow_mut();
+        let block = state.locate(addr);
+        if let Some(id) = state.ids.get(&block) {
+            return *id;
+        }
+
+        let id = state.next;
+        tracing::trace!("new block ID {id} for {addr:#x} ({}+{:#x})", block.module, block.offset);
+        state.next += 1;
+        state.ids.insert(block, id);
+        state.dirty = true;
+        id
+    }
+
+    /// Finds the block that was assigned `id`.
+    pub fn lookup(&self, id: u32) -> Option<StableBlock> {
+        self.state.borrow().ids.iter().find(|(_, x)| **x == id).map(|(block, _)| block.clone())
+    }
+
+    /// The number of blocks that have been assigned an ID.
+    pub fn len(&self) -> usize {
+        self.state.borrow().ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.state.borrow().ids.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ids_are_independent_of_load_address() {
+        let ids = BlockIds::new();
+        ids.add_module("/lib/libc.so", 0x1000, 0x2000);
+        ids.add_module("/lib/libc.so", 0x3000, 0x4000);
+        let a = ids.get_or_insert(0x1010);
+        let b = ids.get_or_insert(0x3020);
+        let unknown = ids.get_or_insert(0x9000);
+
+        let libc = |offset| StableBlock { module: "/lib/libc.so".into(), offset };
+        assert_eq!(ids.locate(0x3020), libc(0x2020));
+        let unknown_block = StableBlock { module: String::new(), offset: 0x9000 };
+        assert_eq!(ids.lookup(unknown), Some(unknown_block));
+
+        let path = std::env::temp_dir().join(format!("icicle-block-ids-{}", std::process::id()));
+        ids.save(&path).unwrap();
+        assert!(!ids.is_dirty());
+
+        // Reload the mapping with the module at a different address.
+        let reloaded = BlockIds::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        reloaded.add_module("/lib/libc.so", 0x7000, 0x8000);
+        reloaded.add_module("/lib/libc.so", 0x9000, 0xa000);
+        assert_eq!(reloaded.get(0x7010), Some(a));
+        assert_eq!(reloaded.get(0x9020), Some(b));
+
+        // New blocks must not reuse existing IDs.
+        let c = reloaded.get_or_insert(0x7100);
+        assert!(![a, b, unknown].contains(&c));
+        assert!(reloaded.is_dirty());
+    }
+}
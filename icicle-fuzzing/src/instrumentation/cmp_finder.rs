@@ -198,6 +198,54 @@ struct CmpProp<'a> {
     out: &'a mut Vec<CmpOp>,
 }
 
+/// Adds every integer comparison in `block` to `out`, regardless of whether the result controls a
+/// branch (e.g. the carry and borrow flags computed by subtractions, or flags that are consumed by
+/// a later block). Guest instructions that already contain a comparison in `out` are skipped.
+pub fn find_all_cmps(block: &pcode::Block, out: &mut Vec<CmpOp>) {
+    // The offset of the instruction marker for each statement.
+    let mut markers = Vec::with_capacity(block.instructions.len());
+    let mut current = 0;
+    for (i, stmt) in block.instructions.iter().enumerate() {
+        if let Op::InstructionMarker = stmt.op {
+            current = i;
+        }
+        markers.push(current);
+    }
+    let covered: HashSet<usize> =
+        out.iter().filter_map(|x| markers.get(x.offset).copied()).collect();
+
+    let mut seen: Vec<(Value, Value)> = vec![];
+    for (i, stmt) in block.instructions.iter().enumerate() {
+        if let Op::InstructionMarker = stmt.op {
+            seen.clear();
+        }
+        if covered.contains(&markers[i]) {
+            continue;
+        }
+
+        let kind = CmpAttr::from(stmt.op);
+        if kind.is_empty() || kind.contains(CmpAttr::IS_FLOAT) {
+            continue;
+        }
+
+        let (a, b) = (stmt.inputs.first(), stmt.inputs.second());
+        let overwritten = |x: Value| matches!(x, Value::Var(var) if var.id == stmt.output.id);
+        if (a.is_const() && b.is_const())
+            || a.const_eq(0)
+            || b.const_eq(0)
+            || overwritten(a)
+            || overwritten(b)
+            || seen.iter().any(|x| *x == (a, b) || *x == (b, a))
+        {
+            continue;
+        }
+
+        seen.push((a, b));
+        out.push(CmpOp { kind, arg1: a, arg2: b, offset: i });
+    }
+    out.sort_by_key(|x| x.offset);
+}
+
 fn is_const_mask_for_size(value: Value) -> bool {
     match value {
         Value::Const(u64::MAX, _) => true,
@@ -686,6 +734,30 @@ mod test {
         display
     }
 
+    #[test]
+    fn all_cmps_includes_sub_flags() {
+        let mut block = pcode::Block::new();
+
+        let x = block.alloc_tmp(4);
+        let y = block.alloc_tmp(4);
+        let diff = block.alloc_tmp(4);
+        let cf = block.alloc_tmp(1);
+        let of = block.alloc_tmp(1);
+        let zf = block.alloc_tmp(1);
+
+        block.push(pcode::Op::InstructionMarker);
+        block.push((cf, pcode::Op::IntLess, (x, y)));
+        block.push((of, pcode::Op::IntSignedBorrow, (x, y)));
+        block.push((diff, pcode::Op::IntSub, (x, y)));
+        block.push((zf, pcode::Op::IntEqual, (diff, 0_u32)));
+
+        let mut out = vec![];
+        super::find_all_cmps(&block, &mut out);
+        assert_eq!(out.len(), 1);
+        assert_eq!(out[0].offset, 1);
+        assert!(out[0].arg1 == x.into() && out[0].arg2 == y.into());
+    }
+
     #[test]
     fn with_copy() {
         let mut block = pcode::Block::new();
@@ -10,7 +10,11 @@ use icicle_vm::{
     CodeInjector, Vm,
 };
 
-use crate::{fnv_hash, instrumentation::cmp_finder::CmpFinder, try_read_mem};
+use crate::{
+    fnv_hash,
+    instrumentation::cmp_finder::{find_all_cmps, CmpFinder},
+    try_read_mem,
+};
 
 pub use crate::instrumentation::cmp_finder::{CmpAttr, CmpOp};
 
@@ -358,11 +362,12 @@ impl Metadata {
 pub struct CmpLogBuilder<F> {
     filter: F,
     enable_rtn: bool,
+    all_cmps: bool,
 }
 
 impl CmpLogBuilder<fn(&Block) -> bool> {
     pub fn new() -> Self {
-        Self { filter: |_| true, enable_rtn: false }
+        Self { filter: |_| true, enable_rtn: false, all_cmps: false }
     }
 }
 
@@ -371,7 +376,7 @@ impl<F> CmpLogBuilder<F> {
     where
         NF: for<'r> Fn(&Block) -> bool + 'static,
     {
-        CmpLogBuilder { filter, enable_rtn: self.enable_rtn }
+        CmpLogBuilder { filter, enable_rtn: self.enable_rtn, all_cmps: self.all_cmps }
     }
 
     pub fn instrument_calls(mut self, value: bool) -> Self {
@@ -379,11 +384,18 @@ impl<F> CmpLogBuilder<F> {
         self
     }
 
+    /// Configures whether every integer comparison (including the flags set by subtractions) is
+    /// logged, instead of only the comparisons that are found to control a conditional branch.
+    pub fn instrument_all_cmps(mut self, value: bool) -> Self {
+        self.all_cmps = value;
+        self
+    }
+
     pub fn finish(self, vm: &mut Vm, map: &'static UnsafeCell<CmpMap>) -> StoreRef
     where
         F: for<'r> Fn(&Block) -> bool + 'static,
     {
-        CmpLog::register(vm, self.filter, map, self.enable_rtn)
+        CmpLog::register(vm, self.filter, map, self.enable_rtn, self.all_cmps)
     }
 }
 
@@ -394,6 +406,7 @@ pub struct CmpLog<F> {
     cmp_finder: CmpFinder,
     equal_only: bool,
     enable_cmplog_return: bool,
+    all_cmps: bool,
     filter: F,
 }
 
@@ -406,6 +419,7 @@ where
         filter: F,
         map: &'static UnsafeCell<CmpMap>,
         enable_cmplog_return: bool,
+        all_cmps: bool,
     ) -> StoreRef {
         let cmp_map = vm.cpu.trace.register_store(map);
 
@@ -464,6 +478,7 @@ where
             cmp_finder: CmpFinder::with_arch(&vm.cpu.arch),
             equal_only: false,
             enable_cmplog_return,
+            all_cmps,
             filter,
         };
         vm.add_injector(tracer);
@@ -473,7 +488,10 @@ where
 
     /// Returns whether any modification was made to the block.
     fn instrument_cmp(&mut self, block: &mut Block) -> bool {
-        let cmps = self.cmp_finder.find_cmp(block);
+        let mut cmps = self.cmp_finder.find_cmp(block).to_vec();
+        if self.all_cmps {
+            find_all_cmps(&block.pcode, &mut cmps);
+        }
         if cmps.is_empty() {
             return false;
         }
@@ -534,6 +552,16 @@ impl<F: FnMut(&Block) -> bool + 'static> CodeInjector for CmpLog<F> {
         if modified {
             code.modified.insert(group.blocks.0);
         }
+
+        // Comparisons in the remaining blocks of the group (e.g. from instructions with internal
+        // control flow) are only instrumented when logging all comparisons.
+        if self.all_cmps {
+            for id in group.blocks.0 + 1..group.blocks.1 {
+                if self.instrument_cmp(&mut code.blocks[id]) {
+                    code.modified.insert(id);
+                }
+            }
+        }
     }
 }
 
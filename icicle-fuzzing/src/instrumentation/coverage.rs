@@ -2,13 +2,14 @@ use ahash::{AHashMap as HashMap, AHashSet as HashSet};
 
 use icicle_vm::{
     cpu::{
-        lifter::Block, BlockGroup, BlockKey, BlockTable, Cpu, HookHandler, StoreRef, ValueSource,
+        lifter::Block, mem::perm, BlockGroup, BlockKey, BlockTable, Cpu, HookHandler, StoreRef,
+        ValueSource,
     },
     CodeInjector, InjectorRef, Vm,
 };
 use pcode::{HookId, Op};
 
-use crate::{fnv_hash, fnv_hash_with};
+use crate::{block_ids::BlockIds, fnv_hash, fnv_hash_with};
 
 pub fn register_afl_hit_counts_all(vm: &mut Vm, bitmap: *mut u8, size: u32) -> StoreRef {
     register_afl_hit_counts(vm, bitmap, size, |_block: &Block| true)
@@ -28,11 +29,18 @@ pub struct AFLHitCountsBuilder<F> {
     context_bits: u8,
     block_only: bool,
     trampoline: bool,
+    block_ids: Option<BlockIds>,
 }
 
 impl AFLHitCountsBuilder<fn(&Block) -> bool> {
     pub fn new() -> Self {
-        Self { filter: |_| true, context_bits: 0, block_only: false, trampoline: false }
+        Self {
+            filter: |_| true,
+            context_bits: 0,
+            block_only: false,
+            trampoline: false,
+            block_ids: None,
+        }
     }
 }
 
@@ -46,6 +54,7 @@ impl<F> AFLHitCountsBuilder<F> {
             context_bits: self.context_bits,
             block_only: self.block_only,
             trampoline: self.trampoline,
+            block_ids: self.block_ids,
         }
     }
 
@@ -62,6 +71,13 @@ impl<F> AFLHitCountsBuilder<F> {
         self
     }
 
+    /// Configures instrumentation to derive the coverage index of each block from its stable ID
+    /// instead of its address, so the coverage map is independent of where modules are loaded.
+    pub fn block_ids(mut self, ids: BlockIds) -> Self {
+        self.block_ids = Some(ids);
+        self
+    }
+
     pub fn finish(self, vm: &mut Vm, bitmap: *mut u8, size: u32) -> StoreRef
     where
         F: for<'r> FnMut(&Block) -> bool + 'static,
@@ -90,9 +106,10 @@ impl<F> AFLHitCountsBuilder<F> {
 
         let bitmap_mem_id = vm.cpu.trace.register_store((bitmap, size as usize));
 
+        let trampoline_ids = self.block_ids.clone();
         let trampoline_hook = self.trampoline.then(|| {
             vm.cpu.add_hook(move |cpu: &mut icicle_vm::cpu::Cpu, addr: u64| {
-                let key: u16 = (fnv_hash(addr) & size_mask) as u16;
+                let key: u16 = (block_hash(trampoline_ids.as_ref(), addr) & size_mask) as u16;
                 let prev_pc = cpu.read_var::<u16>(prev_pc_var);
                 let index = key ^ prev_pc;
                 let data = cpu.trace[bitmap_mem_id].data_mut();
@@ -110,6 +127,7 @@ impl<F> AFLHitCountsBuilder<F> {
             filter: self.filter,
             block_only: self.block_only,
             trampoline_hook,
+            block_ids: self.block_ids,
         };
         vm.add_injector(injector);
 
@@ -117,6 +135,14 @@ impl<F> AFLHitCountsBuilder<F> {
     }
 }
 
+/// Computes the hash used as the coverage key for the block at `addr`.
+fn block_hash(ids: Option<&BlockIds>, addr: u64) -> u32 {
+    match ids {
+        Some(ids) => fnv_hash(ids.get_or_insert(addr) as u64),
+        None => fnv_hash(addr),
+    }
+}
+
 struct ContextState {
     /// The varnode that is storing the current context.
     var: pcode::VarNode,
@@ -216,13 +242,14 @@ struct AFLHitCountsInjector<F> {
     block_only: bool,
     context: Option<ContextState>,
     trampoline_hook: Option<HookId>,
+    block_ids: Option<BlockIds>,
     filter: F,
 }
 
 impl<F> AFLHitCountsInjector<F> {
     fn inject_update_hit_count(&mut self, block: &mut Block) {
         self.tmp_block.clear();
-        let key: u16 = (fnv_hash(block.start) & self.size_mask) as u16;
+        let key: u16 = (block_hash(self.block_ids.as_ref(), block.start) & self.size_mask) as u16;
 
         // index = key ^ prev
         let index = self.tmp_block.alloc_tmp(2);
@@ -294,11 +321,12 @@ pub fn register_block_coverage(
 pub struct BlockCoverageBuilder<F> {
     filter: F,
     enable_context: bool,
+    block_ids: Option<BlockIds>,
 }
 
 impl BlockCoverageBuilder<fn(&Block) -> bool> {
     pub fn new() -> Self {
-        Self { filter: |_| true, enable_context: false }
+        Self { filter: |_| true, enable_context: false, block_ids: None }
     }
 }
 
@@ -307,7 +335,11 @@ impl<F> BlockCoverageBuilder<F> {
     where
         NF: for<'r> FnMut(&Block) -> bool + 'static,
     {
-        BlockCoverageBuilder { filter, enable_context: self.enable_context }
+        BlockCoverageBuilder {
+            filter,
+            enable_context: self.enable_context,
+            block_ids: self.block_ids,
+        }
     }
 
     /// Configures instrumentation to include calling context when determining coverage.
@@ -316,6 +348,13 @@ impl<F> BlockCoverageBuilder<F> {
         self
     }
 
+    /// Configures instrumentation to use the stable ID of each block as its coverage index (see
+    /// [AFLHitCountsBuilder::block_ids]).
+    pub fn block_ids(mut self, ids: BlockIds) -> Self {
+        self.block_ids = Some(ids);
+        self
+    }
+
     pub fn finish(self, vm: &mut Vm, bitmap: *mut u8, size: u32) -> StoreRef
     where
         F: for<'r> FnMut(&Block) -> bool + 'static,
@@ -333,8 +372,13 @@ impl<F> BlockCoverageBuilder<F> {
             context = Some(ContextState::new(&mut vm.cpu, 8));
         }
 
-        let injector =
-            BlockCoverageInjector { bitmap_mem_id, size_mask, context, filter: self.filter };
+        let injector = BlockCoverageInjector {
+            bitmap_mem_id,
+            size_mask,
+            context,
+            block_ids: self.block_ids,
+            filter: self.filter,
+        };
         vm.add_injector(injector);
 
         bitmap_mem_id
@@ -345,6 +389,7 @@ struct BlockCoverageInjector<F> {
     bitmap_mem_id: StoreRef,
     size_mask: u32,
     context: Option<ContextState>,
+    block_ids: Option<BlockIds>,
     filter: F,
 }
 
@@ -355,7 +400,13 @@ impl<F: FnMut(&Block) -> bool + 'static> CodeInjector for BlockCoverageInjector<
         }
 
         let bitmap_id = self.bitmap_mem_id.get_store_id();
-        let index: u16 = (fnv_hash(code.blocks[group.blocks.0].start) & self.size_mask) as u16;
+        let start = code.blocks[group.blocks.0].start;
+        let index: u16 = match self.block_ids.as_ref() {
+            // Block IDs are allocated sequentially, so using them directly avoids collisions until
+            // the map is full.
+            Some(ids) => (ids.get_or_insert(start) & self.size_mask) as u16,
+            None => (fnv_hash(start) & self.size_mask) as u16,
+        };
 
         if let Some(context) = &mut self.context {
             context.maybe_inject(cpu, code, group.blocks.0);
@@ -377,12 +428,50 @@ impl<F: FnMut(&Block) -> bool + 'static> CodeInjector for BlockCoverageInjector<
     }
 }
 
+/// Identifies the code in a block group by its starting address and the instructions it contains.
+///
+/// Code that is patched at runtime is relifted after the modified range is invalidated. Allocating
+/// coverage indices by `CodeKey` means that relifted groups made up of unchanged instructions keep
+/// their existing index, while groups that contain patched instructions are allocated a new index
+/// instead of merging the coverage of the old and new code.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct CodeKey {
+    /// The starting address of the group.
+    pub start: u64,
+    /// A hash of the address and bytes of each instruction in the group.
+    pub hash: u64,
+}
+
+impl CodeKey {
+    pub fn new(cpu: &mut Cpu, group: &BlockGroup, code: &BlockTable) -> Self {
+        let mut data = vec![];
+        let mut buf = [0; 16];
+        for block in &code.blocks[group.range()] {
+            for (addr, len) in block.instructions() {
+                let bytes = &mut buf[..(len as usize).min(16)];
+                // Instructions that can no longer be read, or that are in memory mapped IO (where
+                // reading could have side effects), are identified by their address alone.
+                let readable = bytes.is_empty()
+                    || (cpu.mem.is_regular_region(addr, bytes.len() as u64)
+                        && cpu.mem.read_bytes(addr, bytes, perm::NONE).is_ok());
+                if !readable {
+                    bytes.fill(0);
+                }
+                data.extend_from_slice(&addr.to_le_bytes());
+                data.extend_from_slice(bytes);
+            }
+        }
+
+        Self { start: group.start, hash: icicle_vm::translation_cache::hash_bytes(&data) }
+    }
+}
+
 /// A coverage instrumentation technique that avoids collisions.
 pub struct ExactBlockCoverageInjector {
     /// The bitmap that stores the block coverage.
     pub store: StoreRef,
-    /// A mapping from block address to the bit allocated in the store for the block.
-    pub mapping: HashMap<u64, usize>,
+    /// A mapping from the code of a block to the bit allocated in the store for the block.
+    pub mapping: HashMap<CodeKey, usize>,
 }
 
 impl ExactBlockCoverageInjector {
@@ -395,9 +484,9 @@ impl ExactBlockCoverageInjector {
 
 impl CodeInjector for ExactBlockCoverageInjector {
     fn inject(&mut self, cpu: &mut Cpu, group: &BlockGroup, code: &mut BlockTable) {
-        let addr = group.start;
+        let key = CodeKey::new(cpu, group, code);
         let next_index = self.mapping.len();
-        let index = *self.mapping.entry(addr).or_insert(next_index);
+        let index = *self.mapping.entry(key).or_insert(next_index);
 
         let (byte, bit) = (index / 8, index % 8);
 
@@ -423,8 +512,8 @@ impl CodeInjector for ExactBlockCoverageInjector {
 pub struct ExactBlockCountCoverageInjector {
     /// The bitmap that stores the block coverage.
     pub store: StoreRef,
-    /// A mapping from block address to the byte allocated in the store for the block.
-    pub mapping: HashMap<u64, usize>,
+    /// A mapping from the code of a block to the byte allocated in the store for the block.
+    pub mapping: HashMap<CodeKey, usize>,
     /// Whether to track block hit counts.
     pub capture_counts: bool,
 }
@@ -443,9 +532,9 @@ impl ExactBlockCountCoverageInjector {
 
 impl CodeInjector for ExactBlockCountCoverageInjector {
     fn inject(&mut self, cpu: &mut Cpu, group: &BlockGroup, code: &mut BlockTable) {
-        let addr = group.start;
+        let key = CodeKey::new(cpu, group, code);
         let next_index = self.mapping.len();
-        let index = *self.mapping.entry(addr).or_insert(next_index);
+        let index = *self.mapping.entry(key).or_insert(next_index);
 
         // Resize the underlying storage if required to store the counter for this block.
         let store = &mut cpu.trace[self.store];
@@ -529,3 +618,45 @@ impl HookHandler for EdgeHookData {
         data.prev = addr;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use icicle_vm::cpu::{mem::Mapping, Config};
+
+    use super::*;
+
+    #[test]
+    fn relifting_preserves_indices_of_unchanged_code() {
+        let mut vm = icicle_vm::build(&Config::from_target_triple("x86_64-none")).unwrap();
+        let mapping = Mapping { perm: perm::READ | perm::EXEC, value: 0 };
+        vm.cpu.mem.map_memory_len(0x1000, 0x100, mapping);
+        // The code is patched below, so allow it to be modified after it has been lifted.
+        vm.cpu.mem.add_dynamic_code_region(0x1000, 0x10ff);
+        // a: inc eax; jmp a
+        vm.cpu.mem.write_bytes(0x1000, &[0xff, 0xc0, 0xeb, 0xfc], perm::NONE).unwrap();
+        // b: inc eax; jmp b
+        vm.cpu.mem.write_bytes(0x1010, &[0xff, 0xc0, 0xeb, 0xfc], perm::NONE).unwrap();
+
+        let (injector, _) = ExactBlockCountCoverageInjector::register(&mut vm);
+        let index_of = |vm: &mut Vm, addr: u64| {
+            let group = vm.lift(addr).unwrap();
+            let key = CodeKey::new(&mut vm.cpu, &group, &vm.code);
+            vm.get_injector_mut::<ExactBlockCountCoverageInjector>(injector).unwrap().mapping[&key]
+        };
+        let a = index_of(&mut vm, 0x1000);
+        let b = index_of(&mut vm, 0x1010);
+        assert_ne!(a, b);
+
+        // Patch `b` to: dec eax; jmp b
+        vm.cpu.mem.write_bytes(0x1011, &[0xc8], perm::NONE).unwrap();
+        vm.invalidate_code_range(0x1000, 0x100);
+        assert_eq!(index_of(&mut vm, 0x1000), a);
+        let patched = index_of(&mut vm, 0x1010);
+        assert!(![a, b].contains(&patched));
+
+        // Reverting the patch restores the original index.
+        vm.cpu.mem.write_bytes(0x1011, &[0xc0], perm::NONE).unwrap();
+        vm.invalidate_code_range(0x1000, 0x100);
+        assert_eq!(index_of(&mut vm, 0x1010), b);
+    }
+}
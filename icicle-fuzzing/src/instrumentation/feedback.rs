@@ -0,0 +1,223 @@
+//! Custom feedback channels that allow the harness to report values that are not captured by
+//! control-flow coverage (e.g. the states of a state machine, allocation sizes, or recursion
+//! depths). Reported values are bucketized and merged into the coverage map, so inputs that reach
+//! new buckets are considered interesting by the fuzzer.
+//!
+//! Values can be reported directly from host hooks using [FeedbackChannel::report], sampled from
+//! the guest at a specific address (see [FeedbackSpec]), or written by the guest to the feedback
+//! register of [crate::harness_control::HarnessControl].
+
+use anyhow::Context;
+use icicle_vm::{
+    cpu::{Cpu, StoreRef},
+    Vm,
+};
+
+use crate::{fnv_hash, fnv_hash_with, parse_u64_with_prefix};
+
+/// Controls how reported values are grouped before they are merged into the coverage map.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Bucketing {
+    /// Every distinct value is tracked separately.
+    Exact,
+    /// Values are grouped by their order of magnitude.
+    Log2,
+    /// Values are grouped into buckets of a fixed width.
+    Linear(u64),
+}
+
+impl Bucketing {
+    /// Returns the bucket that `value` is placed in.
+    pub fn bucket(&self, value: u64) -> u64 {
+        match self {
+            Self::Exact => value,
+            Self::Log2 => 64 - value.leading_zeros() as u64,
+            Self::Linear(width) => value / (*width).max(1),
+        }
+    }
+}
+
+impl std::str::FromStr for Bucketing {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("exact") {
+            return Ok(Self::Exact);
+        }
+        if s.eq_ignore_ascii_case("log2") {
+            return Ok(Self::Log2);
+        }
+        if let Some(width) = s.strip_prefix("linear") {
+            let width = width.trim_start_matches('=');
+            let width = parse_u64_with_prefix(width)
+                .ok_or_else(|| anyhow::format_err!("invalid bucket width: {width}"))?;
+            anyhow::ensure!(width != 0, "bucket width must not be zero");
+            return Ok(Self::Linear(width));
+        }
+
+        Err(anyhow::format_err!("Unknown bucketing mode: {s}"))
+    }
+}
+
+/// A channel for reporting custom feedback values that are merged into a coverage map.
+#[derive(Copy, Clone)]
+pub struct FeedbackChannel {
+    coverage_map: StoreRef,
+    seed: u32,
+    bucketing: Bucketing,
+}
+
+impl FeedbackChannel {
+    /// Creates a channel that merges values into `coverage_map`. Each channel should use a
+    /// different `id` so that identical values reported on different channels are distinct.
+    pub fn new(vm: &mut Vm, coverage_map: StoreRef, id: u32, bucketing: Bucketing) -> Self {
+        let map_size = vm.cpu.trace[coverage_map].data().len();
+        assert!(map_size < u32::MAX as usize && map_size.is_power_of_two());
+        Self { coverage_map, seed: fnv_hash(0xfeed_0000_0000 | id as u64), bucketing }
+    }
+
+    /// Reports `value`, incrementing the hit count of the coverage map entry for its bucket.
+    pub fn report(&self, cpu: &mut Cpu, value: u64) {
+        let key = fnv_hash_with(self.seed, self.bucketing.bucket(value));
+        let cov = cpu.trace[self.coverage_map].data_mut();
+        let index = key as usize & (cov.len() - 1);
+        cov[index] = cov[index].wrapping_add(1);
+    }
+
+    /// Reports the value of `var` every time the instruction at `addr` is executed.
+    pub fn report_var_at(self, vm: &mut Vm, addr: u64, var: pcode::VarNode) {
+        vm.hook_address(addr, move |cpu: &mut Cpu, _| {
+            let value = cpu.read_reg(var);
+            self.report(cpu, value);
+        });
+    }
+
+    /// Reports the value of the `n`-th integer argument every time the function at `addr` is
+    /// called.
+    pub fn report_arg_at(self, vm: &mut Vm, addr: u64, n: usize) {
+        vm.hook_address(addr, move |cpu: &mut Cpu, _| {
+            if let Ok(value) = cpu.read_ptr_arg(n) {
+                self.report(cpu, value);
+            }
+        });
+    }
+}
+
+/// The value sampled by a [FeedbackSpec].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FeedbackValue {
+    /// The value of a register.
+    Register(String),
+    /// The value of an integer argument to a function.
+    Argument(usize),
+}
+
+/// A feedback channel configured from a string of the form `<location>:<value>[:<bucketing>]`,
+/// where `location` is a symbol name or an address, `value` is either a register name or `argN`,
+/// and `bucketing` is one of `exact` (the default), `log2` or `linear=<width>`.
+///
+/// For example, `malloc:arg0:log2` reports the order of magnitude of every allocation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FeedbackSpec {
+    pub location: String,
+    pub value: FeedbackValue,
+    pub bucketing: Bucketing,
+}
+
+impl std::str::FromStr for FeedbackSpec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.trim().splitn(3, ':');
+        let (Some(location), Some(value)) = (parts.next(), parts.next())
+        else {
+            anyhow::bail!("expected <location>:<value>[:<bucketing>], got: {s}");
+        };
+
+        let value = match value.strip_prefix("arg").map(str::parse::<usize>) {
+            Some(Ok(n)) => FeedbackValue::Argument(n),
+            _ => FeedbackValue::Register(value.to_string()),
+        };
+        let bucketing = match parts.next() {
+            Some(bucketing) => bucketing.parse()?,
+            None => Bucketing::Exact,
+        };
+
+        Ok(Self { location: location.to_string(), value, bucketing })
+    }
+}
+
+impl FeedbackSpec {
+    /// Parses a comma separated list of feedback channels.
+    pub fn parse_list(list: &str) -> anyhow::Result<Vec<Self>> {
+        list.split(',').filter(|x| !x.trim().is_empty()).map(str::parse).collect()
+    }
+
+    /// Adds instrumentation to `vm` that reports the configured value to `channel`.
+    pub fn register(&self, vm: &mut Vm, channel: FeedbackChannel) -> anyhow::Result<()> {
+        let addr = match parse_u64_with_prefix(&self.location) {
+            Some(addr) => addr,
+            None => vm
+                .env
+                .lookup_symbol(&self.location)
+                .with_context(|| format!("failed to resolve: {}", self.location))?,
+        };
+
+        match &self.value {
+            FeedbackValue::Register(name) => {
+                let var = vm
+                    .cpu
+                    .arch
+                    .sleigh
+                    .get_varnode(name)
+                    .with_context(|| format!("unknown register: {name}"))?;
+                channel.report_var_at(vm, addr, var);
+            }
+            FeedbackValue::Argument(n) => channel.report_arg_at(vm, addr, *n),
+        }
+
+        tracing::info!("custom feedback at {addr:#x}: {:?} ({:?})", self.value, self.bucketing);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_feedback_spec() {
+        let spec: FeedbackSpec = "malloc:arg0:log2".parse().unwrap();
+        assert_eq!(spec.location, "malloc");
+        assert_eq!(spec.value, FeedbackValue::Argument(0));
+        assert_eq!(spec.bucketing, Bucketing::Log2);
+
+        let spec: FeedbackSpec = "0x1000:RAX:linear=0x10".parse().unwrap();
+        assert_eq!(spec.value, FeedbackValue::Register("RAX".into()));
+        assert_eq!(spec.bucketing, Bucketing::Linear(0x10));
+
+        assert_eq!("0x1000:RAX".parse::<FeedbackSpec>().unwrap().bucketing, Bucketing::Exact);
+        assert!("malloc".parse::<FeedbackSpec>().is_err());
+    }
+
+    #[test]
+    fn reported_values_are_merged_into_coverage() {
+        let config = icicle_vm::cpu::Config::from_target_triple("x86_64-none");
+        let mut vm = icicle_vm::build(&config).unwrap();
+        let cov = vm.cpu.trace.register_store(vec![0_u8; 0x10000].into_boxed_slice());
+
+        let channel = FeedbackChannel::new(&mut vm, cov, 0, Bucketing::Log2);
+        channel.report(&mut vm.cpu, 100);
+        channel.report(&mut vm.cpu, 120);
+        let hits = |vm: &mut Vm| vm.cpu.trace[cov].data().iter().filter(|x| **x != 0).count();
+        assert_eq!(hits(&mut vm), 1);
+
+        channel.report(&mut vm.cpu, 1000);
+        assert_eq!(hits(&mut vm), 2);
+
+        // The same value reported on a different channel is tracked separately.
+        let other = FeedbackChannel::new(&mut vm, cov, 1, Bucketing::Log2);
+        other.report(&mut vm.cpu, 1000);
+        assert_eq!(hits(&mut vm), 3);
+    }
+}
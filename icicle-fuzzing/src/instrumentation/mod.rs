@@ -2,11 +2,13 @@ use std::collections::HashMap;
 
 use icicle_vm::cpu::{mem::perm, Cpu};
 
+pub mod block_ids;
 pub mod cmp_finder;
 pub mod cmplog;
 pub mod cmplog2;
 pub mod compcov;
 pub mod coverage;
+pub mod feedback;
 pub mod timer;
 
 /// Computes the hash of an integer using the FNV-1a algorithm.
@@ -1,13 +1,26 @@
 //! Fuzzing extensions and utilities for the emulator
 
+pub mod checkpoint;
+pub mod crash;
+pub mod hang;
+pub mod harness_control;
+pub mod input_device;
 pub mod linux;
 pub mod log;
 pub mod msp430;
+pub mod randomize;
+pub mod repro;
+pub mod scheduler;
+pub mod symbol_cache;
 pub mod trace;
 pub mod utils;
 
+#[cfg(feature = "libafl")]
+pub mod libafl_executor;
+
 mod config;
 mod instrumentation;
+mod minimize;
 
 use std::{
     collections::{BTreeMap, HashSet},
@@ -17,7 +30,12 @@ use std::{
 use anyhow::Context;
 use icicle_vm::{cpu::ExceptionCode, Vm, VmExit};
 
-pub use crate::{config::CustomSetup, instrumentation::*};
+pub use crate::{
+    config::CustomSetup,
+    instrumentation::*,
+    minimize::{minimize, ReplayOracle},
+    repro::reproduce,
+};
 pub use icicle_vm::cpu::utils::parse_u64_with_prefix;
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
@@ -79,6 +97,42 @@ pub struct FuzzConfig {
     /// The path where the 'CmpLog' map should be saved to. If [None] the map is not saved.
     pub cmplog_path: Option<PathBuf>,
 
+    /// The path to a sidecar file used to persist the stable ID assigned to each block. If set,
+    /// coverage indices are derived from these IDs instead of block addresses.
+    pub block_ids_path: Option<PathBuf>,
+
+    /// The directory used to cache symbolized crash locations across workers (see
+    /// [symbol_cache::SymbolCache]).
+    pub symbol_cache_path: Option<PathBuf>,
+
+    /// The directory that ELF core files are written to when an input crashes with a memory error
+    /// (see [icicle_vm::core_dump]). If [None] no core files are written.
+    pub core_dump_dir: Option<PathBuf>,
+
+    /// The directory that campaign checkpoints are saved to and resumed from (see [checkpoint]). If
+    /// [None] no checkpoints are saved.
+    pub checkpoint_dir: Option<PathBuf>,
+
+    /// The minimum time between saving checkpoints.
+    pub checkpoint_interval: std::time::Duration,
+
+    /// Whether to detect binaries instrumented with ASAN, reserving the shadow memory required by
+    /// the runtime and reporting errors as structured crashes (see [icicle_vm::asan]).
+    pub asan: bool,
+
+    /// Whether to replace the guest's heap allocator with one that detects heap-buffer-overflows
+    /// and use-after-frees (see [icicle_vm::heap_sanitizer]). Ignored for ASAN instrumented
+    /// binaries.
+    pub heap_sanitizer: bool,
+
+    /// Whether to check stores to stack variables for stack-buffer-overflows using the debug info
+    /// of the target (see [icicle_vm::stack_sanitizer]).
+    pub stack_sanitizer: bool,
+
+    /// Whether to check that heap accesses stay inside of the allocation their base pointer was
+    /// returned from (see [icicle_vm::provenance]). Requires the heap sanitizer.
+    pub provenance_check: bool,
+
     /// Whether we should perform a dry run before telling AFL++ that we are running. (Avoids
     /// timeouts due to JIT performance).
     pub enable_dry_run: bool,
@@ -95,9 +149,20 @@ pub struct FuzzConfig {
     /// Changes cmplog instrumentation to not log call paramters.
     pub no_cmplog_return: bool,
 
+    /// Log every integer comparison (including flags set by subtractions) with cmplog, instead of
+    /// only the comparisons that control a branch.
+    pub cmplog_all_cmps: bool,
+
     /// Keep track of the exact path taken by the program.
     pub track_path: bool,
 
+    /// Custom feedback channels merged into the coverage map (see [feedback::FeedbackSpec]).
+    pub feedback: Vec<feedback::FeedbackSpec>,
+
+    /// Address ranges (`(start, len)`) that the target should never execute (see
+    /// [icicle_vm::unreachable]).
+    pub unreachable: Vec<(u64, u64)>,
+
     /// The architecture to configure the VM for.
     pub arch: target_lexicon::Triple,
 
@@ -107,6 +172,9 @@ pub struct FuzzConfig {
     /// The maximum number of instructions to execute before exiting.
     pub icount_limit: u64,
 
+    /// The initial seed used for all entropy sources visible to the guest.
+    pub entropy_seed: u64,
+
     /// The number of workers to use for fuzzing.
     pub workers: u16,
 
@@ -138,29 +206,36 @@ impl FuzzConfig {
         icicle_args: Vec<String>,
         guest_args: Vec<String>,
     ) -> anyhow::Result<Self> {
-        let icount_limit: u64 = match std::env::var("ICICLE_ICOUNT_LIMIT") {
+        let icount_limit: u64 = match config_var("ICICLE_ICOUNT_LIMIT") {
             Ok(count) => {
                 parse_u64_with_prefix(&count).context("error parsing `ICICLE_ICOUNT_LIMIT`")?
             }
             Err(_) => 10_000_000_000,
         };
 
-        let start_addr: Option<u64> = match std::env::var("ICICLE_START_ADDR") {
+        let start_addr: Option<u64> = match config_var("ICICLE_START_ADDR") {
             Ok(count) => {
                 Some(parse_u64_with_prefix(&count).context("error parsing `ICICLE_START_ADDR`")?)
             }
             Err(_) => None,
         };
 
-        let arch_string = std::env::var("ICICLE_ARCH").unwrap_or_else(|_| "x86_64-linux".into());
+        let entropy_seed = match config_var("ICICLE_ENTROPY_SEED") {
+            Ok(seed) => {
+                parse_u64_with_prefix(&seed).context("error parsing `ICICLE_ENTROPY_SEED`")?
+            }
+            Err(_) => 0,
+        };
+
+        let arch_string = config_var("ICICLE_ARCH").unwrap_or_else(|_| "x86_64-linux".into());
         let arch =
             arch_string.parse().map_err(|e| anyhow::format_err!("{}: {}", arch_string, e))?;
 
-        let custom_setup = match std::env::var("ICICLE_CUSTOM_SETUP") {
+        let custom_setup = match config_var("ICICLE_CUSTOM_SETUP") {
             Ok(setup) => {
                 Some(ron::from_str(&setup).context("error parsing `ICICLE_CUSTOM_SETUP`")?)
             }
-            Err(_) => match std::env::var("ICICLE_CUSTOM_SETUP_PATH") {
+            Err(_) => match config_var("ICICLE_CUSTOM_SETUP_PATH") {
                 Ok(path) => {
                     let setup = std::fs::read_to_string(&path).with_context(|| {
                         format!("error reading `ICICLE_CUSTOM_SETUP_PATH: {path}")
@@ -173,7 +248,7 @@ impl FuzzConfig {
             },
         };
 
-        let coverage_mode = if let Ok(mode) = std::env::var("COVERAGE_MODE") {
+        let coverage_mode = if let Ok(mode) = config_var("COVERAGE_MODE") {
             mode.parse()?
         }
         else {
@@ -192,7 +267,7 @@ impl FuzzConfig {
             }
         };
 
-        let compcov_level = match std::env::var("AFL_COMPCOV_LEVEL") {
+        let compcov_level = match config_var("AFL_COMPCOV_LEVEL") {
             Ok(level) => Some(
                 level
                     .parse::<u8>()
@@ -201,7 +276,7 @@ impl FuzzConfig {
             Err(_) => None,
         };
 
-        let context_bits = match std::env::var("ICICLE_CONTEXT_BITS") {
+        let context_bits = match config_var("ICICLE_CONTEXT_BITS") {
             Ok(count) => {
                 let bits = count.parse::<u8>().context("error parsing `ICICLE_CONTEXT_BITS`")?;
                 anyhow::ensure!(bits <= 16, "A maximum of 16 bits for context is allowed");
@@ -210,13 +285,37 @@ impl FuzzConfig {
             Err(_) => 0,
         };
 
-        let workers = match std::env::var("WORKERS") {
+        let feedback = match config_var("ICICLE_FEEDBACK") {
+            Ok(list) => feedback::FeedbackSpec::parse_list(&list)
+                .context("error parsing `ICICLE_FEEDBACK`")?,
+            Err(_) => vec![],
+        };
+
+        let unreachable = match config_var("ICICLE_UNREACHABLE") {
+            Ok(list) => list
+                .split(',')
+                .filter(|x| !x.trim().is_empty())
+                .map(|x| {
+                    parse_addr_range(x).with_context(|| format!("invalid unreachable range: {x}"))
+                })
+                .collect::<anyhow::Result<_>>()?,
+            Err(_) => vec![],
+        };
+
+        let workers = match config_var("WORKERS") {
             Ok(workers) => workers
                 .parse::<u16>()
                 .with_context(|| format!("Invalid value for WORKERS: {workers}"))?,
             Err(_) => 1,
         };
 
+        let checkpoint_interval = match config_var("ICICLE_CHECKPOINT_INTERVAL") {
+            Ok(secs) => secs
+                .parse::<u64>()
+                .with_context(|| format!("Invalid value for ICICLE_CHECKPOINT_INTERVAL: {secs}"))?,
+            Err(_) => 300,
+        };
+
         Ok(Self {
             resume: parse_bool_env("RESUME")?.unwrap_or(false),
             save_crashes: parse_bool_env("SAVE_CRASHES")?.unwrap_or(true),
@@ -224,9 +323,20 @@ impl FuzzConfig {
             save_slowest: parse_bool_env("SAVE_SLOWEST")?.unwrap_or(false),
             disable_jit: parse_bool_env("ICICLE_DISABLE_JIT")?.unwrap_or(false),
             shared_mem_inputs: parse_bool_env("ICICLE_SHMEM_INPUT")?.unwrap_or(true),
-            cmplog_path: std::env::var_os("ICICLE_SAVE_CMPLOG_MAP").map(|x| x.into()),
+            cmplog_path: config_var_os("ICICLE_SAVE_CMPLOG_MAP").map(|x| x.into()),
+            block_ids_path: config_var_os("ICICLE_BLOCK_IDS").map(|x| x.into()),
+            symbol_cache_path: config_var_os("ICICLE_SYMBOL_CACHE").map(|x| x.into()),
+            core_dump_dir: config_var_os("ICICLE_CORE_DUMP_DIR").map(|x| x.into()),
+            checkpoint_dir: config_var_os("ICICLE_CHECKPOINT_DIR").map(|x| x.into()),
+            checkpoint_interval: std::time::Duration::from_secs(checkpoint_interval),
+            asan: parse_bool_env("ICICLE_ASAN")?.unwrap_or(true),
+            heap_sanitizer: parse_bool_env("ICICLE_HEAP_SANITIZER")?.unwrap_or(false),
+            stack_sanitizer: parse_bool_env("ICICLE_STACK_SANITIZER")?.unwrap_or(false),
+            provenance_check: parse_bool_env("ICICLE_PROVENANCE_CHECK")?.unwrap_or(false),
             enable_dry_run: parse_bool_env("ICICLE_DRY_RUN")?.unwrap_or(false),
             track_path: parse_bool_env("ICICLE_TRACK_PATH")?.unwrap_or(false),
+            feedback,
+            unreachable,
             enable_shadow_stack: parse_bool_env("ICICLE_ENABLE_SHADOW_STACK")?.unwrap_or(true),
             arch,
             linux: linux::LinuxConfig::from_env(),
@@ -235,9 +345,11 @@ impl FuzzConfig {
             context_bits,
             workers,
             no_cmplog_return: parse_bool_env("ICICLE_CMPLOG_RTN")?.unwrap_or(false),
+            cmplog_all_cmps: parse_bool_env("ICICLE_CMPLOG_ALL_CMPS")?.unwrap_or(false),
             start_addr,
             msp430: Msp430Config::from_env()?,
             icount_limit,
+            entropy_seed,
             icicle_args,
             guest_args,
             custom_setup,
@@ -277,6 +389,7 @@ impl FuzzConfig {
             // Disable automatically recompilation, since this causes AFL to think the emulator
             // hangs.
             enable_recompilation: false,
+            entropy_seed: self.entropy_seed,
             ..Default::default()
         }
     }
@@ -307,7 +420,7 @@ pub struct Msp430Config {
 
 impl Msp430Config {
     pub fn from_env() -> anyhow::Result<Self> {
-        let fuzz_addrs = match std::env::var("MSP430_FUZZ_ADDR") {
+        let fuzz_addrs = match config_var("MSP430_FUZZ_ADDR") {
             Ok(filter) => {
                 let mut fuzz_addrs = std::collections::HashSet::new();
                 for entry in filter.split(',') {
@@ -320,20 +433,20 @@ impl Msp430Config {
             Err(_) => None,
         };
 
-        let interrupt_interval = match std::env::var("MSP430_INTERRUPT_INTERVAL") {
+        let interrupt_interval = match config_var("MSP430_INTERRUPT_INTERVAL") {
             Ok(interval) => parse_u64_with_prefix(&interval)
                 .context("error parsing `MSP430_INTERRUPT_INTERVAL`")?,
             Err(_) => 0x8_0000,
         };
 
-        let load_addr = match std::env::var("MSP430_LOAD_ADDR") {
+        let load_addr = match config_var("MSP430_LOAD_ADDR") {
             Ok(interval) => {
                 Some(parse_u64_with_prefix(&interval).context("error parsing `MSP430_LOAD_ADDR`")?)
             }
             Err(_) => None,
         };
 
-        let fixed_seed = match std::env::var("MSP430_FIXED_SEED") {
+        let fixed_seed = match config_var("MSP430_FIXED_SEED") {
             Ok(seed) => {
                 Some(parse_u64_with_prefix(&seed).context("error parsing `MSP430_FIXED_SEED`")?)
             }
@@ -344,7 +457,7 @@ impl Msp430Config {
             interrupt_interval,
             fuzz_addrs,
             fixed_seed,
-            mcu: std::env::var("MSP430_MCU").ok(),
+            mcu: config_var("MSP430_MCU").ok(),
             load_addr,
         })
     }
@@ -387,6 +500,12 @@ pub trait Runnable {
         panic!("Not supported by this target")
     }
 
+    /// Get the number of times the target attempted to read past the end of the current input, or
+    /// `None` if the target does not track reads.
+    fn input_eof_reads(&mut self, _vm: &mut Vm) -> Option<u64> {
+        None
+    }
+
     /// Run a single fuzzing trial with the current input.
     fn run(&mut self, vm: &mut Vm) -> anyhow::Result<VmExit> {
         Ok(vm.run())
@@ -477,6 +596,23 @@ pub struct CrashEntry {
     /// The exit code of the crash translated for AFL
     pub exit_code: u32,
 
+    /// For multi-process targets, the process (and its ancestors) that crashed.
+    pub process: Option<String>,
+
+    /// The error reported by a sanitizer in the guest (see [icicle_vm::asan],
+    /// [icicle_vm::heap_sanitizer], [icicle_vm::stack_sanitizer] and [icicle_vm::provenance]).
+    pub sanitizer_report: Option<String>,
+
+    /// The normalized backtrace, stack hash and severity of the crash (see [crash::triage]).
+    pub triage: crash::CrashTriage,
+
+    /// For timeouts, a description of where the target was stuck (see [hang::triage]).
+    pub hang_report: Option<String>,
+
+    /// For Linux targets, the working directory, open files and recent syscalls of the process
+    /// that crashed (see [icicle_vm::linux::crash_context]).
+    pub environment: Option<String>,
+
     /// The list of all inputs that crashed at this location.
     pub inputs: Vec<PathBuf>,
 }
@@ -510,6 +646,14 @@ where
                 call_stack_string: icicle_vm::debug::backtrace(&mut vm),
                 exit,
                 exit_code,
+                process: utils::describe_current_process(&vm),
+                sanitizer_report: utils::describe_sanitizer_report(&vm, exit),
+                environment: utils::describe_guest_environment(&vm),
+                triage: crash::triage(&mut vm, exit, crash::DEFAULT_HASH_FRAMES),
+                // Note: triage continues execution so it must happen after everything else.
+                hang_report: CrashKind::from(exit).is_hang().then(|| {
+                    hang::triage(&mut target, &mut vm, exit, hang::DEFAULT_WINDOW).to_string()
+                }),
                 inputs: vec![],
             })
             .inputs
@@ -537,6 +681,14 @@ pub fn gen_crash_key(vm: &mut Vm, exit: VmExit) -> String {
     // Choose a de-duplication strategy depending on how the program crashed.
     match CrashKind::from(exit) {
         CrashKind::Custom(code) => format!("{code:#05x}_{pc:#x}_custom"),
+        CrashKind::Sanitizer(_) => {
+            // The VM always stops inside of the sanitizer runtime, so use the location of the
+            // invalid access (if known) instead of the current pc.
+            let report = vm.sanitizer_report().unwrap_or_default();
+            let site = report.access.map_or(stack_hash, |x| x.pc);
+            let bug_type = report.bug_type.as_deref().unwrap_or("unknown");
+            format!("{site:#x}_{bug_type}_asan")
+        }
         CrashKind::Halt => format!("{stack_hash:#x}_halt"),
         CrashKind::Hang | CrashKind::OutOfMemory => {
             // Caused by timeouts or resource exhaustion. Since the detection is based on
@@ -545,6 +697,7 @@ pub fn gen_crash_key(vm: &mut Vm, exit: VmExit) -> String {
             format!("{:#x}_hang", stack.iter().rev().nth(1).unwrap_or(&pc))
         }
         CrashKind::Killed => format!("{stack_hash:#x}_killed"),
+        CrashKind::Unreachable(addr) => format!("{addr:#x}_unreachable"),
         CrashKind::ExecViolation => {
             // When we have an execution violation, then the final address is invalid. To
             // deduplicate cases where this is caused by the corruption of a function pointer, we
@@ -590,7 +743,10 @@ impl Runnable for HookedTarget {
 impl FuzzTarget for HookedTarget {
     fn create_vm(&mut self, config: &mut FuzzConfig) -> anyhow::Result<Vm> {
         let mut vm = icicle_vm::build(&config.cpu_config())?;
-        let mut env = icicle_vm::env::build_auto(&mut vm)?;
+        // Read the environment settings through `config_var_os` so that they can be overridden
+        // when replaying bundles.
+        let env_config = icicle_vm::env::AutoConfig::from_vars(config_var_os);
+        let mut env = icicle_vm::env::build_auto_with_config(&mut vm, &env_config)?;
         env.load(&mut vm.cpu, config.guest_args[0].as_bytes())
             .map_err(|e| anyhow::format_err!("{}", e))?;
         vm.env = env;
@@ -602,7 +758,7 @@ impl FuzzTarget for HookedTarget {
 
 /// Adds debug instrumentation to `vm` based on environment variables.
 pub fn add_debug_instrumentation(vm: &mut icicle_vm::Vm) {
-    if let Ok(entries) = std::env::var("ICICLE_LOG_WRITES") {
+    if let Ok(entries) = config_var("ICICLE_LOG_WRITES") {
         // A `;` separated list of locations to instrument writes to, e.g:
         // "applet=0x1c00:2;jumptarget=0x1c02:2"
         for entry in entries.split(';') {
@@ -615,7 +771,7 @@ pub fn add_debug_instrumentation(vm: &mut icicle_vm::Vm) {
             }
         }
     }
-    if let Ok(entries) = std::env::var("ICICLE_LOG_REGS") {
+    if let Ok(entries) = config_var("ICICLE_LOG_REGS") {
         for entry in entries.split(';') {
             match parse_reg_print_hook(entry) {
                 Some((name, addr, reglist)) => {
@@ -625,7 +781,7 @@ pub fn add_debug_instrumentation(vm: &mut icicle_vm::Vm) {
             }
         }
     }
-    if let Ok(entries) = std::env::var("BREAKPOINTS") {
+    if let Ok(entries) = config_var("BREAKPOINTS") {
         // A comma separated list of addresses to stop execution at.
         for entry in entries.split(',') {
             match parse_u64_with_prefix(entry) {
@@ -638,6 +794,12 @@ pub fn add_debug_instrumentation(vm: &mut icicle_vm::Vm) {
     }
 }
 
+/// A string of the format `<address>:<size>`.
+pub fn parse_addr_range(entry: &str) -> Option<(u64, u64)> {
+    let (addr, size) = entry.trim().split_once(':')?;
+    Some((parse_u64_with_prefix(addr)?, parse_u64_with_prefix(size)?))
+}
+
 /// A string of the format `<name>=<address>:<size>`.
 pub fn parse_write_hook(entry: &str) -> Option<(&str, u64, u8)> {
     let entry = entry.trim();
@@ -690,9 +852,58 @@ pub fn parse_addr_or_symbol(addr_or_symbol: &str, vm: &mut Vm) -> Option<u64> {
     }
 }
 
-/// Parse a boolean environment varialbe
+thread_local! {
+    /// Variables that replace the environment of the process when configuring the fuzzer on the
+    /// current thread (see [with_config_env]).
+    static CONFIG_ENV: std::cell::RefCell<Option<Vec<(String, String)>>> =
+        const { std::cell::RefCell::new(None) };
+}
+
+/// Runs `f` with the variables used to configure the fuzzer (e.g. by [FuzzConfig::load_with_args])
+/// read from `vars` instead of from the environment of the process. Unlike modifying the
+/// environment, this only affects the current thread.
+pub fn with_config_env<R>(vars: &[(String, String)], f: impl FnOnce() -> R) -> R {
+    struct Restore(Option<Vec<(String, String)>>);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            CONFIG_ENV.with(|env| *env.borrow_mut() = self.0.take());
+        }
+    }
+
+    let _restore = Restore(CONFIG_ENV.with(|env| env.borrow_mut().replace(vars.to_vec())));
+    f()
+}
+
+/// Returns all the variables used for configuring the fuzzer (see [with_config_env]).
+pub fn config_vars() -> Vec<(String, String)> {
+    CONFIG_ENV.with(|env| env.borrow().clone()).unwrap_or_else(|| std::env::vars().collect())
+}
+
+/// Reads a variable used for configuring the fuzzer (see [with_config_env]), with the same
+/// behaviour as [std::env::var].
+pub fn config_var(name: &str) -> Result<String, std::env::VarError> {
+    CONFIG_ENV.with(|env| match env.borrow().as_ref() {
+        Some(vars) => vars
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.clone())
+            .ok_or(std::env::VarError::NotPresent),
+        None => std::env::var(name),
+    })
+}
+
+/// Reads a variable used for configuring the fuzzer (see [with_config_env]), with the same
+/// behaviour as [std::env::var_os].
+pub fn config_var_os(name: &str) -> Option<std::ffi::OsString> {
+    CONFIG_ENV.with(|env| match env.borrow().as_ref() {
+        Some(vars) => vars.iter().find(|(key, _)| key == name).map(|(_, value)| value.into()),
+        None => std::env::var_os(name),
+    })
+}
+
+/// Parse a boolean environment variable
 pub fn parse_bool_env(name: &str) -> anyhow::Result<Option<bool>> {
-    match std::env::var_os(name) {
+    match config_var_os(name) {
         Some(var) => {
             let x =
                 var.to_str().ok_or_else(|| anyhow::format_err!("{name} was not a valid string"))?;
@@ -729,6 +940,14 @@ pub enum CrashKind {
     /// Custom environment defined error.
     Custom(u64),
 
+    /// A sanitizer in the guest detected an error (e.g. an invalid access to the given address).
+    Sanitizer(u64),
+
+    /// The program reached code at the given address that was marked as unreachable (see
+    /// [icicle_vm::unreachable]). These paths are uninteresting, so they are not treated as
+    /// crashes.
+    Unreachable(u64),
+
     /// Generally only caused by either a bug in the emulator, or a handcrafted error exit
     /// condition
     Unknown,
@@ -736,7 +955,7 @@ pub enum CrashKind {
 
 impl CrashKind {
     pub fn is_crash(&self) -> bool {
-        !matches!(self, CrashKind::Halt | CrashKind::Hang)
+        !matches!(self, CrashKind::Halt | CrashKind::Hang | CrashKind::Unreachable(_))
     }
 
     pub fn is_hang(&self) -> bool {
@@ -752,6 +971,10 @@ impl From<VmExit> for CrashKind {
     fn from(exit: VmExit) -> Self {
         match exit {
             VmExit::UnhandledException((ExceptionCode::Environment, value)) => Self::Custom(value),
+            VmExit::UnhandledException((
+                ExceptionCode::SanitizerError | ExceptionCode::StackBufferOverflow,
+                addr,
+            )) => Self::Sanitizer(addr),
 
             VmExit::Halt
             | VmExit::UnhandledException((
@@ -759,9 +982,13 @@ impl From<VmExit> for CrashKind {
                 _,
             )) => Self::Halt,
 
-            VmExit::Running | VmExit::InstructionLimit | VmExit::Interrupted | VmExit::Deadlock => {
-                Self::Hang
-            }
+            VmExit::Unreachable(addr) => Self::Unreachable(addr),
+
+            VmExit::Running
+            | VmExit::InstructionLimit
+            | VmExit::LoopLimit(_)
+            | VmExit::Interrupted
+            | VmExit::Deadlock => Self::Hang,
 
             VmExit::OutOfMemory => Self::OutOfMemory,
 
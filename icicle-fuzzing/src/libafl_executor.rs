@@ -0,0 +1,218 @@
+//! Integration with LibAFL (enabled with the `libafl` feature).
+//!
+//! [IcicleExecutor] implements LibAFL's `Executor` trait on top of a VM and a [Runnable] target.
+//! Coverage and comparison feedback are provided as regular LibAFL observers:
+//!
+//! - [edge_coverage_observer]: an AFL style edge hit-count map.
+//! - [IcicleCmpLogObserver]: adds the comparisons logged by the CmpLog instrumentation to the
+//!   `CmpValuesMetadata` of the fuzzer state (used by LibAFL's input-to-state mutators).
+//! - `TimeObserver` from LibAFL can be used as-is to measure the execution time.
+//!
+//! Observers are reset in `pre_exec`, and the VM is restored to the snapshot taken when the
+//! executor was created before every execution, so no state is carried over between inputs.
+
+use std::{borrow::Cow, cell::UnsafeCell};
+
+use icicle_vm::{Snapshot, Vm};
+use libafl::{
+    common::HasMetadata,
+    executors::{Executor, ExitKind, HasObservers},
+    inputs::HasTargetBytes,
+    observers::{
+        cmp::{CmpValues, CmpValuesMetadata, CmplogBytes},
+        HitcountsMapObserver, Observer, ObserversTuple, StdMapObserver,
+    },
+    state::HasExecutions,
+    Error,
+};
+use libafl_bolts::{tuples::RefIndexable, AsSlice, Named};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cmplog::{CmpHeader, CmpLogBuilder, CmpLogEntry, CmpMap},
+    coverage::AFLHitCountsBuilder,
+    CrashKind, Runnable,
+};
+
+/// Converts the exit of the VM to the exit kind used by LibAFL.
+pub fn exit_kind(exit: icicle_vm::VmExit) -> ExitKind {
+    match CrashKind::from(exit) {
+        CrashKind::Halt | CrashKind::Unreachable(_) => ExitKind::Ok,
+        CrashKind::Hang => ExitKind::Timeout,
+        CrashKind::OutOfMemory => ExitKind::Oom,
+        _ => ExitKind::Crash,
+    }
+}
+
+/// An executor that runs inputs inside of a VM.
+pub struct IcicleExecutor<T, OT> {
+    pub vm: Vm,
+    pub target: T,
+    snapshot: Snapshot,
+    observers: OT,
+}
+
+impl<T: Runnable, OT> IcicleExecutor<T, OT> {
+    /// Creates a new executor for `vm`. The VM should already be instrumented and initialized, the
+    /// current state of the VM is restored before each execution.
+    pub fn new(mut vm: Vm, target: T, observers: OT) -> Self {
+        let snapshot = vm.snapshot();
+        Self { vm, target, snapshot, observers }
+    }
+
+    /// Updates the snapshot that the VM is restored to before each execution.
+    pub fn update_snapshot(&mut self) {
+        self.snapshot = self.vm.snapshot();
+    }
+}
+
+impl<EM, I, S, Z, T, OT> Executor<EM, I, S, Z> for IcicleExecutor<T, OT>
+where
+    T: Runnable,
+    I: HasTargetBytes,
+    S: HasExecutions,
+    OT: ObserversTuple<I, S>,
+{
+    fn run_target(
+        &mut self,
+        _fuzzer: &mut Z,
+        state: &mut S,
+        _mgr: &mut EM,
+        input: &I,
+    ) -> Result<ExitKind, Error> {
+        *state.executions_mut() += 1;
+
+        self.vm.restore(&self.snapshot);
+        let input = input.target_bytes();
+        self.target
+            .set_input(&mut self.vm, input.as_slice())
+            .map_err(|e| Error::unknown(format!("failed to set input: {e:?}")))?;
+        let exit = self
+            .target
+            .run(&mut self.vm)
+            .map_err(|e| Error::unknown(format!("failed to run input: {e:?}")))?;
+
+        // Recompile here, so the time spent recompiling isn't included in the next execution.
+        if self.vm.should_recompile() {
+            self.vm.recompile();
+        }
+
+        Ok(exit_kind(exit))
+    }
+}
+
+impl<T, OT> HasObservers for IcicleExecutor<T, OT> {
+    type Observers = OT;
+
+    fn observers(&self) -> RefIndexable<&Self::Observers, Self::Observers> {
+        RefIndexable::from(&self.observers)
+    }
+
+    fn observers_mut(&mut self) -> RefIndexable<&mut Self::Observers, Self::Observers> {
+        RefIndexable::from(&mut self.observers)
+    }
+}
+
+/// Instruments `vm` with AFL style edge coverage, returning an observer for the coverage map.
+/// Panics if `size` is not a power of two.
+///
+/// Note: the map is leaked, since it must outlive the VM.
+pub fn edge_coverage_observer(
+    vm: &mut Vm,
+    name: &'static str,
+    size: usize,
+) -> HitcountsMapObserver<StdMapObserver<'static, u8, false>> {
+    let map: &'static mut [u8] = Box::leak(vec![0; size].into_boxed_slice());
+    AFLHitCountsBuilder::new().finish(vm, map.as_mut_ptr(), size as u32);
+    // Safety: the map is never freed, and is only written to while executing the VM.
+    HitcountsMapObserver::new(unsafe { StdMapObserver::from_mut_ptr(name, map.as_mut_ptr(), size) })
+}
+
+/// An observer that collects the comparisons logged by the CmpLog instrumentation.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct IcicleCmpLogObserver {
+    name: Cow<'static, str>,
+    #[serde(skip)]
+    map: Option<&'static UnsafeCell<CmpMap>>,
+}
+
+impl IcicleCmpLogObserver {
+    /// Instruments `vm` with CmpLog, returning an observer for the logged comparisons.
+    pub fn new(vm: &mut Vm, name: &'static str) -> Self {
+        let map = Box::leak(Box::new(UnsafeCell::new(CmpMap::new())));
+        CmpLogBuilder::new().finish(vm, map);
+        Self { name: Cow::Borrowed(name), map: Some(map) }
+    }
+
+    fn map(&self) -> Result<&'static UnsafeCell<CmpMap>, Error> {
+        self.map.ok_or_else(|| Error::illegal_state("CmpLog observer is not attached to a VM"))
+    }
+}
+
+impl Named for IcicleCmpLogObserver {
+    fn name(&self) -> &Cow<'static, str> {
+        &self.name
+    }
+}
+
+impl<I, S: HasMetadata> Observer<I, S> for IcicleCmpLogObserver {
+    fn pre_exec(&mut self, _state: &mut S, _input: &I) -> Result<(), Error> {
+        // Only the headers need to be cleared, since the hit count determines which entries of
+        // the log are valid.
+        //
+        // Safety: the map is only accessed by the VM while it is running.
+        unsafe { (*self.map()?.get()).headers.fill(CmpHeader::default()) };
+        Ok(())
+    }
+
+    fn post_exec(&mut self, state: &mut S, _input: &I, _exit_kind: &ExitKind) -> Result<(), Error> {
+        // Safety: the map is only accessed by the VM while it is running.
+        let map = unsafe { &*self.map()?.get() };
+
+        let meta = state.metadata_or_insert_with(CmpValuesMetadata::new);
+        meta.list.clear();
+        meta.list.extend(map.entries_all().into_iter().filter_map(|(_, entry)| cmp_values(entry)));
+        Ok(())
+    }
+}
+
+/// Converts a CmpLog entry to the representation used by LibAFL.
+fn cmp_values(entry: CmpLogEntry) -> Option<CmpValues> {
+    match entry {
+        CmpLogEntry::Op(op) => {
+            // The instrumentation does not track which operands are constants.
+            let is_const = false;
+            Some(match op.size {
+                1 => CmpValues::U8((op.v0 as u8, op.v1 as u8, is_const)),
+                2 => CmpValues::U16((op.v0 as u16, op.v1 as u16, is_const)),
+                4 => CmpValues::U32((op.v0 as u32, op.v1 as u32, is_const)),
+                8 => CmpValues::U64((op.v0 as u64, op.v1 as u64, is_const)),
+                _ => return None,
+            })
+        }
+        CmpLogEntry::Rtn(rtn) => {
+            let bytes = |value: &[u8; 31], len: u8| {
+                let mut buf = [0; 32];
+                buf[..31].copy_from_slice(value);
+                CmplogBytes::from_buf_and_len(buf, len.min(31))
+            };
+            Some(CmpValues::Bytes((bytes(&rtn.v0, rtn.v0_len), bytes(&rtn.v1, rtn.v1_len))))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exit_kinds() {
+        use icicle_vm::{cpu::ExceptionCode, VmExit};
+
+        assert_eq!(exit_kind(VmExit::Halt), ExitKind::Ok);
+        assert_eq!(exit_kind(VmExit::InstructionLimit), ExitKind::Timeout);
+        assert_eq!(exit_kind(VmExit::OutOfMemory), ExitKind::Oom);
+        let segv = VmExit::UnhandledException((ExceptionCode::ReadUnmapped, 0x0));
+        assert_eq!(exit_kind(segv), ExitKind::Crash);
+    }
+}
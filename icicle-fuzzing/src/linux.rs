@@ -3,11 +3,14 @@ use std::path::PathBuf;
 
 use icicle_vm::{
     cpu::{Environment, ExceptionCode},
-    linux::fs::devices::ReadableSharedBufDevice,
+    linux::{
+        fs::devices::{Device, ReadableSharedBufDevice},
+        FollowFork,
+    },
     Vm, VmExit,
 };
 
-use crate::{parse_u64_with_prefix, FuzzConfig, FuzzTarget, Runnable};
+use crate::{config_var, config_var_os, parse_u64_with_prefix, FuzzConfig, FuzzTarget, Runnable};
 
 #[derive(Clone)]
 pub struct LinuxConfig {
@@ -28,21 +31,36 @@ pub struct LinuxConfig {
 
     /// Overwrites the maximum allocation size for the kernel.
     pub max_alloc_size: Option<u64>,
+
+    /// Configures which process to keep executing after the target forks.
+    pub follow_fork: FollowFork,
+
+    /// Directories in the sysroot to load shared libraries from, instead of using the target's
+    /// dynamic linker.
+    pub library_paths: Vec<Vec<u8>>,
 }
 
 impl LinuxConfig {
     pub fn from_env() -> Self {
         Self {
-            mount_path: std::env::var("ICICLE_MOUNT_PATH").unwrap_or_else(|_| "/dev/stdin".into()),
+            mount_path: config_var("ICICLE_MOUNT_PATH").unwrap_or_else(|_| "/dev/stdin".into()),
             mount_stdout: false,
-            sysroot: std::env::var_os("ICICLE_SYSROOT")
+            sysroot: config_var_os("ICICLE_SYSROOT")
                 .map_or_else(|| PathBuf::from("/"), PathBuf::from),
-            instrument_libs: std::env::var("AFL_INST_LIBS").map_or(false, |x| x == "1"),
-            max_alloc_size: std::env::var("ICICLE_MAX_ALLOC_SIZE")
+            instrument_libs: config_var("AFL_INST_LIBS").map_or(false, |x| x == "1"),
+            max_alloc_size: config_var("ICICLE_MAX_ALLOC_SIZE")
                 .map(|x| parse_u64_with_prefix(&x).unwrap())
                 .ok(),
-            kill_on_alloc_failure: std::env::var("ICICLE_KILL_ON_ALLOC_FAILURE")
+            kill_on_alloc_failure: config_var("ICICLE_KILL_ON_ALLOC_FAILURE")
                 .map_or(false, |x| x == "1"),
+            follow_fork: match config_var("ICICLE_FOLLOW_FORK").as_deref() {
+                Ok("parent") => FollowFork::Parent,
+                Ok("child") => FollowFork::Child,
+                _ => FollowFork::Both,
+            },
+            library_paths: config_var("ICICLE_LIBRARY_PATH").map_or(vec![], |paths| {
+                paths.split(':').map(|path| path.as_bytes().to_vec()).collect()
+            }),
         }
     }
 }
@@ -67,6 +85,8 @@ impl FuzzTarget for Target {
                 zero_stack: true,
                 max_alloc_size: Some(config.linux.max_alloc_size.unwrap_or(1 << 24)),
                 kill_on_alloc_failure: config.linux.kill_on_alloc_failure,
+                follow_fork: config.linux.follow_fork,
+                library_paths: config.linux.library_paths.clone(),
                 ..Default::default()
             },
             config.linux.sysroot.clone(),
@@ -80,7 +100,7 @@ impl FuzzTarget for Target {
 
         let mut envs = vec![];
         envs.push((&b"LD_BIND_NOW"[..], &b"1"[..]));
-        let guest_env = std::env::var("ICICLE_SET_ENV");
+        let guest_env = config_var("ICICLE_SET_ENV");
         if let Ok(guest_env) = guest_env.as_ref() {
             // @fixme: this is broken if the environment variable contains internal commas (but it's
             // also broken in QEMU).
@@ -98,6 +118,29 @@ impl FuzzTarget for Target {
             .map_err(|e| anyhow::format_err!("{e}"))?;
         vm.set_env(env);
 
+        // Report errors detected by ASAN in instrumented binaries as structured crashes.
+        let asan = match config.asan {
+            true => icicle_vm::asan::attach(&mut vm),
+            false => None,
+        };
+
+        if config.heap_sanitizer && asan.is_none() {
+            attach_heap_sanitizer(&mut vm, &config.guest_args[0])?;
+        }
+
+        if config.stack_sanitizer && icicle_vm::stack_sanitizer::attach(&mut vm).is_none() {
+            tracing::warn!("stack sanitizer enabled, but the target has no debug info");
+        }
+
+        if config.provenance_check {
+            match vm.heap_sanitizer.clone() {
+                Some(heap) => {
+                    icicle_vm::provenance::attach(&mut vm, heap);
+                }
+                None => tracing::warn!("provenance checker enabled, but the heap sanitizer is not"),
+            }
+        }
+
         Ok(vm)
     }
 
@@ -138,7 +181,42 @@ impl Runnable for Target {
         self.buf.set(input).map_err(|e| anyhow::format_err!("Failed to set input: {}", e))
     }
 
+    fn input_eof_reads(&mut self, _vm: &mut Vm) -> Option<u64> {
+        let size = self.buf.size();
+        let reads = self.buf.read_positions().ok()?;
+        Some(reads.iter().filter(|(offset, len)| *offset >= size && *len != 0).count() as u64)
+    }
+
     fn input_buf(&self) -> Option<&ReadableSharedBufDevice> {
         Some(&self.buf)
     }
 }
+
+/// Attaches the heap sanitizer to the allocator functions of the binary at `path`, either the
+/// functions themselves (for static binaries) or their PLT entries.
+fn attach_heap_sanitizer(vm: &mut Vm, path: &str) -> anyhow::Result<()> {
+    const ALLOC_FUNCTIONS: [&str; 4] = ["malloc", "calloc", "realloc", "free"];
+
+    let mut entries: Vec<(u64, String)> = ALLOC_FUNCTIONS
+        .iter()
+        .filter_map(|name| Some((vm.env.lookup_symbol(name)?, name.to_string())))
+        .collect();
+    if entries.is_empty() {
+        let offset = vm
+            .env_ref::<icicle_vm::linux::Kernel>()
+            .map_or(0, |kernel| kernel.process.image.relocation_offset);
+        let data = std::fs::read(path)
+            .map_err(|e| anyhow::format_err!("failed to read {path} for heap sanitizer: {e}"))?;
+        entries = icicle_vm::ltrace::plt_entries(&data, offset)
+            .map_err(|e| anyhow::format_err!("failed to find PLT entries: {e}"))?;
+    }
+    if entries.is_empty() {
+        tracing::warn!("heap sanitizer enabled, but no allocator functions were found");
+        return Ok(());
+    }
+
+    let options = icicle_vm::heap_sanitizer::HeapSanitizerOptions::default();
+    icicle_vm::heap_sanitizer::attach(vm, entries, options)
+        .map_err(|e| anyhow::format_err!("failed to attach heap sanitizer: {e:?}"))?;
+    Ok(())
+}
@@ -2,17 +2,38 @@ use std::collections::HashSet;
 
 use icicle_vm::{Vm, VmExit};
 
+/// Keeps track of the unique crashes (identified by their crash key, see [crate::gen_crash_key])
+/// that have been found.
 #[derive(Default)]
 pub struct CrashLogger {
     crashes: HashSet<(u64, String)>,
 }
 
 impl CrashLogger {
+    /// Returns whether the crash the VM exited with has not been seen before.
     pub fn check_crash(&mut self, vm: &mut Vm, exit: VmExit) -> bool {
         let pc = vm.cpu.read_pc();
         let key = crate::gen_crash_key(vm, exit);
         self.crashes.insert((pc, key))
     }
+
+    /// Marks crashes found in a previous session (e.g. restored from a checkpoint) as seen.
+    pub fn add_known(&mut self, crashes: impl IntoIterator<Item = (u64, String)>) {
+        self.crashes.extend(crashes);
+    }
+
+    /// The PC and crash key of all unique crashes.
+    pub fn keys(&self) -> impl Iterator<Item = &(u64, String)> {
+        self.crashes.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.crashes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.crashes.is_empty()
+    }
 }
 
 pub struct StatsLogger {
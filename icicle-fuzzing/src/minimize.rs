@@ -0,0 +1,155 @@
+//! Input minimization driver.
+//!
+//! Inputs are reduced by repeatedly trying smaller (or simpler) candidates and keeping any
+//! candidate that the oracle reports as still interesting (e.g. it reaches the same crash or
+//! produces the same coverage as the original input).
+
+use icicle_vm::{Snapshot, Vm, VmExit};
+
+use crate::{gen_crash_key, Runnable};
+
+/// The byte used to replace input bytes during the simplification pass (matches afl-tmin).
+const SIMPLIFIED_BYTE: u8 = b'0';
+
+/// Reduce `input` to a smaller input that is still considered interesting by `oracle`.
+///
+/// The reduction first removes progressively smaller chunks from the input, then attempts to
+/// replace the remaining bytes with a canonical value. The original input is assumed to be
+/// interesting.
+pub fn minimize<F>(input: &[u8], mut oracle: F) -> anyhow::Result<Vec<u8>>
+where
+    F: FnMut(&[u8]) -> anyhow::Result<bool>,
+{
+    let mut current = input.to_vec();
+    let mut candidate = Vec::with_capacity(current.len());
+
+    // Remove chunks, starting with large chunks and halving the size each time we fail to make
+    // progress.
+    let mut chunk_size = (current.len() / 2).max(1);
+    loop {
+        let mut removed_any = false;
+        let mut offset = 0;
+        while offset < current.len() && !current.is_empty() {
+            let end = (offset + chunk_size).min(current.len());
+
+            candidate.clear();
+            candidate.extend_from_slice(&current[..offset]);
+            candidate.extend_from_slice(&current[end..]);
+
+            if oracle(&candidate)? {
+                tracing::debug!("removed {} bytes at offset {offset}", end - offset);
+                std::mem::swap(&mut current, &mut candidate);
+                removed_any = true;
+            }
+            else {
+                offset += chunk_size;
+            }
+        }
+
+        if !removed_any {
+            if chunk_size == 1 {
+                break;
+            }
+            chunk_size /= 2;
+        }
+    }
+
+    // Simplify the remaining bytes.
+    for i in 0..current.len() {
+        if current[i] == SIMPLIFIED_BYTE {
+            continue;
+        }
+        let prev = current[i];
+        current[i] = SIMPLIFIED_BYTE;
+        if !oracle(&current)? {
+            current[i] = prev;
+        }
+    }
+
+    tracing::info!("minimized input from {} to {} bytes", input.len(), current.len());
+    Ok(current)
+}
+
+/// An oracle that re-executes the target from a snapshot and checks that the signature of the
+/// execution matches the signature of the original input.
+pub struct ReplayOracle<'a, T, F, S> {
+    vm: &'a mut Vm,
+    target: &'a mut T,
+    snapshot: Snapshot,
+    signature: F,
+    expected: S,
+    /// The number of executions performed by the oracle.
+    pub execs: u64,
+}
+
+impl<'a, T> ReplayOracle<'a, T, fn(&mut Vm, VmExit) -> String, String>
+where
+    T: Runnable,
+{
+    /// Creates an oracle that considers an input interesting if it generates the same crash key as
+    /// `input`.
+    pub fn crash(vm: &'a mut Vm, target: &'a mut T, input: &[u8]) -> anyhow::Result<Self> {
+        Self::new(vm, target, input, gen_crash_key)
+    }
+}
+
+impl<'a, T, F, S> ReplayOracle<'a, T, F, S>
+where
+    T: Runnable,
+    F: FnMut(&mut Vm, VmExit) -> S,
+    S: PartialEq + std::fmt::Debug,
+{
+    /// Creates an oracle using a custom signature function (e.g. a hash of the coverage map). The
+    /// current VM state is used as the starting point for every execution.
+    pub fn new(
+        vm: &'a mut Vm,
+        target: &'a mut T,
+        input: &[u8],
+        mut signature: F,
+    ) -> anyhow::Result<Self> {
+        let snapshot = vm.snapshot();
+
+        target.set_input(vm, input)?;
+        let exit = target.run(vm)?;
+        let expected = signature(vm, exit);
+        tracing::debug!("expected signature: {expected:?}");
+
+        Ok(Self { vm, target, snapshot, signature, expected, execs: 1 })
+    }
+
+    /// Runs `input` from the snapshot and returns whether its signature matches the original input.
+    pub fn check(&mut self, input: &[u8]) -> anyhow::Result<bool> {
+        self.vm.restore(&self.snapshot);
+        self.execs += 1;
+
+        self.target.set_input(self.vm, input)?;
+        let exit = self.target.run(self.vm)?;
+        Ok((self.signature)(self.vm, exit) == self.expected)
+    }
+}
+
+impl<'a, T, F, S> Drop for ReplayOracle<'a, T, F, S> {
+    fn drop(&mut self) {
+        // Leave the VM in the same state as it was before minimization.
+        self.vm.restore(&self.snapshot);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::minimize;
+
+    #[test]
+    fn removes_unneeded_bytes() {
+        let input = b"xxxxCRASHyyyyyyyyy";
+        let output = minimize(input, |x| Ok(x.windows(5).any(|w| w == b"CRASH"))).unwrap();
+        assert_eq!(output, b"CRASH");
+    }
+
+    #[test]
+    fn simplifies_bytes() {
+        let input = b"abc!";
+        let output = minimize(input, |x| Ok(x.len() == 4 && x[3] == b'!')).unwrap();
+        assert_eq!(output, b"000!");
+    }
+}
@@ -48,6 +48,7 @@ impl crate::FuzzTarget for RandomIoTarget {
         }
 
         let mut env = Msp430::new(&vm.cpu, msp430_config)?;
+        env.guest_log = vm.guest_log.clone();
         env.load(&mut vm.cpu, config.guest_args[0].as_bytes())
             .map_err(|e| anyhow::format_err!("{}", e))?;
 
@@ -0,0 +1,248 @@
+//! Re-randomization of layout dependent state in a snapshot of a Linux target.
+//!
+//! Fuzzing nodes typically start from the same snapshot, so any state derived from randomness
+//! during startup (e.g. the stack protector cookie) is identical across the entire fleet. Bugs that
+//! only trigger for particular values (e.g. a partial overwrite that happens to match the cookie)
+//! or particular heap locations are then either found by every node or by none of them.
+//! [randomize_layout] perturbs this state using a per-node seed.
+
+use anyhow::Context;
+use icicle_vm::{
+    cpu::{
+        mem::{perm, AllocLayout, MemoryMapping},
+        utils::XorShiftRng,
+    },
+    linux::{Kernel, LinuxMmu},
+    Vm,
+};
+
+const PAGE_SIZE: u64 = 0x1000;
+
+/// The maximum number of pages to shift the start of the heap by.
+const MAX_BRK_SHIFT_PAGES: u64 = 0x1000;
+
+/// The maximum number of pages to shift the base address used for future `mmap` calls by.
+const MAX_MMAP_SHIFT_PAGES: u64 = 0x1_0000;
+
+#[derive(Clone, Copy, Debug)]
+pub struct RandomizeOptions {
+    /// Replace the stack protector cookie (derived from `AT_RANDOM`), and all copies of it found
+    /// in writable memory.
+    pub stack_cookie: bool,
+
+    /// Move the start of the `brk` heap (only applied if the heap has not been extended yet).
+    pub heap_base: bool,
+
+    /// Shift the base address used for future anonymous memory mappings.
+    pub mmap_base: bool,
+}
+
+impl Default for RandomizeOptions {
+    fn default() -> Self {
+        Self { stack_cookie: true, heap_base: true, mmap_base: true }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct RandomizeReport {
+    /// The old and new value of the stack cookie.
+    pub stack_cookie: Option<(u64, u64)>,
+
+    /// The number of copies of the stack cookie that were replaced in memory.
+    pub cookies_replaced: usize,
+
+    /// The old and new start address of the `brk` heap.
+    pub heap_base: Option<(u64, u64)>,
+
+    /// The old and new base address for `mmap`.
+    pub mmap_base: Option<(u64, u64)>,
+}
+
+/// Re-randomizes the layout dependent state of the Linux process running in `vm` using `seed`.
+///
+/// This is intended to be called once on each node after restoring a shared snapshot, before any
+/// fuzzing inputs are executed.
+///
+/// Note: copies of the stack cookie are found by scanning writable memory for aligned words that
+/// match the old value, so any copies that have been transformed (e.g. XORed with the frame
+/// address) are not updated. The pointer guard value stored after the cookie in `AT_RANDOM` is
+/// never modified, since mangled pointers derived from it cannot be reliably located.
+pub fn randomize_layout(
+    vm: &mut Vm,
+    seed: u64,
+    options: RandomizeOptions,
+) -> anyhow::Result<RandomizeReport> {
+    // Avoid the fixed point of the RNG.
+    let mut rng = XorShiftRng::new(seed.max(1));
+    let mut report = RandomizeReport::default();
+
+    if options.stack_cookie {
+        report.stack_cookie = randomize_stack_cookie(vm, &mut rng, &mut report.cookies_replaced)?;
+    }
+
+    let kernel = vm
+        .env
+        .as_mut_any()
+        .downcast_mut::<Kernel>()
+        .context("layout randomization requires a Linux environment")?;
+
+    if options.heap_base {
+        let image = &mut kernel.process.image;
+        if image.start_brk == image.end_brk {
+            let old = image.start_brk;
+            let shift = (1 + rng.next() % MAX_BRK_SHIFT_PAGES) * PAGE_SIZE;
+            let layout = AllocLayout { addr: Some(old + shift), size: 0x1_0000, align: PAGE_SIZE };
+            let new = LinuxMmu::next_free(&vm.cpu.mem, layout)
+                .map_err(|e| anyhow::format_err!("failed to find space for heap: {e:?}"))?;
+
+            image.start_brk = new;
+            image.end_brk = new;
+            if let Some(mut entry) = kernel.process.mapping.remove(&old) {
+                entry.end = new;
+                kernel.process.mapping.insert(new, entry);
+            }
+            report.heap_base = Some((old, new));
+        }
+        else {
+            tracing::warn!("heap has already been extended, skipping heap base randomization");
+        }
+    }
+
+    if options.mmap_base {
+        let old = kernel.mmap_start_addr;
+        let new = old + (rng.next() % MAX_MMAP_SHIFT_PAGES) * PAGE_SIZE;
+        kernel.mmap_start_addr = new;
+        report.mmap_base = Some((old, new));
+    }
+
+    Ok(report)
+}
+
+fn randomize_stack_cookie(
+    vm: &mut Vm,
+    rng: &mut XorShiftRng,
+    replaced: &mut usize,
+) -> anyhow::Result<Option<(u64, u64)>> {
+    let rand_ptr = vm
+        .env_ref::<Kernel>()
+        .context("layout randomization requires a Linux environment")?
+        .process
+        .image
+        .rand_ptr;
+    if rand_ptr == 0 {
+        return Ok(None);
+    }
+
+    let ptr_size = vm.cpu.arch.triple.pointer_width().map_or(4, |x| x.bytes()) as usize;
+    let big_endian = vm.cpu.arch.sleigh.big_endian;
+    let decode = |bytes: &[u8]| {
+        let mut buf = [0; 8];
+        match big_endian {
+            true => {
+                buf[8 - bytes.len()..].copy_from_slice(bytes);
+                u64::from_be_bytes(buf)
+            }
+            false => {
+                buf[..bytes.len()].copy_from_slice(bytes);
+                u64::from_le_bytes(buf)
+            }
+        }
+    };
+    let encode = |value: u64| match big_endian {
+        true => value.to_be_bytes()[8 - ptr_size..].to_vec(),
+        false => value.to_le_bytes()[..ptr_size].to_vec(),
+    };
+    let mask = u64::MAX >> (64 - 8 * ptr_size);
+
+    // glibc uses the first word of `AT_RANDOM` as the cookie, with the lowest byte cleared to stop
+    // string functions from reading or writing past it.
+    let mut word = vec![0; ptr_size];
+    vm.cpu
+        .mem
+        .read_bytes(rand_ptr, &mut word, perm::NONE)
+        .map_err(|e| anyhow::format_err!("failed to read AT_RANDOM: {e:?}"))?;
+    let old_word = decode(&word);
+    let old_cookie = old_word & !0xff;
+
+    let new_word = rng.next() & mask;
+    let new_cookie = new_word & !0xff;
+    vm.cpu
+        .mem
+        .write_bytes(rand_ptr, &encode(new_word), perm::NONE)
+        .map_err(|e| anyhow::format_err!("failed to write AT_RANDOM: {e:?}"))?;
+
+    if old_cookie == 0 {
+        // A cookie of zero is too common to safely replace.
+        return Ok(Some((old_cookie, new_cookie)));
+    }
+
+    *replaced += replace_words(vm, &encode(old_cookie), &encode(new_cookie));
+    tracing::debug!("replaced {replaced} copies of stack cookie {old_cookie:#x}");
+    Ok(Some((old_cookie, new_cookie)))
+}
+
+/// Replaces every aligned copy of `old` in writable memory with `new`, returning the number of
+/// copies that were replaced.
+fn replace_words(vm: &mut Vm, old: &[u8], new: &[u8]) -> usize {
+    let size = old.len();
+    let mut regions = vec![];
+    for (start, end, entry) in vm.cpu.mem.get_mapping().iter() {
+        if matches!(entry, MemoryMapping::Physical(_))
+            && vm.cpu.mem.get_perm(start) & perm::WRITE != 0
+        {
+            // Note: the end of each mapping is inclusive.
+            regions.push((start, end - start + 1));
+        }
+    }
+
+    let mut replaced = 0;
+    let mut buf = vec![];
+    for (start, len) in regions {
+        buf.resize(len as usize, 0);
+        if vm.cpu.mem.read_bytes_large(start, &mut buf, perm::NONE).is_err() {
+            continue;
+        }
+        let first = (size - (start as usize % size)) % size;
+        for offset in (first..buf.len().saturating_sub(size - 1)).step_by(size) {
+            if buf[offset..offset + size] == old[..] {
+                let addr = start + offset as u64;
+                if vm.cpu.mem.write_bytes(addr, new, perm::NONE).is_ok() {
+                    replaced += 1;
+                }
+            }
+        }
+    }
+    replaced
+}
+
+#[cfg(test)]
+mod tests {
+    use icicle_vm::cpu::{mem::Mapping, Config};
+
+    use super::*;
+
+    #[test]
+    fn replace_cookie_copies() {
+        let mut vm = icicle_vm::build(&Config::from_target_triple("x86_64-none")).unwrap();
+        let rw = Mapping { perm: perm::READ | perm::WRITE, value: 0 };
+        // Mapped at a high address, so treating the end of a mapping as its length would require
+        // a huge buffer.
+        vm.cpu.mem.map_memory_len(0x7fff_0000_0000, 0x2000, rw);
+        vm.cpu.mem.map_memory_len(0x1000, 0x1000, Mapping { perm: perm::READ, value: 0 });
+
+        let old = 0x1122_3344_5566_7700_u64.to_le_bytes();
+        let new = 0x8877_6655_4433_2200_u64.to_le_bytes();
+        for addr in [0x7fff_0000_0008, 0x7fff_0000_1ff8, 0x1008] {
+            vm.cpu.mem.write_bytes(addr, &old, perm::NONE).unwrap();
+        }
+        // Unaligned copies are not replaced.
+        vm.cpu.mem.write_bytes(0x7fff_0000_0104, &old, perm::NONE).unwrap();
+
+        assert_eq!(replace_words(&mut vm, &old, &new), 2);
+        let read = |vm: &mut Vm, addr| vm.cpu.mem.read_u64(addr, perm::NONE).unwrap();
+        assert_eq!(read(&mut vm, 0x7fff_0000_0008), u64::from_le_bytes(new));
+        assert_eq!(read(&mut vm, 0x7fff_0000_1ff8), u64::from_le_bytes(new));
+        assert_eq!(read(&mut vm, 0x7fff_0000_0104), u64::from_le_bytes(old));
+        assert_eq!(read(&mut vm, 0x1008), u64::from_le_bytes(old));
+    }
+}
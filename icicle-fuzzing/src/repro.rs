@@ -0,0 +1,445 @@
+//! Self-contained crash reproduction bundles.
+//!
+//! A bundle captures everything needed to replay an interesting input on another machine: the
+//! input itself, the environment variables used to configure the fuzzer (with any custom setup
+//! file inlined), the emulator and guest arguments, the entropy seed and the address the fuzzing
+//! snapshot was taken at, a list of the modules the target was built from (with hashes to detect
+//! mismatches), and the crash report generated at the time the input was found.
+//!
+//! Bundles are replayed using [crate::with_config_env], so replaying does not modify the
+//! environment of the current process.
+
+use std::path::Path;
+
+use anyhow::Context;
+use icicle_vm::{translation_cache::hash_bytes, Vm, VmExit};
+
+use crate::{gen_crash_key, FuzzConfig};
+
+/// The name of the file containing the bundle metadata.
+const BUNDLE_FILE: &str = "bundle.ron";
+
+/// The name of the file containing the raw input.
+const INPUT_FILE: &str = "input";
+
+/// Environment variable prefixes that affect how the fuzzer configures the VM.
+const CONFIG_ENV_PREFIXES: &[&str] =
+    &["ICICLE_", "MSP430_", "AFL_COMPCOV_LEVEL", "AFL_INST_LIBS", "COVERAGE_MODE", "BREAKPOINTS"];
+
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct ModuleInfo {
+    /// The path to the module on the host.
+    pub path: String,
+    /// The hash of the contents of the module (see [hash_bytes]).
+    pub hash: u64,
+    /// The size of the module in bytes.
+    pub size: u64,
+}
+
+impl ModuleInfo {
+    pub fn from_path(path: &str) -> anyhow::Result<Self> {
+        let data = std::fs::read(path).with_context(|| format!("failed to read: {path}"))?;
+        Ok(Self { path: path.to_owned(), hash: hash_bytes(&data), size: data.len() as u64 })
+    }
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct ReproBundle {
+    /// The input that triggered the exit.
+    #[serde(skip)]
+    pub input: Vec<u8>,
+
+    /// The target triple the VM was configured for.
+    pub arch: String,
+
+    /// Additional arguments passed to the emulator.
+    #[serde(default)]
+    pub icicle_args: Vec<String>,
+
+    /// Arguments passed to the guest.
+    pub guest_args: Vec<String>,
+
+    /// Environment variables used to configure the fuzzer.
+    pub env: Vec<(String, String)>,
+
+    /// The instruction limit used when the input was executed.
+    pub icount_limit: u64,
+
+    /// The seed used for all entropy sources visible to the guest (see [FuzzConfig::entropy_seed]).
+    #[serde(default)]
+    pub entropy_seed: u64,
+
+    /// The address the VM was run to before taking the snapshot that inputs are executed from (see
+    /// [FuzzConfig::start_addr]).
+    #[serde(default)]
+    pub start_addr: Option<u64>,
+
+    /// Modules that the target depends on.
+    pub modules: Vec<ModuleInfo>,
+
+    /// The deduplication key generated for the exit (see [gen_crash_key]).
+    pub crash_key: String,
+
+    /// The exit condition when the input was executed.
+    pub exit: String,
+
+    /// The instruction count at the exit.
+    pub icount: u64,
+
+    /// The symbolized backtrace at the exit.
+    pub backtrace: String,
+}
+
+impl ReproBundle {
+    /// Captures a reproduction bundle for `input` from the current state of `vm` (which is expected
+    /// to have just exited with `exit` after executing `input`).
+    pub fn capture(
+        vm: &mut Vm,
+        config: &FuzzConfig,
+        input: &[u8],
+        exit: VmExit,
+    ) -> anyhow::Result<Self> {
+        let mut modules = vec![];
+        if let Some(path) = config.guest_args.first() {
+            modules.push(ModuleInfo::from_path(path)?);
+        }
+
+        // Libraries loaded by the guest, which are resolved relative to the sysroot.
+        let mut libraries = vec![];
+        if let Some(debug_info) = vm.env.debug_info() {
+            if !debug_info.dynamic_linker.is_empty() {
+                libraries.push(debug_info.dynamic_linker.clone());
+            }
+            libraries.extend(debug_info.modules.iter().map(|module| module.path.clone()));
+        }
+        for library in libraries {
+            let library = String::from_utf8_lossy(&library).into_owned();
+            let path = host_path(&config.linux.sysroot, &library);
+            if modules.iter().any(|module| module.path == path) {
+                continue;
+            }
+            match ModuleInfo::from_path(&path) {
+                Ok(module) => modules.push(module),
+                Err(e) => tracing::warn!("unable to hash {library}: {e:#}"),
+            }
+        }
+
+        // MCU configs can either be a built-in name or the path to a config file.
+        if let Some(mcu) = config.msp430.mcu.as_ref().filter(|x| Path::new(x).is_file()) {
+            modules.push(ModuleInfo::from_path(mcu)?);
+        }
+
+        let mut env: Vec<_> = crate::config_vars()
+            .into_iter()
+            .filter(|(key, _)| CONFIG_ENV_PREFIXES.iter().any(|prefix| key.starts_with(prefix)))
+            .collect();
+
+        // Inline the custom setup so that the bundle does not depend on the original file.
+        if let Some(index) = env.iter().position(|(key, _)| key == "ICICLE_CUSTOM_SETUP_PATH") {
+            let (_, path) = env.remove(index);
+            if !env.iter().any(|(key, _)| key == "ICICLE_CUSTOM_SETUP") {
+                let setup = std::fs::read_to_string(&path)
+                    .with_context(|| format!("failed to read custom setup: {path}"))?;
+                env.push(("ICICLE_CUSTOM_SETUP".into(), setup));
+            }
+        }
+        env.sort();
+
+        Ok(Self {
+            input: input.to_vec(),
+            arch: config.arch.to_string(),
+            icicle_args: config.icicle_args.clone(),
+            guest_args: config.guest_args.clone(),
+            env,
+            icount_limit: config.icount_limit,
+            entropy_seed: vm.cpu.entropy.seed(),
+            start_addr: config.start_addr,
+            modules,
+            crash_key: gen_crash_key(vm, exit),
+            exit: format!("{exit:?}"),
+            icount: vm.cpu.icount(),
+            backtrace: icicle_vm::debug::backtrace(vm),
+        })
+    }
+
+    /// Saves the bundle to `dir`, creating the directory if it does not exist.
+    pub fn save(&self, dir: &Path) -> anyhow::Result<()> {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("failed to create: {}", dir.display()))?;
+
+        let metadata = ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default())?;
+        std::fs::write(dir.join(BUNDLE_FILE), metadata)
+            .with_context(|| format!("failed to write bundle to: {}", dir.display()))?;
+        std::fs::write(dir.join(INPUT_FILE), &self.input)
+            .with_context(|| format!("failed to write input to: {}", dir.display()))?;
+
+        Ok(())
+    }
+
+    /// Loads a bundle previously saved with [ReproBundle::save].
+    pub fn load(dir: &Path) -> anyhow::Result<Self> {
+        let path = dir.join(BUNDLE_FILE);
+        let metadata = std::fs::read_to_string(&path)
+            .with_context(|| format!("failed to read: {}", path.display()))?;
+        let mut bundle: Self = ron::from_str(&metadata)
+            .with_context(|| format!("failed to parse: {}", path.display()))?;
+
+        let path = dir.join(INPUT_FILE);
+        bundle.input =
+            std::fs::read(&path).with_context(|| format!("failed to read: {}", path.display()))?;
+
+        Ok(bundle)
+    }
+
+    /// Checks that the modules on the host match the modules recorded in the bundle.
+    pub fn check_modules(&self) -> anyhow::Result<()> {
+        for module in &self.modules {
+            let current = ModuleInfo::from_path(&module.path)?;
+            anyhow::ensure!(
+                current == *module,
+                "module mismatch for {} (expected hash={:#x}, found hash={:#x})",
+                module.path,
+                module.hash,
+                current.hash
+            );
+        }
+        Ok(())
+    }
+
+    /// The variables used to configure the fuzzer when the bundle was captured.
+    pub fn config_env(&self) -> Vec<(String, String)> {
+        const OVERRIDDEN: &[&str] =
+            &["ICICLE_ARCH", "ICICLE_ICOUNT_LIMIT", "ICICLE_ENTROPY_SEED", "ICICLE_START_ADDR"];
+
+        let mut env = self.env.clone();
+        env.retain(|(key, _)| !OVERRIDDEN.contains(&key.as_str()));
+        env.push(("ICICLE_ARCH".into(), self.arch.clone()));
+        env.push(("ICICLE_ICOUNT_LIMIT".into(), self.icount_limit.to_string()));
+        env.push(("ICICLE_ENTROPY_SEED".into(), format!("{:#x}", self.entropy_seed)));
+        if let Some(addr) = self.start_addr {
+            env.push(("ICICLE_START_ADDR".into(), format!("{addr:#x}")));
+        }
+        env
+    }
+
+    /// Reconstruct the fuzzer configuration used when the bundle was captured.
+    pub fn config(&self) -> anyhow::Result<FuzzConfig> {
+        crate::with_config_env(&self.config_env(), || {
+            FuzzConfig::load_with_args(self.icicle_args.clone(), self.guest_args.clone())
+        })
+    }
+}
+
+/// Resolves the path of a library loaded by the guest to a path on the host.
+fn host_path(sysroot: &Path, path: &str) -> String {
+    let path = sysroot.join(path.trim_start_matches('/'));
+    path.to_string_lossy().into_owned()
+}
+
+/// The result of replaying a bundle.
+#[derive(Debug)]
+pub struct ReproResult {
+    /// The exit condition of the replayed input.
+    pub exit: VmExit,
+    /// The deduplication key generated for the replayed exit.
+    pub crash_key: String,
+    /// The instruction count at the exit.
+    pub icount: u64,
+    /// Whether the replay exited at the same location, for the same reason, as the original run.
+    pub matches: bool,
+}
+
+/// Replays the bundle stored in `dir`.
+pub fn reproduce(dir: &Path) -> anyhow::Result<ReproResult> {
+    let bundle = ReproBundle::load(dir)?;
+    reproduce_bundle(&bundle)
+}
+
+/// Replays `bundle`, returning information about the exit.
+pub fn reproduce_bundle(bundle: &ReproBundle) -> anyhow::Result<ReproResult> {
+    reproduce_bundle_with_env(bundle, &bundle.config_env())
+}
+
+/// Replays `bundle` with the fuzzer configured using only the variables in `env` (normally
+/// [ReproBundle::config_env]), returning information about the exit.
+pub fn reproduce_bundle_with_env(
+    bundle: &ReproBundle,
+    env: &[(String, String)],
+) -> anyhow::Result<ReproResult> {
+    bundle.check_modules()?;
+
+    // Targets also read configuration variables while the VM is being created.
+    let (mut vm, exit) = crate::with_config_env(env, || {
+        let mut config =
+            FuzzConfig::load_with_args(bundle.icicle_args.clone(), bundle.guest_args.clone())?;
+        let mut target = config.get_target()?;
+        let mut vm = target.create_vm(&mut config)?;
+        target.initialize_vm(&config, &mut vm)?;
+
+        target.set_input(&mut vm, &bundle.input)?;
+        let exit = target.run(&mut vm)?;
+        anyhow::Ok((vm, exit))
+    })?;
+
+    let crash_key = gen_crash_key(&mut vm, exit);
+    let icount = vm.cpu.icount();
+    let matches = crash_key == bundle.crash_key && icount == bundle.icount;
+    if !matches {
+        tracing::warn!(
+            "replay diverged: expected {} (icount={}), got {crash_key} (icount={icount})",
+            bundle.crash_key,
+            bundle.icount
+        );
+    }
+
+    Ok(ReproResult { exit, crash_key, icount, matches })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("icicle-repro-{name}-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn bundle(modules: Vec<ModuleInfo>) -> ReproBundle {
+        ReproBundle {
+            input: b"crash".to_vec(),
+            arch: "x86_64-linux".into(),
+            icicle_args: vec!["icicle".into()],
+            guest_args: vec!["/bin/target".into(), "@@".into()],
+            env: vec![
+                ("ICICLE_ARCH".into(), "msp430-none".into()),
+                ("ICICLE_CONTEXT_BITS".into(), "4".into()),
+            ],
+            icount_limit: 1234,
+            entropy_seed: 0x5eed,
+            start_addr: Some(0x4400),
+            modules,
+            crash_key: "ReadUnmapped@0x1000".into(),
+            exit: "UnhandledException((ReadUnmapped, 0x0))".into(),
+            icount: 100,
+            backtrace: String::new(),
+        }
+    }
+
+    #[test]
+    fn save_and_load() {
+        let dir = temp_dir("save");
+        let module = dir.join("module");
+        std::fs::write(&module, b"module contents").unwrap();
+        let module = ModuleInfo::from_path(module.to_str().unwrap()).unwrap();
+        assert_eq!(module.hash, hash_bytes(b"module contents"));
+        assert_eq!(module.size, 15);
+
+        let bundle = bundle(vec![module.clone()]);
+        bundle.save(&dir.join("bundle")).unwrap();
+        let loaded = ReproBundle::load(&dir.join("bundle")).unwrap();
+        assert_eq!(loaded.input, bundle.input);
+        assert_eq!(loaded.icicle_args, bundle.icicle_args);
+        assert_eq!(loaded.guest_args, bundle.guest_args);
+        assert_eq!(loaded.env, bundle.env);
+        assert_eq!(loaded.entropy_seed, bundle.entropy_seed);
+        assert_eq!(loaded.start_addr, bundle.start_addr);
+        assert_eq!(loaded.modules, std::slice::from_ref(&module));
+        assert_eq!((&loaded.crash_key, loaded.icount), (&bundle.crash_key, bundle.icount));
+        loaded.check_modules().unwrap();
+
+        // Modifying a module is detected before the bundle is replayed.
+        std::fs::write(&module.path, b"modified contents").unwrap();
+        assert!(loaded.check_modules().is_err());
+        assert!(reproduce_bundle(&loaded).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// A static x86-64 executable that reads from a null pointer.
+    fn null_read_elf() -> Vec<u8> {
+        const BASE: u64 = 0x40_0000;
+        let code = [
+            0x8b, 0x04, 0x25, 0x00, 0x00, 0x00, 0x00, // mov eax, dword ptr [0]
+            0xeb, 0xfe, // jmp $
+        ];
+
+        let mut elf = vec![0x7f, b'E', b'L', b'F', 2, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        elf.extend_from_slice(&2_u16.to_le_bytes()); // e_type = ET_EXEC
+        elf.extend_from_slice(&62_u16.to_le_bytes()); // e_machine = EM_X86_64
+        elf.extend_from_slice(&1_u32.to_le_bytes()); // e_version
+        for value in [BASE + 64 + 56, 64, 0] {
+            // e_entry, e_phoff, e_shoff
+            elf.extend_from_slice(&value.to_le_bytes());
+        }
+        elf.extend_from_slice(&0_u32.to_le_bytes()); // e_flags
+        for value in [64_u16, 56, 1, 64, 0, 0] {
+            // e_ehsize, e_phentsize, e_phnum, e_shentsize, e_shnum, e_shstrndx
+            elf.extend_from_slice(&value.to_le_bytes());
+        }
+        let len = (64 + 56 + code.len()) as u64;
+        elf.extend_from_slice(&1_u32.to_le_bytes()); // p_type = PT_LOAD
+        elf.extend_from_slice(&5_u32.to_le_bytes()); // p_flags = PF_R | PF_X
+        for value in [0, BASE, BASE, len, len, 0x1000] {
+            // p_offset, p_vaddr, p_paddr, p_filesz, p_memsz, p_align
+            elf.extend_from_slice(&value.to_le_bytes());
+        }
+        elf.extend_from_slice(&code);
+        elf
+    }
+
+    #[test]
+    fn replay_ignores_process_env() {
+        let sysroot = temp_dir("sysroot");
+        std::fs::write(sysroot.join("prog"), null_read_elf()).unwrap();
+
+        // Custom setups create the environment with `build_auto_with_config`, which resolves the
+        // program relative to the sysroot.
+        let mut bundle = bundle(vec![]);
+        bundle.guest_args = vec!["/prog".into()];
+        bundle.env = vec![
+            ("ICICLE_CUSTOM_SETUP".into(), "()".into()),
+            ("ICICLE_SYSROOT".into(), sysroot.to_str().unwrap().into()),
+        ];
+        let env = bundle.config_env();
+        assert!(env.contains(&("ICICLE_SYSROOT".into(), sysroot.to_str().unwrap().into())));
+        let expected = reproduce_bundle_with_env(&bundle, &env).unwrap();
+        bundle.crash_key = expected.crash_key;
+        bundle.icount = expected.icount;
+
+        // Replaying uses the sysroot from `env`, even if the surrounding configuration (standing in
+        // for the environment of the process) uses a different one.
+        let missing = sysroot.join("missing").to_str().unwrap().to_owned();
+        let process_env = [("ICICLE_SYSROOT".to_owned(), missing)];
+        let result =
+            crate::with_config_env(&process_env, || reproduce_bundle_with_env(&bundle, &env));
+        std::fs::remove_dir_all(&sysroot).unwrap();
+
+        let result = result.unwrap();
+        assert!(result.matches, "{result:?}");
+        assert_eq!(result.exit, expected.exit);
+    }
+
+    #[test]
+    fn config_does_not_modify_process_env() {
+        let config = bundle(vec![]).config().unwrap();
+
+        // The architecture and instruction limit of the bundle take priority over the environment.
+        assert_eq!(config.arch, "x86_64-linux".parse().unwrap());
+        assert_eq!(config.icount_limit, 1234);
+        assert_eq!(config.entropy_seed, 0x5eed);
+        assert_eq!(config.cpu_config().entropy_seed, 0x5eed);
+        assert_eq!(config.start_addr, Some(0x4400));
+        assert_eq!(config.context_bits, 4);
+        assert_eq!(config.icicle_args, ["icicle"]);
+        assert_eq!(config.guest_args, ["/bin/target", "@@"]);
+        assert!(std::env::var_os("ICICLE_CONTEXT_BITS").is_none());
+
+        // Variables that are not part of the bundle are not read from the process environment.
+        assert!(std::env::var_os("PATH").is_some());
+        assert!(crate::with_config_env(&[], || crate::config_var("PATH").is_err()));
+        assert!(crate::config_var("PATH").is_ok());
+    }
+}
@@ -0,0 +1,253 @@
+//! Per-input execution metadata for corpus scheduling.
+//!
+//! Power schedules (e.g. AFL's `explore` or `fast` schedules) decide which input to fuzz next, and
+//! for how long, based on metadata about each input in the corpus: how quickly it executes, how
+//! much new coverage it found, and how often it has already been chosen. [ExecutionObserver]
+//! collects this metadata as part of a normal execution, so fuzzers embedding Icicle do not need
+//! extra calibration runs, and [Scheduler] is the extension point for the schedule itself.
+
+use icicle_vm::{Vm, VmExit};
+
+/// Identifies an input in the corpus of the embedding fuzzer.
+pub type InputId = usize;
+
+/// Metadata collected from a single execution of an input.
+#[derive(Clone, Debug)]
+pub struct ExecMetadata {
+    /// How the execution ended.
+    pub exit: VmExit,
+
+    /// The number of instructions executed.
+    pub icount: u64,
+
+    /// The number of coverage map entries that were hit for the first time.
+    pub new_edges: usize,
+
+    /// The number of previously hit coverage map entries that were hit with a new hit count
+    /// bucket (see [hit_count_bucket]).
+    pub new_hit_counts: usize,
+
+    /// The total number of coverage map entries hit.
+    pub edges: usize,
+
+    /// The number of pages written to by the guest.
+    pub pages_touched: usize,
+}
+
+impl ExecMetadata {
+    /// Returns whether the input found new coverage and should be added to the corpus.
+    pub fn is_interesting(&self) -> bool {
+        self.new_edges != 0 || self.new_hit_counts != 0
+    }
+}
+
+/// A corpus scheduler implemented by the embedding fuzzer.
+pub trait Scheduler {
+    /// Called when an input is added to the corpus, with the metadata from its first execution.
+    fn add_input(&mut self, id: InputId, metadata: &ExecMetadata);
+
+    /// Called after executing a mutated version of the input `parent`.
+    fn on_execution(&mut self, _parent: InputId, _metadata: &ExecMetadata) {}
+
+    /// Chooses the next input to fuzz and the number of mutations to perform on it, or `None` if
+    /// the corpus is empty.
+    fn next(&mut self) -> Option<(InputId, u64)>;
+}
+
+/// Maps a hit count to a single bit, using the same buckets as AFL.
+pub fn hit_count_bucket(count: u8) -> u8 {
+    match count {
+        0 => 0,
+        1 => 1 << 0,
+        2 => 1 << 1,
+        3 => 1 << 2,
+        4..=7 => 1 << 3,
+        8..=15 => 1 << 4,
+        16..=31 => 1 << 5,
+        32..=127 => 1 << 6,
+        128..=255 => 1 << 7,
+    }
+}
+
+/// Computes [ExecMetadata] for executions by comparing the coverage map of each execution against
+/// the coverage seen by all previous executions.
+pub struct ExecutionObserver {
+    /// The hit count buckets seen for each coverage map entry.
+    seen: Vec<u8>,
+
+    /// The instruction count at the start of the current execution.
+    start_icount: u64,
+}
+
+impl ExecutionObserver {
+    pub fn new(map_size: usize) -> Self {
+        Self { seen: vec![0; map_size], start_icount: 0 }
+    }
+
+    /// Marks the start of an execution. This should be called after restoring the VM snapshot for
+    /// the execution and before running the VM.
+    pub fn start(&mut self, vm: &mut Vm) {
+        self.start_icount = vm.cpu.icount();
+        vm.cpu.mem.clear_page_modification_log();
+    }
+
+    /// Computes the metadata for the execution that ended with `exit`, where `coverage` is the
+    /// coverage map filled in by the execution. If `update` is set, the coverage is recorded so it
+    /// is no longer considered new for later executions.
+    pub fn finish(
+        &mut self,
+        vm: &mut Vm,
+        exit: VmExit,
+        coverage: &[u8],
+        update: bool,
+    ) -> ExecMetadata {
+        let mut metadata = ExecMetadata {
+            exit,
+            icount: vm.cpu.icount().saturating_sub(self.start_icount),
+            new_edges: 0,
+            new_hit_counts: 0,
+            edges: 0,
+            pages_touched: vm.cpu.mem.modified.len(),
+        };
+
+        if self.seen.len() < coverage.len() {
+            self.seen.resize(coverage.len(), 0);
+        }
+        for (seen, &count) in self.seen.iter_mut().zip(coverage) {
+            let bucket = hit_count_bucket(count);
+            if bucket == 0 {
+                continue;
+            }
+            metadata.edges += 1;
+            if *seen == 0 {
+                metadata.new_edges += 1;
+            }
+            else if *seen & bucket == 0 {
+                metadata.new_hit_counts += 1;
+            }
+            if update {
+                *seen |= bucket;
+            }
+        }
+
+        metadata
+    }
+
+    /// Clears all previously seen coverage.
+    pub fn reset(&mut self) {
+        self.seen.fill(0);
+    }
+}
+
+#[derive(Clone, Debug)]
+struct FastEntry {
+    id: InputId,
+    icount: u64,
+    new_edges: usize,
+    times_chosen: u32,
+    times_fuzzed: u64,
+}
+
+/// A simple schedule based on AFL's `fast` power schedule: inputs that execute quickly, found more
+/// new coverage, and have been fuzzed less are preferred.
+pub struct FastScheduler {
+    entries: Vec<FastEntry>,
+
+    /// The number of mutations performed for an input with an average score.
+    base_energy: u64,
+
+    /// The maximum number of mutations performed on a single input each time it is chosen.
+    max_energy: u64,
+}
+
+impl Default for FastScheduler {
+    fn default() -> Self {
+        Self { entries: vec![], base_energy: 100, max_energy: 1600 }
+    }
+}
+
+impl FastScheduler {
+    fn score(&self, entry: &FastEntry, avg_icount: f64) -> f64 {
+        let speed = avg_icount / entry.icount.max(1) as f64;
+        let coverage = 1.0 + (entry.new_edges as f64).ln_1p();
+        let fuzzed = (1 + entry.times_fuzzed / self.base_energy).next_power_of_two() as f64;
+        speed.clamp(0.1, 3.0) * coverage / fuzzed
+    }
+}
+
+impl Scheduler for FastScheduler {
+    fn add_input(&mut self, id: InputId, metadata: &ExecMetadata) {
+        self.entries.push(FastEntry {
+            id,
+            icount: metadata.icount,
+            new_edges: metadata.new_edges + metadata.new_hit_counts,
+            times_chosen: 0,
+            times_fuzzed: 0,
+        });
+    }
+
+    fn on_execution(&mut self, parent: InputId, _metadata: &ExecMetadata) {
+        if let Some(entry) = self.entries.iter_mut().find(|x| x.id == parent) {
+            entry.times_fuzzed += 1;
+        }
+    }
+
+    fn next(&mut self) -> Option<(InputId, u64)> {
+        let total: u64 = self.entries.iter().map(|x| x.icount).sum();
+        let avg_icount = total as f64 / self.entries.len().max(1) as f64;
+
+        let (index, score) = self
+            .entries
+            .iter()
+            .enumerate()
+            .map(|(i, entry)| (i, self.score(entry, avg_icount) / (1 + entry.times_chosen) as f64))
+            .max_by(|a, b| a.1.total_cmp(&b.1))?;
+
+        let energy = ((self.base_energy as f64 * score) as u64).clamp(1, self.max_energy);
+        let entry = &mut self.entries[index];
+        entry.times_chosen += 1;
+        Some((entry.id, energy))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{hit_count_bucket, ExecMetadata, FastScheduler, Scheduler};
+
+    fn metadata(icount: u64, new_edges: usize) -> ExecMetadata {
+        ExecMetadata {
+            exit: icicle_vm::VmExit::Halt,
+            icount,
+            new_edges,
+            new_hit_counts: 0,
+            edges: new_edges,
+            pages_touched: 0,
+        }
+    }
+
+    #[test]
+    fn hit_count_buckets() {
+        assert_eq!(hit_count_bucket(0), 0);
+        assert_eq!(hit_count_bucket(3), 0b100);
+        assert_eq!(hit_count_bucket(7), hit_count_bucket(4));
+        assert_eq!(hit_count_bucket(255), 0b1000_0000);
+    }
+
+    #[test]
+    fn fast_schedule_prefers_quick_inputs() {
+        let mut scheduler = FastScheduler::default();
+        scheduler.add_input(0, &metadata(100_000, 5));
+        scheduler.add_input(1, &metadata(1_000, 5));
+
+        let (first, energy) = scheduler.next().unwrap();
+        assert_eq!(first, 1);
+        assert!(energy > 0);
+
+        // Inputs that have already been fuzzed are deprioritized.
+        for _ in 0..energy {
+            scheduler.on_execution(first, &metadata(1_000, 0));
+        }
+        let (second, _) = scheduler.next().unwrap();
+        assert_eq!(second, 0);
+    }
+}
@@ -0,0 +1,264 @@
+//! An on-disk symbolization cache that can be shared between fuzzing workers.
+//!
+//! Symbolizing an address requires parsing the DWARF information of the module containing it, so
+//! triaging crashes across many workers ends up repeating the same work in every process. The
+//! [SymbolCache] stores the result of each lookup in a directory, with one table per module keyed
+//! by the module's build ID and the offset of the address within the module. Tables are merged
+//! with the results of other workers when they are flushed, so each address only needs to be
+//! symbolized once per binary.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+    rc::Rc,
+};
+
+use anyhow::Context;
+use icicle_vm::{
+    cpu::debug_info::{build_id, SourceLocation},
+    Vm,
+};
+
+/// The load-address independent parts of a [SourceLocation].
+#[derive(Clone, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct CachedLocation {
+    pub symbol_with_offset: Option<(String, u64)>,
+    pub function: Option<String>,
+    pub file: Option<String>,
+    pub line: Option<u32>,
+    pub column: Option<u32>,
+    pub library: Option<Vec<u8>>,
+}
+
+impl CachedLocation {
+    pub fn from_location(location: &SourceLocation) -> Self {
+        Self {
+            symbol_with_offset: location.symbol_with_offset.clone(),
+            function: location.function.as_ref().map(|(name, _)| name.clone()),
+            file: location.file.clone(),
+            line: location.line,
+            column: location.column,
+            library: location.library_name_and_offset.as_ref().map(|(name, _)| name.clone()),
+        }
+    }
+
+    /// Converts the cached entry for `addr` back to a [SourceLocation] for a module loaded at
+    /// `base`.
+    ///
+    /// Note: the start address of the function is not cached, so it is set to the start of the
+    /// symbol containing `addr` (or `addr` itself if there is no symbol).
+    pub fn to_location(&self, addr: u64, base: u64) -> SourceLocation {
+        let start =
+            self.symbol_with_offset.as_ref().map_or(addr, |(_, offset)| addr.wrapping_sub(*offset));
+        SourceLocation {
+            symbol_with_offset: self.symbol_with_offset.clone(),
+            function: self.function.clone().map(|name| (name, start)),
+            file: self.file.clone(),
+            line: self.line,
+            column: self.column,
+            library_name_and_offset: self.library.clone().map(|name| (name, base)),
+        }
+    }
+}
+
+/// Cached locations for a single module, keyed by offset from the start of the module. Addresses
+/// that failed to symbolize are stored as `None`.
+type Table = BTreeMap<u64, Option<CachedLocation>>;
+
+#[derive(Default)]
+struct CacheTable {
+    entries: Table,
+
+    /// Whether new entries have been added since the table was last flushed.
+    dirty: bool,
+}
+
+pub struct SymbolCache {
+    /// The directory the cache is stored in.
+    dir: PathBuf,
+
+    /// Regions of memory that belong to a module with a known build ID, keyed by start address:
+    /// (end, base, build ID).
+    regions: BTreeMap<u64, (u64, u64, Rc<str>)>,
+
+    /// The build IDs of files that have already been inspected, keyed by path.
+    build_ids: HashMap<Vec<u8>, Option<Rc<str>>>,
+
+    /// The tables that have been loaded so far, keyed by build ID.
+    tables: HashMap<Rc<str>, CacheTable>,
+}
+
+impl SymbolCache {
+    /// Opens (creating if necessary) the symbolization cache stored in `dir`.
+    pub fn open(dir: &Path) -> anyhow::Result<Self> {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("failed to create: {}", dir.display()))?;
+        Ok(Self {
+            dir: dir.to_owned(),
+            regions: BTreeMap::new(),
+            build_ids: HashMap::new(),
+            tables: HashMap::new(),
+        })
+    }
+
+    /// Registers the memory in `start..end` as part of the module identified by `build_id` that
+    /// was loaded at `base`.
+    pub fn add_module(&mut self, build_id: &str, start: u64, end: u64, base: u64) {
+        self.regions.insert(start, (end, base, build_id.into()));
+    }
+
+    /// Registers all files currently mapped by the active Linux process that have a build ID. Does
+    /// nothing if the VM is not running a Linux environment.
+    pub fn add_linux_modules(&mut self, vm: &mut Vm) {
+        let Some(kernel) = vm.env_mut::<icicle_vm::linux::Kernel>()
+        else {
+            return;
+        };
+
+        // A module can consist of multiple regions, offsets are relative to the lowest address the
+        // module is mapped at.
+        let mut bases: HashMap<&[u8], u64> = HashMap::new();
+        for (start, entry) in &kernel.process.mapping {
+            let base = bases.entry(&entry.path[..]).or_insert(*start);
+            *base = (*base).min(*start);
+        }
+
+        let mut regions = vec![];
+        for (start, entry) in &kernel.process.mapping {
+            regions.push((*start, entry.end, bases[&entry.path[..]], entry.path.clone()));
+        }
+
+        for (start, end, base, path) in regions {
+            let id = self.build_ids.entry(path).or_insert_with_key(|path| {
+                let data = kernel.vfs.read_raw(path).ok()?;
+                let id = build_id(&data)?;
+                Some(id.iter().map(|byte| format!("{byte:02x}")).collect::<String>().into())
+            });
+            if let Some(id) = id.clone() {
+                self.regions.insert(start, (end, base, id));
+            }
+        }
+    }
+
+    /// Finds the build ID of the module containing `addr` and the offset of `addr` within it.
+    fn locate(&self, addr: u64) -> Option<(Rc<str>, u64, u64)> {
+        match self.regions.range(..=addr).next_back() {
+            Some((_, (end, base, id))) if addr < *end => Some((id.clone(), addr - base, *base)),
+            _ => None,
+        }
+    }
+
+    /// Returns the table for `build_id`, loading it from disk if necessary.
+    fn table(&mut self, build_id: &Rc<str>) -> &mut CacheTable {
+        let path = self.dir.join(format!("{build_id}.ron"));
+        self.tables.entry(build_id.clone()).or_insert_with(|| CacheTable {
+            entries: read_table(&path).unwrap_or_else(|e| {
+                tracing::warn!("{e:#}");
+                Table::new()
+            }),
+            dirty: false,
+        })
+    }
+
+    /// Symbolizes `addr`, using the cached result if the address has been symbolized before.
+    /// Addresses outside of any module with a build ID are symbolized without the cache.
+    pub fn symbolize(&mut self, vm: &mut Vm, addr: u64) -> Option<SourceLocation> {
+        let Some((id, offset, base)) = self.locate(addr)
+        else {
+            return vm.env.symbolize_addr(&mut vm.cpu, addr);
+        };
+
+        let table = self.table(&id);
+        if let Some(entry) = table.entries.get(&offset) {
+            return entry.as_ref().map(|x| x.to_location(addr, base));
+        }
+
+        let location = vm.env.symbolize_addr(&mut vm.cpu, addr);
+        table.entries.insert(offset, location.as_ref().map(CachedLocation::from_location));
+        table.dirty = true;
+        location
+    }
+
+    /// Equivalent to [icicle_vm::debug::backtrace], but symbolizes addresses using the cache.
+    pub fn backtrace(&mut self, vm: &mut Vm) -> String {
+        icicle_vm::debug::backtrace_with(vm, 64, |vm, addr| self.symbolize(vm, addr))
+    }
+
+    /// Writes new entries to disk, merging them with any entries added by other processes since
+    /// the table was loaded. Each table is replaced atomically so concurrent readers never observe
+    /// a partially written table, and writers hold an exclusive lock on the table while merging so
+    /// entries added by other processes are never lost.
+    pub fn flush(&mut self) -> anyhow::Result<()> {
+        for (id, table) in self.tables.iter_mut().filter(|(_, table)| table.dirty) {
+            let path = self.dir.join(format!("{id}.ron"));
+
+            let lock_path = path.with_extension("lock");
+            let lock = std::fs::File::create(&lock_path)
+                .with_context(|| format!("failed to create: {}", lock_path.display()))?;
+            lock.lock().with_context(|| format!("failed to lock: {}", lock_path.display()))?;
+
+            let mut entries = read_table(&path)?;
+            entries.extend(std::mem::take(&mut table.entries));
+            let data = ron::ser::to_string(&entries)?;
+
+            let tmp = path.with_extension(format!("{}.tmp", std::process::id()));
+            std::fs::write(&tmp, data)
+                .with_context(|| format!("failed to write: {}", tmp.display()))?;
+            std::fs::rename(&tmp, &path)
+                .with_context(|| format!("failed to write: {}", path.display()))?;
+
+            table.entries = entries;
+            table.dirty = false;
+        }
+        Ok(())
+    }
+}
+
+/// Reads a table from `path`. If `path` does not exist an empty table is returned.
+fn read_table(path: &Path) -> anyhow::Result<Table> {
+    let data = match std::fs::read_to_string(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Table::new()),
+        Err(e) => return Err(e).with_context(|| format!("failed to read: {}", path.display())),
+    };
+    ron::from_str(&data).with_context(|| format!("error parsing symbol cache: {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cached_locations_are_shared_between_instances() {
+        let config = icicle_vm::cpu::Config::from_target_triple("x86_64-none");
+        let mut vm = icicle_vm::build(&config).unwrap();
+
+        let dir = std::env::temp_dir().join(format!("icicle-symbol-cache-{}", std::process::id()));
+        let mut cache = SymbolCache::open(&dir).unwrap();
+        cache.add_module("abcd", 0x1000, 0x2000, 0x1000);
+
+        let location = CachedLocation {
+            symbol_with_offset: Some(("main".into(), 0x10)),
+            function: Some("main".into()),
+            ..CachedLocation::default()
+        };
+        cache.table(&"abcd".into()).entries.insert(0x110, Some(location));
+        cache.table(&"abcd".into()).dirty = true;
+        cache.flush().unwrap();
+
+        // The module is loaded at a different address in the other worker.
+        let mut other = SymbolCache::open(&dir).unwrap();
+        other.add_module("abcd", 0x5000, 0x6000, 0x5000);
+        let symbolized = other.symbolize(&mut vm, 0x5110).unwrap();
+        assert_eq!(symbolized.symbol_with_offset, Some(("main".into(), 0x10)));
+        assert_eq!(symbolized.function, Some(("main".into(), 0x5100)));
+
+        // Addresses that fail to symbolize are also cached.
+        assert_eq!(other.symbolize(&mut vm, 0x5200), None);
+        other.flush().unwrap();
+        let entries = read_table(&dir.join("abcd.ron")).unwrap();
+        assert_eq!(entries.get(&0x200), Some(&None));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -38,12 +38,13 @@ pub fn input_visitor(
 /// Convert an icicle exit code to a status value that AFL understands
 pub fn get_afl_exit_code(vm: &Vm, exit: VmExit) -> u32 {
     const SIGILL: u32 = 4;
+    const SIGABRT: u32 = 6;
     const SIGKILL: u32 = 9;
     const SIGSEGV: u32 = 11;
     const SIGSTOP: u32 = 19;
 
     match CrashKind::from(exit) {
-        CrashKind::Halt => 0,
+        CrashKind::Halt | CrashKind::Unreachable(_) => 0,
         CrashKind::Hang => SIGSTOP,
         CrashKind::OutOfMemory => SIGKILL,
         CrashKind::Killed => match vm
@@ -55,12 +56,43 @@ pub fn get_afl_exit_code(vm: &Vm, exit: VmExit) -> u32 {
             None => 999,
         },
         CrashKind::Custom(_) => SIGILL,
+        CrashKind::Sanitizer(_) => SIGABRT,
         CrashKind::ExecViolation => SIGILL,
         CrashKind::ReadViolation(_) | CrashKind::WriteViolation(_) => SIGSEGV,
         CrashKind::Unknown => 999,
     }
 }
 
+/// Describes the error detected by a sanitizer in the guest (see [icicle_vm::asan],
+/// [icicle_vm::heap_sanitizer], [icicle_vm::stack_sanitizer] and [icicle_vm::provenance]), or
+/// `None` if the VM did not exit because of a sanitizer error.
+pub fn describe_sanitizer_report(vm: &Vm, exit: VmExit) -> Option<String> {
+    if !matches!(CrashKind::from(exit), CrashKind::Sanitizer(_)) {
+        return None;
+    }
+    let report = vm.sanitizer_report()?;
+    match &report.details {
+        Some(details) => Some(format!("{report}\n{details}")),
+        None => Some(report.to_string()),
+    }
+}
+
+/// Describes the process that was running when the VM exited (see
+/// [icicle_vm::linux::process_tree::ProcessTree::describe]), or `None` if the target is not a
+/// Linux process or never created any other processes.
+pub fn describe_current_process(vm: &Vm) -> Option<String> {
+    let kernel = vm.env_ref::<icicle_vm::linux::Kernel>()?;
+    kernel.process_tree.iter().nth(1)?;
+    Some(kernel.process_tree.describe(kernel.process.pid))
+}
+
+/// Describes the environment of the Linux process that was running when the VM exited (see
+/// [icicle_vm::linux::crash_context]), or `None` if the target is not a Linux process.
+pub fn describe_guest_environment(vm: &Vm) -> Option<String> {
+    let kernel = vm.env_ref::<icicle_vm::linux::Kernel>()?;
+    Some(kernel.crash_context().to_string())
+}
+
 pub struct BlockCoverageTracker {
     /// The blocks seen by the fuzzer. Index by the starting address of the block with the time and
     /// input ID corresponding to when the first input reaching that block was found.
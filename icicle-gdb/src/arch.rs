@@ -89,8 +89,20 @@ impl DynamicTarget for IcicleX64 {
     }
 
     fn write_registers(&self, cpu: &mut Cpu, regs: &X86_64CoreRegs) {
-        let _cpu = cpu;
-        let _regs = regs;
+        regs.regs.iter().zip(&self.regs).for_each(|(src, var)| cpu.write_var(*var, *src));
+        icicle_vm::x86::set_eflags(cpu, regs.eflags);
+        cpu.write_var(self.rip, regs.rip);
+
+        cpu.write_var(self.segments.cs, regs.segments.cs as u16);
+        cpu.write_var(self.segments.ss, regs.segments.ss as u16);
+        cpu.write_var(self.segments.ds, regs.segments.ds as u16);
+        cpu.write_var(self.segments.es, regs.segments.es as u16);
+        cpu.write_var(self.segments.fs, regs.segments.fs as u16);
+        cpu.write_var(self.segments.gs, regs.segments.gs as u16);
+
+        regs.st.iter().zip(&self.st).for_each(|(src, var)| cpu.write_var(*var, *src));
+        regs.xmm.iter().zip(&self.xmm).for_each(|(src, var)| cpu.write_var(*var, *src));
+        cpu.write_var(self.mxcsr, regs.mxcsr);
     }
 }
 
@@ -13,9 +13,19 @@ use icicle_vm::Vm;
 use target_lexicon::Architecture;
 
 mod arch;
+mod lldb;
 mod stub;
 
-pub use crate::stub::ExePath;
+pub use crate::stub::{ExePath, exception_to_signal};
+
+/// The size of the packet buffer. This limits the amount of memory the debugger can read or write
+/// with a single packet, so a larger buffer significantly reduces the number of round trips needed
+/// for large memory dumps.
+const PACKET_BUFFER_SIZE: usize = 0x10000;
+
+/// The maximum number of instructions to execute before checking for incoming packets from the
+/// debugger, while the target is running.
+const RUN_SLICE_ICOUNT: u64 = 0x10_0000;
 
 pub type X64Stub<'a> = stub::VmState<'a, arch::IcicleX64>;
 pub type Mips32Stub<'a> = stub::VmState<'a, arch::IcicleMips32>;
@@ -145,7 +155,7 @@ struct BufferedConnection<S> {
 
 impl<S> BufferedConnection<S> {
     fn new(inner: S) -> Self {
-        let buffer_size = 8 * 1024;
+        let buffer_size = PACKET_BUFFER_SIZE;
         Self {
             inner,
             rx_buffer: VecDeque::with_capacity(buffer_size),
@@ -238,8 +248,10 @@ where
     S: ConnectionExt,
     S::Error: Send + Sync + std::fmt::Debug + std::fmt::Display + 'static,
 {
-    let stub = GdbStub::builder(stream).packet_buffer_size(8 * 1024).build()?;
-    match stub.run_blocking::<GdbStubEventLoop<T, S>>(target)? {
+    let info = lldb::TargetInfo::new(target.vm(), target.stop_signal());
+    let stream = lldb::LldbConnection::new(stream, info);
+    let stub = GdbStub::builder(stream).packet_buffer_size(PACKET_BUFFER_SIZE).build()?;
+    match stub.run_blocking::<GdbStubEventLoop<T, lldb::LldbConnection<S>>>(target)? {
         DisconnectReason::TargetExited(status) => {
             tracing::info!("Target exited: {}", status);
         }
@@ -280,18 +292,24 @@ where
             <Self::Connection as gdbstub::conn::Connection>::Error,
         >,
     > {
-        if conn.peek().map(|b| b.is_some()).unwrap_or(true) {
-            let byte = conn.read().map_err(run_blocking::WaitForStopReasonError::Connection)?;
-            return Ok(run_blocking::Event::IncomingData(byte));
-        }
+        loop {
+            if conn.peek().map(|b| b.is_some()).unwrap_or(true) {
+                let byte =
+                    conn.read().map_err(run_blocking::WaitForStopReasonError::Connection)?;
+                return Ok(run_blocking::Event::IncomingData(byte));
+            }
 
-        let exit = target.run();
-        Ok(run_blocking::Event::TargetStopped(exit))
+            if let Some(exit) = target.run_slice(RUN_SLICE_ICOUNT) {
+                return Ok(run_blocking::Event::TargetStopped(exit));
+            }
+        }
     }
 
     fn on_interrupt(
-        _target: &mut Self::Target,
+        target: &mut Self::Target,
     ) -> Result<Option<Self::StopReason>, <Self::Target as gdbstub::target::Target>::Error> {
-        Ok(Some(SingleThreadStopReason::Signal(Signal::SIGINT)))
+        let reason = SingleThreadStopReason::Signal(Signal::SIGINT);
+        target.set_stop_reason(&reason);
+        Ok(Some(reason))
     }
 }
@@ -0,0 +1,297 @@
+//! Support for the LLDB extensions to the GDB remote protocol.
+//!
+//! LLDB queries information about the host and the debugged process with packets that are not part
+//! of the GDB protocol (and are not supported by `gdbstub`). LLDB is able to fall back to the GDB
+//! packets when these are unsupported, but ends up guessing the wrong architecture or OS for many
+//! targets. [LldbConnection] wraps the connection to the debugger, answers these queries directly,
+//! and forwards all other packets to the stub.
+//!
+//! Register descriptions are provided by the target description XML reported by the stub, which
+//! LLDB reads instead of querying `qRegisterInfo`.
+
+use std::{
+    collections::VecDeque,
+    sync::{
+        Arc,
+        atomic::{AtomicU8, Ordering},
+    },
+};
+
+use gdbstub::conn::{Connection, ConnectionExt};
+use icicle_vm::Vm;
+
+/// Information about the target reported to LLDB.
+#[derive(Clone, Debug)]
+pub struct TargetInfo {
+    pub triple: String,
+    pub ptr_size: u32,
+    pub big_endian: bool,
+    pub os_type: &'static str,
+    pub pid: u64,
+
+    /// The signal reported for the most recent stop of the target.
+    pub stop_signal: Arc<AtomicU8>,
+}
+
+impl TargetInfo {
+    pub fn new(vm: &Vm, stop_signal: Arc<AtomicU8>) -> Self {
+        let triple = &vm.cpu.arch.triple;
+        let os_type = match triple.operating_system {
+            target_lexicon::OperatingSystem::Linux => "linux",
+            target_lexicon::OperatingSystem::Windows => "windows",
+            _ => "unknown",
+        };
+        let pid = match vm.env_ref::<icicle_vm::linux::Kernel>() {
+            Some(kernel) => kernel.process.pid,
+            None => 1,
+        };
+        Self {
+            triple: triple.to_string(),
+            ptr_size: triple.pointer_width().map_or(8, |x| x.bytes() as u32),
+            big_endian: vm.cpu.arch.sleigh.big_endian,
+            os_type,
+            pid,
+            stop_signal,
+        }
+    }
+
+    fn endian(&self) -> &'static str {
+        if self.big_endian { "big" } else { "little" }
+    }
+
+    fn host_info(&self) -> String {
+        format!(
+            "triple:{};ptrsize:{};endian:{};ostype:{};hostname:{};",
+            hex_encode(self.triple.as_bytes()),
+            self.ptr_size,
+            self.endian(),
+            self.os_type,
+            hex_encode(b"icicle"),
+        )
+    }
+
+    fn process_info(&self) -> String {
+        format!(
+            "pid:{:x};parent-pid:{:x};real-uid:0;real-gid:0;effective-uid:0;effective-gid:0;\
+             triple:{};ostype:{};endian:{};ptrsize:{};",
+            self.pid,
+            self.pid,
+            hex_encode(self.triple.as_bytes()),
+            self.os_type,
+            self.endian(),
+            self.ptr_size,
+        )
+    }
+
+    fn threads_info(&self) -> String {
+        // The stub only supports a single thread, which is always stopped when LLDB sends a query.
+        let signal = self.stop_signal.load(Ordering::Acquire);
+        format!(r#"[{{"tid":{},"reason":"signal","signal":{signal}}}]"#, self.pid)
+    }
+
+    /// Returns the response to `packet` if it is an LLDB-specific query.
+    pub fn handle_packet(&self, packet: &[u8]) -> Option<String> {
+        match packet {
+            b"qHostInfo" => Some(self.host_info()),
+            b"qProcessInfo" => Some(self.process_info()),
+            b"jThreadsInfo" => Some(self.threads_info()),
+            _ => None,
+        }
+    }
+}
+
+fn hex_encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|x| format!("{x:02x}")).collect()
+}
+
+/// Encodes `payload` as a packet, escaping any characters that have a special meaning in the
+/// protocol.
+fn encode_packet(payload: &str) -> Vec<u8> {
+    let mut out = vec![b'$'];
+    for byte in payload.bytes() {
+        match byte {
+            b'#' | b'$' | b'}' | b'*' => out.extend_from_slice(&[b'}', byte ^ 0x20]),
+            _ => out.push(byte),
+        }
+    }
+    let checksum = out[1..].iter().fold(0_u8, |acc, x| acc.wrapping_add(*x));
+    out.extend_from_slice(format!("#{checksum:02x}").as_bytes());
+    out
+}
+
+/// A connection that answers LLDB-specific queries, forwarding everything else to the stub.
+pub struct LldbConnection<S> {
+    inner: S,
+    info: TargetInfo,
+
+    /// Bytes that have been read from the connection but not yet forwarded to the stub.
+    pending: VecDeque<u8>,
+
+    /// Whether the debugger has disabled acknowledgements.
+    no_ack: bool,
+}
+
+impl<S: ConnectionExt> LldbConnection<S> {
+    pub fn new(inner: S, info: TargetInfo) -> Self {
+        Self { inner, info, pending: VecDeque::new(), no_ack: false }
+    }
+
+    /// Reads the next packet (or single byte outside of a packet) from the connection. LLDB
+    /// queries are answered directly, everything else is added to the pending queue.
+    fn fill_pending(&mut self) -> Result<(), S::Error> {
+        let byte = self.inner.read()?;
+        if byte != b'$' {
+            self.pending.push_back(byte);
+            return Ok(());
+        }
+
+        let mut packet = vec![byte];
+        loop {
+            let byte = self.inner.read()?;
+            packet.push(byte);
+            if byte == b'#' {
+                break;
+            }
+        }
+        packet.push(self.inner.read()?);
+        packet.push(self.inner.read()?);
+
+        let payload = &packet[1..packet.len() - 3];
+        if payload == b"QStartNoAckMode" {
+            // The stub acknowledges this packet, after which neither side sends acks.
+            self.no_ack = true;
+        }
+
+        match self.info.handle_packet(payload) {
+            Some(response) => {
+                tracing::debug!("LLDB query: {}", String::from_utf8_lossy(payload));
+                if !self.no_ack {
+                    self.inner.write(b'+')?;
+                }
+                self.inner.write_all(&encode_packet(&response))?;
+                self.inner.flush()
+            }
+            None => {
+                self.pending.extend(packet);
+                Ok(())
+            }
+        }
+    }
+}
+
+impl<S: ConnectionExt> Connection for LldbConnection<S> {
+    type Error = S::Error;
+
+    fn write(&mut self, byte: u8) -> Result<(), Self::Error> {
+        self.inner.write(byte)
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> Result<(), Self::Error> {
+        self.inner.write_all(buf)
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        self.inner.flush()
+    }
+
+    fn on_session_start(&mut self) -> Result<(), Self::Error> {
+        self.inner.on_session_start()
+    }
+}
+
+impl<S: ConnectionExt> ConnectionExt for LldbConnection<S> {
+    fn read(&mut self) -> Result<u8, Self::Error> {
+        while self.pending.is_empty() {
+            self.fill_pending()?;
+        }
+        Ok(self.pending.pop_front().unwrap())
+    }
+
+    fn peek(&mut self) -> Result<Option<u8>, Self::Error> {
+        // Answer any LLDB queries that have already been received, so that the byte returned here
+        // is the same as the next byte returned by `read`.
+        while self.pending.is_empty() {
+            if self.inner.peek()?.is_none() {
+                return Ok(None);
+            }
+            self.fill_pending()?;
+        }
+        Ok(self.pending.front().copied())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// A connection that reads from a fixed input buffer and records all output.
+    struct MockConnection {
+        input: VecDeque<u8>,
+        output: Vec<u8>,
+    }
+
+    impl Connection for MockConnection {
+        type Error = &'static str;
+
+        fn write(&mut self, byte: u8) -> Result<(), Self::Error> {
+            self.output.push(byte);
+            Ok(())
+        }
+
+        fn flush(&mut self) -> Result<(), Self::Error> {
+            Ok(())
+        }
+    }
+
+    impl ConnectionExt for MockConnection {
+        fn read(&mut self) -> Result<u8, Self::Error> {
+            self.input.pop_front().ok_or("end of input")
+        }
+
+        fn peek(&mut self) -> Result<Option<u8>, Self::Error> {
+            Ok(self.input.front().copied())
+        }
+    }
+
+    fn connection(input: &[u8]) -> LldbConnection<MockConnection> {
+        let info = TargetInfo {
+            triple: "x86_64-unknown-linux".into(),
+            ptr_size: 8,
+            big_endian: false,
+            os_type: "linux",
+            pid: 1,
+            stop_signal: Arc::new(AtomicU8::new(5)),
+        };
+        let inner = MockConnection { input: input.iter().copied().collect(), output: vec![] };
+        LldbConnection::new(inner, info)
+    }
+
+    #[test]
+    fn peek_matches_read() {
+        let mut conn = connection(b"$jThreadsInfo#c1$g#67");
+
+        // The query is answered by the connection, so the next byte is the start of `g`.
+        assert_eq!(conn.peek(), Ok(Some(b'$')));
+        assert_eq!(conn.read(), Ok(b'$'));
+        assert_eq!(conn.read(), Ok(b'g'));
+        assert!(conn.inner.output.starts_with(br#"+$[{"tid":1,"#));
+
+        // Queries are answered even if no other data is available.
+        let mut conn = connection(b"$qHostInfo#9b");
+        assert_eq!(conn.peek(), Ok(None));
+        assert!(conn.inner.output.starts_with(b"+$triple:"));
+    }
+
+    #[test]
+    fn threads_info_reports_stop_signal() {
+        let conn = connection(b"");
+        conn.info.stop_signal.store(11, Ordering::Release);
+        let response = conn.info.handle_packet(b"jThreadsInfo").unwrap();
+        assert_eq!(response, r#"[{"tid":1,"reason":"signal","signal":11}]"#);
+    }
+
+    #[test]
+    fn packets_are_escaped() {
+        assert_eq!(encode_packet("a#b"), b"$a}\x03b#43");
+    }
+}
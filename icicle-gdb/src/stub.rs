@@ -2,7 +2,10 @@ use std::{
     collections::HashMap,
     io::Read,
     path::PathBuf,
-    sync::{Arc, atomic::AtomicBool},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicU8},
+    },
 };
 
 use gdbstub::{
@@ -80,6 +83,8 @@ pub struct VmState<'a, T: DynamicTarget> {
     wsl_remap: bool,
     watchpoints: Vec<WatchPoint>,
     single_stepping: Arc<AtomicBool>,
+    /// The signal reported for the most recent stop (see [VmState::stop_signal]).
+    stop_signal: Arc<AtomicU8>,
     open_files: HashMap<u32, FileSource>,
     next_fd: u32,
 }
@@ -103,6 +108,7 @@ impl<'a, T: DynamicTarget> VmState<'a, T> {
             exe_path: ExePath::Unknown,
             wsl_remap,
             single_stepping: Arc::new(AtomicBool::new(false)),
+            stop_signal: Arc::new(AtomicU8::new(Signal::SIGTRAP.0)),
             open_files: HashMap::new(),
             next_fd: 0,
         }
@@ -112,6 +118,49 @@ impl<'a, T: DynamicTarget> VmState<'a, T> {
         self.exe_path = path;
     }
 
+    pub fn vm(&self) -> &Vm {
+        self.vm
+    }
+
+    /// Gets a handle to the signal reported to the debugger for the most recent stop, updated
+    /// whenever the target stops.
+    pub fn stop_signal(&self) -> Arc<AtomicU8> {
+        self.stop_signal.clone()
+    }
+
+    /// Records the signal associated with `reason` as the reason for the most recent stop.
+    pub fn set_stop_reason(&self, reason: &SingleThreadStopReason<<T::Arch as Arch>::Usize>) {
+        let signal = match reason {
+            SingleThreadStopReason::Signal(signal) | SingleThreadStopReason::Terminated(signal) => {
+                *signal
+            }
+            _ => Signal::SIGTRAP,
+        };
+        self.stop_signal.store(signal.0, std::sync::atomic::Ordering::Release);
+    }
+
+    /// Runs the VM for at most `max_icount` instructions, returning `None` if the VM is still
+    /// running. This allows the stub to check for incoming packets (e.g. an interrupt request from
+    /// the debugger) while the target is running.
+    pub fn run_slice(
+        &mut self,
+        max_icount: u64,
+    ) -> Option<SingleThreadStopReason<<T::Arch as Arch>::Usize>> {
+        if !matches!(self.exec_mode, ExecMode::Continue) {
+            return Some(self.run());
+        }
+
+        let limit = self.vm.icount_limit;
+        self.vm.icount_limit = limit.min(self.vm.cpu.icount().saturating_add(max_icount));
+        let exit = self.vm.run();
+        self.vm.icount_limit = limit;
+
+        if exit == VmExit::InstructionLimit && self.vm.cpu.icount() < limit {
+            return None;
+        }
+        Some(self.stop_reason(exit))
+    }
+
     pub fn run(&mut self) -> SingleThreadStopReason<<T::Arch as Arch>::Usize> {
         let exit = match self.exec_mode {
             ExecMode::Continue => self.vm.run(),
@@ -137,10 +186,15 @@ impl<'a, T: DynamicTarget> VmState<'a, T> {
                 }
             }
         };
+        self.stop_reason(exit)
+    }
+
+    fn stop_reason(&mut self, exit: VmExit) -> SingleThreadStopReason<<T::Arch as Arch>::Usize> {
         tracing::debug!("VmExit: {exit:?} at pc={:#x}", self.vm.cpu.read_pc());
         self.single_stepping.store(true, std::sync::atomic::Ordering::Release);
         let result = translate_stop_reason(self.vm, exit);
         self.single_stepping.store(false, std::sync::atomic::Ordering::Release);
+        self.set_stop_reason(&result);
         result
     }
 }
@@ -189,6 +243,16 @@ impl<T: DynamicTarget> gdbstub::target::Target for VmState<'_, T> {
     fn support_host_io(&mut self) -> Option<ext::host_io::HostIoOps<'_, Self>> {
         Some(self)
     }
+
+    fn support_memory_map(&mut self) -> Option<ext::memory_map::MemoryMapOps<'_, Self>> {
+        Some(self)
+    }
+
+    fn use_no_ack_mode(&self) -> bool {
+        // The connection to the debugger is always reliable (TCP or a Unix socket), so there is no
+        // need to acknowledge every packet.
+        true
+    }
 }
 
 impl<T: DynamicTarget> SingleThreadBase for VmState<'_, T> {
@@ -517,9 +581,67 @@ impl<T: DynamicTarget> ext::monitor_cmd::MonitorCmd for VmState<'_, T> {
                 let backtrace = icicle_vm::debug::backtrace(self.vm);
                 out.write_raw(backtrace.as_bytes());
             }
+            Some("print-var") => {
+                let path = parts.collect::<Vec<_>>().join(" ");
+                if path.is_empty() {
+                    warn!("Expected variable name");
+                    return Ok(());
+                }
+                let result = icicle_vm::debug::read_variable(self.vm, &path).and_then(|var| {
+                    Ok(format!("{} = {}", var.name, var.display(&mut self.vm.cpu)?))
+                });
+                match result {
+                    Ok(value) => gdbstub::outputln!(out, "{value}"),
+                    Err(e) => gdbstub::outputln!(out, "{e}"),
+                }
+            }
             Some("icount") => {
                 gdbstub::outputln!(out, "icount = {}", self.vm.cpu.icount());
             }
+            Some("breakpoints") => {
+                for (addr, bp) in self.vm.breakpoints() {
+                    gdbstub::outputln!(
+                        out,
+                        "{addr:#x}: {} hits={} ignore={}{}",
+                        if bp.enabled { "enabled" } else { "disabled" },
+                        bp.hit_count,
+                        bp.ignore_count,
+                        if bp.temporary { " (temporary)" } else { "" }
+                    );
+                }
+            }
+            Some(cmd @ ("tbreak" | "bp-enable" | "bp-disable")) => {
+                let Some(addr) = parts.next()
+                else {
+                    warn!("Expected address");
+                    return Ok(());
+                };
+                let addr = icicle_vm::cpu::utils::parse_u64_with_prefix(addr)
+                    .ok_or_else(|| anyhow::format_err!("invalid address: {addr}"))?;
+                let found = match cmd {
+                    "tbreak" => {
+                        self.vm.add_temporary_breakpoint(addr);
+                        true
+                    }
+                    _ => self.vm.enable_breakpoint(addr, cmd == "bp-enable"),
+                };
+                if !found {
+                    gdbstub::outputln!(out, "no breakpoint at {addr:#x}");
+                }
+            }
+            Some("bp-ignore") => {
+                let (Some(addr), Some(count)) = (parts.next(), parts.next())
+                else {
+                    warn!("Expected address and count");
+                    return Ok(());
+                };
+                let addr = icicle_vm::cpu::utils::parse_u64_with_prefix(addr)
+                    .ok_or_else(|| anyhow::format_err!("invalid address: {addr}"))?;
+                let count = count.parse().map_err(|e| anyhow::format_err!("{}", e))?;
+                if !self.vm.set_breakpoint_ignore_count(addr, count) {
+                    gdbstub::outputln!(out, "no breakpoint at {addr:#x}");
+                }
+            }
             Some("memory-map") => {
                 gdbstub::outputln!(out, "{:#x?}", self.vm.cpu.mem.get_mapping());
             }
@@ -630,6 +752,51 @@ impl<T: DynamicTarget> ext::exec_file::ExecFile for VmState<'_, T> {
     }
 }
 
+impl<T: DynamicTarget> ext::memory_map::MemoryMap for VmState<'_, T> {
+    fn memory_map_xml(
+        &self,
+        offset: u64,
+        length: usize,
+        buf: &mut [u8],
+    ) -> TargetResult<usize, Self> {
+        let xml = memory_map_xml(&self.vm.cpu.mem.regions());
+        let bytes = xml.as_bytes();
+        let len = length.min(bytes.len().saturating_sub(offset as usize)).min(buf.len());
+        if len == 0 {
+            return Ok(0);
+        }
+        buf[..len].copy_from_slice(&bytes[offset as usize..offset as usize + len]);
+
+        Ok(len)
+    }
+}
+
+/// Generates the XML memory map reported to the debugger. IO regions are omitted so the debugger
+/// does not attempt to read them.
+///
+/// Note: all other regions are reported as RAM (even if they are not writable by the guest), since
+/// the stub ignores permissions when writing and GDB refuses to insert software breakpoints in ROM.
+fn memory_map_xml(regions: &[icicle_vm::cpu::mem::MemoryRegion]) -> String {
+    use std::fmt::Write;
+
+    let mut xml = String::from(concat!(
+        "<?xml version=\"1.0\"?>\n",
+        "<!DOCTYPE memory-map PUBLIC \"+//IDN gnu.org//DTD GDB Memory Map V1.0//EN\" ",
+        "\"http://sourceware.org/gdb/gdb-memory-map.dtd\">\n",
+        "<memory-map>\n",
+    ));
+    for region in regions.iter().filter(|x| !x.is_io) {
+        let _ = writeln!(
+            xml,
+            r#"<memory type="ram" start="{:#x}" length="{:#x}"/>"#,
+            region.start,
+            region.size()
+        );
+    }
+    xml.push_str("</memory-map>\n");
+    xml
+}
+
 fn remap_windows_path_to_wsl(path: PathBuf) -> PathBuf {
     #[cfg(target_os = "windows")]
     {
@@ -701,17 +868,16 @@ where
         VmExit::Running | VmExit::InstructionLimit => SingleThreadStopReason::DoneStep,
         VmExit::Halt => {
             // @fixme get last status code
-            match vm
-                .env_ref::<icicle_vm::linux::Kernel>()
-                .and_then(|k| k.process.termination_reason)
-            {
+            let kernel = vm.env_ref::<icicle_vm::linux::Kernel>();
+            match kernel.and_then(|k| k.process.termination_reason) {
                 Some(TerminationReason::Exit(exit)) => SingleThreadStopReason::Exited(exit as u8),
                 Some(TerminationReason::Killed(sig)) => {
+                    let sigchld = kernel.map(|k| k.arch.signals.sigchld);
                     let signal = match sig as u8 {
                         icicle_vm::linux::sys::signal::SIGKILL => Signal::SIGKILL,
                         icicle_vm::linux::sys::signal::SIGSEGV => Signal::SIGSEGV,
                         icicle_vm::linux::sys::signal::SIGALRM => Signal::SIGALRM,
-                        icicle_vm::linux::sys::signal::SIGCHLD => Signal::SIGCHLD,
+                        sig if Some(sig) == sigchld => Signal::SIGCHLD,
                         _ => Signal::UNKNOWN,
                     };
                     SingleThreadStopReason::Terminated(signal)
@@ -759,14 +925,20 @@ where
                 addr,
             }
         }
-        VmExit::UnhandledException((code, addr)) if code.is_memory_error() => {
-            warn!("Unhandled exception: {code:?}, addr={addr:#0x}");
-            SingleThreadStopReason::Signal(Signal::SIGSEGV)
-        }
         VmExit::UnhandledException((code, addr)) => {
             warn!("Unhandled exception: ({code:?}, {addr:#0x})");
-            SingleThreadStopReason::Signal(Signal::SIGILL)
+            SingleThreadStopReason::Signal(exception_to_signal(code))
+        }
+        VmExit::Interrupted => SingleThreadStopReason::Signal(Signal::SIGINT),
+        VmExit::LoopLimit(header) => {
+            warn!("Loop iteration limit exceeded: header={header:#0x}");
+            SingleThreadStopReason::Signal(Signal::SIGALRM)
         }
+        VmExit::Unreachable(addr) => {
+            warn!("Unreachable code reached: addr={addr:#0x}");
+            SingleThreadStopReason::Signal(Signal::SIGABRT)
+        }
+        VmExit::Killed => SingleThreadStopReason::Terminated(Signal::SIGKILL),
         other => {
             warn!("Unknown error: {:?}", other);
             SingleThreadStopReason::Signal(Signal::SIGILL)
@@ -774,6 +946,20 @@ where
     }
 }
 
+/// Gets the signal reported to the debugger when the VM stops due to an exception with `code`.
+pub fn exception_to_signal(code: ExceptionCode) -> Signal {
+    match code {
+        ExceptionCode::SoftwareBreakpoint => Signal::SIGTRAP,
+        ExceptionCode::DivisionException => Signal::SIGFPE,
+        ExceptionCode::SanitizerError | ExceptionCode::StackBufferOverflow => Signal::SIGABRT,
+        ExceptionCode::ReadUnaligned
+        | ExceptionCode::WriteUnaligned
+        | ExceptionCode::ExecUnaligned => Signal::SIGBUS,
+        code if code.is_memory_error() => Signal::SIGSEGV,
+        _ => Signal::SIGILL,
+    }
+}
+
 // @fixme: these are not actually generated by the VM.
 #[repr(u64)]
 pub enum EnvironmentCode {
@@ -92,6 +92,9 @@ pub struct JIT {
     /// Cached JIT functions indexed by entrypoint.
     pub entry_points: HashMap<u64, JitFunction>,
 
+    /// The size (in bytes) of the host code for the function compiled for each entrypoint.
+    code_sizes: HashMap<u64, u32>,
+
     /// The JIT functions for each address that are currently active.
     active: Box<[(u64, JitFunction); FAST_LOOKUP_TABLE_SIZE]>,
 
@@ -148,6 +151,7 @@ impl JIT {
                 .unwrap(),
             compiled: vec![],
             entry_points: HashMap::new(),
+            code_sizes: HashMap::new(),
             block_mapping: HashMap::new(),
             dead: 0,
             declared_functions: vec![],
@@ -161,6 +165,7 @@ impl JIT {
         self.active.fill(INITIAL_LOOKUP_TABLE_VALUE);
         self.compiled.clear();
         self.entry_points.clear();
+        self.code_sizes.clear();
         self.block_mapping.clear();
         self.dead = 0;
         self.declared_functions.clear();
@@ -193,7 +198,7 @@ impl JIT {
 
     /// Invalidates any generated code that references the specified block.
     pub fn invalidate(&mut self, block_id: usize) {
-        if let Some(&id) = self.block_mapping.get(&block_id) {
+        if let Some(id) = self.block_mapping.remove(&block_id) {
             for &addr in &self.compiled[id] {
                 self.active[Self::lookup_key(addr)] = INITIAL_LOOKUP_TABLE_VALUE;
                 self.entry_points.remove(&addr);
@@ -202,6 +207,17 @@ impl JIT {
         }
     }
 
+    /// Returns the size (in bytes) of the host code generated for the function containing the
+    /// entrypoint at `addr`.
+    ///
+    /// Note: after recompilation, multiple entrypoints may share the same function.
+    pub fn code_size(&self, addr: u64) -> Option<u32> {
+        if !self.entry_points.contains_key(&addr) {
+            return None;
+        }
+        self.code_sizes.get(&addr).copied()
+    }
+
     #[inline(always)]
     fn lookup_key(addr: u64) -> usize {
         addr as usize % FAST_LOOKUP_TABLE_SIZE
@@ -226,7 +242,7 @@ impl JIT {
     }
 
     pub fn compile(&mut self, target: &CompilationTarget) -> ModuleResult<()> {
-        let (func, _size) = self.translate_and_define(target, false)?;
+        let (func, size) = self.translate_and_define(target, false)?;
         self.module.finalize_definitions()?;
 
         for addr in target.entry_points() {
@@ -234,8 +250,12 @@ impl JIT {
             if self.entry_points.insert(addr, jit_fn).is_some() {
                 self.dead += 1;
             }
+            self.code_sizes.insert(addr, size);
             self.active[Self::lookup_key(addr)] = (addr, jit_fn);
         }
+        for &block_id in target.targets {
+            self.block_mapping.insert(block_id, self.compiled.len());
+        }
         self.compiled.push(target.entry_points().collect());
 
         Ok(())
@@ -3,7 +3,7 @@ use std::{
     io::{BufWriter, Write},
 };
 
-const ARCHITECTURES: &[&str] = &["generic", "x64", "i386", "mips", "arm"];
+const ARCHITECTURES: &[&str] = &["generic", "riscv32", "x64", "i386", "mips", "arm"];
 
 fn main() {
     process_errno_table();
@@ -153,7 +153,7 @@ impl ArchSyscall for Mips32 {
     fn setup_signal_frame<C: LinuxCpu>(
         &self,
         cpu: &mut C,
-        signal: u64,
+        frame: &types::SignalFrame,
         sigaction: &types::Sigaction,
     ) -> MemResult<()> {
         const FRAME_SIZE: u64 = 0x100;
@@ -161,20 +161,21 @@ impl ArchSyscall for Mips32 {
         let prev_sp = cpu.read_var(self.regs[reg::sp]);
         let sp = prev_sp - FRAME_SIZE - 32;
 
-        let pc = cpu.read_var(self.regs[reg::pc]);
-
-        self.write_u32(cpu.mem(), sp, pc as u32 + 4)?;
+        self.write_u32(cpu.mem(), sp, frame.resume_addr as u32)?;
         for (i, reg) in self.regs.iter().enumerate() {
             let addr = sp + ((i + 1) as u64 * 4);
             let value = cpu.read_var(*reg) as u32;
             self.write_u32(cpu.mem(), addr, value)?;
         }
+        let mask_addr = sp + (self.regs.len() + 1) as u64 * 4;
+        self.write_u32(cpu.mem(), mask_addr, frame.saved_mask as u32)?;
+        self.write_u32(cpu.mem(), mask_addr + 4, (frame.saved_mask >> 32) as u32)?;
 
         // @fixme: create the proper signal frame structure, instead of just saving registers.
 
         cpu.write_var(self.regs[reg::sp], sp);
 
-        cpu.write_var(self.regs[reg::a0], signal);
+        cpu.write_var(self.regs[reg::a0], frame.signal);
         cpu.write_var(self.regs[reg::a1], 0); // @fixme: set correct arguments.
         cpu.write_var(self.regs[reg::a2], 0); // @fixme: set correct arguments.
 
@@ -188,7 +189,7 @@ impl ArchSyscall for Mips32 {
         Ok(())
     }
 
-    fn restore_signal_frame<C: LinuxCpu>(&self, cpu: &mut C) -> MemResult<()> {
+    fn restore_signal_frame<C: LinuxCpu>(&self, cpu: &mut C) -> MemResult<u64> {
         let sp = cpu.read_var(self.regs[reg::sp]);
 
         let next_pc = self.read_u32(cpu.mem(), sp)? as u64;
@@ -198,11 +199,12 @@ impl ArchSyscall for Mips32 {
             cpu.write_var(*reg, value);
         }
         cpu.write_var(self.regs[reg::pc], next_pc);
+        cpu.set_next_pc(next_pc);
 
-        // @fixme: this should be set as part of other signal handling code.
-        self.set_error(cpu, crate::errno::EINTR);
-
-        Ok(())
+        let mask_addr = sp + (self.regs.len() + 1) as u64 * 4;
+        let mask_lo = self.read_u32(cpu.mem(), mask_addr)? as u64;
+        let mask_hi = self.read_u32(cpu.mem(), mask_addr + 4)? as u64;
+        Ok(mask_lo | (mask_hi << 32))
     }
 }
 
@@ -2,10 +2,11 @@
 
 mod aarch64;
 mod mips;
-mod riscv64;
+mod riscv;
 pub mod x86;
 
 use icicle_cpu::{
+    VmExit,
     mem::{MemError, MemResult},
     utils::align_up,
 };
@@ -16,7 +17,7 @@ use crate::{LinuxCpu, LinuxError, LinuxMmu, LinuxResult, types};
 pub enum Dynamic {
     Aarch64(aarch64::Aarch64),
     Mips32(mips::Mips32),
-    Riscv64(riscv64::Riscv64),
+    Riscv(riscv::Riscv),
     X64(x86::x64::X64),
     I386(x86::i386::I386),
 }
@@ -26,7 +27,7 @@ macro_rules! dispatch {
         match $this {
             Dynamic::Aarch64($ident) => $expr,
             Dynamic::Mips32($ident) => $expr,
-            Dynamic::Riscv64($ident) => $expr,
+            Dynamic::Riscv($ident) => $expr,
             Dynamic::X64($ident) => $expr,
             Dynamic::I386($ident) => $expr,
         }
@@ -60,15 +61,17 @@ pub trait ArchSyscall {
     fn setup_signal_frame<C: LinuxCpu>(
         &self,
         _cpu: &mut C,
-        _signal: u64,
+        _frame: &types::SignalFrame,
         _sigaction: &types::Sigaction,
     ) -> MemResult<()> {
         tracing::error!("Signals are not supported on the current architecture");
         Err(MemError::Unknown)
     }
 
-    fn restore_signal_frame<C: LinuxCpu>(&self, _cpu: &mut C) -> MemResult<()> {
-        Ok(())
+    /// Restores the context saved by `setup_signal_frame` (i.e. the behaviour of `rt_sigreturn`),
+    /// returning the saved signal mask.
+    fn restore_signal_frame<C: LinuxCpu>(&self, _cpu: &mut C) -> MemResult<u64> {
+        Ok(0)
     }
 }
 
@@ -92,6 +95,17 @@ impl Dynamic {
         dispatch!(self, inner, inner.set_error(cpu, err))
     }
 
+    /// Writes the result of a syscall to the cpu using the convention of the current architecture,
+    /// returning the exit if `result` requires the VM to exit.
+    pub fn set_return<C: LinuxCpu>(&self, cpu: &mut C, result: LinuxResult) -> Option<VmExit> {
+        match result {
+            Ok(value) => self.set_result(cpu, value),
+            Err(LinuxError::Error(error)) => self.set_error(cpu, error),
+            Err(LinuxError::VmExit(exit)) => return Some(exit),
+        }
+        None
+    }
+
     pub fn init_vdso<C: LinuxCpu>(&mut self, cpu: &mut C) -> MemResult<()> {
         dispatch!(self, inner, inner.init_vdso(cpu))
     }
@@ -100,13 +114,13 @@ impl Dynamic {
     pub fn setup_signal_frame<C: LinuxCpu>(
         &self,
         cpu: &mut C,
-        signal: u64,
+        frame: &types::SignalFrame,
         sigaction: &types::Sigaction,
     ) -> MemResult<()> {
-        dispatch!(self, inner, inner.setup_signal_frame(cpu, signal, sigaction))
+        dispatch!(self, inner, inner.setup_signal_frame(cpu, frame, sigaction))
     }
 
-    pub fn restore_signal_frame<C: LinuxCpu>(&self, cpu: &mut C) -> MemResult<()> {
+    pub fn restore_signal_frame<C: LinuxCpu>(&self, cpu: &mut C) -> MemResult<u64> {
         dispatch!(self, inner, inner.restore_signal_frame(cpu))
     }
 
@@ -114,7 +128,8 @@ impl Dynamic {
         match self {
             Dynamic::Aarch64(_) => &aarch64::SYSCALL_NAMES[..],
             Dynamic::Mips32(_) => &mips::SYSCALL_NAMES[..],
-            Dynamic::Riscv64(_) => &riscv64::SYSCALL_NAMES[..],
+            Dynamic::Riscv(x) if x.rv32 => &riscv::SYSCALL_NAMES_32[..],
+            Dynamic::Riscv(_) => &riscv::SYSCALL_NAMES[..],
             Dynamic::X64(_) => &x86::x64::SYSCALL_NAMES[..],
             Dynamic::I386(_) => &x86::i386::SYSCALL_NAMES[..],
         }
@@ -124,7 +139,8 @@ impl Dynamic {
         match self {
             Dynamic::Aarch64(_) => &aarch64::SYSCALL_MAPPING[..],
             Dynamic::Mips32(_) => &mips::SYSCALL_MAPPING[..],
-            Dynamic::Riscv64(_) => &riscv64::SYSCALL_MAPPING[..],
+            Dynamic::Riscv(x) if x.rv32 => &riscv::SYSCALL_MAPPING_32[..],
+            Dynamic::Riscv(_) => &riscv::SYSCALL_MAPPING[..],
             Dynamic::X64(_) => &x86::x64::SYSCALL_MAPPING[..],
             Dynamic::I386(_) => &x86::i386::SYSCALL_MAPPING[..],
         }
@@ -164,6 +180,13 @@ pub struct KernelArch {
     /// The varnode that contains the stack pointer.
     pub reg_sp: pcode::VarNode,
 
+    /// The varnode that contains the thread pointer (updated by `clone` with `CLONE_SETTLS`), or
+    /// `None` if the thread pointer is not stored in a register.
+    pub reg_tls: Option<pcode::VarNode>,
+
+    /// The numbers of signals that differ between architectures.
+    pub signals: crate::sys::signal::Signals,
+
     /// Dynamic information about the current architecture.
     pub dynamic: Dynamic,
 }
@@ -177,19 +200,32 @@ impl KernelArch {
             Architecture::Aarch64(Aarch64Architecture::Aarch64) => {
                 Dynamic::Aarch64(aarch64::Aarch64::new(arch))
             }
-            Architecture::Riscv64(_) => Dynamic::Riscv64(riscv64::Riscv64::new(arch)),
+            Architecture::Riscv32(_) | Architecture::Riscv64(_) => {
+                Dynamic::Riscv(riscv::Riscv::new(arch))
+            }
             unknown => unimplemented!("unsupported Linux architecture: {}", unknown),
         };
 
         let mut platform_name = arch.triple.to_string().into_bytes();
         platform_name.push(0);
 
+        let reg_tls = match arch.triple.architecture {
+            Architecture::X86_64 => arch.sleigh.get_varnode("FS_OFFSET"),
+            Architecture::Mips32(_) => arch.sleigh.get_varnode("HW_ULR"),
+            Architecture::Aarch64(_) => arch.sleigh.get_varnode("tpidr_el0"),
+            Architecture::Riscv32(_) | Architecture::Riscv64(_) => arch.sleigh.get_varnode("tp"),
+            // On i386 the thread pointer is set using a segment descriptor.
+            _ => None,
+        };
+
         Self {
             triple: arch.triple.clone(),
             endianness: arch.triple.endianness().unwrap(),
             platform_name,
             reg_pc: arch.reg_pc,
             reg_sp: arch.reg_sp,
+            reg_tls,
+            signals: crate::sys::signal::Signals::for_arch(arch.triple.architecture),
             dynamic,
         }
     }
@@ -1,19 +1,24 @@
 use crate::{arch::ArchSyscall, LinuxCpu, LinuxResult};
 
+/// The syscall ABI used by both RV32 and RV64 targets.
 #[derive(Clone)]
-pub struct Riscv64 {
+pub struct Riscv {
     args: [pcode::VarNode; 7],
+
+    /// Whether the target is RV32, which uses a different syscall table.
+    pub rv32: bool,
 }
 
-impl Riscv64 {
+impl Riscv {
     pub fn new(arch: &icicle_cpu::Arch) -> Self {
         let r = |name: &str| arch.sleigh.get_varnode(name).unwrap();
         let args = [r("a7"), r("a0"), r("a1"), r("a2"), r("a3"), r("a4"), r("a5")];
-        Self { args }
+        let rv32 = matches!(arch.triple.architecture, target_lexicon::Architecture::Riscv32(_));
+        Self { args, rv32 }
     }
 }
 
-impl ArchSyscall for Riscv64 {
+impl ArchSyscall for Riscv {
     fn get_arg<C: LinuxCpu>(&self, cpu: &mut C, n: usize) -> LinuxResult {
         Ok(cpu.read_var(self.args[n]))
     }
@@ -32,3 +37,9 @@ pub static SYSCALL_MAPPING: [usize; 600] =
 
 pub static SYSCALL_NAMES: [&str; 600] =
     include!(concat!(env!("OUT_DIR"), "/generic_syscall_names.rs"));
+
+pub static SYSCALL_MAPPING_32: [usize; 600] =
+    include!(concat!(env!("OUT_DIR"), "/riscv32_syscall_mapping.rs"));
+
+pub static SYSCALL_NAMES_32: [&str; 600] =
+    include!(concat!(env!("OUT_DIR"), "/riscv32_syscall_names.rs"));
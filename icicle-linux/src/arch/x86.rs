@@ -1,4 +1,6 @@
-use crate::{arch::ArchSyscall, LinuxCpu, LinuxResult};
+use icicle_cpu::mem::{MemError, MemResult};
+
+use crate::{arch::ArchSyscall, types, LinuxCpu, LinuxMmu, LinuxResult};
 
 #[derive(Debug)]
 pub struct GDTEntry {
@@ -29,6 +31,46 @@ impl GDTEntry {
 pub mod x64 {
     use super::*;
 
+    /// The registers stored in `gregs` of `mcontext_t` (in order), excluding `RIP` and `EFLAGS`.
+    const GREGS: [&str; 16] = [
+        "R8", "R9", "R10", "R11", "R12", "R13", "R14", "R15", "RDI", "RSI", "RBP", "RBX", "RDX",
+        "RAX", "RCX", "RSP",
+    ];
+    const REG_RSP: usize = 15;
+    const REG_RIP: usize = 16;
+    const REG_EFL: usize = 17;
+    const REG_CSGSFS: usize = 18;
+    const REG_CR2: usize = 22;
+
+    /// The bits of `EFLAGS` that are stored in separate registers.
+    const EFLAGS: [(&str, u32); 9] = [
+        ("CF", 0),
+        ("PF", 2),
+        ("AF", 4),
+        ("ZF", 6),
+        ("SF", 7),
+        ("TF", 8),
+        ("IF", 9),
+        ("DF", 10),
+        ("OF", 11),
+    ];
+
+    /// Signal handlers must not clobber the red zone below the stack pointer.
+    const RED_ZONE: u64 = 128;
+
+    /// Layout of `struct rt_sigframe`: the return address of the handler followed by a
+    /// `ucontext_t` and `siginfo_t`.
+    const UCONTEXT_OFFSET: usize = 8;
+    const MCONTEXT_OFFSET: usize = UCONTEXT_OFFSET + 40;
+    const SIGMASK_OFFSET: usize = MCONTEXT_OFFSET + 256;
+    const SIGINFO_OFFSET: usize = SIGMASK_OFFSET + 8;
+    const FRAME_SIZE: usize = SIGINFO_OFFSET + 128;
+
+    const SA_RESTORER: u64 = 0x04000000;
+
+    /// The code segment selector used for 64-bit user-mode code.
+    const USER_CS: u64 = 0x33;
+
     #[derive(Clone)]
     pub struct X64 {
         rax: pcode::VarNode,
@@ -38,6 +80,8 @@ pub mod x64 {
         r10: pcode::VarNode,
         r8: pcode::VarNode,
         r9: pcode::VarNode,
+        gregs: [pcode::VarNode; 16],
+        eflags: [(pcode::VarNode, u32); 9],
     }
 
     impl X64 {
@@ -51,6 +95,19 @@ pub mod x64 {
                 r10: r("R10"),
                 r8: r("R8"),
                 r9: r("R9"),
+                gregs: GREGS.map(r),
+                eflags: EFLAGS.map(|(name, bit)| (r(name), bit)),
+            }
+        }
+
+        fn read_eflags<C: LinuxCpu>(&self, cpu: &mut C) -> u64 {
+            // Bit 1 of EFLAGS is reserved and always set.
+            self.eflags.iter().fold(0x2, |acc, (var, bit)| acc | (cpu.read_var(*var) & 1) << bit)
+        }
+
+        fn write_eflags<C: LinuxCpu>(&self, cpu: &mut C, value: u64) {
+            for (var, bit) in self.eflags {
+                cpu.write_var(var, (value >> bit) & 1);
             }
         }
     }
@@ -76,6 +133,76 @@ pub mod x64 {
         fn set_error<C: LinuxCpu>(&self, cpu: &mut C, err: u64) {
             cpu.write_var(self.rax, (-(err as i64)) as u64);
         }
+
+        fn setup_signal_frame<C: LinuxCpu>(
+            &self,
+            cpu: &mut C,
+            frame: &types::SignalFrame,
+            sigaction: &types::Sigaction,
+        ) -> MemResult<()> {
+            // The kernel requires a restorer (normally `__restore_rt` in libc) to return from
+            // the handler.
+            if sigaction.flags.value & SA_RESTORER == 0 {
+                tracing::warn!("signal handler for {} has no restorer", frame.signal);
+                return Err(MemError::Unknown);
+            }
+
+            let rsp = cpu.read_var(self.gregs[REG_RSP]);
+            let frame_addr = ((rsp - RED_ZONE - FRAME_SIZE as u64) & !0xf) - 8;
+
+            let mut buf = [0; FRAME_SIZE];
+            write_u64(&mut buf, 0, sigaction.restorer.value);
+
+            let greg = |i: usize| MCONTEXT_OFFSET + i * 8;
+            for (i, var) in self.gregs.iter().enumerate() {
+                write_u64(&mut buf, greg(i), cpu.read_var(*var));
+            }
+            write_u64(&mut buf, greg(REG_RIP), frame.resume_addr);
+            write_u64(&mut buf, greg(REG_EFL), self.read_eflags(cpu));
+            write_u64(&mut buf, greg(REG_CSGSFS), USER_CS);
+            write_u64(&mut buf, greg(REG_CR2), frame.addr);
+            write_u64(&mut buf, SIGMASK_OFFSET, frame.saved_mask);
+
+            let siginfo = &mut buf[SIGINFO_OFFSET..];
+            siginfo[0..4].copy_from_slice(&(frame.signal as u32).to_le_bytes());
+            siginfo[8..12].copy_from_slice(&frame.code.to_le_bytes());
+            write_u64(siginfo, 16, frame.addr);
+
+            cpu.mem().write_bytes(frame_addr, &buf)?;
+
+            cpu.write_var(self.gregs[REG_RSP], frame_addr);
+            cpu.write_var(self.rdi, frame.signal);
+            cpu.write_var(self.rsi, frame_addr + SIGINFO_OFFSET as u64);
+            cpu.write_var(self.rdx, frame_addr + UCONTEXT_OFFSET as u64);
+            cpu.write_var(self.rax, 0);
+
+            Ok(())
+        }
+
+        fn restore_signal_frame<C: LinuxCpu>(&self, cpu: &mut C) -> MemResult<u64> {
+            // The return address of the handler was popped by `ret`.
+            let frame_addr = cpu.read_var(self.gregs[REG_RSP]) - 8;
+
+            let mut buf = [0; FRAME_SIZE];
+            cpu.mem().read_bytes(frame_addr, &mut buf)?;
+
+            let greg = |i: usize| read_u64(&buf, MCONTEXT_OFFSET + i * 8);
+            for (i, var) in self.gregs.iter().enumerate() {
+                cpu.write_var(*var, greg(i));
+            }
+            self.write_eflags(cpu, greg(REG_EFL));
+            cpu.set_next_pc(greg(REG_RIP));
+
+            Ok(read_u64(&buf, SIGMASK_OFFSET))
+        }
+    }
+
+    fn read_u64(buf: &[u8], offset: usize) -> u64 {
+        u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap())
+    }
+
+    fn write_u64(buf: &mut [u8], offset: usize, value: u64) {
+        buf[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
     }
 
     pub static SYSCALL_MAPPING: [usize; 600] =
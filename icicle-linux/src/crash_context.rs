@@ -0,0 +1,182 @@
+//! Captures the state of the emulated environment when a process crashes.
+//!
+//! Crashes in code that handles files or sockets are difficult to triage from the register state
+//! alone, so each process keeps a record of the last few syscalls it executed. Together with the
+//! open file table and the working directory, this is included in crash reports (see
+//! [Kernel::crash_context]).
+
+use std::collections::VecDeque;
+
+use bstr::ByteSlice;
+
+use crate::{sys::syscall::FmtResult, Kernel, LinuxResult};
+
+/// The maximum number of syscall arguments kept for each syscall in the history.
+const MAX_ARGS: usize = 6;
+
+#[derive(Clone, Copy)]
+pub struct SyscallRecord {
+    /// The name of the syscall.
+    pub name: &'static str,
+
+    /// The instruction count when the syscall was executed.
+    pub i_count: u64,
+
+    args: [u64; MAX_ARGS],
+    num_args: u8,
+
+    /// The value returned by the syscall, or `None` if the syscall has not returned to the
+    /// process yet (e.g. the process is blocked, or crashed inside of the syscall).
+    pub result: Option<LinuxResult>,
+}
+
+impl SyscallRecord {
+    /// The arguments the syscall was called with.
+    pub fn args(&self) -> &[u64] {
+        &self.args[..self.num_args as usize]
+    }
+}
+
+impl std::fmt::Display for SyscallRecord {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[{}] {}({:0x?}) = ", self.i_count, self.name, self.args())?;
+        match self.result {
+            Some(result) => write!(f, "{}", FmtResult(result)),
+            None => write!(f, "?"),
+        }
+    }
+}
+
+/// The most recent syscalls executed by a process.
+#[derive(Clone, Default)]
+pub struct SyscallHistory {
+    records: VecDeque<SyscallRecord>,
+
+    /// The maximum number of syscalls to keep, disabled if zero.
+    pub capacity: usize,
+}
+
+impl SyscallHistory {
+    pub fn new(capacity: usize) -> Self {
+        Self { records: VecDeque::with_capacity(capacity), capacity }
+    }
+
+    /// Records the start of a syscall. The result is set by [SyscallHistory::set_result] once the
+    /// syscall returns.
+    pub fn push(&mut self, name: &'static str, args: &[u64], i_count: u64) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.records.len() >= self.capacity {
+            self.records.pop_front();
+        }
+
+        let num_args = args.len().min(MAX_ARGS);
+        let mut record = SyscallRecord {
+            name,
+            i_count,
+            args: [0; MAX_ARGS],
+            num_args: num_args as u8,
+            result: None,
+        };
+        record.args[..num_args].copy_from_slice(&args[..num_args]);
+        self.records.push_back(record);
+    }
+
+    /// Sets the result of the most recent syscall.
+    pub fn set_result(&mut self, result: LinuxResult) {
+        if let Some(record) = self.records.back_mut() {
+            record.result = Some(result);
+        }
+    }
+
+    /// Returns the syscalls in the history, from oldest to newest.
+    pub fn iter(&self) -> impl Iterator<Item = &SyscallRecord> {
+        self.records.iter()
+    }
+
+    pub fn clear(&mut self) {
+        self.records.clear();
+    }
+}
+
+#[derive(Clone)]
+pub struct OpenFile {
+    pub fd: u64,
+
+    /// The path used to open the file (empty for anonymous files, e.g. sockets and pipes).
+    pub path: Vec<u8>,
+
+    /// The current offset within the file.
+    pub pos: usize,
+}
+
+/// A summary of the environment of a process at the point it crashed.
+#[derive(Clone)]
+pub struct CrashContext {
+    pub pid: u64,
+
+    /// The current working directory of the process.
+    pub cwd: Vec<u8>,
+
+    /// The file descriptors that were open in the process.
+    pub files: Vec<OpenFile>,
+
+    /// The most recent syscalls executed by the process, from oldest to newest.
+    pub syscalls: Vec<SyscallRecord>,
+}
+
+impl std::fmt::Display for CrashContext {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "pid: {}", self.pid)?;
+        writeln!(f, "cwd: {}", self.cwd.as_bstr())?;
+
+        writeln!(f, "open files:")?;
+        for file in &self.files {
+            let path = match file.path.is_empty() {
+                true => "<anonymous>".into(),
+                false => file.path.as_bstr().to_string(),
+            };
+            writeln!(f, "  {}: {path} (pos={:#x})", file.fd, file.pos)?;
+        }
+
+        write!(f, "recent syscalls:")?;
+        for syscall in &self.syscalls {
+            write!(f, "\n  {syscall}")?;
+        }
+        Ok(())
+    }
+}
+
+impl Kernel {
+    /// Captures the environment of the active process, for inclusion in crash reports.
+    pub fn crash_context(&self) -> CrashContext {
+        let mut cwd = vec![];
+        if let Some(dir) = &self.process.working_dir {
+            self.vfs.path_to_root(&dir.borrow(), &mut cwd);
+        }
+        if cwd.is_empty() {
+            cwd.push(b'/');
+        }
+
+        let files = self
+            .process
+            .file_table
+            .borrow()
+            .files
+            .iter()
+            .enumerate()
+            .filter_map(|(fd, file)| {
+                let file = file.as_ref()?.borrow();
+                Some(OpenFile { fd: fd as u64, path: file.path.clone(), pos: file.pos })
+            })
+            .collect();
+
+        CrashContext {
+            pid: self.process.pid,
+            cwd,
+            files,
+            syscalls: self.process.syscall_history.iter().copied().collect(),
+        }
+    }
+}
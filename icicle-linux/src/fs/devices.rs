@@ -2,7 +2,7 @@
 
 use std::rc::Rc;
 
-use icicle_cpu::utils::XorShiftRng;
+use icicle_cpu::utils::Drbg;
 
 use crate::{errno, sys};
 
@@ -367,12 +367,12 @@ impl Device for ErrorDevice {
 
 /// A device that behaves like `/dev/urandom`.
 pub struct RandomDevice {
-    rng: XorShiftRng,
+    rng: Drbg,
 }
 
 impl RandomDevice {
-    pub fn new(seed: u64) -> Self {
-        Self { rng: XorShiftRng::new(seed) }
+    pub fn new(rng: Drbg) -> Self {
+        Self { rng }
     }
 }
 
@@ -10,13 +10,21 @@ use super::{InodeRef, Path, Result};
 pub type FileDescriptor = u64;
 pub type ActiveFile = Rc<RefCell<ActiveFileData>>;
 
-#[derive(Default)]
 pub struct FileTable {
     /// The list of open files, index by file descriptor.
     pub files: Vec<Option<ActiveFile>>,
 
     /// List of free file descriptors.
     pub free_files: BTreeSet<FileDescriptor>,
+
+    /// The maximum number of file descriptors that can be allocated (i.e. `RLIMIT_NOFILE`).
+    pub max_files: u64,
+}
+
+impl Default for FileTable {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 /// Active files are reference counted, but when we clone the file table (e.g. for a snapshot) we
@@ -30,13 +38,15 @@ impl Clone for FileTable {
                 .map(|slot| slot.as_ref().map(|value| Rc::new((**value).clone())))
                 .collect(),
             free_files: self.free_files.clone(),
+            max_files: self.max_files,
         }
     }
 }
 
 impl FileTable {
     pub fn new() -> Self {
-        Self { files: vec![], free_files: BTreeSet::new() }
+        let max_files = crate::ResourceLimits::default().max_fds;
+        Self { files: vec![], free_files: BTreeSet::new(), max_files }
     }
 
     /// Get a reference to the file associated with `fd`, returning `EBADF` if `fd` does not
@@ -51,10 +61,20 @@ impl FileTable {
         Ok(file)
     }
 
-    /// Set `fd` to map to `file`, implicitly unmapping any existing file.
+    /// Set `fd` to map to `file`, implicitly unmapping any existing file. Returns `EBADF` if `fd`
+    /// exceeds the file descriptor limit.
     ///
     /// Note: This will also send events to any process listening for changes to this file.
-    pub fn set(&mut self, pm: &mut ProcessManager, fd: FileDescriptor, file: ActiveFile) {
+    pub fn set(
+        &mut self,
+        pm: &mut ProcessManager,
+        fd: FileDescriptor,
+        file: ActiveFile,
+    ) -> Result<()> {
+        if fd >= self.max_files {
+            return Err(errno::EBADF);
+        }
+
         let index = fd as usize;
         if index >= self.files.len() {
             self.free_files.extend(self.files.len() as u64..fd);
@@ -72,6 +92,7 @@ impl FileTable {
         }
 
         self.free_files.remove(&fd);
+        Ok(())
     }
 
     /// Remove the file associated with `fd` from the mapping table.
@@ -95,17 +116,20 @@ impl FileTable {
         }
     }
 
-    /// Allocate a new file descriptor associated with `file`
-    pub fn add(&mut self, file: ActiveFile) -> FileDescriptor {
-        match self.free_files.pop_first() {
-            Some(fd) => {
+    /// Allocate a new file descriptor associated with `file`, returning `EMFILE` if the file
+    /// descriptor limit has been reached.
+    pub fn add(&mut self, file: ActiveFile) -> Result<FileDescriptor> {
+        match self.free_files.first() {
+            Some(&fd) if fd < self.max_files => {
+                self.free_files.remove(&fd);
                 self.files[fd as usize] = Some(file);
-                fd
+                Ok(fd)
             }
-            None => {
+            _ if (self.files.len() as u64) < self.max_files => {
                 self.files.push(Some(file));
-                (self.files.len() - 1) as u64
+                Ok((self.files.len() - 1) as u64)
             }
+            _ => Err(errno::EMFILE),
         }
     }
 }
@@ -241,6 +265,9 @@ impl ActiveFileData {
     }
 }
 
+/// File descriptor flag (`fcntl(F_SETFD)`) that causes the file to be closed on `execve`.
+pub const FD_CLOEXEC: u64 = 1;
+
 bitflags::bitflags! {
     #[allow(bad_style)]
     pub struct OpenFlags: u64 {
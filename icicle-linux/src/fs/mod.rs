@@ -3,7 +3,7 @@
 //! This crate is designed to emulate the outward behavior of the Linux VFS implementation. However,
 //! it has been greatly simplified and is designed to be "snapshotable"
 
-pub use self::file::{ActiveFile, ActiveFileData, FileTable, OpenFlags};
+pub use self::file::{ActiveFile, ActiveFileData, FileTable, OpenFlags, FD_CLOEXEC};
 
 pub mod devices;
 pub mod host;
@@ -19,6 +19,7 @@ use std::{
 };
 
 use bstr::ByteSlice;
+use icicle_cpu::utils::Drbg;
 
 use crate::{errno, fs::host::TempFs, sys, types};
 
@@ -541,7 +542,7 @@ impl VfsRoot {
     }
 
     /// Initialize the VFS to the most commonly used default state
-    pub fn init_default(&mut self, host_root: std::path::PathBuf) -> Result<()> {
+    pub fn init_default(&mut self, host_root: std::path::PathBuf, random: Drbg) -> Result<()> {
         let root = self.root.clone();
 
         // Map host_root to VFS root
@@ -560,7 +561,7 @@ impl VfsRoot {
         // Add some standard devices
         self.create_dev(b"/dev/null", devices::NullDevice)?;
         self.create_dev(b"/dev/zero", devices::ZeroDevice)?;
-        self.create_dev(b"/dev/urandom", devices::RandomDevice::new(0x1234))?;
+        self.create_dev(b"/dev/urandom", devices::RandomDevice::new(random))?;
 
         // @fixme: these should be symlinks instead of devices
         self.create_dev(b"/dev/stdin", devices::NullDevice)?;
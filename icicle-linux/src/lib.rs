@@ -1,6 +1,8 @@
 //! Emulated user mode for linux
+pub mod crash_context;
 pub mod errno;
 pub mod fs;
+pub mod process_tree;
 pub mod sys;
 pub mod types;
 
@@ -9,7 +11,9 @@ mod utils;
 
 use std::{
     any::Any,
+    cell::{Ref, RefCell, RefMut},
     collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
+    rc::Rc,
 };
 
 use bstr::ByteSlice;
@@ -17,11 +21,14 @@ use tracing::info;
 
 use icicle_cpu::{
     debug_info::{DebugInfo, SourceLocation},
-    elf::ElfLoader,
+    elf::{self, ElfLoader},
     mem::{self, perm, AllocLayout, Mapping, MemError, MemResult, VirtualMemoryMap},
+    utils::Drbg,
     Exception, ExceptionCode, ValueSource, VmExit,
 };
 
+use crate::process_tree::{ProcessState, ProcessTree};
+
 pub trait LinuxMmu {
     fn memmap(&mut self, start: u64, len: u64, mapping: Mapping) -> bool;
     fn unmap(&mut self, start: u64, len: u64) -> bool;
@@ -141,6 +148,7 @@ pub trait LinuxCpu {
     fn restore_cpu_state(&mut self, state: &Box<dyn Any>);
     fn i_count(&self) -> u64;
     fn resume(&mut self);
+    fn next_pc(&self) -> u64;
     fn set_next_pc(&mut self, addr: u64);
 
     fn sleigh(&self) -> &sleigh_runtime::SleighData;
@@ -168,7 +176,11 @@ impl LinuxCpu for icicle_cpu::Cpu {
 
     fn restore_cpu_state(&mut self, state: &Box<dyn Any>) {
         let snapshot = state.downcast_ref::<Self::CpuSnapshot>().unwrap();
+        // The instruction count is shared by all processes, so it keeps counting across task
+        // switches.
+        let icount = self.icount;
         self.restore(snapshot);
+        self.icount = icount;
     }
 
     fn i_count(&self) -> u64 {
@@ -181,6 +193,10 @@ impl LinuxCpu for icicle_cpu::Cpu {
         self.set_next_pc(next_pc);
     }
 
+    fn next_pc(&self) -> u64 {
+        ValueSource::read_var(self, self.arch.reg_next_pc)
+    }
+
     fn set_next_pc(&mut self, addr: u64) {
         ValueSource::write_var(self, self.arch.reg_next_pc, addr);
     }
@@ -301,6 +317,10 @@ pub struct LoadedImage {
 
     /// The end address of the original binary.
     pub end_addr: u64,
+
+    /// The address of the stub used to emulate `__tls_get_addr` for binaries linked by the loader
+    /// (or 0 if there is no stub).
+    pub tls_get_addr: u64,
 }
 
 /// Timer subsystem
@@ -310,23 +330,6 @@ pub struct Timer {
     pub alarm: Option<u64>,
 }
 
-/// Module for generating fake random numbers
-pub struct Random {
-    seed: u8,
-}
-
-impl Random {
-    pub fn new(seed: u8) -> Self {
-        Self { seed }
-    }
-
-    pub fn next(&mut self) -> u8 {
-        let next = self.seed;
-        self.seed = self.seed.wrapping_add(1);
-        next
-    }
-}
-
 #[derive(Clone)]
 pub struct MemMappedFile {
     pub path: fs::Path,
@@ -342,7 +345,7 @@ impl std::fmt::Debug for MemMappedFile {
     }
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum TerminationReason {
     /// The process exited normally.
     Exit(u64),
@@ -364,14 +367,22 @@ pub enum PauseReason {
 
     /// The process was suspended to run another task and is always ready to be resumed.
     Switched,
+
+    /// The process is waiting on a futex (see [Process::futex_wait]).
+    WaitFutex,
+
+    /// The process was preempted after using up its time slice (see
+    /// [KernelConfig::thread_quantum]) and is always ready to be resumed.
+    Preempted,
 }
 
 pub struct ParkedProcess {
     /// Metadata about the process
     pub process: Process,
 
-    /// Virtual memory state of the parked process
-    pub mem: VirtualMemoryMap,
+    /// Virtual memory state of the parked process. Threads share a single address space, which is
+    /// only held by one of the parked threads in the group (or none if the group is active).
+    pub mem: Option<VirtualMemoryMap>,
 
     /// CPU state for the parked process
     pub cpu: Box<dyn Any>,
@@ -381,6 +392,18 @@ pub struct ParkedProcess {
 }
 
 impl ParkedProcess {
+    /// Creates a copy of the parked process (see [Process::copy]).
+    pub fn copy(&self, cloner: &mut SharedCloner) -> Self {
+        // Processes are only parked by the `Cpu` implementation of `LinuxCpu`.
+        let cpu = self.cpu.downcast_ref::<icicle_cpu::CpuSnapshot>().unwrap().clone();
+        Self {
+            process: self.process.copy(cloner),
+            mem: self.mem.clone(),
+            cpu: Box::new(cpu),
+            pause_reason: self.pause_reason,
+        }
+    }
+
     pub fn read_var_zxt(&self, var: pcode::VarNode) -> u64 {
         self.cpu
             .downcast_ref::<icicle_cpu::CpuSnapshot>()
@@ -409,6 +432,120 @@ impl SemaphoreSetUndo {
     }
 }
 
+/// Keeps track of the regions of memory created by `mmap`, splitting regions when they are
+/// partially unmapped (similar to VMAs in the Linux kernel) so that the number of mappings can be
+/// limited.
+#[derive(Clone, Default)]
+pub struct MmapRegions {
+    /// Maps from the start address of a region to its end address.
+    regions: BTreeMap<u64, u64>,
+}
+
+impl MmapRegions {
+    /// Returns the number of distinct regions.
+    pub fn count(&self) -> usize {
+        self.regions.len()
+    }
+
+    /// Returns the number of regions there would be after `start..end` is inserted.
+    pub fn count_after_insert(&self, start: u64, end: u64) -> usize {
+        let mut count = self.count() + 1;
+        for (region_start, region_end) in self.overlapping(start, end) {
+            count -= 1;
+            count += (region_start < start) as usize + (region_end > end) as usize;
+        }
+        count
+    }
+
+    pub fn insert(&mut self, start: u64, end: u64) {
+        self.remove(start, end);
+        self.regions.insert(start, end);
+    }
+
+    fn overlapping(&self, start: u64, end: u64) -> Vec<(u64, u64)> {
+        self.regions
+            .range(..end)
+            .rev()
+            .take_while(|(_, &region_end)| region_end > start)
+            .map(|(&a, &b)| (a, b))
+            .collect()
+    }
+
+    /// Removes `start..end` from the tracked regions, splitting any region that partially overlaps
+    /// with the range.
+    pub fn remove(&mut self, start: u64, end: u64) {
+        for (region_start, region_end) in self.overlapping(start, end) {
+            self.regions.remove(&region_start);
+            if region_start < start {
+                self.regions.insert(region_start, start);
+            }
+            if region_end > end {
+                self.regions.insert(end, region_end);
+            }
+        }
+    }
+}
+
+/// State that can be shared between processes, e.g. the file table of threads created by `clone`
+/// with `CLONE_FILES`.
+///
+/// Cloning a `Shared` value creates another handle to the same state, use [SharedCloner] to copy
+/// the state itself.
+pub struct Shared<T>(Rc<RefCell<T>>);
+
+impl<T> Shared<T> {
+    pub fn new(value: T) -> Self {
+        Self(Rc::new(RefCell::new(value)))
+    }
+
+    pub fn borrow(&self) -> Ref<'_, T> {
+        self.0.borrow()
+    }
+
+    pub fn borrow_mut(&self) -> RefMut<'_, T> {
+        self.0.borrow_mut()
+    }
+
+    /// Returns whether `self` and `other` are handles to the same state.
+    pub fn ptr_eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl<T: Clone> Shared<T> {
+    /// Creates a new handle to a copy of the state.
+    pub fn unshare(&self) -> Self {
+        Self::new(self.borrow().clone())
+    }
+}
+
+impl<T> Clone for Shared<T> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<T: Default> Default for Shared<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+/// Copies [Shared] state (e.g. for snapshots), ensuring that state shared between processes before
+/// the copy is still shared between the copied processes.
+#[derive(Default)]
+pub struct SharedCloner {
+    copies: HashMap<*const (), Box<dyn Any>>,
+}
+
+impl SharedCloner {
+    pub fn copy<T: Clone + 'static>(&mut self, value: &Shared<T>) -> Shared<T> {
+        let key = Rc::as_ptr(&value.0) as *const ();
+        let copy = self.copies.entry(key).or_insert_with(|| Box::new(value.unshare()));
+        copy.downcast_ref::<Shared<T>>().unwrap().clone()
+    }
+}
+
 #[derive(Clone, Default)]
 pub struct ProcessIpc {
     /// Attached shared memory segments. Maps from virtual address for shmem ID.
@@ -429,8 +566,11 @@ pub struct Process {
     /// Metadata about the process set by the loader
     pub image: LoadedImage,
 
-    /// The table of files open by this process
-    pub file_table: fs::FileTable,
+    /// The table of files open by this process (shared with threads created with `CLONE_FILES`).
+    pub file_table: Shared<fs::FileTable>,
+
+    /// The resource limits of the process that can be changed with `setrlimit`.
+    pub rlimits: ProcessLimits,
 
     /// Timer subsystem for the process
     pub timer: Timer,
@@ -438,6 +578,11 @@ pub struct Process {
     /// The unique identifier associated with the process
     pub pid: u64,
 
+    /// The ID of the thread group the process belongs to (i.e. the pid of the main thread). Threads
+    /// created by `clone` with `CLONE_THREAD` are tracked as separate processes that share the
+    /// thread group of their parent.
+    pub tgid: u64,
+
     /// The ID of the user that owns this process
     pub uid: u64,
 
@@ -447,6 +592,9 @@ pub struct Process {
     /// The set of pending signals for the process.
     pub pending_signals: u64,
 
+    /// The set of signals that are currently blocked from being delivered to the process.
+    pub signal_mask: u64,
+
     /// The name of the process,
     pub name: [u8; 16],
 
@@ -454,6 +602,10 @@ pub struct Process {
     // @fixme: this is slightly broken due to unmap/remapping
     pub mapping: BTreeMap<u64, MemMappedFile>,
 
+    /// The regions of memory allocated by `mmap` (used for enforcing the mapping limit). Shared
+    /// with processes created with `CLONE_VM`.
+    pub mmap_regions: Shared<MmapRegions>,
+
     /// Keeps track of IPC resources used by the current process
     pub ipc: ProcessIpc,
 
@@ -462,8 +614,9 @@ pub struct Process {
     // @fixme: we should handle dynamically linked libraries.
     pub debug_info: Option<DebugInfo>,
 
-    /// Registered signal handlers for this process
-    pub signal_handlers: SignalHandlerTable,
+    /// Registered signal handlers for this process (shared with threads created with
+    /// `CLONE_SIGHAND`).
+    pub signal_handlers: Shared<SignalHandlerTable>,
 
     /// Other processes that are listining to the status of this process.
     pub listeners: BTreeSet<u64>,
@@ -483,11 +636,43 @@ pub struct Process {
 
     /// The reason why the process was terminated.
     pub termination_reason: Option<TerminationReason>,
+
+    /// The most recent syscalls executed by the process (included in crash reports).
+    pub syscall_history: crash_context::SyscallHistory,
+
+    /// The futex the process is blocked on, cleared when the process is woken by `FUTEX_WAKE`.
+    pub futex_wait: Option<u64>,
+
+    /// The address that is cleared (and woken as a futex) when the thread exits, set by
+    /// `set_tid_address` or `clone` with `CLONE_CHILD_CLEARTID`.
+    pub clear_child_tid: u64,
+
+    /// The layout of the static TLS area, if thread-local storage was initialized by the loader.
+    pub tls: Option<elf::TlsLayout>,
 }
 
 impl Process {
     pub fn new() -> Self {
-        Self { file_table: fs::FileTable::new(), pid: 3333, ..Self::default() }
+        let file_table = Shared::new(fs::FileTable::new());
+        Self { file_table, pid: 3333, tgid: 3333, ..Self::default() }
+    }
+
+    pub fn with_limits(limits: &ResourceLimits) -> Self {
+        let mut process = Self::new();
+        process.rlimits = ProcessLimits::new(limits);
+        process.file_table.borrow_mut().max_files = process.rlimits.nofile.cur;
+        process
+    }
+
+    /// Creates a copy of the process that does not share any state with the original process
+    /// (except with other processes copied using `cloner`).
+    pub fn copy(&self, cloner: &mut SharedCloner) -> Self {
+        Self {
+            file_table: cloner.copy(&self.file_table),
+            mmap_regions: cloner.copy(&self.mmap_regions),
+            signal_handlers: cloner.copy(&self.signal_handlers),
+            ..self.clone()
+        }
     }
 
     pub fn cwd(&self) -> fs::DirEntryRef {
@@ -531,6 +716,16 @@ impl SignalHandlerTable {
         }
         self.entries[signal as usize] = action;
     }
+
+    /// Resets all signals with a handler back to the default action. Ignored signals remain
+    /// ignored (matching the behaviour of `execve`).
+    fn reset_handlers(&mut self) {
+        for entry in &mut self.entries {
+            if entry.handler.value != SIG_IGN {
+                *entry = types::Sigaction::default();
+            }
+        }
+    }
 }
 
 enum SignalAction {
@@ -596,6 +791,17 @@ impl ProcessManager {
         self.parked.clear();
     }
 
+    /// Creates a copy of the process manager and all parked processes (see [Process::copy]).
+    pub fn copy(&self, cloner: &mut SharedCloner) -> Self {
+        Self {
+            next_pid: self.next_pid,
+            parked: self.parked.iter().map(|process| process.copy(cloner)).collect(),
+            last_suspend: self.last_suspend,
+            suspend_count: self.suspend_count,
+            warp_time: self.warp_time,
+        }
+    }
+
     pub fn next_free_pid(&mut self) -> u64 {
         self.next_pid += 1;
         self.next_pid - 1
@@ -609,7 +815,7 @@ impl ProcessManager {
     ) {
         // tracing::trace!("Suspended process at: {:#0x}", cpu.shadow_stack);
         self.parked.push_back(ParkedProcess {
-            mem: cpu.mem().take_virtual_mapping(),
+            mem: Some(cpu.mem().take_virtual_mapping()),
             cpu: cpu.save_cpu_state(),
             process,
             pause_reason,
@@ -638,7 +844,9 @@ impl ProcessManager {
         false
     }
 
-    pub fn resume_next<C: LinuxCpu>(&mut self, cpu: &mut C) -> Option<Process> {
+    /// Resumes the next process that is ready to run, returning the process along with the reason
+    /// it was paused.
+    pub fn resume_next<C: LinuxCpu>(&mut self, cpu: &mut C) -> Option<(Process, PauseReason)> {
         let ready_task = self.ready_now().or_else(|| self.ready_timewarp())?;
 
         if self.detect_hang(self.parked[ready_task].process.pid) {
@@ -648,7 +856,12 @@ impl ProcessManager {
         self.parked.rotate_left(ready_task);
         let parked = self.parked.pop_front()?;
 
-        cpu.mem().restore_virtual_mapping(parked.mem);
+        // Threads without an address space share the one held by another thread in the group.
+        let mem = parked.mem.or_else(|| {
+            let tgid = parked.process.tgid;
+            self.parked.iter_mut().find(|x| x.process.tgid == tgid && x.mem.is_some())?.mem.take()
+        });
+        cpu.mem().restore_virtual_mapping(mem.expect("missing address space for thread group"));
         cpu.restore_cpu_state(&parked.cpu);
 
         tracing::debug!("resumed pid={}", parked.process.pid);
@@ -656,19 +869,20 @@ impl ProcessManager {
         // @fixme: need some way of clearing this timer.
         // parked.process.timeout.take();
 
-        Some(parked.process)
+        Some((parked.process, parked.pause_reason))
     }
 
     /// Find the first process that is ready now (i.e. without skipping forward in time).
     fn ready_now(&mut self) -> Option<usize> {
         self.parked.iter().position(|parked| {
-            if parked.process.pending_signals != 0 {
+            if parked.process.pending_signals & !parked.process.signal_mask != 0 {
                 // Will always wake up if there is a signal pending.
                 return true;
             }
 
             match parked.pause_reason {
-                PauseReason::Switched => true,
+                PauseReason::Switched | PauseReason::Preempted => true,
+                PauseReason::WaitFutex => parked.process.futex_wait.is_none(),
                 PauseReason::WaitSignal => false,
                 PauseReason::WaitFile => !parked.process.file_events.is_empty(),
                 PauseReason::WaitProcess => !parked.process.process_events.is_empty(),
@@ -697,6 +911,45 @@ impl ProcessManager {
         Some(index)
     }
 
+    /// Returns whether any suspended process is ready to run without skipping forward in time.
+    pub fn has_ready(&mut self) -> bool {
+        self.ready_now().is_some()
+    }
+
+    /// Returns whether any suspended thread belongs to the thread group `tgid`.
+    pub fn has_threads(&self, tgid: u64) -> bool {
+        self.parked.iter().any(|x| x.process.tgid == tgid)
+    }
+
+    /// Removes all suspended threads that belong to the thread group `tgid`.
+    pub fn take_threads(&mut self, tgid: u64) -> Vec<ParkedProcess> {
+        let (threads, others): (VecDeque<_>, _) = std::mem::take(&mut self.parked)
+            .into_iter()
+            .partition(|x: &ParkedProcess| x.process.tgid == tgid);
+        self.parked = others;
+        threads.into()
+    }
+
+    /// Wakes up to `count` processes waiting on the futex at `uaddr`, returning the number of
+    /// processes woken. If `tgid` is set, only threads in that thread group are woken (used for
+    /// private futexes).
+    pub fn wake_futex(&mut self, tgid: Option<u64>, uaddr: u64, count: u64) -> u64 {
+        let mut woken = 0;
+        for parked in &mut self.parked {
+            if woken >= count {
+                break;
+            }
+            if parked.process.futex_wait != Some(uaddr)
+                || tgid.is_some_and(|tgid| parked.process.tgid != tgid)
+            {
+                continue;
+            }
+            parked.process.futex_wait = None;
+            woken += 1;
+        }
+        woken
+    }
+
     pub fn get_mut(&mut self, pid: u64) -> Option<&mut ParkedProcess> {
         // @fixme: make this more efficient
         self.parked.iter_mut().find(|x| x.process.pid == pid)
@@ -713,10 +966,11 @@ impl ProcessManager {
         listener_pid: u64,
         child_pid: u64,
         reason: TerminationReason,
+        sigchld: u8,
     ) {
         if let Some(parked) = self.get_mut(listener_pid) {
             parked.process.process_events.push((child_pid, reason));
-            parked.process.pending_signals |= 1 << (sys::signal::SIGCHLD - 1);
+            parked.process.pending_signals |= 1 << (sigchld - 1);
         }
     }
 }
@@ -852,6 +1106,106 @@ pub struct KernelConfig {
     pub kill_on_alloc_failure: bool,
     pub force_small_address_space: bool,
     pub boot_time: std::time::Duration,
+
+    /// If set, every syscall is logged to stderr in the specified format.
+    pub strace: Option<sys::strace::TraceFormat>,
+
+    /// Limits on the resources that the guest is allowed to allocate.
+    pub limits: ResourceLimits,
+
+    /// Controls which process continues executing after a `fork`.
+    pub follow_fork: FollowFork,
+
+    /// The number of recent syscalls recorded for each process (see [crash_context]).
+    pub syscall_history: usize,
+
+    /// The number of instructions a thread executes before being preempted in favour of another
+    /// thread that is ready to run. If set to 0 (the default), threads are only switched when they
+    /// block.
+    pub thread_quantum: u64,
+
+    /// Directories (inside of the sysroot) to search for shared libraries. If set, the
+    /// dependencies of dynamically linked binaries are loaded and relocated by the kernel instead
+    /// of by the binary's interpreter (unless they require the interpreter, e.g. libraries with
+    /// constructors or indirect functions).
+    pub library_paths: Vec<Vec<u8>>,
+}
+
+/// Controls which process is emulated after a process calls `fork` (or `clone` without
+/// `CLONE_VM`). Threads are always emulated alongside their parent.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FollowFork {
+    /// Keep both processes, switching between them whenever one of them blocks or exits.
+    #[default]
+    Both,
+
+    /// Only continue executing the parent. The child is reported as having exited immediately
+    /// with a status of 0.
+    Parent,
+
+    /// Only continue executing the child, which takes the place of the parent (e.g. for
+    /// targets that daemonize on startup).
+    Child,
+}
+
+/// Limits for resources allocated by the guest, preventing the guest from exhausting host
+/// resources. Requests that exceed a limit fail with the same error code as the Linux kernel.
+#[derive(Clone, Copy, Debug)]
+pub struct ResourceLimits {
+    /// The maximum number of open file descriptors per process (`RLIMIT_NOFILE`). Exceeding this
+    /// limit fails with `EMFILE`.
+    pub max_fds: u64,
+
+    /// The maximum number of memory mappings created with `mmap` per process (equivalent to
+    /// `vm.max_map_count`). Exceeding this limit fails with `ENOMEM`.
+    pub max_mmaps: u64,
+
+    /// The maximum number of processes (including threads) that can exist at once
+    /// (`RLIMIT_NPROC`). Exceeding this limit fails with `EAGAIN`.
+    pub max_processes: u64,
+}
+
+impl Default for ResourceLimits {
+    fn default() -> Self {
+        Self { max_fds: 1024, max_mmaps: 65530, max_processes: 1024 }
+    }
+}
+
+/// The soft (`cur`) and hard (`max`) limit for a resource (see `getrlimit(2)`). Only the soft limit
+/// is enforced, the hard limit is the maximum value that the soft limit can be raised to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RLimit {
+    pub cur: u64,
+    pub max: u64,
+}
+
+impl RLimit {
+    pub fn new(limit: u64) -> Self {
+        Self { cur: limit, max: limit }
+    }
+}
+
+/// The limits of a process that are configurable by the guest (the initial values are taken from
+/// [ResourceLimits]).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ProcessLimits {
+    /// `RLIMIT_NOFILE`, the soft limit is enforced by [fs::FileTable::max_files].
+    pub nofile: RLimit,
+
+    /// `RLIMIT_NPROC`.
+    pub nproc: RLimit,
+}
+
+impl ProcessLimits {
+    pub fn new(limits: &ResourceLimits) -> Self {
+        Self { nofile: RLimit::new(limits.max_fds), nproc: RLimit::new(limits.max_processes) }
+    }
+}
+
+impl Default for ProcessLimits {
+    fn default() -> Self {
+        Self::new(&ResourceLimits::default())
+    }
 }
 
 impl Default for KernelConfig {
@@ -863,6 +1217,12 @@ impl Default for KernelConfig {
             force_small_address_space: false,
             kill_on_alloc_failure: false,
             boot_time: std::time::Duration::new(1600000000, 0),
+            strace: None,
+            limits: ResourceLimits::default(),
+            follow_fork: FollowFork::default(),
+            syscall_history: 16,
+            thread_quantum: 0,
+            library_paths: vec![],
         }
     }
 }
@@ -885,15 +1245,44 @@ pub struct Kernel {
     /// Configures whether we should kill the process if exceed the maximum allocation size.
     pub kill_on_alloc_failure: bool,
 
+    /// Address ranges where fixed mappings are allowed to exceed `max_alloc_size`. Used for
+    /// sanitizer shadow memory, which is reserved up-front as a single huge mapping but only
+    /// touched sparsely.
+    pub shadow_regions: Vec<std::ops::Range<u64>>,
+
     /// Configures the starting address for memory mappings
     pub mmap_start_addr: u64,
 
     /// Configures the addres `brk` is initialized at.
     pub brk_start_addr: u64,
 
+    /// Limits on the resources that the guest is allowed to allocate.
+    pub limits: ResourceLimits,
+
+    /// Controls which process continues executing after a `fork`.
+    pub follow_fork: FollowFork,
+
+    /// The number of instructions a thread executes before being preempted (see
+    /// [KernelConfig::thread_quantum]).
+    pub thread_quantum: u64,
+
+    /// Directories searched for shared libraries (see [KernelConfig::library_paths]).
+    pub library_paths: Vec<Vec<u8>>,
+
+    /// The instruction count at which the active process is preempted.
+    preempt_at: u64,
+
+    /// Whether the CPU state was already updated for the process that continues after the current
+    /// syscall (e.g. by a task switch or `rt_sigreturn`), so the syscall result must not be
+    /// written.
+    pub(crate) skip_syscall_result: bool,
+
     /// Includes the current `i_count` in syscall debugging.
     pub trace_i_count: bool,
 
+    /// Logs every syscall with decoded arguments and return values (see [sys::strace]).
+    pub syscall_tracer: Option<sys::strace::SyscallTracer>,
+
     /// Temporary storage used for copying bytes from userspace into
     pub buffer: Vec<u8>,
 
@@ -906,17 +1295,26 @@ pub struct Kernel {
     /// Whether we caught a syscall at the current entry.
     pub did_break_at_entry: bool,
 
+    /// Custom handlers for syscalls, indexed by syscall number (see [sys::hook]).
+    pub syscall_hooks: HashMap<u64, sys::hook::SyscallHook>,
+
+    /// The syscall whose hook is currently running, cleared if the hook is removed while it runs.
+    running_syscall_hook: Option<u64>,
+
     //---
     // Move everything below this point to state struct
     //---
     /// Structure used for fork/clone
     pub clone_state: CloneState,
 
+    /// The path of the binary to replace the current process with, set by `execve`.
+    pub pending_exec: Option<Vec<u8>>,
+
     /// Ipc structures potentially shared between processes.
     pub ipc: Ipc,
 
-    /// Kernel random number source
-    pub random: Random,
+    /// Kernel random number source, shared with `/dev/urandom` (see [Kernel::set_entropy_source]).
+    pub random: Drbg,
 
     /// The kernel's current time
     pub current_time: std::time::Duration,
@@ -930,6 +1328,10 @@ pub struct Kernel {
     /// Processes managed by the kernel.
     pub process_manager: ProcessManager,
 
+    /// Every process created since the initial program was loaded (including processes that have
+    /// terminated).
+    pub process_tree: ProcessTree,
+
     /// The subsystem responsible for managing the virtual file system
     pub vfs: fs::VfsRoot,
 }
@@ -957,30 +1359,52 @@ impl Kernel {
             force_mremap_move: config.force_mremap_move,
             kill_on_alloc_failure: config.kill_on_alloc_failure,
             max_alloc_size,
+            shadow_regions: vec![],
             mmap_start_addr,
             brk_start_addr,
+            limits: config.limits,
+            follow_fork: config.follow_fork,
+            thread_quantum: config.thread_quantum,
+            library_paths: config.library_paths.clone(),
+            preempt_at: u64::MAX,
+            skip_syscall_result: false,
 
             trace_i_count: true,
+            syscall_tracer: config.strace.map(sys::strace::SyscallTracer::stderr),
             buffer: vec![],
 
-            random: Random::new(4),
+            random: Drbg::default(),
             current_time: config.boot_time,
             syscall_breakpoints: HashSet::new(),
             catch_syscalls: CatchSyscalls::None,
             did_break_at_entry: false,
+            syscall_hooks: HashMap::new(),
+            running_syscall_hook: None,
 
             hostname: b"Icicle-VM-0001\0".to_vec(),
 
-            process: Process::new(),
+            process: Process {
+                syscall_history: crash_context::SyscallHistory::new(config.syscall_history),
+                ..Process::with_limits(&config.limits)
+            },
             process_manager: ProcessManager::new(false),
+            process_tree: ProcessTree::default(),
             ipc: Ipc::default(),
 
             vfs: fs::VfsRoot::new(),
 
             clone_state: CloneState::default(),
+            pending_exec: None,
         }
     }
 
+    /// Sets the random number source used for `getrandom`, `AT_RANDOM` and `/dev/urandom`.
+    ///
+    /// Note: this must be called before [Kernel::init_vfs] for `/dev/urandom` to use the source.
+    pub fn set_entropy_source(&mut self, source: Drbg) {
+        self.random = source;
+    }
+
     pub fn init_vfs(&mut self, sysroot: std::path::PathBuf) -> Result<(), String> {
         let mut vfs_path = sysroot;
         if vfs_path.ends_with("{arch}") {
@@ -990,7 +1414,7 @@ impl Kernel {
 
         tracing::info!("Initializing VFS with rootfs: {}", vfs_path.display());
         self.vfs
-            .init_default(vfs_path.clone())
+            .init_default(vfs_path.clone(), self.random.clone())
             .map_err(|e| format!("Failed to load VFS: {e} ({})", vfs_path.display()))
     }
 
@@ -1035,7 +1459,7 @@ impl Kernel {
     where
         M: LinuxMmu,
     {
-        if layout.size > self.max_alloc_size {
+        if layout.size > self.max_alloc_size && !self.is_shadow_alloc(&layout) {
             if self.kill_on_alloc_failure {
                 self.process.pending_signals |= 1 << (sys::signal::SIGSEGV - 1);
             }
@@ -1044,6 +1468,19 @@ impl Kernel {
         mem.alloc(layout, Mapping { perm, value: 0xAA })
     }
 
+    /// Returns whether `layout` is a fixed allocation entirely within one of the shadow regions.
+    fn is_shadow_alloc(&self, layout: &AllocLayout) -> bool {
+        let Some(start) = layout.addr
+        else {
+            return false;
+        };
+        let Some(end) = start.checked_add(layout.size)
+        else {
+            return false;
+        };
+        self.shadow_regions.iter().any(|x| x.start <= start && end <= x.end)
+    }
+
     /// Allocate a region of memory requiring it to start at `start_addr`.
     pub fn alloc_fixed<M>(
         &mut self,
@@ -1056,7 +1493,7 @@ impl Kernel {
         M: LinuxMmu,
     {
         let layout = AllocLayout { addr: Some(start_addr), size, align: sys::PAGE_SIZE };
-        if layout.size > self.max_alloc_size {
+        if layout.size > self.max_alloc_size && !self.is_shadow_alloc(&layout) {
             if self.kill_on_alloc_failure {
                 self.process.pending_signals |= 1 << (sys::signal::SIGSEGV - 1);
             }
@@ -1146,8 +1583,10 @@ impl Kernel {
 
         let mut writer = utils::MemWriter::new(arg_start, 8);
 
-        // Dummy random values, so emulation is deterministic
-        self.process.image.rand_ptr = writer.write_bytes(cpu.mem(), &[0x33; 16][..])?;
+        // Random values from the (deterministic) kernel random number source.
+        let mut rand_bytes = [0; 16];
+        self.random.fill_bytes(&mut rand_bytes);
+        self.process.image.rand_ptr = writer.write_bytes(cpu.mem(), &rand_bytes[..])?;
 
         // Write path and platform name
         let pathname = pathname.iter().copied().chain(std::iter::once(0)).collect::<Vec<_>>();
@@ -1193,34 +1632,261 @@ impl Kernel {
             self.process.working_dir = Some(self.vfs.root.clone());
         }
 
+        Ok(())
+    }
+
+    /// Opens the standard streams for a newly loaded process.
+    fn open_std_streams(&mut self) {
         let mut try_open = |fd, path: &[u8]| {
             if let Ok(file) = self.vfs.open(path, fs::OpenFlags::empty()) {
-                self.process.file_table.set(&mut self.process_manager, fd, file);
+                let mut file_table = self.process.file_table.borrow_mut();
+                let _ = file_table.set(&mut self.process_manager, fd, file);
             }
         };
 
         try_open(sys::STDIN_FD, b"/dev/stdin");
         try_open(sys::STDOUT_FD, b"/dev/stdout");
         try_open(sys::STDERR_FD, b"/dev/stderr");
+    }
+
+    /// Loads the binary at `path` into an empty address space and spawns the process.
+    fn load_image(&mut self, cpu: &mut icicle_cpu::Cpu, path: &[u8]) -> Result<(), String> {
+        self.process.mapping.clear();
+
+        tracing::info!("Reserving null page");
+        cpu.mem.map_memory_len(0x0, sys::PAGE_SIZE, Mapping { perm: perm::NONE, value: 0xAA });
+        self.process
+            .mapping
+            .insert(0x0, MemMappedFile { path: b"(null page)".to_vec(), end: sys::PAGE_SIZE });
+
+        self.process.image.tls_get_addr = 0;
+        self.process.tls = None;
+        let metadata = self.load_elf(cpu, path)?;
+
+        // Keep track of data we just mapped from the ELF file.
+        self.process.mapping.insert(metadata.binary.base_ptr, MemMappedFile {
+            path: path.to_vec(),
+            end: metadata.binary.base_ptr + metadata.binary.length,
+        });
+        if let Some(interpreter) = metadata.interpreter.as_ref() {
+            self.process.mapping.insert(interpreter.base_ptr, MemMappedFile {
+                path: metadata.debug_info.dynamic_linker.clone(),
+                end: interpreter.base_ptr + interpreter.length,
+            });
+        }
+        for module in &metadata.debug_info.modules {
+            self.process.mapping.insert(module.base, MemMappedFile {
+                path: module.path.clone(),
+                end: module.base + module.len,
+            });
+        }
+
+        self.process.debug_info = Some(metadata.debug_info);
+
+        let base_ptr =
+            metadata.interpreter.as_ref().map_or(metadata.binary.base_ptr, |x| x.base_ptr);
+
+        self.process.image.base_ptr = base_ptr;
+        self.process.image.phdr_ptr = metadata.binary.phdr_ptr;
+        self.process.image.phdr_num = metadata.binary.phdr_num;
+        self.process.image.entry_ptr = metadata.binary.entry_ptr;
+        self.process.image.relocation_offset = metadata.binary.offset;
+        self.process.image.start_addr = metadata.binary.base_ptr;
+        self.process.image.end_addr = metadata.binary.base_ptr + metadata.binary.length;
+
+        let entry =
+            metadata.interpreter.as_ref().map_or(metadata.binary.entry_ptr, |x| x.entry_ptr);
+
+        tracing::info!("Setting instruction pointer to: {entry:#0x}");
+        (cpu.arch.on_boot)(cpu, entry);
+
+        self.spawn(cpu, path).map_err(|e| format!("Failed to initialize environment: {e}"))?;
+
+        if let Some(tls) = metadata.tls {
+            self.init_tls(cpu, tls)?;
+        }
 
         Ok(())
     }
 
+    /// Allocates the static TLS area for the main thread and initializes the thread pointer.
+    ///
+    /// Note: threads created later are expected to allocate their own TLS area and pass it to
+    /// `clone` with `CLONE_SETTLS`.
+    fn init_tls(&mut self, cpu: &mut icicle_cpu::Cpu, tls: elf::TlsLayout) -> Result<(), String> {
+        let Some(reg) = self.arch.reg_tls
+        else {
+            tracing::warn!("Thread-local storage is not supported for the current target");
+            return Ok(());
+        };
+
+        let size = icicle_cpu::utils::align_up(tls.size, sys::PAGE_SIZE);
+        let layout = AllocLayout::from_size_align(size, tls.align.max(sys::PAGE_SIZE));
+        let tp = self
+            .alloc(&mut cpu.mem, layout, perm::READ | perm::WRITE)
+            .and_then(|base| {
+                self.process.mapping.insert(base, MemMappedFile {
+                    path: b"(tls)".to_vec(),
+                    end: base + size,
+                });
+                tls.init(cpu, base)
+            })
+            .map_err(|e| format!("Failed to allocate TLS: {e}"))?;
+
+        tracing::info!("Setting thread pointer to: {tp:#0x}");
+        cpu.write_reg(reg, tp);
+        self.process.tls = Some(tls);
+        Ok(())
+    }
+
+    /// Emulates a call to `__tls_get_addr`, returning the address of the thread-local variable
+    /// described by the `tls_index` structure passed as the first argument.
+    fn tls_get_addr(&mut self, cpu: &mut icicle_cpu::Cpu) -> Option<VmExit> {
+        use target_lexicon::Architecture;
+
+        let (Some(reg), Some(tls)) = (self.arch.reg_tls, self.process.tls.as_ref())
+        else {
+            return self.handle_fault(cpu);
+        };
+
+        let mut reader = self.arch.libc(cpu.read_ptr_arg(0).ok()?);
+        let module = reader.read::<arch::Ptr, _>(&mut cpu.mem).ok()?;
+        let offset = reader.read::<arch::Ptr, _>(&mut cpu.mem).ok()?;
+
+        let Some(block) = module.checked_sub(1).and_then(|i| tls.modules.get(i as usize))
+        else {
+            tracing::warn!("__tls_get_addr called with invalid module ID: {module}");
+            return self.handle_fault(cpu);
+        };
+        let addr = cpu.read_reg(reg).wrapping_add_signed(block.tp_offset).wrapping_add(offset);
+        cpu.write_return_value(addr)?;
+
+        // Return to the caller.
+        let ret_addr = match self.arch.triple.architecture {
+            Architecture::X86_64 | Architecture::X86_32(_) => {
+                let sp = cpu.read_reg(self.arch.reg_sp);
+                cpu.write_reg(self.arch.reg_sp, sp + self.arch.reg_sp.size as u64);
+                self.arch.libc(sp).read::<arch::Ptr, _>(&mut cpu.mem).ok()?
+            }
+            Architecture::Aarch64(_) => cpu.read_reg(cpu.arch.sleigh.get_varnode("x30")?),
+            _ => cpu.read_reg(cpu.arch.sleigh.get_varnode("ra")?),
+        };
+        cpu.exception = Exception::new(ExceptionCode::ExternalAddr, ret_addr);
+        None
+    }
+
+    /// Replaces the current process with the binary at `path`, completing an `execve` call.
+    ///
+    /// This needs access to the concrete CPU to load the new image, so it is performed after the
+    /// syscall handler returns. The process keeps its pid, parent, working directory and any files
+    /// that were not marked close-on-exec.
+    fn exec(&mut self, cpu: &mut icicle_cpu::Cpu, path: &[u8]) -> Option<VmExit> {
+        tracing::debug!("[pid={}] exec: {}", self.process.pid, path.as_bstr());
+        self.process_tree.exec(self.process.pid, path);
+
+        // The new image gets its own copy of the file table and signal handlers, even if they were
+        // shared with other threads.
+        self.process.file_table = self.process.file_table.unshare();
+        let mut file_table = self.process.file_table.borrow_mut();
+        for fd in 0..file_table.files.len() {
+            let cloexec = file_table.files[fd]
+                .as_ref()
+                .is_some_and(|file| file.borrow().flags & fs::FD_CLOEXEC != 0);
+            if cloexec {
+                let _ = file_table.close(&mut self.process_manager, fd as u64);
+            }
+        }
+        drop(file_table);
+        self.process.signal_handlers = self.process.signal_handlers.unshare();
+        self.process.signal_handlers.borrow_mut().reset_handlers();
+        self.process.mmap_regions = Shared::default();
+        self.process.image = LoadedImage::default();
+
+        let name = path.rsplit(|&b| b == b'/').next().unwrap_or(path);
+        let len = name.len().min(self.process.name.len() - 1);
+        self.process.name = [0; 16];
+        self.process.name[..len].copy_from_slice(&name[..len]);
+
+        // No register state is inherited by the new image, however the instruction count must be
+        // preserved since it keeps counting across `execve`.
+        let (icount, fuel) = (cpu.icount, cpu.fuel);
+        cpu.mem.reset_virtual();
+        cpu.reset();
+        cpu.icount = icount;
+        cpu.fuel = fuel;
+        let result = self.load_image(cpu, path);
+
+        if let Err(e) = result {
+            // The old address space is already gone, so there is nothing to return to.
+            tracing::warn!("[pid={}] execve failed: {e}", self.process.pid);
+            let reason = TerminationReason::Killed(sys::signal::SIGSEGV as u64);
+            return self.destroy_process(cpu, reason);
+        }
+
+        // All code translated for the previous image is now invalid.
+        let entry = cpu.read_pc();
+        cpu.pending_exception = Some(Exception::new(ExceptionCode::AddressSpaceReplaced, entry));
+        None
+    }
+
     pub(crate) fn get_file(&mut self, fd: u64) -> fs::Result<fs::ActiveFile> {
-        self.process.file_table.get(&mut self.process_manager, fd)
+        self.process.file_table.borrow_mut().get(&mut self.process_manager, fd)
     }
 
     fn fork<C: LinuxCpu>(&mut self, cpu: &mut C) -> LinuxResult {
+        use sys::syscall::clone;
+
+        let active_processes = 1 + self.process_manager.parked.len() as u64;
+        if active_processes >= self.process.rlimits.nproc.cur {
+            return Err(errno::EAGAIN.into());
+        }
+
         let child_pid = self.process_manager.next_free_pid();
         // cpu.set_instr_ptr(cpu.next_instruction);
 
-        let is_thread = self.clone_state.flags.contains(sys::syscall::clone::Flags::VM);
-        let child_mem = if is_thread {
-            // @fixme: need to ensure that changes to the mapping are shared.
-            cpu.mem().clone_virtual_map()
+        let flags = self.clone_state.flags;
+        let shares_vm = flags.contains(clone::Flags::VM);
+        let is_thread = flags.contains(clone::Flags::THREAD);
+        let follow = if shares_vm { FollowFork::Both } else { self.follow_fork };
+        self.process_tree.fork(self.process.pid, child_pid, shares_vm);
+
+        match follow {
+            FollowFork::Both => {}
+            FollowFork::Parent => {
+                // The child never runs, so notify the parent as if it exited immediately.
+                tracing::debug!("following parent, skipping child pid={}", child_pid);
+                let reason = TerminationReason::Exit(0);
+                self.process_tree.set_state(child_pid, ProcessState::Terminated(reason));
+                self.process.process_events.push((child_pid, reason));
+                self.process.pending_signals |= 1 << (self.arch.signals.sigchld - 1);
+                return Ok(child_pid);
+            }
+            FollowFork::Child => {
+                // The parent is discarded, so the child can keep using the current address space
+                // directly. The child inherits the parent's parent so that it becomes the root
+                // process if the parent was the root process.
+                tracing::debug!("following child, discarding parent pid={}", self.process.pid);
+                self.process_tree.set_state(self.process.pid, ProcessState::Abandoned);
+                self.process.pid = child_pid;
+                self.process.tgid = child_pid;
+                if self.clone_state.new_sp != 0 {
+                    cpu.write_var(self.arch.reg_sp, self.clone_state.new_sp);
+                }
+                return Ok(0);
+            }
         }
-        else {
-            cpu.mem().snapshot_virtual_map()
+
+        if flags.contains(clone::Flags::PARENT_SETTID) {
+            let ptr = self.clone_state.parent_tidptr;
+            self.arch.libc(ptr).write::<arch::UInt, _>(cpu.mem(), child_pid)?;
+        }
+
+        // Threads share the address space of the parent, which is handed over to the child below.
+        let child_mem = match (is_thread, shares_vm) {
+            (true, _) => None,
+            // @fixme: need to ensure that changes to the mapping are shared.
+            (false, true) => Some(cpu.mem().clone_virtual_map()),
+            (false, false) => Some(cpu.mem().snapshot_virtual_map()),
         };
 
         // Return value for the parent process is the process id of the child
@@ -1228,16 +1894,59 @@ impl Kernel {
         let parent = self.process.clone();
         self.process_manager.suspend(cpu, parent, PauseReason::Switched);
 
-        tracing::debug!("new process spawned pid={}", child_pid);
+        let child_mem = match child_mem {
+            Some(mem) => mem,
+            None => self.process_manager.parked.back_mut().and_then(|x| x.mem.take()).unwrap(),
+        };
+
+        tracing::debug!("new process spawned pid={} (thread={})", child_pid, is_thread);
         cpu.mem().restore_virtual_mapping(child_mem);
-        self.process.parent_pid = self.process.pid;
+
+        // The child only shares the file table, signal handlers and memory regions of the parent
+        // if requested by the clone flags.
+        if !flags.contains(clone::Flags::FILES) {
+            self.process.file_table = self.process.file_table.unshare();
+        }
+        if !flags.contains(clone::Flags::SIGHAND) {
+            self.process.signal_handlers = self.process.signal_handlers.unshare();
+        }
+        if !shares_vm {
+            self.process.mmap_regions = self.process.mmap_regions.unshare();
+        }
+        if is_thread {
+            // Threads have the same parent as the rest of the thread group, and are never waited
+            // on by `wait4`.
+            self.process.listeners.clear();
+        }
+        else {
+            self.process.parent_pid = self.process.pid;
+            self.process.tgid = child_pid;
+            self.process.listeners.clear();
+            self.process.listeners.insert(self.process.parent_pid);
+        }
         self.process.pid = child_pid;
-        self.process.listeners.clear();
-        self.process.listeners.insert(self.process.parent_pid);
+        self.process.futex_wait = None;
+        self.process.clear_child_tid = match flags.contains(clone::Flags::CHILD_CLEARTID) {
+            true => self.clone_state.child_tidptr,
+            false => 0,
+        };
+
+        if flags.contains(clone::Flags::CHILD_SETTID) {
+            let ptr = self.clone_state.child_tidptr;
+            self.arch.libc(ptr).write::<arch::UInt, _>(cpu.mem(), child_pid)?;
+        }
+
+        if flags.contains(clone::Flags::SETTLS) {
+            match self.arch.reg_tls {
+                Some(reg) => cpu.write_var(reg, self.clone_state.tls_ptr),
+                None => tracing::warn!("CLONE_SETTLS is not supported for the current target"),
+            }
+        }
 
         if self.clone_state.new_sp != 0 {
             cpu.write_var(self.arch.reg_sp, self.clone_state.new_sp);
         }
+        self.reset_time_slice(cpu.i_count());
 
         // Return value for the child process is always 0
         Ok(0)
@@ -1247,12 +1956,100 @@ impl Kernel {
     fn switch_task<C: LinuxCpu>(&mut self, cpu: &mut C, reason: PauseReason) -> LinuxResult {
         self.process_manager.suspend(cpu, std::mem::take(&mut self.process), reason);
 
-        match self.process_manager.resume_next(cpu) {
-            Some(process) => {
-                self.process = process;
-                Ok(0)
+        match self.resume_next_task(cpu) {
+            true => Ok(0),
+            false => Err(VmExit::Halt.into()),
+        }
+    }
+
+    /// Resumes the next process that is ready to run, completing the syscall it was blocked in.
+    /// Returns `false` if there are no processes that can be resumed.
+    fn resume_next_task<C: LinuxCpu>(&mut self, cpu: &mut C) -> bool {
+        let Some((process, reason)) = self.process_manager.resume_next(cpu)
+        else {
+            return false;
+        };
+        self.process = process;
+        self.skip_syscall_result = true;
+        self.reset_time_slice(cpu.i_count());
+
+        match reason {
+            // The process was not in the middle of a syscall (or the result was already set), so
+            // the CPU state is already complete.
+            PauseReason::Switched | PauseReason::Preempted => {}
+            PauseReason::WaitFutex => match self.process.futex_wait.take() {
+                // The process was resumed without being woken, so the wait must have timed out.
+                Some(_) => self.arch.dynamic.set_error(cpu, errno::ETIMEDOUT),
+                None => self.arch.dynamic.set_result(cpu, 0),
+            },
+            PauseReason::WaitFile | PauseReason::WaitProcess | PauseReason::WaitSignal => {
+                self.arch.dynamic.set_result(cpu, 0)
             }
-            None => Err(VmExit::Halt.into()),
+        }
+        true
+    }
+
+    /// Starts a new time slice for the active process.
+    fn reset_time_slice(&mut self, icount: u64) {
+        self.preempt_at = match self.thread_quantum {
+            0 => u64::MAX,
+            _ if self.process_manager.parked.is_empty() => u64::MAX,
+            quantum => icount.saturating_add(quantum),
+        };
+    }
+
+    /// Switches to another process that is ready to run if the active process has used up its
+    /// time slice.
+    fn preempt(&mut self, cpu: &mut icicle_cpu::Cpu) {
+        if !self.process_manager.has_ready() {
+            self.reset_time_slice(cpu.icount);
+            return;
+        }
+
+        // Continue from the current instruction when the process is next scheduled.
+        let pc = cpu.read_pc();
+        cpu.set_next_pc(pc);
+
+        tracing::trace!("[pid={}] preempted at icount={}", self.process.pid, cpu.icount);
+        let process = std::mem::take(&mut self.process);
+        self.process_manager.suspend(cpu, process, PauseReason::Preempted);
+        let resumed = self.resume_next_task(cpu);
+        debug_assert!(resumed, "preempted process should always be ready");
+        cpu.resume();
+    }
+
+    /// Terminates the active thread, leaving the rest of its thread group running.
+    fn exit_thread<C: LinuxCpu>(&mut self, cpu: &mut C, status: u64) -> Option<VmExit> {
+        let pid = self.process.pid;
+        let tgid = self.process.tgid;
+        tracing::debug!("[pid={pid}] thread exited: {status}");
+        self.process.termination_reason = Some(TerminationReason::Exit(status));
+        self.process_tree.set_state(pid, ProcessState::Terminated(TerminationReason::Exit(status)));
+
+        // Wake up any thread waiting for this thread to exit (e.g. in `pthread_join`).
+        let tid_addr = self.process.clear_child_tid;
+        if tid_addr != 0 {
+            if let Err(e) = self.arch.libc(tid_addr).write::<arch::UInt, _>(cpu.mem(), 0) {
+                tracing::debug!("[pid={pid}] failed to clear child tid: {e:?}");
+            }
+            self.process_manager.wake_futex(Some(tgid), tid_addr, 1);
+        }
+
+        // Hand the address space and any listeners over to another thread in the group.
+        let listeners = std::mem::take(&mut self.process.listeners);
+        let mem = cpu.mem().take_virtual_mapping();
+        let sibling = self
+            .process_manager
+            .parked
+            .iter_mut()
+            .find(|x| x.process.tgid == tgid)
+            .expect("thread group has no other threads");
+        sibling.mem = Some(mem);
+        sibling.process.listeners.extend(listeners);
+
+        match self.resume_next_task(cpu) {
+            true => None,
+            false => Some(VmExit::Deadlock),
         }
     }
 
@@ -1263,6 +2060,14 @@ impl Kernel {
     ) -> Option<VmExit> {
         self.process.termination_reason = Some(reason);
         let pid = self.process.pid;
+        self.process_tree.set_state(pid, ProcessState::Terminated(reason));
+
+        // Terminating a process terminates every thread in its thread group.
+        for thread in self.process_manager.take_threads(self.process.tgid) {
+            let state = ProcessState::Terminated(reason);
+            self.process_tree.set_state(thread.process.pid, state);
+            self.process.listeners.extend(thread.process.listeners);
+        }
 
         if self.process.parent_pid == 0 {
             tracing::info!("root process {pid} terminated: {reason:?}");
@@ -1284,45 +2089,121 @@ impl Kernel {
         // @fixme: update parent pid of all child processes
 
         for listener_pid in &self.process.listeners {
-            self.process_manager.process_destroyed_event(*listener_pid, pid, reason);
+            let sigchld = self.arch.signals.sigchld;
+            self.process_manager.process_destroyed_event(*listener_pid, pid, reason, sigchld);
         }
 
-        match self.process_manager.resume_next(cpu) {
-            Some(process) => {
-                self.process = process;
-                None
-            }
-            None => Some(VmExit::Deadlock),
+        match self.resume_next_task(cpu) {
+            true => None,
+            false => Some(VmExit::Deadlock),
         }
     }
 
-    /// Remove the lowest pending signal, and run the appropriate signal handler
+    /// Remove the lowest pending signal that is not blocked, and run the appropriate signal
+    /// handler
     fn handle_pending_signal<C: LinuxCpu>(&mut self, cpu: &mut C) -> Option<VmExit> {
-        if self.process.pending_signals == 0 {
+        let deliverable = self.process.pending_signals & !self.process.signal_mask;
+        if deliverable == 0 {
             return None;
         }
 
         // Find the lowest pending signal and pop it.
-        let signal_idx = self.process.pending_signals.trailing_zeros() as u64;
+        let signal_idx = deliverable.trailing_zeros() as u64;
         self.process.pending_signals &= !(1 << signal_idx);
         let signal = signal_idx + 1;
 
-        match self.process.signal_handlers.get_action(signal) {
+        let action = self.process.signal_handlers.borrow().get_action(signal);
+        match action {
             SignalAction::Ignore => None,
             SignalAction::Terminate => self.destroy_process(cpu, TerminationReason::Killed(signal)),
             SignalAction::Handler(action) => {
-                if let Err(_) = self.arch.dynamic.setup_signal_frame(cpu, signal, &action) {
+                let frame = types::SignalFrame {
+                    signal,
+                    resume_addr: cpu.next_pc(),
+                    ..types::SignalFrame::default()
+                };
+                if let Err(_) = self.enter_signal_handler(cpu, frame, &action) {
                     return self.destroy_process(
                         cpu,
                         TerminationReason::Killed(sys::signal::SIGSEGV as u64),
                     );
                 }
-                cpu.set_next_pc(action.handler.value);
                 None
             }
         }
     }
 
+    /// Delivers the signal raised by the fault currently pending on the CPU (e.g. `SIGSEGV` for
+    /// an invalid memory access) to the active process.
+    ///
+    /// Faults are only delivered if the process has installed a handler for the signal, otherwise
+    /// the fault is left for the VM to report as a crash.
+    fn handle_fault(&mut self, cpu: &mut icicle_cpu::Cpu) -> Option<VmExit> {
+        use sys::signal::*;
+
+        let pc = cpu.read_pc();
+        let value = cpu.exception.value;
+        let (signal, code, addr) = match ExceptionCode::from_u32(cpu.exception.code) {
+            ExceptionCode::ReadUnmapped | ExceptionCode::WriteUnmapped => {
+                (SIGSEGV, SEGV_MAPERR, value)
+            }
+            ExceptionCode::ReadPerm | ExceptionCode::WritePerm | ExceptionCode::ExecViolation => {
+                (SIGSEGV, SEGV_ACCERR, value)
+            }
+            ExceptionCode::ReadUnaligned
+            | ExceptionCode::WriteUnaligned
+            | ExceptionCode::ExecUnaligned => (self.arch.signals.sigbus, BUS_ADRALN, value),
+            ExceptionCode::InvalidInstruction => (SIGILL, ILL_ILLOPC, pc),
+            ExceptionCode::DivisionException => (SIGFPE, FPE_INTDIV, pc),
+            _ => return None,
+        };
+        let signal = signal as u64;
+
+        // Like Linux, faults that occur while the signal is blocked (e.g. a fault inside of the
+        // handler) are not delivered.
+        if self.process.signal_mask & (1 << (signal - 1)) != 0 {
+            return None;
+        }
+        let action = self.process.signal_handlers.borrow().get_action(signal);
+        let SignalAction::Handler(action) = action
+        else {
+            return None;
+        };
+
+        let pid = self.process.pid;
+        tracing::debug!("[pid={pid}] delivering signal {signal} for fault at {pc:#x}");
+        let frame = types::SignalFrame { signal, code, addr, resume_addr: pc, saved_mask: 0 };
+        if let Err(e) = self.enter_signal_handler(cpu, frame, &action) {
+            tracing::warn!("failed to set up signal frame for fault at {pc:#x}: {e:?}");
+            return None;
+        }
+        cpu.resume();
+        None
+    }
+
+    /// Sets up the signal frame for `frame` and redirects execution to the handler.
+    fn enter_signal_handler<C: LinuxCpu>(
+        &mut self,
+        cpu: &mut C,
+        mut frame: types::SignalFrame,
+        action: &types::Sigaction,
+    ) -> MemResult<()> {
+        frame.saved_mask = self.process.signal_mask;
+        self.arch.dynamic.setup_signal_frame(cpu, &frame, action)?;
+
+        let bit = 1 << (frame.signal - 1);
+        self.process.signal_mask |= action.mask.value;
+        if action.flags.value & sys::signal::SA_NODEFER == 0 {
+            self.process.signal_mask |= bit;
+        }
+        if action.flags.value & sys::signal::SA_RESETHAND != 0 {
+            let mut handlers = self.process.signal_handlers.borrow_mut();
+            handlers.set_action(frame.signal, types::Sigaction::default());
+        }
+        cpu.set_next_pc(action.handler.value);
+        Ok(())
+    }
+
     pub fn init_ipc_perm(&self, key: u64, flags: u64) -> IpcPerm {
         let uid = self.process.uid;
         IpcPerm { key, uid, gid: uid, cuid: uid, cgid: uid, mode: flags & 0b111111111 }
@@ -1337,10 +2218,12 @@ impl Kernel {
         }
 
         self.buffer.clear();
-        match sys::syscall::handle_syscall(self, cpu, id) {
-            Ok(value) => self.arch.dynamic.set_result(cpu, value),
-            Err(LinuxError::Error(error)) => self.arch.dynamic.set_error(cpu, error),
-            Err(LinuxError::VmExit(exit)) => return Some(exit),
+        self.skip_syscall_result = false;
+        let result = sys::syscall::handle_syscall(self, cpu, id);
+        if !self.skip_syscall_result {
+            if let Some(exit) = self.arch.dynamic.set_return(cpu, result) {
+                return Some(exit);
+            }
         }
 
         // @fixme: this is used for tracking resumption from syscalls from timeouts, but this should
@@ -1453,7 +2336,6 @@ impl Kernel {
         self.process_manager.reset();
 
         self.buffer.clear();
-        self.random = Random::new(4);
         self.current_time = std::time::Duration::new(1600000000, 0);
 
         // @fixme: eventually handle resetting the VFS.
@@ -1486,6 +2368,25 @@ impl Kernel {
 }
 
 impl ElfLoader for Kernel {
+    fn library_paths(&self) -> &[Vec<u8>] {
+        &self.library_paths
+    }
+
+    fn builtin_symbols(&mut self, cpu: &mut icicle_cpu::Cpu) -> Vec<(Vec<u8>, u64)> {
+        // Calls to `__tls_get_addr` are caught by mapping the stub as non-executable memory.
+        let layout = AllocLayout::from_size_align(sys::PAGE_SIZE, sys::PAGE_SIZE);
+        let Ok(stub) = self.alloc(&mut cpu.mem, layout, perm::READ)
+        else {
+            return vec![];
+        };
+        self.process.mapping.insert(stub, MemMappedFile {
+            path: b"(ld stubs)".to_vec(),
+            end: stub + sys::PAGE_SIZE,
+        });
+        self.process.image.tls_get_addr = stub;
+        vec![(b"__tls_get_addr".to_vec(), stub)]
+    }
+
     fn read_file(&mut self, path: &[u8]) -> Result<Vec<u8>, String> {
         tracing::info!("loading: {}", path.escape_ascii());
         self.vfs
@@ -1494,62 +2395,56 @@ impl ElfLoader for Kernel {
     }
 }
 
+/// Kernel state that is saved and restored with VM snapshots.
+struct KernelSnapshot {
+    process: Process,
+    process_tree: ProcessTree,
+    process_manager: ProcessManager,
+    preempt_at: u64,
+}
+
 impl icicle_cpu::Environment for Kernel {
     fn load(&mut self, cpu: &mut icicle_cpu::Cpu, path: &[u8]) -> Result<(), String> {
         tracing::info!("Clearing memory");
         cpu.mem.reset_virtual();
         cpu.reset();
 
-        self.process.mapping.clear();
-
-        tracing::info!("Reserving null page");
-        cpu.mem.map_memory_len(0x0, sys::PAGE_SIZE, Mapping { perm: perm::NONE, value: 0xAA });
-        self.process
-            .mapping
-            .insert(0x0, MemMappedFile { path: b"(null page)".to_vec(), end: sys::PAGE_SIZE });
-
-        let metadata = self.load_elf(cpu, path)?;
-
-        // Keep track of data we just mapped from the ELF file.
-        self.process.mapping.insert(metadata.binary.base_ptr, MemMappedFile {
-            path: path.to_vec(),
-            end: metadata.binary.base_ptr + metadata.binary.length,
-        });
-        if let Some(interpreter) = metadata.interpreter.as_ref() {
-            self.process.mapping.insert(interpreter.base_ptr, MemMappedFile {
-                path: metadata.debug_info.dynamic_linker.clone(),
-                end: interpreter.base_ptr + interpreter.length,
-            });
-        }
-
-        self.process.debug_info = Some(metadata.debug_info);
-
-        let base_ptr =
-            metadata.interpreter.as_ref().map_or(metadata.binary.base_ptr, |x| x.base_ptr);
-
-        self.process.image.base_ptr = base_ptr;
-        self.process.image.phdr_ptr = metadata.binary.phdr_ptr;
-        self.process.image.phdr_num = metadata.binary.phdr_num;
-        self.process.image.entry_ptr = metadata.binary.entry_ptr;
-        self.process.image.relocation_offset = metadata.binary.offset;
-        self.process.image.start_addr = metadata.binary.base_ptr;
-        self.process.image.end_addr = metadata.binary.base_ptr + metadata.binary.length;
-
-        let entry =
-            metadata.interpreter.as_ref().map_or(metadata.binary.entry_ptr, |x| x.entry_ptr);
-
-        tracing::info!("Setting instruction pointer to: {entry:#0x}");
-        (cpu.arch.on_boot)(cpu, entry);
-
-        self.spawn(cpu, path).map_err(|e| format!("Failed to initialize environment: {e}"))?;
-
+        self.process_tree.reset(self.process.pid, path);
+        self.load_image(cpu, path)?;
+        self.open_std_streams();
         Ok(())
     }
 
     fn handle_exception(&mut self, cpu: &mut icicle_cpu::Cpu) -> Option<VmExit> {
         self.tick(cpu);
         match ExceptionCode::from_u32(cpu.exception.code) {
-            ExceptionCode::Syscall => self.handle_syscall(cpu),
+            ExceptionCode::Syscall => {
+                let exit = self.handle_syscall(cpu);
+                match self.pending_exec.take() {
+                    Some(path) => self.exec(cpu, &path).or(exit),
+                    None => exit,
+                }
+            }
+            ExceptionCode::InstructionLimit if cpu.icount >= self.preempt_at => {
+                self.preempt(cpu);
+                None
+            }
+            ExceptionCode::ExecViolation
+                if self.process.image.tls_get_addr != 0
+                    && cpu.exception.value == self.process.image.tls_get_addr =>
+            {
+                self.tls_get_addr(cpu)
+            }
+            ExceptionCode::ReadUnmapped
+            | ExceptionCode::WriteUnmapped
+            | ExceptionCode::ReadPerm
+            | ExceptionCode::WritePerm
+            | ExceptionCode::ExecViolation
+            | ExceptionCode::ReadUnaligned
+            | ExceptionCode::WriteUnaligned
+            | ExceptionCode::ExecUnaligned
+            | ExceptionCode::InvalidInstruction
+            | ExceptionCode::DivisionException => self.handle_fault(cpu),
             ExceptionCode::Environment => todo!(),
             _ => None,
         }
@@ -1557,15 +2452,33 @@ impl icicle_cpu::Environment for Kernel {
 
     fn snapshot(&mut self) -> Box<dyn std::any::Any> {
         // @fixme: add support for snapshotting additional kernel state.
-        Box::new(self.process.clone())
+        let mut cloner = SharedCloner::default();
+        Box::new(KernelSnapshot {
+            process: self.process.copy(&mut cloner),
+            process_tree: self.process_tree.clone(),
+            process_manager: self.process_manager.copy(&mut cloner),
+            preempt_at: self.preempt_at,
+        })
     }
 
     fn restore(&mut self, snapshot: &Box<dyn std::any::Any>) {
-        self.process = snapshot.downcast_ref::<Process>().unwrap().clone();
+        let snapshot = snapshot.downcast_ref::<KernelSnapshot>().unwrap();
+        let mut cloner = SharedCloner::default();
+        self.process = snapshot.process.copy(&mut cloner);
+        self.process_tree.clone_from(&snapshot.process_tree);
+        self.process_manager = snapshot.process_manager.copy(&mut cloner);
+        self.preempt_at = snapshot.preempt_at;
+    }
+
+    fn save_state(&mut self) -> Result<Vec<u8>, String> {
+        // Open files, sockets and parked processes hold host state that cannot be serialized.
+        Err("the Linux environment does not support saving state (open files and processes \
+             cannot be serialized)"
+            .into())
     }
 
     fn next_timer(&self) -> u64 {
-        u64::MAX
+        self.preempt_at
     }
 
     fn symbolize_addr(&mut self, cpu: &mut icicle_cpu::Cpu, addr: u64) -> Option<SourceLocation> {
@@ -0,0 +1,152 @@
+//! Tracks the relationship between all the processes created by the guest.
+//!
+//! The process manager only keeps track of processes that are still alive, which is not enough to
+//! explain what happened in targets that shell out or daemonize (e.g. a crash in a grandchild
+//! process after the intermediate process has already exited). The process tree keeps a record of
+//! every process spawned since the initial program was loaded.
+//!
+//! Note: all processes are emulated within the same VM, so any coverage collected by the VM is
+//! already aggregated across the entire tree.
+
+use std::collections::BTreeMap;
+
+use bstr::ByteSlice;
+
+use crate::TerminationReason;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProcessState {
+    /// The process is running (or parked by the process manager).
+    Running,
+
+    /// The process was dropped by the fork policy (see [crate::FollowFork]), and is no longer
+    /// being emulated.
+    Abandoned,
+
+    /// The process has terminated.
+    Terminated(TerminationReason),
+}
+
+#[derive(Clone, Debug)]
+pub struct ProcessNode {
+    pub pid: u64,
+
+    /// The pid of the process that created this process, or 0 for the initial process.
+    pub parent_pid: u64,
+
+    /// Whether this process shares its address space with its parent.
+    pub is_thread: bool,
+
+    /// The path of the binary running in the process, updated after every `execve`.
+    pub image: Vec<u8>,
+
+    /// The number of times the process has called `execve`.
+    pub exec_count: u64,
+
+    /// The pids of all processes created by this process.
+    pub children: Vec<u64>,
+
+    pub state: ProcessState,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ProcessTree {
+    nodes: BTreeMap<u64, ProcessNode>,
+}
+
+impl ProcessTree {
+    /// Clears the tree, and adds `pid` as the initial process running `image`.
+    pub fn reset(&mut self, pid: u64, image: &[u8]) {
+        self.nodes.clear();
+        self.nodes.insert(pid, ProcessNode {
+            pid,
+            parent_pid: 0,
+            is_thread: false,
+            image: image.to_vec(),
+            exec_count: 0,
+            children: vec![],
+            state: ProcessState::Running,
+        });
+    }
+
+    pub fn get(&self, pid: u64) -> Option<&ProcessNode> {
+        self.nodes.get(&pid)
+    }
+
+    /// Iterates over all processes in order of their pid.
+    pub fn iter(&self) -> impl Iterator<Item = &ProcessNode> {
+        self.nodes.values()
+    }
+
+    /// Returns the number of processes in the tree that have not terminated.
+    pub fn running(&self) -> usize {
+        self.nodes.values().filter(|x| x.state == ProcessState::Running).count()
+    }
+
+    /// Records that `parent_pid` created a new process with `pid`.
+    pub fn fork(&mut self, parent_pid: u64, pid: u64, is_thread: bool) {
+        let image = match self.nodes.get_mut(&parent_pid) {
+            Some(parent) => {
+                parent.children.push(pid);
+                parent.image.clone()
+            }
+            None => vec![],
+        };
+        self.nodes.insert(pid, ProcessNode {
+            pid,
+            parent_pid,
+            is_thread,
+            image,
+            exec_count: 0,
+            children: vec![],
+            state: ProcessState::Running,
+        });
+    }
+
+    /// Records that `pid` replaced its image with the binary at `path`.
+    pub fn exec(&mut self, pid: u64, path: &[u8]) {
+        if let Some(node) = self.nodes.get_mut(&pid) {
+            node.image = path.to_vec();
+            node.exec_count += 1;
+        }
+    }
+
+    pub fn set_state(&mut self, pid: u64, state: ProcessState) {
+        if let Some(node) = self.nodes.get_mut(&pid) {
+            node.state = state;
+        }
+    }
+
+    /// Returns the chain of processes starting from `pid` up to the initial process.
+    pub fn ancestors(&self, pid: u64) -> Vec<&ProcessNode> {
+        let mut chain = vec![];
+        let mut next = self.nodes.get(&pid);
+        while let Some(node) = next {
+            // Guard against cycles caused by pid reuse.
+            if chain.len() > self.nodes.len() {
+                break;
+            }
+            chain.push(node);
+            next = self.nodes.get(&node.parent_pid);
+        }
+        chain
+    }
+
+    /// Formats the ancestry of `pid` for crash reports, e.g. `sh[3334] <- target[3333]`.
+    pub fn describe(&self, pid: u64) -> String {
+        let chain = self.ancestors(pid);
+        if chain.is_empty() {
+            return format!("[{pid}]");
+        }
+
+        let mut out = String::new();
+        for (i, node) in chain.iter().enumerate() {
+            if i != 0 {
+                out.push_str(" <- ");
+            }
+            let name = node.image.rsplit(|&b| b == b'/').next().unwrap_or(&node.image);
+            out.push_str(&format!("{}[{}]", name.as_bstr(), node.pid));
+        }
+        out
+    }
+}
@@ -0,0 +1,102 @@
+//! Allows harnesses to intercept syscalls with custom handlers.
+//!
+//! Hooks are registered for a syscall number (as used by the guest architecture) and are called
+//! before the built-in handler. A hook can inspect or modify the arguments of the syscall, then
+//! either fall through to the built-in handler (which sees the modified arguments), or skip it and
+//! return a value (or error) directly to the guest. This is useful for stubbing out syscalls that
+//! are not supported by the emulator (e.g. device specific `ioctl` requests), or for controlling
+//! values such as the time returned by `clock_gettime`.
+
+use crate::{Kernel, LinuxMmu, LinuxResult};
+
+/// The number of syscall arguments passed to hooks.
+pub const MAX_ARGS: usize = 6;
+
+pub struct SyscallHookCtx<'a> {
+    pub kernel: &'a mut Kernel,
+
+    /// The memory of the current process, e.g. for reading or writing buffers passed to the
+    /// syscall.
+    pub mem: &'a mut dyn LinuxMmu,
+
+    /// The number of the syscall (as used by the guest architecture).
+    pub id: u64,
+
+    /// The name of the syscall.
+    pub name: &'static str,
+
+    /// The arguments passed to the syscall. Any modifications are passed to the built-in handler if
+    /// the hook returns [SyscallAction::Continue].
+    pub args: [u64; MAX_ARGS],
+
+    /// The instruction count when the syscall was executed.
+    pub i_count: u64,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum SyscallAction {
+    /// Run the built-in handler for the syscall.
+    Continue,
+
+    /// Skip the built-in handler, returning `result` to the guest.
+    Return(LinuxResult),
+}
+
+pub type SyscallHook = Box<dyn FnMut(&mut SyscallHookCtx) -> SyscallAction>;
+
+impl Kernel {
+    /// Registers `hook` to be called whenever the guest executes syscall `id`, replacing any
+    /// existing hook for the syscall.
+    pub fn hook_syscall<F>(&mut self, id: u64, hook: F)
+    where
+        F: FnMut(&mut SyscallHookCtx) -> SyscallAction + 'static,
+    {
+        self.syscall_hooks.insert(id, Box::new(hook));
+    }
+
+    /// Registers `hook` for the syscall called `name`, returning `false` if the syscall is not
+    /// defined for the current architecture.
+    pub fn hook_syscall_by_name<F>(&mut self, name: &str, hook: F) -> bool
+    where
+        F: FnMut(&mut SyscallHookCtx) -> SyscallAction + 'static,
+    {
+        match self.syscall_id(name) {
+            Some(id) => {
+                self.hook_syscall(id, hook);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Removes the hook registered for syscall `id`, returning whether a hook was registered.
+    ///
+    /// Hooks can remove themselves while they are running.
+    pub fn remove_syscall_hook(&mut self, id: u64) -> bool {
+        let removed = self.syscall_hooks.remove(&id).is_some();
+        if self.running_syscall_hook == Some(id) {
+            self.running_syscall_hook = None;
+            return true;
+        }
+        removed
+    }
+
+    /// Marks the hook for syscall `id` as running. The hook is removed from
+    /// [Kernel::syscall_hooks] while it runs, so this is used to track whether the hook removed
+    /// itself.
+    pub(crate) fn start_syscall_hook(&mut self, id: u64) {
+        self.running_syscall_hook = Some(id);
+    }
+
+    /// Returns whether the hook that was running for syscall `id` should be kept, i.e. it was not
+    /// removed while it was running.
+    pub(crate) fn finish_syscall_hook(&mut self, id: u64) -> bool {
+        self.running_syscall_hook.take() == Some(id)
+    }
+
+    /// Returns the number of the syscall called `name` for the current architecture.
+    pub fn syscall_id(&self, name: &str) -> Option<u64> {
+        let id = self.arch.dynamic.syscall_names().iter().position(|x| *x == name)?;
+        Some((id + self.arch.dynamic.syscall_offset()) as u64)
+    }
+}
@@ -1,3 +1,5 @@
+pub mod hook;
+pub mod strace;
 pub mod syscall;
 
 mod auxv;
@@ -70,12 +72,54 @@ pub mod mmem {
     pub const MREMAP_DONTUNMAP: u64 = 0x4;
 }
 
+pub mod rlimit {
+    pub const RLIM_INFINITY: u64 = u64::MAX;
+
+    pub const RLIMIT_NPROC: u64 = 6;
+    pub const RLIMIT_NOFILE: u64 = 7;
+
+    pub const RLIMIT_NOFILE_MIPS: u64 = 5;
+    pub const RLIMIT_NPROC_MIPS: u64 = 8;
+}
+
 pub mod signal {
+    use target_lexicon::Architecture;
+
+    pub const SIGILL: u8 = 4;
     pub const SIGABRT: u8 = 6;
+    pub const SIGFPE: u8 = 8;
     pub const SIGKILL: u8 = 9;
     pub const SIGSEGV: u8 = 11;
     pub const SIGALRM: u8 = 14;
-    pub const SIGCHLD: u8 = 17;
+
+    /// Signal numbers that are different on MIPS.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct Signals {
+        pub sigbus: u8,
+        pub sigchld: u8,
+        pub sigstop: u8,
+    }
+
+    impl Signals {
+        pub fn for_arch(arch: Architecture) -> Self {
+            match arch {
+                Architecture::Mips32(_) | Architecture::Mips64(_) => {
+                    Self { sigbus: 10, sigchld: 18, sigstop: 23 }
+                }
+                _ => Self { sigbus: 7, sigchld: 17, sigstop: 19 },
+            }
+        }
+    }
+
+    pub const SA_NODEFER: u64 = 0x40000000;
+    pub const SA_RESETHAND: u64 = 0x80000000;
+
+    /// Values of `si_code` for signals raised by faults.
+    pub const ILL_ILLOPC: i32 = 1;
+    pub const FPE_INTDIV: i32 = 1;
+    pub const SEGV_MAPERR: i32 = 1;
+    pub const SEGV_ACCERR: i32 = 2;
+    pub const BUS_ADRALN: i32 = 1;
 }
 
 pub mod poll {
@@ -0,0 +1,270 @@
+//! Syscall tracing with output compatible with `strace`.
+
+use std::io::Write;
+
+use crate::{errno, Kernel, LinuxCpu, LinuxError, LinuxMmu, LinuxResult};
+
+/// The maximum number of bytes of a string or buffer to include in the trace output (matches the
+/// default `strace` string limit).
+const MAX_STRING_LEN: usize = 32;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TraceFormat {
+    /// `strace`-like text output, e.g. `openat(AT_FDCWD, "/etc/passwd", 0) = 3`.
+    Text,
+
+    /// One JSON object per syscall.
+    Json,
+}
+
+/// Describes how a syscall argument should be decoded.
+#[derive(Clone, Copy)]
+enum Arg {
+    /// A signed integer.
+    Int,
+    /// An integer displayed in hex (e.g. flags).
+    Hex,
+    /// An integer displayed in octal (e.g. file modes).
+    Oct,
+    /// A pointer.
+    Ptr,
+    /// A file descriptor (handles `AT_FDCWD`).
+    Fd,
+    /// A pointer to a null terminated string.
+    Str,
+    /// A pointer to a buffer with a length given by the argument at the specified index.
+    Buf(usize),
+    /// A pointer to a buffer filled in by the syscall, with a length given by the return value.
+    OutBuf,
+}
+
+/// Gets the argument types for syscalls that we know how to decode.
+fn arg_types(name: &str) -> &'static [Arg] {
+    use Arg::*;
+    match name {
+        "read" => &[Fd, OutBuf, Int],
+        "write" => &[Fd, Buf(2), Int],
+        "pread64" => &[Fd, OutBuf, Int, Int],
+        "pwrite64" => &[Fd, Buf(2), Int, Int],
+        "open" => &[Str, Hex, Oct],
+        "openat" => &[Fd, Str, Hex, Oct],
+        "close" | "dup" | "fsync" | "fchdir" => &[Fd],
+        "dup2" => &[Fd, Fd],
+        "dup3" => &[Fd, Fd, Hex],
+        "fstat" | "fstat64" => &[Fd, Ptr],
+        "stat" | "lstat" | "stat64" | "lstat64" | "statfs" => &[Str, Ptr],
+        "newfstatat" | "fstatat64" => &[Fd, Str, Ptr, Hex],
+        "statx" => &[Fd, Str, Hex, Hex, Ptr],
+        "access" => &[Str, Int],
+        "faccessat" | "faccessat2" => &[Fd, Str, Int, Hex],
+        "chdir" | "unlink" | "rmdir" | "chroot" => &[Str],
+        "mkdir" | "chmod" | "creat" => &[Str, Oct],
+        "mkdirat" | "fchmodat" => &[Fd, Str, Oct],
+        "unlinkat" => &[Fd, Str, Hex],
+        "rename" | "link" | "symlink" => &[Str, Str],
+        "renameat" | "renameat2" => &[Fd, Str, Fd, Str, Hex],
+        "readlink" => &[Str, OutBuf, Int],
+        "readlinkat" => &[Fd, Str, OutBuf, Int],
+        "execve" => &[Str, Ptr, Ptr],
+        "lseek" => &[Fd, Int, Int],
+        "ioctl" => &[Fd, Hex, Ptr],
+        "fcntl" | "fcntl64" => &[Fd, Int, Hex],
+        "getdents" | "getdents64" => &[Fd, Ptr, Int],
+        "mmap" | "mmap2" => &[Ptr, Int, Hex, Hex, Fd, Hex],
+        "munmap" => &[Ptr, Int],
+        "mprotect" => &[Ptr, Int, Hex],
+        "mremap" => &[Ptr, Int, Int, Hex, Ptr],
+        "brk" => &[Ptr],
+        "exit" | "exit_group" => &[Int],
+        _ => &[],
+    }
+}
+
+/// Returns whether the result of the syscall should be displayed as an address.
+fn returns_address(name: &str) -> bool {
+    matches!(name, "mmap" | "mmap2" | "mremap" | "brk" | "shmat")
+}
+
+/// The details of a syscall that are captured before the syscall is executed.
+#[derive(Clone, Copy)]
+pub(crate) struct TracedCall {
+    pub name: &'static str,
+    pub pid: u64,
+    pub i_count: u64,
+}
+
+/// A syscall in the JSON trace format.
+#[derive(serde::Serialize)]
+struct JsonEntry<'a> {
+    pid: u64,
+    icount: u64,
+    nr: u64,
+    syscall: &'a str,
+    args: &'a [String],
+    raw_args: &'a [u64],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ret: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    errno: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    exit: Option<String>,
+}
+
+pub struct SyscallTracer {
+    pub format: TraceFormat,
+    output: Box<dyn Write>,
+}
+
+impl SyscallTracer {
+    pub fn new(format: TraceFormat, output: impl Write + 'static) -> Self {
+        Self { format, output: Box::new(output) }
+    }
+
+    /// Creates a new tracer that writes to stderr (the same as `strace`).
+    pub fn stderr(format: TraceFormat) -> Self {
+        Self::new(format, std::io::stderr())
+    }
+
+    /// Writes a trace entry for a syscall with (raw) arguments `args` (where `args[0]` is the
+    /// syscall number).
+    ///
+    /// `call` describes the syscall at the time it was executed, since the handler may switch to a
+    /// different process (e.g. `exit` or `execve`).
+    pub(crate) fn trace<C: LinuxCpu>(
+        &mut self,
+        kernel: &mut Kernel,
+        cpu: &mut C,
+        call: TracedCall,
+        args: &[u64],
+        result: LinuxResult,
+    ) {
+        let TracedCall { name, pid, i_count } = call;
+        let ptr_bytes = kernel.arch.libc(0).data_model.pointer_width().bytes();
+        let types = arg_types(name);
+
+        let decoded: Vec<String> = args[1..]
+            .iter()
+            .enumerate()
+            .map(|(i, &value)| {
+                let kind = types.get(i).copied().unwrap_or(Arg::Hex);
+                decode_arg(kernel, cpu, kind, value, &args[1..], result, ptr_bytes)
+            })
+            .collect();
+
+        let err = match self.format {
+            TraceFormat::Text => {
+                let ret = match result {
+                    Ok(value) if returns_address(name) => format!("{value:#x}"),
+                    Ok(value) => format!("{}", to_signed(value, ptr_bytes)),
+                    Err(LinuxError::Error(e)) => format!("-1 {} ({e})", errno::errno_str(e)),
+                    Err(LinuxError::VmExit(_)) => "?".into(),
+                };
+                writeln!(self.output, "[pid {pid}] {name}({}) = {ret}", decoded.join(", "))
+            }
+            TraceFormat::Json => {
+                let (ret, errno, exit) = match result {
+                    Ok(value) => (to_signed(value, ptr_bytes), None, None),
+                    Err(LinuxError::Error(e)) => (-1, Some(errno::errno_str(e)), None),
+                    Err(LinuxError::VmExit(exit)) => (-1, None, Some(format!("{exit:?}"))),
+                };
+                let entry = JsonEntry {
+                    pid,
+                    icount: i_count,
+                    nr: args[0],
+                    syscall: name,
+                    args: &decoded,
+                    raw_args: &args[1..],
+                    ret: exit.is_none().then_some(ret),
+                    errno,
+                    exit,
+                };
+                serde_json::to_writer(&mut self.output, &entry)
+                    .map_err(std::io::Error::from)
+                    .and_then(|_| writeln!(self.output))
+            }
+        };
+
+        if let Err(e) = err {
+            tracing::error!("failed to write syscall trace: {e}");
+        }
+    }
+}
+
+fn to_signed(value: u64, ptr_bytes: u8) -> i64 {
+    match ptr_bytes {
+        4 => value as u32 as i32 as i64,
+        _ => value as i64,
+    }
+}
+
+fn decode_arg<C: LinuxCpu>(
+    kernel: &mut Kernel,
+    cpu: &mut C,
+    kind: Arg,
+    value: u64,
+    args: &[u64],
+    result: LinuxResult,
+    ptr_bytes: u8,
+) -> String {
+    const AT_FDCWD: i64 = -100;
+
+    let read_buf = |cpu: &mut C, len: u64| {
+        let mut buf = vec![0; (len as usize).min(MAX_STRING_LEN)];
+        cpu.mem().read_bytes(value, &mut buf).ok()?;
+        Some(format_bytes(&buf, len as usize > MAX_STRING_LEN))
+    };
+
+    match kind {
+        Arg::Int => to_signed(value, ptr_bytes).to_string(),
+        Arg::Hex => format!("{value:#x}"),
+        Arg::Oct => format!("{value:#o}"),
+        Arg::Ptr if value == 0 => "NULL".into(),
+        Arg::Ptr => format!("{value:#x}"),
+        Arg::Fd => match to_signed(value, ptr_bytes) {
+            AT_FDCWD => "AT_FDCWD".into(),
+            fd => fd.to_string(),
+        },
+        Arg::Str if value == 0 => "NULL".into(),
+        Arg::Str => {
+            let mut buf = vec![];
+            match kernel.arch.libc(value).read_cstr(cpu.mem(), &mut buf) {
+                Ok(s) => format_bytes(&s[..s.len().min(MAX_STRING_LEN)], s.len() > MAX_STRING_LEN),
+                Err(_) => format!("{value:#x}"),
+            }
+        }
+        Arg::Buf(len) => read_buf(cpu, args[len]).unwrap_or_else(|| format!("{value:#x}")),
+        Arg::OutBuf => match result {
+            Ok(len) => read_buf(cpu, len).unwrap_or_else(|| format!("{value:#x}")),
+            Err(_) => format!("{value:#x}"),
+        },
+    }
+}
+
+/// Formats `bytes` as a C string literal using the same escaping rules as `strace`.
+fn format_bytes(bytes: &[u8], truncated: bool) -> String {
+    let mut out = String::from("\"");
+    for (i, &byte) in bytes.iter().enumerate() {
+        match byte {
+            b'"' => out.push_str("\\\""),
+            b'\\' => out.push_str("\\\\"),
+            b'\n' => out.push_str("\\n"),
+            b'\t' => out.push_str("\\t"),
+            b'\r' => out.push_str("\\r"),
+            0x0b => out.push_str("\\v"),
+            0x0c => out.push_str("\\f"),
+            0x20..=0x7e => out.push(byte as char),
+            _ => {
+                // Octal escapes need to be padded if the next character is an octal digit.
+                match bytes.get(i + 1) {
+                    Some(b'0'..=b'7') => out.push_str(&format!("\\{byte:03o}")),
+                    _ => out.push_str(&format!("\\{byte:o}")),
+                }
+            }
+        }
+    }
+    out.push('"');
+    if truncated {
+        out.push_str("...");
+    }
+    out
+}
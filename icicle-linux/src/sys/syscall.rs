@@ -128,8 +128,25 @@ where
     C: LinuxCpu,
     F: FnOnce(&mut Ctx<C>, [u64; N]) -> LinuxResult,
 {
-    let args: [u64; N] = ctx.kernel.arch.dynamic.get_args(ctx.cpu)?;
-    let result = handler(ctx, args);
+    let mut args: [u64; N] = ctx.kernel.arch.dynamic.get_args(ctx.cpu)?;
+
+    // Note: the handler may switch to a different process (e.g. if the syscall blocks), in which
+    // case the syscall is left without a result in the history of the original process.
+    let pid = ctx.kernel.process.pid;
+    let name = ctx.kernel.arch.get_syscall_name(ctx.cpu);
+    let i_count = ctx.cpu.i_count();
+    let hook_result = run_syscall_hook(ctx, name, i_count, &mut args);
+
+    // Recorded after the hook runs, so the history contains any arguments modified by the hook.
+    ctx.kernel.process.syscall_history.push(name, args.get(1..).unwrap_or_default(), i_count);
+
+    let result = match hook_result {
+        Some(result) => result,
+        None => handler(ctx, args),
+    };
+    if ctx.kernel.process.pid == pid {
+        ctx.kernel.process.syscall_history.set_result(result);
+    }
 
     tracing::debug!("{}", SyscallFormatter {
         src: CallSource::new(ctx.cpu, ctx.kernel),
@@ -139,9 +156,75 @@ where
         trace_i_count: ctx.kernel.trace_i_count,
     });
 
+    if let Some(mut tracer) = ctx.kernel.syscall_tracer.take() {
+        // The caller is parked while the new process runs (which sees a result of 0), so trace the
+        // result that the caller sees when it is resumed instead.
+        let traced_result = match result {
+            Ok(0)
+                if ctx.kernel.process.pid != pid
+                    && matches!(name, "clone" | "clone3" | "fork" | "vfork") =>
+            {
+                Ok(ctx.kernel.process.pid)
+            }
+            _ => result,
+        };
+        let call = sys::strace::TracedCall { name, pid, i_count };
+        tracer.trace(ctx.kernel, ctx.cpu, call, &args, traced_result);
+        ctx.kernel.syscall_tracer = Some(tracer);
+    }
+
     result
 }
 
+/// Calls the hook registered for the current syscall (if any), copying the arguments modified by
+/// the hook to `args`. Returns the result if the hook skips the built-in handler.
+fn run_syscall_hook<C: LinuxCpu, const N: usize>(
+    ctx: &mut Ctx<C>,
+    name: &'static str,
+    i_count: u64,
+    args: &mut [u64; N],
+) -> Option<LinuxResult> {
+    use sys::hook::{SyscallAction, SyscallHookCtx, MAX_ARGS};
+
+    if ctx.kernel.syscall_hooks.is_empty() {
+        return None;
+    }
+    let id = args[0];
+    let mut hook = ctx.kernel.syscall_hooks.remove(&id)?;
+
+    // Hooks always see all the arguments, even for syscalls that the built-in handler ignores.
+    let mut hook_args = [0; MAX_ARGS];
+    for (i, arg) in hook_args.iter_mut().enumerate() {
+        *arg = ctx.get_arg(i + 1).unwrap_or(0);
+    }
+
+    ctx.kernel.start_syscall_hook(id);
+    let mut hook_ctx = SyscallHookCtx {
+        kernel: &mut *ctx.kernel,
+        mem: ctx.cpu.mem(),
+        id,
+        name,
+        args: hook_args,
+        i_count,
+    };
+    let action = hook(&mut hook_ctx);
+    let hook_args = hook_ctx.args;
+
+    // Reinsert the hook unless it removed itself, keeping any hook that was registered for the
+    // syscall while the hook was running.
+    if ctx.kernel.finish_syscall_hook(id) {
+        ctx.kernel.syscall_hooks.entry(id).or_insert(hook);
+    }
+
+    for (arg, value) in args.iter_mut().skip(1).zip(hook_args) {
+        *arg = value;
+    }
+    match action {
+        SyscallAction::Continue => None,
+        SyscallAction::Return(result) => Some(result),
+    }
+}
+
 struct CallSource<'a> {
     #[allow(unused)]
     id: u64,
@@ -178,7 +261,7 @@ impl std::fmt::Display for SyscallFormatter<'_> {
     }
 }
 
-struct FmtResult(LinuxResult);
+pub(crate) struct FmtResult(pub LinuxResult);
 
 impl std::fmt::Display for FmtResult {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -289,8 +372,12 @@ pub fn open<C: LinuxCpu>(ctx: &mut Ctx<C>, pathname: u64, flags: u64, _mode: u64
 
     let path_buf: &[u8] =
         ctx.kernel.arch.libc(pathname).read_cstr(ctx.cpu.mem(), &mut ctx.kernel.buffer)?;
+    let cloexec = flags.contains(fs::OpenFlags::O_CLOEXEC);
     let file = ctx.kernel.vfs.open_at(&ctx.kernel.process.cwd(), path_buf, flags)?;
-    let fd = ctx.kernel.process.file_table.add(file);
+    if cloexec {
+        file.borrow_mut().flags |= fs::FD_CLOEXEC;
+    }
+    let fd = ctx.kernel.process.file_table.borrow_mut().add(file)?;
     tracing::trace!("opened: {} as fd={}", path_buf.as_bstr(), fd);
     Ok(fd)
 }
@@ -313,7 +400,7 @@ pub fn openat<C: LinuxCpu>(
 }
 
 pub fn close<C: LinuxCpu>(ctx: &mut Ctx<C>, fd: u64) -> LinuxResult {
-    ctx.kernel.process.file_table.close(&mut ctx.kernel.process_manager, fd)?;
+    ctx.kernel.process.file_table.borrow_mut().close(&mut ctx.kernel.process_manager, fd)?;
     Ok(0)
 }
 
@@ -337,17 +424,17 @@ pub fn fadvise64<C: LinuxCpu>(
 
 pub fn dup<C: LinuxCpu>(ctx: &mut Ctx<C>, oldfd: u64) -> LinuxResult {
     // @fixme: new file descriptor should not share flags.
-    let file = ctx.kernel.process.file_table.get(&mut ctx.kernel.process_manager, oldfd)?;
-    Ok(ctx.kernel.process.file_table.add(file))
+    let file = ctx.kernel.get_file(oldfd)?;
+    Ok(ctx.kernel.process.file_table.borrow_mut().add(file)?)
 }
 
 pub fn dup2<C: LinuxCpu>(ctx: &mut Ctx<C>, oldfd: u64, newfd: u64) -> LinuxResult {
     // @fixme: new file descriptor should not share flags.
-    let file = ctx.kernel.process.file_table.get(&mut ctx.kernel.process_manager, oldfd)?;
+    let file = ctx.kernel.get_file(oldfd)?;
     if oldfd == newfd {
         return Ok(newfd);
     }
-    ctx.kernel.process.file_table.set(&mut ctx.kernel.process_manager, newfd, file);
+    ctx.kernel.process.file_table.borrow_mut().set(&mut ctx.kernel.process_manager, newfd, file)?;
     Ok(newfd)
 }
 
@@ -361,11 +448,11 @@ pub fn pipe_m<C: LinuxCpu>(ctx: &mut Ctx<C>) -> LinuxResult {
 
     let fd0 = {
         let file = ctx.kernel.vfs.pipefs.alloc_file(inode.clone())?;
-        ctx.kernel.process.file_table.add(file)
+        ctx.kernel.process.file_table.borrow_mut().add(file)?
     };
     let fd1 = {
         let file = ctx.kernel.vfs.pipefs.alloc_file(inode)?;
-        ctx.kernel.process.file_table.add(file)
+        ctx.kernel.process.file_table.borrow_mut().add(file)?
     };
 
     // This has a special calling convention on mips.
@@ -378,7 +465,7 @@ pub fn pipe_m<C: LinuxCpu>(ctx: &mut Ctx<C>) -> LinuxResult {
 pub fn socket<C: LinuxCpu>(ctx: &mut Ctx<C>, domain: u64, kind: u64, protocol: u64) -> LinuxResult {
     let inode = ctx.kernel.vfs.sockfs.create_socket(domain, kind, protocol)?;
     let file = ctx.kernel.vfs.sockfs.alloc_file(inode)?;
-    let fd = ctx.kernel.process.file_table.add(file);
+    let fd = ctx.kernel.process.file_table.borrow_mut().add(file)?;
     Ok(fd)
 }
 
@@ -390,7 +477,7 @@ pub fn bind<C: LinuxCpu>(ctx: &mut Ctx<C>, sockfd: u64, addr: u64, addrlen: u64)
 
     ctx.cpu.mem().read_bytes(addr, &mut sockaddr.addr[..addrlen as usize])?;
 
-    let file = ctx.kernel.process.file_table.get(&mut ctx.kernel.process_manager, sockfd)?;
+    let file = ctx.kernel.get_file(sockfd)?;
     file.borrow_mut().bind(&sockaddr)?;
 
     Ok(0)
@@ -420,7 +507,7 @@ pub fn sendto<C: LinuxCpu>(
     dst_addr: u64,
     addrlen: u64,
 ) -> LinuxResult {
-    let file = ctx.kernel.process.file_table.get(&mut ctx.kernel.process_manager, sockfd)?;
+    let file = ctx.kernel.get_file(sockfd)?;
     let mut sock_addr = fs::socket::SocketAddr::read_user(ctx.cpu.mem(), dst_addr, addrlen)?;
 
     match do_send(ctx, &file, sock_addr.as_mut(), buf, len) {
@@ -435,7 +522,7 @@ pub fn sendto<C: LinuxCpu>(
 
 pub fn sendmsg<C: LinuxCpu>(ctx: &mut Ctx<C>, socket: u64, msg: u64, _flags: u64) -> LinuxResult {
     let msg = ctx.read_user_struct::<types::MsgHdr>(msg)?;
-    let file = ctx.kernel.process.file_table.get(&mut ctx.kernel.process_manager, socket)?;
+    let file = ctx.kernel.get_file(socket)?;
 
     let mut sock_addr =
         fs::socket::SocketAddr::read_user(ctx.cpu.mem(), msg.name.value, msg.namelen.value)?;
@@ -488,7 +575,7 @@ pub fn recvfrom<C: LinuxCpu>(
     src_addr: u64,
     addrlen: u64,
 ) -> LinuxResult {
-    let file = ctx.kernel.process.file_table.get(&mut ctx.kernel.process_manager, sockfd)?;
+    let file = ctx.kernel.get_file(sockfd)?;
     let mut sock_addr = (src_addr != NULL_PTR).then(fs::socket::SocketAddr::default);
 
     let read_bytes = match do_recv(ctx, &file, sock_addr.as_mut(), buf, len) {
@@ -510,7 +597,7 @@ pub fn recvfrom<C: LinuxCpu>(
 
 pub fn recvmsg<C: LinuxCpu>(ctx: &mut Ctx<C>, socket: u64, msg: u64, _flags: u64) -> LinuxResult {
     let msg = ctx.read_user_struct::<types::MsgHdr>(msg)?;
-    let file = ctx.kernel.process.file_table.get(&mut ctx.kernel.process_manager, socket)?;
+    let file = ctx.kernel.get_file(socket)?;
 
     let mut sock_addr = (msg.name.value != NULL_PTR).then(fs::socket::SocketAddr::default);
 
@@ -563,12 +650,7 @@ pub fn poll<C: LinuxCpu>(ctx: &mut Ctx<C>, fds: u64, nfds: u64, timeout: u64) ->
         let fd = entry.fd.value;
         let events = entry.events.value;
 
-        let file = ctx
-            .kernel
-            .process
-            .file_table
-            .get(&mut ctx.kernel.process_manager, fd)
-            .map_err(|_| sys::poll::POLLNVAL)?;
+        let file = ctx.kernel.get_file(fd).map_err(|_| sys::poll::POLLNVAL)?;
         let revents = file.borrow_mut().poll(events)?;
 
         if revents != 0 {
@@ -634,7 +716,7 @@ pub fn select<C: LinuxCpu>(
         addr => Some(ctx.read_user_struct::<types::libc::intptr_t>(addr)?.value),
     };
 
-    let fd_limit = u64::min(n, ctx.kernel.process.file_table.files.len() as u64);
+    let fd_limit = u64::min(n, ctx.kernel.process.file_table.borrow().files.len() as u64);
     let long_size = ctx.kernel.arch.triple.data_model().unwrap().long_size();
     let long_bytes = long_size.bytes() as usize;
 
@@ -659,7 +741,12 @@ pub fn select<C: LinuxCpu>(
             *byte &= !bit;
 
             let fd = (byte_idx * 8 + bit_idx) as u64;
-            let file = ctx.kernel.process.file_table.get(&mut ctx.kernel.process_manager, fd)?;
+            let file = ctx
+                .kernel
+                .process
+                .file_table
+                .borrow_mut()
+                .get(&mut ctx.kernel.process_manager, fd)?;
 
             let revents = file.borrow_mut().poll(sys::poll::POLLIN)?;
 
@@ -736,7 +823,7 @@ pub fn readlinkat<C: LinuxCpu>(
 }
 
 pub fn lseek<C: LinuxCpu>(ctx: &mut Ctx<C>, fd: u64, offset: u64, whence: u64) -> LinuxResult {
-    let file = ctx.kernel.process.file_table.get(&mut ctx.kernel.process_manager, fd)?;
+    let file = ctx.kernel.get_file(fd)?;
     let mut file = file.borrow_mut();
     let whence: Seek = whence.try_into()?;
     Ok(file.seek(offset as i64, whence)? as u64)
@@ -818,7 +905,7 @@ pub fn chown<C: LinuxCpu>(ctx: &mut Ctx<C>, filename: u64, _user: u64, _group: u
 }
 
 pub fn getpid<C: LinuxCpu>(ctx: &mut Ctx<C>) -> LinuxResult {
-    Ok(ctx.kernel.process.pid)
+    Ok(ctx.kernel.process.tgid)
 }
 
 pub fn getgid<C: LinuxCpu>(ctx: &mut Ctx<C>) -> LinuxResult {
@@ -868,7 +955,7 @@ pub fn fcntl<C: LinuxCpu>(ctx: &mut Ctx<C>, fd: u64, cmd: u64, arg: u64) -> Linu
 
     let file = ctx.kernel.get_file(fd)?;
     match cmd {
-        F_DUPFD => Ok(ctx.kernel.process.file_table.add(file)),
+        F_DUPFD => Ok(ctx.kernel.process.file_table.borrow_mut().add(file)?),
         F_GETFD => Ok(file.borrow_mut().flags),
         F_SETFD => {
             file.borrow_mut().flags = arg;
@@ -1083,6 +1170,19 @@ pub fn mmap2<C: LinuxCpu>(
     }
 
     let is_fixed = flags & mmem::MAP_FIXED != 0;
+    let regions = ctx.kernel.process.mmap_regions.borrow();
+    let new_count = match is_fixed {
+        true => {
+            let end = addr.checked_add(alloc_len).ok_or(errno::ENOMEM)?;
+            regions.count_after_insert(addr, end)
+        }
+        false => regions.count() + 1,
+    };
+    drop(regions);
+    if new_count as u64 > ctx.kernel.limits.max_mmaps {
+        return Err(errno::ENOMEM.into());
+    }
+
     if is_fixed {
         // Remove any existing allocation the overlaps with this allocation
         if addr != NULL_PTR {
@@ -1102,6 +1202,7 @@ pub fn mmap2<C: LinuxCpu>(
         tracing::error!("Wrong allocation address, wanted: {addr:#x} got: {alloc_addr:#x}");
         return Err(VmExit::OutOfMemory.into());
     }
+    ctx.kernel.process.mmap_regions.borrow_mut().insert(alloc_addr, alloc_addr + alloc_len);
 
     let written_bytes = if is_file {
         let file_ref = ctx.kernel.get_file(fd)?;
@@ -1143,6 +1244,7 @@ pub fn munmap<C: LinuxCpu>(ctx: &mut Ctx<C>, addr: u64, length: u64) -> LinuxRes
         return Err(errno::EINVAL.into());
     }
     ctx.kernel.free(ctx.cpu.mem(), addr, length)?;
+    ctx.kernel.process.mmap_regions.borrow_mut().remove(addr, end);
     Ok(0)
 }
 
@@ -1180,6 +1282,7 @@ pub fn mremap<C: LinuxCpu>(
     if new_size < old_size {
         // Shrink memory map
         ctx.kernel.free(ctx.cpu.mem(), new_end, old_size - new_size)?;
+        ctx.kernel.process.mmap_regions.borrow_mut().remove(new_end, old_end);
         return Ok(old_addr);
     }
 
@@ -1188,6 +1291,7 @@ pub fn mremap<C: LinuxCpu>(
         let alloc_after =
             ctx.kernel.alloc_fixed(ctx.cpu.mem(), old_end, new_size - old_size, perm | perm::MAP);
         if alloc_after.is_ok() {
+            ctx.kernel.process.mmap_regions.borrow_mut().insert(old_addr, new_end);
             return Ok(old_addr);
         }
     }
@@ -1210,6 +1314,10 @@ pub fn mremap<C: LinuxCpu>(
     ctx.kernel
         .alloc_fixed(ctx.cpu.mem(), new_addr + old_size, new_size - old_size, perm | perm::MAP)
         .unwrap();
+    let mut regions = ctx.kernel.process.mmap_regions.borrow_mut();
+    regions.remove(old_addr, old_end);
+    regions.insert(new_addr, new_addr + new_size);
+    drop(regions);
 
     Ok(new_addr)
 }
@@ -1484,6 +1592,12 @@ pub fn arch_prctl_x64<C: LinuxCpu>(ctx: &mut Ctx<C>, code: u64, addr: u64) -> Li
             ctx.cpu.write_var(gs_offset, addr);
             Ok(0)
         }
+        x86::arch::GET_FS | x86::arch::GET_GS => {
+            let name = if code as u32 == x86::arch::GET_FS { "FS_OFFSET" } else { "GS_OFFSET" };
+            let base = ctx.cpu.read_var(ctx.cpu.sleigh().get_varnode(name).unwrap());
+            ctx.cpu.mem().write_bytes(addr, &base.to_le_bytes())?;
+            Ok(0)
+        }
         _ => {
             tracing::warn!("Unknown `arch_prctl` code: {:0x}, addr = {:0x}", code, addr);
             Err(errno::EINVAL.into())
@@ -1512,7 +1626,14 @@ pub fn ioctl<C: LinuxCpu>(ctx: &mut Ctx<C>, fd: u64, request: u64) -> LinuxResul
 }
 
 pub fn exit<C: LinuxCpu>(ctx: &mut Ctx<C>, status: u64) -> LinuxResult {
-    exit_group(ctx, status)
+    // Only the calling thread exits if there are other threads in its thread group.
+    if !ctx.kernel.process_manager.has_threads(ctx.kernel.process.tgid) {
+        return exit_group(ctx, status);
+    }
+    match ctx.kernel.exit_thread(ctx.cpu, status) {
+        Some(err) => Err(err.into()),
+        None => Ok(0),
+    }
 }
 
 pub fn exit_group<C: LinuxCpu>(ctx: &mut Ctx<C>, status: u64) -> LinuxResult {
@@ -1620,24 +1741,32 @@ pub fn kill<C: LinuxCpu>(ctx: &mut Ctx<C>, pid: u64, sig: u64) -> LinuxResult {
 }
 
 pub fn tkill<C: LinuxCpu>(ctx: &mut Ctx<C>, tid: u64, sig: u64) -> LinuxResult {
-    let tgid = ctx.kernel.process.pid;
+    let tgid = ctx.kernel.process.tgid;
     tgkill(ctx, tgid, tid, sig)
 }
 
 pub fn tgkill<C: LinuxCpu>(ctx: &mut Ctx<C>, tgid: u64, tid: u64, sig: u64) -> LinuxResult {
     tracing::debug!("tgid={}, tid={}, sig={}", tgid, tid, sig);
 
-    // We only support a single thread/thread group and that thread group must be the main thread
-    if tgid != ctx.kernel.process.pid && (tid != 0 || tid != ctx.kernel.process.pid) {
-        return Err(errno::EINVAL.into());
+    let target_tgid = match tid == ctx.kernel.process.pid {
+        true => ctx.kernel.process.tgid,
+        false => ctx.kernel.process_manager.get_mut(tid).ok_or(errno::ESRCH)?.process.tgid,
+    };
+    if target_tgid != tgid {
+        return Err(errno::ESRCH.into());
     }
 
     kill(ctx, tid, sig)
 }
 
-pub fn set_tid_address<C: LinuxCpu>(_ctx: &mut Ctx<C>, _tidptr: u64) -> LinuxResult {
-    // @fixme: revisit this when we get proper multithreading support
-    Ok(0x1234)
+pub fn set_tid_address<C: LinuxCpu>(ctx: &mut Ctx<C>, tidptr: u64) -> LinuxResult {
+    ctx.kernel.process.clear_child_tid = tidptr;
+    Ok(ctx.kernel.process.pid)
+}
+
+pub fn sched_yield<C: LinuxCpu>(ctx: &mut Ctx<C>) -> LinuxResult {
+    ctx.kernel.arch.dynamic.set_result(ctx.cpu, 0);
+    ctx.kernel.switch_task(ctx.cpu, crate::PauseReason::Switched)
 }
 
 pub fn set_thread_area_mips<C: LinuxCpu>(ctx: &mut Ctx<C>, addr: u64) -> LinuxResult {
@@ -1650,10 +1779,13 @@ pub fn set_thread_area_x86<C: LinuxCpu>(ctx: &mut Ctx<C>, user_desc: u64) -> Lin
     tracing::warn!("set_thread_area might not be implemented correctly");
     let buf = read_user(ctx.cpu.mem(), user_desc, 16, &mut ctx.kernel.buffer)?;
 
+    // Write the entry to the GDT at position 12 (an arbitary position to use for now)
+    let gdb_descriptor: u32 = 12;
+
     let entry_number = i32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]);
-    if entry_number != -1 {
-        tracing::warn!("update to existing thread area not supported");
-        return Err(errno::ENOSYS.into());
+    if entry_number != -1 && entry_number != gdb_descriptor as i32 {
+        tracing::warn!("update to thread area {entry_number} not supported");
+        return Err(errno::EINVAL.into());
     }
 
     let entry = arch::x86::GDTEntry {
@@ -1664,12 +1796,15 @@ pub fn set_thread_area_x86<C: LinuxCpu>(ctx: &mut Ctx<C>, user_desc: u64) -> Lin
         access: 0,
     };
 
-    // Write the entry to the GDT at position 12 (an arbitary position to use for now)
-    let gdb_descriptor: u32 = 12;
     let gdtr = ctx.cpu.sleigh().get_varnode("GDTR").unwrap();
     let gdt_addr: u64 = ctx.cpu.read_var(gdtr);
     ctx.cpu.mem().write_bytes(gdt_addr + gdb_descriptor as u64 * 8, &entry.to_bytes())?;
 
+    // Loading a segment register does not update the segment base used for memory accesses, so
+    // we update it here instead. The entry is used for TLS, which is accessed with GS on i386.
+    let gs_offset = ctx.cpu.sleigh().get_varnode("GS_OFFSET").unwrap();
+    ctx.cpu.write_var(gs_offset, entry.base as u64);
+
     // Update the `user_desc` struct in user space
     ctx.kernel.arch.libc(user_desc).write::<arch::U32, _>(ctx.cpu.mem(), gdb_descriptor as u64)?;
 
@@ -1679,7 +1814,7 @@ pub fn set_thread_area_x86<C: LinuxCpu>(ctx: &mut Ctx<C>, user_desc: u64) -> Lin
 #[allow(unused)]
 pub mod clone {
     bitflags::bitflags! {
-        #[derive(Debug, Default)]
+        #[derive(Clone, Copy, Debug, Default)]
         pub struct Flags: u64 {
             const VM = 0x00000100;
             const FS = 0x00000200;
@@ -1737,29 +1872,17 @@ pub fn execve<C: LinuxCpu>(ctx: &mut Ctx<C>, pathname: u64, argv: u64, envp: u64
         env.push(ctx.kernel.arch.libc(ptr).read_cstr(ctx.cpu.mem(), &mut env_val)?.to_vec());
     }
 
-    ctx.kernel.set_env(&args, &env);
+    let path = ctx.kernel.arch.libc(pathname).read_cstr(ctx.cpu.mem(), &mut buf)?.to_vec();
+    tracing::debug!("[pid={}] execve: {}", ctx.kernel.process.pid, path.as_bstr());
 
-    let path = ctx.kernel.arch.libc(pathname).read_cstr(ctx.cpu.mem(), &mut buf)?;
+    // Check that the target exists before committing to replacing the process, since failures
+    // after this point cannot be reported back to the caller.
+    // @fixme: the loader always resolves paths from the root of the file system.
+    ctx.kernel.vfs.resolve(ctx.kernel.process.cwd(), &path)?;
 
-    // @todo: remove when we have better tools for debugging.
-    eprint!("[pid={}] exec: {} ", ctx.kernel.process.pid, path.as_bstr());
-    for arg in &args {
-        eprint!("{} ", arg[..arg.len() - 1].as_bstr());
-    }
-    eprintln!();
-
-    // match ctx.kernel.load(ctx.cpu, path) {
-    //     Ok(_) => {
-    //         ctx.cpu.set_next_pc(ctx.cpu.read_pc());
-    //         Ok(0)
-    //     }
-    //     Err(e) => {
-    //         tracing::warn!("execve failed: {}", e);
-    //         Err(errno::ENOENT.into())
-    //     }
-    // }
-
-    Err(errno::ENOENT.into())
+    ctx.kernel.set_env(&args, &env);
+    ctx.kernel.pending_exec = Some(path);
+    Ok(0)
 }
 
 pub fn fork<C: LinuxCpu>(ctx: &mut Ctx<C>) -> LinuxResult {
@@ -1768,6 +1891,23 @@ pub fn fork<C: LinuxCpu>(ctx: &mut Ctx<C>) -> LinuxResult {
     ctx.kernel.fork(ctx.cpu)
 }
 
+pub fn clone<C: LinuxCpu>(
+    ctx: &mut Ctx<C>,
+    clone_flags: u64,
+    new_sp: u64,
+    parent_tidptr: u64,
+    tls_ptr: u64,
+    child_tidptr: u64,
+) -> LinuxResult {
+    do_clone(ctx, clone_flags, CloneState {
+        flags: clone::Flags::empty(),
+        new_sp,
+        parent_tidptr,
+        child_tidptr,
+        tls_ptr,
+    })
+}
+
 pub fn clone_x86<C: LinuxCpu>(
     ctx: &mut Ctx<C>,
     clone_flags: u64,
@@ -1776,10 +1916,13 @@ pub fn clone_x86<C: LinuxCpu>(
     child_tidptr: u64,
     tls_ptr: u64,
 ) -> LinuxResult {
-    let flags = clone::Flags::from_bits(clone_flags & !0xff).ok_or(errno::EINVAL)?;
-    ctx.kernel.clone_state = CloneState { flags, new_sp, parent_tidptr, child_tidptr, tls_ptr };
-    tracing::debug!("clone({:0x?})", ctx.kernel.clone_state);
-    ctx.kernel.fork(ctx.cpu)
+    do_clone(ctx, clone_flags, CloneState {
+        flags: clone::Flags::empty(),
+        new_sp,
+        parent_tidptr,
+        child_tidptr,
+        tls_ptr,
+    })
 }
 
 pub fn clone_mips<C: LinuxCpu>(
@@ -1790,8 +1933,18 @@ pub fn clone_mips<C: LinuxCpu>(
     tls_ptr: u64,
     child_tidptr: u64,
 ) -> LinuxResult {
+    clone(ctx, clone_flags, new_sp, parent_tidptr, tls_ptr, child_tidptr)
+}
+
+fn do_clone<C: LinuxCpu>(ctx: &mut Ctx<C>, clone_flags: u64, state: CloneState) -> LinuxResult {
     let flags = clone::Flags::from_bits(clone_flags & !0xff).ok_or(errno::EINVAL)?;
-    ctx.kernel.clone_state = CloneState { flags, new_sp, parent_tidptr, child_tidptr, tls_ptr };
+    // Threads must share the address space and signal handlers of their parent.
+    if flags.contains(clone::Flags::THREAD)
+        && !flags.contains(clone::Flags::VM | clone::Flags::SIGHAND)
+    {
+        return Err(errno::EINVAL.into());
+    }
+    ctx.kernel.clone_state = CloneState { flags, ..state };
     tracing::debug!("clone({:0x?})", ctx.kernel.clone_state);
     ctx.kernel.fork(ctx.cpu)
 }
@@ -1821,16 +1974,40 @@ pub fn futex<C: LinuxCpu>(
     uaddr: u64,
     op: u64,
     val: u64,
-    _timeout: u64,
+    timeout: u64,
     _uaddr2: u64,
     _val3: u64,
 ) -> LinuxResult {
     match op & futex::CMD_MASK {
-        futex::WAIT => {
-            if ctx.kernel.arch.libc(uaddr).read::<arch::Ptr, _>(ctx.cpu.mem())? != val {
+        cmd @ (futex::WAIT | futex::WAIT_BITSET) => {
+            let current = ctx.kernel.arch.libc(uaddr).read::<arch::UInt, _>(ctx.cpu.mem())?;
+            if current != val & 0xffff_ffff {
                 return Err(errno::EAGAIN.into());
             }
-            Ok(0)
+
+            if timeout != NULL_PTR {
+                // @fixme: `futex_time64` on 32-bit targets uses a 64-bit `time_t`.
+                let mut reader = ctx.kernel.arch.libc(timeout);
+                let secs = reader.read::<arch::SLong, _>(ctx.cpu.mem())?;
+                let nanos = reader.read::<arch::SLong, _>(ctx.cpu.mem())?;
+                if (secs as i64) < 0 || nanos >= 1_000_000_000 {
+                    return Err(errno::EINVAL.into());
+                }
+                let mut duration = std::time::Duration::new(secs, nanos as u32);
+                if cmd == futex::WAIT_BITSET {
+                    // `FUTEX_WAIT_BITSET` uses an absolute timeout.
+                    duration = duration.saturating_sub(ctx.kernel.current_time);
+                }
+                ctx.kernel.process.timeout = Some(duration);
+            }
+
+            ctx.kernel.process.futex_wait = Some(uaddr);
+            ctx.kernel.switch_task(ctx.cpu, crate::PauseReason::WaitFutex)
+        }
+        futex::WAKE | futex::WAKE_BITSET => {
+            // Private futexes can only be shared by threads in the same thread group.
+            let tgid = (op & futex::PRIVATE_FLAG != 0).then_some(ctx.kernel.process.tgid);
+            Ok(ctx.kernel.process_manager.wake_futex(tgid, uaddr, val & 0xffff_ffff))
         }
         _ => Err(errno::ENOSYS.into()),
     }
@@ -2433,6 +2610,90 @@ pub const ITIMER_REAL: u64 = 0;
 pub const ITIMER_VIRTUAL: u64 = 1;
 pub const ITIMER_PROF: u64 = 2;
 
+/// Gets a mutable reference to the limit associated with `resource`, or `None` if the emulator
+/// does not limit the resource.
+fn rlimit_mut(kernel: &mut Kernel, resource: u64) -> Option<&mut crate::RLimit> {
+    use crate::sys::rlimit::*;
+    use target_lexicon::Architecture;
+
+    let is_mips = matches!(kernel.arch.triple.architecture, Architecture::Mips32(_));
+    let limits = &mut kernel.process.rlimits;
+    match resource {
+        RLIMIT_NOFILE if !is_mips => Some(&mut limits.nofile),
+        RLIMIT_NOFILE_MIPS if is_mips => Some(&mut limits.nofile),
+        RLIMIT_NPROC if !is_mips => Some(&mut limits.nproc),
+        RLIMIT_NPROC_MIPS if is_mips => Some(&mut limits.nproc),
+        _ => None,
+    }
+}
+
+/// Updates the limit for `resource` returning the previous value. The hard limit can be lowered
+/// but not raised, and the soft limit can be set to any value up to the hard limit.
+fn update_rlimit(
+    kernel: &mut Kernel,
+    resource: u64,
+    new: Option<(u64, u64)>,
+) -> Result<crate::RLimit, u64> {
+    let Some(limit) = rlimit_mut(kernel, resource)
+    else {
+        // Limits for other resources are not enforced, so report them as unlimited.
+        return Ok(crate::RLimit::new(crate::sys::rlimit::RLIM_INFINITY));
+    };
+
+    let old = *limit;
+    if let Some((cur, max)) = new {
+        if cur > max {
+            return Err(errno::EINVAL);
+        }
+        if max > old.max {
+            return Err(errno::EPERM);
+        }
+        *limit = crate::RLimit { cur, max };
+    }
+    kernel.process.file_table.borrow_mut().max_files = kernel.process.rlimits.nofile.cur;
+    Ok(old)
+}
+
+pub fn getrlimit<C: LinuxCpu>(ctx: &mut Ctx<C>, resource: u64, rlim: u64) -> LinuxResult {
+    let limit = update_rlimit(ctx.kernel, resource, None)?;
+    let value = types::rlimit { rlim_cur: limit.cur.into(), rlim_max: limit.max.into() };
+    ctx.write_user_struct(rlim, &value)?;
+    Ok(0)
+}
+
+pub fn setrlimit<C: LinuxCpu>(ctx: &mut Ctx<C>, resource: u64, rlim: u64) -> LinuxResult {
+    let value: types::rlimit = ctx.read_user_struct(rlim)?;
+    update_rlimit(ctx.kernel, resource, Some((value.rlim_cur.value, value.rlim_max.value)))?;
+    Ok(0)
+}
+
+pub fn prlimit64<C: LinuxCpu>(
+    ctx: &mut Ctx<C>,
+    pid: u64,
+    resource: u64,
+    new_limit: u64,
+    old_limit: u64,
+) -> LinuxResult {
+    if pid != 0 && pid != ctx.kernel.process.pid {
+        return Err(errno::ESRCH.into());
+    }
+
+    let new = match new_limit {
+        NULL_PTR => None,
+        ptr => {
+            let value: types::rlimit64 = ctx.read_user_struct(ptr)?;
+            Some((value.rlim_cur.value, value.rlim_max.value))
+        }
+    };
+
+    let old = update_rlimit(ctx.kernel, resource, new)?;
+    if old_limit != NULL_PTR {
+        let value = types::rlimit64 { rlim_cur: old.cur.into(), rlim_max: old.max.into() };
+        ctx.write_user_struct(old_limit, &value)?;
+    }
+    Ok(0)
+}
+
 pub fn setitimer<C: LinuxCpu>(ctx: &mut Ctx<C>, which: u64, curr_value: u64) -> LinuxResult {
     let _value: types::itimerval = ctx.read_user_struct(curr_value)?;
 
@@ -2449,13 +2710,13 @@ pub fn getrandom<C: LinuxCpu>(ctx: &mut Ctx<C>, buf: u64, buflen: u64, flags: u6
     // We don't care about the flags since we never block and only have one rng source
     let _flags = flags;
 
-    let random = &mut ctx.kernel.random;
     let mut offset = 0;
     while offset < buflen {
         let num_bytes = (buflen - offset).min(256);
 
         ctx.kernel.buffer.clear();
-        ctx.kernel.buffer.extend((0..num_bytes).map(|_| random.next()));
+        ctx.kernel.buffer.resize(num_bytes as usize, 0);
+        ctx.kernel.random.fill_bytes(&mut ctx.kernel.buffer);
 
         ctx.cpu.mem().write_bytes(buf.wrapping_add(offset), &ctx.kernel.buffer)?;
 
@@ -2480,31 +2741,58 @@ pub fn rt_sigaction<C: LinuxCpu>(
     }
 
     if oldact != NULL_PTR {
-        let act = ctx.kernel.process.signal_handlers.entries[signum as usize];
+        let act = ctx.kernel.process.signal_handlers.borrow().entries[signum as usize];
         ctx.write_user_struct(oldact, &act)?;
     }
 
     if act != NULL_PTR {
         let act = ctx.read_user_struct::<types::Sigaction>(act)?;
-        ctx.kernel.process.signal_handlers.set_action(signum, act);
+        ctx.kernel.process.signal_handlers.borrow_mut().set_action(signum, act);
     }
 
     Ok(0)
 }
 
 pub fn rt_sigprocmask<C: LinuxCpu>(
-    _ctx: &mut Ctx<C>,
-    _how: u64,
-    _set: u64,
-    _oldset: u64,
+    ctx: &mut Ctx<C>,
+    how: u64,
+    set: u64,
+    oldset: u64,
     _sigsetsize: u64,
 ) -> LinuxResult {
-    tracing::warn!("rt_sigprocmask ignored");
+    const SIG_BLOCK: u64 = 0;
+    const SIG_UNBLOCK: u64 = 1;
+    const SIG_SETMASK: u64 = 2;
+
+    let old_mask = ctx.kernel.process.signal_mask;
+    if set != NULL_PTR {
+        let set = ctx.read_user_struct::<types::Sigset>(set)?.value;
+        // MIPS numbers `how` starting from 1.
+        let how = match ctx.kernel.arch.triple.architecture {
+            target_lexicon::Architecture::Mips32(_) => how.wrapping_sub(1),
+            _ => how,
+        };
+        let mask = match how {
+            SIG_BLOCK => old_mask | set,
+            SIG_UNBLOCK => old_mask & !set,
+            SIG_SETMASK => set,
+            _ => return Err(errno::EINVAL.into()),
+        };
+        // `SIGKILL` and `SIGSTOP` cannot be blocked.
+        let sigstop = ctx.kernel.arch.signals.sigstop;
+        let unblockable = (1 << (sys::signal::SIGKILL - 1)) | (1 << (sigstop - 1));
+        ctx.kernel.process.signal_mask = mask & !unblockable;
+    }
+
+    if oldset != NULL_PTR {
+        ctx.write_user_struct(oldset, &types::Sigset { value: old_mask })?;
+    }
+
     Ok(0)
 }
 
 pub fn rt_sigsuspend<C: LinuxCpu>(ctx: &mut Ctx<C>, mask: u64) -> LinuxResult {
-    let mask = ctx.read_user_struct::<types::libc::ulonglong>(mask)?.value;
+    let mask = ctx.read_user_struct::<types::Sigset>(mask)?.value;
     let pending = ctx.kernel.process.pending_signals;
 
     tracing::debug!("sigsuspend mask = {:#0x}, pending_signals: {:#0x}", mask, pending);
@@ -2515,6 +2803,9 @@ pub fn rt_sigsuspend<C: LinuxCpu>(ctx: &mut Ctx<C>, mask: u64) -> LinuxResult {
 }
 
 pub fn rt_sigreturn<C: LinuxCpu>(ctx: &mut Ctx<C>) -> LinuxResult {
-    ctx.kernel.arch.dynamic.restore_signal_frame(ctx.cpu)?;
+    let mask = ctx.kernel.arch.dynamic.restore_signal_frame(ctx.cpu)?;
+    ctx.kernel.process.signal_mask = mask;
+    // All registers (including the return value register) were restored from the signal frame.
+    ctx.kernel.skip_syscall_result = true;
     Ok(0)
 }
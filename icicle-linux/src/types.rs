@@ -203,30 +203,51 @@ impl arch::Struct for Sigaction {
     }
 }
 
+/// Describes a signal that is being delivered to a handler in the guest.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SignalFrame {
+    /// The signal number (`si_signo`).
+    pub signal: u64,
+
+    /// The reason the signal was raised (`si_code`).
+    pub code: i32,
+
+    /// The faulting address for signals raised by a fault (`si_addr`).
+    pub addr: u64,
+
+    /// The address execution resumes at after the handler returns.
+    pub resume_addr: u64,
+
+    /// The signal mask that is restored after the handler returns.
+    pub saved_mask: u64,
+}
+
+/// A signal set, stored as a bitmask where bit `n - 1` corresponds to signal `n`.
+///
+/// In the guest the set is an array of `unsigned long` words (in guest byte order) with the lowest
+/// signals in the first word.
 #[derive(Debug, Default, Clone, Copy)]
 pub struct Sigset {
-    pub value: [u8; 64 / 8],
+    pub value: u64,
 }
 
 impl arch::Struct for Sigset {
     fn read<M: LinuxMmu>(libc: &mut arch::Libc, mem: &mut M) -> MemResult<Self> {
-        let mut value = [0; 64 / 8];
-        let bytes_per_word = libc.data_model.long_size().bytes();
+        let bits_per_word = libc.data_model.long_size().bits() as usize;
 
-        for chunk in value.chunks_exact_mut(bytes_per_word as usize) {
-            // @fixme: byteswap
-            libc.read_bytes(mem, chunk)?;
+        let mut value = 0;
+        for shift in (0..64).step_by(bits_per_word) {
+            value |= libc.read::<arch::ULong, _>(mem)? << shift;
         }
 
         Ok(Self { value })
     }
 
     fn write<M: LinuxMmu>(&self, libc: &mut arch::Libc, mem: &mut M) -> MemResult<()> {
-        let bytes_per_word = libc.data_model.long_size().bytes();
+        let bits_per_word = libc.data_model.long_size().bits() as usize;
 
-        for chunk in self.value.chunks_exact(bytes_per_word as usize) {
-            // @fixme: byteswap
-            libc.write_bytes(mem, chunk)?;
+        for shift in (0..64).step_by(bits_per_word) {
+            libc.write::<arch::ULong, _>(mem, self.value >> shift)?;
         }
 
         Ok(())
@@ -415,6 +436,29 @@ impl Stat {
                 };
                 buf.extend_from_slice(bytemuck::bytes_of(&native_stat64));
             }
+            Architecture::Riscv32(_) => {
+                let native_stat64 = generic32::stat64 {
+                    st_dev: self.dev,
+                    st_ino: self.ino,
+                    st_mode: self.mode,
+                    st_nlink: self.nlink as u32,
+                    st_uid: self.uid,
+                    st_gid: self.gid,
+                    st_rdev: self.rdev,
+                    st_size: self.size,
+                    st_blksize: self.blksize as i32,
+                    st_blocks: self.blocks,
+                    st_atime: self.atime as i32,
+                    st_atime_nsec: self.atime_nsec as u32,
+                    st_mtime: self.mtime as i32,
+                    st_mtime_nsec: self.mtime_nsec as u32,
+                    st_ctime: self.ctime as i32,
+                    st_ctime_nsec: self.ctime_nsec as u32,
+                    ..generic32::stat64::default()
+                };
+                assert_eq!(std::mem::size_of::<generic32::stat64>(), 0x68);
+                buf.extend_from_slice(bytemuck::bytes_of(&native_stat64));
+            }
             arch if arch.pointer_width().map_or(false, |x| x.bits() == 64) => {
                 let native_stat = generic64::stat {
                     st_dev: self.dev,
@@ -444,6 +488,8 @@ impl Stat {
 
     pub fn encode_stat(&self, arch: Architecture, buf: &mut Vec<u8>) {
         match arch {
+            // RV32 only supports the `stat64` variants of the stat syscalls.
+            Architecture::Riscv32(_) => self.encode_stat64(arch, buf),
             arch if arch.pointer_width().map_or(false, |x| x.bits() == 64) => {
                 self.encode_stat64(arch, buf)
             }
@@ -533,7 +579,10 @@ pub struct Timespec {
 impl Timespec {
     pub fn encode(&self, arch: Architecture, buf: &mut Vec<u8>) {
         match arch {
-            arch if arch.pointer_width().map_or(false, |x| x.bits() == 64) => {
+            // RV32 uses a 64-bit `time_t`.
+            arch if arch.pointer_width().map_or(false, |x| x.bits() == 64)
+                || matches!(arch, Architecture::Riscv32(_)) =>
+            {
                 let data = generic64::timespec { tv_sec: self.seconds, tv_nsec: self.nanoseconds };
                 buf.extend_from_slice(bytemuck::bytes_of(&data));
             }
@@ -565,7 +614,9 @@ pub struct Timeval {
 impl Timeval {
     pub fn encode(&self, arch: Architecture, buf: &mut Vec<u8>) {
         match arch {
-            arch if arch.pointer_width().map_or(false, |x| x.bits() == 64) => {
+            arch if arch.pointer_width().map_or(false, |x| x.bits() == 64)
+                || matches!(arch, Architecture::Riscv32(_)) =>
+            {
                 let data = generic64::timeval {
                     tv_sec: self.seconds as i32,
                     tv_usec: self.microseconds as i32,
@@ -620,6 +671,22 @@ libc_struct!(
     }
 );
 
+libc_struct!(
+    #[derive(Default)]
+    pub struct rlimit {
+        pub rlim_cur: Value<arch::ULong>,
+        pub rlim_max: Value<arch::ULong>,
+    }
+);
+
+libc_struct!(
+    #[derive(Default)]
+    pub struct rlimit64 {
+        pub rlim_cur: Value<arch::ULongLong>,
+        pub rlim_max: Value<arch::ULongLong>,
+    }
+);
+
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum Seek {
     /// Set absolute file offset
@@ -773,6 +840,39 @@ mod generic64 {
     unsafe impl bytemuck::Pod for stat {}
 }
 
+/// Types from `asm-generic` for 32-bit architectures.
+mod generic32 {
+    use super::*;
+
+    #[repr(C)]
+    #[derive(Copy, Clone, Default)]
+    pub struct stat64 {
+        pub st_dev: u64,
+        pub st_ino: u64,
+        pub st_mode: unsigned_int,
+        pub st_nlink: unsigned_int,
+        pub st_uid: unsigned_int,
+        pub st_gid: unsigned_int,
+        pub st_rdev: u64,
+        pub __pad1: u64,
+        pub st_size: i64,
+        pub st_blksize: int,
+        pub __pad2: int,
+        pub st_blocks: i64,
+        pub st_atime: int,
+        pub st_atime_nsec: unsigned_int,
+        pub st_mtime: int,
+        pub st_mtime_nsec: unsigned_int,
+        pub st_ctime: int,
+        pub st_ctime_nsec: unsigned_int,
+        pub __unused4: unsigned_int,
+        pub __unused5: unsigned_int,
+    }
+
+    unsafe impl bytemuck::Zeroable for stat64 {}
+    unsafe impl bytemuck::Pod for stat64 {}
+}
+
 mod x64 {
     use super::{generic64::*, *};
 
@@ -0,0 +1,79 @@
+//! The guest physical address space.
+//!
+//! Regions in this address space are independent of the virtual address mapping, allowing the
+//! same RAM to be mapped at multiple virtual addresses (aliasing), and allowing I/O devices to be
+//! addressed by their physical address regardless of how guest page tables map them.
+
+use std::collections::BTreeMap;
+
+use crate::{IoHandler, physical};
+
+#[derive(Clone, Debug)]
+pub enum GuestPhysicalRegion {
+    /// RAM backed by physical pages (one entry for each page in the region).
+    Ram(Vec<physical::Index>),
+
+    /// A region handled by an I/O device, accesses are passed the guest physical address.
+    Io(IoHandler),
+}
+
+#[derive(Clone, Default, Debug)]
+pub struct GuestPhysicalMap {
+    /// Regions in the address space keyed by their start address, with the (inclusive) end address
+    /// of the region.
+    regions: BTreeMap<u64, (u64, GuestPhysicalRegion)>,
+}
+
+impl GuestPhysicalMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a new region to the address space starting at `start` and ending at `end` (inclusive).
+    ///
+    /// Returns `false` if the region overlaps with an existing region.
+    pub fn insert(&mut self, start: u64, end: u64, region: GuestPhysicalRegion) -> bool {
+        if self.overlaps(start, end) {
+            return false;
+        }
+        self.regions.insert(start, (end, region));
+        true
+    }
+
+    /// Removes the region starting at `start`.
+    pub fn remove(&mut self, start: u64) -> Option<GuestPhysicalRegion> {
+        self.regions.remove(&start).map(|(_, region)| region)
+    }
+
+    /// Gets the region containing `addr` along with its start and end address.
+    pub fn get(&self, addr: u64) -> Option<(u64, u64, &GuestPhysicalRegion)> {
+        let (start, (end, region)) = self.regions.range(..=addr).next_back()?;
+        (addr <= *end).then_some((*start, *end, region))
+    }
+
+    /// Returns an iterator over all regions in the address space.
+    pub fn iter(&self) -> impl Iterator<Item = (u64, u64, &GuestPhysicalRegion)> {
+        self.regions.iter().map(|(start, (end, region))| (*start, *end, region))
+    }
+
+    /// Checks whether any region overlaps with `start..=end`.
+    pub fn overlaps(&self, start: u64, end: u64) -> bool {
+        self.regions
+            .range(..=end)
+            .next_back()
+            .is_some_and(|(_, (prev_end, _))| *prev_end >= start)
+    }
+
+    /// Replaces references to the physical page at `old` with `new` (e.g. after a copy-on-write).
+    pub fn replace_page(&mut self, old: physical::Index, new: physical::Index) {
+        for (_, region) in self.regions.values_mut() {
+            if let GuestPhysicalRegion::Ram(pages) = region {
+                pages.iter_mut().filter(|index| **index == old).for_each(|index| *index = new);
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.regions.clear();
+    }
+}
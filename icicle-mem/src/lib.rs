@@ -1,9 +1,12 @@
+pub mod guest_physical;
+pub mod mmio;
 pub mod perm;
 pub mod physical;
 pub mod tlb;
 
 mod mmu;
 pub mod range_map;
+pub mod shadow;
 
 #[cfg(test)]
 mod tests;
@@ -16,7 +19,8 @@ use crate::range_map::RangeMap;
 pub const UNINIT_VALUE: u8 = 0xaa;
 
 pub use crate::{
-    mmu::{Mmu, ReadAfterHook, ReadHook, WriteHook},
+    mmio::MmioHandler,
+    mmu::{AccessHook, MemoryRegion, Mmu, PermHook, ReadAfterHook, ReadHook, WriteHook},
     perm::{MemError, MemResult},
 };
 
@@ -57,6 +61,22 @@ pub struct PhysicalMapping {
     pub index: physical::Index,
 }
 
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct PhysicalIoMapping {
+    /// The I/O handler responsible for the region.
+    pub id: usize,
+
+    /// The value added to a virtual address to get the guest physical address passed to the
+    /// handler.
+    pub offset: u64,
+}
+
+impl PhysicalIoMapping {
+    pub fn guest_physical_addr(&self, vaddr: u64) -> u64 {
+        vaddr.wrapping_add(self.offset)
+    }
+}
+
 #[derive(Clone, PartialEq, Eq)]
 pub enum MemoryMapping {
     /// Represents a region of memory backed by a physical page.
@@ -67,6 +87,10 @@ pub enum MemoryMapping {
 
     /// Represents a region of memory handled externally.
     Io(usize),
+
+    /// Represents a region of memory handled externally that is addressed using guest physical
+    /// addresses (see [guest_physical]).
+    PhysicalIo(PhysicalIoMapping),
 }
 
 impl std::fmt::Debug for MemoryMapping {
@@ -75,6 +99,7 @@ impl std::fmt::Debug for MemoryMapping {
             Self::Physical(inner) => write!(f, "{:?}", inner.index),
             Self::Unallocated(inner) => write!(f, "{}", inner),
             Self::Io(i) => write!(f, "io[{}]", i),
+            Self::PhysicalIo(inner) => write!(f, "io[{}] (offset={:#x})", inner.id, inner.offset),
         }
     }
 }
@@ -84,6 +109,13 @@ pub trait IoMemory {
     fn read(&mut self, addr: u64, buf: &mut [u8]) -> MemResult<()>;
     fn write(&mut self, addr: u64, value: &[u8]) -> MemResult<()>;
 
+    /// Called when the guest is busy-waiting for the value of `size` bytes at `addr` to change,
+    /// see [MmioHandler::resolve_poll](crate::mmio::MmioHandler::resolve_poll).
+    fn resolve_poll(&mut self, addr: u64, size: u8) -> Option<u64> {
+        let _ = (addr, size);
+        None
+    }
+
     fn snapshot(&mut self) -> Box<dyn Any> {
         Box::new(())
     }
@@ -120,7 +152,7 @@ impl IoMemory for NullMemory {
     }
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub struct IoHandler(usize);
 
 impl From<IoHandler> for MemoryMapping {
@@ -175,12 +207,22 @@ pub struct SnapshotData {
     /// A snapshot of the physical memory state.
     pub physical: physical::PhysicalMemory,
 
+    /// The layout of the guest physical address space.
+    pub guest_physical: guest_physical::GuestPhysicalMap,
+
     /// The parent of this snapshot.
     pub parent: Option<Snapshot>,
 
     /// The snapshot state of all peripherals.
     // @todo: need to handle dynamic adding of I/O handlers.
     pub io: Vec<Box<dyn Any>>,
+
+    /// The number of times each I/O handler had been replaced when the snapshot was taken (see
+    /// [Mmu::replace_io_handler]).
+    pub io_generation: Vec<u32>,
+
+    /// The state of each shadow memory registered with the MMU (see [Mmu::add_shadow]).
+    pub shadows: Vec<shadow::ShadowMemory>,
 }
 
 impl SnapshotData {
@@ -188,8 +230,11 @@ impl SnapshotData {
         Self {
             mapping: VirtualMemoryMap::new(),
             physical: physical::PhysicalMemory::new(0),
+            guest_physical: guest_physical::GuestPhysicalMap::new(),
             parent: None,
             io: vec![],
+            io_generation: vec![],
+            shadows: vec![],
         }
     }
 }
@@ -0,0 +1,122 @@
+//! Memory mapped peripherals that operate on the values of loads and stores.
+//!
+//! [IoMemory] handlers operate on the raw bytes of an access, which is convenient for regions that
+//! behave like memory, but requires peripheral models (e.g. UARTs, timers, and flash controllers)
+//! to decode the access themselves. A [MmioHandler] is instead called with the address, size and
+//! value of each access, with the value converted from the byte order of the guest.
+
+use std::any::Any;
+
+use crate::{IoHandler, IoMemory, IoMemoryAny, MemResult, Mmu};
+
+/// A handler for a memory mapped peripheral, see [Mmu::map_mmio].
+pub trait MmioHandler {
+    /// Called for a load of `size` bytes from `addr`, returning the value read by the guest.
+    fn load(&mut self, addr: u64, size: u8) -> MemResult<u64>;
+
+    /// Called for a store of `size` bytes of `value` to `addr`.
+    fn store(&mut self, addr: u64, size: u8, value: u64) -> MemResult<()>;
+
+    /// Called when the guest is stuck in a loop polling `size` bytes at `addr` (e.g. waiting for a
+    /// status bit to be set). The handler can resolve the wait by updating its state, returning
+    /// the number of ticks of the virtual clock that the wait should take, or `None` if the wait
+    /// cannot be resolved.
+    fn resolve_poll(&mut self, addr: u64, size: u8) -> Option<u64> {
+        let _ = (addr, size);
+        None
+    }
+
+    fn snapshot(&mut self) -> Box<dyn Any> {
+        Box::new(())
+    }
+
+    fn restore(&mut self, snapshot: &Box<dyn Any>) {
+        let _ = snapshot;
+    }
+}
+
+/// Adapts a [MmioHandler] to the [IoMemory] interface used by the MMU.
+pub struct MmioRegion<T> {
+    pub handler: T,
+    big_endian: bool,
+}
+
+impl<T: MmioHandler> MmioRegion<T> {
+    pub fn new(handler: T, big_endian: bool) -> Self {
+        Self { handler, big_endian }
+    }
+}
+
+impl<T: MmioHandler> IoMemory for MmioRegion<T> {
+    fn read(&mut self, addr: u64, buf: &mut [u8]) -> MemResult<()> {
+        let size = buf.len().min(8);
+        let value = self.handler.load(addr, size as u8)?;
+        match self.big_endian {
+            true => buf[..size].copy_from_slice(&value.to_be_bytes()[8 - size..]),
+            false => buf[..size].copy_from_slice(&value.to_le_bytes()[..size]),
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, addr: u64, value: &[u8]) -> MemResult<()> {
+        let size = value.len().min(8);
+        let mut bytes = [0; 8];
+        let value = match self.big_endian {
+            true => {
+                bytes[8 - size..].copy_from_slice(&value[..size]);
+                u64::from_be_bytes(bytes)
+            }
+            false => {
+                bytes[..size].copy_from_slice(&value[..size]);
+                u64::from_le_bytes(bytes)
+            }
+        };
+        self.handler.store(addr, size as u8, value)
+    }
+
+    fn resolve_poll(&mut self, addr: u64, size: u8) -> Option<u64> {
+        self.handler.resolve_poll(addr, size)
+    }
+
+    fn snapshot(&mut self) -> Box<dyn Any> {
+        self.handler.snapshot()
+    }
+
+    fn restore(&mut self, snapshot: &Box<dyn Any>) {
+        self.handler.restore(snapshot)
+    }
+}
+
+impl Mmu {
+    /// Maps the region `start..start + len` to `handler`, which is called for every load and store
+    /// to the region. Returns `None` if the region could not be mapped.
+    pub fn map_mmio(
+        &mut self,
+        start: u64,
+        len: u64,
+        handler: impl MmioHandler + 'static,
+    ) -> Option<IoHandler> {
+        let id = self.register_io_handler(MmioRegion::new(handler, self.big_endian));
+        self.map_memory_len(start, len, id).then_some(id)
+    }
+
+    /// Replaces the handler for the peripheral mapped with [Mmu::map_mmio] as `id` (see
+    /// [Mmu::replace_io_handler]).
+    pub fn replace_mmio(
+        &mut self,
+        id: IoHandler,
+        handler: impl MmioHandler + 'static,
+    ) -> Box<dyn IoMemoryAny> {
+        self.replace_io_handler(id, MmioRegion::new(handler, self.big_endian))
+    }
+
+    /// Returns the handler registered by [Mmu::map_mmio] for `id`, or `None` if `id` refers to a
+    /// different type of handler.
+    pub fn get_mmio_handler_mut<T: MmioHandler + 'static>(
+        &mut self,
+        id: IoHandler,
+    ) -> Option<&mut T> {
+        let region = self.get_io_memory_mut(id).as_mut_any().downcast_mut::<MmioRegion<T>>()?;
+        Some(&mut region.handler)
+    }
+}
@@ -3,11 +3,13 @@ use ahash::AHashSet as HashSet;
 use tracing::debug;
 
 use crate::{
-    Addr, AllocLayout, IoHandler, IoMemory, IoMemoryAny, MemoryMapping, PhysicalMapping, Snapshot,
-    SnapshotData, VirtualMemoryMap,
+    Addr, AllocLayout, IoHandler, IoMemory, IoMemoryAny, MemoryMapping, PhysicalIoMapping,
+    PhysicalMapping, Snapshot, SnapshotData, VirtualMemoryMap,
+    guest_physical::{GuestPhysicalMap, GuestPhysicalRegion},
     perm::{self, MemError, MemResult},
     physical::{self, PageData, PhysicalAddr},
     range_map::RangeMap,
+    shadow::{ShadowId, ShadowMemory},
     tlb,
 };
 
@@ -15,6 +17,29 @@ pub const DETECT_SELF_MODIFYING_CODE: bool = true;
 pub const ENABLE_ZERO_PAGE_OPTIMIZATION: bool = true;
 pub const ENABLE_MEMORY_HOOKS: bool = true;
 
+/// A contiguous region of the virtual address space with the same kind of mapping.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MemoryRegion {
+    pub start: u64,
+
+    /// The address of the last byte in the region (inclusive).
+    pub end: u64,
+
+    /// The permissions of the first byte of the region. Permissions can be tracked at byte
+    /// granularity, so other bytes in the region may have different permissions.
+    pub perm: u8,
+
+    /// Whether the region is handled by an IO handler rather than backed by memory.
+    pub is_io: bool,
+}
+
+impl MemoryRegion {
+    /// The number of bytes in the region.
+    pub fn size(&self) -> u64 {
+        self.end - self.start + 1
+    }
+}
+
 pub trait ReadHook {
     fn read(&mut self, mem: &mut Mmu, addr: u64, size: u8) -> Option<u64>;
 }
@@ -55,6 +80,43 @@ where
     }
 }
 
+/// A hook that is called for every read from and write to a range of memory, which can replace the
+/// value that is read or written.
+pub trait AccessHook {
+    /// Called after `value` is read from `addr` (if `is_write` is false), or before `value` is
+    /// written to `addr`. Any changes to `value` are seen by the guest.
+    ///
+    /// Writes are only passed to the hook once the mapping and permissions of the target have been
+    /// checked, so the hook does not observe writes that fault. Accesses that are not naturally
+    /// aligned (or that cross a mapping boundary) are split into single byte accesses, so the hook
+    /// is called once for each byte of the access.
+    fn access(&mut self, mem: &mut Mmu, addr: u64, value: &mut [u8], is_write: bool);
+}
+
+impl<T> AccessHook for T
+where
+    T: FnMut(&mut Mmu, u64, &mut [u8], bool),
+{
+    fn access(&mut self, mem: &mut Mmu, addr: u64, value: &mut [u8], is_write: bool) {
+        self(mem, addr, value, is_write);
+    }
+}
+
+/// A hook that is called whenever a region of memory is mapped or its permissions are changed.
+pub trait PermHook {
+    /// Called after the permissions of the `len` bytes starting at `start` are set to `perm`.
+    fn perm_changed(&mut self, start: u64, len: u64, perm: u8);
+}
+
+impl<T> PermHook for T
+where
+    T: FnMut(u64, u64, u8),
+{
+    fn perm_changed(&mut self, start: u64, len: u64, perm: u8) {
+        self(start, len, perm);
+    }
+}
+
 pub struct HookEntry<T: ?Sized> {
     pub start: u64,
     pub end: u64,
@@ -62,11 +124,11 @@ pub struct HookEntry<T: ?Sized> {
 }
 
 impl<T: ?Sized> HookEntry<T> {
-    // @fixme: Handle case where self.end + page_size overflows.
     fn range(&self, page_size: u64) -> std::ops::RangeInclusive<u64> {
         let alignment_mask = !(page_size - 1);
         let start = self.start & alignment_mask;
-        let end = (self.end + page_size) & alignment_mask;
+        // Hooks that cover the end of the address space (e.g. `0..u64::MAX`) end at the last page.
+        let end = self.end.saturating_add(page_size) & alignment_mask;
         start..=end
     }
 }
@@ -83,11 +145,17 @@ impl<T: ?Sized> HookStore<T> {
 
     fn add(&mut self, start: u64, end: u64, handler: Box<T>) -> u32 {
         // Check if there is a dead slot that can be reused.
-        let id = self.hooks.iter().position(|x| x.handler.is_none()).unwrap_or_else(|| {
-            let id = self.hooks.len();
-            self.hooks.push(HookEntry { start, end, handler: Some(handler) });
-            id
-        });
+        let entry = HookEntry { start, end, handler: Some(handler) };
+        let id = match self.hooks.iter().position(|x| x.handler.is_none()) {
+            Some(id) => {
+                self.hooks[id] = entry;
+                id
+            }
+            None => {
+                self.hooks.push(entry);
+                self.hooks.len() - 1
+            }
+        };
         id.try_into().expect("too many hooks")
     }
 
@@ -137,14 +205,35 @@ pub struct Mmu {
     /// @fixme: handle self-modifying code more carefully.
     pub detect_self_modifying_code: bool,
 
+    /// Ranges of memory (start, end inclusive) where the guest generates code at runtime, see
+    /// [Mmu::add_dynamic_code_region].
+    dynamic_code: Vec<(u64, u64)>,
+
+    /// Whether the guest stores values in big-endian byte order, used for converting the values
+    /// passed to MMIO handlers (see [Mmu::map_mmio]).
+    pub big_endian: bool,
+
     pub tlb_hit_count: u64,
     pub tlb_miss_count: u64,
     pub mapping_changed: bool,
 
+    /// The number of times memory was restored by copying the entire state of a snapshot.
+    pub full_restore_count: u64,
+
+    /// The number of times memory was restored by [Mmu::restore_dirty_only] without falling back
+    /// to a full restore.
+    pub dirty_restore_count: u64,
+
+    /// The total number of pages reset by [Mmu::restore_dirty_only].
+    pub restored_page_count: u64,
+
     /// The set of virtual (page-aligned) addresses that have been modified since this was last
     /// cleared.
     pub modified: HashSet<u64>,
 
+    /// The (page-aligned) addresses of pages written to since [Mmu::start_write_log] was called.
+    write_log: Option<HashSet<u64>>,
+
     /// The translation lookahead buffer for the MMU.
     ///
     /// Note: care needs to be taken to ensure that the relevant entries in this cache are cleared
@@ -159,21 +248,32 @@ pub struct Mmu {
     read_hooks: HookStore<dyn ReadHook>,
     read_after_hooks: HookStore<dyn ReadAfterHook>,
     write_hooks: HookStore<dyn WriteHook>,
+    access_hooks: HookStore<dyn AccessHook>,
+    perm_hooks: Vec<Option<Box<dyn PermHook>>>,
 
     /// The underlying physical memory.
     physical: physical::PhysicalMemory,
 
+    /// The layout of the guest physical address space.
+    guest_physical: GuestPhysicalMap,
+
     /// The parent snapshot for the MMU.
     parent_state: Snapshot,
 
     /// Registed handlers for I/O memory
     io: Vec<Box<dyn IoMemoryAny>>,
 
+    /// The number of times each I/O handler has been replaced.
+    io_generation: Vec<u32>,
+
     /// Last IO memory region read -- IO reads are not currently translatable in the JIT, so always
     /// trigger tlb misses. To mitigate some of the performance impact of repeat accesses to the
     /// same address, we keep track of the last IO handler used and check if it matches the address
     /// before doing a search for the region.
-    last_io_handler: Option<(u64, u64, IoHandler)>,
+    last_io_handler: Option<(u64, u64, IoHandler, u64)>,
+
+    /// Shadow memory that is kept in sync with the mapping (see [Mmu::add_shadow]).
+    shadows: Vec<ShadowMemory>,
 }
 
 impl crate::Resettable for Mmu {
@@ -198,20 +298,31 @@ impl Mmu {
             invalidate_icache: false,
             track_uninitialized: false,
             detect_self_modifying_code: DETECT_SELF_MODIFYING_CODE,
+            dynamic_code: vec![],
+            big_endian: false,
             tlb_hit_count: 0,
             tlb_miss_count: 0,
             mapping_changed: false,
+            full_restore_count: 0,
+            dirty_restore_count: 0,
+            restored_page_count: 0,
             modified: HashSet::new(),
+            write_log: None,
             tlb: Box::new(tlb::TranslationCache::new()),
             mapping: RangeMap::new(),
             physical: physical::PhysicalMemory::new(physical::MAX_PAGES),
+            guest_physical: GuestPhysicalMap::new(),
             parent_state: Snapshot::new(SnapshotData::new()),
             io: vec![],
+            io_generation: vec![],
 
             read_hooks: HookStore::new(),
             read_after_hooks: HookStore::new(),
             write_hooks: HookStore::new(),
+            access_hooks: HookStore::new(),
+            perm_hooks: vec![],
             last_io_handler: None,
+            shadows: vec![],
         }
     }
 
@@ -267,14 +378,120 @@ impl Mmu {
         &mut self.read_after_hooks.hooks[id as usize]
     }
 
+    /// Adds a hook that is called for every read and write to `start..end`, see [AccessHook].
+    pub fn add_access_hook(
+        &mut self,
+        start: u64,
+        end: u64,
+        hook: Box<dyn AccessHook>,
+    ) -> Option<u32> {
+        self.tlb.clear();
+        Some(self.access_hooks.add(start, end, hook))
+    }
+
+    pub fn remove_access_hook(&mut self, id: u32) -> bool {
+        self.access_hooks.remove(id)
+    }
+
+    /// Adds a hook that is called whenever memory is mapped or its permissions are changed, see
+    /// [PermHook]. Unlike other hooks, permission hooks remain registered after [Mmu::clear].
+    pub fn add_perm_hook(&mut self, hook: Box<dyn PermHook>) -> u32 {
+        let id = match self.perm_hooks.iter().position(|x| x.is_none()) {
+            Some(id) => {
+                self.perm_hooks[id] = Some(hook);
+                id
+            }
+            None => {
+                self.perm_hooks.push(Some(hook));
+                self.perm_hooks.len() - 1
+            }
+        };
+        id.try_into().expect("too many hooks")
+    }
+
+    pub fn remove_perm_hook(&mut self, id: u32) -> bool {
+        self.perm_hooks.get_mut(id as usize).and_then(|x| x.take()).is_some()
+    }
+
+    fn notify_perm_changed(&mut self, start: u64, len: u64, perm: u8) {
+        if self.perm_hooks.is_empty() {
+            return;
+        }
+        let mut hooks = std::mem::take(&mut self.perm_hooks);
+        for hook in hooks.iter_mut().flatten() {
+            hook.perm_changed(start, len, perm);
+        }
+        self.perm_hooks = hooks;
+    }
+
+    /// Marks `start..=end` as memory that the guest generates code in at runtime (e.g. the code
+    /// cache of a JIT compiler). Code executed from these regions is not protected against
+    /// modification, so writes to the region never fail with [MemError::SelfModifyingCode].
+    /// Instead, the guest is expected to notify the emulator whenever it generates new code.
+    pub fn add_dynamic_code_region(&mut self, start: u64, end: u64) {
+        if !self.dynamic_code.contains(&(start, end)) {
+            self.dynamic_code.push((start, end));
+        }
+
+        // Allow code that has already been executed in the region to be modified.
+        let physical = &mut self.physical;
+        let _ = self.mapping.overlapping_mut::<_, MemError>(start..=end, |start, len, entry| {
+            if let Some(MemoryMapping::Physical(mapping)) = entry {
+                let page = physical.get_mut(mapping.index);
+                if page.executed {
+                    let offset = PageData::offset(start);
+                    for perm in &mut page.data_mut().perm[offset..offset + len as usize] {
+                        *perm &= !perm::IN_CODE_CACHE;
+                    }
+                }
+            }
+            Ok(())
+        });
+    }
+
+    /// Removes a region added by [Mmu::add_dynamic_code_region].
+    pub fn remove_dynamic_code_region(&mut self, start: u64, end: u64) -> bool {
+        let len = self.dynamic_code.len();
+        self.dynamic_code.retain(|x| *x != (start, end));
+        self.dynamic_code.len() != len
+    }
+
+    /// Returns whether any part of `start..=end` is inside of a dynamic code region.
+    pub fn is_dynamic_code(&self, start: u64, end: u64) -> bool {
+        self.dynamic_code.iter().any(|(x_start, x_end)| *x_start <= end && start <= *x_end)
+    }
+
     pub fn clear(&mut self) {
         self.tlb.clear();
         self.write_hooks.hooks.clear();
         self.read_hooks.hooks.clear();
         self.read_after_hooks.hooks.clear();
+        self.access_hooks.hooks.clear();
+        // Permission hooks observe the entire address space (rather than a region of it), so they
+        // remain registered.
+        self.dynamic_code.clear();
         self.mapping = RangeMap::new();
         self.physical.clear();
+        self.guest_physical.clear();
         self.last_io_handler = None;
+        self.shadows.iter_mut().for_each(|x| x.clear());
+    }
+
+    /// Registers a shadow memory with `bits` bits of metadata for every byte of guest memory (see
+    /// [crate::shadow]). The metadata of a region is cleared whenever the region is mapped or
+    /// unmapped, moves with the region in [Mmu::move_region_len], and is saved and restored as part
+    /// of snapshots.
+    pub fn add_shadow(&mut self, bits: u8) -> ShadowId {
+        self.shadows.push(ShadowMemory::new(bits));
+        ShadowId(self.shadows.len() - 1)
+    }
+
+    pub fn shadow(&self, id: ShadowId) -> &ShadowMemory {
+        &self.shadows[id.0]
+    }
+
+    pub fn shadow_mut(&mut self, id: ShadowId) -> &mut ShadowMemory {
+        &mut self.shadows[id.0]
     }
 
     /// Get size (in bytes) of a single page in physical memory.
@@ -399,14 +616,39 @@ impl Mmu {
     pub fn register_io_handler(&mut self, handler: impl IoMemory + 'static) -> IoHandler {
         let id = self.io.len();
         self.io.push(Box::new(handler));
+        self.io_generation.push(0);
         IoHandler(id)
     }
 
+    /// Replaces the handler registered for `id` with `handler`, returning the previous handler. All
+    /// memory mapped to `id` is handled by the new handler from the next access.
+    ///
+    /// The new handler may store a different snapshot state to the previous handler, so restoring
+    /// a snapshot taken before the handler was replaced leaves the state of the new handler as is.
+    pub fn replace_io_handler(
+        &mut self,
+        id: IoHandler,
+        handler: impl IoMemory + 'static,
+    ) -> Box<dyn IoMemoryAny> {
+        self.last_io_handler = None;
+        self.io_generation[id.0] += 1;
+        std::mem::replace(&mut self.io[id.0], Box::new(handler))
+    }
+
     /// Get the memory associated with an I/O handle
     pub fn get_io_memory_mut(&mut self, handler: IoHandler) -> &mut dyn IoMemoryAny {
         &mut *self.io[handler.0]
     }
 
+    /// Asks the I/O handler mapped at `addr` to resolve a busy-wait on `size` bytes at `addr` (see
+    /// [IoMemory::resolve_poll]). Returns `None` if `addr` is not mapped to an I/O handler.
+    pub fn resolve_io_poll(&mut self, addr: u64, size: u8) -> Option<u64> {
+        match self.mapping.get(addr)? {
+            MemoryMapping::Io(id) => self.io[*id].resolve_poll(addr, size),
+            _ => None,
+        }
+    }
+
     #[deprecated(
         note = "The behavior of this function may change in the future. Use `map_memory_len"
     )]
@@ -433,6 +675,7 @@ impl Mmu {
         };
         let mapping = mapping.into();
         debug!("map_memory: start={:#0x}, end={:#0x}, mapping={:?}", start, end, mapping);
+        let is_io = matches!(mapping, MemoryMapping::Io(_) | MemoryMapping::PhysicalIo(_));
 
         if let Err(e) = self.mapping.insert(start..=end, mapping) {
             debug!("map_memory: failed: {:0x?}", e);
@@ -441,6 +684,10 @@ impl Mmu {
         self.mapping_changed = true;
         self.tlb.remove_range(start, len);
         self.last_io_handler = None;
+        self.shadows.iter_mut().for_each(|x| x.clear_range(start, len));
+        if !is_io {
+            self.notify_perm_changed(start, len, self.get_perm(start));
+        }
 
         true
     }
@@ -453,6 +700,209 @@ impl Mmu {
         )
     }
 
+    /// Get the layout of the guest physical address space.
+    pub fn get_guest_physical_map(&self) -> &GuestPhysicalMap {
+        &self.guest_physical
+    }
+
+    /// Allocates `len` bytes of RAM at `paddr` in the guest physical address space. The RAM is
+    /// not accessible until it is mapped into the virtual address space with
+    /// [Mmu::map_guest_physical].
+    pub fn add_physical_ram(&mut self, paddr: u64, len: u64, perm: u8) -> MemResult<()> {
+        let (start, end) = self.guest_physical_range(paddr, len)?;
+        if self.guest_physical.overlaps(start, end) {
+            return Err(MemError::Unknown);
+        }
+        debug!("add_physical_ram: start={start:#x}, end={end:#x}, perm={}", perm::display(perm));
+
+        let init_perm = if self.track_uninitialized { perm::NONE } else { perm::INIT };
+        let pages = self.alloc_physical((len / self.page_size()) as usize)?;
+        for index in &pages {
+            let page = self.physical.get_mut(*index);
+            page.aliased = true;
+            let data = page.data_mut();
+            data.data.fill(0);
+            data.perm.fill(perm | perm::MAP | init_perm);
+        }
+        self.guest_physical.insert(start, end, GuestPhysicalRegion::Ram(pages));
+        Ok(())
+    }
+
+    /// Registers `handler` to handle accesses to the `len` bytes starting at `paddr` in the guest
+    /// physical address space.
+    pub fn add_physical_io(&mut self, paddr: u64, len: u64, handler: IoHandler) -> MemResult<()> {
+        let (start, end) = self.guest_physical_range(paddr, len)?;
+        debug!("add_physical_io: start={start:#x}, end={end:#x}, handler={handler:?}");
+        match self.guest_physical.insert(start, end, GuestPhysicalRegion::Io(handler)) {
+            true => Ok(()),
+            false => Err(MemError::Unknown),
+        }
+    }
+
+    /// Maps `len` bytes of the guest physical address space starting at `paddr` to the virtual
+    /// address `vaddr`. The same physical region can be mapped at multiple virtual addresses.
+    ///
+    /// Returns `true` if the memory was succesfully mapped.
+    pub fn map_guest_physical(&mut self, vaddr: u64, paddr: u64, len: u64) -> bool {
+        let Ok((pstart, pend)) = self.guest_physical_range(paddr, len)
+        else {
+            return false;
+        };
+        if vaddr & (self.page_size() - 1) != 0 || vaddr.checked_add(len - 1).is_none() {
+            return false;
+        }
+        debug!("map_guest_physical: vaddr={vaddr:#x}, paddr={pstart:#x}, len={len:#x}");
+
+        let (region_start, region_end, region) = match self.guest_physical.get(pstart) {
+            Some((start, end, region)) if pend <= end => (start, end, region.clone()),
+            _ => {
+                debug!("map_guest_physical: {pstart:#x}..={pend:#x} is not backed by a region");
+                return false;
+            }
+        };
+        if self.mapping.overlapping_iter((vaddr, vaddr + (len - 1))).any(|(_, _, x)| x.is_some()) {
+            debug!("map_guest_physical: virtual region is already mapped");
+            return false;
+        }
+
+        match region {
+            GuestPhysicalRegion::Ram(pages) => {
+                let page_size = self.page_size();
+                let first = ((pstart - region_start) / page_size) as usize;
+                for (i, index) in pages[first..].iter().take((len / page_size) as usize).enumerate()
+                {
+                    let addr = vaddr + i as u64 * page_size;
+                    if !self.map_physical(addr, *index) {
+                        return false;
+                    }
+                }
+                true
+            }
+            GuestPhysicalRegion::Io(handler) => {
+                tracing::trace!("mapping io region: {region_start:#x}..={region_end:#x}");
+                let offset = pstart.wrapping_sub(vaddr);
+                self.map_memory_len(
+                    vaddr,
+                    len,
+                    MemoryMapping::PhysicalIo(PhysicalIoMapping { id: handler.0, offset }),
+                )
+            }
+        }
+    }
+
+    /// Reads from the guest physical address space without performing any permission checks.
+    pub fn read_guest_physical(&mut self, mut paddr: u64, buf: &mut [u8]) -> MemResult<()> {
+        let mut buf = buf;
+        while !buf.is_empty() {
+            let (start, end, region) = self.guest_physical.get(paddr).ok_or(MemError::Unmapped)?;
+            let len = ((end - paddr) as usize + 1).min(buf.len());
+            match region {
+                GuestPhysicalRegion::Ram(pages) => {
+                    let page_size = self.page_size();
+                    let (chunk, rest) = buf.split_at_mut(len);
+                    for (i, byte) in chunk.iter_mut().enumerate() {
+                        let addr = paddr + i as u64;
+                        let index = pages[((addr - start) / page_size) as usize];
+                        *byte = self.physical.get(index).data().data[PageData::offset(addr)];
+                    }
+                    buf = rest;
+                }
+                GuestPhysicalRegion::Io(handler) => {
+                    let id = handler.0;
+                    let (chunk, rest) = buf.split_at_mut(len);
+                    self.io[id].read(paddr, chunk)?;
+                    buf = rest;
+                }
+            }
+            paddr += len as u64;
+        }
+        Ok(())
+    }
+
+    /// Writes to the guest physical address space without performing any permission checks (e.g.
+    /// for modelling DMA).
+    pub fn write_guest_physical(&mut self, mut paddr: u64, mut buf: &[u8]) -> MemResult<()> {
+        while !buf.is_empty() {
+            let (start, end, region) = self.guest_physical.get(paddr).ok_or(MemError::Unmapped)?;
+            let region = region.clone();
+            let len = ((end - paddr) as usize + 1).min(buf.len());
+            let (chunk, rest) = buf.split_at(len);
+            match region {
+                GuestPhysicalRegion::Ram(pages) => {
+                    let page_size = self.page_size();
+                    let mut offset = 0;
+                    while offset < chunk.len() {
+                        let addr = paddr + offset as u64;
+                        let page_offset = PageData::offset(addr);
+                        let n = (page_size as usize - page_offset).min(chunk.len() - offset);
+                        let mut index = pages[((addr - start) / page_size) as usize];
+                        if self.physical.get(index).copy_on_write {
+                            let copy =
+                                self.physical.clone_page(index).ok_or(MemError::OutOfMemory)?;
+                            self.physical.get_mut(copy).aliased = true;
+                            self.remap_aliases(index, copy);
+                            index = copy;
+                        }
+
+                        let page = self.physical.get_mut(index);
+                        if page.executed && self.detect_self_modifying_code {
+                            check_self_modifying_write(page.data(), addr, &chunk[offset..][..n])?;
+                        }
+                        page.modified = true;
+                        page.data_mut().data[page_offset..][..n]
+                            .copy_from_slice(&chunk[offset..][..n]);
+                        // Cached translations may point to an older copy of the page.
+                        self.invalidate_aliases(index);
+
+                        offset += n;
+                    }
+                }
+                GuestPhysicalRegion::Io(handler) => {
+                    let id = handler.0;
+                    self.io[id].write(paddr, chunk)?;
+                }
+            }
+            buf = rest;
+            paddr += len as u64;
+        }
+        Ok(())
+    }
+
+    /// Checks that `paddr` and `len` describe a non-empty page aligned range returning the start
+    /// and (inclusive) end of the range.
+    fn guest_physical_range(&self, paddr: u64, len: u64) -> MemResult<(u64, u64)> {
+        let mask = self.page_size() - 1;
+        if len == 0 || paddr & mask != 0 || len & mask != 0 {
+            return Err(MemError::Unaligned);
+        }
+        let end = paddr.checked_add(len - 1).ok_or(MemError::AddressOverflow)?;
+        Ok((paddr, end))
+    }
+
+    /// Invalidates any TLB entries for virtual addresses that reference the physical page at
+    /// `index`.
+    fn invalidate_aliases(&mut self, index: physical::Index) {
+        for (start, end, entry) in self.mapping.iter() {
+            if matches!(entry, MemoryMapping::Physical(x) if x.index == index) {
+                self.tlb.remove_range(start, (end - start) + 1);
+            }
+        }
+    }
+
+    /// Updates all virtual mappings (and guest physical RAM) that reference the physical page at
+    /// `old` to reference `new`.
+    fn remap_aliases(&mut self, old: physical::Index, new: physical::Index) {
+        self.guest_physical.replace_page(old, new);
+        for (start, end, entry) in self.mapping.iter_mut() {
+            if let MemoryMapping::Physical(mapping) = entry {
+                if mapping.index == old {
+                    mapping.index = new;
+                    self.tlb.remove_range(start, (end - start) + 1);
+                }
+            }
+        }
+    }
+
     /// Unmaps the region of memory between `start` and `start+len`
     #[deprecated(
         note = "The behavior of this function may change in the future. Use `unmap_memory_len`"
@@ -473,6 +923,7 @@ impl Mmu {
 
         debug!("unmap_memory: start={:#0x}, end={:#0x}", start, end);
         self.mapping_changed = true;
+        self.shadows.iter_mut().for_each(|x| x.clear_range(start, len));
 
         let physical = &mut self.physical;
         let tlb = &mut self.tlb;
@@ -559,7 +1010,7 @@ impl Mmu {
 
         let physical = &mut self.physical;
         let tlb = &mut self.tlb;
-        self.mapping.overlapping_mut(addr..=end, |start, len, entry| {
+        let result = self.mapping.overlapping_mut(addr..=end, |start, len, entry| {
             match entry.as_mut().ok_or(MemError::Unmapped)? {
                 MemoryMapping::Physical(entry) => 'physical: {
                     tlb.remove_range(start, len);
@@ -582,13 +1033,18 @@ impl Mmu {
                     page.data_mut().perm[offset..offset + len].fill(perm);
                 }
                 MemoryMapping::Unallocated(entry) => entry.perm = perm,
-                MemoryMapping::Io(_) => {
-                    unimplemented!("attempted to update permission of I/O region")
+                MemoryMapping::Io(_) | MemoryMapping::PhysicalIo(_) => {
+                    debug!("update_perm: attempted to update permission of I/O region");
+                    return Err(MemError::Unknown);
                 }
             }
 
             Ok(())
-        })
+        });
+        if result.is_ok() {
+            self.notify_perm_changed(addr, count, perm);
+        }
+        result
     }
 
     /// Fill a region of memory with `value`
@@ -628,8 +1084,9 @@ impl Mmu {
                     entry.value = value;
                     entry.perm |= perm::INIT;
                 }
-                MemoryMapping::Io(_) => {
-                    unimplemented!("attempted to memset an I/O region")
+                MemoryMapping::Io(_) | MemoryMapping::PhysicalIo(_) => {
+                    debug!("fill_mem: attempted to memset an I/O region");
+                    return Err(MemError::Unknown);
                 }
             }
             Ok(())
@@ -664,6 +1121,7 @@ impl Mmu {
 
             end = overlap_start
         }
+        self.shadows.iter_mut().for_each(|x| x.move_range(start, dst, len));
         Ok(())
     }
 
@@ -691,11 +1149,17 @@ impl Mmu {
         // TLB is invalidated whenever we clone the physical memory state.
         self.tlb.clear();
 
+        // Modifications are now tracked relative to the new snapshot.
+        self.physical.clear_dirty();
+
         let snapshot = SnapshotData {
             mapping: self.mapping.clone(),
             physical: self.physical.snapshot(),
+            guest_physical: self.guest_physical.clone(),
             parent: Some(self.parent_state.clone()),
             io: self.io.iter_mut().map(|x| x.snapshot()).collect(),
+            io_generation: self.io_generation.clone(),
+            shadows: self.shadows.iter_mut().map(|x| x.snapshot()).collect(),
         };
 
         // Reconfigure the current modification state to be tracked based on the new snapshot
@@ -703,6 +1167,26 @@ impl Mmu {
         self.parent_state.clone()
     }
 
+    /// Create a copy-on-write copy of the current memory state that can later be restored.
+    ///
+    /// Unlike [Mmu::snapshot], the fork does not become the parent of the current state, so forks
+    /// can be created repeatedly without keeping every previous state alive.
+    pub fn fork(&mut self) -> Snapshot {
+        // The TLB contains pointers to page content that is about to become shared.
+        self.tlb.clear();
+        self.last_io_handler = None;
+
+        std::sync::Arc::new(SnapshotData {
+            mapping: self.mapping.clone(),
+            physical: self.physical.snapshot(),
+            guest_physical: self.guest_physical.clone(),
+            parent: None,
+            io: self.io.iter_mut().map(|x| x.snapshot()).collect(),
+            io_generation: self.io_generation.clone(),
+            shadows: self.shadows.iter_mut().map(|x| x.snapshot()).collect(),
+        })
+    }
+
     /// Restore the full memory state from `snapshot`
     pub fn restore(&mut self, snapshot: Snapshot) {
         self.tlb.clear();
@@ -710,15 +1194,95 @@ impl Mmu {
 
         self.modified.clear();
         self.mapping_changed = true;
+        self.full_restore_count += 1;
 
         self.physical.restore(&snapshot.physical);
-        self.io.iter_mut().zip(&snapshot.io).for_each(|(io, snapshot)| io.restore(snapshot));
+        self.restore_io(&snapshot);
+        self.restore_shadows(&snapshot);
 
         // Configure our state to match the snapshot
         self.mapping.clone_from(&snapshot.mapping);
+        self.guest_physical.clone_from(&snapshot.guest_physical);
         self.parent_state = snapshot;
     }
 
+    /// Restore the memory state from `snapshot`, only resetting the pages that have been modified
+    /// since `snapshot` was created or last restored.
+    ///
+    /// This is much cheaper than [Mmu::restore] when only a few pages are modified between resets
+    /// (e.g. for persistent-mode fuzzing). If `snapshot` is not the most recent snapshot created
+    /// or restored by this MMU, this falls back to a full restore.
+    pub fn restore_dirty_only(&mut self, snapshot: &Snapshot) {
+        if !std::sync::Arc::ptr_eq(&self.parent_state, snapshot) {
+            self.restore(snapshot.clone());
+            return;
+        }
+        let Some(count) = self.physical.restore_dirty(&snapshot.physical)
+        else {
+            self.restore(snapshot.clone());
+            return;
+        };
+        tracing::trace!("restored {count} dirty pages");
+
+        self.tlb.clear();
+        self.last_io_handler = None;
+
+        self.modified.clear();
+        self.mapping_changed = true;
+        self.dirty_restore_count += 1;
+        self.restored_page_count += count as u64;
+
+        self.restore_io(snapshot);
+        self.restore_shadows(snapshot);
+
+        // The mapping only refers to pages, so restoring it does not copy any page content.
+        self.mapping.clone_from(&snapshot.mapping);
+        self.guest_physical.clone_from(&snapshot.guest_physical);
+    }
+
+    fn restore_shadows(&mut self, snapshot: &SnapshotData) {
+        // Shadows registered after the snapshot was taken are left unchanged.
+        for (shadow, saved) in self.shadows.iter_mut().zip(&snapshot.shadows) {
+            shadow.restore(saved);
+        }
+    }
+
+    fn restore_io(&mut self, snapshot: &SnapshotData) {
+        let handlers = self.io.iter_mut().zip(&self.io_generation);
+        let states = snapshot.io.iter().zip(&snapshot.io_generation);
+        for ((io, generation), (state, snapshot_generation)) in handlers.zip(states) {
+            // Skip handlers that were replaced after the snapshot was taken.
+            if generation == snapshot_generation {
+                io.restore(state);
+            }
+        }
+    }
+
+    /// Get the number of pages that may have been modified since the last snapshot or restore
+    /// (i.e. the number of pages that the next call to [Mmu::restore_dirty_only] will reset).
+    pub fn dirty_page_count(&self) -> usize {
+        self.physical.dirty_pages()
+    }
+
+    /// Returns whether the page containing `addr` is mapped to the same content in `snapshot` as
+    /// in the current state.
+    pub fn is_page_unchanged(&self, snapshot: &Snapshot, addr: u64) -> bool {
+        match (self.mapping.get(addr), snapshot.mapping.get(addr)) {
+            (Some(MemoryMapping::Physical(a)), Some(MemoryMapping::Physical(b))) => {
+                // Pages are copied when code is marked as executed, so compare the data and the
+                // permissions (other than `IN_CODE_CACHE`) if the page is no longer shared.
+                let (a, b) = (self.physical.get(a.index), snapshot.physical.get(b.index));
+                if a.shares_data(b) {
+                    return true;
+                }
+                let (a, b) = (a.data(), b.data());
+                a.data == b.data
+                    && a.perm.iter().zip(&b.perm).all(|(a, b)| (a ^ b) & !perm::IN_CODE_CACHE == 0)
+            }
+            _ => false,
+        }
+    }
+
     /// Create a snapshot of just the virtual address space
     pub fn snapshot_virtual_mapping(&mut self) -> VirtualMemoryMap {
         // Clear the TLB to ensure that no writes will be missed.
@@ -763,6 +1327,21 @@ impl Mmu {
         self.mapping_changed = true;
     }
 
+    /// Starts logging the pages of regular memory that are written to (e.g. to capture the side
+    /// effects of an operation that writes to guest memory), see [Mmu::take_write_log].
+    pub fn start_write_log(&mut self) {
+        // Writes that hit in the TLB are never logged, so ensure that the first write to each page
+        // goes through the slow path.
+        self.tlb.clear_write();
+        self.write_log = Some(HashSet::new());
+    }
+
+    /// Stops logging writes, returning the addresses of the pages that were written to since
+    /// [Mmu::start_write_log] was called.
+    pub fn take_write_log(&mut self) -> HashSet<u64> {
+        self.write_log.take().unwrap_or_default()
+    }
+
     /// Clear the page modification log
     pub fn clear_page_modification_log(&mut self) {
         self.tlb.clear_write();
@@ -783,7 +1362,7 @@ impl Mmu {
                 page.perm[offset]
             }
             MemoryMapping::Unallocated(metadata) => metadata.perm,
-            MemoryMapping::Io(_) => {
+            MemoryMapping::Io(_) | MemoryMapping::PhysicalIo(_) => {
                 // @fixme?
                 perm::NONE
             }
@@ -817,7 +1396,11 @@ impl Mmu {
 
                     // Prevent writes to the region we are executing (we don't currently support
                     // self modifying code).
-                    if self.detect_self_modifying_code {
+                    let dynamic_code = self
+                        .dynamic_code
+                        .iter()
+                        .any(|(x_start, x_end)| *x_start < start + len as u64 && start <= *x_end);
+                    if self.detect_self_modifying_code && !dynamic_code {
                         unsafe {
                             page.write_ptr().ptr.as_mut().add_perm_unchecked(
                                 offset,
@@ -853,7 +1436,7 @@ impl Mmu {
                     });
                 }
                 MemoryMapping::Unallocated(x) => x.perm &= !perm::EXEC,
-                MemoryMapping::Io(_) => {}
+                MemoryMapping::Io(_) | MemoryMapping::PhysicalIo(_) => {}
             }
         }
     }
@@ -926,7 +1509,9 @@ impl Mmu {
                     *entry = Some(MemoryMapping::Physical(new_mapping));
                     return Ok(());
                 }
-                Some(MemoryMapping::Io(_)) => (crate::UNINIT_VALUE, perm::NONE),
+                Some(MemoryMapping::Io(_) | MemoryMapping::PhysicalIo(_)) => {
+                    (crate::UNINIT_VALUE, perm::NONE)
+                }
                 None => (crate::UNINIT_VALUE, perm::NONE),
             };
 
@@ -974,6 +1559,27 @@ impl Mmu {
         true
     }
 
+    /// Gets a list of all mapped regions in the virtual address space. Adjacent entries of the
+    /// same kind and permission are merged into a single region.
+    pub fn regions(&self) -> Vec<MemoryRegion> {
+        let mut regions: Vec<MemoryRegion> = vec![];
+        for (start, end, entry) in self.mapping.iter() {
+            let is_io = matches!(entry, MemoryMapping::Io(_) | MemoryMapping::PhysicalIo(_));
+            let perm = self.get_perm(start);
+            match regions.last_mut() {
+                Some(prev)
+                    if prev.end.checked_add(1) == Some(start)
+                        && prev.is_io == is_io
+                        && prev.perm == perm =>
+                {
+                    prev.end = end;
+                }
+                _ => regions.push(MemoryRegion { start, end, perm, is_io }),
+            }
+        }
+        regions
+    }
+
     /// Gets the physical address assocated with a virtual address, returning `None` if `addr` is
     /// unmapped or unallocated
     pub fn get_physical_addr(&self, addr: u64) -> Option<PhysicalAddr> {
@@ -1013,13 +1619,15 @@ impl Mmu {
         perm: u8,
     ) -> MemResult<[u8; N]> {
         let page_size = self.page_size();
-        let page = self.physical.get_mut(index);
+        // Reading does not modify the page, so avoid marking it as dirty.
+        let page = self.physical.get(index);
         let result = page.data().read(addr, perm)?;
 
         // If there is no memory hook set on the current page, cache the translated address in the
         // TLB.
         let uncachable = self.read_hooks.contains_address(addr, page_size)
-            || self.read_after_hooks.contains_address(addr, page_size);
+            || self.read_after_hooks.contains_address(addr, page_size)
+            || self.access_hooks.contains_address(addr, page_size);
         if !uncachable {
             self.tlb.insert_read(addr, unsafe { page.read_ptr() });
         }
@@ -1036,27 +1644,39 @@ impl Mmu {
         let page_start = self.page_aligned(addr);
         let page_size = self.page_size();
 
+        let mut index = index;
         let mut page = self.physical.get_mut(index);
         if page.executed && self.detect_self_modifying_code {
             check_self_modifying_write(page.data(), addr, &value)?;
         }
+        let aliased = page.aliased;
 
         if page.copy_on_write {
             // Make a copy and update the mapping to point to the new copy.
             let copy_index = self.physical.clone_page(index).ok_or(MemError::OutOfMemory)?;
-            let copy_mapping = PhysicalMapping { index: copy_index, addr: page_start };
             tracing::trace!("{:?} ({:#0x}) copy-on-write -> {copy_index:?}", index, page_start);
 
-            let page_end = page_start + (page_size - 1);
-            self.mapping.overlapping_mut(page_start..=page_end, |_start, _end, entry| {
-                if let Some(mapping @ MemoryMapping::Physical(_)) = entry {
-                    *mapping = MemoryMapping::Physical(copy_mapping);
-                }
-                Ok(())
-            })?;
+            if aliased {
+                // The page may be mapped at other locations in this address space, so all aliases
+                // need to be updated to see the copy.
+                self.physical.get_mut(copy_index).aliased = true;
+                self.remap_aliases(index, copy_index);
+            }
+            else {
+                let copy_mapping = PhysicalMapping { index: copy_index, addr: page_start };
+                let page_end = page_start + (page_size - 1);
+                self.mapping.overlapping_mut(page_start..=page_end, |_start, _end, entry| {
+                    if let Some(mapping @ MemoryMapping::Physical(_)) = entry {
+                        *mapping = MemoryMapping::Physical(copy_mapping);
+                    }
+                    Ok(())
+                })?;
+            }
 
+            index = copy_index;
             page = self.physical.get_mut(copy_index);
         }
+        let prev_data = aliased.then(|| unsafe { page.read_ptr() }.ptr);
 
         // `data_mut` may cause a new copy of page to be created, so invalidate the read entry for
         // the TLB cache.
@@ -1067,10 +1687,21 @@ impl Mmu {
         if !page.modified {
             self.modified.insert(page_start);
         }
+        if let Some(log) = self.write_log.as_mut() {
+            log.insert(page_start);
+        }
         page.modified = true;
         page.data_mut().write(addr, value, perm)?;
 
-        let uncachable = self.write_hooks.contains_address(addr, page_size);
+        // If `data_mut` created a new copy of an aliased page, then other virtual addresses that
+        // map this page may have stale entries in the TLB.
+        if prev_data.is_some_and(|prev| prev != unsafe { page.read_ptr() }.ptr) {
+            self.invalidate_aliases(index);
+            page = self.physical.get_mut(index);
+        }
+
+        let uncachable = self.write_hooks.contains_address(addr, page_size)
+            || self.access_hooks.contains_address(addr, page_size);
         if !uncachable {
             // Safety: `page.data_mut()` ensures the page is a unique copy of the underlying data.
             self.tlb.insert_write(page_start, unsafe { page.write_ptr() });
@@ -1079,6 +1710,23 @@ impl Mmu {
         Ok(())
     }
 
+    /// Checks that an `N` byte write to `addr` is allowed by the mapping, without modifying memory.
+    /// I/O handlers perform their own checks, so writes to I/O regions are always allowed.
+    fn check_write<const N: usize>(&self, addr: u64, perm: u8) -> MemResult<()> {
+        match self.mapping.get(addr).ok_or(MemError::Unmapped)? {
+            MemoryMapping::Physical(entry) => {
+                let offset = PageData::offset(addr);
+                let page = self.physical.get(entry.index).data();
+                perm::check_bytes::<N>(
+                    page.perm[offset..offset + N].try_into().unwrap(),
+                    perm | perm::MAP,
+                )
+            }
+            MemoryMapping::Unallocated(entry) => perm::check(entry.perm | perm::MAP, perm),
+            MemoryMapping::Io(_) | MemoryMapping::PhysicalIo(_) => Ok(()),
+        }
+    }
+
     #[cold]
     fn read_unaligned<const N: usize>(&mut self, addr: u64, perm: u8) -> MemResult<[u8; N]> {
         let mut value = [0; N];
@@ -1116,6 +1764,9 @@ impl Mmu {
                             let mut buf = [0; N];
                             buf.copy_from_slice(&result.to_le_bytes()[..N]);
                             self.read_hooks.hooks = hooks;
+                            active_hooks!(addr, self.access_hooks, |hook: &mut dyn AccessHook| {
+                                hook.access(self, addr, &mut buf, false)
+                            });
                             return Ok(buf);
                         }
                     }
@@ -1126,18 +1777,19 @@ impl Mmu {
         }
 
         macro_rules! handle_io {
-            ($id:expr) => {
+            ($id:expr, $addr:expr) => {
                 (|| {
                     let mut buf = [0; N];
-                    self.io[$id].read(addr, &mut buf)?;
+                    self.io[$id].read($addr, &mut buf)?;
                     Ok(buf)
                 })()
             };
         }
 
-        let result = match self.last_io_handler.as_ref() {
-            Some((start, end, id)) if (*start..=*end).contains(&addr) => {
-                handle_io!(id.0)
+        let mut result = match self.last_io_handler.as_ref() {
+            Some((start, end, id, offset)) if (*start..=*end).contains(&addr) => {
+                let (id, io_addr) = (id.0, addr.wrapping_add(*offset));
+                handle_io!(id, io_addr)
             }
             _ => {
                 tracing::trace!("read_tlb_miss: {:#0x}", self.page_aligned(addr));
@@ -1152,8 +1804,14 @@ impl Mmu {
                         self.read_physical(index, addr, perm)
                     }
                     (start, end, MemoryMapping::Io(id)) => {
-                        self.last_io_handler = Some((start, end, IoHandler(*id)));
-                        handle_io!(*id)
+                        self.last_io_handler = Some((start, end, IoHandler(*id), 0));
+                        handle_io!(*id, addr)
+                    }
+                    (start, end, MemoryMapping::PhysicalIo(entry)) => {
+                        let entry = *entry;
+                        self.last_io_handler =
+                            Some((start, end, IoHandler(entry.id), entry.offset));
+                        handle_io!(entry.id, entry.guest_physical_addr(addr))
                     }
                 }
             }
@@ -1166,10 +1824,13 @@ impl Mmu {
             return self.read_unaligned(addr, perm);
         }
 
-        if let Ok(value) = result {
+        if let Ok(value) = result.as_mut() {
             if perm != perm::NONE && ENABLE_MEMORY_HOOKS {
+                active_hooks!(addr, self.access_hooks, |hook: &mut dyn AccessHook| {
+                    hook.access(self, addr, value, false)
+                });
                 active_hooks!(addr, self.read_after_hooks, |hook: &mut dyn ReadAfterHook| {
-                    hook.read(self, addr, &value)
+                    hook.read(self, addr, value)
                 })
             }
         }
@@ -1188,6 +1849,20 @@ impl Mmu {
             return self.write_unaligned(addr, value, perm);
         }
 
+        let mut value = value;
+        if perm != perm::NONE && ENABLE_MEMORY_HOOKS && !self.access_hooks.hooks.is_empty() {
+            match self.check_write::<N>(addr, perm) {
+                Ok(()) => {}
+                Err(MemError::Unmapped) if N != 1 => {
+                    return self.write_unaligned(addr, value, perm);
+                }
+                Err(e) => return Err(e),
+            }
+            active_hooks!(addr, self.access_hooks, |hook: &mut dyn AccessHook| {
+                hook.access(self, addr, &mut value, true)
+            })
+        }
+
         tracing::trace!("write_tlb_miss: {:#0x}", self.page_aligned(addr));
         self.tlb_miss_count += 1;
         let result = match self.mapping.get(addr).ok_or(MemError::Unmapped)? {
@@ -1198,6 +1873,10 @@ impl Mmu {
                 self.write_physical(index, addr, value, perm)
             }
             MemoryMapping::Io(id) => self.io[*id].write(addr, &value),
+            MemoryMapping::PhysicalIo(entry) => {
+                let entry = *entry;
+                self.io[entry.id].write(entry.guest_physical_addr(addr), &value)
+            }
         };
 
         // Handle case where we are writing across a mapping boundary (see `read_tlb_miss`).
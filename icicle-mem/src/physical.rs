@@ -54,6 +54,9 @@ pub struct PhysicalMemory {
     capacity: usize,
     allocated: Vec<Page>,
     free: Vec<Index>,
+
+    /// Pages that may have been modified since the last snapshot or restore.
+    dirty: Vec<Index>,
 }
 
 impl PhysicalMemory {
@@ -64,7 +67,12 @@ impl PhysicalMemory {
     pub fn new(capacity: usize) -> Self {
         let zero_page_read_only = Page::zero_page(Self::READ_ONLY_ZERO_PERM, false);
         let zero_page_read_write = Page::zero_page(Self::READ_WRITE_ZERO_PERM, true);
-        Self { capacity, allocated: vec![zero_page_read_only, zero_page_read_write], free: vec![] }
+        Self {
+            capacity,
+            allocated: vec![zero_page_read_only, zero_page_read_write],
+            free: vec![],
+            dirty: vec![],
+        }
     }
 
     #[inline]
@@ -97,7 +105,7 @@ impl PhysicalMemory {
                 Index((self.allocated.len() - 1).try_into().unwrap())
             }
         };
-        self.allocated[index.0 as usize].clear();
+        self.get_mut(index).clear();
         Some(index)
     }
 
@@ -135,9 +143,32 @@ impl PhysicalMemory {
 
     #[inline]
     pub fn get_mut(&mut self, index: Index) -> &mut Page {
+        self.mark_dirty(index);
         &mut self.allocated[index.0 as usize]
     }
 
+    #[inline]
+    fn mark_dirty(&mut self, index: Index) {
+        let page = &mut self.allocated[index.0 as usize];
+        if !page.dirty {
+            page.dirty = true;
+            self.dirty.push(index);
+        }
+    }
+
+    /// Get the number of pages that may have been modified since the last snapshot or restore.
+    pub fn dirty_pages(&self) -> usize {
+        self.dirty.len()
+    }
+
+    /// Start tracking modifications relative to the current state (called when the current state
+    /// is snapshotted).
+    pub fn clear_dirty(&mut self) {
+        for index in self.dirty.drain(..) {
+            self.allocated[index.0 as usize].dirty = false;
+        }
+    }
+
     #[inline]
     pub fn address_of(&self, vaddr: u64, index: Index) -> PhysicalAddr {
         let base = (index.0 << OFFSET_BITS) as u64;
@@ -157,6 +188,8 @@ impl PhysicalMemory {
     pub fn get_pair_mut(&mut self, a: Index, b: Index) -> (&mut Page, &mut Page) {
         let end = self.allocated.len() as u32;
         assert!(a.0 != b.0 && a.0 < end && b.0 <= end);
+        self.mark_dirty(a);
+        self.mark_dirty(b);
 
         // Safety: we have ensured that both indices are inbounds and are distinct.
         unsafe {
@@ -169,15 +202,60 @@ impl PhysicalMemory {
         // Remove all allocated memory except the zero page.
         self.allocated.truncate(2);
         self.free.clear();
+        self.dirty.retain(|index| index.is_zero_page());
     }
 
     pub fn snapshot(&self) -> Self {
-        Self { capacity: self.capacity, allocated: self.allocated.clone(), free: self.free.clone() }
+        Self {
+            capacity: self.capacity,
+            allocated: self.allocated.clone(),
+            free: self.free.clone(),
+            dirty: vec![],
+        }
     }
 
     pub fn restore(&mut self, snapshot: &Self) {
         self.allocated.clone_from(&snapshot.allocated);
         self.free.clone_from(&snapshot.free);
+        self.dirty.clear();
+    }
+
+    /// Restore only the pages that may have been modified since `snapshot` was taken or restored,
+    /// returning the number of pages that were restored.
+    ///
+    /// The caller must ensure that `snapshot` is the most recent snapshot taken or restored.
+    /// Returns `None` (without changing any state) if pages that existed in `snapshot` have been
+    /// removed since, in which case a full restore is required.
+    pub fn restore_dirty(&mut self, snapshot: &Self) -> Option<usize> {
+        if self.allocated.len() < snapshot.allocated.len() {
+            return None;
+        }
+
+        let count = self.dirty.len();
+        for index in self.dirty.drain(..) {
+            // Pages allocated after the snapshot was taken are removed below.
+            if let Some(page) = snapshot.allocated.get(index.0 as usize) {
+                self.allocated[index.0 as usize].clone_from(page);
+            }
+        }
+        self.allocated.truncate(snapshot.allocated.len());
+        self.free.clone_from(&snapshot.free);
+
+        Some(count)
+    }
+
+    /// Counts the pages that do not share their content with the page at the same index in
+    /// `other`. For a snapshot, this is the number of pages that were copied since `other` was
+    /// taken.
+    pub fn count_unshared_pages(&self, other: &Self) -> usize {
+        self.allocated
+            .iter()
+            .enumerate()
+            .filter(|(i, page)| match other.allocated.get(*i) {
+                Some(other) => !page.shares_data(other),
+                None => true,
+            })
+            .count()
     }
 }
 
@@ -194,8 +272,16 @@ pub struct Page {
     /// Keeps track of whether this page has been written to.
     pub modified: bool,
 
+    /// Keeps track of whether this page is in the dirty list of the physical memory it belongs to.
+    /// Always false for copies of the page, since they are not part of the list.
+    dirty: bool,
+
     /// Keeps track of whether code within this page has been lifted.
     pub executed: bool,
+
+    /// Keeps track of whether this page may be mapped at more than one virtual address (e.g. guest
+    /// RAM mapped through [crate::guest_physical]).
+    pub aliased: bool,
 }
 
 impl Clone for Page {
@@ -205,7 +291,9 @@ impl Clone for Page {
             data: Rc::clone(unsafe { self.data.get().as_ref().unwrap() }).into(),
             copy_on_write: self.copy_on_write,
             modified: self.modified,
+            dirty: false,
             executed: self.executed,
+            aliased: self.aliased,
         }
     }
 }
@@ -215,8 +303,10 @@ impl Page {
         Self {
             data: UnsafeCell::new(Rc::default()),
             modified: false,
+            dirty: false,
             copy_on_write: false,
             executed: false,
+            aliased: false,
         }
     }
 
@@ -236,6 +326,16 @@ impl Page {
         self.modified = false;
         self.copy_on_write = false;
         self.executed = false;
+        self.aliased = false;
+    }
+
+    /// Returns whether this page refers to the same copy of the page content as `other` (in which
+    /// case the content is guaranteed to be identical).
+    pub fn shares_data(&self, other: &Page) -> bool {
+        // Safety: the references are only used for comparing pointers.
+        unsafe {
+            Rc::ptr_eq(self.data.get().as_ref().unwrap(), other.data.get().as_ref().unwrap())
+        }
     }
 
     #[inline(always)]
@@ -270,8 +370,8 @@ impl Page {
     /// The returned pointer is only valid while `data` is valid (e.g., the pointer must not be used
     /// after a call to [Drop::drop]).
     #[inline(always)]
-    pub unsafe fn read_ptr(&mut self) -> PageRef {
-        PageRef::new(NonNull::new(Rc::as_ptr(self.data.get_mut()) as *mut _).unwrap())
+    pub unsafe fn read_ptr(&self) -> PageRef {
+        PageRef::new(NonNull::from(self.data()))
     }
 }
 
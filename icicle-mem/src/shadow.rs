@@ -0,0 +1,298 @@
+//! Sparse storage for user-defined metadata associated with every byte of guest memory.
+//!
+//! Each byte is shadowed by `N` bits of metadata (where `N` is 1, 2, 4, or 8), with zero meaning
+//! "no metadata". Pages of shadow memory are only allocated once a byte in the page is assigned
+//! non-zero metadata, and are freed when an entire page is cleared.
+//!
+//! Shadow memory can either be used standalone, or registered with an [Mmu](crate::Mmu) (see
+//! [Mmu::add_shadow](crate::Mmu::add_shadow)), in which case the metadata is cleared when memory
+//! is mapped or unmapped, moved along with memory regions, and saved and restored with snapshots.
+
+use std::sync::{
+    Arc,
+    atomic::{AtomicU64, Ordering},
+};
+
+use ahash::AHashMap as HashMap;
+
+/// The number of guest bytes covered by each page of shadow memory.
+const PAGE_SIZE: u64 = 0x1000;
+
+/// Used for allocating a unique version for each distinct state of a shadow memory.
+static NEXT_VERSION: AtomicU64 = AtomicU64::new(0);
+
+/// Identifies a shadow memory registered with an [Mmu](crate::Mmu).
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ShadowId(pub(crate) usize);
+
+/// Sparse storage for `bits` bits of metadata for each byte of guest memory.
+///
+/// Pages are shared with snapshots (see [ShadowMemory::snapshot]) until they are modified.
+#[derive(Clone)]
+pub struct ShadowMemory {
+    bits: u8,
+    pages: HashMap<u64, Arc<Box<[u8]>>>,
+
+    /// Identifies the current state of the shadow, used to avoid restoring unmodified shadows.
+    version: u64,
+
+    /// Whether `version` is shared with a snapshot, and must be changed before any modification.
+    shared: bool,
+}
+
+impl ShadowMemory {
+    pub fn new(bits: u8) -> Self {
+        assert!(matches!(bits, 1 | 2 | 4 | 8), "unsupported shadow size: {bits} bits");
+        Self {
+            bits,
+            pages: HashMap::new(),
+            version: NEXT_VERSION.fetch_add(1, Ordering::Relaxed),
+            shared: false,
+        }
+    }
+
+    /// Called before the shadow is modified.
+    fn touch(&mut self) {
+        if self.shared {
+            self.version = NEXT_VERSION.fetch_add(1, Ordering::Relaxed);
+            self.shared = false;
+        }
+    }
+
+    /// Creates a copy of the current state, sharing pages with `self` until they are modified.
+    pub fn snapshot(&mut self) -> Self {
+        self.shared = true;
+        self.clone()
+    }
+
+    /// Restores the state saved by [ShadowMemory::snapshot], without copying anything if the
+    /// shadow has not been modified since it was saved or restored.
+    pub fn restore(&mut self, saved: &Self) {
+        if self.version != saved.version {
+            self.clone_from(saved);
+        }
+        self.shared = true;
+    }
+
+    /// The number of bits of metadata stored for each byte.
+    pub fn bits(&self) -> u8 {
+        self.bits
+    }
+
+    /// The mask of the bits of a metadata value that are stored.
+    pub fn mask(&self) -> u8 {
+        (u16::MAX >> (16 - self.bits)) as u8
+    }
+
+    /// The number of pages of shadow memory that are allocated.
+    pub fn page_count(&self) -> usize {
+        self.pages.len()
+    }
+
+    /// Returns the index of the byte in the page that stores the metadata for `addr` and the shift
+    /// within that byte.
+    fn position(&self, addr: u64) -> (usize, u32) {
+        let bit = (addr % PAGE_SIZE) * self.bits as u64;
+        ((bit / 8) as usize, (bit % 8) as u32)
+    }
+
+    fn page_len(&self) -> usize {
+        (PAGE_SIZE * self.bits as u64 / 8) as usize
+    }
+
+    /// Gets the metadata for `addr` from `page`, the page containing `addr`.
+    fn entry(&self, page: &[u8], addr: u64) -> u8 {
+        let (index, shift) = self.position(addr);
+        (page[index] >> shift) & self.mask()
+    }
+
+    /// Gets a mutable reference to the page with index `key`, allocating it if it does not exist.
+    fn page_mut(&mut self, key: u64) -> &mut [u8] {
+        let page_len = self.page_len();
+        let page = self.pages.entry(key).or_insert_with(|| Arc::new(vec![0; page_len].into()));
+        Arc::make_mut(page).as_mut()
+    }
+
+    pub fn get(&self, addr: u64) -> u8 {
+        let Some(page) = self.pages.get(&(addr / PAGE_SIZE))
+        else {
+            return 0;
+        };
+        self.entry(page, addr)
+    }
+
+    pub fn set(&mut self, addr: u64, value: u8) {
+        let mask = self.mask();
+        if value & mask == 0 && !self.pages.contains_key(&(addr / PAGE_SIZE)) {
+            return;
+        }
+        self.touch();
+        let (index, shift) = self.position(addr);
+        let page = self.page_mut(addr / PAGE_SIZE);
+        page[index] = (page[index] & !(mask << shift)) | ((value & mask) << shift);
+    }
+
+    /// Calls `f` with the start address and length of each part of `addr..addr+len` that is
+    /// contained within a single page.
+    fn for_each_page(addr: u64, len: u64, mut f: impl FnMut(u64, u64)) {
+        let mut addr = addr;
+        let mut remaining = len;
+        while remaining > 0 {
+            let count = (PAGE_SIZE - addr % PAGE_SIZE).min(remaining);
+            f(addr, count);
+            addr = addr.wrapping_add(count);
+            remaining -= count;
+        }
+    }
+
+    /// Sets the metadata of the `len` bytes starting at `addr` to `value`.
+    pub fn set_range(&mut self, addr: u64, len: u64, value: u8) {
+        let value = value & self.mask();
+        let bits = self.bits as u64;
+
+        // A byte of shadow memory where every entry is set to `value`.
+        let mut pattern = 0_u8;
+        for i in 0..8 / bits {
+            pattern |= value << (i * bits);
+        }
+
+        self.touch();
+        Self::for_each_page(addr, len, |start, count| {
+            let key = start / PAGE_SIZE;
+            if value == 0 {
+                if count == PAGE_SIZE {
+                    self.pages.remove(&key);
+                    return;
+                }
+                if !self.pages.contains_key(&key) {
+                    return;
+                }
+            }
+
+            // Set entries individually until the start of the next shadow byte, then fill whole
+            // shadow bytes at once.
+            let per_byte = 8 / bits;
+            let mut addr = start;
+            let end = start + count;
+            while addr < end && (addr % per_byte != 0 || end - addr < per_byte) {
+                self.set(addr, value);
+                addr += 1;
+            }
+            let full_bytes = (end - addr) / per_byte;
+            if full_bytes > 0 {
+                let page = self.page_mut(key);
+                let index = ((addr % PAGE_SIZE) * bits / 8) as usize;
+                page[index..index + full_bytes as usize].fill(pattern);
+                addr += full_bytes * per_byte;
+            }
+            while addr < end {
+                self.set(addr, value);
+                addr += 1;
+            }
+        });
+    }
+
+    /// Sets the metadata of the bytes starting at `addr` to each of the values in `values`.
+    pub fn set_bytes(&mut self, addr: u64, values: &[u8]) {
+        for (i, value) in values.iter().enumerate() {
+            self.set(addr.wrapping_add(i as u64), *value);
+        }
+    }
+
+    /// Reads the metadata of the `out.len()` bytes starting at `addr` into `out`.
+    pub fn get_range(&self, addr: u64, out: &mut [u8]) {
+        let mut offset = 0;
+        Self::for_each_page(addr, out.len() as u64, |start, count| {
+            let out = &mut out[offset..offset + count as usize];
+            offset += count as usize;
+            let Some(page) = self.pages.get(&(start / PAGE_SIZE))
+            else {
+                out.fill(0);
+                return;
+            };
+            for (i, value) in out.iter_mut().enumerate() {
+                *value = self.entry(page, start + i as u64);
+            }
+        });
+    }
+
+    /// Returns whether any of the `len` bytes starting at `addr` have non-zero metadata.
+    pub fn any(&self, addr: u64, len: u64) -> bool {
+        self.find(addr, len).is_some()
+    }
+
+    /// Returns the address of the first byte in `addr..addr+len` with non-zero metadata.
+    pub fn find(&self, addr: u64, len: u64) -> Option<u64> {
+        let mut found = None;
+        Self::for_each_page(addr, len, |start, count| {
+            if found.is_some() {
+                return;
+            }
+            let Some(page) = self.pages.get(&(start / PAGE_SIZE))
+            else {
+                return;
+            };
+            // Skip over shadow bytes that are entirely zero before checking individual entries.
+            let per_byte = 8 / self.bits as u64;
+            let mut addr = start;
+            let end = start + count;
+            while addr < end {
+                let (index, _) = self.position(addr);
+                if page[index] == 0 {
+                    addr = (addr / per_byte + 1) * per_byte;
+                    continue;
+                }
+                if self.entry(page, addr) != 0 {
+                    found = Some(addr);
+                    return;
+                }
+                addr += 1;
+            }
+        });
+        found
+    }
+
+    /// Removes the metadata of the `len` bytes starting at `addr`.
+    pub fn clear_range(&mut self, addr: u64, len: u64) {
+        self.set_range(addr, len, 0);
+    }
+
+    /// Moves the metadata of the `len` bytes starting at `src` to `dst`, clearing the metadata at
+    /// `src` that is not overwritten.
+    pub fn move_range(&mut self, src: u64, dst: u64, len: u64) {
+        if len == 0 || src == dst {
+            return;
+        }
+
+        // Only allocated pages can contain metadata, so avoid visiting every byte of large ranges.
+        let first = src / PAGE_SIZE;
+        let last = src.saturating_add(len - 1) / PAGE_SIZE;
+        let mut pages: Vec<u64> =
+            self.pages.keys().copied().filter(|page| (first..=last).contains(page)).collect();
+        pages.sort_unstable();
+
+        let mut entries = vec![];
+        for key in pages {
+            let page = &self.pages[&key];
+            let start = (key * PAGE_SIZE).max(src);
+            let end = ((key + 1) * PAGE_SIZE).min(src.saturating_add(len));
+            for addr in start..end {
+                let value = self.entry(page, addr);
+                if value != 0 {
+                    entries.push((addr - src, value));
+                }
+            }
+        }
+
+        self.clear_range(src, len);
+        self.clear_range(dst, len);
+        for (offset, value) in entries {
+            self.set(dst.wrapping_add(offset), value);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.touch();
+        self.pages.clear();
+    }
+}
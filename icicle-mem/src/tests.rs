@@ -379,6 +379,44 @@ fn snapshot_reset_and_restore() {
     assert_eq!(&out, b"after ");
 }
 
+#[test]
+fn restore_dirty_pages_only() {
+    let mut mmu = Mmu::new();
+    mmu.map_memory_len(0x1000, 0x8000, Mapping { perm: perm::NONE, value: 0 });
+    for addr in (0x1000..0x6000).step_by(0x1000) {
+        mmu.write_bytes(addr, b"before", perm::NONE).unwrap();
+    }
+
+    let snapshot = mmu.snapshot();
+    assert_eq!(mmu.dirty_page_count(), 0);
+
+    for _ in 0..3 {
+        // Modify an existing page, and allocate a new one.
+        mmu.write_bytes(0x2000, b"after ", perm::NONE).unwrap();
+        mmu.write_bytes(0x8000, b"after ", perm::NONE).unwrap();
+        assert_eq!(mmu.dirty_page_count(), 2);
+
+        mmu.restore_dirty_only(&snapshot);
+        assert_eq!(mmu.dirty_page_count(), 0);
+
+        let mut out = [0; 6];
+        for addr in (0x1000..0x6000).step_by(0x1000) {
+            mmu.read_bytes(addr, &mut out, perm::NONE).unwrap();
+            assert_eq!(&out, b"before", "failed to restore: {addr:#x}");
+        }
+        mmu.read_bytes(0x8000, &mut out, perm::NONE).unwrap();
+        assert_eq!(out, [0; 6]);
+    }
+    assert_eq!(mmu.dirty_restore_count, 3);
+    assert_eq!(mmu.restored_page_count, 6);
+    assert_eq!(mmu.full_restore_count, 0);
+
+    // Restoring a snapshot other than the most recent one requires a full restore.
+    let _newer = mmu.snapshot();
+    mmu.restore_dirty_only(&snapshot);
+    assert_eq!(mmu.full_restore_count, 1);
+}
+
 #[test]
 fn complex_interactions() {
     let mut mmu = Mmu::new();
@@ -484,3 +522,236 @@ fn boundary_init() {
     let second = mmu.read::<1>(0x1001, perm::NONE).unwrap()[0];
     assert_eq!(second, 0xaa);
 }
+
+#[test]
+fn aliased_physical_ram() {
+    let mut mmu = Mmu::new();
+    mmu.add_physical_ram(0x8000_0000, 0x2000, perm::READ | perm::WRITE).unwrap();
+    assert!(mmu.map_guest_physical(0x1000, 0x8000_0000, 0x2000));
+    assert!(mmu.map_guest_physical(0x10000, 0x8000_0000, 0x2000));
+
+    // Populate the TLB for the alias before writing.
+    assert_eq!(mmu.read::<1>(0x10004, perm::READ).unwrap(), [0x00]);
+    let snapshot = mmu.snapshot();
+    assert_eq!(mmu.read::<1>(0x10004, perm::READ).unwrap(), [0x00]);
+
+    mmu.write(0x1004, [0xaa], perm::WRITE).unwrap();
+    assert_eq!(mmu.read::<1>(0x10004, perm::READ).unwrap(), [0xaa]);
+
+    let mut buf = [0; 1];
+    mmu.read_guest_physical(0x8000_0004, &mut buf).unwrap();
+    assert_eq!(buf, [0xaa]);
+
+    mmu.write_guest_physical(0x8000_1000, &[0xbb]).unwrap();
+    assert_eq!(mmu.read::<1>(0x2000, perm::READ).unwrap(), [0xbb]);
+    assert_eq!(mmu.read::<1>(0x11000, perm::READ).unwrap(), [0xbb]);
+
+    mmu.restore(snapshot);
+    assert_eq!(mmu.read::<1>(0x1004, perm::READ).unwrap(), [0x00]);
+    assert_eq!(mmu.read::<1>(0x10004, perm::READ).unwrap(), [0x00]);
+}
+
+#[test]
+fn physical_ram_copy_on_write() {
+    let mut mmu = Mmu::new();
+    mmu.add_physical_ram(0x8000_0000, 0x1000, perm::READ | perm::WRITE).unwrap();
+    assert!(mmu.map_guest_physical(0x1000, 0x8000_0000, 0x1000));
+
+    // Writes to a copy-on-write page are visible in the guest physical address space.
+    let _ = mmu.snapshot_virtual_mapping();
+    mmu.write(0x1004, [0xaa], perm::WRITE).unwrap();
+    let mut buf = [0; 1];
+    mmu.read_guest_physical(0x8000_0004, &mut buf).unwrap();
+    assert_eq!(buf, [0xaa]);
+
+    // Writes to the guest physical address space do not modify a saved copy of the page.
+    let saved = mmu.snapshot_virtual_mapping();
+    mmu.write_guest_physical(0x8000_0004, &[0xbb]).unwrap();
+    assert_eq!(mmu.read::<1>(0x1004, perm::READ).unwrap(), [0xbb]);
+    mmu.restore_virtual_mapping(saved);
+    assert_eq!(mmu.read::<1>(0x1004, perm::READ).unwrap(), [0xaa]);
+}
+
+#[test]
+fn io_regions_reject_perm_changes_and_fill() {
+    struct Null;
+    impl crate::MmioHandler for Null {
+        fn load(&mut self, _addr: u64, _size: u8) -> crate::MemResult<u64> {
+            Ok(0)
+        }
+
+        fn store(&mut self, _addr: u64, _size: u8, _value: u64) -> crate::MemResult<()> {
+            Ok(())
+        }
+    }
+
+    let mut mmu = Mmu::new();
+    mmu.map_mmio(0x4000_0000, 0x1000, Null).unwrap();
+    assert_eq!(mmu.update_perm(0x4000_0000, 0x1000, perm::READ), Err(MemError::Unknown));
+    assert_eq!(mmu.fill_mem(0x4000_0000, 0x10, 0xff), Err(MemError::Unknown));
+}
+
+#[test]
+fn regions_merge_adjacent_mappings() {
+    let mut mmu = Mmu::new();
+    let rw = perm::READ | perm::WRITE;
+    mmu.map_memory_len(0x1000, 0x1000, Mapping { perm: rw, value: 0x00 });
+    mmu.map_memory_len(0x2000, 0x1000, Mapping { perm: rw, value: 0xaa });
+    mmu.map_memory_len(0x5000, 0x1000, Mapping { perm: perm::READ, value: 0x00 });
+
+    let regions = mmu.regions();
+    assert_eq!(regions.len(), 2, "{regions:#x?}");
+    assert_eq!((regions[0].start, regions[0].size()), (0x1000, 0x2000));
+    assert_eq!((regions[1].start, regions[1].perm), (0x5000, perm::READ));
+}
+
+#[test]
+fn mmio_handler_receives_values() {
+    #[derive(Default)]
+    struct Timer {
+        counter: u64,
+        stores: Vec<(u64, u8, u64)>,
+    }
+
+    impl crate::MmioHandler for Timer {
+        fn load(&mut self, _addr: u64, _size: u8) -> crate::MemResult<u64> {
+            self.counter += 1;
+            Ok(self.counter)
+        }
+
+        fn store(&mut self, addr: u64, size: u8, value: u64) -> crate::MemResult<()> {
+            self.stores.push((addr, size, value));
+            Ok(())
+        }
+    }
+
+    let mut mmu = Mmu::new();
+    let id = mmu.map_mmio(0x4000_0000, 0x100, Timer::default()).unwrap();
+    assert_eq!(mmu.read::<4>(0x4000_0004, perm::READ).unwrap(), [1, 0, 0, 0]);
+    mmu.write(0x4000_0008, [0x34, 0x12], perm::WRITE).unwrap();
+
+    mmu.big_endian = true;
+    let be_id = mmu.map_mmio(0x5000_0000, 0x100, Timer::default()).unwrap();
+    assert_eq!(mmu.read::<4>(0x5000_0000, perm::READ).unwrap(), [0, 0, 0, 1]);
+    mmu.write(0x5000_0010, [0x12, 0x34], perm::WRITE).unwrap();
+
+    let timer = mmu.get_mmio_handler_mut::<Timer>(id).unwrap();
+    assert_eq!(timer.stores, [(0x4000_0008, 2, 0x1234)]);
+    let timer = mmu.get_mmio_handler_mut::<Timer>(be_id).unwrap();
+    assert_eq!(timer.stores, [(0x5000_0010, 2, 0x1234)]);
+}
+
+#[test]
+fn shadow_memory() {
+    let mut mmu = Mmu::new();
+    mmu.map_memory_len(0x10000, 0x4000, Mapping { perm: perm::NONE, value: 0 });
+
+    let id = mmu.add_shadow(2);
+    let shadow = mmu.shadow_mut(id);
+    shadow.set_range(0x10ffe, 0x1003, 0b10);
+    shadow.set(0x12000, 0xff);
+    assert_eq!(shadow.get(0x10ffd), 0);
+    assert_eq!(shadow.get(0x11000), 0b10);
+    assert_eq!(shadow.get(0x12000), 0b11);
+    assert_eq!(shadow.find(0x10000, 0x4000), Some(0x10ffe));
+    assert!(!shadow.any(0x12001, 0x1000));
+
+    let mut out = [0; 4];
+    shadow.get_range(0x10ffc, &mut out);
+    assert_eq!(out, [0, 0, 0b10, 0b10]);
+
+    let snapshot = mmu.snapshot();
+
+    // Moving a region moves its metadata.
+    mmu.move_region_len(0x12000, 0x2000, 0x20000).unwrap();
+    assert_eq!(mmu.shadow(id).get(0x12000), 0);
+    assert_eq!(mmu.shadow(id).get(0x20000), 0b11);
+
+    // Unmapping a region clears its metadata.
+    mmu.unmap_memory_len(0x10000, 0x2000);
+    assert!(!mmu.shadow(id).any(0x10000, 0x2000));
+    assert_eq!(mmu.shadow(id).page_count(), 1);
+
+    mmu.restore(snapshot.clone());
+    assert_eq!(mmu.shadow(id).get(0x11000), 0b10);
+    assert_eq!(mmu.shadow(id).get(0x12000), 0b11);
+    assert_eq!(mmu.shadow(id).get(0x20000), 0);
+
+    // Pages are shared with the snapshot, so modifying them must not modify the snapshot.
+    mmu.shadow_mut(id).set(0x11000, 0b01);
+    mmu.restore_dirty_only(&snapshot);
+    assert_eq!(mmu.shadow(id).get(0x11000), 0b10);
+    mmu.restore_dirty_only(&snapshot);
+    assert_eq!(mmu.shadow(id).get(0x11000), 0b10);
+
+    // Check that searching skips over empty entries correctly for the smallest shadow size.
+    let bits = mmu.add_shadow(1);
+    mmu.shadow_mut(bits).set(0x10009, 1);
+    assert_eq!(mmu.shadow(bits).find(0x10001, 0x1000), Some(0x10009));
+    assert_eq!(mmu.shadow(bits).find(0x1000a, 0x1000), None);
+}
+
+#[test]
+fn access_hooks_rewrite_values() {
+    let mut mmu = Mmu::new();
+    mmu.map_memory_len(0x1000, 0x1000, Mapping { perm: perm::READ | perm::WRITE, value: 0 });
+
+    let log = std::rc::Rc::new(std::cell::RefCell::new(vec![]));
+    let hook_log = log.clone();
+    let hook = move |_: &mut Mmu, addr: u64, value: &mut [u8], is_write: bool| {
+        hook_log.borrow_mut().push((addr, value.len(), is_write));
+        value[0] = if is_write { value[0] + 1 } else { value[0] * 2 };
+    };
+    let id = mmu.add_access_hook(0x1000, 0x1010, Box::new(hook)).unwrap();
+
+    // Writes are modified before they are stored, and reads are modified after they are loaded.
+    mmu.write_u32(0x1004, 5, perm::WRITE).unwrap();
+    assert_eq!(mmu.read_u32(0x1004, perm::NONE).unwrap(), 6);
+    assert_eq!(mmu.read_u32(0x1004, perm::READ).unwrap(), 12);
+    assert_eq!(*log.borrow(), [(0x1004, 4, true), (0x1004, 4, false)]);
+
+    // Accesses outside of the range (or without permission checks) are not hooked.
+    mmu.write_u32(0x1020, 5, perm::WRITE).unwrap();
+    assert_eq!(mmu.read_u32(0x1020, perm::READ).unwrap(), 5);
+    assert_eq!(log.borrow().len(), 2);
+
+    // Removed hooks are no longer called, and their slot is reused by new hooks.
+    assert!(mmu.remove_access_hook(id));
+    assert_eq!(mmu.read_u32(0x1004, perm::READ).unwrap(), 6);
+    let hook = |_: &mut Mmu, _: u64, value: &mut [u8], _: bool| value.fill(0xff);
+    assert_eq!(mmu.add_access_hook(0x1000, 0x1010, Box::new(hook)), Some(id));
+    assert_eq!(mmu.read_u32(0x1004, perm::READ).unwrap(), 0xffff_ffff);
+}
+
+#[test]
+fn access_hooks_only_observe_valid_accesses() {
+    let mut mmu = Mmu::new();
+    mmu.map_memory_len(0x1000, 0x1000, Mapping { perm: perm::READ | perm::WRITE, value: 0 });
+    mmu.map_memory_len(0x2000, 0x1000, Mapping { perm: perm::READ, value: 0 });
+
+    let log = std::rc::Rc::new(std::cell::RefCell::new(vec![]));
+    let hook_log = log.clone();
+    let hook = move |_: &mut Mmu, addr: u64, value: &mut [u8], is_write: bool| {
+        hook_log.borrow_mut().push((addr, value.len(), is_write));
+    };
+    mmu.add_access_hook(0x1000, 0x4000, Box::new(hook)).unwrap();
+
+    // Writes that fault are not passed to the hook.
+    assert_eq!(mmu.write_u32(0x2000, 5, perm::WRITE), Err(MemError::WriteViolation));
+    assert_eq!(mmu.write_u32(0x3000, 5, perm::WRITE), Err(MemError::Unmapped));
+    assert!(log.borrow().is_empty());
+
+    // Unaligned accesses are split into single byte accesses.
+    mmu.write_u32(0x1001, 5, perm::WRITE).unwrap();
+    assert_eq!(
+        *log.borrow(),
+        [(0x1001, 1, true), (0x1002, 1, true), (0x1003, 1, true), (0x1004, 1, true)]
+    );
+    log.borrow_mut().clear();
+
+    // Values provided by read hooks are still passed to access hooks.
+    let read_hook = |_: &mut Mmu, _: u64, _: u8| Some(0xaa);
+    mmu.add_read_hook(0x1800, 0x1900, Box::new(read_hook)).unwrap();
+    assert_eq!(mmu.read_u32(0x1800, perm::READ).unwrap(), 0xaa);
+    assert_eq!(*log.borrow(), [(0x1800, 4, false)]);
+}
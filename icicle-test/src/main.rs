@@ -31,6 +31,7 @@ enum TestMode {
     TraceNoOpt(String, String),
     Bench,
     Fib,
+    LiftCoverage(String, String),
 }
 
 fn run() -> anyhow::Result<()> {
@@ -41,6 +42,13 @@ fn run() -> anyhow::Result<()> {
         Some("debug") => TestMode::Debug,
         Some("bench") => TestMode::Bench,
         Some("fib") => TestMode::Fib,
+        Some("lift-coverage") => {
+            let arch =
+                std::env::args().nth(2).ok_or_else(|| anyhow::format_err!("Expected target"))?;
+            let path =
+                std::env::args().nth(3).ok_or_else(|| anyhow::format_err!("Expected binary"))?;
+            TestMode::LiftCoverage(arch, path)
+        }
         Some("trace") => {
             let arch =
                 std::env::args().nth(2).ok_or_else(|| anyhow::format_err!("Expected test file"))?;
@@ -152,6 +160,14 @@ fn run() -> anyhow::Result<()> {
         TestMode::One(name, test) => {
             test_icicle_cpu(&name, TestConfig::default(&test))?;
         }
+        TestMode::LiftCoverage(name, path) => {
+            let triple: target_lexicon::Triple =
+                name.parse().map_err(|_| anyhow::format_err!("Unknown target: {name}"))?;
+            let data = std::fs::read(&path)
+                .with_context(|| anyhow::format_err!("Failed to read: {path}"))?;
+            let report = icicle_vm::lift_coverage::scan_elf(&triple, &data)?;
+            print!("{report}");
+        }
         TestMode::Trace(name, test) => {
             test_icicle_cpu(&name, TestConfig {
                 save_pcode: true,
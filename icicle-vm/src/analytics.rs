@@ -0,0 +1,235 @@
+//! Collection of execution statistics for finding hot paths and the p-code operations that
+//! dominate execution time.
+//!
+//! Every lifted block is instrumented with a hook that counts the number of times the block is
+//! executed. The operations in each block are counted when the block is lifted, so the number of
+//! times each operation was executed is estimated as `block hits * operations in block` (blocks
+//! that exit early due to an exception are counted as if they executed completely).
+//!
+//! [Analytics::report] aggregates the statistics into an [AnalyticsReport], resolving hot blocks
+//! to functions using the symbol table of the environment's [DebugInfo], which can be exported as
+//! JSON for use by other tools.
+//!
+//! [DebugInfo]: icicle_cpu::debug_info::DebugInfo
+
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, HashMap},
+    rc::Rc,
+};
+
+use icicle_cpu::{BlockGroup, BlockTable, Cpu, ValueSource};
+use pcode::{Op, VarNode};
+
+use crate::{Vm, injector::CodeInjector};
+
+struct BlockInfo {
+    start: u64,
+    hits: u64,
+
+    /// The number of times each operation appears in the block.
+    ops: Vec<(Op, u32)>,
+}
+
+#[derive(Default)]
+struct AnalyticsState {
+    /// Information about each instrumented block, indexed by the value written to the custom
+    /// register before calling the hook.
+    blocks: Vec<BlockInfo>,
+}
+
+/// A handle to the analytics collector attached to a VM. Cloning the handle produces a handle to
+/// the same state.
+#[derive(Clone)]
+pub struct Analytics {
+    state: Rc<RefCell<AnalyticsState>>,
+}
+
+/// A block or function that was executed.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize)]
+pub struct HotSpot {
+    pub addr: u64,
+
+    /// The symbol associated with `addr` (if known).
+    pub name: Option<String>,
+
+    /// The number of times a block was executed.
+    pub hits: u64,
+
+    /// The estimated number of p-code operations executed.
+    pub ops: u64,
+}
+
+#[derive(Clone, Debug, Default, serde::Serialize)]
+pub struct AnalyticsReport {
+    /// The instruction count of the VM when the report was generated.
+    pub icount: u64,
+
+    /// The total number of blocks executed.
+    pub blocks_executed: u64,
+
+    /// The estimated number of times each kind of p-code operation was executed, ordered from the
+    /// most to least common.
+    #[serde(serialize_with = "serialize_ops")]
+    pub ops: Vec<(String, u64)>,
+
+    /// The most frequently executed blocks, ordered by the number of operations executed.
+    pub hot_blocks: Vec<HotSpot>,
+
+    /// The functions that executed the most operations, ordered by the number of operations
+    /// executed. Only blocks that resolve to a symbol are included.
+    pub hot_functions: Vec<HotSpot>,
+}
+
+impl Analytics {
+    /// Aggregates the statistics collected so far, including up to `top_n` blocks and functions.
+    pub fn report(&self, vm: &mut Vm, top_n: usize) -> AnalyticsReport {
+        let state = self.state.borrow();
+
+        let mut ops: HashMap<String, u64> = HashMap::new();
+        let mut blocks: BTreeMap<u64, (u64, u64)> = BTreeMap::new();
+        let mut blocks_executed = 0;
+        for block in state.blocks.iter().filter(|block| block.hits != 0) {
+            blocks_executed += block.hits;
+            let mut block_ops = 0;
+            for &(op, count) in &block.ops {
+                let executed = block.hits * count as u64;
+                *ops.entry(op_name(op)).or_default() += executed;
+                block_ops += executed;
+            }
+            let entry = blocks.entry(block.start).or_default();
+            entry.0 += block.hits;
+            entry.1 += block_ops;
+        }
+
+        let symbols = vm.env.debug_info().map(|info| info.symbols.clone());
+        let mut functions: BTreeMap<u64, HotSpot> = BTreeMap::new();
+        let mut hot_blocks = vec![];
+        for (&addr, &(hits, ops)) in &blocks {
+            let symbol = symbols.as_ref().and_then(|symbols| symbols.resolve_addr(addr));
+            let name = symbol.map(|(name, base, _)| match addr - base {
+                0 => name.to_owned(),
+                offset => format!("{name}+{offset:#x}"),
+            });
+            hot_blocks.push(HotSpot { addr, name, hits, ops });
+
+            if let Some((name, base, _)) = symbol {
+                let function = functions.entry(base).or_insert_with(|| HotSpot {
+                    addr: base,
+                    name: Some(name.to_owned()),
+                    hits: 0,
+                    ops: 0,
+                });
+                function.hits += hits;
+                function.ops += ops;
+            }
+        }
+
+        let mut ops: Vec<_> = ops.into_iter().collect();
+        ops.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+
+        let top = |mut spots: Vec<HotSpot>| {
+            spots.sort_by(|a, b| (b.ops, b.hits, a.addr).cmp(&(a.ops, a.hits, b.addr)));
+            spots.truncate(top_n);
+            spots
+        };
+
+        AnalyticsReport {
+            icount: vm.cpu.icount(),
+            blocks_executed,
+            ops,
+            hot_blocks: top(hot_blocks),
+            hot_functions: top(functions.into_values().collect()),
+        }
+    }
+
+    /// Resets the statistics of all blocks.
+    pub fn clear(&self) {
+        self.state.borrow_mut().blocks.iter_mut().for_each(|block| block.hits = 0);
+    }
+}
+
+impl AnalyticsReport {
+    /// Serializes the report as a JSON object.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+
+    /// Saves the report to `path` as JSON.
+    pub fn save(&self, path: &std::path::Path) -> std::io::Result<()> {
+        std::fs::write(path, self.to_json())
+    }
+}
+
+/// Serializes the operation counts as a JSON object, keeping the order of the operations.
+fn serialize_ops<S: serde::Serializer>(ops: &[(String, u64)], s: S) -> Result<S::Ok, S::Error> {
+    s.collect_map(ops.iter().map(|(name, count)| (name, count)))
+}
+
+/// Gets the name of an operation, ignoring any operands (e.g. the address space of a load).
+fn op_name(op: Op) -> String {
+    let name = format!("{op:?}");
+    match name.find('(') {
+        Some(end) => name[..end].to_owned(),
+        None => name,
+    }
+}
+
+/// Attaches an analytics collector to the VM. Only code lifted after the collector is attached is
+/// counted, so this should be called before the VM starts executing.
+pub fn attach(vm: &mut Vm) -> Analytics {
+    let block_var = vm
+        .cpu
+        .arch
+        .sleigh
+        .add_custom_reg("analytics.block", 4)
+        .expect("analytics has already been attached");
+    let state = Rc::new(RefCell::new(AnalyticsState::default()));
+
+    let hook_state = state.clone();
+    let hook = vm.cpu.add_hook(move |cpu: &mut Cpu, _addr: u64| {
+        let index = cpu.read_var::<u32>(block_var) as usize;
+        hook_state.borrow_mut().blocks[index].hits += 1;
+    });
+    vm.add_injector(AnalyticsInjector { hook, block_var, state: state.clone() });
+
+    Analytics { state }
+}
+
+struct AnalyticsInjector {
+    hook: pcode::HookId,
+    block_var: VarNode,
+    state: Rc<RefCell<AnalyticsState>>,
+}
+
+impl CodeInjector for AnalyticsInjector {
+    fn inject(&mut self, _cpu: &mut Cpu, group: &BlockGroup, code: &mut BlockTable) {
+        let mut state = self.state.borrow_mut();
+        for id in group.range() {
+            let block = &mut code.blocks[id];
+
+            let mut ops: Vec<(Op, u32)> = vec![];
+            for inst in &block.pcode.instructions {
+                match ops.iter_mut().find(|(op, _)| *op == inst.op) {
+                    Some((_, count)) => *count += 1,
+                    None => ops.push((inst.op, 1)),
+                }
+            }
+
+            let index = state.blocks.len() as u32;
+            state.blocks.push(BlockInfo { start: block.start, hits: 0, ops });
+
+            // The hook is placed after the first instruction marker, since the interpreter assumes
+            // that a block entered at an offset that is not an instruction marker is resuming
+            // partway through an instruction (which would consume an extra unit of fuel).
+            let instructions = &mut block.pcode.instructions;
+            let i = instructions
+                .iter()
+                .position(|x| x.op == Op::InstructionMarker)
+                .map_or(0, |i| i + 1);
+            instructions.insert(i, (self.block_var, Op::Copy, index).into());
+            instructions.insert(i + 1, Op::Hook(self.hook).into());
+            code.modified.insert(id);
+        }
+    }
+}
@@ -0,0 +1,166 @@
+//! A store for facts derived about the guest code (e.g. loop headers or function boundaries).
+//!
+//! Block IDs are only valid until the code is relifted (e.g. after a snapshot restore or a full
+//! flush of the code cache), so annotations are stored against the guest address of the block or
+//! instruction instead. This means annotations remain attached to the code after it is relifted,
+//! and are only discarded if the VM invalidates the code at that address (e.g. due to
+//! self-modifying code).
+//!
+//! [Annotations] is a shared handle, so a clone of it can be moved into hooks and injectors.
+
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, HashMap},
+    rc::Rc,
+};
+
+use icicle_cpu::{BlockTable, lifter::BlockId};
+
+/// The code an annotation is attached to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, serde::Serialize)]
+pub enum AnnotationTarget {
+    /// The block that starts at the given address.
+    Block(u64),
+
+    /// The instruction at the given address.
+    Instruction(u64),
+}
+
+impl AnnotationTarget {
+    pub fn addr(&self) -> u64 {
+        match self {
+            Self::Block(addr) | Self::Instruction(addr) => *addr,
+        }
+    }
+
+    /// Gets the target for the lifted block `id` in `code`.
+    pub fn for_block(code: &BlockTable, id: BlockId) -> Option<Self> {
+        code.blocks.get(id).map(|block| Self::Block(block.start))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum AnnotationValue {
+    Flag,
+    Int(u64),
+    Text(String),
+}
+
+impl From<u64> for AnnotationValue {
+    fn from(value: u64) -> Self {
+        Self::Int(value)
+    }
+}
+
+impl From<&str> for AnnotationValue {
+    fn from(value: &str) -> Self {
+        Self::Text(value.to_owned())
+    }
+}
+
+impl From<String> for AnnotationValue {
+    fn from(value: String) -> Self {
+        Self::Text(value)
+    }
+}
+
+/// A single annotation, used when exporting the store.
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct Annotation {
+    pub target: AnnotationTarget,
+    pub key: String,
+    pub value: AnnotationValue,
+}
+
+/// A shared handle to the annotation store. Cloning the store produces a handle to the same store.
+#[derive(Clone, Default)]
+pub struct Annotations {
+    inner: Rc<RefCell<BTreeMap<AnnotationTarget, HashMap<String, AnnotationValue>>>>,
+}
+
+impl Annotations {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the annotation `key` on `target` to `value`, returning the previous value if any.
+    pub fn set(
+        &self,
+        target: AnnotationTarget,
+        key: &str,
+        value: impl Into<AnnotationValue>,
+    ) -> Option<AnnotationValue> {
+        self.inner.borrow_mut().entry(target).or_default().insert(key.to_owned(), value.into())
+    }
+
+    pub fn get(&self, target: AnnotationTarget, key: &str) -> Option<AnnotationValue> {
+        self.inner.borrow().get(&target)?.get(key).cloned()
+    }
+
+    /// Returns whether `target` has an annotation named `key`.
+    pub fn contains(&self, target: AnnotationTarget, key: &str) -> bool {
+        self.inner.borrow().get(&target).is_some_and(|x| x.contains_key(key))
+    }
+
+    pub fn remove(&self, target: AnnotationTarget, key: &str) -> Option<AnnotationValue> {
+        let mut inner = self.inner.borrow_mut();
+        let entries = inner.get_mut(&target)?;
+        let value = entries.remove(key);
+        if entries.is_empty() {
+            inner.remove(&target);
+        }
+        value
+    }
+
+    /// Gets all the annotations attached to `target`.
+    pub fn get_all(&self, target: AnnotationTarget) -> Vec<(String, AnnotationValue)> {
+        let inner = self.inner.borrow();
+        let mut entries: Vec<_> = inner
+            .get(&target)
+            .into_iter()
+            .flatten()
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        entries
+    }
+
+    /// Finds all targets that have an annotation named `key` (e.g. all loop headers).
+    pub fn find(&self, key: &str) -> Vec<(AnnotationTarget, AnnotationValue)> {
+        self.inner
+            .borrow()
+            .iter()
+            .filter_map(|(target, entries)| Some((*target, entries.get(key)?.clone())))
+            .collect()
+    }
+
+    /// Returns a copy of every annotation in the store, for exporting.
+    pub fn export(&self) -> Vec<Annotation> {
+        let inner = self.inner.borrow();
+        let mut out = vec![];
+        for (target, entries) in inner.iter() {
+            let mut entries: Vec<_> = entries.iter().collect();
+            entries.sort_by(|a, b| a.0.cmp(b.0));
+            out.extend(entries.into_iter().map(|(key, value)| Annotation {
+                target: *target,
+                key: key.clone(),
+                value: value.clone(),
+            }));
+        }
+        out
+    }
+
+    /// Removes all annotations attached to `target`.
+    pub fn remove_target(&self, target: AnnotationTarget) {
+        self.inner.borrow_mut().remove(&target);
+    }
+
+    /// Removes all annotations attached to code in the range `start..=end`.
+    pub fn invalidate_range(&self, start: u64, end: u64) {
+        self.inner.borrow_mut().retain(|target, _| !(start..=end).contains(&target.addr()));
+    }
+
+    pub fn clear(&self) {
+        self.inner.borrow_mut().clear();
+    }
+}
@@ -0,0 +1,279 @@
+//! Support for guests instrumented with AddressSanitizer (ASAN).
+//!
+//! During initialization the ASAN runtime reserves a huge region of shadow memory at a fixed,
+//! target specific, offset. This is much larger than the maximum allocation size allowed by the
+//! Linux environment, so without special handling the runtime fails to start. Once attached, the
+//! shadow region is exempt from this limit (see [icicle_linux::Kernel::shadow_regions]).
+//!
+//! When ASAN detects an error, the runtime prints a report to stderr then aborts the process.
+//! Instead of reporting the abort (which always happens at the same location in the runtime), the
+//! VM exits with [ExceptionCode::SanitizerError] and the details of the error are available as a
+//! structured [AsanReport].
+
+use std::{cell::RefCell, collections::HashMap, ops::Range, rc::Rc, time::Duration};
+
+use icicle_cpu::{Cpu, Exception, ExceptionCode};
+use target_lexicon::Architecture;
+
+use crate::{
+    Vm,
+    guest_log::{GuestLog, LogOrigin},
+};
+
+/// The mangled name of `__sanitizer::Die`, called by the runtime after printing a report.
+const DIE_SYMBOL: &str = "_ZN11__sanitizer3DieEv";
+
+/// Prefix of the summary line printed at the end of every report.
+const SUMMARY_PREFIX: &[u8] = b"SUMMARY: AddressSanitizer: ";
+
+/// Describes where the ASAN runtime places shadow memory for a target.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ShadowLayout {
+    /// The offset added to scaled addresses to get the shadow address.
+    pub offset: u64,
+
+    /// The number of bits each address is shifted by (each shadow byte covers `1 << scale` bytes).
+    pub scale: u8,
+
+    /// The end of the user address space.
+    pub user_end: u64,
+}
+
+impl ShadowLayout {
+    /// Gets the default layout used by the ASAN runtime on Linux for `arch`.
+    pub fn for_arch(arch: Architecture) -> Option<Self> {
+        let (offset, user_end) = match arch {
+            Architecture::X86_64 => (0x7fff_8000, 1 << 47),
+            Architecture::X86_32(_) | Architecture::Arm(_) => (1 << 29, 1 << 32),
+            Architecture::Aarch64(_) => (1 << 36, 1 << 48),
+            Architecture::Riscv64(_) => (0xd_5555_0000, 1 << 39),
+            Architecture::Mips32(_) => (0x0aaa_0000, 1 << 32),
+            Architecture::Mips64(_) => (1 << 37, 1 << 40),
+            Architecture::Powerpc64 | Architecture::Powerpc64le => (1 << 44, 1 << 47),
+            _ => return None,
+        };
+        Some(Self { offset, scale: 3, user_end })
+    }
+
+    /// Gets the address of the shadow byte for `addr`.
+    pub fn shadow_addr(&self, addr: u64) -> u64 {
+        (addr >> self.scale) + self.offset
+    }
+
+    /// Gets the address range reserved by the runtime, including the low shadow, the shadow gap,
+    /// and the high shadow.
+    pub fn region(&self) -> Range<u64> {
+        self.offset..self.shadow_addr(self.user_end - 1) + 1
+    }
+}
+
+/// A memory access that ASAN reported as invalid.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AsanAccess {
+    pub addr: u64,
+    pub size: u64,
+    pub is_write: bool,
+
+    /// The address the instrumented code called the report function from.
+    pub pc: u64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct AsanReport {
+    /// The type of bug reported by ASAN (e.g. `heap-buffer-overflow`).
+    pub bug_type: Option<String>,
+
+    /// The access that triggered the report, if the error was detected by instrumented code
+    /// (errors detected by the runtime, e.g. a double free, have no associated access).
+    pub access: Option<AsanAccess>,
+
+    /// The summary line printed by the runtime.
+    pub summary: Option<String>,
+
+    /// Additional details about the error (e.g. where the accessed memory was allocated).
+    pub details: Option<String>,
+}
+
+impl std::fmt::Display for AsanReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.bug_type.as_deref().unwrap_or("unknown-error"))?;
+        if let Some(access) = self.access {
+            let kind = if access.is_write { "WRITE" } else { "READ" };
+            write!(
+                f,
+                ": {kind} of size {} at {:#x} (pc={:#x})",
+                access.size, access.addr, access.pc
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct AsanState {
+    /// The most recent access passed to a report function.
+    access: Option<AsanAccess>,
+
+    /// The time of the last guest log entry before the current report started.
+    log_start: Option<Duration>,
+
+    /// The last completed report.
+    report: Option<AsanReport>,
+}
+
+/// A handle to the ASAN support attached to a VM. Cloning the handle produces a handle to the same
+/// state.
+#[derive(Clone)]
+pub struct Asan {
+    layout: ShadowLayout,
+    state: Rc<RefCell<AsanState>>,
+}
+
+impl Asan {
+    pub fn layout(&self) -> ShadowLayout {
+        self.layout
+    }
+
+    /// Gets the report for the most recent error detected by ASAN.
+    pub fn last_report(&self) -> Option<AsanReport> {
+        self.state.borrow().report.clone()
+    }
+
+    /// Removes and returns the report for the most recent error detected by ASAN.
+    pub fn take_report(&self) -> Option<AsanReport> {
+        self.state.borrow_mut().report.take()
+    }
+}
+
+/// Returns whether the binary loaded in the VM was built with ASAN.
+pub fn is_instrumented(vm: &mut Vm) -> bool {
+    vm.env.lookup_symbol("__asan_init").is_some()
+}
+
+/// Configures the VM for running a binary built with ASAN. Returns `None` if the loaded binary is
+/// not instrumented or if the ASAN layout of the target is unknown.
+///
+/// This must be called after the binary is loaded, but before the ASAN runtime is initialized.
+pub fn attach(vm: &mut Vm) -> Option<Asan> {
+    if !is_instrumented(vm) {
+        return None;
+    }
+
+    let Some(layout) = ShadowLayout::for_arch(vm.cpu.arch.triple.architecture)
+    else {
+        tracing::warn!("ASAN instrumented binary detected, but the shadow layout is unknown");
+        return None;
+    };
+    tracing::info!("ASAN instrumented binary detected, shadow region: {:#x?}", layout.region());
+
+    if let Some(kernel) = vm.env_mut::<icicle_linux::Kernel>() {
+        kernel.shadow_regions.push(layout.region());
+    }
+
+    let mut hooks = HashMap::new();
+    for kind in ["load", "store"] {
+        for size in ["1", "2", "4", "8", "16", "_n"] {
+            for suffix in ["", "_noabort"] {
+                let name = format!("__asan_report_{kind}{size}{suffix}");
+                if let Some(addr) = vm.env.lookup_symbol(&name) {
+                    hooks.insert(addr, Hook::Access { is_write: kind == "store", size });
+                }
+            }
+        }
+    }
+    if let Some(addr) = vm.env.lookup_symbol("__asan_report_error") {
+        hooks.insert(addr, Hook::ReportError);
+    }
+
+    // Prefer stopping after the runtime has printed the report, so the summary can be captured.
+    let die = vm.env.lookup_symbol(DIE_SYMBOL);
+    if let Some(addr) = vm.env.lookup_symbol("__asan_on_error") {
+        hooks.insert(addr, Hook::OnError { finish: die.is_none() });
+    }
+    if let Some(addr) = die {
+        hooks.insert(addr, Hook::Die);
+    }
+
+    let asan = Asan { layout, state: Rc::new(RefCell::new(AsanState::default())) };
+    let state = asan.state.clone();
+    // The report summary is recovered from the output of the ASAN runtime.
+    vm.guest_log.set_enabled(true);
+    let log = vm.guest_log.clone();
+    let addrs: Vec<u64> = hooks.keys().copied().collect();
+    vm.hook_many_addresses(&addrs, move |cpu, addr| {
+        if let Some(hook) = hooks.get(&addr) {
+            hook.run(cpu, &mut state.borrow_mut(), &log);
+        }
+    });
+    vm.asan = Some(asan.clone());
+
+    Some(asan)
+}
+
+enum Hook {
+    /// `__asan_report_{load,store}{size}`: called by instrumented code with the address (and for
+    /// `_n` variants, the size) of an invalid access.
+    Access { is_write: bool, size: &'static str },
+
+    /// `__asan_report_error(pc, bp, sp, addr, is_write, size)`
+    ReportError,
+
+    /// `__asan_on_error`: called by the runtime at the start of every report.
+    OnError { finish: bool },
+
+    /// `__sanitizer::Die`: called by the runtime after printing a report.
+    Die,
+}
+
+impl Hook {
+    fn run(&self, cpu: &mut Cpu, state: &mut AsanState, log: &GuestLog) {
+        match self {
+            Self::Access { is_write, size } => {
+                let addr = cpu.read_arg(0);
+                let size = match *size {
+                    "_n" => cpu.read_arg(1),
+                    size => size.parse().unwrap(),
+                };
+                let pc = cpu.read_return_addr().unwrap_or(0);
+                state.access = Some(AsanAccess { addr, size, is_write: *is_write, pc });
+            }
+            Self::ReportError => {
+                state.access = Some(AsanAccess {
+                    pc: cpu.read_arg(0),
+                    addr: cpu.read_arg(3),
+                    is_write: cpu.read_arg(4) != 0,
+                    size: cpu.read_arg(5),
+                });
+            }
+            Self::OnError { finish } => {
+                state.log_start = log.entries().last().map(|x| x.time);
+                if *finish {
+                    finish_report(cpu, state, log);
+                }
+            }
+            Self::Die => finish_report(cpu, state, log),
+        }
+    }
+}
+
+fn finish_report(cpu: &mut Cpu, state: &mut AsanState, log: &GuestLog) {
+    let log_start = state.log_start.take();
+    let summary = log
+        .entries()
+        .into_iter()
+        .rev()
+        .take_while(|x| log_start.is_none_or(|start| x.time > start))
+        .find(|x| x.origin == LogOrigin::Stderr && x.text.starts_with(SUMMARY_PREFIX))
+        .map(|x| String::from_utf8_lossy(&x.text).into_owned());
+    let bug_type = summary.as_ref().and_then(|x| {
+        let rest = &x[SUMMARY_PREFIX.len()..];
+        rest.split_whitespace().next().map(str::to_owned)
+    });
+
+    let report = AsanReport { bug_type, access: state.access.take(), summary, details: None };
+    tracing::error!("AddressSanitizer: {report}");
+
+    let addr = report.access.map_or(0, |x| x.addr);
+    state.report = Some(report);
+    cpu.exception = Exception::new(ExceptionCode::SanitizerError, addr);
+}
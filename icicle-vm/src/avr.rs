@@ -0,0 +1,222 @@
+//! An environment for 8-bit AVR microcontrollers.
+//!
+//! AVR is a Harvard architecture: code is fetched from flash using a separate address space to the
+//! one used for data (registers, I/O and SRAM). The SLEIGH specification models these as separate
+//! RAM spaces, with the default (code) space addressed in 16-bit words. The emulator stores both
+//! spaces in the same MMU:
+//!
+//! - Flash is mapped at address 0. The PC and code addresses are in words, and are converted to
+//!   byte addresses when instructions are fetched or flash is read using `lpm`.
+//! - The data space is mapped at [pcode::DATA_SPACE_BASE] (see [data_addr]).
+//! - EEPROM is mapped after the data space at [EEPROM_OFFSET], outside of the range addressable by
+//!   the guest. The EEPROM controller is not modelled, so EEPROM can only be accessed by the
+//!   emulator (e.g. to inspect values written by the loader).
+//!
+//! Firmware is loaded from Intel HEX files (containing only flash), or ELF files using the
+//! conventions of avr-gcc, where virtual addresses starting at [ELF_DATA_BASE] are in the data
+//! space and addresses starting at [ELF_EEPROM_BASE] are in EEPROM. Segments are loaded at their
+//! physical address, so initialized data is loaded into flash to be copied by the startup code.
+
+use std::any::Any;
+
+use icicle_cpu::{
+    Cpu, Environment, VmExit,
+    debug_info::{DebugInfo, SourceLocation},
+    mem::{Mapping, perm},
+};
+use object::{
+    Endianness,
+    elf,
+    read::elf::{ElfFile32, FileHeader, ProgramHeader},
+};
+
+/// The base of the data space in the virtual address space of ELF files generated by avr-gcc.
+pub const ELF_DATA_BASE: u64 = 0x80_0000;
+
+/// The base of EEPROM in the virtual address space of ELF files generated by avr-gcc.
+pub const ELF_EEPROM_BASE: u64 = 0x81_0000;
+
+/// The offset of EEPROM from the start of the data space.
+pub const EEPROM_OFFSET: u64 = ELF_EEPROM_BASE - ELF_DATA_BASE;
+
+/// The number of bytes in each address of the code space.
+const CODE_WORD_SIZE: u64 = 2;
+
+/// Returns the address in the MMU that `addr` in the data space is mapped at.
+pub fn data_addr(addr: u64) -> u64 {
+    pcode::DATA_SPACE_BASE + addr
+}
+
+/// Returns the address in the MMU that `offset` in EEPROM is mapped at.
+pub fn eeprom_addr(offset: u64) -> u64 {
+    data_addr(EEPROM_OFFSET + offset)
+}
+
+/// The memory layout of the microcontroller, the default configuration matches the ATmega328P.
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// The size of flash in bytes.
+    pub flash_size: u64,
+
+    /// The size of the data space in bytes (including registers, I/O and SRAM).
+    pub data_size: u64,
+
+    /// The size of EEPROM in bytes.
+    pub eeprom_size: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self { flash_size: 0x8000, data_size: 0x900, eeprom_size: 0x400 }
+    }
+}
+
+pub struct Avr {
+    config: Config,
+    debug_info: DebugInfo,
+
+    /// The address (in words) that execution starts at.
+    entry: u64,
+}
+
+impl Avr {
+    pub fn new(config: Config) -> Self {
+        Self { config, debug_info: DebugInfo::default(), entry: 0 }
+    }
+
+    fn configure_mem(&self, cpu: &mut Cpu) -> Result<(), String> {
+        // Unprogrammed flash and EEPROM are read as 0xff.
+        let regions = [
+            (0, self.config.flash_size, perm::READ | perm::EXEC, 0xff),
+            (data_addr(0), self.config.data_size, perm::READ | perm::WRITE, 0x00),
+            (eeprom_addr(0), self.config.eeprom_size, perm::READ | perm::WRITE, 0xff),
+        ];
+        for (start, len, perm, value) in regions {
+            let mapping = Mapping { perm: perm | perm::MAP | perm::INIT, value };
+            if !cpu.mem.map_memory_len(start, len, mapping) {
+                return Err(format!("failed to map memory at {start:#x}"));
+            }
+        }
+        Ok(())
+    }
+
+    fn load_ihex(&mut self, cpu: &mut Cpu, data: &[u8]) -> Result<(), String> {
+        let input = std::str::from_utf8(data).map_err(|e| format!("invalid ihex file: {e}"))?;
+
+        let mut base_addr = 0x0;
+        for record in ihex::Reader::new(input) {
+            match record.map_err(|e| format!("invalid ihex file: {e}"))? {
+                ihex::Record::Data { offset, value } => {
+                    write_flash(cpu, base_addr + offset as u64, &value)?
+                }
+                ihex::Record::ExtendedSegmentAddress(segment) => base_addr = (segment as u64) << 4,
+                ihex::Record::ExtendedLinearAddress(upper) => base_addr = (upper as u64) << 16,
+                ihex::Record::EndOfFile => break,
+                _ => {}
+            }
+        }
+        self.entry = 0;
+        Ok(())
+    }
+
+    fn load_elf(&mut self, cpu: &mut Cpu, data: &[u8]) -> Result<(), String> {
+        let file = ElfFile32::<Endianness>::parse(data)
+            .map_err(|e| format!("failed to parse ELF file: {e}"))?;
+        let endian = file.endian();
+
+        for segment in file.elf_program_headers() {
+            if segment.p_type(endian) != elf::PT_LOAD {
+                continue;
+            }
+            let addr = segment.p_paddr(endian) as u64;
+            let bytes = segment
+                .data(endian, data)
+                .map_err(|_| format!("invalid segment data at {addr:#x}"))?;
+            match addr {
+                ELF_EEPROM_BASE.. => write_data(cpu, eeprom_addr(addr - ELF_EEPROM_BASE), bytes)?,
+                ELF_DATA_BASE.. => write_data(cpu, data_addr(addr - ELF_DATA_BASE), bytes)?,
+                _ => write_flash(cpu, addr, bytes)?,
+            }
+        }
+
+        self.debug_info = DebugInfo::default();
+        if let Err(e) = self.debug_info.add_file(data, 0) {
+            tracing::warn!("failed to load debug info: {e}");
+        }
+        self.entry = file.elf_header().e_entry(endian) as u64 / CODE_WORD_SIZE;
+        self.debug_info.entry_ptr = self.entry;
+        Ok(())
+    }
+}
+
+fn write_flash(cpu: &mut Cpu, addr: u64, data: &[u8]) -> Result<(), String> {
+    cpu.mem
+        .write_bytes(addr, data, perm::NONE)
+        .map_err(|e| format!("failed to write to flash at {addr:#x}: {e}"))
+}
+
+fn write_data(cpu: &mut Cpu, addr: u64, data: &[u8]) -> Result<(), String> {
+    cpu.mem
+        .write_bytes(addr, data, perm::NONE)
+        .map_err(|e| format!("failed to write to data memory at {addr:#x}: {e}"))
+}
+
+impl Environment for Avr {
+    fn load(&mut self, cpu: &mut Cpu, path: &[u8]) -> Result<(), String> {
+        self.configure_mem(cpu)?;
+
+        let path = std::str::from_utf8(path)
+            .map_err(|e| format!("@fixme: only utf-8 paths are supported: {e}"))?;
+        let data = std::fs::read(path).map_err(|e| format!("Failed to read {path}: {e}"))?;
+        if path.ends_with(".hex") || path.ends_with(".ihex") {
+            self.load_ihex(cpu, &data)?;
+        }
+        else {
+            self.load_elf(cpu, &data)?;
+        }
+
+        (cpu.arch.on_boot)(cpu, self.entry);
+        Ok(())
+    }
+
+    fn handle_exception(&mut self, _: &mut Cpu) -> Option<VmExit> {
+        None
+    }
+
+    fn debug_info(&self) -> Option<&DebugInfo> {
+        Some(&self.debug_info)
+    }
+
+    fn symbolize_addr(&mut self, _: &mut Cpu, addr: u64) -> Option<SourceLocation> {
+        // Symbols use byte addresses.
+        self.debug_info.symbolize_addr(addr * CODE_WORD_SIZE)
+    }
+
+    fn lookup_symbol(&mut self, symbol: &str) -> Option<u64> {
+        let addr = self.debug_info.symbols.resolve_sym(symbol)?;
+        Some(match addr {
+            ELF_EEPROM_BASE.. => eeprom_addr(addr - ELF_EEPROM_BASE),
+            ELF_DATA_BASE.. => data_addr(addr - ELF_DATA_BASE),
+            _ => addr / CODE_WORD_SIZE,
+        })
+    }
+
+    fn entry_point(&mut self) -> u64 {
+        self.entry
+    }
+
+    fn snapshot(&mut self) -> Box<dyn Any> {
+        Box::new(())
+    }
+
+    fn restore(&mut self, _: &Box<dyn Any>) {}
+
+    fn save_state(&mut self) -> Result<Vec<u8>, String> {
+        // All of the state of the environment is stored in memory and registers.
+        Ok(vec![])
+    }
+
+    fn load_state(&mut self, _: &[u8]) -> Result<(), String> {
+        Ok(())
+    }
+}
@@ -0,0 +1,156 @@
+//! Utilities for comparing the first instructions executed by two runs of a target.
+//!
+//! This is mainly useful for debugging changes to environments or loaders that break boot: record
+//! a trace with a known-good configuration, record a trace with the new configuration, then find
+//! the first point where the two runs diverge.
+
+use std::fmt::Write;
+
+use icicle_cpu::VmExit;
+
+use crate::Vm;
+
+/// The state of the CPU just before an instruction was executed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TraceEntry {
+    pub icount: u64,
+    pub pc: u64,
+    pub disasm: String,
+    pub regs: Vec<(String, u64)>,
+}
+
+impl TraceEntry {
+    fn capture(vm: &mut Vm, regs: &[pcode::VarNode]) -> Self {
+        let pc = vm.cpu.read_pc();
+        let disasm = vm.get_disasm(pc).unwrap_or("").to_owned();
+        let regs = regs
+            .iter()
+            .map(|&var| {
+                let name = vm.cpu.arch.sleigh.name_of_varnode(var).unwrap_or("?").to_owned();
+                (name, vm.cpu.read_reg(var))
+            })
+            .collect();
+        Self { icount: vm.cpu.icount(), pc, disasm, regs }
+    }
+
+    /// Formats the entry as a single line: `<icount> <pc> <reg>=<value>... ; <disasm>`
+    pub fn to_line(&self) -> String {
+        let mut out = format!("{} {:#x}", self.icount, self.pc);
+        for (name, value) in &self.regs {
+            write!(out, " {name}={value:#x}").unwrap();
+        }
+        write!(out, " ; {}", self.disasm).unwrap();
+        out
+    }
+
+    /// Parses an entry previously formatted with [TraceEntry::to_line].
+    pub fn parse_line(line: &str) -> Option<Self> {
+        let (state, disasm) = line.split_once(" ; ").unwrap_or((line, ""));
+        let mut parts = state.split_whitespace();
+        let icount = parts.next()?.parse().ok()?;
+        let pc = parse_hex(parts.next()?)?;
+        let regs = parts
+            .map(|part| {
+                let (name, value) = part.split_once('=')?;
+                Some((name.to_owned(), parse_hex(value)?))
+            })
+            .collect::<Option<_>>()?;
+        Some(Self { icount, pc, disasm: disasm.to_owned(), regs })
+    }
+}
+
+fn parse_hex(value: &str) -> Option<u64> {
+    u64::from_str_radix(value.trim_start_matches("0x"), 16).ok()
+}
+
+/// Records the state of the CPU before each of the first `count` instructions (or until the VM
+/// exits for a reason other than reaching the instruction limit).
+///
+/// Note: Execution is single stepped so this is significantly slower than regular execution.
+pub fn record(vm: &mut Vm, count: u64) -> (Vec<TraceEntry>, VmExit) {
+    let regs = crate::debug::get_debug_regs(&vm.cpu);
+
+    let mut trace = vec![];
+    let mut exit = VmExit::InstructionLimit;
+    for _ in 0..count {
+        let mut entry = TraceEntry::capture(vm, &regs);
+        exit = vm.step(1);
+        // The disassembly is only available after the instruction has been lifted.
+        if entry.disasm.is_empty() {
+            entry.disasm = vm.get_disasm(entry.pc).unwrap_or("").to_owned();
+        }
+        trace.push(entry);
+        if exit != VmExit::InstructionLimit {
+            break;
+        }
+    }
+    (trace, exit)
+}
+
+pub fn save(trace: &[TraceEntry], path: &std::path::Path) -> std::io::Result<()> {
+    let mut out = String::new();
+    for entry in trace {
+        out.push_str(&entry.to_line());
+        out.push('\n');
+    }
+    std::fs::write(path, out)
+}
+
+pub fn load(path: &std::path::Path) -> Result<Vec<TraceEntry>, String> {
+    let data = std::fs::read_to_string(path)
+        .map_err(|e| format!("failed to read {}: {e}", path.display()))?;
+    data.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            TraceEntry::parse_line(line).ok_or_else(|| format!("invalid entry at line {}", i + 1))
+        })
+        .collect()
+}
+
+/// Finds the index of the first entry where `a` and `b` differ (in either the program counter or
+/// the value of any register), or where one of the traces ends before the other.
+pub fn first_divergence(a: &[TraceEntry], b: &[TraceEntry]) -> Option<usize> {
+    let diverged = a.iter().zip(b).position(|(a, b)| a.pc != b.pc || a.regs != b.regs);
+    match diverged {
+        Some(index) => Some(index),
+        None if a.len() != b.len() => Some(a.len().min(b.len())),
+        None => None,
+    }
+}
+
+/// Generates a side-by-side diff of the two traces at the first divergence, including `context`
+/// instructions before the divergence. Returns `None` if the traces are identical.
+pub fn diff(a: &[TraceEntry], b: &[TraceEntry], context: usize) -> Option<String> {
+    let index = first_divergence(a, b)?;
+
+    let mut out = String::new();
+    writeln!(out, "traces diverge at instruction {index}:").unwrap();
+    for entry in &a[index.saturating_sub(context)..index] {
+        writeln!(out, "  {:#x}: {}", entry.pc, entry.disasm).unwrap();
+    }
+
+    let (a, b) = (a.get(index), b.get(index));
+    let describe = |entry: Option<&TraceEntry>| match entry {
+        Some(entry) => format!("{:#x}: {}", entry.pc, entry.disasm),
+        None => "<end of trace>".into(),
+    };
+    writeln!(out, "{:<48} | {}", describe(a), describe(b)).unwrap();
+
+    // The register state at `index` reflects the effect of the previous instruction, so show all
+    // registers, marking any that differ.
+    let (Some(a), Some(b)) = (a, b)
+    else {
+        return Some(out);
+    };
+    let regs_b: std::collections::HashMap<_, _> = b.regs.iter().cloned().collect();
+    for (name, value_a) in &a.regs {
+        let value_b = regs_b.get(name);
+        let marker = if value_b != Some(value_a) { "  <--" } else { "" };
+        let value_b = value_b.map_or("?".into(), |x| format!("{x:#x}"));
+        writeln!(out, "{:<48} | {name:>6} = {value_b}{marker}", format!("{name:>6} = {value_a:#x}"))
+            .unwrap();
+    }
+
+    Some(out)
+}
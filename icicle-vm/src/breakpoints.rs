@@ -0,0 +1,124 @@
+//! Breakpoint metadata (enabled state, hit counts, ignore counts and temporary breakpoints).
+//!
+//! The set of addresses the VM stops at is stored in [icicle_cpu::BlockTable::breakpoints], which
+//! is checked by the interpreter and the lifter. The metadata for each breakpoint is stored
+//! separately, and is only consulted when execution reaches one of these addresses.
+
+use crate::{Vm, VmExit};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Breakpoint {
+    /// Whether the VM stops when the breakpoint is reached. Disabled breakpoints keep their
+    /// metadata but are removed from the code.
+    pub enabled: bool,
+
+    /// The number of times the breakpoint has been reached while enabled (including ignored hits).
+    pub hit_count: u64,
+
+    /// The number of remaining hits to ignore before stopping.
+    pub ignore_count: u64,
+
+    /// Whether the breakpoint is removed the first time the VM stops at it.
+    pub temporary: bool,
+}
+
+impl Default for Breakpoint {
+    fn default() -> Self {
+        Self { enabled: true, hit_count: 0, ignore_count: 0, temporary: false }
+    }
+}
+
+impl Breakpoint {
+    /// Creates a breakpoint that is removed the first time it is hit.
+    pub fn temporary() -> Self {
+        Self { temporary: true, ..Self::default() }
+    }
+
+    /// Creates a breakpoint that ignores the first `count` hits.
+    pub fn with_ignore_count(count: u64) -> Self {
+        Self { ignore_count: count, ..Self::default() }
+    }
+}
+
+impl Vm {
+    /// Adds (or replaces) the breakpoint at `addr` with `breakpoint`.
+    pub fn set_breakpoint(&mut self, addr: u64, breakpoint: Breakpoint) {
+        match breakpoint.enabled {
+            true => self.arm_breakpoint(addr),
+            false => self.disarm_breakpoint(addr),
+        };
+        self.breakpoint_info.insert(addr, breakpoint);
+    }
+
+    /// Adds a breakpoint at `addr` that is removed the first time it is hit.
+    pub fn add_temporary_breakpoint(&mut self, addr: u64) {
+        self.set_breakpoint(addr, Breakpoint::temporary());
+    }
+
+    /// Gets the metadata for the breakpoint at `addr`.
+    pub fn breakpoint(&self, addr: u64) -> Option<&Breakpoint> {
+        self.breakpoint_info.get(&addr)
+    }
+
+    /// Returns an iterator over all breakpoints (including disabled breakpoints) ordered by
+    /// address.
+    pub fn breakpoints(&self) -> impl Iterator<Item = (u64, &Breakpoint)> {
+        self.breakpoint_info.iter().map(|(addr, bp)| (*addr, bp))
+    }
+
+    /// Enables or disables the breakpoint at `addr`. Returns false if there is no breakpoint at
+    /// `addr`.
+    pub fn enable_breakpoint(&mut self, addr: u64, enabled: bool) -> bool {
+        let Some(bp) = self.breakpoint_info.get_mut(&addr)
+        else {
+            return false;
+        };
+        bp.enabled = enabled;
+        match enabled {
+            true => self.arm_breakpoint(addr),
+            false => self.disarm_breakpoint(addr),
+        };
+        true
+    }
+
+    /// Configures the breakpoint at `addr` to ignore the next `count` hits. Returns false if there
+    /// is no breakpoint at `addr`.
+    pub fn set_breakpoint_ignore_count(&mut self, addr: u64, count: u64) -> bool {
+        match self.breakpoint_info.get_mut(&addr) {
+            Some(bp) => {
+                bp.ignore_count = count;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Called when execution reaches an armed breakpoint at `addr`, updating the metadata for the
+    /// breakpoint and returning whether the VM should stop.
+    pub(crate) fn on_breakpoint_hit(&mut self, addr: u64) -> VmExit {
+        let Some(bp) = self.breakpoint_info.get_mut(&addr)
+        else {
+            // Breakpoints added internally (e.g. by `run_until`) have no metadata.
+            return VmExit::Breakpoint;
+        };
+
+        bp.hit_count += 1;
+        if bp.ignore_count > 0 {
+            bp.ignore_count -= 1;
+
+            // Execution would stop at the same address again, so step over the instruction with
+            // the breakpoint disarmed.
+            self.disarm_breakpoint(addr);
+            let exit = self.step(1);
+            self.arm_breakpoint(addr);
+            return match exit {
+                VmExit::InstructionLimit => VmExit::Running,
+                exit => exit,
+            };
+        }
+        if bp.temporary {
+            self.remove_breakpoint(addr);
+        }
+        VmExit::Breakpoint
+    }
+}
@@ -1,6 +1,6 @@
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
-use icicle_cpu::{cpu::CallCov, exec::helpers, lifter, Arch, Config, Cpu};
+use icicle_cpu::{cpu::CallCov, exec::helpers, lifter, Arch, Config, Cpu, SmcPolicy};
 use sleigh_compile::ldef::SleighLanguage;
 
 use crate::Vm;
@@ -13,6 +13,7 @@ pub enum BuildError {
     FailedToParsePspec(String),
     FailedToInitEnvironment(String),
     UnknownContextField(String),
+    UnknownRegister(String),
     UnsupportedOperatingSystem,
     InvalidConfig,
 }
@@ -30,6 +31,7 @@ impl std::fmt::Display for BuildError {
             Self::UnknownContextField(name) => {
                 write!(f, "Unknown context field found in pspec: {name}")
             }
+            Self::UnknownRegister(name) => write!(f, "Unknown register: {name}"),
             Self::UnsupportedOperatingSystem => write!(f, "Unsupported operating system"),
             Self::InvalidConfig => write!(f, "Invalid config"),
         }
@@ -43,8 +45,97 @@ pub fn build(config: &Config) -> Result<Vm, BuildError> {
 }
 
 pub fn build_with_path(config: &Config, processors: &Path) -> Result<Vm, BuildError> {
-    let mut lang = sleigh_init_with_path(&config.triple, processors)?;
+    let lang = sleigh_init_cached(config, processors)?;
+    build_with_lang(config, lang, &ProcessorSpec::default())
+}
+
+/// A processor definition supplied at runtime, used to emulate cores that are not part of the
+/// SLEIGH specifications distributed with Ghidra.
+#[derive(Clone, Debug, Default)]
+pub struct ProcessorSpec {
+    /// The path to the `.slaspec` file.
+    pub slaspec: PathBuf,
+
+    /// The path to the `.pspec` file, used for the program counter and the initial context.
+    pub pspec: PathBuf,
+
+    /// The path to the `.cspec` file (if any), used for the stack pointer and calling convention.
+    pub cspec: Option<PathBuf>,
+
+    /// Additional symbols defined when compiling the specification.
+    pub defines: Vec<String>,
+
+    /// The register containing the stack pointer, overriding the one from the `.cspec` file.
+    pub sp: Option<String>,
+
+    /// The registers used to pass integer arguments, overriding the ones from the `.cspec` file.
+    pub int_args: Option<Vec<String>>,
+
+    /// Registers that are only used to hold values within a single instruction.
+    pub temporaries: Vec<String>,
 
+    /// Values that registers are initialized to when the CPU is reset.
+    pub boot_values: Vec<(String, u128)>,
+}
+
+impl ProcessorSpec {
+    pub fn new(slaspec: impl Into<PathBuf>, pspec: impl Into<PathBuf>) -> Self {
+        Self { slaspec: slaspec.into(), pspec: pspec.into(), ..Self::default() }
+    }
+
+    /// Compiles the specification.
+    pub fn compile(&self) -> Result<SleighLanguage, BuildError> {
+        for path in [&self.slaspec, &self.pspec].into_iter().chain(&self.cspec) {
+            if !path.exists() {
+                return Err(BuildError::SpecNotFound(path.clone()));
+            }
+        }
+        let mut builder = sleigh_compile::ldef::SleighSpecBuilder::new(&self.slaspec, &self.pspec);
+        if let Some(cspec) = &self.cspec {
+            builder = builder.set_cspec_path(cspec);
+        }
+        for define in &self.defines {
+            builder = builder.define(define);
+        }
+        builder.build().map_err(|e| BuildError::SpecCompileError(e.to_string()))
+    }
+}
+
+/// Builds a VM for a processor defined at runtime. Architecture specific behaviour (e.g. helpers
+/// and the environment) is still selected using `config.triple`, which should be left as unknown
+/// unless the specification is a variant of a supported architecture.
+pub fn build_with_processor(config: &Config, spec: &ProcessorSpec) -> Result<Vm, BuildError> {
+    let mut lang = if config.cache_sleigh {
+        // Files included by the `.slaspec` file are assumed to be in the same directory.
+        let slaspec_dir = spec.slaspec.parent().filter(|dir| !dir.as_os_str().is_empty());
+        let mut paths = vec![slaspec_dir.unwrap_or(Path::new(".")), spec.pspec.as_path()];
+        paths.extend(spec.cspec.as_deref());
+        let id = format!("{}:{:?}", spec.slaspec.display(), spec.defines);
+        crate::sleigh_cache::load_or_compile(&paths, &id, || spec.compile())?
+    }
+    else {
+        spec.compile()?
+    };
+
+    let get_reg = |name: &str| {
+        lang.sleigh.get_varnode(name).ok_or_else(|| BuildError::UnknownRegister(name.into()))
+    };
+    if let Some(sp) = &spec.sp {
+        lang.sp = get_reg(sp)?;
+    }
+    if let Some(int_args) = &spec.int_args {
+        let int_args = int_args.iter().map(|name| get_reg(name)).collect::<Result<_, _>>()?;
+        lang.default_calling_cov.int_args = int_args;
+    }
+
+    build_with_lang(config, lang, spec)
+}
+
+fn build_with_lang(
+    config: &Config,
+    mut lang: SleighLanguage,
+    spec: &ProcessorSpec,
+) -> Result<Vm, BuildError> {
     let reg_next_pc = lang
         .sleigh
         .add_custom_reg("NEXT_PC", 8)
@@ -52,6 +143,26 @@ pub fn build_with_path(config: &Config, processors: &Path) -> Result<Vm, BuildEr
 
     let reg_isa_mode = lang.sleigh.get_varnode("ISAModeSwitch");
 
+    // Bare-metal x86-64 targets may run kernel code that uses `swapgs`, so they need storage for
+    // the kernel GS base. For user-mode targets `swapgs` is a privileged instruction and faults.
+    if config.triple.architecture == target_lexicon::Architecture::X86_64
+        && config.triple.operating_system == target_lexicon::OperatingSystem::None_
+    {
+        lang.sleigh.add_custom_reg(helpers::x86::KERNEL_GS_BASE, 8);
+    }
+
+    // Bare-metal Thumb targets are assumed to be Cortex-M cores, which need storage for the
+    // exception mask registers (see `crate::cortex_m`).
+    let is_thumb = match config.triple.architecture {
+        target_lexicon::Architecture::Arm(arm) => arm.is_thumb(),
+        _ => false,
+    };
+    if is_thumb && config.triple.operating_system == target_lexicon::OperatingSystem::None_ {
+        lang.sleigh.add_custom_reg(helpers::arm::PRIMASK, 4);
+        lang.sleigh.add_custom_reg(helpers::arm::BASEPRI, 4);
+        lang.sleigh.add_custom_reg(helpers::arm::IPSR, 4);
+    }
+
     // Set initial context values for architectures that support mode switching.
     //
     // @todo: Support other architectures.
@@ -70,11 +181,19 @@ pub fn build_with_path(config: &Config, processors: &Path) -> Result<Vm, BuildEr
     for &(name, value) in get_boot_values(config.triple.architecture) {
         reg_init.push((get_reg(name)?, value));
     }
+    for (name, value) in &spec.boot_values {
+        let var = lang.sleigh.get_varnode(name);
+        reg_init.push((var.ok_or_else(|| BuildError::UnknownRegister(name.clone()))?, *value));
+    }
 
-    let temporaries = get_temporary_varnodes(config.triple.architecture)
+    let mut temporaries = get_temporary_varnodes(config.triple.architecture)
         .iter()
         .map(|name| Ok(get_reg(name)?.id))
         .collect::<Result<Vec<_>, _>>()?;
+    for name in &spec.temporaries {
+        let var = lang.sleigh.get_varnode(name);
+        temporaries.push(var.ok_or_else(|| BuildError::UnknownRegister(name.clone()))?.id);
+    }
 
     let arch = Arch {
         triple: config.triple.clone(),
@@ -101,10 +220,18 @@ fn build_vm(config: &Config, arch: Arch) -> Result<Vm, BuildError> {
     let mut cpu = Cpu::new_boxed(arch);
     cpu.enable_shadow_stack = config.enable_shadow_stack;
     cpu.mem.track_uninitialized = config.track_uninitialized;
+    cpu.entropy.reseed(config.entropy_seed);
+    if config.smc_policy == SmcPolicy::InvalidateOnHint {
+        // The guest is responsible for informing us about modified code.
+        cpu.mem.detect_self_modifying_code = false;
+    }
 
     let settings = lifter::Settings {
         optimize: config.optimize_instructions,
         optimize_block: config.optimize_block,
+        recover_jump_tables: config.recover_jump_tables,
+        max_pcode_ops_per_block: config.max_pcode_ops_per_block,
+        max_lift_time: config.max_lift_time,
         ..Default::default()
     };
     let instruction_lifter = lifter::InstructionLifter::new();
@@ -115,7 +242,23 @@ fn build_vm(config: &Config, arch: Arch) -> Result<Vm, BuildError> {
 
     let mut vm = Vm::new(cpu, lifter);
     vm.enable_jit = config.enable_jit;
+    vm.smc_policy = config.smc_policy;
+    vm.guest_log.set_enabled(config.enable_guest_log);
     register_helpers_for(&mut vm, config.triple.architecture);
+    if matches!(
+        config.triple.architecture,
+        target_lexicon::Architecture::X86_32(_) | target_lexicon::Architecture::X86_64
+    ) {
+        vm.cpu.x86 = Some(Box::new(helpers::x86::X86Model::new(config.x86.clone())));
+    }
+
+    if config.cycle_timing {
+        match lifter::timing::CostTable::for_arch(config.triple.architecture) {
+            Some(table) => lifter::timing::cycle_patch(&mut vm.cpu, &mut vm.lifter, table),
+            None => tracing::warn!("cycle timing is not supported for {}", config.triple),
+        }
+    }
+    vm.builtin_lifter_hooks = crate::translation_cache::LifterHooks::of(&vm);
 
     Ok(vm)
 }
@@ -137,6 +280,22 @@ fn patch_instruction_pointer_access(vm: &mut Vm, use_next_pc: bool) {
     vm.lifter.patchers.push(icicle_cpu::lifter::read_pc_patcher(pc, tmp_pc, use_next_pc));
 }
 
+/// Handles reads from system registers that return random numbers (e.g. `RNDR` on AArch64).
+struct RandomRegHandler {
+    reg: pcode::VarNode,
+}
+
+impl icicle_cpu::RegHandler for RandomRegHandler {
+    fn read(&mut self, cpu: &mut Cpu) {
+        use icicle_cpu::ValueSource;
+
+        let value = cpu.entropy.next_u64();
+        cpu.write_trunc(self.reg, value);
+    }
+
+    fn write(&mut self, _: &mut Cpu) {}
+}
+
 fn register_helpers_for(vm: &mut Vm, arch: target_lexicon::Architecture) {
     use target_lexicon::Architecture;
 
@@ -149,7 +308,14 @@ fn register_helpers_for(vm: &mut Vm, arch: target_lexicon::Architecture) {
             // Fixes `pop {..., pc}`
             patch_instruction_pointer_access(vm, false);
         }
-        Architecture::Aarch64(_) => register_helpers(vm, helpers::aarch64::HELPERS),
+        Architecture::Aarch64(_) => {
+            register_helpers(vm, helpers::aarch64::HELPERS);
+            for name in ["rndr", "rndrrs"] {
+                if let Some(reg) = vm.cpu.arch.sleigh.get_varnode(name) {
+                    vm.cpu.add_reg_handler(reg.id, Box::new(RandomRegHandler { reg }));
+                }
+            }
+        }
         Architecture::X86_32(_) | Architecture::X86_64 => {
             register_helpers(vm, helpers::x86::HELPERS);
             patch_instruction_pointer_access(vm, false);
@@ -166,6 +332,9 @@ fn register_helpers_for(vm: &mut Vm, arch: target_lexicon::Architecture) {
             // Fixes RETI, RETA, CALLA
             patch_instruction_pointer_access(vm, true);
         }
+        Architecture::Riscv32(_) | Architecture::Riscv64(_) => {
+            lifter::riscv::csr_patch(&mut vm.cpu, &mut vm.lifter);
+        }
         _ => {}
     }
 }
@@ -214,6 +383,34 @@ pub fn sleigh_init(target: &target_lexicon::Triple) -> Result<SleighLanguage, Bu
 }
 
 pub fn sleigh_init_with_path(target: &target_lexicon::Triple, processors: &Path) -> Result<SleighLanguage, BuildError> {
+    compile_language(sleigh_language_builder(target, processors)?)
+}
+
+fn compile_language(
+    builder: sleigh_compile::SleighLanguageBuilder,
+) -> Result<SleighLanguage, BuildError> {
+    builder.build().map_err(|e| BuildError::SpecCompileError(e.to_string()))
+}
+
+/// Compiles the specification for `target`, using the cache in the user's cache directory (see
+/// [crate::sleigh_cache]) if enabled.
+fn sleigh_init_cached(config: &Config, processors: &Path) -> Result<SleighLanguage, BuildError> {
+    let builder = sleigh_language_builder(&config.triple, processors)?;
+    if !config.cache_sleigh {
+        return compile_language(builder);
+    }
+
+    // The ldefs file and all the files it references are in the same directory.
+    let languages_dir = builder.ldef_path.parent().unwrap_or(processors).to_path_buf();
+    let id = format!("{}:{:?}:{:?}", builder.lang_id, builder.cspec_id, builder.defines);
+    let paths = [languages_dir.as_path()];
+    crate::sleigh_cache::load_or_compile(&paths, &id, || compile_language(builder))
+}
+
+fn sleigh_language_builder(
+    target: &target_lexicon::Triple,
+    processors: &Path,
+) -> Result<sleigh_compile::SleighLanguageBuilder, BuildError> {
     use target_lexicon::{
         Aarch64Architecture, Architecture, ArmArchitecture, Mips32Architecture,
         Riscv32Architecture, Riscv64Architecture,
@@ -286,6 +483,7 @@ pub fn sleigh_init_with_path(target: &target_lexicon::Triple, processors: &Path)
             };
             (ldef, id)
         }
+        Architecture::Avr => ("Atmel/data/languages/avr8.ldefs", "avr8:LE:16:default"),
         Architecture::M68k => ("68000/data/languages/68000.ldefs", "68000:BE:32:Coldfire"),
         Architecture::Mips32(variant) => {
             let ldef = "MIPS/data/languages/mips.ldefs";
@@ -311,6 +509,7 @@ pub fn sleigh_init_with_path(target: &target_lexicon::Triple, processors: &Path)
                 Riscv32Architecture::Riscv32gc => "RISCV:LE:32:RV32GC",
                 Riscv32Architecture::Riscv32i => "RISCV:LE:32:RV32I",
                 Riscv32Architecture::Riscv32imc => "RISCV:LE:32:RV32IMC",
+                Riscv32Architecture::Riscv32imac => "RISCV:LE:32:default",
                 _ => return Err(BuildError::UnsupportedArchitecture),
             };
             (ldef, id)
@@ -320,6 +519,7 @@ pub fn sleigh_init_with_path(target: &target_lexicon::Triple, processors: &Path)
             let id = match variant {
                 Riscv64Architecture::Riscv64 => "RISCV:LE:64:default",
                 Riscv64Architecture::Riscv64gc => "RISCV:LE:64:RV64GC",
+                Riscv64Architecture::Riscv64imac => "RISCV:LE:64:default",
                 _ => return Err(BuildError::UnsupportedArchitecture),
             };
             (ldef, id)
@@ -343,7 +543,7 @@ pub fn sleigh_init_with_path(target: &target_lexicon::Triple, processors: &Path)
     }
 
     // @todo: use compiler specific variants for cspec when available.
-    builder.build().map_err(|e| BuildError::SpecCompileError(e.to_string()))
+    Ok(builder)
 }
 
 fn get_default_processors_path() -> std::path::PathBuf {
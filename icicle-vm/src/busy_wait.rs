@@ -0,0 +1,362 @@
+//! Fast-forwarding of busy-wait loops.
+//!
+//! Firmware often waits for time to pass (or for hardware to become ready) by spinning in a loop,
+//! e.g. software delay loops used during clock calibration, or `while (!(reg & READY))` waits for
+//! a PLL to lock. Emulating these loops one iteration at a time can use most of the instruction
+//! budget while booting. These loops are skipped by advancing the virtual clock instead:
+//!
+//! - Delay loops configured with [BusyWait::add_delay_loop]: when execution reaches the header of
+//!   the loop, the number of remaining iterations is read from a counter register, the clock is
+//!   advanced by the time the iterations would have taken, the counter is cleared, and execution
+//!   continues at the exit of the loop. Other effects of the loop (e.g. condition flags) are not
+//!   modelled.
+//! - Spin loops, found automatically when enabled with [BusyWait::set_spin_detection]: a loop
+//!   consisting of a single block that does not write to memory, and where the registers it writes
+//!   are unchanged after [SPIN_THRESHOLD] iterations, will keep spinning until an interrupt or
+//!   peripheral changes the state it depends on. The clock is advanced to the next time the VM
+//!   exits to the environment (e.g. when the next timer expires) or reaches its instruction limit.
+//! - Spin loops that only read from a single MMIO register (e.g. a status register) are first
+//!   passed to the peripheral using [MmioHandler::resolve_poll], allowing peripheral models to
+//!   resolve the wait (e.g. by setting a ready bit) and advance the clock by the time the wait
+//!   should take, instead of emulating the loop until the next timer.
+//!
+//! Note: the header of a delay loop is only instrumented when the code is lifted, so delay loops
+//! should be configured before the VM starts executing.
+//!
+//! [MmioHandler::resolve_poll]: icicle_cpu::mem::MmioHandler::resolve_poll
+
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, HashMap},
+    rc::Rc,
+};
+
+use icicle_cpu::{
+    BlockGroup, BlockTable, Cpu, Exception, ExceptionCode,
+    lifter::{BlockExit, Target},
+};
+use pcode::{Op, VarNode};
+
+use crate::{Vm, injector::CodeInjector};
+
+/// The number of consecutive iterations that must leave the state unchanged before a loop is
+/// treated as spinning.
+pub const SPIN_THRESHOLD: u32 = 2;
+
+/// Fuel values larger than this indicate that there is no deadline to skip to.
+const NO_DEADLINE: u64 = 1 << 62;
+
+/// A loop that delays for a number of iterations stored in a register.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DelayLoop {
+    /// The register containing the number of iterations remaining at the loop header.
+    pub counter: VarNode,
+
+    /// The address that execution continues at after the loop completes.
+    pub exit: u64,
+
+    /// The number of instructions executed by each iteration of the loop.
+    pub instructions_per_iteration: u64,
+}
+
+/// Statistics about the loops that were fast-forwarded.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SkippedLoop {
+    /// The address of the first instruction of the loop.
+    pub header: u64,
+
+    /// The number of times the loop was skipped.
+    pub count: u64,
+
+    /// The total number of ticks of the virtual clock that were skipped.
+    pub skipped: u64,
+}
+
+struct SpinLoop {
+    header: u64,
+
+    /// The registers written by the loop.
+    outputs: Vec<VarNode>,
+
+    /// The values of `outputs` at the end of the previous iteration.
+    last: Vec<u8>,
+
+    /// The number of consecutive iterations where `outputs` were unchanged.
+    repeats: u32,
+
+    /// The size of the value loaded from memory, if the loop contains exactly one load.
+    poll_size: Option<u8>,
+}
+
+impl SpinLoop {
+    /// Updates the state of the loop, returning whether the loop is spinning.
+    fn update(&mut self, cpu: &Cpu) -> bool {
+        let mut changed = false;
+        let mut offset = 0;
+        for var in &self.outputs {
+            let value = cpu.regs.get(*var).unwrap_or(&[]);
+            let end = offset + value.len();
+            if self.last.len() < end {
+                self.last.resize(end, 0);
+                changed = true;
+            }
+            if self.last[offset..end] != *value {
+                self.last[offset..end].copy_from_slice(value);
+                changed = true;
+            }
+            offset = end;
+        }
+
+        self.repeats = match changed {
+            true => 0,
+            false => self.repeats + 1,
+        };
+        self.repeats >= SPIN_THRESHOLD
+    }
+}
+
+#[derive(Default)]
+struct BusyWaitState {
+    delays: HashMap<u64, DelayLoop>,
+
+    /// Loops that are checked for spinning, keyed by the address of the back edge.
+    spins: HashMap<u64, SpinLoop>,
+    detect_spins: bool,
+
+    skipped: BTreeMap<u64, SkippedLoop>,
+}
+
+impl BusyWaitState {
+    fn record(&mut self, header: u64, skipped: u64) {
+        tracing::debug!("fast-forwarding loop at {header:#x} by {skipped} instructions");
+        let entry = self
+            .skipped
+            .entry(header)
+            .or_insert_with(|| SkippedLoop { header, ..SkippedLoop::default() });
+        entry.count += 1;
+        entry.skipped += skipped;
+    }
+}
+
+/// A handle to the busy-wait skipper attached to a VM. Cloning the handle produces a handle to the
+/// same state.
+#[derive(Clone)]
+pub struct BusyWait {
+    state: Rc<RefCell<BusyWaitState>>,
+}
+
+impl BusyWait {
+    /// Skips the delay loop starting at `header` whenever it is reached.
+    pub fn add_delay_loop(&self, header: u64, delay: DelayLoop) {
+        self.state.borrow_mut().delays.insert(header, delay);
+    }
+
+    /// Stops skipping the delay loop at `header`.
+    pub fn remove_delay_loop(&self, header: u64) {
+        self.state.borrow_mut().delays.remove(&header);
+    }
+
+    /// Configures whether spin loops are detected and skipped.
+    pub fn set_spin_detection(&self, enabled: bool) {
+        let mut state = self.state.borrow_mut();
+        state.detect_spins = enabled;
+        state.spins.values_mut().for_each(|spin| spin.repeats = 0);
+    }
+
+    /// Gets the loops that have been skipped so far, ordered by header address.
+    pub fn skipped(&self) -> Vec<SkippedLoop> {
+        self.state.borrow().skipped.values().cloned().collect()
+    }
+
+    /// Clears the statistics of skipped loops.
+    pub fn reset_stats(&self) {
+        self.state.borrow_mut().skipped.clear();
+    }
+}
+
+/// Attaches a busy-wait skipper to the VM, with spin detection disabled. Only loops in code lifted
+/// after the skipper is attached are skipped.
+pub fn attach(vm: &mut Vm) -> BusyWait {
+    let state = Rc::new(RefCell::new(BusyWaitState::default()));
+    let poll_addr = vm
+        .cpu
+        .arch
+        .sleigh
+        .add_custom_reg("busy_wait.poll_addr", 8)
+        .expect("busy-wait skipper has already been attached");
+
+    let hook_state = state.clone();
+    let delay_hook = vm.cpu.add_hook(move |cpu: &mut Cpu, addr: u64| {
+        let mut state = hook_state.borrow_mut();
+        let Some(&delay) = state.delays.get(&addr)
+        else {
+            return;
+        };
+        let iterations = cpu.read_reg(delay.counter);
+        if iterations == 0 {
+            return;
+        }
+        let skipped = iterations.saturating_mul(delay.instructions_per_iteration);
+        cpu.icount = cpu.icount.saturating_add(skipped);
+        cpu.write_reg(delay.counter, 0);
+        cpu.exception = Exception::new(ExceptionCode::ExternalAddr, delay.exit);
+        state.record(addr, skipped);
+    });
+
+    let hook_state = state.clone();
+    let spin_hook = vm.cpu.add_hook(move |cpu: &mut Cpu, addr: u64| {
+        let mut state = hook_state.borrow_mut();
+        if !state.detect_spins {
+            return;
+        }
+        let Some(spin) = state.spins.get_mut(&addr)
+        else {
+            return;
+        };
+        if !spin.update(cpu) {
+            return;
+        }
+        let header = spin.header;
+
+        // If the loop is polling a peripheral, allow the peripheral to resolve the wait. Otherwise,
+        // skip to the next time the VM exits to check timers or limits. If there is no deadline,
+        // nothing can change the state of the loop so it is left to run.
+        let resolved = spin.poll_size.and_then(|size| {
+            let target = cpu.read_reg(poll_addr);
+            let ticks = cpu.mem.resolve_io_poll(target, size)?;
+            tracing::debug!("poll of {target:#x} resolved by peripheral after {ticks} ticks");
+            Some(ticks)
+        });
+        let skipped = match resolved {
+            Some(ticks) => ticks,
+            None if cpu.fuel.remaining == 0 || cpu.fuel.remaining >= NO_DEADLINE => return,
+            None => cpu.fuel.remaining,
+        };
+        spin.repeats = 0;
+        cpu.icount = cpu.icount.saturating_add(skipped);
+        cpu.exception = Exception::new(ExceptionCode::ExternalAddr, header);
+        state.record(header, skipped);
+    });
+
+    vm.add_injector(BusyWaitInjector { delay_hook, spin_hook, poll_addr, state: state.clone() });
+
+    BusyWait { state }
+}
+
+struct BusyWaitInjector {
+    delay_hook: pcode::HookId,
+    spin_hook: pcode::HookId,
+    poll_addr: VarNode,
+    state: Rc<RefCell<BusyWaitState>>,
+}
+
+impl CodeInjector for BusyWaitInjector {
+    fn inject(&mut self, _cpu: &mut Cpu, group: &BlockGroup, code: &mut BlockTable) {
+        let mut state = self.state.borrow_mut();
+        for id in group.range() {
+            let block = &mut code.blocks[id];
+
+            // Call the delay hook at the start of each delay loop header.
+            let headers: Vec<usize> = block
+                .pcode
+                .instructions
+                .iter()
+                .enumerate()
+                .filter(|(_, x)| {
+                    x.op == Op::InstructionMarker
+                        && state.delays.contains_key(&x.inputs.first().as_u64())
+                })
+                .map(|(i, _)| i)
+                .collect();
+            for &i in headers.iter().rev() {
+                block.pcode.instructions.insert(i + 1, Op::Hook(self.delay_hook).into());
+                code.modified.insert(id);
+            }
+
+            // Find blocks that loop back to their own start without side effects.
+            let header = block.start;
+            let is_header = |target: &Target| {
+                matches!(target, Target::External(pcode::Value::Const(dst, _)) if *dst == header)
+            };
+            let has_side_effects = block.pcode.instructions.iter().any(|x| {
+                matches!(
+                    x.op,
+                    Op::Store(_)
+                        | Op::TracerStore(_)
+                        | Op::PcodeOp(_)
+                        | Op::Hook(_)
+                        | Op::HookIf(_)
+                        | Op::Exception
+                )
+            });
+            if has_side_effects {
+                continue;
+            }
+            let Some(addr) = block
+                .pcode
+                .instructions
+                .iter()
+                .rev()
+                .find(|x| x.op == Op::InstructionMarker)
+                .map(|x| x.inputs.first().as_u64())
+            else {
+                continue;
+            };
+
+            let is_back_edge = match block.exit {
+                BlockExit::Jump { target } => is_header(&target),
+                BlockExit::Branch { target, fallthrough, .. } => {
+                    is_header(&target) || is_header(&fallthrough)
+                }
+                _ => false,
+            };
+            if !is_back_edge {
+                continue;
+            }
+
+            // Keep track of the address of the load in loops that poll a single location.
+            let mut loads = block
+                .pcode
+                .instructions
+                .iter()
+                .enumerate()
+                .filter(|(_, x)| x.op == Op::Load(pcode::RAM_SPACE));
+            let poll_size = match (loads.next(), loads.next()) {
+                (Some((i, load)), None) => {
+                    let (addr, size) = (load.inputs.first(), load.output.size);
+                    let copy = match addr.size() {
+                        8 => (self.poll_addr, Op::Copy, addr),
+                        _ => (self.poll_addr, Op::ZeroExtend, addr),
+                    };
+                    block.pcode.instructions.insert(i, copy.into());
+                    Some(size)
+                }
+                _ => None,
+            };
+
+            match block.exit {
+                BlockExit::Branch { cond, target, .. } if is_header(&target) => {
+                    block.pcode.push((Op::HookIf(self.spin_hook), cond));
+                }
+                BlockExit::Branch { cond, .. } => {
+                    let not_taken = block.pcode.alloc_tmp(1);
+                    block.pcode.push((not_taken, Op::BoolNot, cond));
+                    block.pcode.push((Op::HookIf(self.spin_hook), not_taken));
+                }
+                _ => block.pcode.push(Op::Hook(self.spin_hook)),
+            }
+
+            let mut outputs: Vec<VarNode> = vec![];
+            for inst in &block.pcode.instructions {
+                let var = inst.output;
+                if var != VarNode::NONE && !var.is_temp() && !outputs.contains(&var) {
+                    outputs.push(var);
+                }
+            }
+            tracing::debug!("possible spin loop: header={header:#x}, back edge={addr:#x}");
+            let spin = SpinLoop { header, outputs, last: vec![], repeats: 0, poll_size };
+            state.spins.insert(addr, spin);
+            code.modified.insert(id);
+        }
+    }
+}
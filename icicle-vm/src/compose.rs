@@ -0,0 +1,481 @@
+//! Support for composing an address space out of multiple images (e.g. a bootloader ELF, an
+//! application stored as an Intel HEX file, and a configuration blob at a fixed address).
+//!
+//! Each image claims the address ranges it is loaded into, loading an image that overlaps with an
+//! existing image is reported as an error. The owner of each address is tracked so that addresses
+//! can be symbolized using the debug info of the image they belong to.
+
+use std::{any::Any, collections::BTreeMap};
+
+use icicle_cpu::{
+    debug_info::{DebugInfo, SourceLocation},
+    elf::ElfLoader,
+    mem::{perm, Mapping},
+    utils::{align_down, align_up},
+    Cpu, Environment, VmExit,
+};
+use object::{Object, ObjectSegment};
+
+#[derive(Clone, Debug)]
+pub enum ImageKind {
+    /// An ELF file, loaded at the addresses specified in the program headers.
+    Elf,
+    /// An Intel HEX file.
+    IntelHex,
+    /// A raw binary blob loaded at a fixed address.
+    Raw { addr: u64 },
+}
+
+impl ImageKind {
+    /// Guess the kind of image based on the file extension and contents.
+    pub fn detect(path: &str, data: &[u8]) -> Option<Self> {
+        if data.starts_with(b"\x7fELF") {
+            return Some(Self::Elf);
+        }
+        if path.ends_with(".hex") || path.ends_with(".ihex") {
+            return Some(Self::IntelHex);
+        }
+        None
+    }
+}
+
+/// Metadata about an image loaded into the address space.
+pub struct Image {
+    /// A user specified name for the image.
+    pub name: String,
+
+    /// The path the image was loaded from (if it was loaded from a file).
+    pub path: Option<String>,
+
+    /// The address ranges (start, end) claimed by the image.
+    pub ranges: Vec<(u64, u64)>,
+
+    /// The entry point specified by the image (if any).
+    pub entry: Option<u64>,
+
+    /// Debug info associated with the image.
+    pub debug_info: DebugInfo,
+}
+
+impl Image {
+    fn new(name: &str, path: Option<&str>) -> Self {
+        Self {
+            name: name.to_owned(),
+            path: path.map(str::to_owned),
+            ranges: vec![],
+            entry: None,
+            debug_info: DebugInfo::default(),
+        }
+    }
+}
+
+/// An environment built from multiple images.
+pub struct Composition {
+    /// All images that have been loaded.
+    pub images: Vec<Image>,
+
+    /// A mapping from the start of an address range to the end of the range and the index of the
+    /// image that owns it.
+    owners: BTreeMap<u64, (u64, usize)>,
+
+    /// The index of the image used for the entrypoint and for the default debug info.
+    primary: Option<usize>,
+}
+
+impl ElfLoader for Composition {
+    const DYNAMIC_MEMORY: bool = true;
+}
+
+impl Default for Composition {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Composition {
+    pub fn new() -> Self {
+        Self { images: vec![], owners: BTreeMap::new(), primary: None }
+    }
+
+    /// Returns the image that contains `addr`.
+    pub fn image_at(&self, addr: u64) -> Option<&Image> {
+        let (_, (end, index)) = self.owners.range(..=addr).next_back()?;
+        (addr < *end).then(|| &self.images[*index])
+    }
+
+    /// Returns the image with the given name.
+    pub fn image_by_name(&self, name: &str) -> Option<&Image> {
+        self.images.iter().find(|image| image.name == name)
+    }
+
+    /// Configures the image that should be used to determine the entrypoint.
+    pub fn set_primary(&mut self, name: &str) -> Result<(), String> {
+        let index = self
+            .images
+            .iter()
+            .position(|image| image.name == name)
+            .ok_or_else(|| format!("unknown image: {name}"))?;
+        self.primary = Some(index);
+        Ok(())
+    }
+
+    /// Load the file at `path` into the address space, using `kind` to determine the format.
+    pub fn add_file(
+        &mut self,
+        cpu: &mut Cpu,
+        name: &str,
+        path: &str,
+        kind: Option<ImageKind>,
+    ) -> Result<usize, String> {
+        let data = std::fs::read(path).map_err(|e| format!("Failed to read {path}: {e}"))?;
+        let kind = match kind.or_else(|| ImageKind::detect(path, &data)) {
+            Some(kind) => kind,
+            None => return Err(format!("unable to determine image type of: {path}")),
+        };
+
+        match kind {
+            ImageKind::Elf => self.add_elf(cpu, name, path, &data),
+            ImageKind::IntelHex => self.add_ihex(cpu, name, Some(path), &data),
+            ImageKind::Raw { addr } => {
+                let perm = perm::READ | perm::WRITE | perm::EXEC;
+                self.add_blob(cpu, name, Some(path), addr, &data, perm)
+            }
+        }
+    }
+
+    /// Load an ELF file (with contents `data`) into the address space.
+    pub fn add_elf(
+        &mut self,
+        cpu: &mut Cpu,
+        name: &str,
+        path: &str,
+        data: &[u8],
+    ) -> Result<usize, String> {
+        let object =
+            object::read::File::parse(data).map_err(|e| format!("Error parsing elf: {e}"))?;
+        let ranges: Vec<_> = object
+            .segments()
+            .filter(|segment| segment.size() != 0)
+            .map(|segment| (segment.address(), segment.address() + segment.size()))
+            .collect();
+        let index = self.claim(name, Some(path), &ranges)?;
+
+        let metadata = match self.load_elf(cpu, path.as_bytes()) {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                self.release(index);
+                return Err(e);
+            }
+        };
+        if metadata.interpreter.is_some() {
+            self.release(index);
+            return Err(format!("{name}: dynamically linked images are not supported"));
+        }
+        if metadata.binary.offset != 0 {
+            self.release(index);
+            return Err(format!(
+                "{name}: image was relocated (offset={:#x}), conflicting with existing memory",
+                metadata.binary.offset
+            ));
+        }
+
+        let image = &mut self.images[index];
+        image.entry = Some(metadata.binary.entry_ptr);
+        image.debug_info = metadata.debug_info;
+        image.debug_info.entry_ptr = metadata.binary.entry_ptr;
+        if self.primary.is_none() {
+            self.primary = Some(index);
+        }
+
+        Ok(index)
+    }
+
+    /// Load an Intel HEX file (with contents `data`) into the address space.
+    pub fn add_ihex(
+        &mut self,
+        cpu: &mut Cpu,
+        name: &str,
+        path: Option<&str>,
+        data: &[u8],
+    ) -> Result<usize, String> {
+        let input = std::str::from_utf8(data).map_err(|e| format!("invalid ihex file: {e}"))?;
+
+        let mut chunks: BTreeMap<u64, Vec<u8>> = BTreeMap::new();
+        let mut entry = None;
+        let mut base_addr = 0x0;
+        for record in ihex::Reader::new(input) {
+            match record.map_err(|e| format!("invalid ihex file: {e}"))? {
+                ihex::Record::Data { offset, value } => {
+                    let addr = base_addr + offset as u64;
+                    // Merge with the previous chunk if the data is contiguous.
+                    match chunks.range_mut(..addr).next_back() {
+                        Some((start, chunk)) if start + chunk.len() as u64 == addr => {
+                            chunk.extend_from_slice(&value)
+                        }
+                        _ => {
+                            chunks.insert(addr, value);
+                        }
+                    }
+                }
+                ihex::Record::ExtendedSegmentAddress(segment) => base_addr = (segment as u64) << 4,
+                ihex::Record::ExtendedLinearAddress(upper) => base_addr = (upper as u64) << 16,
+                ihex::Record::StartLinearAddress(addr) => entry = Some(addr as u64),
+                ihex::Record::StartSegmentAddress { cs, ip } => {
+                    entry = Some(((cs as u64) << 4) + ip as u64)
+                }
+                ihex::Record::EndOfFile => break,
+            }
+        }
+
+        let ranges: Vec<_> =
+            chunks.iter().map(|(start, data)| (*start, start + data.len() as u64)).collect();
+        let index = self.claim(name, path, &ranges)?;
+
+        let perm = perm::READ | perm::EXEC;
+        for (addr, data) in &chunks {
+            if let Err(e) = write_region(cpu, *addr, data, perm) {
+                self.release(index);
+                return Err(format!("{name}: {e}"));
+            }
+        }
+        self.images[index].entry = entry;
+
+        Ok(index)
+    }
+
+    /// Load `data` at a fixed address into the address space.
+    pub fn add_blob(
+        &mut self,
+        cpu: &mut Cpu,
+        name: &str,
+        path: Option<&str>,
+        addr: u64,
+        data: &[u8],
+        perm: u8,
+    ) -> Result<usize, String> {
+        let end = addr
+            .checked_add(data.len() as u64)
+            .ok_or_else(|| format!("{name}: image overflows the address space"))?;
+        let index = self.claim(name, path, &[(addr, end)])?;
+        if let Err(e) = write_region(cpu, addr, data, perm) {
+            self.release(index);
+            return Err(format!("{name}: {e}"));
+        }
+        Ok(index)
+    }
+
+    /// Reserve `ranges` for a new image, returning an error if any of the ranges overlap with an
+    /// existing image.
+    fn claim(
+        &mut self,
+        name: &str,
+        path: Option<&str>,
+        ranges: &[(u64, u64)],
+    ) -> Result<usize, String> {
+        if self.image_by_name(name).is_some() {
+            return Err(format!("an image named `{name}` has already been loaded"));
+        }
+
+        for (i, &(start, end)) in ranges.iter().enumerate() {
+            if let Some((other, other_start, other_end)) = self.find_overlap(start, end) {
+                return Err(format!(
+                    "image `{name}` ({start:#x}..{end:#x}) overlaps with image `{other}` \
+                     ({other_start:#x}..{other_end:#x})"
+                ));
+            }
+            // Also check for overlaps within the image itself.
+            if let Some(&(a, b)) = ranges[..i].iter().find(|(a, b)| start < *b && *a < end) {
+                return Err(format!(
+                    "image `{name}` contains overlapping regions ({a:#x}..{b:#x} and \
+                     {start:#x}..{end:#x})"
+                ));
+            }
+        }
+
+        let index = self.images.len();
+        let mut image = Image::new(name, path);
+        for &(start, end) in ranges.iter().filter(|(start, end)| start < end) {
+            self.owners.insert(start, (end, index));
+            image.ranges.push((start, end));
+        }
+        self.images.push(image);
+
+        tracing::debug!("image `{name}` claimed: {:#x?}", self.images[index].ranges);
+        Ok(index)
+    }
+
+    /// Removes the claim of the most recently added image (used for cleaning up after a failure).
+    fn release(&mut self, index: usize) {
+        assert_eq!(index + 1, self.images.len(), "only the last image can be released");
+        self.owners.retain(|_, (_, owner)| *owner != index);
+        self.images.pop();
+    }
+
+    /// Rebuilds the owner of each address range from the ranges of each image.
+    fn rebuild_owners(&mut self) {
+        self.owners.clear();
+        for (index, image) in self.images.iter().enumerate() {
+            for &(start, end) in &image.ranges {
+                self.owners.insert(start, (end, index));
+            }
+        }
+    }
+
+    fn saved_state(&self) -> SavedState {
+        SavedState {
+            images: self
+                .images
+                .iter()
+                .map(|image| SavedImage {
+                    name: image.name.clone(),
+                    ranges: image.ranges.clone(),
+                    entry: image.entry,
+                })
+                .collect(),
+            primary: self.primary,
+        }
+    }
+
+    fn find_overlap(&self, start: u64, end: u64) -> Option<(&str, u64, u64)> {
+        // Any existing range that overlaps must start before `end`, the last such range is the
+        // only range that can overlap since claimed ranges never overlap each other.
+        let (other_start, (other_end, index)) = self.owners.range(..end).next_back()?;
+        (start < *other_end).then(|| (self.images[*index].name.as_str(), *other_start, *other_end))
+    }
+}
+
+/// Map (if required) and write `data` to memory starting at `addr`, then set the permissions of
+/// the region to `perm`.
+fn write_region(cpu: &mut Cpu, addr: u64, data: &[u8], perm: u8) -> Result<(), String> {
+    if data.is_empty() {
+        return Ok(());
+    }
+
+    // Map any pages that are not already mapped (e.g. by a previous image that shares the page).
+    let page_size = cpu.mem.page_size();
+    let end = align_up(addr + data.len() as u64, page_size);
+    let mut page = align_down(addr, page_size);
+    while page < end {
+        if cpu.mem.get_mapping().get(page).is_none() {
+            cpu.mem.map_memory_len(page, page_size, Mapping { perm: perm::NONE, value: 0x00 });
+        }
+        page += page_size;
+    }
+
+    cpu.mem
+        .write_bytes(addr, data, perm::NONE)
+        .map_err(|e| format!("Failed to write to memory at {addr:#0x}: {e}"))?;
+    cpu.mem
+        .update_perm(addr, data.len() as u64, perm)
+        .map_err(|e| format!("Failed to update permissions at {addr:#0x}: {e}"))
+}
+
+/// The serialized form of a [Composition], see [Environment::save_state].
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+struct SavedState {
+    images: Vec<SavedImage>,
+    primary: Option<usize>,
+}
+
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+struct SavedImage {
+    name: String,
+    ranges: Vec<(u64, u64)>,
+    entry: Option<u64>,
+}
+
+impl Environment for Composition {
+    fn load(&mut self, cpu: &mut Cpu, path: &[u8]) -> Result<(), String> {
+        let path = std::str::from_utf8(path)
+            .map_err(|e| format!("@fixme: only utf-8 paths are supported: {e}"))?;
+
+        if self.image_by_name(path).is_none() {
+            self.add_file(cpu, path, path, None)?;
+        }
+
+        let entry = self
+            .primary
+            .or_else(|| self.images.iter().position(|image| image.entry.is_some()))
+            .and_then(|index| self.images[index].entry)
+            .ok_or_else(|| "no image specifies an entrypoint".to_string())?;
+        (cpu.arch.on_boot)(cpu, entry);
+
+        Ok(())
+    }
+
+    fn handle_exception(&mut self, _: &mut Cpu) -> Option<VmExit> {
+        None
+    }
+
+    fn debug_info(&self) -> Option<&DebugInfo> {
+        self.primary.map(|index| &self.images[index].debug_info)
+    }
+
+    fn symbolize_addr(&mut self, _: &mut Cpu, addr: u64) -> Option<SourceLocation> {
+        let image = self.image_at(addr)?;
+        let base = image.ranges.iter().map(|(start, _)| *start).min().unwrap_or(0);
+        let mut location = image.debug_info.symbolize_addr(addr).unwrap_or_default();
+        location.library_name_and_offset = Some((image.name.as_bytes().to_vec(), addr - base));
+        Some(location)
+    }
+
+    fn lookup_symbol(&mut self, symbol: &str) -> Option<u64> {
+        // Symbols can be qualified with the image name (e.g. `bootloader!main`).
+        if let Some((name, symbol)) = symbol.split_once('!') {
+            return self.image_by_name(name)?.debug_info.symbols.resolve_sym(symbol);
+        }
+        self.images.iter().find_map(|image| image.debug_info.symbols.resolve_sym(symbol))
+    }
+
+    fn entry_point(&mut self) -> u64 {
+        self.primary.and_then(|index| self.images[index].entry).unwrap_or(0)
+    }
+
+    fn snapshot(&mut self) -> Box<dyn Any> {
+        Box::new(self.saved_state())
+    }
+
+    fn restore(&mut self, snapshot: &Box<dyn Any>) {
+        let state = snapshot.downcast_ref::<SavedState>().unwrap();
+
+        // Images are only ever appended, so any image added after the snapshot is at the end.
+        self.images.truncate(state.images.len());
+        for (image, saved) in self.images.iter_mut().zip(&state.images) {
+            image.ranges.clone_from(&saved.ranges);
+            image.entry = saved.entry;
+        }
+        self.primary = state.primary.filter(|index| *index < self.images.len());
+        self.rebuild_owners();
+    }
+
+    fn save_state(&mut self) -> Result<Vec<u8>, String> {
+        ron::to_string(&self.saved_state())
+            .map(String::into_bytes)
+            .map_err(|e| format!("failed to serialize composition state: {e}"))
+    }
+
+    fn load_state(&mut self, data: &[u8]) -> Result<(), String> {
+        let state: SavedState =
+            ron::de::from_bytes(data).map_err(|e| format!("invalid composition state: {e}"))?;
+
+        // Debug info is not saved, so every image must have already been loaded by the setup code.
+        let mut images = Vec::with_capacity(state.images.len());
+        for saved in state.images {
+            let index = self
+                .images
+                .iter()
+                .position(|image| image.name == saved.name)
+                .ok_or_else(|| format!("image `{}` has not been loaded", saved.name))?;
+            let mut image = Image::new(&saved.name, self.images[index].path.as_deref());
+            image.debug_info = std::mem::take(&mut self.images[index].debug_info);
+            image.ranges = saved.ranges;
+            image.entry = saved.entry;
+            images.push(image);
+        }
+
+        self.images = images;
+        self.rebuild_owners();
+        self.primary = state.primary.filter(|index| *index < self.images.len());
+        Ok(())
+    }
+}
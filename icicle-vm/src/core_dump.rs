@@ -0,0 +1,334 @@
+//! Generation of ELF core files for crashed guests.
+//!
+//! Core files contain every memory region mapped in the guest along with `NT_PRSTATUS` (register
+//! state and the signal that terminated the guest) and `NT_FILE` (files mapped by the Linux
+//! environment) notes, so crashes can be inspected with `gdb <binary> <core>` without re-running
+//! them under the emulator.
+
+use std::path::Path;
+
+use object::elf;
+
+use crate::{
+    Vm,
+    cpu::{
+        ExceptionCode,
+        mem::{MemoryMapping, perm},
+    },
+};
+
+const SIGILL: u32 = 4;
+const SIGTRAP: u32 = 5;
+const SIGABRT: u32 = 6;
+const SIGBUS: u32 = 7;
+const SIGFPE: u32 = 8;
+const SIGSEGV: u32 = 11;
+
+/// Writes a core file for the current state of `vm` to `path`. `code` is the exception that caused
+/// the crash, which determines the signal reported in the core file.
+pub fn write_core_dump(
+    vm: &mut Vm,
+    code: ExceptionCode,
+    path: impl AsRef<Path>,
+) -> anyhow::Result<()> {
+    let data = build_core_dump(vm, code)?;
+    std::fs::write(path.as_ref(), data)?;
+    Ok(())
+}
+
+/// Returns the signal Linux would deliver for `code`.
+pub fn signal_for_exception(code: ExceptionCode) -> u32 {
+    match code {
+        ExceptionCode::ReadUnaligned
+        | ExceptionCode::WriteUnaligned
+        | ExceptionCode::ExecUnaligned => SIGBUS,
+        ExceptionCode::InvalidInstruction => SIGILL,
+        ExceptionCode::DivisionException => SIGFPE,
+        ExceptionCode::SoftwareBreakpoint => SIGTRAP,
+        code if code.is_memory_error() => SIGSEGV,
+        ExceptionCode::ExecViolation => SIGSEGV,
+        _ => SIGABRT,
+    }
+}
+
+/// A contiguous range of memory with the same permissions.
+pub(crate) struct Segment {
+    pub addr: u64,
+    pub len: u64,
+    pub perm: u8,
+    /// The content of the segment, or `None` if the memory has not been allocated yet.
+    pub data: Option<Vec<u8>>,
+    file_offset: usize,
+}
+
+/// Builds an ELF core file for the current state of `vm`.
+pub fn build_core_dump(vm: &mut Vm, code: ExceptionCode) -> anyhow::Result<Vec<u8>> {
+    let arch = vm.cpu.arch.triple.architecture;
+    let Some((machine, regs)) = core_target(arch)
+    else {
+        anyhow::bail!("core dumps are not supported for {arch}");
+    };
+
+    let big_endian = vm.cpu.arch.sleigh.big_endian;
+    let word = arch.pointer_width().map_or(4, |x| x.bytes() as usize);
+    let mut segments = collect_segments(vm);
+
+    let mut notes = vec![];
+    let prstatus = build_prstatus(vm, regs, signal_for_exception(code), word, big_endian);
+    push_note(&mut notes, big_endian, b"CORE", elf::NT_PRSTATUS, &prstatus);
+    if let Some(files) = build_file_note(vm, word, big_endian) {
+        push_note(&mut notes, big_endian, b"CORE", elf::NT_FILE, &files);
+    }
+
+    let endian = match big_endian {
+        true => object::Endianness::Big,
+        false => object::Endianness::Little,
+    };
+    let mut out = vec![];
+    let mut writer = object::write::elf::Writer::new(endian, word == 8, &mut out);
+    writer.reserve_file_header();
+    writer.reserve_program_headers(segments.len() as u32 + 1);
+
+    let notes_offset = writer.reserve(notes.len(), 4);
+    for segment in &mut segments {
+        segment.file_offset = match &segment.data {
+            Some(data) => writer.reserve(data.len(), 0x1000),
+            None => writer.reserved_len(),
+        };
+    }
+
+    writer.write_file_header(&object::write::elf::FileHeader {
+        os_abi: elf::ELFOSABI_NONE,
+        abi_version: 0,
+        e_type: elf::ET_CORE,
+        e_machine: machine,
+        e_entry: 0,
+        e_flags: 0,
+    })?;
+
+    writer.write_align_program_headers();
+    writer.write_program_header(&object::write::elf::ProgramHeader {
+        p_type: elf::PT_NOTE,
+        p_flags: 0,
+        p_offset: notes_offset as u64,
+        p_vaddr: 0,
+        p_paddr: 0,
+        p_filesz: notes.len() as u64,
+        p_memsz: 0,
+        p_align: 4,
+    });
+    for segment in &segments {
+        let mut flags = 0;
+        if segment.perm & perm::READ != 0 {
+            flags |= elf::PF_R;
+        }
+        if segment.perm & perm::WRITE != 0 {
+            flags |= elf::PF_W;
+        }
+        if segment.perm & perm::EXEC != 0 {
+            flags |= elf::PF_X;
+        }
+        writer.write_program_header(&object::write::elf::ProgramHeader {
+            p_type: elf::PT_LOAD,
+            p_flags: flags,
+            p_offset: segment.file_offset as u64,
+            p_vaddr: segment.addr,
+            p_paddr: 0,
+            p_filesz: segment.data.as_ref().map_or(0, |x| x.len() as u64),
+            p_memsz: segment.len,
+            p_align: 0x1000,
+        });
+    }
+
+    writer.pad_until(notes_offset);
+    writer.write(&notes);
+    for segment in &segments {
+        if let Some(data) = &segment.data {
+            writer.pad_until(segment.file_offset);
+            writer.write(data);
+        }
+    }
+
+    Ok(out)
+}
+
+/// Collects the memory regions of the guest, merging adjacent regions with the same permissions.
+/// Memory mapped IO regions are excluded.
+pub(crate) fn collect_segments(vm: &mut Vm) -> Vec<Segment> {
+    // Note: `end` is inclusive.
+    let mut entries = vec![];
+    for (start, end, entry) in vm.cpu.mem.get_mapping().iter() {
+        match entry {
+            MemoryMapping::Physical(_) => entries.push((start, end, true)),
+            MemoryMapping::Unallocated(_) => entries.push((start, end, false)),
+            MemoryMapping::Io(_) | MemoryMapping::PhysicalIo(_) => continue,
+        }
+    }
+
+    let mut segments: Vec<Segment> = vec![];
+    for (start, end, is_allocated) in entries {
+        // Assume that all permissions are the same for the region of memory.
+        let perm = vm.cpu.mem.get_perm(start);
+        let len = end - start + 1;
+        let data = match is_allocated {
+            true => {
+                let mut buf = vec![0; len as usize];
+                vm.cpu.mem.read_bytes_large(start, &mut buf, perm::NONE).ok().map(|_| buf)
+            }
+            false => None,
+        };
+
+        match segments.last_mut() {
+            Some(prev)
+                if prev.addr + prev.len == start
+                    && prev.perm == perm
+                    && prev.data.is_some() == data.is_some() =>
+            {
+                prev.len += len;
+                if let (Some(prev), Some(data)) = (&mut prev.data, data) {
+                    prev.extend_from_slice(&data);
+                }
+            }
+            _ => segments.push(Segment { addr: start, len, perm, data, file_offset: 0 }),
+        }
+    }
+    segments
+}
+
+/// Gets the ELF machine type for `arch`, and the names of the registers stored in the `pr_reg`
+/// field of `elf_prstatus` in the order used by the kernel. Empty names are stored as zero.
+#[rustfmt::skip]
+fn core_target(arch: target_lexicon::Architecture) -> Option<(u16, &'static [&'static str])> {
+    use target_lexicon::Architecture;
+
+    Some(match arch {
+        Architecture::X86_64 => (elf::EM_X86_64, &[
+            "R15", "R14", "R13", "R12", "RBP", "RBX", "R11", "R10", "R9", "R8",
+            "RAX", "RCX", "RDX", "RSI", "RDI", "", "RIP", "CS", "eflags", "RSP",
+            "SS", "FS_OFFSET", "GS_OFFSET", "DS", "ES", "FS", "GS",
+        ]),
+        Architecture::X86_32(_) => (elf::EM_386, &[
+            "EBX", "ECX", "EDX", "ESI", "EDI", "EBP", "EAX", "DS", "ES", "FS",
+            "GS", "", "EIP", "CS", "eflags", "ESP", "SS",
+        ]),
+        Architecture::Aarch64(_) => (elf::EM_AARCH64, &[
+            "x0", "x1", "x2", "x3", "x4", "x5", "x6", "x7", "x8", "x9",
+            "x10", "x11", "x12", "x13", "x14", "x15", "x16", "x17", "x18", "x19",
+            "x20", "x21", "x22", "x23", "x24", "x25", "x26", "x27", "x28", "x29",
+            "x30", "sp", "pc", "",
+        ]),
+        Architecture::Arm(_) => (elf::EM_ARM, &[
+            "r0", "r1", "r2", "r3", "r4", "r5", "r6", "r7", "r8", "r9",
+            "r10", "r11", "r12", "sp", "lr", "pc", "cpsr", "",
+        ]),
+        Architecture::Riscv32(_) | Architecture::Riscv64(_) => (elf::EM_RISCV, &[
+            "pc", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1",
+            "a0", "a1", "a2", "a3", "a4", "a5", "a6", "a7", "s2", "s3",
+            "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+            "t5", "t6",
+        ]),
+        Architecture::Mips32(_) => (elf::EM_MIPS, &[
+            "", "", "", "", "", "",
+            "zero", "at", "v0", "v1", "a0", "a1", "a2", "a3", "t0", "t1",
+            "t2", "t3", "t4", "t5", "t6", "t7", "s0", "s1", "s2", "s3",
+            "s4", "s5", "s6", "s7", "t8", "t9", "k0", "k1", "gp", "sp",
+            "s8", "ra", "lo", "hi", "pc", "", "", "", "",
+        ]),
+        _ => return None,
+    })
+}
+
+/// Builds the `elf_prstatus` structure for the current thread.
+fn build_prstatus(
+    vm: &mut Vm,
+    regs: &[&str],
+    signal: u32,
+    word: usize,
+    big_endian: bool,
+) -> Vec<u8> {
+    let mut out = vec![];
+    let push = |out: &mut Vec<u8>, value: u64, size: usize| match big_endian {
+        true => out.extend_from_slice(&value.to_be_bytes()[8 - size..]),
+        false => out.extend_from_slice(&value.to_le_bytes()[..size]),
+    };
+
+    let pid = vm.env_ref::<crate::linux::Kernel>().map_or(1, |kernel| kernel.process.pid);
+
+    // si_signo, si_code, si_errno
+    push(&mut out, signal as u64, 4);
+    push(&mut out, 0, 4);
+    push(&mut out, 0, 4);
+    // pr_cursig (+ padding)
+    push(&mut out, signal as u64, 2);
+    push(&mut out, 0, 2);
+    // pr_sigpend, pr_sighold
+    push(&mut out, 0, word);
+    push(&mut out, 0, word);
+    // pr_pid, pr_ppid, pr_pgrp, pr_sid
+    push(&mut out, pid, 4);
+    push(&mut out, 0, 4);
+    push(&mut out, pid, 4);
+    push(&mut out, pid, 4);
+    // pr_utime, pr_stime, pr_cutime, pr_cstime
+    out.resize(out.len() + 8 * word, 0);
+
+    // pr_reg
+    for name in regs {
+        let value = match *name {
+            "eflags" => crate::x86::eflags(&vm.cpu) as u64,
+            name => vm.cpu.arch.sleigh.get_varnode(name).map_or(0, |var| vm.cpu.read_reg(var)),
+        };
+        push(&mut out, value, word);
+    }
+
+    // pr_fpvalid (+ padding)
+    push(&mut out, 0, 4);
+    out.resize(out.len().next_multiple_of(word), 0);
+    out
+}
+
+/// Builds the `NT_FILE` note describing the files mapped by the Linux environment. Returns `None`
+/// if the VM is not running a Linux environment.
+fn build_file_note(vm: &Vm, word: usize, big_endian: bool) -> Option<Vec<u8>> {
+    let kernel = vm.env_ref::<crate::linux::Kernel>()?;
+
+    // Pseudo-files (e.g. the stack) are named using parentheses.
+    let files: Vec<_> =
+        kernel.process.mapping.iter().filter(|(_, file)| !file.path.starts_with(b"(")).collect();
+
+    let mut out = vec![];
+    let push = |out: &mut Vec<u8>, value: u64| match big_endian {
+        true => out.extend_from_slice(&value.to_be_bytes()[8 - word..]),
+        false => out.extend_from_slice(&value.to_le_bytes()[..word]),
+    };
+    push(&mut out, files.len() as u64);
+    push(&mut out, 0x1000);
+    for (start, file) in &files {
+        // @fixme: the offset of the mapping within the file is not tracked.
+        push(&mut out, **start);
+        push(&mut out, file.end);
+        push(&mut out, 0);
+    }
+    for (_, file) in &files {
+        out.extend_from_slice(&file.path);
+        out.push(0);
+    }
+    Some(out)
+}
+
+/// Appends an ELF note to `out`.
+fn push_note(out: &mut Vec<u8>, big_endian: bool, name: &[u8], n_type: u32, desc: &[u8]) {
+    let mut push = |value: u32| match big_endian {
+        true => out.extend_from_slice(&value.to_be_bytes()),
+        false => out.extend_from_slice(&value.to_le_bytes()),
+    };
+    push(name.len() as u32 + 1);
+    push(desc.len() as u32);
+    push(n_type);
+
+    out.extend_from_slice(name);
+    out.push(0);
+    out.resize(out.len().next_multiple_of(4), 0);
+    out.extend_from_slice(desc);
+    out.resize(out.len().next_multiple_of(4), 0);
+}
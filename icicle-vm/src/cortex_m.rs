@@ -0,0 +1,892 @@
+//! An environment for ARM Cortex-M microcontrollers.
+//!
+//! [CortexM] extends [GenericEmbedded] with the parts of the system control space (SCS) required
+//! to run interrupt-driven firmware:
+//!
+//! - The vector table, which is used to find the initial stack pointer and reset handler when the
+//!   firmware is loaded, and the handler of each exception.
+//! - Exception entry and return, including stacking of the caller-saved registers, preemption based
+//!   on the priority of each exception, and tail-chaining of pending exceptions.
+//! - Masking of exceptions using `PRIMASK` (`cpsid i`/`cpsie i`) and `BASEPRI`.
+//! - The SysTick timer, which is decremented on each tick of the virtual clock (i.e. once per
+//!   instruction, or approximately once per cycle if [icicle_cpu::Config::cycle_timing] is set).
+//! - The NVIC enable, pending, active and priority registers of each external interrupt.
+//!
+//! Interrupts can also be raised by the emulator using [CortexM::set_pending] and
+//! [CortexM::schedule_interrupt], or queued on the VM (see [crate::interrupts]).
+//!
+//! Exceptions are only delivered when the VM exits to the environment, so the environment requests
+//! an exit (see [Environment::next_timer]) when the next SysTick or scheduled interrupt is due, and
+//! at least every [Config::poll_interval] instructions to handle changes made by the guest. The
+//! value of the SysTick counter observed by the guest is only updated at these points.
+//!
+//! Only the main stack is modelled, and floating-point state is never stacked.
+
+use std::{any::Any, cell::RefCell, rc::Rc};
+
+use icicle_cpu::{
+    Cpu, Environment, Exception, ExceptionCode, VmExit,
+    debug_info::DebugInfo,
+    exec::helpers,
+    mem::{IoHandler, MemError, MemResult, MmioHandler, mmio::MmioRegion, perm},
+};
+
+use crate::{BuildError, env::GenericEmbedded};
+
+/// The base address of the system control space.
+pub const SCS_BASE: u64 = 0xe000_e000;
+
+/// The size of the system control space.
+pub const SCS_LEN: u64 = 0x1000;
+
+/// The number of external interrupts supported by the NVIC.
+pub const NUM_IRQS: usize = 240;
+
+/// The total number of exceptions (system exceptions followed by external interrupts).
+const NUM_EXCEPTIONS: usize = IRQ_BASE as usize + NUM_IRQS;
+
+pub const RESET: u16 = 1;
+pub const NMI: u16 = 2;
+pub const HARD_FAULT: u16 = 3;
+pub const SVCALL: u16 = 11;
+pub const PENDSV: u16 = 14;
+pub const SYSTICK: u16 = 15;
+
+/// The exception number of the first external interrupt.
+pub const IRQ_BASE: u16 = 16;
+
+/// The value of `LR` on exception entry from thread mode (returning to thread mode using the main
+/// stack).
+const EXC_RETURN_THREAD: u32 = 0xffff_fff9;
+
+/// The value of `LR` on exception entry from handler mode.
+const EXC_RETURN_HANDLER: u32 = 0xffff_fff1;
+
+/// The execution priority when no exceptions are active.
+const BASE_PRIORITY: i16 = 256;
+
+/// Symbols commonly used for the vector table by startup code.
+const VECTOR_TABLE_SYMBOLS: &[&str] =
+    &["g_pfnVectors", "__isr_vector", "__Vectors", "__vector_table", "_vectors", "vector_table"];
+
+const SYST_ENABLE: u32 = 1 << 0;
+const SYST_TICKINT: u32 = 1 << 1;
+const SYST_COUNTFLAG: u32 = 1 << 16;
+
+const ICSR_NMIPENDSET: u32 = 1 << 31;
+const ICSR_PENDSVSET: u32 = 1 << 28;
+const ICSR_PENDSVCLR: u32 = 1 << 27;
+const ICSR_PENDSTSET: u32 = 1 << 26;
+const ICSR_PENDSTCLR: u32 = 1 << 25;
+const ICSR_ISRPENDING: u32 = 1 << 22;
+
+/// The value of the CPUID register (Cortex-M4 r0p1).
+const CPUID: u32 = 0x410f_c241;
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// The address of the vector table. If `None`, the address of a known vector table symbol in
+    /// the firmware is used (e.g. `g_pfnVectors`), falling back to address 0.
+    pub vtor: Option<u64>,
+
+    /// The maximum number of instructions to execute before checking for pending exceptions.
+    pub poll_interval: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self { vtor: None, poll_interval: 0x1000 }
+    }
+}
+
+#[derive(Clone, Copy, Default, serde::Serialize, serde::Deserialize)]
+struct SysTick {
+    ctrl: u32,
+    reload: u32,
+
+    /// The value of the counter at `base_icount`.
+    base_value: u32,
+
+    /// The value of the virtual clock when the counter was last updated.
+    base_icount: u64,
+}
+
+impl SysTick {
+    fn enabled(&self) -> bool {
+        self.ctrl & SYST_ENABLE != 0
+    }
+
+    /// Returns the value of the counter at `now`.
+    fn value(&self, now: u64) -> u32 {
+        if !self.enabled() {
+            return self.base_value;
+        }
+        let elapsed = now.saturating_sub(self.base_icount);
+        if elapsed <= self.base_value as u64 {
+            return self.base_value - elapsed as u32;
+        }
+        if self.reload == 0 {
+            return 0;
+        }
+        // After reaching zero, the counter is reloaded on the next tick.
+        let period = self.reload as u64 + 1;
+        self.reload - ((elapsed - self.base_value as u64 - 1) % period) as u32
+    }
+
+    fn rebase(&mut self, now: u64) {
+        self.base_value = self.value(now);
+        self.base_icount = now;
+    }
+
+    /// Returns the value of the virtual clock when the counter next reaches zero.
+    fn deadline(&self) -> Option<u64> {
+        if !self.enabled() {
+            return None;
+        }
+        match self.base_value {
+            0 if self.reload == 0 => None,
+            0 => Some(self.base_icount + self.reload as u64 + 1),
+            value => Some(self.base_icount + value as u64),
+        }
+    }
+}
+
+/// The state of the NVIC, SCB and SysTick registers.
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+pub struct Nvic {
+    // The following are indexed by exception number.
+    enabled: Vec<bool>,
+    pending: Vec<bool>,
+    active: Vec<bool>,
+    priority: Vec<u8>,
+
+    /// The address of the vector table.
+    pub vtor: u32,
+
+    /// The number of the exception currently being handled (or 0 in thread mode).
+    current: u16,
+
+    systick: SysTick,
+
+    /// The value of the virtual clock the last time the environment was run.
+    now: u64,
+}
+
+impl Nvic {
+    fn new() -> Self {
+        let mut enabled = vec![false; NUM_EXCEPTIONS];
+        // System exceptions are always enabled.
+        enabled[..IRQ_BASE as usize].fill(true);
+        Self {
+            enabled,
+            pending: vec![false; NUM_EXCEPTIONS],
+            active: vec![false; NUM_EXCEPTIONS],
+            priority: vec![0; NUM_EXCEPTIONS],
+            vtor: 0,
+            current: 0,
+            systick: SysTick::default(),
+            now: 0,
+        }
+    }
+
+    /// Marks `exception` as pending. Returns `false` if the exception number is invalid.
+    pub fn set_pending(&mut self, exception: u16) -> bool {
+        match self.pending.get_mut(exception as usize) {
+            Some(pending) if exception > RESET => {
+                *pending = true;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn is_pending(&self, exception: u16) -> bool {
+        self.pending.get(exception as usize).copied().unwrap_or(false)
+    }
+
+    pub fn is_enabled(&self, exception: u16) -> bool {
+        self.enabled.get(exception as usize).copied().unwrap_or(false)
+    }
+
+    pub fn set_enabled(&mut self, exception: u16, enabled: bool) {
+        if exception >= IRQ_BASE && (exception as usize) < NUM_EXCEPTIONS {
+            self.enabled[exception as usize] = enabled;
+        }
+    }
+
+    /// Returns the priority of `exception` (lower values have higher priority).
+    pub fn priority(&self, exception: u16) -> i16 {
+        match exception {
+            RESET => -3,
+            NMI => -2,
+            HARD_FAULT => -1,
+            n => self.priority.get(n as usize).map_or(0, |x| *x as i16),
+        }
+    }
+
+    pub fn set_priority(&mut self, exception: u16, priority: u8) {
+        if exception > HARD_FAULT && (exception as usize) < NUM_EXCEPTIONS {
+            self.priority[exception as usize] = priority;
+        }
+    }
+
+    /// Returns the pending and enabled exception with the highest priority.
+    fn highest_pending(&self) -> Option<u16> {
+        (1..NUM_EXCEPTIONS as u16)
+            .filter(|&n| self.pending[n as usize] && self.enabled[n as usize])
+            .min_by_key(|&n| (self.priority(n), n))
+    }
+
+    fn read_reg(&mut self, offset: u64) -> u32 {
+        match offset {
+            // ICTR
+            0x004 => (NUM_IRQS as u32).div_ceil(32) - 1,
+
+            // SysTick
+            0x010 => {
+                let value = self.systick.ctrl;
+                self.systick.ctrl &= !SYST_COUNTFLAG;
+                value
+            }
+            0x014 => self.systick.reload,
+            0x018 => self.systick.value(self.now),
+
+            // NVIC
+            0x100..0x140 => self.irq_bits(&self.enabled, offset - 0x100),
+            0x180..0x1c0 => self.irq_bits(&self.enabled, offset - 0x180),
+            0x200..0x240 => self.irq_bits(&self.pending, offset - 0x200),
+            0x280..0x2c0 => self.irq_bits(&self.pending, offset - 0x280),
+            0x300..0x340 => self.irq_bits(&self.active, offset - 0x300),
+
+            // SCB
+            0xd00 => CPUID,
+            0xd04 => self.icsr(),
+            0xd08 => self.vtor,
+            0xd0c => 0xfa05_0000,
+            // CCR (STKALIGN is always set)
+            0xd14 => 0x200,
+
+            _ => 0,
+        }
+    }
+
+    fn write_reg(&mut self, offset: u64, value: u32) {
+        match offset {
+            // SysTick
+            0x010 => {
+                self.systick.rebase(self.now);
+                self.systick.ctrl = (value & 0x7) | (self.systick.ctrl & SYST_COUNTFLAG);
+            }
+            0x014 => {
+                self.systick.rebase(self.now);
+                self.systick.reload = value & 0xff_ffff;
+            }
+            0x018 => {
+                // Any write clears the counter.
+                self.systick.base_value = 0;
+                self.systick.base_icount = self.now;
+                self.systick.ctrl &= !SYST_COUNTFLAG;
+            }
+
+            // NVIC
+            0x100..0x140 => set_irq_bits(&mut self.enabled, offset - 0x100, value, true),
+            0x180..0x1c0 => set_irq_bits(&mut self.enabled, offset - 0x180, value, false),
+            0x200..0x240 => set_irq_bits(&mut self.pending, offset - 0x200, value, true),
+            0x280..0x2c0 => set_irq_bits(&mut self.pending, offset - 0x280, value, false),
+
+            // SCB
+            0xd04 => {
+                if value & ICSR_NMIPENDSET != 0 {
+                    self.pending[NMI as usize] = true;
+                }
+                if value & ICSR_PENDSVSET != 0 {
+                    self.pending[PENDSV as usize] = true;
+                }
+                if value & ICSR_PENDSVCLR != 0 {
+                    self.pending[PENDSV as usize] = false;
+                }
+                if value & ICSR_PENDSTSET != 0 {
+                    self.pending[SYSTICK as usize] = true;
+                }
+                if value & ICSR_PENDSTCLR != 0 {
+                    self.pending[SYSTICK as usize] = false;
+                }
+            }
+            0xd08 => self.vtor = value & !0x7f,
+            0xd0c if value >> 16 == 0x05fa && value & 0b100 != 0 => {
+                tracing::warn!("system reset requested (ignored)");
+            }
+
+            // STIR
+            0xf00 => {
+                self.set_pending(IRQ_BASE + (value & 0x1ff) as u16);
+            }
+
+            _ => {}
+        }
+    }
+
+    fn irq_bits(&self, bits: &[bool], offset: u64) -> u32 {
+        let first = IRQ_BASE as usize + offset as usize * 8;
+        let bits = bits.get(first..).unwrap_or(&[]);
+        bits.iter().take(32).enumerate().fold(0, |acc, (i, &set)| acc | ((set as u32) << i))
+    }
+
+    fn icsr(&self) -> u32 {
+        let mut value = self.current as u32;
+        if let Some(pending) = self.highest_pending() {
+            value |= (pending as u32) << 12;
+        }
+        if self.pending[IRQ_BASE as usize..].iter().any(|&x| x) {
+            value |= ICSR_ISRPENDING;
+        }
+        if self.pending[NMI as usize] {
+            value |= ICSR_NMIPENDSET;
+        }
+        if self.pending[PENDSV as usize] {
+            value |= ICSR_PENDSVSET;
+        }
+        if self.pending[SYSTICK as usize] {
+            value |= ICSR_PENDSTSET;
+        }
+        value
+    }
+
+    /// Returns the address of the byte-sized priority field at `offset`, if there is one.
+    fn priority_field(offset: u64) -> Option<usize> {
+        match offset {
+            0x400..0x4f0 => Some(IRQ_BASE as usize + (offset - 0x400) as usize),
+            // SHPR1-3 (starting from MemManage)
+            0xd18..0xd24 => Some(4 + (offset - 0xd18) as usize),
+            _ => None,
+        }
+    }
+
+    fn load(&mut self, offset: u64, size: u8) -> u64 {
+        if Self::priority_field(offset).is_some() {
+            return (0..size as u64).fold(0, |acc, i| {
+                let value = Self::priority_field(offset + i).map_or(0, |n| self.priority[n]);
+                acc | ((value as u64) << (i * 8))
+            });
+        }
+        let shift = (offset & 0x3) * 8;
+        (self.read_reg(offset & !0x3) as u64 >> shift) & pcode::mask(size as u64 * 8)
+    }
+
+    fn store(&mut self, offset: u64, size: u8, value: u64) {
+        if Self::priority_field(offset).is_some() {
+            for i in 0..size as u64 {
+                let priority = (value >> (i * 8)) as u8;
+                if let Some(n) = Self::priority_field(offset + i) {
+                    self.set_priority(n as u16, priority);
+                }
+            }
+            return;
+        }
+        let shift = (offset & 0x3) * 8;
+        self.write_reg(offset & !0x3, (value << shift) as u32);
+    }
+}
+
+fn set_irq_bits(bits: &mut [bool], offset: u64, value: u32, set: bool) {
+    let first = IRQ_BASE as usize + offset as usize * 8;
+    for (i, bit) in bits.iter_mut().skip(first).take(32).enumerate() {
+        if value & (1 << i) != 0 {
+            *bit = set;
+        }
+    }
+}
+
+/// The system control space peripheral, which shares its state with the environment.
+struct Scs(Rc<RefCell<Nvic>>);
+
+impl MmioHandler for Scs {
+    fn load(&mut self, addr: u64, size: u8) -> MemResult<u64> {
+        Ok(self.0.borrow_mut().load(addr - SCS_BASE, size))
+    }
+
+    fn store(&mut self, addr: u64, size: u8, value: u64) -> MemResult<()> {
+        self.0.borrow_mut().store(addr - SCS_BASE, size, value);
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, serde::Serialize, serde::Deserialize)]
+struct ActiveException {
+    number: u16,
+
+    /// The PC value the exception was entered from.
+    return_addr: u64,
+
+    /// The (block id, offset) that the exception was entered from.
+    #[serde(skip, default = "no_block")]
+    return_block: (u64, u64),
+}
+
+fn no_block() -> (u64, u64) {
+    (u64::MAX, 0)
+}
+
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+struct State {
+    nvic: Nvic,
+    active: Vec<ActiveException>,
+    scheduled: Vec<(u64, u16)>,
+    next_timer: u64,
+}
+
+struct Registers {
+    /// The registers saved on exception entry (`r0-r3`, `r12`, `lr`).
+    saved: [pcode::VarNode; 6],
+    sp: pcode::VarNode,
+    lr: pcode::VarNode,
+
+    /// The `N`, `Z`, `C`, `V` and `Q` flags.
+    flags: [Option<pcode::VarNode>; 5],
+
+    primask: Option<pcode::VarNode>,
+    basepri: Option<pcode::VarNode>,
+    ipsr: Option<pcode::VarNode>,
+}
+
+pub struct CortexM {
+    inner: GenericEmbedded,
+    config: Config,
+    regs: Registers,
+    nvic: Rc<RefCell<Nvic>>,
+    scs: IoHandler,
+
+    /// Exceptions that have been entered but not returned from (innermost last).
+    active: Vec<ActiveException>,
+
+    /// Interrupts scheduled using [CortexM::schedule_interrupt] as (clock, exception) pairs,
+    /// sorted by clock.
+    scheduled: Vec<(u64, u16)>,
+
+    /// The icount that the environment next needs to run at.
+    next_timer: u64,
+}
+
+impl CortexM {
+    pub fn new(cpu: &mut Cpu, inner: GenericEmbedded, config: Config) -> Result<Self, BuildError> {
+        let sleigh = &cpu.arch.sleigh;
+        let r = |name: &str| sleigh.get_varnode(name).ok_or(BuildError::UnsupportedArchitecture);
+        let regs = Registers {
+            saved: [r("r0")?, r("r1")?, r("r2")?, r("r3")?, r("r12")?, r("lr")?],
+            sp: r("sp")?,
+            lr: r("lr")?,
+            flags: ["NG", "ZR", "CY", "OV", "Q"].map(|name| sleigh.get_varnode(name)),
+            primask: sleigh.get_varnode(helpers::arm::PRIMASK),
+            basepri: sleigh.get_varnode(helpers::arm::BASEPRI),
+            ipsr: sleigh.get_varnode(helpers::arm::IPSR),
+        };
+
+        let nvic = Rc::new(RefCell::new(Nvic::new()));
+        let scs = Scs(nvic.clone());
+        let scs = cpu.mem.register_io_handler(MmioRegion::new(scs, cpu.mem.big_endian));
+
+        Ok(Self {
+            inner,
+            next_timer: config.poll_interval,
+            config,
+            regs,
+            nvic,
+            scs,
+            active: vec![],
+            scheduled: vec![],
+        })
+    }
+
+    /// Marks `exception` as pending, it will be taken the next time the environment runs if it is
+    /// enabled and has a high enough priority. Returns `false` if the exception number is invalid.
+    pub fn set_pending(&mut self, exception: u16) -> bool {
+        // Check for the exception the next time the VM is run.
+        self.next_timer = 0;
+        self.nvic.borrow_mut().set_pending(exception)
+    }
+
+    /// Marks external interrupt `irq` as pending (see [CortexM::set_pending]).
+    pub fn set_irq_pending(&mut self, irq: u16) -> bool {
+        irq < NUM_IRQS as u16 && self.set_pending(IRQ_BASE + irq)
+    }
+
+    /// Marks `exception` as pending when the virtual clock (see [Cpu::clock]) reaches `clock`.
+    /// Without cycle timing, the virtual clock is the instruction count.
+    pub fn schedule_interrupt(&mut self, clock: u64, exception: u16) {
+        let index = self.scheduled.partition_point(|(x, _)| *x <= clock);
+        self.scheduled.insert(index, (clock, exception));
+        // The instruction count the deadline is reached at depends on the state of the CPU, so
+        // run the environment immediately to recompute the next timer.
+        self.next_timer = 0;
+    }
+
+    /// Gets mutable access to the state of the NVIC. Must not be held while the VM is running.
+    pub fn nvic(&self) -> std::cell::RefMut<'_, Nvic> {
+        self.nvic.borrow_mut()
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Replaces the configuration of the environment without resetting it. The poll interval takes
+    /// effect immediately, while [Config::vtor] is only used the next time the core is reset.
+    pub fn set_config(&mut self, cpu: &Cpu, config: Config) {
+        self.config = config;
+        self.update_next_timer(cpu);
+    }
+
+    /// Returns the number of the exception currently being handled, or 0 in thread mode.
+    pub fn current_exception(&self) -> u16 {
+        self.active.last().map_or(0, |x| x.number)
+    }
+
+    /// Maps the system control space and resets the state of all exceptions, then loads the initial
+    /// stack pointer and PC from the vector table. Called after firmware is loaded.
+    pub fn reset(&mut self, cpu: &mut Cpu) {
+        if !cpu.mem.map_memory_len(SCS_BASE, SCS_LEN, self.scs) {
+            tracing::warn!("failed to map system control space at {SCS_BASE:#x}");
+        }
+
+        let vtor = self.vector_table_addr();
+        self.set_state(State {
+            nvic: Nvic { vtor: vtor as u32, now: cpu.clock(), ..Nvic::new() },
+            active: vec![],
+            scheduled: vec![],
+            next_timer: 0,
+        });
+        self.update_current(cpu);
+        self.update_next_timer(cpu);
+
+        let sp = cpu.mem.read_u32(vtor, perm::READ);
+        let reset = cpu.mem.read_u32(vtor + 4, perm::READ);
+        match (sp, reset) {
+            (Ok(sp), Ok(reset)) => {
+                tracing::debug!("vector table at {vtor:#x}: sp={sp:#x}, reset={reset:#x}");
+                cpu.write_reg(self.regs.sp, sp as u64);
+                cpu.write_pc(reset as u64 & !1);
+            }
+            _ => tracing::warn!("failed to read vector table at {vtor:#x}, using ELF entrypoint"),
+        }
+    }
+
+    fn vector_table_addr(&mut self) -> u64 {
+        if let Some(addr) = self.config.vtor {
+            return addr;
+        }
+        VECTOR_TABLE_SYMBOLS.iter().find_map(|name| self.inner.lookup_symbol(name)).unwrap_or(0)
+    }
+
+    fn read_xpsr(&self, cpu: &mut Cpu) -> u32 {
+        let mut xpsr = (1 << 24) | self.current_exception() as u32;
+        for (i, flag) in self.regs.flags.iter().enumerate() {
+            if let Some(flag) = flag {
+                xpsr |= (cpu.read_reg(*flag) as u32 & 1) << (31 - i);
+            }
+        }
+        xpsr
+    }
+
+    fn write_flags(&self, cpu: &mut Cpu, xpsr: u32) {
+        for (i, flag) in self.regs.flags.iter().enumerate() {
+            if let Some(flag) = flag {
+                cpu.write_reg(*flag, ((xpsr >> (31 - i)) & 1) as u64);
+            }
+        }
+    }
+
+    fn update_current(&mut self, cpu: &mut Cpu) {
+        let current = self.current_exception();
+        self.nvic.borrow_mut().current = current;
+        if let Some(ipsr) = self.regs.ipsr {
+            cpu.write_reg(ipsr, current as u64);
+        }
+    }
+
+    /// Returns the priority of the currently executing code, taking into account the priority of
+    /// active exceptions and the exception mask registers.
+    fn execution_priority(&self, cpu: &mut Cpu) -> i16 {
+        let nvic = self.nvic.borrow();
+        let mut priority =
+            self.active.iter().map(|x| nvic.priority(x.number)).min().unwrap_or(BASE_PRIORITY);
+        if let Some(basepri) = self.regs.basepri {
+            let basepri = cpu.read_reg(basepri) as u8;
+            if basepri != 0 {
+                priority = priority.min(basepri as i16);
+            }
+        }
+        if let Some(primask) = self.regs.primask {
+            if cpu.read_reg(primask) & 1 != 0 {
+                priority = priority.min(0);
+            }
+        }
+        priority
+    }
+
+    /// Updates the state of timers, then enters the highest priority pending exception if it can
+    /// preempt the currently executing code. Returns whether an exception was entered.
+    fn check_exceptions(&mut self, cpu: &mut Cpu) -> bool {
+        let now = cpu.clock();
+        {
+            let mut nvic = self.nvic.borrow_mut();
+            nvic.now = now;
+
+            if let Some(deadline) = nvic.systick.deadline().filter(|x| *x <= now) {
+                tracing::trace!("[{now}] SysTick expired (deadline={deadline})");
+                let systick = &mut nvic.systick;
+                systick.ctrl |= SYST_COUNTFLAG;
+                systick.base_value = 0;
+                // Avoid triggering the timer repeatedly if we are running behind.
+                systick.base_icount = match systick.deadline() {
+                    Some(next) if next <= now => now,
+                    _ => deadline,
+                };
+                if systick.ctrl & SYST_TICKINT != 0 {
+                    nvic.pending[SYSTICK as usize] = true;
+                }
+            }
+
+            let due = self.scheduled.partition_point(|(clock, _)| *clock <= now);
+            for (_, exception) in self.scheduled.drain(..due) {
+                nvic.set_pending(exception);
+            }
+        }
+
+        let Some(exception) = self.nvic.borrow().highest_pending()
+        else {
+            return false;
+        };
+        if self.nvic.borrow().priority(exception) >= self.execution_priority(cpu) {
+            return false;
+        }
+
+        if let Err(e) = self.enter(cpu, exception) {
+            tracing::debug!("[{now}] failed to enter exception {exception}: {e:?}");
+            let addr = cpu.read_reg(self.regs.sp);
+            cpu.exception = Exception::new(ExceptionCode::from_store_error(e), addr);
+        }
+        true
+    }
+
+    fn enter(&mut self, cpu: &mut Cpu, exception: u16) -> MemResult<()> {
+        let vtor = self.nvic.borrow().vtor as u64;
+        let handler = cpu.mem.read_u32(vtor + exception as u64 * 4, perm::READ)?;
+
+        // If the environment is already redirecting execution (e.g. when tail-chaining), then the
+        // exception returns to the new address.
+        let (return_addr, return_block) =
+            match ExceptionCode::from_u32(cpu.exception.code) == ExceptionCode::ExternalAddr {
+                true => (cpu.exception.value, no_block()),
+                false => (cpu.read_pc(), (cpu.block_id, cpu.block_offset)),
+            };
+        tracing::trace!(
+            "[{}] enter exception {exception} (handler={handler:#x}, return={return_addr:#x})",
+            cpu.icount
+        );
+
+        let mut xpsr = self.read_xpsr(cpu);
+        let sp = cpu.read_reg(self.regs.sp) as u32;
+        if sp & 0x4 != 0 {
+            // The stack is realigned to 8 bytes, which is recorded in the stacked xPSR.
+            xpsr |= 1 << 9;
+        }
+        let frame_ptr = sp.wrapping_sub(0x20) & !0x7;
+
+        let mut frame = [0; 8];
+        for (value, reg) in frame.iter_mut().zip(self.regs.saved) {
+            *value = cpu.read_reg(reg) as u32;
+        }
+        frame[6] = return_addr as u32;
+        frame[7] = xpsr;
+        for (i, value) in frame.iter().enumerate() {
+            cpu.mem.write_u32(frame_ptr as u64 + i as u64 * 4, *value, perm::WRITE)?;
+        }
+
+        cpu.write_reg(self.regs.sp, frame_ptr as u64);
+        let exc_return = match self.active.is_empty() {
+            true => EXC_RETURN_THREAD,
+            false => EXC_RETURN_HANDLER,
+        };
+        cpu.write_reg(self.regs.lr, exc_return as u64);
+
+        {
+            let mut nvic = self.nvic.borrow_mut();
+            nvic.pending[exception as usize] = false;
+            nvic.active[exception as usize] = true;
+        }
+        self.active.push(ActiveException { number: exception, return_addr, return_block });
+        self.update_current(cpu);
+
+        cpu.exception = Exception::new(ExceptionCode::ExternalAddr, handler as u64 & !1);
+        Ok(())
+    }
+
+    fn exception_return(&mut self, cpu: &mut Cpu) -> MemResult<()> {
+        let Some(entry) = self.active.pop()
+        else {
+            return Err(MemError::Unmapped);
+        };
+        tracing::trace!("[{}] return from exception {}", cpu.icount, entry.number);
+
+        let sp = cpu.read_reg(self.regs.sp);
+        let mut frame = [0; 8];
+        for (i, value) in frame.iter_mut().enumerate() {
+            *value = cpu.mem.read_u32(sp + i as u64 * 4, perm::READ)?;
+        }
+
+        for (value, reg) in frame.iter().zip(self.regs.saved) {
+            cpu.write_reg(reg, *value as u64);
+        }
+        let xpsr = frame[7];
+        self.write_flags(cpu, xpsr);
+        let padding = if xpsr & (1 << 9) != 0 { 4 } else { 0 };
+        cpu.write_reg(self.regs.sp, sp + 0x20 + padding);
+
+        self.nvic.borrow_mut().active[entry.number as usize] = false;
+        self.update_current(cpu);
+
+        let return_addr = frame[6] as u64 & !1;
+        cpu.write_pc(return_addr);
+        if return_addr == entry.return_addr && entry.return_block.0 != u64::MAX {
+            cpu.block_id = entry.return_block.0;
+            cpu.block_offset = entry.return_block.1;
+            cpu.exception.clear();
+        }
+        else {
+            cpu.exception = Exception::new(ExceptionCode::ExternalAddr, return_addr);
+        }
+        Ok(())
+    }
+
+    /// Handles `wfi`/`wfe` by skipping ahead to the next time an exception could become pending.
+    /// Returns `false` if there is nothing that could wake the processor.
+    fn wait_for_interrupt(&mut self, cpu: &mut Cpu) -> bool {
+        if self.nvic.borrow().highest_pending().is_none() {
+            let systick = self.nvic.borrow().systick.deadline();
+            let scheduled = self.scheduled.first().map(|(clock, _)| *clock);
+            let Some(wakeup) = systick.into_iter().chain(scheduled).min()
+            else {
+                return false;
+            };
+            tracing::trace!("[{}] sleeping until {wakeup}", cpu.icount);
+            cpu.icount = cpu.clock_to_icount(wakeup);
+        }
+
+        cpu.resume_next();
+        cpu.exception = Exception::new(ExceptionCode::ExternalAddr, cpu.read_pc());
+        true
+    }
+
+    fn update_next_timer(&mut self, cpu: &Cpu) {
+        let systick = self.nvic.borrow().systick.deadline().unwrap_or(u64::MAX);
+        let scheduled = self.scheduled.first().map_or(u64::MAX, |(clock, _)| *clock);
+        let poll = cpu.icount.saturating_add(self.config.poll_interval);
+        self.next_timer = cpu.clock_to_icount(systick.min(scheduled)).min(poll);
+    }
+
+    fn state(&self) -> State {
+        State {
+            nvic: self.nvic.borrow().clone(),
+            active: self.active.clone(),
+            scheduled: self.scheduled.clone(),
+            next_timer: self.next_timer,
+        }
+    }
+
+    fn set_state(&mut self, state: State) {
+        *self.nvic.borrow_mut() = state.nvic;
+        self.active = state.active;
+        self.scheduled = state.scheduled;
+        self.next_timer = state.next_timer;
+    }
+}
+
+fn is_exc_return(addr: u64) -> bool {
+    addr as u32 & 0xffff_ffe0 == 0xffff_ffe0
+}
+
+impl Environment for CortexM {
+    fn load(&mut self, cpu: &mut Cpu, path: &[u8]) -> Result<(), String> {
+        self.inner.load(cpu, path)?;
+        self.reset(cpu);
+        Ok(())
+    }
+
+    fn next_timer(&self) -> u64 {
+        self.next_timer
+    }
+
+    /// Marks exception number `interrupt` as pending (external interrupt `n` is exception
+    /// `16 + n`), see [CortexM::set_pending].
+    fn raise_interrupt(&mut self, _: &mut Cpu, interrupt: u64) -> bool {
+        u16::try_from(interrupt).is_ok_and(|exception| self.set_pending(exception))
+    }
+
+    fn handle_exception(&mut self, cpu: &mut Cpu) -> Option<VmExit> {
+        let exit = match ExceptionCode::from_u32(cpu.exception.code) {
+            ExceptionCode::InstructionLimit => {
+                self.check_exceptions(cpu);
+                None
+            }
+
+            // `wfi`/`wfe`, if nothing can wake the processor then the VM halts.
+            ExceptionCode::Sleep => {
+                if self.wait_for_interrupt(cpu) {
+                    self.check_exceptions(cpu);
+                }
+                None
+            }
+
+            // Return from exception
+            ExceptionCode::ExecViolation
+            | ExceptionCode::ShadowStackInvalid
+            | ExceptionCode::InvalidTarget
+            | ExceptionCode::InvalidInstruction
+                if is_exc_return(cpu.exception.value) && !self.active.is_empty() =>
+            {
+                match self.exception_return(cpu) {
+                    Ok(()) => {
+                        // Tail-chain any pending exceptions.
+                        self.check_exceptions(cpu);
+                    }
+                    Err(e) => {
+                        let addr = cpu.read_reg(self.regs.sp);
+                        cpu.exception = Exception::new(ExceptionCode::from_load_error(e), addr);
+                    }
+                }
+                None
+            }
+
+            _ => self.inner.handle_exception(cpu),
+        };
+
+        self.update_next_timer(cpu);
+        exit
+    }
+
+    fn debug_info(&self) -> Option<&DebugInfo> {
+        self.inner.debug_info()
+    }
+
+    fn snapshot(&mut self) -> Box<dyn Any> {
+        Box::new(self.state())
+    }
+
+    fn restore(&mut self, snapshot: &Box<dyn Any>) {
+        let state = snapshot.downcast_ref::<State>().unwrap();
+        self.set_state(state.clone());
+    }
+
+    fn save_state(&mut self) -> Result<Vec<u8>, String> {
+        ron::to_string(&self.state())
+            .map(String::into_bytes)
+            .map_err(|e| format!("failed to serialize Cortex-M state: {e}"))
+    }
+
+    fn load_state(&mut self, data: &[u8]) -> Result<(), String> {
+        let state: State =
+            ron::de::from_bytes(data).map_err(|e| format!("invalid Cortex-M state: {e}"))?;
+        self.set_state(state);
+        Ok(())
+    }
+}
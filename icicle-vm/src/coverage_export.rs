@@ -0,0 +1,279 @@
+//! Export of coverage and call traces in formats used by common reverse engineering tools.
+//!
+//! Supported formats:
+//!
+//! - [CoverageFormat::Drcov]: see [crate::drcov] (Lighthouse, Lightkeeper, Binary Ninja's bncov).
+//! - [CoverageFormat::ModuleOffset]: one `module+offset` line per block (Lighthouse, bncov).
+//! - [CoverageFormat::Addresses]: one absolute address per line (bncov, Ghidra scripts).
+//! - [CoverageFormat::Ezcov]: the EZCOV format used by Ghidra's Cartographer plugin.
+//! - [CoverageFormat::Json]: a generic schema (see [CoverageReport]) for custom tooling.
+//!
+//! Call traces are derived from a trace of executed blocks (e.g. from a
+//! [crate::injector::PathTracerRef]) and are included in the JSON output, or can be written
+//! separately with [CoverageExporter::write_call_trace].
+
+use std::{collections::HashMap, io::Write, path::Path};
+
+use anyhow::Context;
+use icicle_cpu::lifter::BlockExit;
+
+use crate::{
+    Vm,
+    drcov::{DrcovWriter, covered_blocks},
+};
+
+/// The version of the JSON schema written by [CoverageFormat::Json].
+pub const JSON_SCHEMA_VERSION: u32 = 1;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CoverageFormat {
+    Drcov,
+    ModuleOffset,
+    Addresses,
+    Ezcov,
+    Json,
+}
+
+impl CoverageFormat {
+    /// The file extension typically used for the format.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Drcov => "drcov",
+            Self::ModuleOffset | Self::Addresses => "txt",
+            Self::Ezcov => "ezcov",
+            Self::Json => "json",
+        }
+    }
+}
+
+impl std::str::FromStr for CoverageFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.to_ascii_lowercase().as_str() {
+            "drcov" => Self::Drcov,
+            "modoff" | "module+offset" | "lighthouse" => Self::ModuleOffset,
+            "addr" | "addresses" | "bncov" => Self::Addresses,
+            "ezcov" | "ghidra" | "cartographer" => Self::Ezcov,
+            "json" => Self::Json,
+            _ => return Err(anyhow::format_err!("Unknown coverage format: {s}")),
+        })
+    }
+}
+
+/// A call executed by the guest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CallEvent {
+    /// The instruction count at the start of the callee.
+    pub icount: u64,
+
+    /// The address of the call instruction.
+    pub site: u64,
+
+    /// The address of the callee.
+    pub target: u64,
+}
+
+/// Finds the calls in a trace of executed blocks (a list of `(block address, icount)` entries).
+/// Blocks that have not been lifted by `vm` are ignored.
+pub fn call_trace(vm: &Vm, entries: impl IntoIterator<Item = (u64, u64)>) -> Vec<CallEvent> {
+    let mut call_sites = HashMap::new();
+    for block in &vm.code.blocks {
+        if let BlockExit::Call { .. } = block.exit {
+            if let Some((site, _)) = block.instructions().last() {
+                call_sites.insert(block.start, site);
+            }
+        }
+    }
+
+    let mut calls = vec![];
+    let mut prev_site = None;
+    for (addr, icount) in entries {
+        if let Some(site) = prev_site {
+            calls.push(CallEvent { icount, site, target: addr });
+        }
+        prev_site = call_sites.get(&addr).copied();
+    }
+    calls
+}
+
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct ModuleEntry {
+    pub id: usize,
+    pub name: String,
+    pub base: u64,
+    pub end: u64,
+}
+
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct BlockEntry {
+    pub addr: u64,
+    pub size: u64,
+    /// The ID of the module containing the block, or `None` if it is not part of a known module.
+    pub module: Option<usize>,
+    /// The offset of the block from the base of the module.
+    pub offset: Option<u64>,
+}
+
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct CallEntry {
+    pub icount: u64,
+    pub site: u64,
+    pub target: u64,
+    /// `site` formatted relative to the module containing it.
+    pub site_label: String,
+    /// `target` formatted relative to the module containing it.
+    pub target_label: String,
+}
+
+/// The coverage and call trace of an execution, serialized by [CoverageFormat::Json].
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct CoverageReport {
+    pub version: u32,
+    pub modules: Vec<ModuleEntry>,
+    pub blocks: Vec<BlockEntry>,
+    pub calls: Vec<CallEntry>,
+}
+
+/// Writes the coverage of the blocks translated by a VM (and optionally a call trace) in any of
+/// the supported [CoverageFormat]s.
+#[derive(Default)]
+pub struct CoverageExporter {
+    modules: DrcovWriter,
+    calls: Vec<CallEvent>,
+}
+
+impl CoverageExporter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates an exporter with the modules loaded by the environment of `vm`.
+    pub fn from_env(vm: &Vm) -> Self {
+        Self { modules: DrcovWriter::from_env(vm), calls: vec![] }
+    }
+
+    /// Adds a module named `name` covering `base..end` (see [DrcovWriter::add_module]).
+    pub fn add_module(&mut self, name: &str, base: u64, end: u64) {
+        self.modules.add_module(name, base, end);
+    }
+
+    /// Sets the calls included in the exported call trace (see [call_trace]).
+    pub fn set_calls(&mut self, calls: Vec<CallEvent>) {
+        self.calls = calls;
+    }
+
+    /// Formats `addr` as `module+offset`, or as the absolute address if it is not part of any
+    /// known module.
+    fn label(&self, addr: u64) -> String {
+        match self.modules.find_module(addr) {
+            Some(id) => {
+                let (name, base, _) = self.modules.modules().nth(id).unwrap();
+                let name = name.rsplit('/').next().unwrap_or(name);
+                format!("{name}+{:#x}", addr - base)
+            }
+            None => format!("{addr:#x}"),
+        }
+    }
+
+    /// Builds the report containing all translated blocks in `vm`, and the current call trace.
+    pub fn report(&self, vm: &Vm) -> CoverageReport {
+        let modules: Vec<_> = self
+            .modules
+            .modules()
+            .enumerate()
+            .map(|(id, (name, base, end))| ModuleEntry { id, name: name.to_owned(), base, end })
+            .collect();
+
+        let blocks = covered_blocks(vm)
+            .into_iter()
+            .map(|(addr, size)| {
+                let module = self.modules.find_module(addr);
+                let offset = module.map(|id| addr - modules[id].base);
+                BlockEntry { addr, size, module, offset }
+            })
+            .collect();
+
+        let calls = self
+            .calls
+            .iter()
+            .map(|call| CallEntry {
+                icount: call.icount,
+                site: call.site,
+                target: call.target,
+                site_label: self.label(call.site),
+                target_label: self.label(call.target),
+            })
+            .collect();
+
+        CoverageReport { version: JSON_SCHEMA_VERSION, modules, blocks, calls }
+    }
+
+    /// Writes the coverage of all translated blocks in `vm` to `out` using `format`. Returns the
+    /// number of blocks written.
+    pub fn write(
+        &self,
+        vm: &Vm,
+        format: CoverageFormat,
+        mut out: impl Write,
+    ) -> anyhow::Result<usize> {
+        if format == CoverageFormat::Drcov {
+            return Ok(self.modules.write(vm, out)?);
+        }
+
+        let report = self.report(vm);
+        let module_name = |block: &BlockEntry| {
+            let name = &report.modules[block.module?].name;
+            Some(name.rsplit('/').next().unwrap_or(name))
+        };
+
+        match format {
+            CoverageFormat::Drcov => unreachable!(),
+            CoverageFormat::ModuleOffset => {
+                // Blocks outside of known modules cannot be represented in this format.
+                for block in &report.blocks {
+                    if let (Some(name), Some(offset)) = (module_name(block), block.offset) {
+                        writeln!(out, "{name}+{offset:#x}")?;
+                    }
+                }
+            }
+            CoverageFormat::Addresses => {
+                for block in &report.blocks {
+                    writeln!(out, "{:#x}", block.addr)?;
+                }
+            }
+            CoverageFormat::Ezcov => {
+                writeln!(out, "EZCOV VERSION: 1")?;
+                for block in &report.blocks {
+                    match (module_name(block), block.offset) {
+                        (Some(name), Some(offset)) => {
+                            writeln!(out, "{offset:#010x}, {}, [ {name} ]", block.size)?
+                        }
+                        _ => writeln!(out, "{:#010x}, {}, [ ]", block.addr, block.size)?,
+                    }
+                }
+            }
+            CoverageFormat::Json => serde_json::to_writer_pretty(&mut out, &report)?,
+        }
+        out.flush()?;
+
+        Ok(report.blocks.len())
+    }
+
+    /// Writes the current call trace to `out`, one call per line.
+    pub fn write_call_trace(&self, mut out: impl Write) -> std::io::Result<()> {
+        for call in &self.calls {
+            let (site, target) = (self.label(call.site), self.label(call.target));
+            writeln!(out, "{}: {site} -> {target}", call.icount)?;
+        }
+        out.flush()
+    }
+
+    /// Writes the coverage of all translated blocks in `vm` to a file at `path` using `format`.
+    pub fn save(&self, vm: &Vm, format: CoverageFormat, path: &Path) -> anyhow::Result<usize> {
+        let file = std::fs::File::create(path)
+            .with_context(|| format!("failed to create: {}", path.display()))?;
+        self.write(vm, format, std::io::BufWriter::new(file))
+            .with_context(|| format!("failed to write: {}", path.display()))
+    }
+}
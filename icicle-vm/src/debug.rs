@@ -1,9 +1,12 @@
 use std::collections::HashSet;
 
-use icicle_cpu::{debug_info::SourceLocation, utils::get_u64};
+use icicle_cpu::{
+    debug_info::{Frame, ScopedVariable, SourceLocation, Variable},
+    utils::get_u64,
+};
 use pcode::PcodeDisplay;
 
-use crate::{lifter::BlockGroup, ValueSource, Vm};
+use crate::{lifter::{self, BlockGroup}, ValueSource, Vm};
 
 pub fn dump_disasm(vm: &Vm) -> Result<String, std::fmt::Error> {
     let sorted_groups = {
@@ -72,6 +75,58 @@ pub fn dump_semantics(vm: &Vm) -> Result<String, std::fmt::Error> {
     Ok(out)
 }
 
+/// Exports the control flow graph of all translated code in graphviz (DOT) format, with a node for
+/// each block group and an edge for every statically known successor (including the targets of
+/// recovered jump tables).
+pub fn dump_cfg(vm: &Vm) -> Result<String, std::fmt::Error> {
+    use std::fmt::Write;
+
+    let sorted_groups = {
+        let mut groups: Vec<_> = vm.code.map.values().copied().collect();
+        groups.sort_by_key(|x| x.start);
+        groups
+    };
+
+    let mut out = String::new();
+    writeln!(out, "digraph cfg {{")?;
+    writeln!(out, "\tnode [shape=box];")?;
+    for group in sorted_groups {
+        let start = group.start;
+        writeln!(out, "\t\"{start:#x}\";")?;
+
+        let mut edges = vec![];
+        for block in &vm.code.blocks[group.range()] {
+            let style = match block.exit {
+                lifter::BlockExit::Call { target, fallthrough } => {
+                    if let pcode::Value::Const(addr, _) = target {
+                        edges.push((addr, "call"));
+                    }
+                    edges.push((fallthrough, "return"));
+                    continue;
+                }
+                _ => "jump",
+            };
+            for target in block.exit.targets() {
+                if let lifter::Target::External(pcode::Value::Const(addr, _)) = target {
+                    edges.push((addr, style));
+                }
+            }
+        }
+        for (_, addr) in vm.code.group_jump_table_targets(&group) {
+            edges.push((*addr, "switch"));
+        }
+
+        edges.sort_unstable();
+        edges.dedup();
+        for (addr, kind) in edges {
+            writeln!(out, "\t\"{start:#x}\" -> \"{addr:#x}\" [label=\"{kind}\"];")?;
+        }
+    }
+    writeln!(out, "}}")?;
+
+    Ok(out)
+}
+
 pub fn debug_addr(vm: &mut Vm, addr: u64) -> Result<String, std::fmt::Error> {
     let key = vm.get_block_key(addr);
     match vm.code.map.get(&key) {
@@ -123,6 +178,15 @@ pub fn backtrace(vm: &mut Vm) -> String {
 }
 
 pub fn backtrace_with_limit(vm: &mut Vm, max_frames: usize) -> String {
+    backtrace_with(vm, max_frames, |vm, addr| vm.env.symbolize_addr(&mut vm.cpu, addr))
+}
+
+/// Like [backtrace_with_limit], but using `symbolize` to find the location of each frame.
+pub fn backtrace_with(
+    vm: &mut Vm,
+    max_frames: usize,
+    mut symbolize: impl FnMut(&mut Vm, u64) -> Option<SourceLocation>,
+) -> String {
     use std::fmt::Write;
 
     let mut buf = String::new();
@@ -133,8 +197,7 @@ pub fn backtrace_with_limit(vm: &mut Vm, max_frames: usize) -> String {
         // For all return address subtract 1 so we get the address of the call not the return for
         // the symbol.
         let symbol_addr = if i == 0 { addr } else { addr - 1 };
-        let location =
-            vm.env.symbolize_addr(&mut vm.cpu, symbol_addr).unwrap_or(SourceLocation::default());
+        let location = symbolize(vm, symbol_addr).unwrap_or_default();
         writeln!(buf, "{addr:#012x}: {location}").unwrap();
     }
 
@@ -144,6 +207,57 @@ pub fn backtrace_with_limit(vm: &mut Vm, max_frames: usize) -> String {
     buf
 }
 
+/// A frame of the guest's call stack computed by [Vm::backtrace].
+#[derive(Clone, Debug)]
+pub struct BacktraceFrame {
+    /// The register state of the frame.
+    pub frame: Frame,
+
+    /// The symbolized location of the frame (the call site for outer frames).
+    pub location: Option<SourceLocation>,
+}
+
+impl std::fmt::Display for BacktraceFrame {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.location.as_ref() {
+            Some(location) => write!(f, "{:#012x}: {location}", self.frame.pc),
+            None => write!(f, "{:#012x}: <unknown>", self.frame.pc),
+        }
+    }
+}
+
+/// Computes the call stack of the guest by unwinding the current register state using call frame
+/// information (see [icicle_cpu::debug_info::DebugInfo::backtrace]).
+pub fn unwind_backtrace(vm: &mut Vm, max_frames: usize) -> Vec<BacktraceFrame> {
+    let frames = match vm.env.debug_info() {
+        Some(debug_info) => debug_info.backtrace(&mut vm.cpu, max_frames),
+        None => vec![Frame::current(&mut vm.cpu)],
+    };
+    frames
+        .into_iter()
+        .map(|frame| {
+            let location = vm.env.symbolize_addr(&mut vm.cpu, frame.lookup_pc());
+            BacktraceFrame { frame, location }
+        })
+        .collect()
+}
+
+/// Resolves the source-level variable accessed by `path` (e.g. `state->buf[0]`) in the current
+/// frame, see [icicle_cpu::debug_info::DebugInfo::read_variable].
+pub fn read_variable(vm: &mut Vm, path: &str) -> Result<Variable, String> {
+    let debug_info = vm.env.debug_info().ok_or("no debug info available")?;
+    let frame = Frame::current(&mut vm.cpu);
+    debug_info.read_variable(&mut vm.cpu, &frame, path)
+}
+
+/// Finds the parameters and local variables in scope in the current frame, see
+/// [icicle_cpu::debug_info::DebugInfo::variables].
+pub fn local_variables(vm: &mut Vm) -> Result<Vec<ScopedVariable>, String> {
+    let debug_info = vm.env.debug_info().ok_or("no debug info available")?;
+    let frame = Frame::current(&mut vm.cpu);
+    debug_info.variables(&mut vm.cpu, &frame)
+}
+
 pub fn callstack_from_debug_info(vm: &mut Vm) -> Option<Vec<u64>> {
     let debug_info = vm.env.debug_info()?;
     // @todo: use proper dwarf based unwinding.
@@ -335,7 +449,7 @@ pub fn get_debug_regs(cpu: &crate::Cpu) -> Vec<pcode::VarNode> {
             "t7", "s0", "s1", "s2", "s3", "s4", "s5", "s6", "s7", "t8", "t9", "k0", "k1", "gp",
             "sp", "s8", "ra", "pc",
         ][..],
-        Architecture::Riscv64(_) => &[
+        Architecture::Riscv32(_) | Architecture::Riscv64(_) => &[
             "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4",
             "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3",
             "t4", "t5", "t6",
@@ -0,0 +1,158 @@
+//! Notifications for newly discovered code, and lifting code ahead of execution.
+//!
+//! Hooks added with [Vm::add_new_code_hook] are called whenever execution enters code that does
+//! not have an active translation. If [Vm::enable_prelift] is set, the direct jump and call targets
+//! of newly discovered code (including recovered jump table targets) are queued, and can be lifted
+//! ahead of time by calling [Vm::prelift] while the VM is otherwise idle (e.g. between fuzzing
+//! executions), hiding the cost of lifting from the executions that first reach the code.
+//!
+//! Pre-lifted code is kept inactive until execution reaches it, at which point it is activated
+//! without being lifted again (and reported to the new code hooks as usual).
+
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use icicle_cpu::{BlockKey, BlockTable, Cpu, DecodeError, lifter::BlockGroup};
+
+use crate::Vm;
+
+pub type NewCodeHook = Box<dyn FnMut(&mut Cpu, &BlockGroup, &BlockTable)>;
+
+#[derive(Default)]
+pub struct CodeDiscovery {
+    hooks: Vec<NewCodeHook>,
+
+    /// Addresses that are likely to be executed soon.
+    queue: VecDeque<BlockKey>,
+    queued: HashSet<BlockKey>,
+
+    /// Code that has been lifted ahead of execution, but is not yet active.
+    pending: HashMap<BlockKey, BlockGroup>,
+
+    /// The generation of the code cache that the pending code was lifted into.
+    generation: u64,
+
+    /// The number of block groups lifted by [Vm::prelift].
+    pub prelifted: u64,
+
+    /// The number of pre-lifted block groups that were later reached by execution.
+    pub prelift_hits: u64,
+}
+
+impl CodeDiscovery {
+    /// Drops any pending code if the code cache has been flushed.
+    fn sync(&mut self, generation: u64) {
+        if self.generation != generation {
+            self.queue.clear();
+            self.queued.clear();
+            self.pending.clear();
+            self.generation = generation;
+        }
+    }
+
+    /// Removes any pending code that `keep` returns false for.
+    pub(crate) fn retain_pending(&mut self, mut keep: impl FnMut(&BlockGroup) -> bool) {
+        self.pending.retain(|_, group| keep(group));
+    }
+
+    /// Returns the number of block groups waiting to be pre-lifted.
+    pub fn queued(&self) -> usize {
+        self.queue.len()
+    }
+}
+
+impl Vm {
+    /// Registers a hook that is called whenever execution enters code that has not been executed
+    /// before (or was invalidated since it was last executed).
+    pub fn add_new_code_hook(
+        &mut self,
+        hook: impl FnMut(&mut Cpu, &BlockGroup, &BlockTable) + 'static,
+    ) {
+        self.discovery.hooks.push(Box::new(hook));
+    }
+
+    /// Lifts up to `max_groups` block groups from the queue of likely targets, returning the number
+    /// of groups that were lifted.
+    pub fn prelift(&mut self, max_groups: usize) -> usize {
+        self.discovery.sync(self.code.generation);
+
+        let isa_mode = self.cpu.isa_mode() as u64;
+        let mut count = 0;
+        while count < max_groups {
+            let Some(key) = self.discovery.queue.pop_front()
+            else {
+                break;
+            };
+            self.discovery.queued.remove(&key);
+
+            // The lifter uses the context of the current ISA mode.
+            if key.isa_mode != isa_mode {
+                continue;
+            }
+
+            match self.prelift_key(key) {
+                Ok(true) => count += 1,
+                Ok(false) => {}
+                Err(e) => tracing::trace!("prelift failed at {:#x}: {e:?}", key.vaddr),
+            }
+        }
+        count
+    }
+
+    /// Lifts the code at `addr` (in the current ISA mode) ahead of execution. Returns `false` if
+    /// the code is already active or pending.
+    pub(crate) fn prelift_addr(&mut self, addr: u64) -> Result<bool, DecodeError> {
+        self.discovery.sync(self.code.generation);
+        self.prelift_key(self.get_block_key(addr))
+    }
+
+    fn prelift_key(&mut self, key: BlockKey) -> Result<bool, DecodeError> {
+        if self.code.map.contains_key(&key) || self.discovery.pending.contains_key(&key) {
+            return Ok(false);
+        }
+        let group = self.lift_unmapped(key.vaddr)?;
+        self.discovery.pending.insert(key, group);
+        self.discovery.prelifted += 1;
+        Ok(true)
+    }
+
+    /// Activates the code at `key` if it was lifted ahead of execution.
+    pub(crate) fn activate_prelifted(&mut self, key: BlockKey) -> Option<BlockGroup> {
+        self.discovery.sync(self.code.generation);
+        let group = self.discovery.pending.remove(&key)?;
+        self.code.insert(key, group);
+        self.discovery.prelift_hits += 1;
+        Some(group)
+    }
+
+    /// Notifies hooks that execution has reached `group` for the first time, and queues the targets
+    /// of the code for pre-lifting.
+    pub(crate) fn code_discovered(&mut self, group: BlockGroup) {
+        for hook in &mut self.discovery.hooks {
+            hook(&mut self.cpu, &group, &self.code);
+        }
+
+        if !self.enable_prelift {
+            return;
+        }
+        self.discovery.sync(self.code.generation);
+
+        let isa_mode = self.cpu.isa_mode() as u64;
+        let direct = self.code.blocks[group.range()].iter().flat_map(|block| {
+            block.exit.targets().filter_map(|target| match target {
+                icicle_cpu::lifter::Target::External(pcode::Value::Const(addr, _)) => Some(addr),
+                _ => None,
+            })
+        });
+        let jump_tables = self.code.group_jump_table_targets(&group).iter().map(|(_, addr)| *addr);
+        for addr in direct.chain(jump_tables) {
+            let key = BlockKey { vaddr: addr, isa_mode };
+            if self.code.map.contains_key(&key)
+                || self.discovery.pending.contains_key(&key)
+                || !self.discovery.queued.insert(key)
+            {
+                continue;
+            }
+            self.discovery.queue.push_back(key);
+        }
+    }
+}
@@ -0,0 +1,130 @@
+//! Export of block coverage in the DRCov format (used by Lighthouse, Lightkeeper, and other
+//! coverage visualization tools).
+//!
+//! Coverage is derived from the blocks that have been translated by the VM, so it includes every
+//! block that execution has reached (and can include blocks that were lifted but did not finish
+//! executing, e.g. due to a fault). Each block is attributed to the module it was loaded from,
+//! with offsets relative to the base address of the module.
+
+use std::{collections::BTreeMap, io::Write, path::Path};
+
+use anyhow::Context;
+
+use crate::Vm;
+
+struct Module {
+    name: String,
+    base: u64,
+    end: u64,
+}
+
+/// Collects the modules of the target and writes coverage files.
+#[derive(Default)]
+pub struct DrcovWriter {
+    modules: Vec<Module>,
+}
+
+impl DrcovWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a writer with the modules loaded by the environment of `vm`.
+    pub fn from_env(vm: &Vm) -> Self {
+        let mut writer = Self::new();
+        writer.add_env_modules(vm);
+        writer
+    }
+
+    /// Adds a module named `name` covering `base..end`. If a module with the same name already
+    /// exists, the existing module is extended to include the range.
+    pub fn add_module(&mut self, name: &str, base: u64, end: u64) {
+        match self.modules.iter_mut().find(|x| x.name == name) {
+            Some(module) => {
+                module.base = module.base.min(base);
+                module.end = module.end.max(end);
+            }
+            None => self.modules.push(Module { name: name.to_owned(), base, end }),
+        }
+    }
+
+    /// Adds all modules that are known to the environment. Currently only the files mapped by the
+    /// active Linux process are supported, for other environments modules must be added manually
+    /// with [DrcovWriter::add_module].
+    pub fn add_env_modules(&mut self, vm: &Vm) {
+        if let Some(kernel) = vm.env_ref::<crate::linux::Kernel>() {
+            for (start, entry) in &kernel.process.mapping {
+                self.add_module(&String::from_utf8_lossy(&entry.path), *start, entry.end);
+            }
+        }
+    }
+
+    pub(crate) fn find_module(&self, addr: u64) -> Option<usize> {
+        self.modules.iter().position(|x| x.base <= addr && addr < x.end)
+    }
+
+    /// Gets the name and address range of each module, indexed by module ID.
+    pub(crate) fn modules(&self) -> impl Iterator<Item = (&str, u64, u64)> {
+        self.modules.iter().map(|x| (x.name.as_str(), x.base, x.end))
+    }
+
+    /// Gets the coverage entries for all translated blocks: (module ID, offset, size).
+    fn entries(&self, vm: &Vm) -> Vec<(u16, u32, u16)> {
+        covered_blocks(vm)
+            .into_iter()
+            .filter_map(|(start, size)| {
+                let id = self.find_module(start)?;
+                let offset = start - self.modules[id].base;
+                Some((id as u16, offset.try_into().ok()?, size.min(u16::MAX as u64) as u16))
+            })
+            .collect()
+    }
+
+    /// Writes the coverage of all translated blocks in `vm` to `out`. Blocks that are not part of
+    /// any known module are skipped. Returns the number of blocks written.
+    pub fn write(&self, vm: &Vm, mut out: impl Write) -> std::io::Result<usize> {
+        let entries = self.entries(vm);
+
+        writeln!(out, "DRCOV VERSION: 2")?;
+        writeln!(out, "DRCOV FLAVOR: icicle")?;
+        writeln!(out, "Module Table: version 2, count {}", self.modules.len())?;
+        writeln!(out, "Columns: id, base, end, entry, checksum, timestamp, path")?;
+        for (id, module) in self.modules.iter().enumerate() {
+            writeln!(
+                out,
+                "{id:3}, {:#018x}, {:#018x}, {:#018x}, {:#010x}, {:#010x}, {}",
+                module.base, module.end, 0, 0, 0, module.name
+            )?;
+        }
+
+        writeln!(out, "BB Table: {} bbs", entries.len())?;
+        for (id, offset, size) in &entries {
+            out.write_all(&offset.to_le_bytes())?;
+            out.write_all(&size.to_le_bytes())?;
+            out.write_all(&id.to_le_bytes())?;
+        }
+        out.flush()?;
+
+        Ok(entries.len())
+    }
+
+    /// Writes the coverage of all translated blocks in `vm` to a file at `path`.
+    pub fn save(&self, vm: &Vm, path: &Path) -> anyhow::Result<usize> {
+        let file = std::fs::File::create(path)
+            .with_context(|| format!("failed to create: {}", path.display()))?;
+        self.write(vm, std::io::BufWriter::new(file))
+            .with_context(|| format!("failed to write: {}", path.display()))
+    }
+}
+
+/// Gets the start address and size of every translated block in `vm`.
+pub(crate) fn covered_blocks(vm: &Vm) -> BTreeMap<u64, u64> {
+    let mut blocks = BTreeMap::new();
+    for block in &vm.code.blocks {
+        if block.end > block.start {
+            let size = blocks.entry(block.start).or_insert(0);
+            *size = (*size).max(block.end - block.start);
+        }
+    }
+    blocks
+}
@@ -77,7 +77,7 @@ pub fn dump_elf(vm: &mut Vm, path: impl AsRef<std::path::Path>) -> anyhow::Resul
                     file_offset: 0,
                 });
             }
-            MemoryMapping::Io(_) => continue,
+            MemoryMapping::Io(_) | MemoryMapping::PhysicalIo(_) => continue,
         };
     }
 
@@ -1,19 +1,66 @@
 use std::{any::Any, path::PathBuf};
 
 use icicle_cpu::{
-    debug_info::DebugInfo, elf::ElfLoader, pe::PeLoader, Cpu, Environment, EnvironmentAny, VmExit,
+    debug_info::DebugInfo,
+    elf::ElfLoader,
+    pe::{ImportStubs, PeLoader, UnimplementedApiCall},
+    mem::IoHandler,
+    Cpu, Environment, EnvironmentAny, Exception, ExceptionCode, VmExit,
 };
+use icicle_linux::fs::devices::WriteOnlyDevice;
 use object::read::FileKind;
 
-use crate::{msp430::Msp430, BuildError, Vm};
+use crate::{
+    cortex_m::CortexM,
+    guest_log::{GuestLog, LogOrigin},
+    msp430::Msp430,
+    BuildError, Vm,
+};
 
-struct GenericEmbedded {
+pub struct GenericEmbedded {
     debug_info: DebugInfo,
+    import_stubs: ImportStubs,
+    last_unimplemented_call: Option<UnimplementedApiCall>,
+    itm: Option<IoHandler>,
 }
 
 impl GenericEmbedded {
-    fn new() -> Self {
-        Self { debug_info: DebugInfo::default() }
+    pub(crate) fn new() -> Self {
+        Self {
+            debug_info: DebugInfo::default(),
+            import_stubs: ImportStubs::default(),
+            last_unimplemented_call: None,
+            itm: None,
+        }
+    }
+
+    /// Captures output written to the ARM ITM stimulus ports in `log` (see [crate::hw::Itm]). Once
+    /// enabled, the ITM is mapped every time a binary is loaded.
+    ///
+    /// Environments created by [build_auto] only enable the ITM if `ICICLE_ENABLE_ITM` is set (see
+    /// [AutoConfig::enable_itm]).
+    pub fn enable_itm(&mut self, cpu: &mut Cpu, log: GuestLog) {
+        self.itm = Some(cpu.mem.register_io_handler(crate::hw::Itm::new(log)));
+    }
+
+    fn map_itm(&self, cpu: &mut Cpu) {
+        let Some(itm) = self.itm
+        else {
+            return;
+        };
+        if !cpu.mem.map_memory_len(crate::hw::ITM_BASE, crate::hw::ITM_LEN, itm) {
+            tracing::warn!("failed to map ITM at {:#x}", crate::hw::ITM_BASE);
+        }
+    }
+
+    /// Gets the stubs generated for unresolved imports of the loaded PE file.
+    pub fn import_stubs(&self) -> &ImportStubs {
+        &self.import_stubs
+    }
+
+    /// Gets the details of the call that caused the most recent `UnimplementedApi` exit.
+    pub fn last_unimplemented_call(&self) -> Option<&UnimplementedApiCall> {
+        self.last_unimplemented_call.as_ref()
     }
 }
 
@@ -53,6 +100,7 @@ impl Environment for GenericEmbedded {
 
                 self.debug_info = metadata.debug_info;
                 self.debug_info.entry_ptr = metadata.binary.entry_ptr;
+                self.map_itm(cpu);
 
                 (cpu.arch.on_boot)(cpu, metadata.binary.entry_ptr);
 
@@ -67,6 +115,7 @@ impl Environment for GenericEmbedded {
 
                 self.debug_info = metadata.debug_info;
                 self.debug_info.entry_ptr = metadata.binary.entry_ptr;
+                self.import_stubs = metadata.import_stubs;
                 (cpu.arch.on_boot)(cpu, metadata.binary.entry_ptr);
                 Ok(())
             }
@@ -75,8 +124,18 @@ impl Environment for GenericEmbedded {
         }
     }
 
-    fn handle_exception(&mut self, _: &mut Cpu) -> Option<VmExit> {
-        None
+    fn handle_exception(&mut self, cpu: &mut Cpu) -> Option<VmExit> {
+        if ExceptionCode::from_u32(cpu.exception.code) != ExceptionCode::ExecViolation {
+            return None;
+        }
+
+        let addr = cpu.exception.value;
+        let call = self.import_stubs.describe_call(cpu, addr)?;
+        tracing::error!("{call}");
+        self.last_unimplemented_call = Some(call);
+
+        cpu.exception = Exception::new(ExceptionCode::UnimplementedApi, addr);
+        Some(VmExit::UnhandledException((ExceptionCode::UnimplementedApi, addr)))
     }
 
     fn debug_info(&self) -> Option<&DebugInfo> {
@@ -88,18 +147,76 @@ impl Environment for GenericEmbedded {
     }
 
     fn restore(&mut self, _: &Box<dyn Any>) {}
+
+    fn save_state(&mut self) -> Result<Vec<u8>, String> {
+        Ok(vec![])
+    }
+
+    fn load_state(&mut self, _: &[u8]) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// Settings used by [build_auto_with_config] to configure the environment.
+#[derive(Clone, Debug)]
+pub struct AutoConfig {
+    /// The format to trace syscalls in for Linux targets (`ICICLE_STRACE`).
+    pub strace: Option<icicle_linux::sys::strace::TraceFormat>,
+
+    /// The path to use as the sysroot for Linux targets (`ICICLE_SYSROOT`).
+    pub sysroot: PathBuf,
+
+    /// Whether output written to the ARM ITM stimulus ports should be captured
+    /// (`ICICLE_ENABLE_ITM`).
+    pub enable_itm: bool,
+
+    /// The name (or path) of the MCU config to use for MSP430 targets (`MSP430_MCU`).
+    pub msp430_mcu: Option<String>,
+}
+
+impl AutoConfig {
+    /// Reads the settings from the environment of the process.
+    pub fn from_env() -> Self {
+        Self::from_vars(|name| std::env::var_os(name))
+    }
+
+    /// Reads the settings using `var` to look up each variable, e.g. to read them from a saved
+    /// configuration instead of the environment of the process.
+    pub fn from_vars(var: impl Fn(&str) -> Option<std::ffi::OsString>) -> Self {
+        let string = |name| var(name).and_then(|value| value.into_string().ok());
+        Self {
+            strace: string("ICICLE_STRACE").map(|format| match format.as_str() {
+                "json" => icicle_linux::sys::strace::TraceFormat::Json,
+                _ => icicle_linux::sys::strace::TraceFormat::Text,
+            }),
+            sysroot: var("ICICLE_SYSROOT").map_or_else(|| PathBuf::from("/"), PathBuf::from),
+            enable_itm: var(ENABLE_ITM_ENV_VAR).is_some(),
+            msp430_mcu: string("MSP430_MCU"),
+        }
+    }
 }
 
+/// Builds the environment for the target of `vm`, configured using environment variables (see
+/// [AutoConfig::from_env]).
 pub fn build_auto(vm: &mut Vm) -> Result<Box<dyn EnvironmentAny>, BuildError> {
+    build_auto_with_config(vm, &AutoConfig::from_env())
+}
+
+pub fn build_auto_with_config(
+    vm: &mut Vm,
+    config: &AutoConfig,
+) -> Result<Box<dyn EnvironmentAny>, BuildError> {
     match vm.cpu.arch.triple.operating_system {
         target_lexicon::OperatingSystem::Linux => {
-            let config = icicle_linux::KernelConfig::default();
-            let sysroot = std::env::var_os("ICICLE_SYSROOT")
-                .map_or_else(|| std::path::PathBuf::from("/"), std::path::PathBuf::from);
-            Ok(Box::new(build_linux_env(vm, &config, sysroot, true)?))
+            let kernel_config =
+                icicle_linux::KernelConfig { strace: config.strace, ..Default::default() };
+            Ok(Box::new(build_linux_env(vm, &kernel_config, config.sysroot.clone(), true)?))
+        }
+        target_lexicon::OperatingSystem::Windows => {
+            Ok(Box::new(crate::windows::env::WindowsEnvironment::default()))
         }
         target_lexicon::OperatingSystem::None_ | target_lexicon::OperatingSystem::Unknown => {
-            build_machine_env(vm)
+            build_machine_env(vm, config)
         }
         _ => Err(BuildError::UnsupportedOperatingSystem),
     }
@@ -112,13 +229,18 @@ pub fn build_linux_env(
     mount_stddev: bool,
 ) -> Result<icicle_linux::Kernel, BuildError> {
     let mut kernel = icicle_linux::Kernel::new(&vm.cpu.arch, config);
+    kernel.set_entropy_source(vm.cpu.entropy.clone());
 
     kernel.init_vfs(sysroot).map_err(BuildError::FailedToInitEnvironment)?;
     if mount_stddev {
         kernel
             .mount_stddev(
-                icicle_linux::fs::devices::WriteOnlyDevice(std::io::stdout()),
-                icicle_linux::fs::devices::WriteOnlyDevice(std::io::stderr()),
+                WriteOnlyDevice(
+                    vm.guest_log.writer(LogOrigin::Stdout).with_passthrough(std::io::stdout()),
+                ),
+                WriteOnlyDevice(
+                    vm.guest_log.writer(LogOrigin::Stderr).with_passthrough(std::io::stderr()),
+                ),
                 None,
             )
             .map_err(BuildError::FailedToInitEnvironment)?;
@@ -127,14 +249,46 @@ pub fn build_linux_env(
     Ok(kernel)
 }
 
-fn build_machine_env(vm: &mut Vm) -> Result<Box<dyn EnvironmentAny>, BuildError> {
+/// Environment variable that enables capturing output written to the ARM ITM stimulus ports.
+const ENABLE_ITM_ENV_VAR: &str = "ICICLE_ENABLE_ITM";
+
+fn build_machine_env(
+    vm: &mut Vm,
+    config: &AutoConfig,
+) -> Result<Box<dyn EnvironmentAny>, BuildError> {
+    let enable_itm = |vm: &mut Vm, env: &mut GenericEmbedded| {
+        if config.enable_itm {
+            // The output written to the ITM is only accessible through the guest log.
+            vm.guest_log.set_enabled(true);
+            env.enable_itm(&mut vm.cpu, vm.guest_log.clone());
+        }
+    };
     match vm.cpu.arch.triple.architecture {
         target_lexicon::Architecture::Msp430 => {
-            let msp430_config = match std::env::var("MSP430_MCU") {
-                Ok(path) => crate::msp430::Config { mcu: path, ..crate::msp430::Config::default() },
-                Err(_) => crate::msp430::Config::default(),
+            let msp430_config = match &config.msp430_mcu {
+                Some(path) => {
+                    crate::msp430::Config { mcu: path.clone(), ..crate::msp430::Config::default() }
+                }
+                None => crate::msp430::Config::default(),
             };
-            Ok(Box::new(Msp430::new(&vm.cpu, msp430_config)?))
+            let mut env = Msp430::new(&vm.cpu, msp430_config)?;
+            env.guest_log = vm.guest_log.clone();
+            Ok(Box::new(env))
+        }
+        target_lexicon::Architecture::Avr => {
+            Ok(Box::new(crate::avr::Avr::new(crate::avr::Config::default())))
+        }
+        // Bare-metal Thumb targets are assumed to be Cortex-M microcontrollers.
+        target_lexicon::Architecture::Arm(arm) if arm.is_thumb() => {
+            let mut inner = GenericEmbedded::new();
+            enable_itm(vm, &mut inner);
+            let config = crate::cortex_m::Config::default();
+            Ok(Box::new(CortexM::new(&mut vm.cpu, inner, config)?))
+        }
+        target_lexicon::Architecture::Arm(_) => {
+            let mut env = GenericEmbedded::new();
+            enable_itm(vm, &mut env);
+            Ok(Box::new(env))
         }
         _ => Ok(Box::new(GenericEmbedded::new())),
     }
@@ -0,0 +1,89 @@
+//! Copy-on-write forking of the guest state, for tools that explore many execution states.
+//!
+//! A [VmFork] captures the CPU, memory and environment state of a VM (and the state of any
+//! [SnapshotHook](crate::SnapshotHook)s). Memory pages are shared between the VM and all forks
+//! until one of them writes to the page, so creating a fork only costs a reference count per mapped
+//! page (instead of copying page contents).
+//!
+//! Unlike a [crate::Snapshot], a fork does not include the code cache, and forks are not linked to
+//! each other. All forks run on the VM they were created from (sharing its translated code, hooks
+//! and injectors): [Vm::switch_to] replaces the guest state of the VM with the state of a fork,
+//! invalidating only the translations lifted from pages that differ between the two states.
+
+use std::rc::Rc;
+
+use icicle_cpu::{CpuSnapshot, mem};
+
+use crate::Vm;
+
+struct ForkState {
+    cpu: Box<CpuSnapshot>,
+    mem: mem::Snapshot,
+    env: Box<dyn std::any::Any>,
+    hooks: Vec<Box<dyn std::any::Any>>,
+}
+
+/// A copy-on-write copy of the guest state of a VM, see [Vm::fork].
+#[derive(Clone)]
+pub struct VmFork {
+    state: Rc<ForkState>,
+}
+
+impl VmFork {
+    /// The instruction count of the VM at the time the fork was created.
+    pub fn icount(&self) -> u64 {
+        self.state.cpu.icount
+    }
+}
+
+impl Vm {
+    /// Creates a copy-on-write fork of the current CPU, memory and environment state.
+    pub fn fork(&mut self) -> VmFork {
+        VmFork {
+            state: Rc::new(ForkState {
+                cpu: self.cpu.snapshot(),
+                mem: self.cpu.mem.fork(),
+                env: self.env.snapshot(),
+                hooks: self.snapshot_hook_state(),
+            }),
+        }
+    }
+
+    /// Replaces the guest state of the VM with the state captured by `fork`. The fork itself is
+    /// unchanged, so it can be switched to again later.
+    pub fn switch_to(&mut self, fork: &VmFork) {
+        // Must be done before switching memory, to compare the memory that code was lifted from
+        // with the memory of the fork.
+        self.invalidate_code_changed_in(&fork.state.mem);
+
+        self.cpu.restore(&fork.state.cpu);
+        self.cpu.mem.restore(fork.state.mem.clone());
+        self.env.restore(&fork.state.env);
+        self.restore_hook_state(&fork.state.hooks);
+        self.clear_sanitizer_reports();
+        self.update_context();
+    }
+
+    /// Removes any translations lifted from pages with different content in `mem`.
+    fn invalidate_code_changed_in(&mut self, mem: &mem::Snapshot) {
+        let current = &self.cpu.mem;
+        let is_unchanged = |group: &icicle_cpu::lifter::BlockGroup| {
+            let mut page = current.page_aligned(group.start);
+            while page <= group.end {
+                if !current.is_page_unchanged(mem, page) {
+                    return false;
+                }
+                match page.checked_add(current.page_size()) {
+                    Some(next) => page = next,
+                    None => break,
+                }
+            }
+            true
+        };
+
+        for group in self.code.retain(is_unchanged) {
+            group.range().for_each(|id| self.jit.invalidate(id));
+        }
+        self.discovery.retain_pending(is_unchanged);
+    }
+}
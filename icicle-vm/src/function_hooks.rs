@@ -0,0 +1,245 @@
+//! Hooks on the entry and exit of guest functions.
+//!
+//! Unlike [Vm::hook_address], function hooks are passed a [FunctionCall] which provides access to
+//! the arguments and return value of the call using the standard calling convention of the target,
+//! so the same hook can be used regardless of the architecture of the guest. Hooks can also skip
+//! the original function entirely (see [FunctionCall::skip]), e.g. to replace the implementation
+//! of a function that depends on hardware or an environment that is not emulated.
+//!
+//! Hooks registered with [hook_function_return] (or [hook_function_returns]) are called when the
+//! function returns to its caller, and are passed a [FunctionReturn] which provides access to (and
+//! can replace) the value returned by the function. Returns are detected by checking the target of
+//! every return instruction against the return addresses of the calls to the function that are in
+//! progress.
+//!
+//! Only integer and pointer arguments that fit in a single register (or stack slot) are supported.
+//!
+//! Note: hooks must be registered before the code of the function has been executed, or existing
+//! translations of the function should be removed with [Vm::invalidate_code_range].
+
+use std::{cell::RefCell, rc::Rc};
+
+use icicle_cpu::{BlockGroup, BlockTable, Cpu, lifter::BlockExit};
+use pcode::Op;
+
+use crate::{Vm, injector::CodeInjector, libc_models::return_from_call};
+
+/// The maximum number of calls to functions with a return hook that can be in progress at once.
+/// Calls that never return through a return instruction (e.g. because of `longjmp` or because the
+/// function was skipped) are discarded once this is exceeded.
+const MAX_PENDING_RETURNS: usize = 1024;
+
+/// A value that can be passed to or returned from a function in a single integer register.
+pub trait AbiValue: Sized {
+    /// Converts the (zero-extended) raw value of a register to `Self`.
+    fn from_raw(raw: u64) -> Self;
+
+    /// Converts `self` to the raw value of a register, sign-extending signed values.
+    fn into_raw(self) -> u64;
+}
+
+macro_rules! impl_abi_value {
+    ($($ty:ty),*) => {
+        $(
+            impl AbiValue for $ty {
+                fn from_raw(raw: u64) -> Self {
+                    raw as $ty
+                }
+
+                fn into_raw(self) -> u64 {
+                    self as u64
+                }
+            }
+        )*
+    };
+}
+
+impl_abi_value!(u8, u16, u32, u64, usize, i8, i16, i32, i64, isize);
+
+impl AbiValue for bool {
+    fn from_raw(raw: u64) -> Self {
+        raw != 0
+    }
+
+    fn into_raw(self) -> u64 {
+        self as u64
+    }
+}
+
+/// The state of a call to a hooked function, passed to the hook at the entry of the function.
+pub struct FunctionCall<'a> {
+    pub cpu: &'a mut Cpu,
+
+    /// The address of the function that was called.
+    pub addr: u64,
+
+    /// The value to return to the caller if the function is skipped.
+    skip: Option<u64>,
+}
+
+impl FunctionCall<'_> {
+    /// Reads the `n`th argument of the call.
+    pub fn arg<T: AbiValue>(&mut self, n: usize) -> T {
+        T::from_raw(self.cpu.read_arg(n))
+    }
+
+    /// Replaces the `n`th argument of the call with `value`. Returns `None` if the argument could
+    /// not be written.
+    pub fn set_arg<T: AbiValue>(&mut self, n: usize, value: T) -> Option<()> {
+        self.cpu.write_arg(n, value.into_raw())
+    }
+
+    /// Reads the address the function will return to.
+    pub fn return_addr(&mut self) -> Option<u64> {
+        self.cpu.read_return_addr()
+    }
+
+    /// Skips the original function, returning `value` to the caller.
+    pub fn skip<T: AbiValue>(&mut self, value: T) {
+        self.skip = Some(value.into_raw());
+    }
+
+    /// Gets the value that will be returned to the caller if the function was skipped.
+    ///
+    /// Note: the value returned by the original function can be accessed using a hook registered
+    /// with [hook_function_return].
+    pub fn return_value<T: AbiValue>(&self) -> Option<T> {
+        self.skip.map(T::from_raw)
+    }
+
+    /// Returns whether the original function will be skipped.
+    pub fn is_skipped(&self) -> bool {
+        self.skip.is_some()
+    }
+}
+
+/// Registers `hook` to be called at the entry of the function at `addr`.
+pub fn hook_function(vm: &mut Vm, addr: u64, mut hook: impl FnMut(&mut FunctionCall) + 'static) {
+    vm.hook_address(addr, move |cpu, addr| {
+        let mut call = FunctionCall { cpu: &mut *cpu, addr, skip: None };
+        hook(&mut call);
+        if let Some(value) = call.skip {
+            return_from_call(cpu, value);
+        }
+    });
+}
+
+/// The state of a hooked function when it returns to its caller, passed to hooks registered with
+/// [hook_function_return].
+pub struct FunctionReturn<'a> {
+    pub cpu: &'a mut Cpu,
+
+    /// The address of the function that was called.
+    pub addr: u64,
+
+    /// The address that the function is returning to.
+    pub return_addr: u64,
+}
+
+impl FunctionReturn<'_> {
+    /// Reads the value returned by the function. Returns `None` if the return register is unknown.
+    pub fn return_value<T: AbiValue>(&mut self) -> Option<T> {
+        self.cpu.read_return_value().map(T::from_raw)
+    }
+
+    /// Replaces the value returned to the caller with `value`. Returns `None` if the return value
+    /// could not be written.
+    pub fn set_return_value<T: AbiValue>(&mut self, value: T) -> Option<()> {
+        self.cpu.write_return_value(value.into_raw())
+    }
+}
+
+/// Registers `hook` to be called when the function at `addr` returns to its caller.
+///
+/// Note: the hook is not called for calls that do not return using a return instruction, e.g. if
+/// the function is skipped by a hook registered with [hook_function], or exits using `longjmp`.
+pub fn hook_function_return(
+    vm: &mut Vm,
+    addr: u64,
+    hook: impl FnMut(&mut FunctionReturn) + 'static,
+) {
+    hook_function_returns(vm, &[addr], hook)
+}
+
+/// Registers `hook` to be called when any of the functions at `addrs` returns to its caller (see
+/// [hook_function_return]). This is cheaper than hooking each function individually, since return
+/// instructions are only instrumented once.
+pub fn hook_function_returns(
+    vm: &mut Vm,
+    addrs: &[u64],
+    mut hook: impl FnMut(&mut FunctionReturn) + 'static,
+) {
+    const TARGET_REG: &str = "function_hooks.return_target";
+    let target_var = match vm.cpu.arch.sleigh.add_custom_reg(TARGET_REG, 8) {
+        Some(var) => var,
+        None => vm.cpu.arch.sleigh.get_varnode(TARGET_REG).unwrap(),
+    };
+
+    // The return address and function address of the calls that are in progress.
+    let pending: Rc<RefCell<Vec<(u64, u64)>>> = Rc::default();
+
+    let entry_pending = pending.clone();
+    vm.hook_many_addresses(addrs, move |cpu, addr| {
+        if let Some(return_addr) = cpu.read_return_addr() {
+            let mut pending = entry_pending.borrow_mut();
+            if pending.len() >= MAX_PENDING_RETURNS {
+                pending.remove(0);
+            }
+            pending.push((return_addr, addr));
+        }
+    });
+
+    let hook = vm.cpu.add_hook(move |cpu: &mut Cpu, _: u64| {
+        let return_addr = cpu.read_reg(target_var);
+        let addr = {
+            let mut pending = pending.borrow_mut();
+            // Calls that are more recent than the returning call were exited without returning
+            // (e.g. using `longjmp`), so they are discarded.
+            let Some(index) = pending.iter().rposition(|(x, _)| *x == return_addr)
+            else {
+                return;
+            };
+            let (_, addr) = pending[index];
+            pending.truncate(index);
+            addr
+        };
+        hook(&mut FunctionReturn { cpu, addr, return_addr });
+    });
+    vm.add_injector(ReturnInjector { hook, target: target_var });
+}
+
+/// Calls a hook with the target of each return instruction before the return is executed.
+struct ReturnInjector {
+    hook: pcode::HookId,
+    target: pcode::VarNode,
+}
+
+impl CodeInjector for ReturnInjector {
+    fn inject(&mut self, _cpu: &mut Cpu, group: &BlockGroup, code: &mut BlockTable) {
+        for id in group.range() {
+            let block = &mut code.blocks[id];
+            let BlockExit::Return { target } = block.exit
+            else {
+                continue;
+            };
+            match target.size() {
+                8 => block.pcode.push(self.target.copy_from(target)),
+                _ => block.pcode.push(self.target.zext_from(target)),
+            }
+            block.pcode.push(Op::Hook(self.hook));
+            code.modified.insert(id);
+        }
+    }
+}
+
+/// Registers `hook` to be called at the entry of the function named `symbol`, returning the address
+/// of the function, or `None` if the symbol could not be resolved.
+pub fn hook_symbol(
+    vm: &mut Vm,
+    symbol: &str,
+    hook: impl FnMut(&mut FunctionCall) + 'static,
+) -> Option<u64> {
+    let addr = vm.env.lookup_symbol(symbol)?;
+    hook_function(vm, addr, hook);
+    Some(addr)
+}
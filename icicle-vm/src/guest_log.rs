@@ -0,0 +1,266 @@
+//! A single channel for textual output produced by the guest.
+//!
+//! Guest output can come from many places (writes to stdout in a Linux process, memory mapped
+//! logging peripherals, modelled `printf` calls, ...). Each source gets a [GuestLogWriter] tagged
+//! with the origin of the output, and complete lines are collected in a shared [GuestLog] with a
+//! timestamp. Entries can be retrieved later or streamed live as they are produced.
+//!
+//! The log attached to a VM is disabled unless [icicle_cpu::Config::enable_guest_log] is set, in
+//! which case writes are only passed through to the writer's output.
+
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    io::Write,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+/// The default maximum number of entries kept in the log.
+const DEFAULT_CAPACITY: usize = 10_000;
+
+/// Partial lines longer than this are split into multiple entries.
+const MAX_LINE_LEN: usize = 4096;
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum LogOrigin {
+    /// Writes to the standard output of the guest process.
+    Stdout,
+
+    /// Writes to the standard error of the guest process.
+    Stderr,
+
+    /// Output from a modelled `printf`-like function.
+    Printf,
+
+    /// Output written to a memory mapped peripheral (e.g. a UART).
+    Peripheral(String),
+
+    /// Output written to a stimulus port of the ARM Instrumentation Trace Macrocell.
+    Itm(u8),
+}
+
+impl std::fmt::Display for LogOrigin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Stdout => f.write_str("stdout"),
+            Self::Stderr => f.write_str("stderr"),
+            Self::Printf => f.write_str("printf"),
+            Self::Peripheral(name) => f.write_str(name),
+            Self::Itm(port) => write!(f, "itm{port}"),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct LogEntry {
+    /// The (host) time since the log was created when the line was completed.
+    pub time: Duration,
+
+    /// Where the output came from.
+    pub origin: LogOrigin,
+
+    /// The contents of the line (excluding the line terminator).
+    pub text: Vec<u8>,
+}
+
+impl std::fmt::Display for LogEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let text = String::from_utf8_lossy(&self.text);
+        write!(f, "[{:>12.6}] [{}] {text}", self.time.as_secs_f64(), self.origin)
+    }
+}
+
+struct GuestLogInner {
+    enabled: bool,
+    start: Instant,
+    entries: VecDeque<LogEntry>,
+    capacity: usize,
+    /// The incomplete line for each origin that has written to the log. There are only a handful
+    /// of origins, so a linear search is cheaper than hashing the origin on every write.
+    partial: Vec<(LogOrigin, Vec<u8>)>,
+    stream: Option<Box<dyn Write>>,
+}
+
+impl GuestLogInner {
+    fn push_line(&mut self, origin: &LogOrigin, text: Vec<u8>) {
+        let entry = LogEntry { time: self.start.elapsed(), origin: origin.clone(), text };
+        if let Some(stream) = self.stream.as_mut() {
+            if let Err(e) = writeln!(stream, "{entry}") {
+                tracing::error!("failed to stream guest log: {e}");
+            }
+        }
+        if self.entries.len() >= self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(entry);
+    }
+}
+
+/// A shared handle to the guest log. Cloning the log produces a handle to the same log.
+#[derive(Clone)]
+pub struct GuestLog {
+    inner: Rc<RefCell<GuestLogInner>>,
+}
+
+impl Default for GuestLog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl GuestLog {
+    pub fn new() -> Self {
+        Self {
+            inner: Rc::new(RefCell::new(GuestLogInner {
+                enabled: true,
+                start: Instant::now(),
+                entries: VecDeque::new(),
+                capacity: DEFAULT_CAPACITY,
+                partial: Vec::new(),
+                stream: None,
+            })),
+        }
+    }
+
+    /// Creates a new log that ignores all writes until it is enabled with [GuestLog::set_enabled].
+    pub fn disabled() -> Self {
+        let log = Self::new();
+        log.set_enabled(false);
+        log
+    }
+
+    /// Controls whether output written to the log is recorded. Disabling the log discards any
+    /// incomplete lines.
+    pub fn set_enabled(&self, enabled: bool) {
+        let mut inner = self.inner.borrow_mut();
+        inner.enabled = enabled;
+        if !enabled {
+            inner.partial.clear();
+        }
+    }
+
+    /// Returns whether output written to the log is recorded.
+    pub fn is_enabled(&self) -> bool {
+        self.inner.borrow().enabled
+    }
+
+    /// Sets the maximum number of entries kept in the log, older entries are discarded first.
+    pub fn set_capacity(&self, capacity: usize) {
+        let mut inner = self.inner.borrow_mut();
+        inner.capacity = capacity.max(1);
+        while inner.entries.len() > inner.capacity {
+            inner.entries.pop_front();
+        }
+    }
+
+    /// Writes every new entry to `output` as soon as it is complete.
+    pub fn stream_to(&self, output: impl Write + 'static) {
+        self.inner.borrow_mut().stream = Some(Box::new(output));
+    }
+
+    /// Stops streaming entries.
+    pub fn stop_stream(&self) {
+        self.inner.borrow_mut().stream = None;
+    }
+
+    /// Creates a new writer that adds output to the log tagged with `origin`.
+    pub fn writer(&self, origin: LogOrigin) -> GuestLogWriter {
+        GuestLogWriter { log: self.clone(), origin, passthrough: None }
+    }
+
+    /// Appends `data` to the log, creating a new entry for each complete line.
+    pub fn write(&self, origin: &LogOrigin, data: &[u8]) {
+        let mut inner = self.inner.borrow_mut();
+        if !inner.enabled {
+            return;
+        }
+
+        let index = match inner.partial.iter().position(|(x, _)| x == origin) {
+            Some(index) => index,
+            None => {
+                inner.partial.push((origin.clone(), Vec::new()));
+                inner.partial.len() - 1
+            }
+        };
+        let mut partial = std::mem::take(&mut inner.partial[index].1);
+        for &byte in data {
+            match byte {
+                b'\n' => inner.push_line(origin, std::mem::take(&mut partial)),
+                b'\r' => {}
+                _ => {
+                    partial.push(byte);
+                    if partial.len() >= MAX_LINE_LEN {
+                        inner.push_line(origin, std::mem::take(&mut partial));
+                    }
+                }
+            }
+        }
+        inner.partial[index].1 = partial;
+    }
+
+    /// Adds any incomplete lines to the log as entries.
+    pub fn flush(&self) {
+        let mut inner = self.inner.borrow_mut();
+        let mut partial: Vec<_> = inner
+            .partial
+            .iter_mut()
+            .filter(|(_, text)| !text.is_empty())
+            .map(|(origin, text)| (origin.clone(), std::mem::take(text)))
+            .collect();
+        partial.sort_by_key(|(origin, _)| origin.to_string());
+        for (origin, text) in partial {
+            inner.push_line(&origin, text);
+        }
+    }
+
+    /// Returns a copy of all entries currently in the log.
+    pub fn entries(&self) -> Vec<LogEntry> {
+        self.inner.borrow().entries.iter().cloned().collect()
+    }
+
+    /// Removes and returns all entries currently in the log.
+    pub fn take_entries(&self) -> Vec<LogEntry> {
+        self.inner.borrow_mut().entries.drain(..).collect()
+    }
+
+    /// Removes all entries and incomplete lines from the log.
+    pub fn clear(&self) {
+        let mut inner = self.inner.borrow_mut();
+        inner.entries.clear();
+        inner.partial.clear();
+    }
+}
+
+/// A writer that adds output to a [GuestLog], optionally also passing it through to another writer
+/// unmodified.
+pub struct GuestLogWriter {
+    log: GuestLog,
+    origin: LogOrigin,
+    passthrough: Option<Box<dyn Write>>,
+}
+
+impl GuestLogWriter {
+    /// Also writes all output to `output`.
+    pub fn with_passthrough(mut self, output: impl Write + 'static) -> Self {
+        self.passthrough = Some(Box::new(output));
+        self
+    }
+}
+
+impl Write for GuestLogWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.log.write(&self.origin, buf);
+        if let Some(output) = self.passthrough.as_mut() {
+            output.write_all(buf)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self.passthrough.as_mut() {
+            Some(output) => output.flush(),
+            None => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,441 @@
+//! A heap sanitizer for guests that are not built with ASAN.
+//!
+//! Calls to `malloc`, `calloc`, `realloc` and `free` are interposed (similar to
+//! [crate::libc_models]) and serviced from a separate region of memory. The region is mapped
+//! without any access permissions, and only the bytes of live allocations are made accessible, so
+//! every allocation is surrounded by redzones. Freed allocations are poisoned and never reused.
+//! This means that heap-buffer-overflows and use-after-frees fault at the invalid access, even if
+//! they would not cause a crash when running natively.
+//!
+//! Faults inside of the heap are reported as [ExceptionCode::SanitizerError] with an [AsanReport]
+//! that describes the allocation that was accessed, along with the callstacks of where it was
+//! allocated and freed.
+//!
+//! The state of the heap is saved and restored along with the VM (see [crate::SnapshotHook]), so
+//! memory allocated after a snapshot is reclaimed when the snapshot is restored.
+
+use std::{any::Any, cell::RefCell, collections::BTreeMap, fmt::Write, rc::Rc};
+
+use icicle_cpu::{
+    Cpu, Exception, ExceptionCode,
+    mem::{AllocLayout, Mapping, MemResult, perm},
+};
+
+use crate::{
+    SnapshotHook, Vm,
+    asan::{AsanAccess, AsanReport},
+    libc_models::{return_from_call, write_zeros},
+    provenance::AllocatorModel,
+};
+
+/// Allocations are aligned to this value.
+const HEAP_ALIGN: u64 = 16;
+
+/// The maximum number of frames saved for the allocation and free callstacks.
+const MAX_STACK_FRAMES: usize = 16;
+
+#[derive(Clone, Copy, Debug)]
+pub struct HeapSanitizerOptions {
+    /// The size of the memory region reserved for the heap.
+    pub heap_size: u64,
+
+    /// The minimum number of inaccessible bytes placed between allocations.
+    pub redzone: u64,
+}
+
+impl Default for HeapSanitizerOptions {
+    fn default() -> Self {
+        Self { heap_size: 0x1000_0000, redzone: 32 }
+    }
+}
+
+/// An allocation made by the sanitized heap.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SanitizedAllocation {
+    pub addr: u64,
+    pub size: u64,
+
+    /// The callstack (outermost frame first) when the allocation was made.
+    pub alloc_stack: Vec<u64>,
+
+    /// The callstack (outermost frame first) when the allocation was freed, or `None` if the
+    /// allocation is live.
+    pub free_stack: Option<Vec<u64>>,
+}
+
+struct HeapState {
+    start: u64,
+    end: u64,
+    next: u64,
+    redzone: u64,
+    allocations: BTreeMap<u64, SanitizedAllocation>,
+
+    /// The last error detected by the sanitizer.
+    report: Option<AsanReport>,
+
+    /// The allocation associated with `report` (if any).
+    report_allocation: Option<SanitizedAllocation>,
+}
+
+impl HeapState {
+    fn alloc(&mut self, cpu: &mut Cpu, size: u64) -> Option<u64> {
+        let addr = icicle_cpu::utils::align_up(self.next + self.redzone, HEAP_ALIGN);
+        let end = addr.checked_add(size)?;
+        if end.checked_add(self.redzone)? > self.end {
+            tracing::warn!("sanitized heap exhausted allocating {size:#x} bytes");
+            return None;
+        }
+        if size != 0 {
+            cpu.mem.update_perm(addr, size, perm::READ | perm::WRITE).ok()?;
+        }
+        self.next = end;
+        let alloc_stack = callstack(cpu);
+        self.allocations.insert(addr, SanitizedAllocation {
+            addr,
+            size,
+            alloc_stack,
+            free_stack: None,
+        });
+        Some(addr)
+    }
+
+    /// Frees the allocation at `addr`, reporting an error if it is not a live allocation.
+    fn free(&mut self, cpu: &mut Cpu, addr: u64) -> Option<()> {
+        let Some(alloc) = self.allocations.get_mut(&addr).filter(|x| x.free_stack.is_none())
+        else {
+            let bug_type = match self.allocations.contains_key(&addr) {
+                true => "double-free",
+                false => "bad-free",
+            };
+            let alloc = self.allocations.get(&addr).cloned();
+            self.set_report(cpu, bug_type, addr, None, alloc);
+            return None;
+        };
+        if alloc.size != 0 {
+            cpu.mem.update_perm(addr, alloc.size, perm::NONE).ok()?;
+        }
+        alloc.free_stack = Some(callstack(cpu));
+        Some(())
+    }
+
+    /// Finds the allocation that is closest to `addr`, returning the allocation and the bug type
+    /// of an invalid access at `addr`.
+    fn classify(&self, addr: u64) -> (&'static str, Option<&SanitizedAllocation>) {
+        let before = self.allocations.range(..=addr).next_back().map(|(_, x)| x);
+        let after = self.allocations.range(addr + 1..).next().map(|(_, x)| x);
+
+        if let Some(alloc) = before.filter(|x| addr < x.addr + x.size) {
+            return match alloc.free_stack {
+                Some(_) => ("heap-use-after-free", Some(alloc)),
+                None => ("unknown-crash", Some(alloc)),
+            };
+        }
+        let alloc = match (before, after) {
+            (Some(before), Some(after)) => {
+                match addr - (before.addr + before.size) <= after.addr - addr {
+                    true => before,
+                    false => after,
+                }
+            }
+            (Some(alloc), None) | (None, Some(alloc)) => alloc,
+            (None, None) => return ("wild-heap-access", None),
+        };
+        ("heap-buffer-overflow", Some(alloc))
+    }
+
+    fn set_report(
+        &mut self,
+        cpu: &mut Cpu,
+        bug_type: &str,
+        addr: u64,
+        access: Option<AsanAccess>,
+        alloc: Option<SanitizedAllocation>,
+    ) {
+        let report = AsanReport {
+            bug_type: Some(bug_type.into()),
+            access,
+            summary: Some(format!("SUMMARY: HeapSanitizer: {bug_type} on address {addr:#x}")),
+            details: None,
+        };
+        tracing::error!("HeapSanitizer: {report}");
+        self.report = Some(report);
+        self.report_allocation = alloc;
+        cpu.exception = Exception::new(ExceptionCode::SanitizerError, addr);
+    }
+}
+
+/// Gets the callstack at the entry of a function.
+fn callstack(cpu: &mut Cpu) -> Vec<u64> {
+    let stack = cpu.shadow_stack.as_slice();
+    if !stack.is_empty() {
+        let skip = stack.len().saturating_sub(MAX_STACK_FRAMES);
+        return stack[skip..].iter().map(|entry| entry.addr).collect();
+    }
+    cpu.read_return_addr().into_iter().collect()
+}
+
+/// A handle to the heap sanitizer attached to a VM. Cloning the handle produces a handle to the
+/// same state.
+#[derive(Clone)]
+pub struct HeapSanitizer {
+    state: Rc<RefCell<HeapState>>,
+}
+
+impl HeapSanitizer {
+    /// Reserves memory for the sanitized heap.
+    pub fn new(cpu: &mut Cpu, options: HeapSanitizerOptions) -> MemResult<Self> {
+        let layout = AllocLayout { addr: None, size: options.heap_size, align: 0x1000 };
+        let start = cpu.mem.alloc_memory(layout, Mapping { perm: perm::MAP, value: 0xaa })?;
+        let state = HeapState {
+            start,
+            end: start + options.heap_size,
+            next: start,
+            redzone: options.redzone.max(1),
+            allocations: BTreeMap::new(),
+            report: None,
+            report_allocation: None,
+        };
+        Ok(Self { state: Rc::new(RefCell::new(state)) })
+    }
+
+    /// Returns whether `addr` is inside of the memory region used by the heap.
+    pub fn contains(&self, addr: u64) -> bool {
+        let state = self.state.borrow();
+        state.start <= addr && addr < state.end
+    }
+
+    /// Gets the start and (exclusive) end address of the memory region used by the heap.
+    pub fn region(&self) -> (u64, u64) {
+        let state = self.state.borrow();
+        (state.start, state.end)
+    }
+
+    /// Allocates `size` bytes from the sanitized heap.
+    pub fn alloc(&self, cpu: &mut Cpu, size: u64) -> Option<u64> {
+        self.state.borrow_mut().alloc(cpu, size)
+    }
+
+    /// Frees the allocation at `addr`. If `addr` is not a live allocation, an error is reported
+    /// and `None` is returned.
+    pub fn free(&self, cpu: &mut Cpu, addr: u64) -> Option<()> {
+        self.state.borrow_mut().free(cpu, addr)
+    }
+
+    /// Finds the allocation (live or freed) that starts at `addr`.
+    pub fn get(&self, addr: u64) -> Option<SanitizedAllocation> {
+        self.state.borrow().allocations.get(&addr).cloned()
+    }
+
+    /// Iterates over all allocations that have not been freed.
+    pub fn live(&self) -> Vec<SanitizedAllocation> {
+        let state = self.state.borrow();
+        state.allocations.values().filter(|x| x.free_stack.is_none()).cloned().collect()
+    }
+
+    /// Gets the report for the most recent error detected by the sanitizer.
+    pub fn last_report(&self) -> Option<AsanReport> {
+        self.state.borrow().report.clone()
+    }
+
+    /// Removes and returns the report for the most recent error detected by the sanitizer.
+    pub fn take_report(&self) -> Option<AsanReport> {
+        self.state.borrow_mut().report.take()
+    }
+
+    /// Called by the VM on every exception, converts memory errors inside of the heap to sanitizer
+    /// errors and adds the symbolized allocation details to new reports.
+    pub(crate) fn on_exception(&self, vm: &mut Vm) {
+        let code = ExceptionCode::from_u32(vm.cpu.exception.code);
+        let addr = vm.cpu.exception.value;
+        let is_write = match code {
+            ExceptionCode::ReadPerm | ExceptionCode::ReadUnmapped => false,
+            ExceptionCode::WritePerm | ExceptionCode::WriteUnmapped => true,
+            ExceptionCode::SanitizerError => return self.add_details(vm),
+            _ => return,
+        };
+        if !self.contains(addr) {
+            return;
+        }
+
+        let mut state = self.state.borrow_mut();
+        let (bug_type, alloc) = state.classify(addr);
+        let alloc = alloc.cloned();
+        let access = AsanAccess { addr, size: 0, is_write, pc: vm.cpu.read_pc() };
+        state.set_report(&mut vm.cpu, bug_type, addr, Some(access), alloc);
+        drop(state);
+
+        self.add_details(vm);
+    }
+
+    fn add_details(&self, vm: &mut Vm) {
+        let mut state = self.state.borrow_mut();
+        let Some(alloc) = state.report_allocation.take()
+        else {
+            return;
+        };
+        let Some(report) = state.report.as_mut().filter(|x| x.details.is_none())
+        else {
+            return;
+        };
+
+        let mut details = String::new();
+        let end = alloc.addr + alloc.size;
+        if let Some(access) = report.access {
+            let location = match access.addr {
+                x if x < alloc.addr => format!("{} bytes before", alloc.addr - x),
+                x if x >= end => format!("{} bytes after", x - end),
+                x => format!("{} bytes inside of", x - alloc.addr),
+            };
+            let _ = writeln!(
+                details,
+                "{:#x} is located {location} {}-byte region [{:#x},{end:#x})",
+                access.addr, alloc.size, alloc.addr
+            );
+        }
+        if let Some(stack) = alloc.free_stack.as_ref() {
+            let _ = writeln!(details, "freed by:");
+            write_stack(vm, stack, &mut details);
+        }
+        let _ = writeln!(details, "previously allocated by:");
+        write_stack(vm, &alloc.alloc_stack, &mut details);
+        report.details = Some(details);
+    }
+}
+
+struct HeapSnapshot {
+    next: u64,
+    allocations: BTreeMap<u64, SanitizedAllocation>,
+}
+
+impl SnapshotHook for HeapSanitizer {
+    fn snapshot(&mut self) -> Box<dyn Any> {
+        let state = self.state.borrow();
+        Box::new(HeapSnapshot { next: state.next, allocations: state.allocations.clone() })
+    }
+
+    fn restore(&mut self, snapshot: &Box<dyn Any>) {
+        let snapshot = snapshot.downcast_ref::<HeapSnapshot>().unwrap();
+        let mut state = self.state.borrow_mut();
+        // The permissions of the heap region are restored with the rest of memory.
+        state.next = snapshot.next;
+        state.allocations.clone_from(&snapshot.allocations);
+        state.report = None;
+        state.report_allocation = None;
+    }
+}
+
+impl AllocatorModel for HeapSanitizer {
+    fn region(&self) -> (u64, u64) {
+        HeapSanitizer::region(self)
+    }
+
+    fn find_allocation(&self, addr: u64) -> Option<(u64, u64)> {
+        let state = self.state.borrow();
+        let (_, alloc) = state.allocations.range(..=addr).next_back()?;
+        (addr < alloc.addr + alloc.size).then_some((alloc.addr, alloc.size))
+    }
+}
+
+fn write_stack(vm: &mut Vm, stack: &[u64], out: &mut String) {
+    for addr in stack.iter().rev() {
+        // Use the address of the call instruction instead of the return address.
+        let location = vm.env.symbolize_addr(&mut vm.cpu, addr.wrapping_sub(1)).unwrap_or_default();
+        let _ = writeln!(out, "    {addr:#012x}: {location}");
+    }
+}
+
+/// Reserves memory for the sanitized heap and hooks each of `entries` (a list of (address, name)
+/// pairs) that is the entry point of `malloc`, `calloc`, `realloc` or `free`. Pointers that were
+/// not allocated by the sanitized heap (e.g. allocations made before the sanitizer was attached)
+/// are passed to the guest implementation.
+///
+/// Note: this must be called before any code at the entries has been executed.
+pub fn attach(
+    vm: &mut Vm,
+    entries: Vec<(u64, String)>,
+    options: HeapSanitizerOptions,
+) -> MemResult<HeapSanitizer> {
+    let sanitizer = HeapSanitizer::new(&mut vm.cpu, options)?;
+
+    let entries: BTreeMap<u64, String> = entries
+        .into_iter()
+        .filter(|(_, name)| matches!(name.as_str(), "malloc" | "calloc" | "realloc" | "free"))
+        .collect();
+    let addrs: Vec<u64> = entries.keys().copied().collect();
+    let heap = sanitizer.clone();
+    vm.hook_many_addresses(&addrs, move |cpu, addr| {
+        if let Some(name) = entries.get(&addr) {
+            if let Some(value) = call(&heap, cpu, name) {
+                return_from_call(cpu, value);
+            }
+        }
+    });
+    vm.heap_sanitizer = Some(sanitizer.clone());
+    vm.add_snapshot_hook(sanitizer.clone());
+
+    Ok(sanitizer)
+}
+
+/// Runs the sanitized implementation of `name`, returning `None` if the guest implementation should
+/// be used instead (or if an error was reported).
+fn call(heap: &HeapSanitizer, cpu: &mut Cpu, name: &str) -> Option<u64> {
+    match name {
+        "malloc" => {
+            let size = cpu.read_arg(0);
+            heap.alloc(cpu, size).or(Some(0))
+        }
+        "calloc" => {
+            let Some(size) = cpu.read_arg(0).checked_mul(cpu.read_arg(1))
+            else {
+                return Some(0);
+            };
+            let Some(addr) = heap.alloc(cpu, size)
+            else {
+                return Some(0);
+            };
+            write_zeros(cpu, addr, size).ok()?;
+            Some(addr)
+        }
+        "realloc" => {
+            let (ptr, size) = (cpu.read_arg(0), cpu.read_arg(1));
+            if ptr == 0 {
+                return heap.alloc(cpu, size).or(Some(0));
+            }
+            if !heap.contains(ptr) {
+                return None;
+            }
+            let old_size = match heap.get(ptr) {
+                Some(alloc) if alloc.free_stack.is_none() => alloc.size,
+                _ => {
+                    heap.free(cpu, ptr);
+                    return None;
+                }
+            };
+            if size == 0 {
+                heap.free(cpu, ptr)?;
+                return Some(0);
+            }
+            let Some(new) = heap.alloc(cpu, size)
+            else {
+                return Some(0);
+            };
+            let mut buf = vec![0; old_size.min(size) as usize];
+            cpu.mem.read_bytes_large(ptr, &mut buf, perm::READ).ok()?;
+            cpu.mem.write_bytes_large(new, &buf, perm::WRITE).ok()?;
+            heap.free(cpu, ptr)?;
+            Some(new)
+        }
+        "free" => {
+            let ptr = cpu.read_arg(0);
+            if ptr == 0 {
+                return Some(0);
+            }
+            if !heap.contains(ptr) {
+                return None;
+            }
+            heap.free(cpu, ptr)?;
+            Some(0)
+        }
+        _ => None,
+    }
+}
@@ -0,0 +1,74 @@
+//! Reconfiguring the peripherals and environment of a live VM.
+//!
+//! Developing a harness typically involves many small tweaks to peripheral models or environment
+//! settings. Instead of rebuilding the VM and replaying execution up to the point of interest after
+//! each tweak, peripherals can be swapped or reconfigured in place between calls to [Vm::run],
+//! keeping the current CPU and memory state.
+//!
+//! Snapshots taken before a peripheral is replaced store the state of the previous peripheral, so
+//! restoring them does not modify the state of the new peripheral. Take a new snapshot after
+//! reloading to reset the new peripheral along with the rest of the VM.
+
+use icicle_cpu::{
+    Cpu, Environment,
+    mem::{IoHandler, IoMemory, IoMemoryAny, MmioHandler},
+};
+
+use crate::Vm;
+
+impl Vm {
+    /// Replaces the I/O handler registered as `id` with `handler`, returning the previous handler.
+    /// Memory that is currently mapped to `id` is handled by `handler` from the next access.
+    pub fn reload_peripheral(
+        &mut self,
+        id: IoHandler,
+        handler: impl IoMemory + 'static,
+    ) -> Box<dyn IoMemoryAny> {
+        tracing::debug!("reloading peripheral: {id:?}");
+        self.cpu.mem.replace_io_handler(id, handler)
+    }
+
+    /// Replaces the peripheral mapped with [icicle_cpu::Mmu::map_mmio] as `id` with `handler`,
+    /// returning the previous handler.
+    pub fn reload_mmio_peripheral(
+        &mut self,
+        id: IoHandler,
+        handler: impl MmioHandler + 'static,
+    ) -> Box<dyn IoMemoryAny> {
+        tracing::debug!("reloading MMIO peripheral: {id:?}");
+        self.cpu.mem.replace_mmio(id, handler)
+    }
+
+    /// Calls `update` to reconfigure the MMIO peripheral registered as `id` in place, keeping any
+    /// state not modified by `update`. Returns `false` if `id` is not a peripheral of type `T`.
+    pub fn reconfigure_peripheral<T: MmioHandler + 'static>(
+        &mut self,
+        id: IoHandler,
+        update: impl FnOnce(&mut T),
+    ) -> bool {
+        match self.cpu.mem.get_mmio_handler_mut::<T>(id) {
+            Some(handler) => {
+                update(handler);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Calls `update` to reconfigure the environment of the VM in place. Returns `false` if the
+    /// environment is not of type `E`.
+    ///
+    /// The next timer of the environment is recomputed the next time the VM is run, so changes that
+    /// affect it (e.g. [crate::cortex_m::Config::poll_interval]) take effect immediately.
+    pub fn reconfigure_env<E: Environment + 'static>(
+        &mut self,
+        update: impl FnOnce(&mut E, &mut Cpu),
+    ) -> bool {
+        let Some(env) = self.env.as_mut_any().downcast_mut::<E>()
+        else {
+            return false;
+        };
+        update(env, &mut self.cpu);
+        true
+    }
+}
@@ -5,6 +5,8 @@ use icicle_cpu::{
     utils,
 };
 
+use crate::guest_log::{GuestLog, LogOrigin};
+
 pub struct RngMem {
     limit: Option<usize>,
     rng: utils::XorShiftRng,
@@ -99,3 +101,89 @@ impl DebugOutput {
         }
     }
 }
+
+/// The base address of the Instrumentation Trace Macrocell on ARMv7-M/ARMv8-M cores.
+pub const ITM_BASE: u64 = 0xe000_0000;
+
+/// The size of the ITM register block.
+pub const ITM_LEN: u64 = 0x1000;
+
+const ITM_TER: u64 = 0xe00;
+const ITM_TPR: u64 = 0xe40;
+const ITM_TCR: u64 = 0xe80;
+const ITM_LAR: u64 = 0xfb0;
+const ITM_LSR: u64 = 0xfb4;
+
+/// The `ITMENA` bit of the trace control register.
+const ITM_TCR_ITMENA: u32 = 0x1;
+
+/// Models the stimulus ports of the ARM Instrumentation Trace Macrocell (ITM).
+///
+/// Firmware that logs with `ITM_SendChar` (or writes to the stimulus ports directly) normally
+/// needs a debug probe capturing the SWO pin. Instead, bytes written to an enabled stimulus port
+/// are added to the guest log tagged with the port number. The ITM starts enabled with all ports
+/// enabled, as if a debugger had already configured tracing, and the stimulus ports always report
+/// that the FIFO is ready.
+pub struct Itm {
+    log: GuestLog,
+    regs: ItmRegs,
+}
+
+#[derive(Clone, Copy)]
+struct ItmRegs {
+    ter: u32,
+    tpr: u32,
+    tcr: u32,
+}
+
+impl Itm {
+    pub fn new(log: GuestLog) -> Self {
+        Self { log, regs: ItmRegs { ter: 0xffff_ffff, tpr: 0, tcr: ITM_TCR_ITMENA } }
+    }
+}
+
+impl IoMemory for Itm {
+    fn read(&mut self, addr: u64, buf: &mut [u8]) -> MemResult<()> {
+        let value: u32 = match addr & (ITM_LEN - 1) {
+            // Stimulus ports: bit 0 indicates that the port can accept more data.
+            0x0..=0x7f => 1,
+            ITM_TER => self.regs.ter,
+            ITM_TPR => self.regs.tpr,
+            ITM_TCR => self.regs.tcr,
+            // Lock mechanism implemented, but never locked.
+            ITM_LSR => 1,
+            _ => 0,
+        };
+        let bytes = value.to_le_bytes();
+        let len = buf.len().min(bytes.len());
+        buf[..len].copy_from_slice(&bytes[..len]);
+        Ok(())
+    }
+
+    fn write(&mut self, addr: u64, value: &[u8]) -> MemResult<()> {
+        let offset = addr & (ITM_LEN - 1);
+        match offset {
+            0x0..=0x7f => {
+                let port = (offset / 4) as u8;
+                let enabled = self.regs.tcr & ITM_TCR_ITMENA != 0;
+                if enabled && self.regs.ter & (1 << port) != 0 {
+                    self.log.write(&LogOrigin::Itm(port), value);
+                }
+            }
+            ITM_TER => self.regs.ter = utils::get_u64(value) as u32,
+            ITM_TPR => self.regs.tpr = utils::get_u64(value) as u32,
+            ITM_TCR => self.regs.tcr = utils::get_u64(value) as u32,
+            ITM_LAR => {}
+            _ => tracing::debug!("ignoring write to unsupported ITM register: {offset:#x}"),
+        }
+        Ok(())
+    }
+
+    fn snapshot(&mut self) -> Box<dyn Any> {
+        Box::new(self.regs)
+    }
+
+    fn restore(&mut self, snapshot: &Box<dyn Any>) {
+        self.regs = *snapshot.downcast_ref().unwrap();
+    }
+}
@@ -154,9 +154,22 @@ impl PathTracerRef {
         blocks.extend_from_slice(snapshot)
     }
 
+    /// Saves the blocks executed by the VM to `path`. If `path` has a `.zst` extension the trace is
+    /// saved in the compressed format defined in [crate::trace_file], otherwise each block is saved
+    /// as a line of text.
     pub fn save_trace(&self, vm: &mut Vm, path: &std::path::Path) -> anyhow::Result<()> {
         use std::io::Write;
 
+        if path.extension().is_some_and(|ext| ext == "zst") {
+            let file = std::io::BufWriter::new(std::fs::File::create(path)?);
+            let mut writer = crate::trace_file::TraceWriter::new(file)?;
+            for (addr, icount) in self.get_last_blocks(vm) {
+                writer.push(addr, icount)?;
+            }
+            writer.finish()?;
+            return Ok(());
+        }
+
         let mut output = std::io::BufWriter::new(std::fs::File::create(path)?);
         for (addr, icount) in self.get_last_blocks(vm) {
             writeln!(output, "{addr:#x},{icount}")?;
@@ -0,0 +1,126 @@
+//! Injection of interrupts at deterministic points during execution.
+//!
+//! Interrupts are queued on the VM with a trigger, either an instruction count or an address. When
+//! the trigger is reached the VM calls [Environment::raise_interrupt], which handles the
+//! architecture specific parts of delivering the interrupt (e.g. stacking registers and jumping to
+//! the handler in the vector table).
+//!
+//! The meaning of the interrupt number depends on the environment, see
+//! [crate::cortex_m::CortexM] and [crate::msp430::Msp430].
+//!
+//! [Environment::raise_interrupt]: icicle_cpu::Environment::raise_interrupt
+
+use std::collections::{BTreeMap, HashSet};
+
+use icicle_cpu::ExceptionCode;
+
+use crate::{Vm, VmExit};
+
+/// The point at which a queued interrupt is raised.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum InterruptTrigger {
+    /// Raise the interrupt once the instruction count reaches the value.
+    Icount(u64),
+
+    /// Raise the interrupt the next time execution reaches the address (before the instruction at
+    /// the address is executed).
+    Address(u64),
+}
+
+#[derive(Default)]
+pub struct InterruptQueue {
+    /// Interrupts triggered by instruction count as (icount, interrupt) pairs, sorted by icount.
+    by_icount: Vec<(u64, u64)>,
+
+    /// Interrupts triggered by address, in the order they were queued.
+    by_addr: BTreeMap<u64, Vec<u64>>,
+
+    /// Addresses where a breakpoint was armed to stop at an address trigger.
+    armed: HashSet<u64>,
+}
+
+impl InterruptQueue {
+    pub fn is_empty(&self) -> bool {
+        self.by_icount.is_empty() && self.by_addr.is_empty()
+    }
+
+    /// Returns the icount of the next interrupt triggered by instruction count.
+    pub fn next_icount(&self) -> u64 {
+        self.by_icount.first().map_or(u64::MAX, |(icount, _)| *icount)
+    }
+
+    /// Returns an iterator over all queued interrupts.
+    pub fn iter(&self) -> impl Iterator<Item = (InterruptTrigger, u64)> + '_ {
+        let by_icount = self.by_icount.iter().map(|(x, n)| (InterruptTrigger::Icount(*x), *n));
+        let by_addr = self
+            .by_addr
+            .iter()
+            .flat_map(|(addr, n)| n.iter().map(|n| (InterruptTrigger::Address(*addr), *n)));
+        by_icount.chain(by_addr)
+    }
+
+    /// Removes all interrupts that are due at `pc` and `icount`, in the order they were queued.
+    fn take_due(&mut self, pc: u64, icount: u64) -> Vec<u64> {
+        let due = self.by_icount.partition_point(|(x, _)| *x <= icount);
+        let mut interrupts: Vec<_> = self.by_icount.drain(..due).map(|(_, n)| n).collect();
+        if let Some(at_pc) = self.by_addr.remove(&pc) {
+            interrupts.extend(at_pc);
+        }
+        interrupts
+    }
+}
+
+impl Vm {
+    /// Queues `interrupt` to be raised in the environment when `trigger` is reached.
+    pub fn queue_interrupt(&mut self, trigger: InterruptTrigger, interrupt: u64) {
+        match trigger {
+            InterruptTrigger::Icount(icount) => {
+                let queue = &mut self.interrupts.by_icount;
+                let index = queue.partition_point(|(x, _)| *x <= icount);
+                queue.insert(index, (icount, interrupt));
+                self.next_timer = self.next_timer.min(icount);
+            }
+            InterruptTrigger::Address(addr) => {
+                if self.arm_breakpoint(addr) {
+                    self.interrupts.armed.insert(addr);
+                }
+                self.interrupts.by_addr.entry(addr).or_default().push(interrupt);
+            }
+        }
+    }
+
+    /// Removes all queued interrupts that have not been raised yet.
+    pub fn clear_queued_interrupts(&mut self) {
+        for addr in std::mem::take(&mut self.interrupts.armed) {
+            self.disarm_breakpoint(addr);
+        }
+        self.interrupts.by_icount.clear();
+        self.interrupts.by_addr.clear();
+    }
+
+    /// Returns the interrupts that are currently queued.
+    pub fn queued_interrupts(&self) -> &InterruptQueue {
+        &self.interrupts
+    }
+
+    /// Raises any queued interrupts that are due at the current location. Returns an exit if the
+    /// environment does not support one of the interrupts.
+    pub(crate) fn raise_queued_interrupts(&mut self) -> Option<VmExit> {
+        let pc = self.cpu.read_pc();
+        let due = self.interrupts.take_due(pc, self.cpu.icount);
+        if self.interrupts.armed.remove(&pc) {
+            self.disarm_breakpoint(pc);
+        }
+
+        for interrupt in due {
+            tracing::debug!("[{}] raising interrupt {interrupt} at {pc:#x}", self.cpu.icount);
+            if !self.env.raise_interrupt(&mut self.cpu, interrupt) {
+                return Some(VmExit::UnhandledException((
+                    ExceptionCode::UnknownInterrupt,
+                    interrupt,
+                )));
+            }
+        }
+        None
+    }
+}
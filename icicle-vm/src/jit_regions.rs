@@ -0,0 +1,125 @@
+//! Support for guests that generate code at runtime (e.g. interpreters with a JIT compiler).
+//!
+//! By default, modifying code that has already been translated stops the VM with
+//! [ExceptionCode::SelfModifyingCode](icicle_cpu::ExceptionCode::SelfModifyingCode). Guests that
+//! legitimately generate code can instead register the regions of memory they generate code in:
+//! writes to a registered region are always allowed, and whenever the region is registered (again)
+//! any existing translations of the region are invalidated, and the entry points of the new code
+//! are lifted immediately rather than on first execution.
+//!
+//! Regions can be registered directly with [Vm::register_jit_region], or from a hook (which does
+//! not have access to the VM) with [JitRegionQueue::register]. For guests with a function that is
+//! called after new code is generated (e.g. `__clear_cache(start, end)`, or a hypercall added to
+//! the guest), [hook_notifier] registers the code passed to the function.
+//!
+//! Note: code in a registered region that is modified without registering the region again will
+//! continue to execute the stale translation.
+
+use std::{cell::RefCell, rc::Rc};
+
+use crate::{
+    Vm,
+    function_hooks::{FunctionCall, hook_function},
+};
+
+/// A region of memory that the guest has generated code in.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct JitRegion {
+    pub start: u64,
+    pub len: u64,
+
+    /// The addresses of code in the region that is likely to be executed.
+    pub entries: Vec<u64>,
+}
+
+/// A queue of regions to register with the VM the next time it exits. Cloning the queue produces a
+/// handle to the same queue.
+#[derive(Clone, Default)]
+pub struct JitRegionQueue {
+    pending: Rc<RefCell<Vec<JitRegion>>>,
+}
+
+impl JitRegionQueue {
+    /// Queues `region` to be registered with [Vm::register_jit_region].
+    ///
+    /// Note: the region is only registered once the VM exits, so hooks that queue a region should
+    /// also cause the VM to exit (e.g. by raising [ExceptionCode::ExternalAddr]) before the new
+    /// code is executed.
+    ///
+    /// [ExceptionCode::ExternalAddr]: icicle_cpu::ExceptionCode::ExternalAddr
+    pub fn register(&self, region: JitRegion) {
+        self.pending.borrow_mut().push(region);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.borrow().is_empty()
+    }
+
+    fn take(&self) -> Vec<JitRegion> {
+        std::mem::take(&mut *self.pending.borrow_mut())
+    }
+}
+
+impl Vm {
+    /// Registers `start..start+len` as memory that the guest generates code in, invalidating any
+    /// existing translations of the region and lifting the code at each address in `entries`.
+    /// Returns the number of entries that were lifted.
+    ///
+    /// Note: the region is specified in bytes, while `entries` are code addresses, these only
+    /// differ for word addressed targets (e.g. AVR).
+    pub fn register_jit_region(&mut self, start: u64, len: u64, entries: &[u64]) -> usize {
+        if len == 0 {
+            return 0;
+        }
+        let end = start.saturating_add(len - 1);
+        tracing::debug!("JIT region registered: {start:#x}..={end:#x}");
+
+        self.cpu.mem.add_dynamic_code_region(start, end);
+        self.invalidate_memory_range(start, len);
+
+        let mut lifted = 0;
+        for &addr in entries {
+            match self.prelift_addr(addr) {
+                Ok(true) => lifted += 1,
+                Ok(false) => {}
+                Err(e) => tracing::debug!("failed to lift JIT code at {addr:#x}: {e:?}"),
+            }
+        }
+        lifted
+    }
+
+    /// Removes a region registered with [Vm::register_jit_region], re-enabling self-modifying code
+    /// detection for code that is translated from the region in the future.
+    pub fn unregister_jit_region(&mut self, start: u64, len: u64) -> bool {
+        if len == 0 {
+            return false;
+        }
+        let end = start.saturating_add(len - 1);
+        self.invalidate_memory_range(start, len);
+        self.cpu.mem.remove_dynamic_code_region(start, end)
+    }
+
+    /// Registers any regions queued with [JitRegionQueue::register].
+    pub(crate) fn register_queued_jit_regions(&mut self) {
+        for region in self.jit_regions.take() {
+            self.register_jit_region(region.start, region.len, &region.entries);
+        }
+    }
+}
+
+/// Hooks the guest function at `addr`, which is called with the `(start, end)` address of newly
+/// generated code, registering the code as a [JitRegion] with `start` as the entry point.
+///
+/// The original function is skipped (returning 0), which also ensures that the region is
+/// registered before the guest continues.
+pub fn hook_notifier(vm: &mut Vm, addr: u64) {
+    let queue = vm.jit_regions.clone();
+    hook_function(vm, addr, move |call: &mut FunctionCall| {
+        let start: u64 = call.arg(0);
+        let end: u64 = call.arg(1);
+        if start < end {
+            queue.register(JitRegion { start, len: end - start, entries: vec![start] });
+        }
+        call.skip(0_u64);
+    });
+}
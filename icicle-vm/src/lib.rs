@@ -1,10 +1,55 @@
+pub mod analytics;
+pub mod annotations;
+pub mod asan;
+pub mod avr;
+pub mod boot_trace;
+pub mod breakpoints;
 mod builder;
+pub mod busy_wait;
+pub mod compose;
+pub mod core_dump;
+pub mod coverage_export;
+pub mod cortex_m;
 pub mod debug;
+pub mod discovery;
+pub mod drcov;
 pub mod elf_dump;
 pub mod env;
+pub mod fork;
+pub mod function_hooks;
+pub mod guest_log;
+pub mod heap_sanitizer;
+pub mod hot_reload;
 pub mod hw;
 pub mod injector;
+pub mod interrupts;
+pub mod jit_regions;
+pub mod libc_models;
+pub mod lift_coverage;
+pub mod loops;
+pub mod ltrace;
+pub mod memory_dump;
+pub mod memory_hooks;
+pub mod mmio_input;
 pub mod msp430;
+pub mod provenance;
+pub mod record;
+pub mod reg_hooks;
+pub mod run_control;
+pub mod secret;
+pub mod shadow;
+pub mod sleigh_cache;
+pub mod snapshot_file;
+pub mod snapshot_tree;
+pub mod source_trace;
+pub mod stack_sanitizer;
+pub mod taint;
+pub mod tenet;
+pub mod trace_file;
+pub mod translation_cache;
+pub mod unreachable;
+pub mod windows;
+pub mod wx_audit;
 
 #[cfg(test)]
 mod tests;
@@ -14,13 +59,15 @@ pub use icicle_cpu::VmExit;
 pub use icicle_linux as linux;
 
 pub use crate::{
-    builder::{BuildError, build, build_with_path, sleigh_init, x86},
+    builder::{
+        BuildError, ProcessorSpec, build, build_with_path, build_with_processor, sleigh_init, x86,
+    },
     injector::{CodeInjector, InjectorRef},
 };
 pub use icicle_cpu::BlockTable;
 
 use std::{
-    collections::{BTreeMap, HashSet},
+    collections::{BTreeMap, HashMap, HashSet},
     rc::Rc,
 };
 
@@ -48,6 +95,9 @@ pub struct Vm {
     pub enable_recompilation: bool,
     prev_isa_mode: u8,
 
+    /// Controls how the VM responds to code being modified.
+    pub smc_policy: cpu::SmcPolicy,
+
     /// The number of new blocks that have been compiled since the last full recompilation step.
     pub compiled_blocks: u64,
 
@@ -61,6 +111,57 @@ pub struct Vm {
 
     /// Snapshots at different icounts for reverse execution.
     snapshots: BTreeMap<u64, Rc<Snapshot>>,
+
+    /// Collects all textual output produced by the guest (if enabled), cleared when the VM is
+    /// restored.
+    pub guest_log: guest_log::GuestLog,
+
+    /// ASAN support for the current binary, set by [asan::attach].
+    pub asan: Option<asan::Asan>,
+
+    /// The heap sanitizer attached to the VM, set by [heap_sanitizer::attach].
+    pub heap_sanitizer: Option<heap_sanitizer::HeapSanitizer>,
+
+    /// The stack sanitizer attached to the VM, set by [stack_sanitizer::attach].
+    pub stack_sanitizer: Option<stack_sanitizer::StackSanitizer>,
+
+    /// The pointer-provenance checker attached to the VM, set by [provenance::attach].
+    pub provenance: Option<provenance::ProvenanceChecker>,
+
+    /// The W^X auditor attached to the VM, set by [wx_audit::attach].
+    pub wx_audit: Option<wx_audit::WxAudit>,
+
+    /// Facts derived about the guest code by analyses, see [annotations].
+    pub annotations: annotations::Annotations,
+
+    /// The active recording, see [Vm::start_recording].
+    pub recording: Option<record::Recording>,
+
+    /// A persistent cache of lifted code, see [Vm::load_translation_cache].
+    pub translation_cache: Option<translation_cache::TranslationCache>,
+
+    /// The number of lifter hooks registered when the VM was built, see
+    /// [translation_cache::LifterHooks].
+    pub(crate) builtin_lifter_hooks: translation_cache::LifterHooks,
+
+    /// Metadata for breakpoints added by [Vm::add_breakpoint] or [Vm::set_breakpoint].
+    breakpoint_info: BTreeMap<u64, breakpoints::Breakpoint>,
+
+    /// Controls whether the targets of newly discovered code are queued for [Vm::prelift].
+    pub enable_prelift: bool,
+
+    /// Tracks newly discovered code and code lifted ahead of execution, see [discovery].
+    pub discovery: discovery::CodeDiscovery,
+
+    /// Interrupts queued by [Vm::queue_interrupt] that have not been raised yet.
+    interrupts: interrupts::InterruptQueue,
+
+    /// Regions of generated code that are registered the next time the VM exits, see
+    /// [jit_regions].
+    pub jit_regions: jit_regions::JitRegionQueue,
+
+    /// Additional state that is saved and restored with the VM, see [Vm::add_snapshot_hook].
+    snapshot_hooks: Vec<Box<dyn SnapshotHook>>,
 }
 
 impl Drop for Vm {
@@ -86,11 +187,28 @@ impl Vm {
             enable_jit: true,
             enable_recompilation: true,
             prev_isa_mode: u8::MAX,
+            smc_policy: cpu::SmcPolicy::Exit,
 
             compiled_blocks: 0,
             last_recompile: std::time::Instant::now(),
             recompile_offset: 0,
             snapshots: BTreeMap::new(),
+            guest_log: guest_log::GuestLog::disabled(),
+            asan: None,
+            heap_sanitizer: None,
+            stack_sanitizer: None,
+            provenance: None,
+            wx_audit: None,
+            annotations: annotations::Annotations::new(),
+            recording: None,
+            translation_cache: None,
+            builtin_lifter_hooks: translation_cache::LifterHooks::default(),
+            breakpoint_info: BTreeMap::new(),
+            enable_prelift: false,
+            discovery: discovery::CodeDiscovery::default(),
+            interrupts: interrupts::InterruptQueue::default(),
+            jit_regions: jit_regions::JitRegionQueue::default(),
+            snapshot_hooks: Vec::new(),
         }
     }
 
@@ -109,6 +227,37 @@ impl Vm {
         self.env.as_mut_any().downcast_mut::<T>()
     }
 
+    /// Gets the report for the error detected by a sanitizer attached to the VM (see [asan],
+    /// [heap_sanitizer], [stack_sanitizer] and [provenance]) since the VM was last restored.
+    ///
+    /// Note: the VM exits at the first error detected by a sanitizer, so (unless execution is
+    /// resumed after an error without restoring a snapshot) at most one sanitizer has a report.
+    pub fn sanitizer_report(&self) -> Option<asan::AsanReport> {
+        self.asan
+            .as_ref()
+            .and_then(|x| x.last_report())
+            .or_else(|| self.heap_sanitizer.as_ref()?.last_report())
+            .or_else(|| self.stack_sanitizer.as_ref()?.last_report())
+            .or_else(|| self.provenance.as_ref()?.last_report())
+    }
+
+    /// Discards the reports of all sanitizers attached to the VM, so that errors detected in an
+    /// earlier execution are not returned by [Vm::sanitizer_report].
+    pub fn clear_sanitizer_reports(&mut self) {
+        if let Some(asan) = self.asan.as_ref() {
+            asan.take_report();
+        }
+        if let Some(heap) = self.heap_sanitizer.as_ref() {
+            heap.take_report();
+        }
+        if let Some(stack) = self.stack_sanitizer.as_ref() {
+            stack.take_report();
+        }
+        if let Some(provenance) = self.provenance.as_ref() {
+            provenance.take_report();
+        }
+    }
+
     /// Registers a [CodeInjector] in the VM which is invoked whenever the emulator lifts a new
     /// block of code.
     ///
@@ -168,8 +317,21 @@ impl Vm {
         true
     }
 
+    /// Registers a function `callback` that is called with every newly lifted group of blocks
+    /// before block level optimizations and code generation. This allows custom instrumentation to
+    /// be added to the p-code of the guest (e.g. counters or shadow memory operations).
+    ///
+    /// Note: like injectors, the callback is only executed on newly lifted blocks.
+    pub fn add_lift_callback(
+        &mut self,
+        callback: impl FnMut(&lifter::BlockGroup, &mut BlockTable) + 'static,
+    ) {
+        self.lifter.lift_callbacks.push(Box::new(callback));
+    }
+
     /// Runs the VM until it encounters an exit condition.
     pub fn run(&mut self) -> VmExit {
+        self.invalidate_wx_writable_code();
         if self.should_recompile() && self.enable_recompilation {
             self.recompile();
         }
@@ -226,9 +388,51 @@ impl Vm {
     }
 
     fn handle_exception(&mut self) -> VmExit {
-        if let Some(exit) = self.env.handle_exception(&mut self.cpu) {
+        if !self.jit_regions.is_empty() {
+            self.register_queued_jit_regions();
+        }
+
+        let (pc, code, value, icount) = (
+            self.cpu.read_pc(),
+            self.cpu.exception.code,
+            self.cpu.exception.value,
+            self.cpu.icount,
+        );
+        if !self.interrupts.is_empty()
+            && matches!(
+                ExceptionCode::from_u32(code),
+                ExceptionCode::None | ExceptionCode::InstructionLimit
+            )
+        {
+            if let Some(exit) = self.raise_queued_interrupts() {
+                return exit;
+            }
+        }
+        if let Some(sanitizer) = self.heap_sanitizer.clone() {
+            sanitizer.on_exception(self);
+        }
+        let recording = self.recording.is_some();
+        if recording {
+            self.cpu.mem.start_write_log();
+        }
+        let exit = self.env.handle_exception(&mut self.cpu);
+        self.invalidate_wx_writable_code();
+        if recording {
+            // Only record exceptions that the environment handled.
+            let written = self.cpu.mem.take_write_log();
+            if exit.is_some()
+                || !written.is_empty()
+                || (self.cpu.exception.code, self.cpu.exception.value) != (code, value)
+            {
+                self.record_env_event(pc, code, value, icount, written);
+            }
+        }
+        if let Some(exit) = exit {
             return exit;
         }
+        // Handling the exception may have changed when the environment next needs to run (e.g.
+        // when switching to a different thread), even if execution continues at a new address.
+        self.update_timer();
 
         let code = ExceptionCode::from_u32(self.cpu.exception.code);
         tracing::trace!("{code:?}: icount={}, next_timer={}", self.cpu.icount, self.next_timer);
@@ -240,13 +444,22 @@ impl Vm {
                 if self.cpu.icount >= self.icount_limit {
                     return VmExit::InstructionLimit;
                 }
-                if self.code.breakpoints.contains(&self.cpu.read_pc()) {
-                    return VmExit::Breakpoint;
+                let pc = self.cpu.read_pc();
+                if self.code.breakpoints.contains(&pc) {
+                    match self.on_breakpoint_hit(pc) {
+                        VmExit::Running => {}
+                        exit => return exit,
+                    }
+                }
+                if self.recording.is_some() {
+                    self.maybe_checkpoint();
                 }
                 self.update_timer();
                 VmExit::Running
             }
             ExceptionCode::SoftwareBreakpoint => VmExit::Breakpoint,
+            ExceptionCode::LoopLimit => VmExit::LoopLimit(self.cpu.exception.value),
+            ExceptionCode::Unreachable => VmExit::Unreachable(self.cpu.exception.value),
 
             ExceptionCode::ExternalAddr => self.handle_external_address(self.cpu.exception.value),
             ExceptionCode::CodeNotTranslated => self.handle_code_not_translated(),
@@ -258,11 +471,47 @@ impl Vm {
                 VmExit::UnhandledException((code, self.cpu.exception.value))
             }
             ExceptionCode::Halt | ExceptionCode::Sleep => VmExit::Halt,
+            ExceptionCode::CpuStateChanged => {
+                // Raised for register writes the environment asked to be notified of, which
+                // environments that do not model the register ignore.
+                self.cpu.exception.clear();
+                VmExit::Running
+            }
             ExceptionCode::OutOfMemory => VmExit::OutOfMemory,
+            ExceptionCode::InvalidateICache => self.handle_icache_invalidate(),
+            ExceptionCode::AddressSpaceReplaced => self.handle_address_space_replaced(),
             code => VmExit::UnhandledException((code, self.cpu.exception.value)),
         }
     }
 
+    fn handle_icache_invalidate(&mut self) -> VmExit {
+        /// The granularity used for invalidating code by address. This is conservatively larger
+        /// than the cache line size of most targets.
+        const ICACHE_LINE_SIZE: u64 = 64;
+
+        if self.smc_policy == cpu::SmcPolicy::InvalidateOnHint {
+            match self.cpu.exception.value {
+                lifter::pcodeops::INVALIDATE_ALL => self.invalidate_code_range(0, u64::MAX),
+                addr => {
+                    self.invalidate_memory_range(addr & !(ICACHE_LINE_SIZE - 1), ICACHE_LINE_SIZE)
+                }
+            }
+        }
+        self.cpu.exception.clear();
+        self.cpu.resume_next();
+        VmExit::Running
+    }
+
+    /// Called when the environment has replaced the entire address space of the guest (e.g. as part
+    /// of `execve`), so none of the existing translations are valid.
+    fn handle_address_space_replaced(&mut self) -> VmExit {
+        let entry = self.cpu.exception.value;
+        self.invalidate_code_range(0, u64::MAX);
+        self.prev_isa_mode = u8::MAX;
+        self.cpu.exception.clear();
+        self.handle_external_address(entry)
+    }
+
     fn handle_external_address(&mut self, addr: u64) -> VmExit {
         self.cpu.write_pc(addr);
 
@@ -293,8 +542,13 @@ impl Vm {
             ));
         }
 
-        match self.lift(pc) {
+        let lifted = match self.activate_prelifted(key) {
+            Some(group) => Ok(group),
+            None => self.lift(pc),
+        };
+        match lifted {
             Ok(group) => {
+                self.code_discovered(group);
                 self.cpu.block_id = group.blocks.0 as u64;
                 self.cpu.block_offset = 0;
                 VmExit::Running
@@ -355,8 +609,13 @@ impl Vm {
 
         let user_exit = self.icount_limit;
         let env_exit = self.env.next_timer();
-        self.next_timer =
-            user_exit.min(env_exit).min(CHECK_FOR_INTERRUPT_FLAG_TIMER + self.cpu.icount);
+        let checkpoint = self.recording.as_ref().map_or(u64::MAX, |x| x.next_checkpoint);
+        let interrupt = self.interrupts.next_icount();
+        self.next_timer = user_exit
+            .min(env_exit)
+            .min(checkpoint)
+            .min(interrupt)
+            .min(CHECK_FOR_INTERRUPT_FLAG_TIMER + self.cpu.icount);
     }
 
     #[cold]
@@ -565,6 +824,66 @@ impl Vm {
         }
     }
 
+    /// Rolls back the code cache to match `snapshot`, keeping translations created after the
+    /// snapshot if the memory they were lifted from is unchanged in the snapshot.
+    fn restore_code(&mut self, snapshot: &Snapshot) {
+        let mem = &self.cpu.mem;
+        let is_unchanged = |group: &lifter::BlockGroup| {
+            let mut page = mem.page_aligned(group.start);
+            while page <= group.end {
+                if !mem.is_page_unchanged(&snapshot.mem, page) {
+                    return false;
+                }
+                match page.checked_add(mem.page_size()) {
+                    Some(next) => page = next,
+                    None => break,
+                }
+            }
+            true
+        };
+        let removed = self.code.restore(&snapshot.code, is_unchanged);
+        self.discovery.retain_pending(is_unchanged);
+        for group in removed {
+            group.range().for_each(|id| self.jit.invalidate(id));
+            self.code.disasm.retain(|addr, _| !(group.start <= *addr && *addr < group.end));
+        }
+    }
+
+    /// Invalidates any translations of code stored in memory at `start..start+len`. Unlike
+    /// [Vm::invalidate_code_range], the range is in bytes, even on word addressed targets (e.g.
+    /// AVR).
+    pub fn invalidate_memory_range(&mut self, start: u64, len: u64) {
+        let word_size = self.cpu.arch.sleigh.word_size();
+        let end = start.saturating_add(len);
+        self.invalidate_code_range(start / word_size, end.div_ceil(word_size) - start / word_size);
+    }
+
+    /// Removes any translated code that overlaps with `start..start+len`, causing it to be lifted
+    /// again the next time it is executed.
+    pub fn invalidate_code_range(&mut self, start: u64, len: u64) {
+        let end = start.saturating_add(len.saturating_sub(1));
+        self.annotations.invalidate_range(start, end);
+        self.discovery.retain_pending(|group| !(group.start <= end && start <= group.end));
+        let removed = self.code.retain(|group| !(group.start <= end && start <= group.end));
+        // The new code may decode to different instructions, which would otherwise be reported as
+        // self-modifying code when it is lifted.
+        self.code.disasm.retain(|addr, _| !(start <= *addr && *addr <= end));
+        for group in removed {
+            group.range().for_each(|id| self.jit.invalidate(id));
+            for block in &self.code.blocks[group.range()] {
+                let target = annotations::AnnotationTarget::Block(block.start);
+                self.annotations.remove_target(target);
+            }
+        }
+    }
+
+    /// Reseeds the generator used for all entropy sources visible to the guest (e.g. `getrandom`,
+    /// `/dev/urandom`, `RDRAND`), making subsequent random values a deterministic function of
+    /// `seed`.
+    pub fn reseed_entropy(&mut self, seed: u64) {
+        self.cpu.entropy.reseed(seed);
+    }
+
     pub fn reset(&mut self) {
         self.cpu.reset();
         self.cpu.mem.clear();
@@ -584,11 +903,71 @@ impl Vm {
         self.code.get_info(key)
     }
 
+    /// Returns statistics about the code generated for the active group at `addr`.
+    pub fn get_block_stats(&self, addr: u64) -> Option<cpu::BlockGroupStats> {
+        let mut stats = self.get_block_info(addr)?.stats();
+        stats.host_code_bytes = self.jit.code_size(addr);
+        Some(stats)
+    }
+
+    /// Returns the start address and statistics for all active groups (in the current ISA mode),
+    /// ordered by address.
+    pub fn all_block_stats(&self) -> Vec<(u64, cpu::BlockGroupStats)> {
+        let isa_mode = self.cpu.isa_mode() as u64;
+        let mut stats: Vec<_> = self
+            .code
+            .map
+            .keys()
+            .filter(|key| key.isa_mode == isa_mode)
+            .filter_map(|key| Some((key.vaddr, self.get_block_stats(key.vaddr)?)))
+            .collect();
+        stats.sort_by_key(|(addr, _)| *addr);
+        stats
+    }
+
     pub fn lift(&mut self, addr: u64) -> Result<lifter::BlockGroup, DecodeError> {
+        let key = self.get_block_key(addr);
+        let group = self.lift_unmapped(addr)?;
+        self.code.insert(key, group);
+
+        tracing::trace!(
+            "lifted: {key:x?} => {}",
+            group.to_string(&self.code.blocks, &self.cpu.arch.sleigh, false).unwrap()
+        );
+
+        Ok(group)
+    }
+
+    /// Lifts the code at `addr` without making it active.
+    fn lift_unmapped(&mut self, addr: u64) -> Result<lifter::BlockGroup, DecodeError> {
         self.update_context();
 
-        let mut ctx = lifter::Context::new(&mut *self.cpu, &mut self.code, addr);
-        let group = self.lifter.lift_block(&mut ctx)?;
+        let key = self.get_block_key(addr);
+        // Blocks split by the lifting time limit depend on host timing, so must not be cached.
+        let cacheable = translation_cache::LifterHooks::of(self) == self.builtin_lifter_hooks
+            && self.lifter.settings.max_lift_time.is_none();
+        let cache = self.translation_cache.as_mut().filter(|_| cacheable);
+        let cached = match cache {
+            Some(cache) => cache.restore(key, &mut self.cpu, &mut self.code),
+            None => None,
+        };
+        let group = match cached {
+            Some(mut group) => {
+                if self.lifter.settings.recover_jump_tables {
+                    group.jump_table_targets =
+                        lifter::jump_table::recover(&mut *self.cpu, &mut self.code, group.blocks);
+                }
+                group
+            }
+            None => {
+                let mut ctx = lifter::Context::new(&mut *self.cpu, &mut self.code, addr);
+                let group = self.lifter.lift_block(&mut ctx)?;
+                if let Some(cache) = self.translation_cache.as_mut().filter(|_| cacheable) {
+                    cache.insert(key, &mut self.cpu, &self.code, group);
+                }
+                group
+            }
+        };
 
         // Add breakpoints to the lifted code.
         if self.code.breakpoints.len() > 0 {
@@ -626,14 +1005,6 @@ impl Vm {
             self.jit.invalidate(id);
         }
 
-        let key = self.get_block_key(addr);
-        self.code.map.insert(key, group);
-
-        tracing::trace!(
-            "lifted: {key:x?} => {}",
-            group.to_string(&self.code.blocks, &self.cpu.arch.sleigh, false).unwrap()
-        );
-
         Ok(group)
     }
 
@@ -743,6 +1114,11 @@ impl Vm {
         // @fixme: this is broken if a jump performs a context switch.
         let isa_mode = self.cpu.isa_mode() as u64;
 
+        let mut jump_tables: HashMap<usize, Vec<u64>> = HashMap::new();
+        for (id, target) in &self.code.jump_table_targets {
+            jump_tables.entry(*id).or_default().push(*target);
+        }
+
         for (i, block) in self.code.blocks.iter().enumerate().skip(self.recompile_offset) {
             let entry = match block.entry {
                 Some(entry) => entry,
@@ -771,6 +1147,9 @@ impl Vm {
                     _ => {}
                 };
 
+                for addr in jump_tables.get(&id).into_iter().flatten() {
+                    add_target(&Target::External(pcode::Value::Const(*addr, 8)));
+                }
                 match block.exit {
                     lifter::BlockExit::Jump { target } => add_target(&target),
                     lifter::BlockExit::Branch { target, fallthrough, .. } => {
@@ -910,18 +1289,34 @@ impl Vm {
     }
 
     pub fn run_until(&mut self, addr: u64) -> VmExit {
-        let added_bp = self.add_breakpoint(addr);
+        let added_bp = self.arm_breakpoint(addr);
         let exit = self.run();
         if added_bp {
-            self.remove_breakpoint(addr);
+            self.disarm_breakpoint(addr);
         }
         exit
     }
 
     /// Adds a breakpoint at `addr`.
     ///
-    /// Returns a boolean representing whether a new breakpoint was added.
+    /// Returns a boolean representing whether a new breakpoint was added. If the VM already stops
+    /// at `addr`, only the metadata for the breakpoint is updated.
     pub fn add_breakpoint(&mut self, addr: u64) -> bool {
+        let added = self.arm_breakpoint(addr);
+        self.breakpoint_info.entry(addr).or_default().enabled = true;
+        added
+    }
+
+    /// Removes the breakpoint at `addr`.
+    ///
+    /// Returns a boolean representing whether a breakpoint was remove.
+    pub fn remove_breakpoint(&mut self, addr: u64) -> bool {
+        let had_info = self.breakpoint_info.remove(&addr).is_some();
+        self.disarm_breakpoint(addr) || had_info
+    }
+
+    /// Makes the VM stop at `addr` (without updating the metadata for the breakpoint).
+    pub(crate) fn arm_breakpoint(&mut self, addr: u64) -> bool {
         if !self.code.breakpoints.insert(addr) {
             // There is already a breakpoint at the target address.
             return false;
@@ -937,10 +1332,9 @@ impl Vm {
         true
     }
 
-    /// Removes the breakpoint at `addr`.
-    ///
-    /// Returns a boolean representing whether a breakpoint was remove.
-    pub fn remove_breakpoint(&mut self, addr: u64) -> bool {
+    /// Removes `addr` from the set of addresses the VM stops at (without updating the metadata for
+    /// the breakpoint).
+    pub(crate) fn disarm_breakpoint(&mut self, addr: u64) -> bool {
         if !self.code.breakpoints.remove(&addr) {
             // The breakpoint we are trying to remove does not exist.
             return false;
@@ -980,6 +1374,14 @@ impl Vm {
             .unwrap_or_else(|| vec![self.cpu.read_pc()])
     }
 
+    /// Computes the call stack of the guest (innermost frame first) by unwinding the current
+    /// register state using the `.eh_frame`/`.debug_frame` sections of the loaded objects. Unlike
+    /// [Vm::get_callstack] this does not depend on the shadow stack, so it is accurate for code that
+    /// uses `setjmp`/`longjmp` or hand-written assembly.
+    pub fn backtrace(&mut self) -> Vec<debug::BacktraceFrame> {
+        debug::unwind_backtrace(self, 256)
+    }
+
     pub fn save_snapshot(&mut self) {
         let snapshot = Rc::new(self.snapshot());
         self.snapshots.insert(self.cpu.icount(), snapshot);
@@ -990,13 +1392,24 @@ impl Vm {
             cpu: self.cpu.snapshot(),
             mem: self.cpu.mem.snapshot(),
             env: self.env.snapshot(),
+            code: self.code.snapshot(),
+            hooks: self.snapshot_hook_state(),
         }
     }
 
     pub fn restore(&mut self, snapshot: &Snapshot) {
+        // Must be done before restoring memory, to compare the memory that code was lifted from
+        // with the memory in the snapshot.
+        self.restore_code(snapshot);
+
         self.cpu.restore(&snapshot.cpu);
-        self.cpu.mem.restore(snapshot.mem.clone());
+        // Repeatedly restoring the same snapshot only needs to reset the pages modified since the
+        // last restore.
+        self.cpu.mem.restore_dirty_only(&snapshot.mem);
         self.env.restore(&snapshot.env);
+        self.restore_hook_state(&snapshot.hooks);
+        self.clear_sanitizer_reports();
+        self.guest_log.clear();
         self.update_context();
 
         tracing::trace!(
@@ -1006,10 +1419,41 @@ impl Vm {
             self.cpu.block_offset
         );
     }
+
+    /// Registers `hook` to save and restore state kept outside of the VM (e.g. by instrumentation)
+    /// whenever the VM is snapshotted and restored (including by [Vm::fork] and [Vm::switch_to]).
+    ///
+    /// Note: snapshots taken before the hook was added do not include its state, so restoring them
+    /// leaves the state of the hook unchanged.
+    pub fn add_snapshot_hook(&mut self, hook: impl SnapshotHook + 'static) {
+        self.snapshot_hooks.push(Box::new(hook));
+    }
+
+    pub(crate) fn snapshot_hook_state(&mut self) -> Vec<Box<dyn std::any::Any>> {
+        self.snapshot_hooks.iter_mut().map(|hook| hook.snapshot()).collect()
+    }
+
+    pub(crate) fn restore_hook_state(&mut self, state: &[Box<dyn std::any::Any>]) {
+        for (hook, state) in self.snapshot_hooks.iter_mut().zip(state) {
+            hook.restore(state);
+        }
+    }
+}
+
+/// State kept outside of the CPU, memory and environment that is saved and restored with the VM,
+/// see [Vm::add_snapshot_hook].
+pub trait SnapshotHook {
+    /// Creates a snapshot of the current state which can be restored later.
+    fn snapshot(&mut self) -> Box<dyn std::any::Any>;
+
+    /// Restores the state to the state of the snapshot.
+    fn restore(&mut self, snapshot: &Box<dyn std::any::Any>);
 }
 
 pub struct Snapshot {
     pub cpu: Box<CpuSnapshot>,
     pub mem: mem::Snapshot,
     pub env: Box<dyn std::any::Any>,
+    pub code: cpu::BlockTableSnapshot,
+    pub hooks: Vec<Box<dyn std::any::Any>>,
 }
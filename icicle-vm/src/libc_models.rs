@@ -0,0 +1,509 @@
+//! High-level models of common libc functions.
+//!
+//! When attached, calls to the modelled functions are handled directly by the emulator instead of
+//! executing the guest implementation. This is significantly faster for hot functions like
+//! `memcpy` or `strcmp`, and the heap model provides introspection of all allocations (e.g. for
+//! detecting use-after-free bugs).
+//!
+//! Functions are typically hooked at their PLT entries (see [crate::ltrace::plt_entries]), but any
+//! address that is the entry point of a function with the standard calling convention works.
+//!
+//! If a model encounters an invalid memory access it falls back to the guest implementation, so
+//! the crash is reported at the same location as it would be without the model.
+
+use std::{cell::RefCell, collections::BTreeMap, io::Write, rc::Rc};
+
+use icicle_cpu::{
+    Cpu, Exception, ExceptionCode,
+    mem::{AllocLayout, Mapping, MemResult, perm},
+};
+
+use crate::{Vm, guest_log::LogOrigin, provenance::AllocatorModel};
+
+/// The maximum length of strings read by the string models.
+const MAX_STRING_LEN: u64 = 0x10_0000;
+
+/// Allocations are aligned to this value, and separated by at least this many bytes.
+const HEAP_ALIGN: u64 = 16;
+
+/// The size of the buffer used by the memory models, the guest controls the length of each
+/// operation so it is processed in chunks of this size.
+const CHUNK_SIZE: usize = 0x1000;
+
+#[derive(Clone, Copy, Debug)]
+pub struct LibcModelOptions {
+    /// Model `malloc`, `calloc`, `realloc` and `free`.
+    pub heap: bool,
+
+    /// Model `memcpy`, `memmove`, `memset` and `memcmp`.
+    pub memory: bool,
+
+    /// Model `strlen`, `strcmp` and `strncmp`.
+    pub strings: bool,
+
+    /// Model `printf` and `puts`, writing the output to the guest log (see [crate::guest_log])
+    /// instead of the guest's stdout.
+    pub printf: bool,
+
+    /// The size of the memory region reserved for the heap model.
+    pub heap_size: u64,
+}
+
+impl Default for LibcModelOptions {
+    fn default() -> Self {
+        Self { heap: true, memory: true, strings: true, printf: false, heap_size: 0x400_0000 }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HeapError {
+    /// The pointer passed to `free` or `realloc` was not returned by the allocator.
+    InvalidFree(u64),
+
+    /// The pointer passed to `free` or `realloc` has already been freed.
+    DoubleFree(u64),
+}
+
+/// Metadata for an allocation made by the heap model.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Allocation {
+    pub addr: u64,
+    pub size: u64,
+    pub freed: bool,
+}
+
+/// A simple bump allocator that never reuses memory, so that every access to freed memory can be
+/// detected.
+///
+/// Note: the heap model is not part of VM snapshots, so callers that restore snapshots must
+/// save and restore the model separately (e.g. by cloning it).
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct HeapModel {
+    start: u64,
+    end: u64,
+    next: u64,
+    allocations: BTreeMap<u64, Allocation>,
+}
+
+impl HeapModel {
+    /// Reserves `size` bytes of memory in `cpu` for the heap.
+    pub fn new(cpu: &mut Cpu, size: u64) -> MemResult<Self> {
+        let layout = AllocLayout { addr: None, size, align: 0x1000 };
+        let perm = perm::READ | perm::WRITE | perm::INIT | perm::MAP;
+        let mapping = Mapping { perm, value: 0xaa };
+        let start = cpu.mem.alloc_memory(layout, mapping)?;
+        Ok(Self { start, end: start + size, next: start, allocations: BTreeMap::new() })
+    }
+
+    /// Returns whether `addr` is inside of the memory region used by the heap.
+    pub fn contains(&self, addr: u64) -> bool {
+        self.start <= addr && addr < self.end
+    }
+
+    /// Gets the start and (exclusive) end address of the memory region used by the heap.
+    pub fn region(&self) -> (u64, u64) {
+        (self.start, self.end)
+    }
+
+    pub fn alloc(&mut self, size: u64) -> Option<u64> {
+        let addr = icicle_cpu::utils::align_up(self.next, HEAP_ALIGN);
+        let end = addr.checked_add(size.max(1))?;
+        if end > self.end {
+            tracing::warn!("heap model exhausted allocating {size:#x} bytes");
+            return None;
+        }
+        // Leave a gap after each allocation so off-by-one accesses never hit another allocation.
+        self.next = end + HEAP_ALIGN;
+        self.allocations.insert(addr, Allocation { addr, size, freed: false });
+        Some(addr)
+    }
+
+    /// Marks the allocation at `addr` as freed returning its size.
+    pub fn free(&mut self, addr: u64) -> Result<u64, HeapError> {
+        match self.allocations.get_mut(&addr) {
+            Some(alloc) if alloc.freed => Err(HeapError::DoubleFree(addr)),
+            Some(alloc) => {
+                alloc.freed = true;
+                Ok(alloc.size)
+            }
+            None => Err(HeapError::InvalidFree(addr)),
+        }
+    }
+
+    /// Finds the allocation (live or freed) that contains `addr`.
+    pub fn find(&self, addr: u64) -> Option<&Allocation> {
+        let (_, alloc) = self.allocations.range(..=addr).next_back()?;
+        (addr < alloc.addr + alloc.size).then_some(alloc)
+    }
+
+    /// Iterates over all allocations that have not been freed.
+    pub fn live(&self) -> impl Iterator<Item = &Allocation> {
+        self.allocations.values().filter(|x| !x.freed)
+    }
+}
+
+impl AllocatorModel for Rc<RefCell<HeapModel>> {
+    fn region(&self) -> (u64, u64) {
+        self.borrow().region()
+    }
+
+    fn find_allocation(&self, addr: u64) -> Option<(u64, u64)> {
+        self.borrow().find(addr).map(|alloc| (alloc.addr, alloc.size))
+    }
+}
+
+pub struct LibcModels {
+    options: LibcModelOptions,
+    heap: Option<Rc<RefCell<HeapModel>>>,
+    output: Box<dyn Write>,
+}
+
+impl LibcModels {
+    /// Creates a new set of models, reserving memory for the heap model if it is enabled.
+    pub fn new(vm: &mut Vm, options: LibcModelOptions) -> MemResult<Self> {
+        let heap = match options.heap {
+            true => Some(Rc::new(RefCell::new(HeapModel::new(&mut vm.cpu, options.heap_size)?))),
+            false => None,
+        };
+        let output = vm.guest_log.writer(LogOrigin::Printf).with_passthrough(std::io::stderr());
+        Ok(Self { options, heap, output: Box::new(output) })
+    }
+
+    /// Configures where the output of the `printf` model is written to.
+    pub fn with_output(mut self, output: impl Write + 'static) -> Self {
+        self.output = Box::new(output);
+        self
+    }
+
+    /// Gets a reference to the heap model (if enabled).
+    pub fn heap(&self) -> Option<Rc<RefCell<HeapModel>>> {
+        self.heap.clone()
+    }
+
+    /// Returns whether there is an enabled model for the function called `name`.
+    pub fn is_modelled(&self, name: &str) -> bool {
+        match name {
+            "malloc" | "calloc" | "realloc" | "free" => self.options.heap,
+            "memcpy" | "memmove" | "memset" | "memcmp" => self.options.memory,
+            "strlen" | "strcmp" | "strncmp" => self.options.strings,
+            "printf" | "puts" => self.options.printf,
+            _ => false,
+        }
+    }
+
+    /// Hooks each of `entries` (a list of (address, name) pairs) that has an enabled model.
+    ///
+    /// Note: this must be called before any code at the entries has been executed.
+    pub fn attach(mut self, vm: &mut Vm, entries: Vec<(u64, String)>) {
+        let entries: BTreeMap<u64, String> =
+            entries.into_iter().filter(|(_, name)| self.is_modelled(name)).collect();
+        let addrs: Vec<u64> = entries.keys().copied().collect();
+        vm.hook_many_addresses(&addrs, move |cpu, addr| {
+            if let Some(name) = entries.get(&addr) {
+                if let Some(value) = self.call(cpu, name) {
+                    return_from_call(cpu, value);
+                }
+            }
+        });
+    }
+
+    /// Runs the model for `name`, returning `None` if the guest implementation should be used
+    /// instead.
+    fn call(&mut self, cpu: &mut Cpu, name: &str) -> Option<u64> {
+        match name {
+            "malloc" => self.heap.as_ref()?.borrow_mut().alloc(cpu.read_arg(0)).or(Some(0)),
+            "calloc" => {
+                let size = cpu.read_arg(0).checked_mul(cpu.read_arg(1));
+                let addr = size.and_then(|size| self.heap.as_ref()?.borrow_mut().alloc(size));
+                match (addr, size) {
+                    (Some(addr), Some(size)) => {
+                        write_zeros(cpu, addr, size).ok()?;
+                        Some(addr)
+                    }
+                    _ => Some(0),
+                }
+            }
+            "realloc" => {
+                let (ptr, size) = (cpu.read_arg(0), cpu.read_arg(1));
+                let mut heap = self.heap.as_ref()?.borrow_mut();
+                if ptr == 0 {
+                    return heap.alloc(size).or(Some(0));
+                }
+                let old_size = heap.find(ptr).filter(|x| x.addr == ptr && !x.freed)?.size;
+                if size == 0 {
+                    heap.free(ptr).ok()?;
+                    return Some(0);
+                }
+                let Some(new) = heap.alloc(size)
+                else {
+                    return Some(0);
+                };
+                copy_mem(cpu, new, ptr, old_size.min(size)).ok()?;
+                heap.free(ptr).ok()?;
+                Some(new)
+            }
+            "free" => {
+                let ptr = cpu.read_arg(0);
+                if ptr == 0 {
+                    return Some(0);
+                }
+                let mut heap = self.heap.as_ref()?.borrow_mut();
+                match heap.free(ptr) {
+                    Ok(_) => Some(0),
+                    Err(e) => {
+                        // Let the guest allocator handle pointers that we don't own, and report
+                        // invalid frees of our own allocations as a crash.
+                        if !heap.contains(ptr) {
+                            return None;
+                        }
+                        tracing::warn!("{e:x?}");
+                        cpu.exception = Exception::new(ExceptionCode::InvalidTarget, ptr);
+                        None
+                    }
+                }
+            }
+            "memcpy" | "memmove" => {
+                let (dst, src, len) = (cpu.read_arg(0), cpu.read_arg(1), cpu.read_arg(2));
+                copy_mem(cpu, dst, src, len).ok()?;
+                Some(dst)
+            }
+            "memset" => {
+                let (dst, value, len) = (cpu.read_arg(0), cpu.read_arg(1) as u8, cpu.read_arg(2));
+                fill_mem(cpu, dst, value, len).ok()?;
+                Some(dst)
+            }
+            "memcmp" => {
+                let (a, b, len) = (cpu.read_arg(0), cpu.read_arg(1), cpu.read_arg(2));
+                compare_mem(cpu, a, b, len).ok()
+            }
+            "strlen" => {
+                let ptr = cpu.read_arg(0);
+                Some(read_cstr(cpu, ptr, MAX_STRING_LEN).ok()?.len() as u64)
+            }
+            "strcmp" | "strncmp" => {
+                let max = if name == "strncmp" { cpu.read_arg(2) } else { MAX_STRING_LEN };
+                let (a, b) = (cpu.read_arg(0), cpu.read_arg(1));
+                let a = read_cstr(cpu, a, max).ok()?;
+                let b = read_cstr(cpu, b, max).ok()?;
+                Some(compare(&a, &b))
+            }
+            "puts" => {
+                let ptr = cpu.read_arg(0);
+                let mut line = read_cstr(cpu, ptr, MAX_STRING_LEN).ok()?;
+                line.push(b'\n');
+                self.write_output(&line);
+                Some(line.len() as u64)
+            }
+            "printf" => {
+                let ptr = cpu.read_arg(0);
+                let fmt = read_cstr(cpu, ptr, MAX_STRING_LEN).ok()?;
+                let out = format_printf(cpu, &fmt, 1).ok()?;
+                self.write_output(&out);
+                Some(out.len() as u64)
+            }
+            _ => None,
+        }
+    }
+
+    fn write_output(&mut self, buf: &[u8]) {
+        if let Err(e) = self.output.write_all(buf) {
+            tracing::error!("failed to write printf output: {e}");
+        }
+    }
+}
+
+/// Compares two byte strings returning the result sign extended to a register sized value.
+fn compare(a: &[u8], b: &[u8]) -> u64 {
+    let diff = a.iter().zip(b).find(|(a, b)| a != b).map_or_else(
+        || a.len().cmp(&b.len()) as i64,
+        |(a, b)| *a as i64 - *b as i64,
+    );
+    diff as u64
+}
+
+pub(crate) fn write_zeros(cpu: &mut Cpu, addr: u64, len: u64) -> MemResult<()> {
+    fill_mem(cpu, addr, 0, len)
+}
+
+/// Fills `len` bytes starting at `addr` with `value`.
+fn fill_mem(cpu: &mut Cpu, addr: u64, value: u8, len: u64) -> MemResult<()> {
+    let chunk = [value; CHUNK_SIZE];
+    let mut offset = 0;
+    while offset < len {
+        let n = (len - offset).min(CHUNK_SIZE as u64);
+        cpu.mem.write_bytes_large(addr.wrapping_add(offset), &chunk[..n as usize], perm::WRITE)?;
+        offset += n;
+    }
+    Ok(())
+}
+
+/// Copies `len` bytes from `src` to `dst`, handling overlapping regions like `memmove`.
+fn copy_mem(cpu: &mut Cpu, dst: u64, src: u64, len: u64) -> MemResult<()> {
+    // Copy from the end if the start of `dst` overlaps the end of `src`.
+    let backwards = dst > src && dst - src < len;
+    let mut buf = [0; CHUNK_SIZE];
+    let mut offset = 0;
+    while offset < len {
+        let n = (len - offset).min(CHUNK_SIZE as u64);
+        let pos = if backwards { len - offset - n } else { offset };
+        let buf = &mut buf[..n as usize];
+        cpu.mem.read_bytes_large(src.wrapping_add(pos), buf, perm::READ)?;
+        cpu.mem.write_bytes_large(dst.wrapping_add(pos), buf, perm::WRITE)?;
+        offset += n;
+    }
+    Ok(())
+}
+
+/// Compares `len` bytes at `a` and `b` returning the result of `memcmp`.
+fn compare_mem(cpu: &mut Cpu, a: u64, b: u64, len: u64) -> MemResult<u64> {
+    let (mut buf_a, mut buf_b) = ([0; CHUNK_SIZE], [0; CHUNK_SIZE]);
+    let mut offset = 0;
+    while offset < len {
+        let n = (len - offset).min(CHUNK_SIZE as u64) as usize;
+        cpu.mem.read_bytes_large(a.wrapping_add(offset), &mut buf_a[..n], perm::READ)?;
+        cpu.mem.read_bytes_large(b.wrapping_add(offset), &mut buf_b[..n], perm::READ)?;
+        if buf_a[..n] != buf_b[..n] {
+            return Ok(compare(&buf_a[..n], &buf_b[..n]));
+        }
+        offset += n as u64;
+    }
+    Ok(0)
+}
+
+/// Reads a NUL-terminated string of at most `max` bytes from `addr`.
+fn read_cstr(cpu: &mut Cpu, addr: u64, max: u64) -> MemResult<Vec<u8>> {
+    let mut out = vec![];
+    for i in 0..max {
+        match cpu.mem.read_u8(addr.wrapping_add(i), perm::READ)? {
+            0 => break,
+            byte => out.push(byte),
+        }
+    }
+    Ok(out)
+}
+
+/// Formats `fmt` using integer and string arguments starting from argument `first_arg`.
+///
+/// Floating point conversions are not supported, since they are passed in different registers.
+fn format_printf(cpu: &mut Cpu, fmt: &[u8], first_arg: usize) -> MemResult<Vec<u8>> {
+    let ptr_bytes = cpu.arch.triple.pointer_width().map_or(4, |x| x.bytes()) as u32;
+
+    let mut out = vec![];
+    let mut next_arg = first_arg;
+    let mut iter = fmt.iter().copied().peekable();
+    while let Some(byte) = iter.next() {
+        if byte != b'%' {
+            out.push(byte);
+            continue;
+        }
+
+        let mut left_align = false;
+        let mut zero_pad = false;
+        while let Some(&flag @ (b'-' | b'0' | b'+' | b' ' | b'#')) = iter.peek() {
+            left_align |= flag == b'-';
+            zero_pad |= flag == b'0';
+            iter.next();
+        }
+        let mut width = 0;
+        while let Some(digit @ b'0'..=b'9') = iter.peek().copied() {
+            width = width * 10 + (digit - b'0') as usize;
+            iter.next();
+        }
+        let mut precision = None;
+        if iter.peek() == Some(&b'.') {
+            iter.next();
+            let mut value = 0;
+            while let Some(digit @ b'0'..=b'9') = iter.peek().copied() {
+                value = value * 10 + (digit - b'0') as usize;
+                iter.next();
+            }
+            precision = Some(value);
+        }
+
+        // Length modifiers: determine the size of integer arguments.
+        let mut bits = 32;
+        while let Some(&modifier @ (b'h' | b'l' | b'z' | b'j' | b't' | b'q')) = iter.peek() {
+            bits = match modifier {
+                b'h' => bits.min(16),
+                b'l' if bits == 32 => 8 * ptr_bytes,
+                _ => 64,
+            };
+            iter.next();
+        }
+
+        let Some(conversion) = iter.next()
+        else {
+            break;
+        };
+        let mask = if bits >= 64 { u64::MAX } else { (1 << bits) - 1 };
+        let mut arg = || {
+            next_arg += 1;
+            cpu.read_arg(next_arg - 1)
+        };
+        let formatted: Vec<u8> = match conversion {
+            b'%' => b"%".to_vec(),
+            b'd' | b'i' => {
+                let value = arg() & mask;
+                let value = ((value << (64 - bits)) as i64) >> (64 - bits);
+                value.to_string().into_bytes()
+            }
+            b'u' => (arg() & mask).to_string().into_bytes(),
+            b'x' => format!("{:x}", arg() & mask).into_bytes(),
+            b'X' => format!("{:X}", arg() & mask).into_bytes(),
+            b'o' => format!("{:o}", arg() & mask).into_bytes(),
+            b'p' => format!("{:#x}", arg()).into_bytes(),
+            b'c' => vec![arg() as u8],
+            b's' => {
+                let ptr = arg();
+                match ptr {
+                    0 => b"(null)".to_vec(),
+                    _ => read_cstr(cpu, ptr, precision.map_or(MAX_STRING_LEN, |x| x as u64))?,
+                }
+            }
+            other => {
+                tracing::debug!("unsupported printf conversion: %{}", other as char);
+                vec![b'%', other]
+            }
+        };
+
+        let padding = width.saturating_sub(formatted.len());
+        let pad_byte = if zero_pad && !left_align && conversion != b's' { b'0' } else { b' ' };
+        if !left_align {
+            out.extend(std::iter::repeat_n(pad_byte, padding));
+        }
+        out.extend_from_slice(&formatted);
+        if left_align {
+            out.extend(std::iter::repeat_n(b' ', padding));
+        }
+    }
+
+    Ok(out)
+}
+
+/// Sets the return value of the current function to `value` then returns to the caller, using the
+/// standard calling convention for the target.
+pub(crate) fn return_from_call(cpu: &mut Cpu, value: u64) {
+    use target_lexicon::Architecture;
+
+    let Some(ret_addr) = cpu.read_return_addr()
+    else {
+        return;
+    };
+
+    if cpu.write_return_value(value).is_none() {
+        return;
+    }
+
+    if matches!(cpu.arch.triple.architecture, Architecture::X86_64 | Architecture::X86_32(_)) {
+        // Pop the return address from the stack.
+        let sp = cpu.read_reg(cpu.arch.reg_sp);
+        cpu.write_reg(cpu.arch.reg_sp, sp + cpu.arch.reg_sp.size as u64);
+    }
+
+    let mut target = ret_addr;
+    if matches!(cpu.arch.triple.architecture, Architecture::Arm(_)) {
+        cpu.set_isa_mode((ret_addr & 1) as u8);
+        target &= !1;
+    }
+    cpu.exception = Exception::new(ExceptionCode::ExternalAddr, target);
+}
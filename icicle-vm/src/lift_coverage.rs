@@ -0,0 +1,220 @@
+//! Reports which instructions of a binary can be handled by the lifter.
+//!
+//! [LiftScanner] performs a linear sweep over regions of code, attempting to decode and lift every
+//! instruction. Instructions that are not supported are grouped by the reason they failed and
+//! their encoding (the mnemonic for instructions that can be decoded, otherwise the raw bytes), so
+//! gaps in support for a target can be found without having to run it.
+//!
+//! Note: a linear sweep also visits any data embedded in code (e.g. literal pools), and does not
+//! track ISA mode switches (e.g. ARM/Thumb), so some of the reported failures may never be
+//! executed.
+
+use std::collections::HashMap;
+
+use icicle_cpu::{
+    lifter::{DecodeError, InstructionLifter},
+    utils::BasicInstructionSource,
+};
+use object::{Object, ObjectSection, SectionFlags, SectionKind, elf};
+
+use crate::BuildError;
+
+/// The number of bytes used to identify instructions that cannot be decoded.
+const UNDECODED_KEY_BYTES: usize = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum FailureKind {
+    /// The bytes could not be decoded as an instruction.
+    Decode,
+
+    /// The instruction was decoded, but the lifter failed to generate p-code for it.
+    Lift,
+
+    /// The instruction was lifted to p-code that raises an invalid instruction exception.
+    InvalidOp,
+}
+
+impl std::fmt::Display for FailureKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Decode => "decode",
+            Self::Lift => "lift",
+            Self::InvalidOp => "invalid-op",
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct LiftFailure {
+    pub kind: FailureKind,
+
+    /// The mnemonic of the instruction, or the first few bytes (in hex) if it could not be decoded.
+    pub encoding: String,
+
+    /// The number of times the encoding appeared in the scanned code.
+    pub count: usize,
+
+    /// The address of the first occurrence of the encoding.
+    pub first_addr: u64,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct LiftCoverage {
+    /// The total number of instructions (including failures) that were scanned.
+    pub instructions: usize,
+
+    /// The number of instructions that were lifted successfully.
+    pub lifted: usize,
+
+    /// The instructions that failed to lift, ordered from the most to least common.
+    pub failures: Vec<LiftFailure>,
+}
+
+impl LiftCoverage {
+    /// Returns the fraction of scanned instructions that were lifted successfully.
+    pub fn coverage(&self) -> f64 {
+        match self.instructions {
+            0 => 1.0,
+            n => self.lifted as f64 / n as f64,
+        }
+    }
+}
+
+impl std::fmt::Display for LiftCoverage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "lifted {}/{} instructions ({:.2}%)",
+            self.lifted,
+            self.instructions,
+            self.coverage() * 100.0
+        )?;
+        for failure in &self.failures {
+            writeln!(
+                f,
+                "{:>8} {:<10} {:<24} (first at {:#x})",
+                failure.count, failure.kind, failure.encoding, failure.first_addr
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Lifts every instruction in regions of code, keeping track of the instructions that fail.
+pub struct LiftScanner {
+    source: BasicInstructionSource,
+    lifter: InstructionLifter,
+    instructions: usize,
+    lifted: usize,
+    failures: HashMap<(FailureKind, String), (usize, u64)>,
+}
+
+impl LiftScanner {
+    pub fn new(triple: &target_lexicon::Triple) -> Result<Self, BuildError> {
+        let lang = crate::sleigh_init(triple)?;
+        let mut lifter = InstructionLifter::new();
+        lifter.set_context(lang.initial_ctx);
+        Ok(Self {
+            source: BasicInstructionSource::new(lang.sleigh),
+            lifter,
+            instructions: 0,
+            lifted: 0,
+            failures: HashMap::new(),
+        })
+    }
+
+    /// Scans the instructions in `code`, which is loaded at `addr`.
+    pub fn scan(&mut self, addr: u64, code: &[u8]) {
+        let step = (self.source.arch.sleigh.alignment as u64).max(1);
+        let end = addr + code.len() as u64;
+        self.source.set_inst(addr, code);
+
+        let mut pc = addr;
+        while pc < end {
+            let result = self.lifter.lift(&mut self.source, pc);
+            let (kind, next) = match result {
+                Ok(next) => {
+                    let invalid =
+                        self.lifter.lifted.instructions.iter().any(|x| x.op == pcode::Op::Invalid);
+                    match invalid {
+                        true => (Some(FailureKind::InvalidOp), next),
+                        false => (None, next),
+                    }
+                }
+                Err(DecodeError::LifterError(_)) => {
+                    (Some(FailureKind::Lift), pc + self.lifter.decoded.num_bytes())
+                }
+                // The instruction extends past the end of the region.
+                Err(DecodeError::NonExecutableMemory) => break,
+                Err(_) => (Some(FailureKind::Decode), pc + step),
+            };
+
+            self.instructions += 1;
+            match kind {
+                Some(kind) => {
+                    let encoding = self.encoding(kind, &code[(pc - addr) as usize..]);
+                    let entry = self.failures.entry((kind, encoding)).or_insert((0, pc));
+                    entry.0 += 1;
+                }
+                None => self.lifted += 1,
+            }
+
+            // Avoid getting stuck if an instruction reports a length of zero.
+            pc = next.max(pc + step);
+        }
+    }
+
+    /// Returns the key used for grouping a failed instruction starting at the first byte of `code`.
+    fn encoding(&mut self, kind: FailureKind, code: &[u8]) -> String {
+        if kind == FailureKind::Decode {
+            let bytes = &code[..code.len().min(UNDECODED_KEY_BYTES)];
+            return bytes.iter().map(|x| format!("{x:02x}")).collect::<Vec<_>>().join(" ");
+        }
+
+        // Use the mnemonic, since the operands of an instruction rarely affect whether it can be
+        // lifted.
+        self.lifter.disasm_current(&self.source);
+        self.lifter.disasm.split_whitespace().next().unwrap_or("").to_owned()
+    }
+
+    /// Returns the coverage for all the code scanned so far.
+    pub fn finish(self) -> LiftCoverage {
+        let mut failures: Vec<_> = self
+            .failures
+            .into_iter()
+            .map(|((kind, encoding), (count, first_addr))| LiftFailure {
+                kind,
+                encoding,
+                count,
+                first_addr,
+            })
+            .collect();
+        failures.sort_by(|a, b| b.count.cmp(&a.count).then(a.first_addr.cmp(&b.first_addr)));
+        LiftCoverage { instructions: self.instructions, lifted: self.lifted, failures }
+    }
+}
+
+/// Scans the executable sections of the ELF binary in `data` for instructions that cannot be
+/// lifted for `triple`.
+pub fn scan_elf(triple: &target_lexicon::Triple, data: &[u8]) -> anyhow::Result<LiftCoverage> {
+    let file = object::File::parse(data)?;
+    let mut scanner = LiftScanner::new(triple)?;
+    for section in file.sections() {
+        let executable = match section.flags() {
+            SectionFlags::Elf { sh_flags } => sh_flags & elf::SHF_EXECINSTR as u64 != 0,
+            _ => section.kind() == SectionKind::Text,
+        };
+        if !executable {
+            continue;
+        }
+        let code = section.data()?;
+        tracing::debug!(
+            "scanning {} ({:#x}, {:#x} bytes)",
+            section.name().unwrap_or("?"),
+            section.address(),
+            code.len()
+        );
+        scanner.scan(section.address(), code);
+    }
+    Ok(scanner.finish())
+}
@@ -0,0 +1,187 @@
+//! Detection of loops in the guest code, with optional per-loop iteration limits.
+//!
+//! Loops are discovered as code is lifted: any block that ends with a direct jump to an address
+//! that is not after the jump itself is treated as a back edge of a loop, with the target of the
+//! jump as the loop header. Each time a back edge is taken, the iteration count of the loop is
+//! incremented, and if the count exceeds the limit configured for the loop the VM exits with
+//! [VmExit::LoopLimit](crate::VmExit::LoopLimit).
+//!
+//! Iteration counts are not part of the VM snapshot, so [LoopDetector::reset_counts] should be
+//! called when restoring the VM to run a new input.
+
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, HashMap},
+    rc::Rc,
+};
+
+use icicle_cpu::{
+    BlockGroup, BlockTable, Cpu, Exception, ExceptionCode,
+    lifter::{BlockExit, Target},
+};
+
+use crate::{Vm, injector::CodeInjector};
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LoopInfo {
+    /// The address of the first instruction of the loop.
+    pub header: u64,
+
+    /// The addresses of the instructions that jump back to the header.
+    pub back_edges: Vec<u64>,
+
+    /// The number of times a back edge of the loop was taken since the last reset.
+    pub iterations: u64,
+}
+
+#[derive(Default)]
+struct LoopState {
+    loops: BTreeMap<u64, LoopInfo>,
+
+    /// Maps the address of a back edge to the header of the loop.
+    back_edges: HashMap<u64, u64>,
+
+    limits: HashMap<u64, u64>,
+    default_limit: Option<u64>,
+}
+
+impl LoopState {
+    fn add_back_edge(&mut self, addr: u64, header: u64) {
+        if self.back_edges.insert(addr, header).is_some() {
+            return;
+        }
+        self.loops
+            .entry(header)
+            .or_insert_with(|| LoopInfo { header, ..LoopInfo::default() })
+            .back_edges
+            .push(addr);
+    }
+
+    fn limit(&self, header: u64) -> Option<u64> {
+        self.limits.get(&header).copied().or(self.default_limit)
+    }
+}
+
+/// A handle to the loop detector attached to a VM. Cloning the handle produces a handle to the
+/// same state.
+#[derive(Clone)]
+pub struct LoopDetector {
+    state: Rc<RefCell<LoopState>>,
+}
+
+impl LoopDetector {
+    /// Sets the maximum number of iterations of the loop starting at `header`.
+    pub fn set_limit(&self, header: u64, limit: u64) {
+        self.state.borrow_mut().limits.insert(header, limit);
+    }
+
+    /// Removes the iteration limit for the loop at `header`, the default limit (if any) will be
+    /// used instead.
+    pub fn clear_limit(&self, header: u64) {
+        self.state.borrow_mut().limits.remove(&header);
+    }
+
+    /// Sets the iteration limit used for loops without a specific limit.
+    pub fn set_default_limit(&self, limit: Option<u64>) {
+        self.state.borrow_mut().default_limit = limit;
+    }
+
+    /// Gets information about the loop starting at `header`.
+    pub fn get(&self, header: u64) -> Option<LoopInfo> {
+        self.state.borrow().loops.get(&header).cloned()
+    }
+
+    /// Gets all loops discovered so far, ordered by header address.
+    pub fn loops(&self) -> Vec<LoopInfo> {
+        self.state.borrow().loops.values().cloned().collect()
+    }
+
+    /// Resets the iteration count of every loop to zero.
+    pub fn reset_counts(&self) {
+        self.state.borrow_mut().loops.values_mut().for_each(|x| x.iterations = 0);
+    }
+}
+
+/// Attaches a loop detector to the VM. Only loops in code lifted after the detector is attached are
+/// detected.
+pub fn attach(vm: &mut Vm) -> LoopDetector {
+    let state = Rc::new(RefCell::new(LoopState::default()));
+
+    let hook_state = state.clone();
+    let hook = vm.cpu.add_hook(move |cpu: &mut Cpu, addr: u64| {
+        let mut state = hook_state.borrow_mut();
+        let Some(&header) = state.back_edges.get(&addr)
+        else {
+            return;
+        };
+        let limit = state.limit(header);
+        let info = state.loops.get_mut(&header).unwrap();
+        info.iterations += 1;
+        if limit.is_some_and(|limit| info.iterations > limit) {
+            cpu.exception = Exception::new(ExceptionCode::LoopLimit, header);
+        }
+    });
+    vm.add_injector(BackEdgeInjector { hook, state: state.clone() });
+
+    LoopDetector { state }
+}
+
+struct BackEdgeInjector {
+    hook: pcode::HookId,
+    state: Rc<RefCell<LoopState>>,
+}
+
+impl CodeInjector for BackEdgeInjector {
+    fn inject(&mut self, _cpu: &mut Cpu, group: &BlockGroup, code: &mut BlockTable) {
+        for id in group.range() {
+            let block = &mut code.blocks[id];
+
+            // The hook is called with the address of the last instruction in the block.
+            let Some(addr) = block
+                .pcode
+                .instructions
+                .iter()
+                .rev()
+                .find(|x| matches!(x.op, pcode::Op::InstructionMarker))
+                .map(|x| x.inputs.first().as_u64())
+            else {
+                continue;
+            };
+            let is_back_edge = |target: &Target| match target {
+                Target::External(pcode::Value::Const(dst, _)) if *dst <= addr => Some(*dst),
+                _ => None,
+            };
+
+            let header = match block.exit {
+                BlockExit::Jump { target } => {
+                    let Some(header) = is_back_edge(&target)
+                    else {
+                        continue;
+                    };
+                    block.pcode.push(pcode::Op::Hook(self.hook));
+                    header
+                }
+                BlockExit::Branch { cond, target, fallthrough } => {
+                    if let Some(header) = is_back_edge(&target) {
+                        block.pcode.push((pcode::Op::HookIf(self.hook), cond));
+                        header
+                    }
+                    else if let Some(header) = is_back_edge(&fallthrough) {
+                        let not_taken = block.pcode.alloc_tmp(1);
+                        block.pcode.push((not_taken, pcode::Op::BoolNot, cond));
+                        block.pcode.push((pcode::Op::HookIf(self.hook), not_taken));
+                        header
+                    }
+                    else {
+                        continue;
+                    }
+                }
+                _ => continue,
+            };
+
+            tracing::debug!("loop detected: header={header:#x}, back edge={addr:#x}");
+            self.state.borrow_mut().add_back_edge(addr, header);
+            code.modified.insert(id);
+        }
+    }
+}
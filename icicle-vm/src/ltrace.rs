@@ -0,0 +1,326 @@
+//! `ltrace`-style library call tracing.
+//!
+//! Calls to imported functions are detected by hooking the PLT entries of an ELF binary. The
+//! arguments of each call are decoded based on a set of prototypes, defined using a simplified
+//! version of the `ltrace.conf` format, e.g.:
+//!
+//! ```text
+//! int puts(string);
+//! addr memcpy(addr, addr, ulong);
+//! ```
+//!
+//! Calls are logged when they return to the caller, together with the return value (see
+//! [crate::function_hooks::hook_function_returns]). Calls to functions that return `void` are
+//! logged immediately.
+
+use std::{cell::RefCell, collections::HashMap, io::Write, rc::Rc};
+
+use icicle_cpu::{Cpu, mem::perm};
+use object::{Object, ObjectSection, ObjectSymbol, ObjectSymbolTable, RelocationFlags};
+
+use crate::{Vm, function_hooks::FunctionReturn};
+
+/// The maximum number of bytes to display for string arguments.
+const MAX_STRING_LEN: usize = 32;
+
+/// The number of arguments to display for functions without a prototype.
+const UNKNOWN_ARG_COUNT: usize = 4;
+
+/// The maximum number of calls that can be waiting for a return value at once.
+const MAX_PENDING_CALLS: usize = 1024;
+
+/// Prototypes for common libc functions.
+pub const DEFAULT_PROTOTYPES: &str = "
+int puts(string);
+int printf(string);
+int fprintf(addr, string);
+int sprintf(addr, string);
+int snprintf(addr, ulong, string);
+addr malloc(ulong);
+addr calloc(ulong, ulong);
+addr realloc(addr, ulong);
+void free(addr);
+addr memcpy(addr, addr, ulong);
+addr memmove(addr, addr, ulong);
+addr memset(addr, int, ulong);
+int memcmp(addr, addr, ulong);
+ulong strlen(string);
+int strcmp(string, string);
+int strncmp(string, string, ulong);
+addr strcpy(addr, string);
+addr strncpy(addr, string, ulong);
+addr strdup(string);
+addr strchr(string, char);
+addr strstr(string, string);
+addr getenv(string);
+int atoi(string);
+long strtol(string, addr, int);
+addr fopen(string, string);
+int fclose(addr);
+int open(string, hex, octal);
+long read(int, addr, ulong);
+long write(int, addr, ulong);
+int close(int);
+void exit(int);
+";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArgType {
+    Int,
+    Uint,
+    Hex,
+    Octal,
+    Addr,
+    Char,
+    String,
+}
+
+impl ArgType {
+    fn parse(name: &str) -> Result<Option<Self>, String> {
+        Ok(Some(match name {
+            "int" | "long" | "short" | "ssize_t" => Self::Int,
+            "uint" | "ulong" | "ushort" | "size_t" => Self::Uint,
+            "hex" => Self::Hex,
+            "octal" => Self::Octal,
+            "addr" | "ptr" | "file" => Self::Addr,
+            "char" => Self::Char,
+            "string" => Self::String,
+            "void" => return Ok(None),
+            _ => return Err(format!("unknown argument type: {name}")),
+        }))
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Prototype {
+    pub ret: Option<ArgType>,
+    pub args: Vec<ArgType>,
+}
+
+/// Parses a list of prototypes. Each prototype is of the form `<ret> <name>(<arg>, ...);`, lines
+/// starting with `#` or `;` are treated as comments.
+pub fn parse_prototypes(src: &str) -> Result<HashMap<String, Prototype>, String> {
+    let mut prototypes = HashMap::new();
+    for (i, line) in src.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+        let err = |msg: &str| format!("line {}: {msg}: {line}", i + 1);
+
+        let line = line.trim_end_matches(';');
+        let (head, args) = line.split_once('(').ok_or_else(|| err("expected '('"))?;
+        let args = args.strip_suffix(')').ok_or_else(|| err("expected ')'"))?;
+        let (ret, name) =
+            head.trim().split_once(char::is_whitespace).ok_or_else(|| err("expected type"))?;
+
+        let ret = ArgType::parse(ret).map_err(|e| err(&e))?;
+        let args = args
+            .split(',')
+            .map(str::trim)
+            .filter(|x| !x.is_empty())
+            .filter_map(|x| ArgType::parse(x).map_err(|e| err(&e)).transpose())
+            .collect::<Result<_, _>>()?;
+
+        prototypes.insert(name.trim().to_owned(), Prototype { ret, args });
+    }
+    Ok(prototypes)
+}
+
+/// Finds the PLT entries in an ELF binary, returning the (relocated) address of each entry along
+/// with the name of the imported function.
+pub fn plt_entries(data: &[u8], offset: u64) -> Result<Vec<(u64, String)>, String> {
+    use object::{Architecture, elf};
+
+    let file = object::File::parse(data).map_err(|e| format!("failed to parse ELF: {e}"))?;
+
+    // Determine the layout of the PLT (header size, entry size, and the relocation type of the
+    // corresponding GOT entries).
+    let (header_size, entry_size, jump_slot) = match file.architecture() {
+        Architecture::X86_64 => (16, 16, elf::R_X86_64_JUMP_SLOT),
+        Architecture::I386 => (16, 16, elf::R_386_JMP_SLOT),
+        Architecture::Aarch64 => (32, 16, elf::R_AARCH64_JUMP_SLOT),
+        Architecture::Arm => (20, 12, elf::R_ARM_JUMP_SLOT),
+        Architecture::Riscv64 | Architecture::Riscv32 => (32, 16, elf::R_RISCV_JUMP_SLOT),
+        arch => return Err(format!("PLT tracing is not supported for {arch:?}")),
+    };
+
+    // Binaries built with IBT/CET have a separate section containing the entries that are called.
+    let (plt_start, header_size) = match file.section_by_name(".plt.sec") {
+        Some(section) => (section.address(), 0),
+        None => match file.section_by_name(".plt") {
+            Some(section) => (section.address(), header_size),
+            None => return Ok(vec![]),
+        },
+    };
+
+    let symbols = file.dynamic_symbol_table();
+    let mut entries = vec![];
+    for (_, reloc) in file.dynamic_relocations().into_iter().flatten() {
+        if !matches!(reloc.flags(), RelocationFlags::Elf { r_type } if r_type == jump_slot) {
+            continue;
+        }
+        let name = match (reloc.target(), &symbols) {
+            (object::RelocationTarget::Symbol(index), Some(symbols)) => symbols
+                .symbol_by_index(index)
+                .ok()
+                .and_then(|sym| sym.name().ok().map(str::to_owned)),
+            _ => None,
+        };
+        let addr = plt_start + header_size + entries.len() as u64 * entry_size;
+        entries.push((addr + offset, name.unwrap_or_else(|| format!("<plt+{addr:#x}>"))));
+    }
+
+    Ok(entries)
+}
+
+/// A call that has not returned yet.
+struct PendingCall {
+    return_addr: u64,
+    ret: ArgType,
+    call: String,
+}
+
+pub struct Ltrace {
+    prototypes: HashMap<String, Prototype>,
+    output: Box<dyn Write>,
+    pending: Vec<PendingCall>,
+}
+
+impl Ltrace {
+    /// Creates a new tracer using the default prototypes that writes to stderr.
+    pub fn new() -> Self {
+        Self {
+            prototypes: parse_prototypes(DEFAULT_PROTOTYPES).unwrap(),
+            output: Box::new(std::io::stderr()),
+            pending: vec![],
+        }
+    }
+
+    pub fn with_output(mut self, output: impl Write + 'static) -> Self {
+        self.output = Box::new(output);
+        self
+    }
+
+    /// Adds prototypes from `src` (see [parse_prototypes]), replacing any existing prototypes with
+    /// the same name.
+    pub fn add_prototypes(&mut self, src: &str) -> Result<(), String> {
+        self.prototypes.extend(parse_prototypes(src)?);
+        Ok(())
+    }
+
+    /// Hooks each of `entries` (a list of (address, name) pairs) and logs a call whenever the
+    /// address is executed.
+    ///
+    /// Note: this must be called before any code at the entries has been executed.
+    pub fn attach(self, vm: &mut Vm, entries: Vec<(u64, String)>) {
+        let addrs: Vec<u64> = entries.iter().map(|(addr, _)| *addr).collect();
+        let names: HashMap<u64, String> = entries.into_iter().collect();
+        let tracer = Rc::new(RefCell::new(self));
+
+        let call_tracer = tracer.clone();
+        vm.hook_many_addresses(&addrs, move |cpu, addr| {
+            if let Some(name) = names.get(&addr) {
+                call_tracer.borrow_mut().log_call(cpu, name);
+            }
+        });
+        crate::function_hooks::hook_function_returns(vm, &addrs, move |ret| {
+            tracer.borrow_mut().log_return(ret);
+        });
+    }
+
+    fn log_call(&mut self, cpu: &mut Cpu, name: &str) {
+        let (args, ret): (Vec<String>, _) = match self.prototypes.get(name) {
+            Some(prototype) => (
+                prototype
+                    .args
+                    .iter()
+                    .enumerate()
+                    .map(|(i, ty)| {
+                        let value = cpu.read_arg(i);
+                        format_arg(cpu, *ty, value)
+                    })
+                    .collect(),
+                prototype.ret,
+            ),
+            None => (
+                (0..UNKNOWN_ARG_COUNT).map(|i| format!("{:#x}", cpu.read_arg(i))).collect(),
+                Some(ArgType::Hex),
+            ),
+        };
+        let call = format!("{name}({})", args.join(", "));
+
+        match (ret, cpu.read_return_addr()) {
+            (Some(ret), Some(return_addr)) => {
+                if self.pending.len() >= MAX_PENDING_CALLS {
+                    let call = self.pending.remove(0).call;
+                    self.write_line(format_args!("{call} <unfinished ...>"));
+                }
+                self.pending.push(PendingCall { return_addr, ret, call });
+            }
+            _ => self.write_line(format_args!("{call}")),
+        }
+    }
+
+    fn log_return(&mut self, ret: &mut FunctionReturn) {
+        let Some(index) = self.pending.iter().rposition(|x| x.return_addr == ret.return_addr)
+        else {
+            return;
+        };
+
+        // Calls that are more recent than the returning call were exited without returning.
+        let mut calls = self.pending.split_off(index);
+        let returned = calls.remove(0);
+        for unfinished in calls {
+            self.write_line(format_args!("{} <unfinished ...>", unfinished.call));
+        }
+
+        let value = match ret.cpu.read_return_value() {
+            Some(value) => format_arg(ret.cpu, returned.ret, value),
+            None => "?".into(),
+        };
+        self.write_line(format_args!("{} = {value}", returned.call));
+    }
+
+    fn write_line(&mut self, line: std::fmt::Arguments) {
+        if let Err(e) = writeln!(self.output, "{line}") {
+            tracing::error!("failed to write library call trace: {e}");
+        }
+    }
+}
+
+impl Default for Ltrace {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn format_arg(cpu: &mut Cpu, ty: ArgType, value: u64) -> String {
+    match ty {
+        ArgType::Int => match cpu.arch.triple.pointer_width().map_or(4, |x| x.bytes()) {
+            8 => (value as i64).to_string(),
+            _ => (value as u32 as i32).to_string(),
+        },
+        ArgType::Uint => value.to_string(),
+        ArgType::Hex => format!("{value:#x}"),
+        ArgType::Octal if value == 0 => "0".into(),
+        ArgType::Octal => format!("0{value:o}"),
+        ArgType::Addr if value == 0 => "nil".into(),
+        ArgType::Addr => format!("{value:#x}"),
+        ArgType::Char => format!("{:?}", value as u8 as char),
+        ArgType::String if value == 0 => "nil".into(),
+        ArgType::String => {
+            let mut bytes = vec![];
+            for i in 0..=MAX_STRING_LEN as u64 {
+                match cpu.mem.read_u8(value + i, perm::NONE) {
+                    Ok(0) => break,
+                    Ok(byte) => bytes.push(byte),
+                    Err(_) => return format!("{value:#x}"),
+                }
+            }
+            let truncated = bytes.len() > MAX_STRING_LEN;
+            bytes.truncate(MAX_STRING_LEN);
+            format!("\"{}\"{}", bytes.escape_ascii(), if truncated { "..." } else { "" })
+        }
+    }
+}
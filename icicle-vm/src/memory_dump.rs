@@ -0,0 +1,142 @@
+//! Exporting the memory of a VM in a format that can be consumed by memory forensics tools.
+//!
+//! Similar to tools like AVML, each region of memory is written to a separate raw file, and a
+//! `manifest.json` file describes where each region is mapped in the guest address space:
+//!
+//! ```text
+//! manifest.json
+//! 0000000000010000-0000000000012000.bin
+//! 00007ffffffde000-00007ffffffff000.bin
+//! ...
+//! ```
+//!
+//! Adjacent regions with the same permissions are merged, and memory mapped IO regions are
+//! excluded. Regions that have been reserved but never allocated are listed in the manifest
+//! without a file.
+
+use std::path::Path;
+
+use anyhow::Context;
+use icicle_cpu::mem::perm;
+
+use crate::Vm;
+
+/// The version of the manifest format.
+const VERSION: u32 = 1;
+
+const MANIFEST_FILE: &str = "manifest.json";
+
+/// Describes the memory exported by [Vm::dump_memory].
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct MemoryManifest {
+    pub version: u32,
+
+    /// The target triple of the guest.
+    pub triple: String,
+
+    pub big_endian: bool,
+
+    /// The size of a pointer in bytes.
+    pub pointer_size: u8,
+
+    /// The program counter at the time of the dump.
+    pub pc: u64,
+
+    /// The number of instructions executed at the time of the dump.
+    pub icount: u64,
+
+    pub regions: Vec<MemoryRegionEntry>,
+}
+
+/// A contiguous region of memory with the same permissions.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct MemoryRegionEntry {
+    pub start: u64,
+
+    /// The end address of the region (exclusive).
+    pub end: u64,
+
+    /// The permissions of the region in `/proc/<pid>/maps` style, e.g. `r-x`.
+    pub perm: String,
+
+    /// The name of the file (relative to the manifest) containing the content of the region, or
+    /// `None` if the region has not been allocated.
+    pub file: Option<String>,
+
+    /// The name of the file or pseudo-file (e.g. `(stack)`) mapped at the region, if known.
+    pub name: Option<String>,
+}
+
+impl Vm {
+    /// Writes every region of memory to a separate file in `dir` along with a manifest describing
+    /// the regions, see [memory_dump](crate::memory_dump).
+    pub fn dump_memory(&mut self, dir: &Path) -> anyhow::Result<MemoryManifest> {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("failed to create: {}", dir.display()))?;
+
+        let mut regions = vec![];
+        for segment in crate::core_dump::collect_segments(self) {
+            let end = segment.addr + segment.len;
+            let file = match segment.data {
+                Some(data) => {
+                    let name = format!("{:016x}-{end:016x}.bin", segment.addr);
+                    let path = dir.join(&name);
+                    std::fs::write(&path, data)
+                        .with_context(|| format!("failed to write: {}", path.display()))?;
+                    Some(name)
+                }
+                None => None,
+            };
+            regions.push(MemoryRegionEntry {
+                start: segment.addr,
+                end,
+                perm: perm_string(segment.perm),
+                file,
+                name: self.region_name(segment.addr),
+            });
+        }
+
+        let manifest = MemoryManifest {
+            version: VERSION,
+            triple: self.cpu.arch.triple.to_string(),
+            big_endian: self.cpu.arch.sleigh.big_endian,
+            pointer_size: self.cpu.arch.triple.pointer_width().map_or(4, |x| x.bytes()),
+            pc: self.cpu.read_pc(),
+            icount: self.cpu.icount(),
+            regions,
+        };
+
+        let path = dir.join(MANIFEST_FILE);
+        let file = std::fs::File::create(&path)
+            .with_context(|| format!("failed to create: {}", path.display()))?;
+        serde_json::to_writer_pretty(std::io::BufWriter::new(file), &manifest)
+            .with_context(|| format!("failed to write: {}", path.display()))?;
+
+        Ok(manifest)
+    }
+
+    /// Gets the name of the file mapped at `addr` by the Linux environment.
+    fn region_name(&self, addr: u64) -> Option<String> {
+        let kernel = self.env_ref::<crate::linux::Kernel>()?;
+        let (_, file) = kernel.process.mapping.range(..=addr).next_back()?;
+        (addr < file.end).then(|| String::from_utf8_lossy(&file.path).into_owned())
+    }
+}
+
+/// Reads a manifest written by [Vm::dump_memory] from `dir`.
+pub fn read_manifest(dir: &Path) -> anyhow::Result<MemoryManifest> {
+    let path = dir.join(MANIFEST_FILE);
+    let data =
+        std::fs::read(&path).with_context(|| format!("failed to read: {}", path.display()))?;
+    let manifest: MemoryManifest = serde_json::from_slice(&data)
+        .with_context(|| format!("invalid manifest: {}", path.display()))?;
+    if manifest.version != VERSION {
+        anyhow::bail!("unsupported manifest version {} (expected {VERSION})", manifest.version);
+    }
+    Ok(manifest)
+}
+
+fn perm_string(value: u8) -> String {
+    let flag = |bit, c| if value & bit != 0 { c } else { '-' };
+    [flag(perm::READ, 'r'), flag(perm::WRITE, 'w'), flag(perm::EXEC, 'x')].iter().collect()
+}
@@ -0,0 +1,89 @@
+//! Hooks that are called for every memory access to a range of addresses.
+//!
+//! Unlike watchpoints (which stop the VM with a [VmExit](crate::VmExit)), range hooks are called
+//! inline while the guest is executing (from both the interpreter and the JIT), and can replace
+//! the value that is read or written. This makes them useful for emulating simple memory mapped
+//! peripherals on top of RAM, or for logging data-flow through a buffer.
+//!
+//! Pages that contain a hooked range are never cached in the TLB, so every access to these pages
+//! takes the slow path through the MMU.
+//!
+//! Writes are only reported once they have passed the permission checks for the target address.
+//! Unaligned accesses are reported as a sequence of single byte accesses.
+
+use icicle_cpu::{Cpu, mem::Mmu};
+
+use crate::Vm;
+
+/// A memory access passed to a hook registered with [Vm::hook_memory_range].
+#[derive(Clone, Copy, Debug)]
+pub struct MemAccess {
+    pub addr: u64,
+
+    /// The number of bytes accessed.
+    pub size: u8,
+
+    /// The value that was read, or is about to be written. Any changes to the value are seen by
+    /// the guest.
+    pub value: u64,
+
+    /// The address of the instruction performing the access.
+    pub pc: u64,
+
+    pub is_write: bool,
+}
+
+impl Vm {
+    /// Registers `hook` to be called for every read and write by the guest to `start..end`,
+    /// returning an ID that can be used to remove the hook with [Vm::remove_memory_range_hook].
+    pub fn hook_memory_range(
+        &mut self,
+        start: u64,
+        end: u64,
+        mut hook: impl FnMut(&mut MemAccess) + 'static,
+    ) -> Option<u32> {
+        // @fixme: safety, make memory subsystem take CPU as a parameter.
+        let cpu_ptr = self.cpu.as_ref() as *const Cpu;
+        self.cpu.mem.add_access_hook(
+            start,
+            end,
+            Box::new(move |mem: &mut Mmu, addr: u64, value: &mut [u8], is_write: bool| {
+                let pc = unsafe { (*cpu_ptr).read_pc() };
+                let raw = read_value(value, mem.big_endian);
+                let size = value.len() as u8;
+                let mut access = MemAccess { addr, size, value: raw, pc, is_write };
+                hook(&mut access);
+                if access.value != raw {
+                    write_value(value, access.value, mem.big_endian);
+                }
+            }),
+        )
+    }
+
+    /// Removes a hook added by [Vm::hook_memory_range].
+    pub fn remove_memory_range_hook(&mut self, id: u32) -> bool {
+        self.cpu.mem.remove_access_hook(id)
+    }
+}
+
+fn read_value(bytes: &[u8], big_endian: bool) -> u64 {
+    let mut buf = [0; 8];
+    match big_endian {
+        true => {
+            buf[8 - bytes.len()..].copy_from_slice(bytes);
+            u64::from_be_bytes(buf)
+        }
+        false => {
+            buf[..bytes.len()].copy_from_slice(bytes);
+            u64::from_le_bytes(buf)
+        }
+    }
+}
+
+fn write_value(bytes: &mut [u8], value: u64, big_endian: bool) {
+    let len = bytes.len();
+    match big_endian {
+        true => bytes.copy_from_slice(&value.to_be_bytes()[8 - len..]),
+        false => bytes.copy_from_slice(&value.to_le_bytes()[..len]),
+    }
+}
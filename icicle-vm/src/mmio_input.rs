@@ -0,0 +1,195 @@
+//! A peripheral that services MMIO reads using bytes from the fuzz input (as done by Fuzzware).
+//!
+//! Firmware typically spends most of its time interacting with peripherals that are not modelled
+//! by the emulator. Instead of modelling each peripheral, [InputMmio] is mapped over the parts of
+//! the peripheral address space that are not handled by anything else, and every read consumes the
+//! next bytes of the input, allowing the fuzzer to explore all the values that the hardware could
+//! return.
+//!
+//! Many registers only have a few meaningful values, so reads from specific addresses can be
+//! modelled with a [MmioModel] to avoid wasting input bytes (and reduce the search space):
+//!
+//! - [MmioModel::Constant]: the register always reads as a fixed value.
+//! - [MmioModel::Passthrough]: the register reads back the last value written to it.
+//! - [MmioModel::Bitfield]: only the bits in a mask are taken from the input.
+//!
+//! Reads that require more bytes than are left in the input fail with [MemError::ReadWatch],
+//! which should be treated as the end of the input by the harness.
+
+use std::{any::Any, collections::HashMap};
+
+use icicle_cpu::mem::{IoHandler, MemError, MemResult, MmioHandler, mmio::MmioRegion};
+
+use crate::Vm;
+
+/// How reads from a single MMIO address are modelled.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MmioModel {
+    /// Reads always return the value, without consuming any input.
+    Constant(u64),
+
+    /// Reads return the last value written to the address (or the initial value if it was never
+    /// written), without consuming any input.
+    Passthrough { initial: u64 },
+
+    /// Reads consume just enough input to fill the bits set in `mask`, all other bits are zero.
+    Bitfield { mask: u64 },
+}
+
+#[derive(Default)]
+pub struct InputMmio {
+    models: HashMap<u64, MmioModel>,
+
+    /// The last value written to each address modelled with [MmioModel::Passthrough].
+    passthrough: HashMap<u64, u64>,
+
+    input: Vec<u8>,
+    offset: usize,
+
+    /// The number of reads from each address that consumed input.
+    pub input_reads: HashMap<u64, u64>,
+}
+
+impl InputMmio {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the model used for reads from `addr`.
+    pub fn set_model(&mut self, addr: u64, model: MmioModel) {
+        self.models.insert(addr, model);
+    }
+
+    pub fn model(&self, addr: u64) -> Option<MmioModel> {
+        self.models.get(&addr).copied()
+    }
+
+    /// Replaces the input used for reads, and resets the state of passthrough registers.
+    pub fn set_input(&mut self, input: &[u8]) {
+        self.input.clear();
+        self.input.extend_from_slice(input);
+        self.offset = 0;
+        self.passthrough.clear();
+    }
+
+    /// The number of input bytes consumed so far.
+    pub fn consumed(&self) -> usize {
+        self.offset
+    }
+
+    /// The number of input bytes that have not been consumed.
+    pub fn remaining(&self) -> usize {
+        self.input.len().saturating_sub(self.offset)
+    }
+
+    /// Gets the current input, allowing it to be modified in place.
+    pub fn input_mut(&mut self) -> &mut [u8] {
+        &mut self.input
+    }
+
+    fn take(&mut self, addr: u64, len: usize) -> MemResult<u64> {
+        if self.remaining() < len {
+            return Err(MemError::ReadWatch);
+        }
+        Ok(self.take_padded(addr, len))
+    }
+
+    /// Consumes up to `len` bytes of the input for a read from `addr`. Bytes past the end of the
+    /// input read as zero.
+    pub fn take_padded(&mut self, addr: u64, len: usize) -> u64 {
+        let end = (self.offset + len).min(self.input.len());
+        let bytes = self.input.get(self.offset..end).unwrap_or(&[]);
+        let mut buf = [0; 8];
+        buf[..bytes.len()].copy_from_slice(bytes);
+        self.offset = self.offset.max(end);
+        *self.input_reads.entry(addr).or_default() += 1;
+        u64::from_le_bytes(buf)
+    }
+}
+
+impl MmioHandler for InputMmio {
+    fn load(&mut self, addr: u64, size: u8) -> MemResult<u64> {
+        let size_mask = pcode::mask(size as u64 * 8);
+        match self.models.get(&addr).copied() {
+            Some(MmioModel::Constant(value)) => Ok(value & size_mask),
+            Some(MmioModel::Passthrough { initial }) => {
+                Ok(self.passthrough.get(&addr).copied().unwrap_or(initial) & size_mask)
+            }
+            Some(MmioModel::Bitfield { mask }) => {
+                let mask = mask & size_mask;
+                let bits = self.take(addr, mask.count_ones().div_ceil(8) as usize)?;
+                Ok(deposit_bits(bits, mask))
+            }
+            None => self.take(addr, size as usize),
+        }
+    }
+
+    fn store(&mut self, addr: u64, _size: u8, value: u64) -> MemResult<()> {
+        if let Some(MmioModel::Passthrough { .. }) = self.models.get(&addr) {
+            self.passthrough.insert(addr, value);
+        }
+        Ok(())
+    }
+
+    fn snapshot(&mut self) -> Box<dyn Any> {
+        Box::new((self.offset, self.passthrough.clone()))
+    }
+
+    fn restore(&mut self, snapshot: &Box<dyn Any>) {
+        let (offset, passthrough) = snapshot.downcast_ref::<(usize, HashMap<u64, u64>)>().unwrap();
+        self.offset = *offset;
+        self.passthrough.clone_from(passthrough);
+    }
+}
+
+/// Places the low bits of `value` at the positions of the bits set in `mask`.
+fn deposit_bits(mut value: u64, mut mask: u64) -> u64 {
+    let mut out = 0;
+    while mask != 0 {
+        let bit = mask & mask.wrapping_neg();
+        if value & 1 != 0 {
+            out |= bit;
+        }
+        value >>= 1;
+        mask &= mask - 1;
+    }
+    out
+}
+
+impl Vm {
+    /// Maps `peripheral` to all parts of `start..start + len` that are not already mapped,
+    /// returning the handler for accessing the peripheral (see
+    /// [icicle_cpu::Mmu::get_mmio_handler_mut]).
+    pub fn map_input_mmio(&mut self, start: u64, len: u64, peripheral: InputMmio) -> IoHandler {
+        let mut gaps = vec![];
+        if len != 0 {
+            let end = start.saturating_add(len - 1);
+            let mut next = start;
+            let mut covered = false;
+            for (region_start, region_end, _) in self.cpu.mem.get_mapping().iter() {
+                if region_end < next || region_start > end {
+                    continue;
+                }
+                if region_start > next {
+                    gaps.push((next, region_start - next));
+                }
+                if region_end >= end {
+                    covered = true;
+                    break;
+                }
+                next = region_end + 1;
+            }
+            if !covered {
+                gaps.push((next, (end - next).saturating_add(1)));
+            }
+        }
+
+        let big_endian = self.cpu.mem.big_endian;
+        let id = self.cpu.mem.register_io_handler(MmioRegion::new(peripheral, big_endian));
+        for (addr, len) in gaps {
+            tracing::debug!("input MMIO: {addr:#x}..{:#x}", addr + len);
+            self.cpu.mem.map_memory_len(addr, len, id);
+        }
+        id
+    }
+}
@@ -11,6 +11,7 @@ use crate::{
         utils::XorShiftRng,
         Cpu, Environment, Exception, ExceptionCode, ValueSource,
     },
+    guest_log::{GuestLog, LogOrigin},
     hw, BuildError, VmExit,
 };
 
@@ -71,6 +72,9 @@ pub struct Msp430 {
     /// Enable writing log data to stdout.
     log_stdout: bool,
 
+    /// The log that output written to the logging peripheral is captured in.
+    pub guest_log: GuestLog,
+
     /// The address we should load raw binaries at.
     load_addr: u64,
 
@@ -111,6 +115,7 @@ impl Msp430 {
             )),
             mcu,
             log_stdout: config.log_stdout,
+            guest_log: GuestLog::disabled(),
             load_addr: config.load_addr,
             flags: CpuFlags::default(),
             interrupt: None,
@@ -188,9 +193,12 @@ impl Msp430 {
             self.interrupts.clone(),
         ));
 
+        let writer = self.guest_log.writer(LogOrigin::Peripheral("msp430".into()));
         let logger = match self.log_stdout {
-            true => cpu.mem.register_io_handler(hw::AsciiLogger::new(std::io::stdout())),
-            false => cpu.mem.register_io_handler(hw::AsciiLogger::new(std::io::sink())),
+            true => cpu.mem.register_io_handler(hw::AsciiLogger::new(
+                writer.with_passthrough(std::io::stdout()),
+            )),
+            false => cpu.mem.register_io_handler(hw::AsciiLogger::new(writer)),
         };
 
         // Note: we sort the values here to avoid non-determinism. This non-determinism is caused by
@@ -371,6 +379,19 @@ impl Environment for Msp430 {
         self.next_interrupt
     }
 
+    /// Calls the handler of the `interrupt`-th interrupt of the MCU. Note: this ignores the GIE
+    /// flag, and the interrupt is dropped if another interrupt is already active.
+    fn raise_interrupt(&mut self, cpu: &mut Cpu, interrupt: u64) -> bool {
+        let Some(isr_addr) = self.interrupts.get(interrupt as usize).map(|x| x.isr)
+        else {
+            return false;
+        };
+        if let Err(e) = self.call_interrupt(cpu, isr_addr) {
+            cpu.exception = Exception::new(ExceptionCode::from_load_error(e), cpu.read_pc());
+        }
+        true
+    }
+
     fn handle_exception(&mut self, cpu: &mut Cpu) -> Option<crate::VmExit> {
         match ExceptionCode::from_u32(cpu.exception.code) {
             ExceptionCode::InstructionLimit => {
@@ -452,6 +473,13 @@ impl Environment for Msp430 {
         self.interrupt = *interrupt;
     }
 
+    fn save_state(&mut self) -> Result<Vec<u8>, String> {
+        // The interrupt state is shared with the peripherals, which are not serializable.
+        Err("the MSP430 environment does not support saving state (peripheral state cannot be \
+             serialized)"
+            .into())
+    }
+
     fn debug_info(&self) -> Option<&DebugInfo> {
         Some(&self.debug_info)
     }
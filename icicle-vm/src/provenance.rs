@@ -0,0 +1,300 @@
+//! A lightweight pointer-provenance checker for pointers produced by allocator models.
+//!
+//! Allocator models (e.g. [crate::libc_models::HeapModel] or
+//! [crate::heap_sanitizer::HeapSanitizer]) know the bounds of every allocation they return, but
+//! an access that uses a large enough offset from a valid pointer can skip over any redzones and
+//! land inside of an adjacent allocation, which is not detected by checking the accessed address
+//! alone. This checker instead compares the accessed address with the pointer it was computed
+//! from: if the base pointer is inside of an allocation and the access is not, the access is
+//! reported as a [ExceptionCode::SanitizerError] with an [AsanReport] that describes both
+//! allocations.
+//!
+//! To keep the overhead low, base pointers are only tracked through the P-code of a single block:
+//! when a load or store uses an address computed by adding to (or subtracting from) a register,
+//! the register is the base pointer of the access. A check is inserted before the access which
+//! only calls into the checker if the base pointer is inside of the memory region used by the
+//! allocator.
+//!
+//! Note: pointers that are incremented in place (e.g. `add rdi, 8`) or that are loaded from memory
+//! are not tracked, so the checker is complementary to the redzones of the heap sanitizer rather
+//! than a replacement for them.
+
+use std::{cell::RefCell, collections::HashMap, fmt::Write, rc::Rc};
+
+use icicle_cpu::{BlockGroup, BlockTable, Cpu, Exception, ExceptionCode, ValueSource};
+use pcode::{Op, Value, VarNode};
+
+use crate::{
+    Vm,
+    asan::{AsanAccess, AsanReport},
+    injector::CodeInjector,
+};
+
+/// An allocator model that can be queried for the allocation that a pointer points into.
+pub trait AllocatorModel {
+    /// Gets the start and (exclusive) end address of the memory region that allocations are made
+    /// from.
+    fn region(&self) -> (u64, u64);
+
+    /// Finds the `(addr, size)` of the allocation that contains `addr`.
+    fn find_allocation(&self, addr: u64) -> Option<(u64, u64)>;
+}
+
+/// A memory access that was instrumented with a provenance check.
+#[derive(Clone, Copy)]
+struct AccessCheck {
+    /// The address of the instruction performing the access.
+    pc: u64,
+
+    /// The number of bytes accessed.
+    size: u64,
+
+    is_write: bool,
+}
+
+struct ProvenanceState {
+    allocator: Box<dyn AllocatorModel>,
+
+    /// Every check inserted into the code, indexed by the value assigned to `check_var`.
+    checks: Vec<AccessCheck>,
+
+    /// The last error detected by the checker.
+    report: Option<AsanReport>,
+}
+
+impl ProvenanceState {
+    /// Checks an access to `addr` using a pointer derived from `base`.
+    fn check(&mut self, cpu: &mut Cpu, base: u64, addr: u64, check: AccessCheck) {
+        let Some((alloc_addr, alloc_size)) = self.allocator.find_allocation(base)
+        else {
+            return;
+        };
+        let end = alloc_addr + alloc_size;
+        if alloc_addr <= addr && addr.saturating_add(check.size) <= end {
+            return;
+        }
+
+        let location = match addr {
+            x if x < alloc_addr => format!("{} bytes before", alloc_addr - x),
+            x if x >= end => format!("{} bytes after", x - end),
+            x => format!("{} bytes inside of", x - alloc_addr),
+        };
+        let mut details = String::new();
+        let _ = writeln!(
+            details,
+            "{addr:#x} is located {location} {alloc_size}-byte region [{alloc_addr:#x},{end:#x}) \
+             that the pointer {base:#x} was derived from"
+        );
+        if let Some((other_addr, other_size)) = self.allocator.find_allocation(addr) {
+            let other_end = other_addr + other_size;
+            let _ = writeln!(
+                details,
+                "{addr:#x} is inside of {other_size}-byte region [{other_addr:#x},{other_end:#x})"
+            );
+        }
+
+        let bug_type = "heap-buffer-overflow";
+        let access = AsanAccess { addr, size: check.size, is_write: check.is_write, pc: check.pc };
+        let report = AsanReport {
+            bug_type: Some(bug_type.into()),
+            access: Some(access),
+            summary: Some(format!("SUMMARY: ProvenanceChecker: {bug_type} on address {addr:#x}")),
+            details: Some(details),
+        };
+        tracing::error!("ProvenanceChecker: {report}");
+        self.report = Some(report);
+        cpu.exception = Exception::new(ExceptionCode::SanitizerError, addr);
+    }
+}
+
+/// A handle to the provenance checker attached to a VM. Cloning the handle produces a handle to
+/// the same state.
+#[derive(Clone)]
+pub struct ProvenanceChecker {
+    state: Rc<RefCell<ProvenanceState>>,
+}
+
+impl ProvenanceChecker {
+    /// Gets the report for the most recent error detected by the checker.
+    pub fn last_report(&self) -> Option<AsanReport> {
+        self.state.borrow().report.clone()
+    }
+
+    /// Removes and returns the report for the most recent error detected by the checker.
+    pub fn take_report(&self) -> Option<AsanReport> {
+        self.state.borrow_mut().report.take()
+    }
+
+    /// The number of memory accesses that have been instrumented with a provenance check.
+    pub fn check_count(&self) -> usize {
+        self.state.borrow().checks.len()
+    }
+}
+
+/// Attaches the provenance checker to the VM, checking accesses through pointers returned by
+/// `allocator`. Only code lifted after the checker is attached is checked.
+pub fn attach(
+    vm: &mut Vm,
+    allocator: impl AllocatorModel + 'static,
+) -> Option<ProvenanceChecker> {
+    let base_var = vm.cpu.arch.sleigh.add_custom_reg("provenance.base", 8)?;
+    let addr_var = vm.cpu.arch.sleigh.add_custom_reg("provenance.addr", 8)?;
+    let check_var = vm.cpu.arch.sleigh.add_custom_reg("provenance.check", 4)?;
+
+    let region = allocator.region();
+    let state = ProvenanceState { allocator: Box::new(allocator), checks: vec![], report: None };
+    let checker = ProvenanceChecker { state: Rc::new(RefCell::new(state)) };
+
+    let hook_state = checker.state.clone();
+    let hook = vm.cpu.add_hook(move |cpu: &mut Cpu, _addr: u64| {
+        let base = cpu.read_var::<u64>(base_var);
+        let addr = cpu.read_var::<u64>(addr_var);
+        let index = cpu.read_var::<u32>(check_var) as usize;
+        let mut state = hook_state.borrow_mut();
+        let check = state.checks[index];
+        state.check(cpu, base, addr, check);
+    });
+
+    vm.add_injector(ProvenanceInjector {
+        hook,
+        region,
+        base_var,
+        addr_var,
+        check_var,
+        state: checker.state.clone(),
+    });
+    vm.provenance = Some(checker.clone());
+
+    Some(checker)
+}
+
+/// Tracks the varnodes that contain addresses computed from a base pointer within a block.
+#[derive(Default)]
+struct BaseTracker {
+    /// Maps a varnode to the register that its value was derived from.
+    derived: HashMap<pcode::VarId, (VarNode, VarNode)>,
+}
+
+impl BaseTracker {
+    /// Gets the base pointer that `value` was derived from.
+    fn base(&self, value: Value) -> Option<VarNode> {
+        match value {
+            Value::Var(var) => {
+                self.derived.get(&var.id).filter(|(x, _)| *x == var).map(|(_, base)| *base)
+            }
+            Value::Const(..) => None,
+        }
+    }
+
+    /// Gets the base pointer of a value computed by adding to (or subtracting from) `value`.
+    fn base_of_operand(&self, value: Value) -> Option<VarNode> {
+        match value {
+            Value::Var(var) if !var.is_temp() => Some(self.base(value).unwrap_or(var)),
+            _ => self.base(value),
+        }
+    }
+
+    /// Updates the tracked values after `inst` is executed.
+    fn step(&mut self, inst: &pcode::Instruction) {
+        let out = inst.output;
+        if out.is_invalid() {
+            return;
+        }
+
+        let [a, b] = inst.inputs.get();
+        let base = match inst.op {
+            Op::Copy => self.base(a),
+            Op::IntAdd => self
+                .base(a)
+                .or_else(|| self.base(b))
+                .or_else(|| self.base_of_operand(a))
+                .or_else(|| self.base_of_operand(b)),
+            Op::IntSub => self.base_of_operand(a),
+            _ => None,
+        };
+
+        // Values derived from the output are no longer valid after it is overwritten.
+        self.derived.retain(|_, (_, base)| base.id != out.id);
+        self.derived.remove(&out.id);
+        if let Some(base) = base.filter(|base| base.id != out.id && base.size == out.size) {
+            self.derived.insert(out.id, (out, base));
+        }
+    }
+}
+
+struct ProvenanceInjector {
+    hook: pcode::HookId,
+    region: (u64, u64),
+    base_var: VarNode,
+    addr_var: VarNode,
+    check_var: VarNode,
+    state: Rc<RefCell<ProvenanceState>>,
+}
+
+impl ProvenanceInjector {
+    /// Inserts a check that calls the hook if `base` points inside of the allocator's region.
+    fn push_check(&self, block: &mut pcode::Block, base: VarNode, addr: Value, index: u32) {
+        // in_region = (base - region.start) < (region.end - region.start)
+        let (start, end) = self.region;
+        let rel = block.alloc_tmp(base.size);
+        block.push((rel, Op::IntSub, base, Value::Const(start, base.size)));
+        let in_region = block.alloc_tmp(1);
+        block.push((in_region, Op::IntLess, rel, Value::Const(end - start, base.size)));
+        match base.size {
+            8 => {
+                block.push(self.base_var.copy_from(base));
+                block.push(self.addr_var.copy_from(addr));
+            }
+            _ => {
+                block.push(self.base_var.zext_from(base));
+                block.push(self.addr_var.zext_from(addr));
+            }
+        }
+        block.push(self.check_var.copy_from(index));
+        block.push((Op::HookIf(self.hook), in_region));
+    }
+}
+
+impl CodeInjector for ProvenanceInjector {
+    fn inject(&mut self, _cpu: &mut Cpu, group: &BlockGroup, code: &mut BlockTable) {
+        let mut state = self.state.borrow_mut();
+        for id in group.range() {
+            let block = &mut code.blocks[id];
+
+            let count = state.checks.len();
+            let mut tracker = BaseTracker::default();
+            let mut pc = block.start;
+            block.pcode.recompute_next_tmp();
+            let instructions = std::mem::take(&mut block.pcode.instructions);
+            for inst in instructions {
+                let access = match inst.op {
+                    Op::InstructionMarker => {
+                        pc = inst.inputs.first().as_u64();
+                        None
+                    }
+                    Op::Load(pcode::RAM_SPACE) => {
+                        Some((inst.inputs.first(), inst.output.size as u64, false))
+                    }
+                    Op::Store(pcode::RAM_SPACE) => {
+                        let [addr, value] = inst.inputs.get();
+                        Some((addr, value.size() as u64, true))
+                    }
+                    _ => None,
+                };
+                if let Some((addr, size, is_write)) = access {
+                    if let Some(base) = tracker.base(addr) {
+                        let index = state.checks.len() as u32;
+                        self.push_check(&mut block.pcode, base, addr, index);
+                        state.checks.push(AccessCheck { pc, size, is_write });
+                    }
+                }
+                block.pcode.push(inst);
+                tracker.step(&inst);
+            }
+
+            if state.checks.len() != count {
+                code.modified.insert(id);
+            }
+        }
+    }
+}
@@ -0,0 +1,182 @@
+//! Record and replay of VM execution.
+//!
+//! While recording, the VM saves a checkpoint at a fixed instruction interval (in addition to the
+//! snapshot taken when recording starts) so that [Vm::step_back] and [Vm::goto_icount] only need
+//! to re-execute a small number of instructions from the nearest checkpoint.
+//!
+//! Re-execution is only equivalent to the original execution if everything the guest observes is
+//! deterministic. The main source of nondeterminism is the environment (e.g. syscalls that read
+//! the host time or host files), so the CPU state and the content of every page of memory written
+//! by the environment are logged after every exception handled by the environment. When the same
+//! point is reached again during replay, the logged state is restored instead of trusting the
+//! environment to produce the same result, and any divergence from the recording (e.g. a different
+//! exception at the same instruction count) is reported.
+//!
+//! Note: changes to the memory mapping made by the environment and side effects of hooks are not
+//! logged, and are only reproduced if they are deterministic.
+
+use std::collections::BTreeMap;
+
+use icicle_cpu::{CpuSnapshot, ExceptionCode, mem::perm};
+
+use crate::Vm;
+
+#[derive(Clone, Copy, Debug)]
+pub struct RecordConfig {
+    /// The number of instructions between each checkpoint.
+    pub checkpoint_interval: u64,
+
+    /// The maximum number of checkpoints to keep, older checkpoints are removed first (the
+    /// checkpoint at the start of the recording is always kept).
+    pub max_checkpoints: usize,
+}
+
+impl Default for RecordConfig {
+    fn default() -> Self {
+        Self { checkpoint_interval: 100_000, max_checkpoints: 256 }
+    }
+}
+
+/// An exception handled by the environment during the recording.
+pub struct EnvEvent {
+    /// The address of the instruction that caused the exception.
+    pub pc: u64,
+
+    pub code: ExceptionCode,
+    pub value: u64,
+
+    /// The CPU state after the environment handled the exception.
+    pub result: Box<CpuSnapshot>,
+
+    /// The address and content of every page written by the environment while handling the
+    /// exception.
+    pub memory: Vec<(u64, Box<[u8]>)>,
+}
+
+pub struct Recording {
+    pub config: RecordConfig,
+
+    /// The instruction count when the recording was started.
+    pub start_icount: u64,
+
+    /// The instruction count of the next checkpoint.
+    pub(crate) next_checkpoint: u64,
+
+    /// Environment events indexed by the instruction count they occurred at.
+    pub events: BTreeMap<u64, EnvEvent>,
+
+    /// The number of events that did not match the recording when replayed.
+    pub divergences: u64,
+}
+
+impl Recording {
+    pub(crate) fn new(config: RecordConfig, icount: u64) -> Self {
+        Self {
+            config,
+            start_icount: icount,
+            next_checkpoint: icount.saturating_add(config.checkpoint_interval.max(1)),
+            events: BTreeMap::new(),
+            divergences: 0,
+        }
+    }
+
+    /// Returns whether the VM is currently re-executing a part of the recording.
+    pub fn is_replaying(&self, icount: u64) -> bool {
+        self.events.last_key_value().is_some_and(|(last, _)| icount <= *last)
+    }
+}
+
+impl Vm {
+    /// Starts recording execution, saving a snapshot of the current state as the start of the
+    /// recording.
+    pub fn start_recording(&mut self, config: RecordConfig) {
+        let icount = self.cpu.icount();
+        self.save_snapshot();
+        self.recording = Some(Recording::new(config, icount));
+    }
+
+    /// Stops recording, returning the recording. Checkpoints that were saved remain available for
+    /// stepping backwards.
+    pub fn stop_recording(&mut self) -> Option<Recording> {
+        self.recording.take()
+    }
+
+    /// Saves a checkpoint if one is due, removing old checkpoints if there are too many.
+    pub(crate) fn maybe_checkpoint(&mut self) {
+        let icount = self.cpu.icount();
+        let Some(recording) = self.recording.as_mut()
+        else {
+            return;
+        };
+        if icount < recording.next_checkpoint {
+            return;
+        }
+        let interval = recording.config.checkpoint_interval.max(1);
+        recording.next_checkpoint = icount.saturating_add(interval);
+
+        let start = recording.start_icount;
+        let max_checkpoints = recording.config.max_checkpoints.max(1);
+        if !self.snapshots.contains_key(&icount) {
+            self.save_snapshot();
+        }
+        while self.snapshots.range(start..).count() > max_checkpoints {
+            let Some(oldest) = self.snapshots.range(start + 1..).next().map(|(x, _)| *x)
+            else {
+                break;
+            };
+            self.snapshots.remove(&oldest);
+        }
+    }
+
+    /// Called after the environment handles an exception, either logging the result, or if the
+    /// event was already recorded, replaying the result that was logged. `written` is the set of
+    /// pages written to by the environment (see [icicle_cpu::mem::Mmu::start_write_log]).
+    pub(crate) fn record_env_event(
+        &mut self,
+        pc: u64,
+        code: u32,
+        value: u64,
+        icount: u64,
+        written: impl IntoIterator<Item = u64>,
+    ) {
+        let Some(recording) = self.recording.as_mut()
+        else {
+            return;
+        };
+        let code = ExceptionCode::from_u32(code);
+
+        if recording.is_replaying(icount) {
+            match recording.events.get(&icount) {
+                Some(event) if event.pc == pc && event.code == code && event.value == value => {
+                    self.cpu.restore(&event.result);
+                    for (addr, data) in &event.memory {
+                        if let Err(e) = self.cpu.mem.write_bytes_large(*addr, data, perm::NONE) {
+                            tracing::warn!("failed to replay write to {addr:#x}: {e:?}");
+                        }
+                    }
+                    return;
+                }
+                _ => {
+                    tracing::warn!(
+                        "replay diverged from recording at icount={icount}: {code:?} at {pc:#x}"
+                    );
+                    recording.divergences += 1;
+                    // Events after this point are from a different timeline.
+                    recording.events.retain(|&x, _| x < icount);
+                }
+            }
+        }
+
+        let mut memory: Vec<(u64, Box<[u8]>)> = written
+            .into_iter()
+            .filter_map(|addr| {
+                let index = self.cpu.mem.get_physical_index(addr)?;
+                Some((addr, self.cpu.mem.get_physical(index).data().data.to_vec().into()))
+            })
+            .collect();
+        memory.sort_unstable_by_key(|(addr, _)| *addr);
+
+        let result = self.cpu.snapshot();
+        recording.events.insert(icount, EnvEvent { pc, code, value, result, memory });
+    }
+}
@@ -0,0 +1,154 @@
+//! Hooks that are called when the guest writes to a register.
+//!
+//! [RegHandler](icicle_cpu::RegHandler) only handles accesses made by the emulator (e.g. through
+//! [Cpu::read_reg] and [Cpu::write_reg]), so environments that model control registers (e.g. ARM
+//! `CONTROL`/`PRIMASK`, x86 `CR3` or the MSP430 status register) would otherwise need to poll the
+//! register every time the VM exits. Register write hooks are instead called inline after each
+//! guest instruction that writes to the register (or any part of it), from both the interpreter
+//! and the JIT. Environments can be notified of writes using [Vm::notify_register_write].
+//!
+//! Writes that are skipped at runtime (e.g. predicated ARM instructions whose condition fails, or
+//! x86 `CMOVcc` when the condition is false) do not call the hook.
+//!
+//! Note: hooks are inserted as code is lifted, so hooks should be registered before the code that
+//! writes to the register is executed, or existing translations should be removed with
+//! [Vm::invalidate_code_range].
+
+use icicle_cpu::{
+    BlockGroup, BlockTable, Cpu, Exception, ExceptionCode, ValueSource,
+    lifter::{BlockExit, Target},
+};
+use pcode::Op;
+
+use crate::{
+    Vm,
+    injector::{CodeInjector, InjectorRef},
+};
+
+impl Vm {
+    /// Registers `hook` to be called with the new value of `reg` after every guest instruction
+    /// that writes to `reg`, returning an ID that can be used to remove the hook with
+    /// [Vm::remove_register_write_hook].
+    pub fn hook_register_write(
+        &mut self,
+        reg: pcode::VarNode,
+        mut hook: impl FnMut(&mut Cpu, u64) + 'static,
+    ) -> Option<InjectorRef> {
+        let name = format!("reg_write_hook{}.written", self.injectors.len());
+        let written = self.cpu.arch.sleigh.add_custom_reg(&name, 1)?;
+        let hook = self.cpu.add_hook(move |cpu: &mut Cpu, _addr: u64| {
+            // Clear the flag before calling the hook, so that the check is a no-op if execution
+            // resumes at the check after the hook raises an exception.
+            cpu.write_reg(written, 0);
+            let value = cpu.read_dynamic(reg.into()).zxt();
+            hook(cpu, value);
+        });
+        Some(self.add_injector(RegWriteInjector { reg: reg.id, written, hook, removed: false }))
+    }
+
+    /// Makes every guest write to `reg` exit the VM with [ExceptionCode::CpuStateChanged] (with
+    /// the ID of `reg` as the exception value) after the instruction completes, so that the
+    /// environment can react to the write in
+    /// [Environment::handle_exception](icicle_cpu::Environment::handle_exception).
+    ///
+    /// If the environment does not handle the exception, the VM continues running.
+    pub fn notify_register_write(&mut self, reg: pcode::VarNode) -> Option<InjectorRef> {
+        self.hook_register_write(reg, move |cpu, _| {
+            cpu.exception = Exception::new(ExceptionCode::CpuStateChanged, reg.id as u64);
+        })
+    }
+
+    /// Removes a hook added by [Vm::hook_register_write] or [Vm::notify_register_write],
+    /// invalidating any code that calls the hook.
+    pub fn remove_register_write_hook(&mut self, id: InjectorRef) -> bool {
+        if id >= self.injectors.len() {
+            return false;
+        }
+        let Some(injector) = self.get_injector_mut::<RegWriteInjector>(id)
+        else {
+            return false;
+        };
+        if std::mem::replace(&mut injector.removed, true) {
+            return false;
+        }
+        let hook = injector.hook;
+
+        // Translations restored from snapshots may still call the hook, so replace it with a no-op.
+        *self.cpu.get_hook_mut(hook) = (|_: &mut Cpu, _: u64| {}).into();
+
+        let calls_hook = |code: &BlockTable, group: &BlockGroup| {
+            code.blocks[group.range()]
+                .iter()
+                .any(|block| block.pcode.instructions.iter().any(|x| x.op == Op::HookIf(hook)))
+        };
+        let ranges: Vec<_> = self
+            .code
+            .map
+            .values()
+            .filter(|group| calls_hook(&self.code, group))
+            .map(|group| (group.start, group.end))
+            .collect();
+        for (start, end) in ranges {
+            self.invalidate_code_range(start, end - start);
+        }
+        true
+    }
+}
+
+struct RegWriteInjector {
+    reg: pcode::VarId,
+    /// Set by the guest when the register is written, and cleared once the hook is called.
+    written: pcode::VarNode,
+    hook: pcode::HookId,
+    removed: bool,
+}
+
+impl RegWriteInjector {
+    fn push_check(&self, block: &mut pcode::Block) {
+        block.push((Op::HookIf(self.hook), self.written));
+    }
+}
+
+/// Returns whether `exit` leaves the current instruction (i.e. whether the instruction is complete
+/// at the end of the block).
+fn ends_instruction(exit: &BlockExit) -> bool {
+    exit.targets().any(|target| !matches!(target, Target::Internal(_)))
+}
+
+impl CodeInjector for RegWriteInjector {
+    fn inject(&mut self, _cpu: &mut Cpu, group: &BlockGroup, code: &mut BlockTable) {
+        if self.removed {
+            return;
+        }
+        let writes_reg = |block: &icicle_cpu::lifter::Block| {
+            block.pcode.instructions.iter().any(|x| x.output.id == self.reg)
+        };
+        if !code.blocks[group.range()].iter().any(writes_reg) {
+            return;
+        }
+
+        // Instructions with predicated writes are split into multiple blocks, so the guest sets a
+        // flag after each write, which is checked once the instruction completes (either at the
+        // start of the next instruction or when exiting the block). This ensures that the hook is
+        // called once per instruction, after the full value is available, and only if the write
+        // was actually executed.
+        for id in group.range() {
+            let block = &mut code.blocks[id];
+            let instructions = std::mem::take(&mut block.pcode.instructions);
+            for (i, inst) in instructions.into_iter().enumerate() {
+                if inst.op == Op::InstructionMarker && (i != 0 || id != group.blocks.0) {
+                    self.push_check(&mut block.pcode);
+                }
+                let written = inst.output.id == self.reg;
+                block.pcode.push(inst);
+                if written {
+                    block.pcode.push(self.written.copy_from(1_u8));
+                }
+            }
+            if ends_instruction(&block.exit) {
+                self.push_check(&mut block.pcode);
+            }
+            code.modified.insert(id);
+        }
+    }
+}
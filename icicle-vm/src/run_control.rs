@@ -0,0 +1,185 @@
+//! A run-control state machine for driving a VM from other threads.
+//!
+//! The VM itself is not thread-safe, so it is owned by a single thread that calls
+//! [Vm::run_controlled]. Other threads (e.g. a GUI or debug server) hold a [RunControl] handle,
+//! which they use to observe the current [RunState] and to request that the VM pauses, resumes or
+//! steps. Pausing a running VM uses [Vm::interrupt_flag], so the VM stops at the next point it
+//! checks for interrupts.
+
+use std::{
+    sync::{
+        Arc, Condvar, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
+};
+
+use crate::{Vm, VmExit};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum RunState {
+    /// The VM is executing code.
+    Running,
+
+    /// The VM is stopped and waiting for a request. Contains the exit that caused the VM to stop
+    /// (or `None` before the VM is started for the first time).
+    Paused(Option<VmExit>),
+
+    /// The VM has stopped permanently (e.g. the program halted or an unhandled exception
+    /// occurred) or the controller was shut down.
+    Exited(VmExit),
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Request {
+    Resume,
+    Step(u64),
+    Shutdown,
+}
+
+struct Inner {
+    state: RunState,
+    request: Option<Request>,
+}
+
+struct Shared {
+    inner: Mutex<Inner>,
+    changed: Condvar,
+    interrupt_flag: Arc<AtomicBool>,
+}
+
+/// A thread-safe handle for controlling a VM running on another thread.
+#[derive(Clone)]
+pub struct RunControl {
+    shared: Arc<Shared>,
+}
+
+impl RunControl {
+    /// Creates a new controller for `vm`. The VM starts in the paused state.
+    pub fn new(vm: &Vm) -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                inner: Mutex::new(Inner { state: RunState::Paused(None), request: None }),
+                changed: Condvar::new(),
+                interrupt_flag: vm.interrupt_flag.clone(),
+            }),
+        }
+    }
+
+    /// Gets the current state of the VM.
+    pub fn state(&self) -> RunState {
+        self.shared.inner.lock().unwrap().state
+    }
+
+    fn send(&self, request: Request) -> bool {
+        let mut inner = self.shared.inner.lock().unwrap();
+        if !matches!(inner.state, RunState::Paused(_)) || inner.request.is_some() {
+            return false;
+        }
+        inner.request = Some(request);
+        self.shared.changed.notify_all();
+        true
+    }
+
+    /// Requests that a paused VM continues execution. Returns false if the VM is not paused.
+    pub fn resume(&self) -> bool {
+        self.send(Request::Resume)
+    }
+
+    /// Requests that a paused VM executes `count` instructions. Returns false if the VM is not
+    /// paused.
+    pub fn step(&self, count: u64) -> bool {
+        self.send(Request::Step(count))
+    }
+
+    /// Requests that a running VM (or a VM that is about to start running) pauses. Returns false
+    /// if the VM is already stopped.
+    pub fn pause(&self) -> bool {
+        let inner = self.shared.inner.lock().unwrap();
+        let is_starting = matches!(inner.request, Some(Request::Resume | Request::Step(_)));
+        if inner.state != RunState::Running && !is_starting {
+            return false;
+        }
+        self.shared.interrupt_flag.store(true, Ordering::Release);
+        true
+    }
+
+    /// Stops the VM (interrupting it if it is running), causing [Vm::run_controlled] to return.
+    pub fn shutdown(&self) {
+        let mut inner = self.shared.inner.lock().unwrap();
+        if !matches!(inner.state, RunState::Exited(_)) {
+            inner.request = Some(Request::Shutdown);
+            self.shared.interrupt_flag.store(true, Ordering::Release);
+            self.shared.changed.notify_all();
+        }
+    }
+
+    /// Blocks until the VM is no longer running (or a pending request has been handled), or until
+    /// `timeout` has elapsed. Returns the state of the VM.
+    pub fn wait(&self, timeout: Option<Duration>) -> RunState {
+        let is_busy = |x: &mut Inner| x.state == RunState::Running || x.request.is_some();
+        let changed = &self.shared.changed;
+        let inner = self.shared.inner.lock().unwrap();
+        let inner = match timeout {
+            Some(timeout) => changed.wait_timeout_while(inner, timeout, is_busy).unwrap().0,
+            None => changed.wait_while(inner, is_busy).unwrap(),
+        };
+        inner.state
+    }
+
+    /// Blocks until the next request arrives, and updates the state to reflect the request.
+    fn next_request(&self) -> Request {
+        let inner = self.shared.inner.lock().unwrap();
+        let mut inner = self.shared.changed.wait_while(inner, |x| x.request.is_none()).unwrap();
+        let request = inner.request.take().unwrap();
+        if request != Request::Shutdown {
+            inner.state = RunState::Running;
+        }
+        self.shared.changed.notify_all();
+        request
+    }
+
+    /// Marks the VM as stopped. Any pause request that arrived after the VM had already stopped
+    /// is cleared here (while holding the lock, so it cannot race with [RunControl::pause]).
+    fn set_stopped(&self, state: RunState) {
+        let mut inner = self.shared.inner.lock().unwrap();
+        self.shared.interrupt_flag.store(false, Ordering::Release);
+        inner.state = state;
+        self.shared.changed.notify_all();
+    }
+}
+
+/// Returns whether the VM can continue executing after `exit`.
+fn is_resumable(exit: VmExit) -> bool {
+    matches!(
+        exit,
+        VmExit::Running
+            | VmExit::InstructionLimit
+            | VmExit::Breakpoint
+            | VmExit::LoopLimit(_)
+            | VmExit::Interrupted
+    )
+}
+
+impl Vm {
+    /// Runs the VM under the control of `control`, handling requests until the VM exits or the
+    /// controller is shut down. Returns the exit that caused the VM to stop.
+    pub fn run_controlled(&mut self, control: &RunControl) -> VmExit {
+        loop {
+            let exit = match control.next_request() {
+                Request::Resume => self.run(),
+                Request::Step(count) => self.step(count),
+                Request::Shutdown => {
+                    control.set_stopped(RunState::Exited(VmExit::Interrupted));
+                    return VmExit::Interrupted;
+                }
+            };
+
+            if !is_resumable(exit) {
+                control.set_stopped(RunState::Exited(exit));
+                return exit;
+            }
+            control.set_stopped(RunState::Paused(Some(exit)));
+        }
+    }
+}
@@ -0,0 +1,157 @@
+//! Detection of secret-dependent control flow and memory accesses (i.e. constant-time violations).
+//!
+//! Regions of memory (or registers) are marked as secret, and secrecy is propagated through the
+//! lifted code using [crate::shadow]. Whenever a value derived from a secret is used as a branch
+//! condition, an indirect branch target, or the address of a memory access, a [SecretLeak] is
+//! recorded. Code that handles secrets in constant time (e.g. cryptographic primitives) should not
+//! produce any leaks, regardless of the values of the secrets.
+//!
+//! Note: this is data-flow only, so secrets that influence execution through implicit flows (e.g.
+//! a value selected by a secret-dependent branch) are only reported at the branch itself.
+
+use std::collections::HashMap;
+
+use icicle_cpu::Cpu;
+use pcode::{BranchHint, VarNode};
+
+use crate::{
+    Vm,
+    shadow::{self, Shadow, ShadowAccess, ShadowBranch, ShadowPolicy},
+};
+
+/// The metadata value used for bytes derived from a secret.
+const SECRET: u8 = 1;
+
+/// The way a secret-derived value influenced the observable behaviour of the guest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum LeakKind {
+    /// A conditional branch depended on a secret.
+    BranchCondition,
+
+    /// The target of an indirect jump, call, or return depended on a secret.
+    BranchTarget(BranchHint),
+
+    /// The address of a load depended on a secret.
+    LoadAddress,
+
+    /// The address of a store depended on a secret.
+    StoreAddress,
+}
+
+/// A location where a secret-derived value influenced the observable behaviour of the guest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SecretLeak {
+    /// The address of the instruction that leaked the secret.
+    pub pc: u64,
+    pub kind: LeakKind,
+
+    /// The address of the first memory access that leaked the secret (zero for branches).
+    pub addr: u64,
+
+    /// The number of times the instruction leaked the secret.
+    pub count: u64,
+}
+
+#[derive(Default)]
+pub struct SecretPolicy {
+    /// Leaks in the order they were first detected.
+    leaks: Vec<SecretLeak>,
+
+    /// The index of the leak in `leaks` for each location.
+    index: HashMap<(u64, LeakKind), usize>,
+}
+
+impl SecretPolicy {
+    fn record(&mut self, pc: u64, kind: LeakKind, addr: u64) {
+        let index = *self.index.entry((pc, kind)).or_insert_with(|| {
+            tracing::debug!("secret leaked at {pc:#x} ({kind:?})");
+            self.leaks.push(SecretLeak { pc, kind, addr, count: 0 });
+            self.leaks.len() - 1
+        });
+        self.leaks[index].count += 1;
+    }
+}
+
+impl ShadowPolicy for SecretPolicy {
+    const TRACK_BRANCHES: bool = true;
+
+    fn on_load(&mut self, _: &mut Cpu, access: ShadowAccess, _: &mut [u8]) {
+        if access.addr_meta != 0 {
+            self.record(access.pc, LeakKind::LoadAddress, access.addr);
+        }
+    }
+
+    fn on_store(&mut self, _: &mut Cpu, access: ShadowAccess, _: &mut [u8]) {
+        if access.addr_meta != 0 {
+            self.record(access.pc, LeakKind::StoreAddress, access.addr);
+        }
+    }
+
+    fn on_branch(&mut self, _: &mut Cpu, branch: ShadowBranch) {
+        if branch.cond_meta != 0 {
+            self.record(branch.pc, LeakKind::BranchCondition, 0);
+        }
+        if branch.target_meta != 0 {
+            self.record(branch.pc, LeakKind::BranchTarget(branch.hint), 0);
+        }
+    }
+}
+
+/// A handle to the secret tracker attached to a VM. Cloning the handle produces a handle to the
+/// same state.
+#[derive(Clone)]
+pub struct SecretTracker {
+    shadow: Shadow<SecretPolicy>,
+}
+
+impl SecretTracker {
+    /// Marks the `len` bytes of memory starting at `addr` as secret.
+    pub fn mark_secret(&self, vm: &mut Vm, addr: u64, len: u64) {
+        self.shadow.set_memory(vm, addr, len, SECRET);
+    }
+
+    /// Marks the register `var` as secret.
+    pub fn mark_register_secret(&self, var: VarNode) {
+        self.shadow.set_register(var, SECRET);
+    }
+
+    /// Marks the `len` bytes of memory starting at `addr` as no longer secret (e.g. the output of
+    /// an encryption routine).
+    pub fn declassify(&self, vm: &mut Vm, addr: u64, len: u64) {
+        self.shadow.set_memory(vm, addr, len, 0);
+    }
+
+    /// Returns whether the byte of memory at `addr` is derived from a secret.
+    pub fn is_secret(&self, vm: &Vm, addr: u64) -> bool {
+        self.shadow.memory(vm, addr) != 0
+    }
+
+    /// Returns whether the register `var` is derived from a secret.
+    pub fn is_register_secret(&self, var: VarNode) -> bool {
+        self.shadow.register(var) != 0
+    }
+
+    /// Gets all the leaks detected so far, in the order they were first detected.
+    pub fn leaks(&self) -> Vec<SecretLeak> {
+        self.shadow.policy().leaks.clone()
+    }
+
+    /// Removes all recorded leaks, keeping the secrets.
+    pub fn clear_leaks(&self) {
+        let mut policy = self.shadow.policy();
+        policy.leaks.clear();
+        policy.index.clear();
+    }
+
+    /// Removes all secrets and recorded leaks.
+    pub fn clear(&self, vm: &mut Vm) {
+        self.clear_leaks();
+        self.shadow.clear(vm);
+    }
+}
+
+/// Attaches a secret tracker to the VM. Only code lifted after the tracker is attached is checked,
+/// so this should be called before the VM starts executing.
+pub fn attach(vm: &mut Vm) -> SecretTracker {
+    SecretTracker { shadow: shadow::attach(vm, "secret", 1, SecretPolicy::default()) }
+}
@@ -0,0 +1,464 @@
+//! A framework for attaching user-defined metadata to every byte of guest memory and registers.
+//!
+//! Each byte is shadowed by `N` bits of metadata (where `N` is 1, 2, 4, or 8), with zero meaning
+//! "no metadata". Metadata is propagated through the lifted code by instrumenting every operation:
+//! copies and extensions move the metadata of individual bytes, and all other operations combine
+//! the metadata of their inputs using [ShadowPolicy::combine] ([crate::taint] uses the same
+//! propagation with wider labels). Loads and stores call [ShadowPolicy::on_load] and
+//! [ShadowPolicy::on_store], which can inspect or modify the metadata being transferred, allowing
+//! custom analyses (e.g. tracking secrets, or type maps) to be built without modifying the MMU.
+//! Policies can also set [ShadowPolicy::TRACK_BRANCHES] to inspect the metadata of branch
+//! conditions and indirect branch targets.
+//!
+//! The metadata of memory is stored in a shadow memory registered with the MMU, so it is cleared
+//! when memory is mapped or unmapped and is saved and restored with VM snapshots. The metadata of
+//! registers is not part of the snapshot, and memory written by the environment is not tracked.
+
+use std::{cell::RefCell, collections::HashMap, rc::Rc};
+
+use icicle_cpu::{
+    BlockGroup, BlockTable, Cpu, Regs, ValueSource,
+    lifter::{BlockExit, Target},
+};
+use pcode::{BranchHint, Op, Value, VarNode};
+
+use crate::{Vm, injector::CodeInjector};
+
+pub use icicle_cpu::mem::shadow::{ShadowId, ShadowMemory};
+
+/// A memory access performed by the guest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ShadowAccess<M = u8> {
+    pub pc: u64,
+    pub addr: u64,
+
+    /// The combined metadata of the value used as the address of the access.
+    pub addr_meta: M,
+}
+
+/// A conditional or indirect branch performed by the guest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ShadowBranch<M = u8> {
+    pub pc: u64,
+    pub hint: BranchHint,
+
+    /// The combined metadata of the branch condition (zero for unconditional branches).
+    pub cond_meta: M,
+
+    /// The combined metadata of the branch target (zero for direct branches).
+    pub target_meta: M,
+}
+
+/// Controls how metadata is propagated, all methods have defaults that treat metadata as a set of
+/// independent flags.
+pub trait ShadowPolicy {
+    /// Whether the code should be instrumented to call [ShadowPolicy::on_branch].
+    const TRACK_BRANCHES: bool = false;
+
+    /// Combines the metadata of two inputs to an operation.
+    fn combine(&mut self, a: u8, b: u8) -> u8 {
+        a | b
+    }
+
+    /// Called before the guest loads from memory. `data` contains the metadata of each byte that is
+    /// loaded (in memory order) and can be modified to change the metadata of the loaded value.
+    fn on_load(&mut self, cpu: &mut Cpu, access: ShadowAccess, data: &mut [u8]) {
+        let _ = (cpu, access, data);
+    }
+
+    /// Called before the guest stores to memory. `data` contains the metadata of each byte that is
+    /// stored (in memory order) and can be modified to change the metadata written to memory.
+    fn on_store(&mut self, cpu: &mut Cpu, access: ShadowAccess, data: &mut [u8]) {
+        let _ = (cpu, access, data);
+    }
+
+    /// Called at the end of a block that exits with a conditional or indirect branch. Only called
+    /// if [ShadowPolicy::TRACK_BRANCHES] is set.
+    fn on_branch(&mut self, cpu: &mut Cpu, branch: ShadowBranch) {
+        let _ = (cpu, branch);
+    }
+}
+
+/// Storage for the metadata of guest memory, and the rules for combining metadata, used by
+/// [Propagator]. The default value of `Meta` means "no metadata".
+pub(crate) trait MetaStore {
+    type Meta: Copy + Default + Eq;
+
+    /// Truncates `meta` to a value that can be stored.
+    fn normalize(&self, meta: Self::Meta) -> Self::Meta {
+        meta
+    }
+
+    /// Combines the metadata of two inputs to an operation.
+    fn combine(&mut self, a: Self::Meta, b: Self::Meta) -> Self::Meta;
+
+    /// Reads the metadata of each byte loaded by `access` (in memory order) into `data`.
+    fn load(&mut self, cpu: &mut Cpu, access: ShadowAccess<Self::Meta>, data: &mut [Self::Meta]);
+
+    /// Writes the metadata of each byte stored by `access` (in memory order) from `data`.
+    fn store(&mut self, cpu: &mut Cpu, access: ShadowAccess<Self::Meta>, data: &mut [Self::Meta]);
+
+    /// Called for each conditional or indirect branch if branches are instrumented.
+    fn branch(&mut self, cpu: &mut Cpu, branch: ShadowBranch<Self::Meta>) {
+        let _ = (cpu, branch);
+    }
+}
+
+/// Propagates metadata through the lifted code, using `S` to store the metadata of memory.
+pub(crate) struct Propagator<S: MetaStore> {
+    pub store: S,
+
+    /// Metadata for each byte of the register file with non-zero metadata (keyed by offset in the
+    /// register file).
+    regs: HashMap<isize, S::Meta>,
+}
+
+impl<S: MetaStore> Propagator<S> {
+    pub fn new(store: S) -> Self {
+        Self { store, regs: HashMap::new() }
+    }
+
+    /// Removes the metadata of all registers.
+    pub fn clear_regs(&mut self) {
+        self.regs.clear();
+    }
+
+    fn reg_byte(&self, var: VarNode, i: u8) -> S::Meta {
+        self.regs.get(&(Regs::var_offset(var) + i as isize)).copied().unwrap_or_default()
+    }
+
+    fn set_reg_byte(&mut self, var: VarNode, i: u8, value: S::Meta) {
+        let key = Regs::var_offset(var) + i as isize;
+        match self.store.normalize(value) {
+            value if value == S::Meta::default() => self.regs.remove(&key),
+            value => self.regs.insert(key, value),
+        };
+    }
+
+    fn value_byte(&self, value: Value, i: u8) -> S::Meta {
+        match value {
+            Value::Var(var) if i < var.size => self.reg_byte(var, i),
+            _ => S::Meta::default(),
+        }
+    }
+
+    /// Gets the combined metadata for all the bytes of `value`.
+    pub fn value_meta(&mut self, value: Value) -> S::Meta {
+        let Value::Var(var) = value
+        else {
+            return S::Meta::default();
+        };
+        let mut meta = S::Meta::default();
+        for i in 0..var.size {
+            let byte = self.reg_byte(var, i);
+            meta = self.store.combine(meta, byte);
+        }
+        meta
+    }
+
+    /// Sets the metadata of all the bytes of `out` to `value`.
+    pub fn set_output(&mut self, out: VarNode, value: S::Meta) {
+        for i in 0..out.size {
+            self.set_reg_byte(out, i, value);
+        }
+    }
+
+    fn set_output_bytes(&mut self, out: VarNode, values: Vec<S::Meta>) {
+        for (i, value) in values.into_iter().enumerate() {
+            self.set_reg_byte(out, i as u8, value);
+        }
+    }
+
+    fn access(&mut self, cpu: &mut Cpu, addr_value: Value) -> ShadowAccess<S::Meta> {
+        let addr = cpu.read_dynamic(addr_value).zxt();
+        ShadowAccess { pc: cpu.read_pc(), addr, addr_meta: self.value_meta(addr_value) }
+    }
+
+    /// Updates the shadow state for `inst`, called before `inst` is executed.
+    pub fn propagate(&mut self, cpu: &mut Cpu, inst: pcode::Instruction) {
+        let [a, b] = inst.inputs.get();
+        let out = inst.output;
+        let big_endian = cpu.arch.sleigh.big_endian;
+        let none = S::Meta::default();
+
+        // Converts between the index of a byte in a value and its offset in memory.
+        let mem_offset = |i: u8, size: u8| if big_endian { size - 1 - i } else { i };
+
+        match inst.op {
+            Op::Copy if out.size == a.size() => {
+                let values: Vec<_> = (0..out.size).map(|i| self.value_byte(a, i)).collect();
+                self.set_output_bytes(out, values);
+            }
+            Op::Subpiece(offset) => {
+                let values: Vec<_> =
+                    (0..out.size).map(|i| self.value_byte(a, offset + i)).collect();
+                self.set_output_bytes(out, values);
+            }
+            Op::ZeroExtend | Op::SignExtend => {
+                let high = match inst.op {
+                    Op::SignExtend => self.value_byte(a, a.size().saturating_sub(1)),
+                    _ => none,
+                };
+                let values: Vec<_> = (0..out.size)
+                    .map(|i| if i < a.size() { self.value_byte(a, i) } else { high })
+                    .collect();
+                self.set_output_bytes(out, values);
+            }
+            Op::IntXor | Op::IntSub if a == b => self.set_output(out, none),
+
+            Op::Load(pcode::RAM_SPACE) => {
+                let access = self.access(cpu, a);
+                let mut data = vec![none; out.size as usize];
+                self.store.load(cpu, access, &mut data);
+                for i in 0..out.size {
+                    self.set_reg_byte(out, i, data[mem_offset(i, out.size) as usize]);
+                }
+            }
+            Op::Store(pcode::RAM_SPACE) => {
+                let access = self.access(cpu, a);
+                let size = b.size();
+                let mut data: Vec<_> =
+                    (0..size).map(|i| self.value_byte(b, mem_offset(i, size))).collect();
+                self.store.store(cpu, access, &mut data);
+            }
+            Op::Branch(hint) => {
+                let branch = ShadowBranch {
+                    pc: cpu.read_pc(),
+                    hint,
+                    cond_meta: self.value_meta(a),
+                    target_meta: self.value_meta(b),
+                };
+                self.store.branch(cpu, branch);
+            }
+
+            _ if !out.is_invalid() => {
+                let meta = self.value_meta(a);
+                let meta = match b.is_invalid() {
+                    true => meta,
+                    false => {
+                        let b = self.value_meta(b);
+                        self.store.combine(meta, b)
+                    }
+                };
+                self.set_output(out, meta);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Stores metadata in a shadow memory registered with the MMU, using a [ShadowPolicy] to combine
+/// and transfer metadata.
+struct PolicyStore<P> {
+    policy: P,
+    mem: ShadowId,
+    mask: u8,
+}
+
+impl<P: ShadowPolicy> MetaStore for PolicyStore<P> {
+    type Meta = u8;
+
+    fn normalize(&self, meta: u8) -> u8 {
+        meta & self.mask
+    }
+
+    fn combine(&mut self, a: u8, b: u8) -> u8 {
+        self.policy.combine(a, b)
+    }
+
+    fn load(&mut self, cpu: &mut Cpu, access: ShadowAccess, data: &mut [u8]) {
+        cpu.mem.shadow(self.mem).get_range(access.addr, data);
+        self.policy.on_load(cpu, access, data);
+    }
+
+    fn store(&mut self, cpu: &mut Cpu, access: ShadowAccess, data: &mut [u8]) {
+        self.policy.on_store(cpu, access, data);
+        cpu.mem.shadow_mut(self.mem).set_bytes(access.addr, data);
+    }
+
+    fn branch(&mut self, cpu: &mut Cpu, branch: ShadowBranch) {
+        self.policy.on_branch(cpu, branch);
+    }
+}
+
+/// A handle to a shadow state attached to a VM. Cloning the handle produces a handle to the same
+/// state.
+pub struct Shadow<P: ShadowPolicy> {
+    state: Rc<RefCell<Propagator<PolicyStore<P>>>>,
+    mem: ShadowId,
+}
+
+impl<P: ShadowPolicy> Clone for Shadow<P> {
+    fn clone(&self) -> Self {
+        Self { state: self.state.clone(), mem: self.mem }
+    }
+}
+
+impl<P: ShadowPolicy> Shadow<P> {
+    /// The ID of the shadow memory used for storing the metadata of memory.
+    pub fn id(&self) -> ShadowId {
+        self.mem
+    }
+
+    /// Sets the metadata of the `len` bytes of memory starting at `addr`.
+    pub fn set_memory(&self, vm: &mut Vm, addr: u64, len: u64, value: u8) {
+        vm.cpu.mem.shadow_mut(self.mem).set_range(addr, len, value);
+    }
+
+    /// Gets the metadata of the byte of memory at `addr`.
+    pub fn memory(&self, vm: &Vm, addr: u64) -> u8 {
+        vm.cpu.mem.shadow(self.mem).get(addr)
+    }
+
+    /// Sets the metadata of all the bytes of the register `var`.
+    pub fn set_register(&self, var: VarNode, value: u8) {
+        self.state.borrow_mut().set_output(var, value);
+    }
+
+    /// Gets the combined metadata of all the bytes of the register `var`.
+    pub fn register(&self, var: VarNode) -> u8 {
+        self.state.borrow_mut().value_meta(var.into())
+    }
+
+    /// Gets access to the policy used for propagating metadata. Must not be held while the VM is
+    /// running.
+    pub fn policy(&self) -> std::cell::RefMut<'_, P> {
+        std::cell::RefMut::map(self.state.borrow_mut(), |state| &mut state.store.policy)
+    }
+
+    /// Removes all metadata from registers and memory.
+    pub fn clear(&self, vm: &mut Vm) {
+        self.state.borrow_mut().clear_regs();
+        vm.cpu.mem.shadow_mut(self.mem).clear();
+    }
+}
+
+/// Attaches a shadow state with `bits` bits of metadata per byte to the VM, using `policy` to
+/// propagate metadata. `name` must be unique for each shadow state attached to the VM.
+///
+/// Only code lifted after the shadow state is attached propagates metadata, so this should be
+/// called before the VM starts executing.
+pub fn attach<P: ShadowPolicy + 'static>(
+    vm: &mut Vm,
+    name: &str,
+    bits: u8,
+    policy: P,
+) -> Shadow<P> {
+    let mem = vm.cpu.mem.add_shadow(bits);
+    let mask = vm.cpu.mem.shadow(mem).mask();
+    let state = Rc::new(RefCell::new(Propagator::new(PolicyStore { policy, mem, mask })));
+
+    let hook_state = state.clone();
+    attach_op_hook(vm, &format!("{name}.op"), P::TRACK_BRANCHES, move |cpu, inst| {
+        hook_state.borrow_mut().propagate(cpu, inst)
+    })
+    .unwrap_or_else(|| panic!("shadow state `{name}` has already been attached"));
+
+    Shadow { state, mem }
+}
+
+/// Instruments every p-code operation that produces a value or stores to memory with a call to
+/// `hook`, which is called with the operation before it is executed. The index of the operation is
+/// passed to the hook using a custom register called `reg_name`. Identical operations share an
+/// index, so relifting code does not grow the table of instrumented operations.
+///
+/// If `branches` is set, blocks that exit with a conditional or indirect branch also call `hook`
+/// with an [Op::Branch] operation, with the branch condition and target as inputs.
+///
+/// Returns `None` if `reg_name` is already in use.
+pub(crate) fn attach_op_hook(
+    vm: &mut Vm,
+    reg_name: &str,
+    branches: bool,
+    mut hook: impl FnMut(&mut Cpu, pcode::Instruction) + 'static,
+) -> Option<()> {
+    let op_var = vm.cpu.arch.sleigh.add_custom_reg(reg_name, 4)?;
+    let ops: Rc<RefCell<OpTable>> = Rc::default();
+
+    let hook_ops = ops.clone();
+    let hook = vm.cpu.add_hook(move |cpu: &mut Cpu, _addr: u64| {
+        let index = cpu.read_var::<u32>(op_var) as usize;
+        let inst = hook_ops.borrow().ops[index];
+        hook(cpu, inst);
+    });
+    vm.add_injector(OpHookInjector { hook, op_var, ops, branches });
+    Some(())
+}
+
+/// The operations instrumented by an [OpHookInjector], indexed by the value passed to the hook.
+#[derive(Default)]
+struct OpTable {
+    ops: Vec<pcode::Instruction>,
+    indices: HashMap<pcode::Instruction, u32>,
+}
+
+impl OpTable {
+    fn index_of(&mut self, inst: pcode::Instruction) -> u32 {
+        *self.indices.entry(inst).or_insert_with(|| {
+            self.ops.push(inst);
+            self.ops.len() as u32 - 1
+        })
+    }
+}
+
+struct OpHookInjector {
+    hook: pcode::HookId,
+    op_var: VarNode,
+    ops: Rc<RefCell<OpTable>>,
+    branches: bool,
+}
+
+impl OpHookInjector {
+    fn push_hook(&self, ops: &mut OpTable, block: &mut pcode::Block, inst: pcode::Instruction) {
+        let index = ops.index_of(inst);
+        block.push((self.op_var, Op::Copy, index));
+        block.push(Op::Hook(self.hook));
+    }
+}
+
+/// Returns a branch operation representing the exit of a block if the exit depends on a value
+/// computed at runtime.
+fn exit_op(exit: &BlockExit) -> Option<pcode::Instruction> {
+    let none = Value::Const(0, 1);
+    let (hint, cond, target) = match *exit {
+        BlockExit::Branch { cond, target, .. } => {
+            let target = match target {
+                Target::External(target @ Value::Var(_)) => target,
+                _ => none,
+            };
+            (BranchHint::Jump, cond, target)
+        }
+        BlockExit::Jump { target: Target::External(target @ Value::Var(_)) } => {
+            (BranchHint::Jump, none, target)
+        }
+        BlockExit::Call { target: target @ Value::Var(_), .. } => (BranchHint::Call, none, target),
+        BlockExit::Return { target: target @ Value::Var(_) } => (BranchHint::Return, none, target),
+        _ => return None,
+    };
+    Some((Op::Branch(hint), (cond, target)).into())
+}
+
+impl CodeInjector for OpHookInjector {
+    fn inject(&mut self, _cpu: &mut Cpu, group: &BlockGroup, code: &mut BlockTable) {
+        let mut ops = self.ops.borrow_mut();
+        for id in group.range() {
+            let block = &mut code.blocks[id];
+            let instructions = std::mem::take(&mut block.pcode.instructions);
+            for inst in instructions {
+                let tracked = match inst.op {
+                    Op::Store(pcode::RAM_SPACE) => true,
+                    Op::Hook(_) | Op::HookIf(_) | Op::InstructionMarker => false,
+                    _ => !inst.output.is_invalid(),
+                };
+                if tracked {
+                    self.push_hook(&mut ops, &mut block.pcode, inst);
+                }
+                block.pcode.push(inst);
+            }
+            if let Some(inst) = exit_op(&block.exit).filter(|_| self.branches) {
+                self.push_hook(&mut ops, &mut block.pcode, inst);
+            }
+            code.modified.insert(id);
+        }
+    }
+}
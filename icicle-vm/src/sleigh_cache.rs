@@ -0,0 +1,211 @@
+//! A persistent cache of compiled SLEIGH specifications.
+//!
+//! Compiling the SLEIGH specification for a large architecture (e.g. x86-64) can take a significant
+//! amount of time compared to the rest of VM startup. The compiled specification is saved to the
+//! user's cache directory in a binary format the first time it is built, and memory-mapped from
+//! the cache on later runs.
+//!
+//! Entries are keyed by a hash of the contents of the specification files, the language ID, and
+//! any symbols defined when compiling it, so modifying the specification invalidates the entry.
+//! Errors accessing the cache are logged and otherwise ignored, falling back to compiling the
+//! specification.
+//!
+//! The cache directory can be overridden with the `ICICLE_SLEIGH_CACHE` environment variable, and
+//! caching can be disabled by clearing [icicle_cpu::Config::cache_sleigh]. Commands run by cargo in
+//! this workspace (including tests) use `target/sleigh-cache` instead (see `.cargo/config.toml`).
+
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use sleigh_compile::ldef::{CallingCov, Endianness, SleighLanguage};
+use sleigh_runtime::SleighData;
+
+use crate::{BuildError, translation_cache::hash_bytes};
+
+const VERSION: u32 = 2;
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct CachedLanguage {
+    version: u32,
+    key: u64,
+    processor: String,
+    big_endian: bool,
+    size: u32,
+    compiler: Option<String>,
+    sleigh: SleighData,
+    initial_ctx: u64,
+    pc: pcode::VarNode,
+    sp: pcode::VarNode,
+    int_args: Vec<pcode::VarNode>,
+    float_args: Vec<pcode::VarNode>,
+    unaffected: Vec<pcode::VarNode>,
+    int_return: pcode::VarNode,
+    float_return: pcode::VarNode,
+}
+
+impl CachedLanguage {
+    fn new(key: u64, lang: SleighLanguage) -> Self {
+        let cov = lang.default_calling_cov;
+        Self {
+            version: VERSION,
+            key,
+            processor: lang.processor,
+            big_endian: lang.endian == Endianness::Big,
+            size: lang.size,
+            compiler: lang.compiler,
+            sleigh: lang.sleigh,
+            initial_ctx: lang.initial_ctx,
+            pc: lang.pc,
+            sp: lang.sp,
+            int_args: cov.int_args,
+            float_args: cov.float_args,
+            unaffected: cov.unaffected,
+            int_return: cov.int_return,
+            float_return: cov.float_return,
+        }
+    }
+
+    fn into_language(self) -> SleighLanguage {
+        SleighLanguage {
+            processor: self.processor,
+            endian: if self.big_endian { Endianness::Big } else { Endianness::Little },
+            size: self.size,
+            compiler: self.compiler,
+            sleigh: self.sleigh,
+            initial_ctx: self.initial_ctx,
+            pc: self.pc,
+            sp: self.sp,
+            default_calling_cov: CallingCov {
+                int_args: self.int_args,
+                float_args: self.float_args,
+                unaffected: self.unaffected,
+                int_return: self.int_return,
+                float_return: self.float_return,
+            },
+        }
+    }
+}
+
+/// Returns the directory that compiled specifications are cached in (if any).
+pub fn cache_dir() -> Option<PathBuf> {
+    if let Some(dir) = std::env::var_os("ICICLE_SLEIGH_CACHE") {
+        return Some(dir.into());
+    }
+    let base = match std::env::var_os("XDG_CACHE_HOME") {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(std::env::var_os("HOME")?).join(".cache"),
+    };
+    Some(base.join("icicle").join("sleigh"))
+}
+
+/// Computes the cache key for the specification compiled from the files in `paths` (either files
+/// or directories, where all files directly inside the directory are included) with `id`
+/// identifying the language and compiler options.
+pub fn spec_key(paths: &[&Path], id: &str) -> std::io::Result<u64> {
+    let mut data = format!("{VERSION}:{id}").into_bytes();
+    for path in paths {
+        let mut files = vec![];
+        if path.is_dir() {
+            for entry in std::fs::read_dir(path)? {
+                let entry = entry?;
+                if entry.file_type()?.is_file() {
+                    files.push(entry.path());
+                }
+            }
+            files.sort();
+        }
+        else {
+            files.push(path.to_path_buf());
+        }
+
+        for file in files {
+            data.extend_from_slice(file.file_name().unwrap_or_default().as_encoded_bytes());
+            data.extend_from_slice(&std::fs::read(&file)?);
+        }
+    }
+    Ok(hash_bytes(&data))
+}
+
+/// Loads the specification with `key` from the cache in `dir`, returning `None` if it has not been
+/// cached.
+fn load(dir: &Path, key: u64) -> anyhow::Result<Option<SleighLanguage>> {
+    let path = entry_path(dir, key);
+    let file = match std::fs::File::open(&path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("failed to open: {}", path.display())),
+    };
+
+    // Safety: cache entries are only ever replaced by renaming a new file over them (see `save`),
+    // so the mapped file is never modified while it is in use.
+    let data = unsafe { memmap2::Mmap::map(&file) }
+        .with_context(|| format!("failed to map: {}", path.display()))?;
+    let cached: CachedLanguage = bincode::deserialize(&data)
+        .with_context(|| format!("error parsing cached SLEIGH spec: {}", path.display()))?;
+
+    if cached.version != VERSION || cached.key != key {
+        tracing::info!("cached SLEIGH spec is stale, ignoring: {}", path.display());
+        return Ok(None);
+    }
+    Ok(Some(cached.into_language()))
+}
+
+/// Saves a compiled specification to the cache in `dir`.
+fn save(dir: &Path, cached: &CachedLanguage) -> anyhow::Result<()> {
+    std::fs::create_dir_all(dir).with_context(|| format!("failed to create: {}", dir.display()))?;
+    let data = bincode::serialize(cached)?;
+
+    // Write to a temporary file first so other processes never observe a partial entry.
+    let path = entry_path(dir, cached.key);
+    let tmp = path.with_extension(format!("{}.tmp", std::process::id()));
+    std::fs::write(&tmp, data).with_context(|| format!("failed to write: {}", tmp.display()))?;
+    std::fs::rename(&tmp, &path).with_context(|| format!("failed to write: {}", path.display()))
+}
+
+/// Returns the specification compiled from the files in `paths` with `id` from the cache, using
+/// `compile` to compile it (and add it to the cache) if it is not cached.
+pub fn load_or_compile(
+    paths: &[&Path],
+    id: &str,
+    compile: impl FnOnce() -> Result<SleighLanguage, BuildError>,
+) -> Result<SleighLanguage, BuildError> {
+    match cache_dir() {
+        Some(dir) => load_or_compile_in(&dir, paths, id, compile),
+        None => compile(),
+    }
+}
+
+/// Like [load_or_compile], but using the cache in `dir` instead of the user's cache directory.
+pub fn load_or_compile_in(
+    dir: &Path,
+    paths: &[&Path],
+    id: &str,
+    compile: impl FnOnce() -> Result<SleighLanguage, BuildError>,
+) -> Result<SleighLanguage, BuildError> {
+    let key = match spec_key(paths, id) {
+        Ok(key) => key,
+        Err(e) => {
+            tracing::warn!("failed to hash SLEIGH spec: {e}");
+            return compile();
+        }
+    };
+
+    match load(dir, key) {
+        Ok(Some(lang)) => {
+            tracing::debug!("loaded cached SLEIGH spec for {id}: {key:016x}");
+            return Ok(lang);
+        }
+        Ok(None) => {}
+        Err(e) => tracing::warn!("failed to load cached SLEIGH spec: {e:#}"),
+    }
+
+    let cached = CachedLanguage::new(key, compile()?);
+    if let Err(e) = save(dir, &cached) {
+        tracing::warn!("failed to save SLEIGH spec to cache: {e:#}");
+    }
+    Ok(cached.into_language())
+}
+
+fn entry_path(dir: &Path, key: u64) -> PathBuf {
+    dir.join(format!("{key:016x}.bin"))
+}
@@ -0,0 +1,354 @@
+//! Saving the state of a VM to a file, so it can be resumed in a different process.
+//!
+//! The file contains the registers, memory, breakpoints and environment state of the VM. It does
+//! not contain the VM configuration, any I/O handlers, or the code cache, so the state must be
+//! loaded into a VM that was built and set up the same way as the VM the state was saved from (e.g.
+//! by running the same harness setup code before calling [Vm::load_state_file]).
+//!
+//! The file format is:
+//!
+//! ```text
+//! header: MAGIC, version: u32
+//! body (zstd compressed):
+//!   triple: str
+//!   registers: bytes, icount: u64, entropy: u64, exception: (u32, u64),
+//!   pending_exception: u8 (0 = none) [, (u32, u64)]
+//!   breakpoints: u64 count, [addr: u64]
+//!   breakpoint metadata: u64 count, [addr: u64, enabled: u8, hit_count: u64, ignore_count: u64,
+//!     temporary: u8]
+//!   memory: u64 count, [start: u64, end: u64, region]
+//!   env: u8 (0 = not saved) [, bytes]
+//! ```
+//!
+//! where `str` and `bytes` are stored as a u64 length followed by the data, and `region` is one of:
+//!
+//! - `0, perm: u8, value: u8`: an unallocated region.
+//! - `1, data: [u8; len], perm: [u8; len]`: a region backed by memory.
+//! - `2, data: [u8; len], perm: [u8; len]`: a region of memory shared with the guest physical
+//!   address space (which is updated in place instead of being remapped).
+//!
+//! All integers are stored in little-endian byte order.
+
+use std::{collections::BTreeMap, path::Path};
+
+use anyhow::Context;
+use icicle_cpu::{
+    Exception, ShadowStack,
+    mem::{Mapping, MemoryMapping, perm},
+};
+
+use crate::{Vm, breakpoints::Breakpoint};
+
+const MAGIC: &[u8; 8] = b"ICLSNAP\0";
+const VERSION: u32 = 3;
+
+/// The zstd compression level used for the body of the file.
+const COMPRESSION_LEVEL: i32 = 3;
+
+const REGION_UNALLOCATED: u8 = 0;
+const REGION_PHYSICAL: u8 = 1;
+const REGION_ALIASED: u8 = 2;
+
+impl Vm {
+    /// Saves the current state of the VM to `path`, see [snapshot_file](crate::snapshot_file).
+    pub fn save_state_file(&mut self, path: &Path) -> anyhow::Result<()> {
+        let data = self.save_state()?;
+        std::fs::write(path, data).with_context(|| format!("failed to write: {}", path.display()))
+    }
+
+    /// Loads the state of the VM from a file created by [Vm::save_state_file].
+    pub fn load_state_file(&mut self, path: &Path) -> anyhow::Result<()> {
+        let data =
+            std::fs::read(path).with_context(|| format!("failed to read: {}", path.display()))?;
+        self.load_state(&data).with_context(|| format!("failed to load: {}", path.display()))
+    }
+
+    /// Serializes the current state of the VM.
+    pub fn save_state(&mut self) -> anyhow::Result<Vec<u8>> {
+        let env = self.env.save_state().map_err(|e| anyhow::format_err!("{e}"))?;
+        self.save_state_inner(Some(&env))
+    }
+
+    /// Serializes the current state of the VM, excluding the state of the environment.
+    ///
+    /// Loading the state keeps the current state of the environment, so this is only useful when
+    /// the environment of the VM the state is loaded into is already in the same state, e.g. when
+    /// the state was saved at a point that is reached deterministically by the setup code. This
+    /// allows saving the state of environments that do not support
+    /// [Environment::save_state](icicle_cpu::Environment::save_state).
+    pub fn save_state_without_env(&mut self) -> anyhow::Result<Vec<u8>> {
+        self.save_state_inner(None)
+    }
+
+    fn save_state_inner(&mut self, env: Option<&[u8]>) -> anyhow::Result<Vec<u8>> {
+        let mut w = Writer::default();
+        w.bytes(self.cpu.arch.triple.to_string().as_bytes());
+
+        let valid_regs = self.cpu.arch.sleigh.num_registers();
+        w.bytes(self.cpu.regs.valid_bytes(valid_regs));
+        w.u64(self.cpu.icount);
+        w.u64(self.cpu.entropy.state());
+        w.exception(self.cpu.exception);
+        match self.cpu.pending_exception {
+            Some(exception) => {
+                w.u8(1);
+                w.exception(exception);
+            }
+            None => w.u8(0),
+        }
+
+        let mut breakpoints: Vec<_> = self.code.breakpoints.iter().copied().collect();
+        breakpoints.sort_unstable();
+        w.u64(breakpoints.len() as u64);
+        breakpoints.iter().for_each(|addr| w.u64(*addr));
+        w.u64(self.breakpoint_info.len() as u64);
+        for (addr, bp) in &self.breakpoint_info {
+            w.u64(*addr);
+            w.u8(bp.enabled as u8);
+            w.u64(bp.hit_count);
+            w.u64(bp.ignore_count);
+            w.u8(bp.temporary as u8);
+        }
+
+        self.save_memory(&mut w);
+        match env {
+            Some(env) => {
+                w.u8(1);
+                w.bytes(env);
+            }
+            None => w.u8(0),
+        }
+
+        let mut out = MAGIC.to_vec();
+        out.extend_from_slice(&VERSION.to_le_bytes());
+        out.extend_from_slice(&zstd::bulk::compress(&w.0, COMPRESSION_LEVEL)?);
+        Ok(out)
+    }
+
+    fn save_memory(&mut self, w: &mut Writer) {
+        let mem = &self.cpu.mem;
+        let regions: Vec<_> = mem
+            .get_mapping()
+            .iter()
+            .filter(|(_, _, entry)| {
+                matches!(entry, MemoryMapping::Physical(_) | MemoryMapping::Unallocated(_))
+            })
+            .collect();
+
+        w.u64(regions.len() as u64);
+        for (start, end, entry) in regions {
+            w.u64(start);
+            w.u64(end);
+            match entry {
+                MemoryMapping::Unallocated(x) => {
+                    w.u8(REGION_UNALLOCATED);
+                    w.u8(x.perm);
+                    w.u8(x.value);
+                }
+                MemoryMapping::Physical(x) if x.index.is_zero_page() => {
+                    // Zero pages have the same permissions for every byte, and are recreated
+                    // lazily when loaded.
+                    let page = mem.get_physical(x.index);
+                    w.u8(REGION_UNALLOCATED);
+                    w.u8(page.data().perm[mem.page_offset(start)]);
+                    w.u8(0);
+                }
+                MemoryMapping::Physical(x) => {
+                    let page = mem.get_physical(x.index);
+                    let offset = mem.page_offset(start);
+                    let len = (end - start + 1) as usize;
+                    w.u8(if page.aliased { REGION_ALIASED } else { REGION_PHYSICAL });
+                    w.0.extend_from_slice(&page.data().data[offset..][..len]);
+                    w.0.extend_from_slice(&page.data().perm[offset..][..len]);
+                }
+                _ => unreachable!(),
+            }
+        }
+    }
+
+    /// Loads state serialized by [Vm::save_state].
+    pub fn load_state(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        if data.len() < 12 || &data[..8] != MAGIC {
+            anyhow::bail!("invalid snapshot file");
+        }
+        let version = u32::from_le_bytes(data[8..12].try_into().unwrap());
+        if version != VERSION {
+            anyhow::bail!("unsupported snapshot version: {version} (expected {VERSION})");
+        }
+        let body = zstd::decode_all(&data[12..]).context("failed to decompress snapshot")?;
+        let mut r = Reader(&body);
+
+        let triple = String::from_utf8_lossy(r.bytes()?).into_owned();
+        if triple != self.cpu.arch.triple.to_string() {
+            anyhow::bail!("snapshot was saved for {triple}, not {}", self.cpu.arch.triple);
+        }
+
+        let valid_regs = self.cpu.arch.sleigh.num_registers();
+        let regs = r.bytes()?;
+        if regs.len() != self.cpu.regs.valid_bytes(valid_regs).len() {
+            anyhow::bail!("snapshot register layout does not match the VM");
+        }
+        self.cpu.regs.valid_bytes_mut(valid_regs).copy_from_slice(regs);
+        self.cpu.icount = r.u64()?;
+        self.cpu.entropy.set_state(r.u64()?);
+        self.cpu.exception = r.exception()?;
+        self.cpu.pending_exception = match r.u8()? {
+            0 => None,
+            _ => Some(r.exception()?),
+        };
+        // Call stack tracking and block IDs are tied to the code cache, which is not saved.
+        self.cpu.shadow_stack = ShadowStack::new();
+        self.cpu.block_id = u64::MAX;
+        self.cpu.block_offset = 0;
+
+        let breakpoints = (0..r.u64()?).map(|_| r.u64()).collect::<anyhow::Result<Vec<_>>>()?;
+        let mut breakpoint_info = BTreeMap::new();
+        for _ in 0..r.u64()? {
+            let addr = r.u64()?;
+            let bp = Breakpoint {
+                enabled: r.u8()? != 0,
+                hit_count: r.u64()?,
+                ignore_count: r.u64()?,
+                temporary: r.u8()? != 0,
+            };
+            breakpoint_info.insert(addr, bp);
+        }
+
+        self.load_memory(&mut r)?;
+
+        if r.u8()? != 0 {
+            let env = r.bytes()?;
+            self.env.load_state(env).map_err(|e| anyhow::format_err!("{e}"))?;
+        }
+
+        // All previously translated code is potentially invalid.
+        self.code.flush_code();
+        if self.enable_jit {
+            self.jit.clear();
+        }
+        self.prev_isa_mode = u8::MAX;
+        self.clear_sanitizer_reports();
+
+        let existing: Vec<_> = self.code.breakpoints.iter().copied().collect();
+        for addr in existing {
+            self.remove_breakpoint(addr);
+        }
+        for addr in breakpoints {
+            self.arm_breakpoint(addr);
+        }
+        self.breakpoint_info = breakpoint_info;
+
+        self.update_context();
+        Ok(())
+    }
+
+    fn load_memory(&mut self, r: &mut Reader) -> anyhow::Result<()> {
+        let mem = &mut self.cpu.mem;
+
+        // Remove all memory that is not handled by I/O handlers or shared with the guest physical
+        // address space.
+        let existing: Vec<_> = mem
+            .get_mapping()
+            .iter()
+            .filter(|(_, _, entry)| match entry {
+                MemoryMapping::Physical(x) => !mem.get_physical(x.index).aliased,
+                MemoryMapping::Unallocated(_) => true,
+                _ => false,
+            })
+            .map(|(start, end, _)| (start, end))
+            .collect();
+        for (start, end) in existing {
+            mem.unmap_memory_len(start, end - start + 1);
+        }
+
+        for _ in 0..r.u64()? {
+            let (start, end) = (r.u64()?, r.u64()?);
+            let len = end - start + 1;
+            match r.u8()? {
+                REGION_UNALLOCATED => {
+                    let mapping = Mapping { perm: r.u8()?, value: r.u8()? };
+                    if !mem.map_memory_len(start, len, mapping) {
+                        anyhow::bail!("failed to map memory at {start:#x}");
+                    }
+                }
+                kind @ (REGION_PHYSICAL | REGION_ALIASED) => {
+                    let data = r.take(len as usize)?;
+                    let perm = r.take(len as usize)?;
+                    if kind == REGION_PHYSICAL {
+                        let mapping = Mapping { perm: perm::NONE, value: 0 };
+                        if !mem.map_memory_len(start, len, mapping) {
+                            anyhow::bail!("failed to map memory at {start:#x}");
+                        }
+                    }
+                    mem.write_bytes(start, data, perm::NONE)
+                        .map_err(|e| anyhow::format_err!("failed to write {start:#x}: {e:?}"))?;
+                    let index = mem
+                        .get_physical_index(start)
+                        .ok_or_else(|| anyhow::format_err!("{start:#x} is not backed by memory"))?;
+                    let offset = mem.page_offset(start);
+                    mem.get_physical_mut(index).data_mut().perm[offset..][..perm.len()]
+                        .copy_from_slice(perm);
+                }
+                kind => anyhow::bail!("unknown memory region kind: {kind}"),
+            }
+        }
+
+        // Permissions were modified directly, so any cached translations may be stale.
+        mem.tlb.clear();
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn u8(&mut self, value: u8) {
+        self.0.push(value);
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn bytes(&mut self, value: &[u8]) {
+        self.u64(value.len() as u64);
+        self.0.extend_from_slice(value);
+    }
+
+    fn exception(&mut self, value: Exception) {
+        self.0.extend_from_slice(&value.code.to_le_bytes());
+        self.u64(value.value);
+    }
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> anyhow::Result<&'a [u8]> {
+        if self.0.len() < len {
+            anyhow::bail!("unexpected end of snapshot");
+        }
+        let (data, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(data)
+    }
+
+    fn u8(&mut self) -> anyhow::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u64(&mut self) -> anyhow::Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn bytes(&mut self) -> anyhow::Result<&'a [u8]> {
+        let len = self.u64()?;
+        self.take(len as usize)
+    }
+
+    fn exception(&mut self) -> anyhow::Result<Exception> {
+        let code = u32::from_le_bytes(self.take(4)?.try_into().unwrap());
+        Ok(Exception { code, value: self.u64()? })
+    }
+}
@@ -0,0 +1,168 @@
+//! Management of multiple snapshots organized as a tree.
+//!
+//! Every snapshot taken with [SnapshotTree::take] becomes a child of the snapshot that the VM was
+//! last restored from (or taken at), so the tree records how the different execution branches were
+//! explored. Memory snapshots are copy-on-write, so a snapshot only owns the pages that were
+//! modified since its parent was taken, which is reported as the cost of the node.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    rc::Rc,
+};
+
+use icicle_cpu::mem::physical::PageData;
+
+use crate::{Snapshot, Vm};
+
+pub type SnapshotId = usize;
+
+pub struct SnapshotNode {
+    /// The name the snapshot was saved with, if any.
+    pub name: Option<String>,
+
+    pub parent: Option<SnapshotId>,
+    pub children: Vec<SnapshotId>,
+
+    /// The instruction count at the time the snapshot was taken.
+    pub icount: u64,
+
+    /// The number of memory pages that are not shared with the parent snapshot.
+    pub unique_pages: usize,
+
+    pub snapshot: Rc<Snapshot>,
+}
+
+impl SnapshotNode {
+    /// An estimate of the memory (in bytes) used by this snapshot in addition to its parent.
+    pub fn memory_cost(&self) -> usize {
+        self.unique_pages * std::mem::size_of::<PageData>()
+    }
+}
+
+#[derive(Default)]
+pub struct SnapshotTree {
+    nodes: BTreeMap<SnapshotId, SnapshotNode>,
+    names: HashMap<String, SnapshotId>,
+    next_id: SnapshotId,
+
+    /// The snapshot that was most recently taken or restored.
+    current: Option<SnapshotId>,
+}
+
+impl SnapshotTree {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Takes a snapshot of the current VM state and adds it as a child of the current snapshot.
+    pub fn take(&mut self, vm: &mut Vm, name: Option<&str>) -> Result<SnapshotId, String> {
+        if let Some(name) = name {
+            if self.names.contains_key(name) {
+                return Err(format!("a snapshot named `{name}` already exists"));
+            }
+        }
+
+        let snapshot = Rc::new(vm.snapshot());
+        let parent = self.current;
+        let unique_pages = self.count_unique_pages(&snapshot, parent);
+
+        let id = self.next_id;
+        self.next_id += 1;
+        if let Some(parent) = parent.and_then(|x| self.nodes.get_mut(&x)) {
+            parent.children.push(id);
+        }
+        if let Some(name) = name {
+            self.names.insert(name.to_owned(), id);
+        }
+        self.nodes.insert(id, SnapshotNode {
+            name: name.map(str::to_owned),
+            parent,
+            children: vec![],
+            icount: vm.cpu.icount(),
+            unique_pages,
+            snapshot,
+        });
+        self.current = Some(id);
+
+        Ok(id)
+    }
+
+    /// Restores the VM to the state saved in snapshot `id`. New snapshots will become children of
+    /// this snapshot.
+    pub fn restore(&mut self, vm: &mut Vm, id: SnapshotId) -> Result<(), String> {
+        let node = self.nodes.get(&id).ok_or_else(|| format!("unknown snapshot: {id}"))?;
+        vm.restore(&node.snapshot);
+        self.current = Some(id);
+        Ok(())
+    }
+
+    /// Restores the VM to the state saved in the snapshot called `name`.
+    pub fn restore_named(&mut self, vm: &mut Vm, name: &str) -> Result<(), String> {
+        let id = self.lookup(name).ok_or_else(|| format!("unknown snapshot: {name}"))?;
+        self.restore(vm, id)
+    }
+
+    /// Removes the snapshot `id` from the tree, any children of the snapshot are moved to its
+    /// parent.
+    pub fn remove(&mut self, id: SnapshotId) -> Result<(), String> {
+        let node = self.nodes.remove(&id).ok_or_else(|| format!("unknown snapshot: {id}"))?;
+        if let Some(name) = node.name.as_ref() {
+            self.names.remove(name);
+        }
+        if self.current == Some(id) {
+            self.current = node.parent;
+        }
+
+        if let Some(parent) = node.parent.and_then(|x| self.nodes.get_mut(&x)) {
+            parent.children.retain(|&x| x != id);
+            parent.children.extend_from_slice(&node.children);
+        }
+        for child in node.children {
+            let unique_pages = match self.nodes.get(&child) {
+                Some(x) => self.count_unique_pages(&x.snapshot, node.parent),
+                None => continue,
+            };
+            let child = self.nodes.get_mut(&child).unwrap();
+            child.parent = node.parent;
+            child.unique_pages = unique_pages;
+        }
+
+        Ok(())
+    }
+
+    pub fn lookup(&self, name: &str) -> Option<SnapshotId> {
+        self.names.get(name).copied()
+    }
+
+    pub fn get(&self, id: SnapshotId) -> Option<&SnapshotNode> {
+        self.nodes.get(&id)
+    }
+
+    /// Gets the snapshot that was most recently taken or restored.
+    pub fn current(&self) -> Option<SnapshotId> {
+        self.current
+    }
+
+    /// Iterates over all snapshots in the tree that do not have a parent.
+    pub fn roots(&self) -> impl Iterator<Item = SnapshotId> + '_ {
+        self.nodes.iter().filter(|(_, node)| node.parent.is_none()).map(|(id, _)| *id)
+    }
+
+    /// Iterates over all snapshots in the tree in the order they were taken.
+    pub fn iter(&self) -> impl Iterator<Item = (SnapshotId, &SnapshotNode)> {
+        self.nodes.iter().map(|(id, node)| (*id, node))
+    }
+
+    /// An estimate of the total memory (in bytes) used by all snapshots in the tree.
+    pub fn total_memory_cost(&self) -> usize {
+        self.nodes.values().map(|x| x.memory_cost()).sum()
+    }
+
+    fn count_unique_pages(&self, snapshot: &Snapshot, parent: Option<SnapshotId>) -> usize {
+        let physical = &snapshot.mem.physical;
+        match parent.and_then(|x| self.nodes.get(&x)) {
+            Some(parent) => physical.count_unshared_pages(&parent.snapshot.mem.physical),
+            None => physical.allocated_pages(),
+        }
+    }
+}
@@ -0,0 +1,220 @@
+//! Annotates execution traces with source locations from debug info.
+//!
+//! Executed instructions are interleaved with the function, file and line (and the text of the
+//! source line if the file is available) whenever these change, e.g.:
+//!
+//! ```text
+//! main:
+//!   src/main.c:10: int x = parse(input);
+//!     0x401000 [12]: MOV EAX,dword ptr [RBP + -0x4]
+//! ```
+//!
+//! Traces can either be annotated after execution from a list of executed blocks (e.g. from a
+//! [crate::injector::PathTracerRef] or a [crate::trace_file::TraceReader]), or captured while
+//! executing with [add_source_tracer].
+
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    io::{self, Write},
+    rc::Rc,
+};
+
+use icicle_cpu::{BlockGroup, BlockTable, Cpu, debug_info::SourceLocation};
+
+use crate::{Vm, injector::CodeInjector};
+
+/// Keeps track of the last source location written so that annotations are only emitted when the
+/// location changes.
+#[derive(Default)]
+pub struct SourceAnnotator {
+    /// Whether to include the text of each source line (if the source file can be read).
+    pub include_source: bool,
+
+    /// Cached source locations for each instruction.
+    locations: HashMap<u64, Option<SourceLocation>>,
+
+    /// Cached lines of each source file (or `None` if the file could not be read).
+    files: HashMap<String, Option<Vec<String>>>,
+
+    /// The index of each lifted block keyed by its start address.
+    blocks: HashMap<u64, usize>,
+
+    last_function: Option<String>,
+    last_line: Option<(String, u32)>,
+}
+
+impl SourceAnnotator {
+    pub fn new() -> Self {
+        Self { include_source: true, ..Self::default() }
+    }
+
+    /// Writes the instruction at `addr` (executed at `icount`), preceded by the function and source
+    /// line of the instruction if they differ from the previous instruction.
+    pub fn write_instruction(
+        &mut self,
+        vm: &mut Vm,
+        addr: u64,
+        icount: u64,
+        out: &mut dyn Write,
+    ) -> io::Result<()> {
+        let location = self
+            .locations
+            .entry(addr)
+            .or_insert_with(|| vm.env.symbolize_addr(&mut vm.cpu, addr))
+            .clone()
+            .unwrap_or_default();
+
+        let function = location.label();
+        if function.is_some() && function != self.last_function {
+            writeln!(out, "{}:", function.as_deref().unwrap_or("<unknown>"))?;
+            self.last_function = function;
+            self.last_line = None;
+        }
+
+        if let (Some(file), Some(line)) = (location.file, location.line) {
+            if self.last_line.as_ref() != Some(&(file.clone(), line)) {
+                write!(out, "  {file}:{line}:")?;
+                if self.include_source {
+                    if let Some(text) = self.source_line(&file, line) {
+                        write!(out, " {}", text.trim())?;
+                    }
+                }
+                writeln!(out)?;
+                self.last_line = Some((file, line));
+            }
+        }
+
+        match vm.code.disasm.get(&addr) {
+            Some(disasm) => writeln!(out, "    {addr:#x} [{icount}]: {disasm}"),
+            None => writeln!(out, "    {addr:#x} [{icount}]"),
+        }
+    }
+
+    /// Writes the instructions of the block starting at `addr` (entered at `icount`), stopping
+    /// after `count` instructions if the block exited early. If the block has not been lifted by
+    /// `vm` only the start of the block is written.
+    pub fn write_block(
+        &mut self,
+        vm: &mut Vm,
+        addr: u64,
+        icount: u64,
+        count: Option<u64>,
+        out: &mut dyn Write,
+    ) -> io::Result<()> {
+        if self.blocks.len() != vm.code.blocks.len() {
+            self.blocks.clear();
+            for (i, block) in vm.code.blocks.iter().enumerate() {
+                self.blocks.entry(block.start).or_insert(i);
+            }
+        }
+
+        let Some(&id) = self.blocks.get(&addr)
+        else {
+            return self.write_instruction(vm, addr, icount, out);
+        };
+        let count = count.map_or(usize::MAX, |count| count as usize);
+        let instructions: Vec<_> = vm.code.blocks[id].instructions().take(count).collect();
+        for (i, (addr, _)) in instructions.into_iter().enumerate() {
+            self.write_instruction(vm, addr, icount + i as u64, out)?;
+        }
+        Ok(())
+    }
+
+    fn source_line(&mut self, file: &str, line: u32) -> Option<&str> {
+        let lines = self.files.entry(file.to_owned()).or_insert_with(|| {
+            let data = std::fs::read_to_string(file).ok()?;
+            Some(data.lines().map(str::to_owned).collect())
+        });
+        lines.as_ref()?.get((line as usize).checked_sub(1)?).map(String::as_str)
+    }
+}
+
+/// Annotates a trace of executed blocks (a list of `(block address, icount)` entries) with source
+/// locations, writing the result to `out`.
+///
+/// The number of instructions executed in each block is determined from the instruction count of
+/// the next entry, and the trace is assumed to end at the current instruction count of `vm` (if
+/// it is after the start of the last block).
+pub fn annotate_trace(
+    vm: &mut Vm,
+    entries: impl IntoIterator<Item = (u64, u64)>,
+    mut out: impl Write,
+) -> io::Result<()> {
+    let mut annotator = SourceAnnotator::new();
+    let mut entries = entries.into_iter().peekable();
+    while let Some((addr, icount)) = entries.next() {
+        let end = entries.peek().map_or(vm.cpu.icount(), |(_, next)| *next);
+        let count = end.checked_sub(icount).filter(|count| *count != 0);
+        annotator.write_block(vm, addr, icount, count, &mut out)?;
+    }
+    out.flush()
+}
+
+/// A handle to a source tracer attached to a VM.
+#[derive(Clone)]
+pub struct SourceTracer {
+    state: Rc<RefCell<SourceTracerState>>,
+}
+
+struct SourceTracerState {
+    out: Box<dyn Write>,
+    annotator: SourceAnnotator,
+    /// Instructions executed since the last flush.
+    pending: Vec<(u64, u64)>,
+}
+
+impl SourceTracer {
+    /// Annotates and writes all instructions executed since the last call to `flush`.
+    ///
+    /// Symbolization and disassembly require access to the VM, so executed instructions are
+    /// buffered until this is called. To bound memory usage during long executions, run the VM in
+    /// smaller steps and call this after each step.
+    pub fn flush(&self, vm: &mut Vm) -> io::Result<()> {
+        let state = &mut *self.state.borrow_mut();
+        for (addr, icount) in state.pending.drain(..) {
+            state.annotator.write_instruction(vm, addr, icount, &mut state.out)?;
+        }
+        state.out.flush()
+    }
+}
+
+/// Attaches a tracer to the VM that records every executed instruction to be written to `out` with
+/// source annotations (see [SourceTracer::flush]). Only code lifted after the tracer is attached is
+/// traced, so this should be called before the VM starts executing.
+pub fn add_source_tracer(vm: &mut Vm, out: impl Write + 'static) -> SourceTracer {
+    let state = Rc::new(RefCell::new(SourceTracerState {
+        out: Box::new(out),
+        annotator: SourceAnnotator::new(),
+        pending: vec![],
+    }));
+
+    let hook_state = state.clone();
+    let hook = vm.cpu.add_hook(move |cpu: &mut Cpu, addr: u64| {
+        hook_state.borrow_mut().pending.push((addr, cpu.icount()));
+    });
+    vm.add_injector(SourceTracerInjector { hook });
+
+    SourceTracer { state }
+}
+
+/// Inserts a call to the tracer hook at the start of every instruction.
+struct SourceTracerInjector {
+    hook: pcode::HookId,
+}
+
+impl CodeInjector for SourceTracerInjector {
+    fn inject(&mut self, _cpu: &mut Cpu, group: &BlockGroup, code: &mut BlockTable) {
+        for id in group.range() {
+            let block = &mut code.blocks[id];
+            let instructions = std::mem::take(&mut block.pcode.instructions);
+            for inst in instructions {
+                block.pcode.push(inst);
+                if let pcode::Op::InstructionMarker = inst.op {
+                    block.pcode.push(pcode::Op::Hook(self.hook));
+                }
+            }
+            code.modified.insert(id);
+        }
+    }
+}
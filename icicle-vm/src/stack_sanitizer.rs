@@ -0,0 +1,375 @@
+//! A sanitizer for stack-buffer-overflows in guests with DWARF debug info.
+//!
+//! Overflows of stack buffers frequently overwrite adjacent variables or saved registers without
+//! touching the guard page, so they are only detected (if at all) when the corrupted data is used.
+//! This sanitizer detects them at the invalid write instead.
+//!
+//! The layout of each function's frame is derived from the variables in the debug info that are
+//! stored at a fixed offset from the frame base (see [FrameLayout]). When code is lifted, the
+//! value of the frame base is tracked through the P-code of each block (starting from the
+//! register used to define the frame base, or the CFA rule of the call frame information), and
+//! every store to an address derived from the frame base is assigned to the object that the
+//! constant part of the address points into. A check is then inserted before the store that
+//! raises [ExceptionCode::StackBufferOverflow] if the address computed at runtime (e.g. after
+//! adding an index) writes outside of that object, and the details are available as an
+//! [AsanReport].
+//!
+//! Note: only stores with an address computed from the frame base within a single block are
+//! checked, so the sanitizer is most effective for unoptimized code (where variables are accessed
+//! relative to the frame base and are not kept in registers). Writes through pointers loaded from
+//! memory (e.g. by `memcpy`) are not checked.
+
+use std::{cell::RefCell, collections::HashMap, fmt::Write, rc::Rc};
+
+use icicle_cpu::{
+    BlockGroup, BlockTable, Cpu, Exception, ExceptionCode, ValueSource,
+    debug_info::{DebugInfo, FrameBase, FrameLayout, FrameObject, dwarf_register_name},
+};
+use pcode::{Op, Value, VarNode};
+
+use crate::{
+    Vm,
+    asan::{AsanAccess, AsanReport},
+    injector::CodeInjector,
+};
+
+/// A store that was checked against the bounds of a frame object.
+#[derive(Clone)]
+struct StoreCheck {
+    /// The address of the instruction performing the store.
+    pc: u64,
+
+    /// The number of bytes written by the store.
+    size: u64,
+
+    function: Option<String>,
+
+    object: FrameObject,
+}
+
+struct StackState {
+    debug_info: DebugInfo,
+
+    /// Every check inserted into the code, indexed by the value assigned to `check_var`.
+    checks: Vec<StoreCheck>,
+
+    /// The last error detected by the sanitizer.
+    report: Option<AsanReport>,
+}
+
+impl StackState {
+    fn set_report(&mut self, cpu: &mut Cpu, addr: u64, object_addr: u64, check: StoreCheck) {
+        let object = &check.object;
+        let end = object_addr.wrapping_add(object.size);
+        let location = match addr {
+            x if x < object_addr => format!("{} bytes before", object_addr - x),
+            x if x >= end => format!("{} bytes after", x - end),
+            x => format!("{} bytes inside of", x - object_addr),
+        };
+        let function = check.function.as_deref().unwrap_or("<unknown>");
+        let mut details = String::new();
+        let _ = writeln!(
+            details,
+            "{addr:#x} is located {location} {}-byte object `{}` [{object_addr:#x},{end:#x}) in \
+             the frame of `{function}`",
+            object.size, object.name
+        );
+
+        let bug_type = "stack-buffer-overflow";
+        let report = AsanReport {
+            bug_type: Some(bug_type.into()),
+            access: Some(AsanAccess { addr, size: check.size, is_write: true, pc: check.pc }),
+            summary: Some(format!("SUMMARY: StackSanitizer: {bug_type} on address {addr:#x}")),
+            details: Some(details),
+        };
+        tracing::error!("StackSanitizer: {report}");
+        self.report = Some(report);
+        cpu.exception = Exception::new(ExceptionCode::StackBufferOverflow, addr);
+    }
+}
+
+/// A handle to the stack sanitizer attached to a VM. Cloning the handle produces a handle to the
+/// same state.
+#[derive(Clone)]
+pub struct StackSanitizer {
+    state: Rc<RefCell<StackState>>,
+}
+
+impl StackSanitizer {
+    /// Gets the report for the most recent error detected by the sanitizer.
+    pub fn last_report(&self) -> Option<AsanReport> {
+        self.state.borrow().report.clone()
+    }
+
+    /// Removes and returns the report for the most recent error detected by the sanitizer.
+    pub fn take_report(&self) -> Option<AsanReport> {
+        self.state.borrow_mut().report.take()
+    }
+
+    /// The number of stores that have been instrumented with a bounds check.
+    pub fn check_count(&self) -> usize {
+        self.state.borrow().checks.len()
+    }
+}
+
+/// Attaches the stack sanitizer to the VM. Returns `None` if the environment has no debug info.
+///
+/// Only code lifted after the sanitizer is attached is checked, and only the debug info available
+/// when the sanitizer is attached is used (i.e. this should be called after the binary is loaded).
+pub fn attach(vm: &mut Vm) -> Option<StackSanitizer> {
+    let debug_info = vm.env.debug_info()?.clone();
+
+    let addr_var = vm.cpu.arch.sleigh.add_custom_reg("stack_sanitizer.addr", 8)?;
+    let object_var = vm.cpu.arch.sleigh.add_custom_reg("stack_sanitizer.object", 8)?;
+    let check_var = vm.cpu.arch.sleigh.add_custom_reg("stack_sanitizer.check", 4)?;
+
+    let state = StackState { debug_info, checks: vec![], report: None };
+    let sanitizer = StackSanitizer { state: Rc::new(RefCell::new(state)) };
+
+    let hook_state = sanitizer.state.clone();
+    let hook = vm.cpu.add_hook(move |cpu: &mut Cpu, _addr: u64| {
+        let addr = cpu.read_var::<u64>(addr_var);
+        let object_addr = cpu.read_var::<u64>(object_var);
+        let index = cpu.read_var::<u32>(check_var) as usize;
+        let mut state = hook_state.borrow_mut();
+        let check = state.checks[index].clone();
+        state.set_report(cpu, addr, object_addr, check);
+    });
+
+    vm.add_injector(StackInjector {
+        hook,
+        addr_var,
+        object_var,
+        check_var,
+        state: sanitizer.state.clone(),
+    });
+    vm.stack_sanitizer = Some(sanitizer.clone());
+
+    Some(sanitizer)
+}
+
+/// A value computed from the frame base.
+#[derive(Clone, Copy)]
+struct FrameValue {
+    var: VarNode,
+
+    /// The constant offset of the value from the frame base.
+    offset: i64,
+
+    /// Whether an unknown value (e.g. an index) was added to the value.
+    dynamic: bool,
+}
+
+/// Tracks the varnodes that contain values derived from the frame base within a block.
+struct FrameTracker {
+    layout: FrameLayout,
+
+    /// The address of the current instruction.
+    pc: u64,
+
+    /// The register that the frame base is computed from at the current instruction.
+    anchor: Option<VarNode>,
+
+    values: HashMap<pcode::VarId, FrameValue>,
+}
+
+impl FrameTracker {
+    fn new(layout: FrameLayout) -> Self {
+        Self { layout, pc: 0, anchor: None, values: HashMap::new() }
+    }
+
+    /// Starts tracking the instruction at `pc`, where the frame base is the value of `anchor`
+    /// plus an offset.
+    fn start_instruction(&mut self, pc: u64, anchor: Option<(VarNode, i64)>) {
+        // The frame base is the same for the entire function, so values derived from it stay valid
+        // across instructions.
+        self.pc = pc;
+        self.anchor = None;
+        if let Some((var, offset)) = anchor {
+            let value = FrameValue { var, offset: offset.wrapping_neg(), dynamic: false };
+            self.values.insert(var.id, value);
+            self.anchor = Some(var);
+        }
+    }
+
+    fn get(&self, value: Value) -> Option<FrameValue> {
+        match value {
+            Value::Var(var) => self.values.get(&var.id).copied().filter(|x| x.var == var),
+            Value::Const(..) => None,
+        }
+    }
+
+    /// Updates the tracked values after `inst` is executed.
+    fn step(&mut self, inst: &pcode::Instruction) {
+        let out = inst.output;
+        if out.is_invalid() {
+            return;
+        }
+
+        let [a, b] = inst.inputs.get();
+        let value = match inst.op {
+            Op::Copy => self.get(a).map(|x| (x.offset, x.dynamic)),
+            Op::IntAdd => match (self.get(a), self.get(b)) {
+                (Some(x), None) => Some(add(x, b, false)),
+                (None, Some(x)) => Some(add(x, a, false)),
+                _ => None,
+            },
+            Op::IntSub => match (self.get(a), self.get(b)) {
+                (Some(x), None) => Some(add(x, b, true)),
+                _ => None,
+            },
+            _ => None,
+        };
+
+        self.values.remove(&out.id);
+        if let Some((offset, dynamic)) = value {
+            self.values.insert(out.id, FrameValue { var: out, offset, dynamic });
+        }
+    }
+
+    /// Finds the object that a store of `size` bytes to `addr` should be checked against, if the
+    /// address is derived from the frame base and the store could write outside of the object.
+    /// Returns the anchor register, the offset of the object from the anchor, and the object.
+    fn store_target(&self, addr: Value, size: u64) -> Option<(VarNode, i64, &FrameObject)> {
+        let target = self.get(addr)?;
+        let object = self.layout.object_at(target.offset)?;
+
+        // Stores to a constant offset inside of the object can never overflow.
+        let end = object.offset.saturating_add(object.size as i64);
+        if !target.dynamic && target.offset.saturating_add(size as i64) <= end {
+            return None;
+        }
+
+        let anchor = self.anchor?;
+        let base = self.get(anchor.into()).filter(|x| !x.dynamic)?;
+        if anchor.size != target.var.size {
+            return None;
+        }
+        Some((anchor, object.offset.wrapping_sub(base.offset), object))
+    }
+}
+
+/// Computes the offset of `base + value` (or `base - value` if `negate` is set).
+fn add(base: FrameValue, value: Value, negate: bool) -> (i64, bool) {
+    match value {
+        Value::Const(x, size) => {
+            let x = pcode::sxt64(x, size as u64 * 8) as i64;
+            let x = if negate { x.wrapping_neg() } else { x };
+            (base.offset.wrapping_add(x), base.dynamic)
+        }
+        Value::Var(_) => (base.offset, true),
+    }
+}
+
+struct StackInjector {
+    hook: pcode::HookId,
+    addr_var: VarNode,
+    object_var: VarNode,
+    check_var: VarNode,
+    state: Rc<RefCell<StackState>>,
+}
+
+impl StackInjector {
+    /// Inserts a check that calls the hook if a store to `addr` writes outside of the object
+    /// located at `anchor + delta`.
+    fn push_check(
+        &self,
+        block: &mut pcode::Block,
+        anchor: VarNode,
+        delta: i64,
+        addr: Value,
+        check: &StoreCheck,
+        index: u32,
+    ) {
+        // overflow = (addr - object_addr) > (object.size - size)
+        let object_addr = block.alloc_tmp(anchor.size);
+        block.push((object_addr, Op::IntAdd, anchor, Value::Const(delta as u64, anchor.size)));
+        let rel = block.alloc_tmp(anchor.size);
+        block.push((rel, Op::IntSub, addr, object_addr));
+        let overflow = block.alloc_tmp(1);
+        match check.size > check.object.size {
+            true => block.push(overflow.copy_from(Value::Const(1, 1))),
+            false => {
+                let limit = Value::Const(check.object.size - check.size, anchor.size);
+                block.push((overflow, Op::IntLess, limit, rel));
+            }
+        }
+        match anchor.size {
+            8 => {
+                block.push(self.addr_var.copy_from(addr));
+                block.push(self.object_var.copy_from(object_addr));
+            }
+            _ => {
+                block.push(self.addr_var.zext_from(addr));
+                block.push(self.object_var.zext_from(object_addr));
+            }
+        }
+        block.push(self.check_var.copy_from(index));
+        block.push((Op::HookIf(self.hook), overflow));
+    }
+}
+
+/// Gets the register that contains the frame base (minus the returned offset) at the start of the
+/// instruction at `pc`.
+fn frame_base_anchor(
+    cpu: &Cpu,
+    debug_info: &DebugInfo,
+    layout: &FrameLayout,
+    pc: u64,
+) -> Option<(VarNode, i64)> {
+    let (reg, offset) = match layout.frame_base {
+        FrameBase::Register { reg, offset } => (reg, offset),
+        FrameBase::Cfa => debug_info.cfa_rule(pc)?,
+    };
+    let name = dwarf_register_name(cpu.arch.triple.architecture, reg)?;
+    Some((cpu.arch.sleigh.get_varnode(name)?, offset))
+}
+
+impl CodeInjector for StackInjector {
+    fn inject(&mut self, cpu: &mut Cpu, group: &BlockGroup, code: &mut BlockTable) {
+        let mut state = self.state.borrow_mut();
+        for id in group.range() {
+            let block = &mut code.blocks[id];
+            let layout = match state.debug_info.frame_layout(block.start) {
+                Ok(Some(layout)) if !layout.objects.is_empty() => layout,
+                _ => continue,
+            };
+
+            let count = state.checks.len();
+            let mut tracker = FrameTracker::new(layout);
+            block.pcode.recompute_next_tmp();
+            let instructions = std::mem::take(&mut block.pcode.instructions);
+            for inst in instructions {
+                match inst.op {
+                    Op::InstructionMarker => {
+                        let pc = inst.inputs.first().as_u64();
+                        let anchor = frame_base_anchor(cpu, &state.debug_info, &tracker.layout, pc);
+                        tracker.start_instruction(pc, anchor);
+                    }
+                    Op::Store(pcode::RAM_SPACE) => {
+                        let [addr, value] = inst.inputs.get();
+                        let size = value.size() as u64;
+                        if let Some((anchor, delta, object)) = tracker.store_target(addr, size) {
+                            let check = StoreCheck {
+                                pc: tracker.pc,
+                                size,
+                                function: tracker.layout.function.clone(),
+                                object: object.clone(),
+                            };
+                            let index = state.checks.len() as u32;
+                            self.push_check(&mut block.pcode, anchor, delta, addr, &check, index);
+                            state.checks.push(check);
+                        }
+                    }
+                    _ => {}
+                }
+                block.pcode.push(inst);
+                tracker.step(&inst);
+            }
+
+            if state.checks.len() != count {
+                code.modified.insert(id);
+            }
+        }
+    }
+}
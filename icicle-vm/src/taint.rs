@@ -0,0 +1,235 @@
+//! Byte-level taint tracking.
+//!
+//! Every byte of the register file (including temporaries) and of guest memory is shadowed by a
+//! [TaintLabel]. Labels are created for individual bytes of the input with [Taint::taint_input],
+//! and are combined as they flow through the lifted code, so the label of any value can be
+//! resolved back to the set of input bytes that influenced it (see [Taint::input_bytes]).
+//!
+//! Propagation uses the same instrumentation as [crate::shadow]: every operation is preceded by a
+//! call to a hook that updates the shadow state before the operation executes, with labels
+//! combined into unions instead of using a [ShadowPolicy](crate::shadow::ShadowPolicy). This
+//! means taint is tracked identically by the interpreter and the JIT, at a significant cost to
+//! performance.
+//!
+//! Note: memory written by the environment (e.g. by syscalls) is not tracked, and taint is not
+//! part of the VM snapshot, so [Taint::clear] should be called when restoring the VM to run a new
+//! input.
+
+use std::{
+    cell::RefCell,
+    collections::{BTreeSet, HashMap},
+    rc::Rc,
+};
+
+use icicle_cpu::{Cpu, VmExit};
+use pcode::VarNode;
+
+use crate::{
+    Vm,
+    shadow::{self, MetaStore, Propagator, ShadowAccess},
+};
+
+/// Identifies a set of input bytes.
+pub type TaintLabel = u32;
+
+/// The label of values not influenced by any input bytes.
+pub const UNTAINTED: TaintLabel = 0;
+
+enum LabelNode {
+    /// A single byte of the input.
+    Input(u64),
+
+    /// The union of two labels.
+    Union(TaintLabel, TaintLabel),
+}
+
+/// A memory access performed by the guest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TaintedAccess {
+    pub pc: u64,
+    pub addr: u64,
+    pub is_write: bool,
+
+    /// The label of the value used as the address of the access.
+    pub label: TaintLabel,
+}
+
+/// Taint labels for memory, and the set of input bytes for each label.
+#[derive(Default)]
+struct TaintStore {
+    /// Label `n` is stored at index `n - 1`.
+    labels: Vec<LabelNode>,
+    input_labels: HashMap<u64, TaintLabel>,
+    unions: HashMap<(TaintLabel, TaintLabel), TaintLabel>,
+
+    /// Labels for each tainted byte of memory.
+    mem: HashMap<u64, TaintLabel>,
+
+    last_access: Option<TaintedAccess>,
+}
+
+impl TaintStore {
+    fn new_label(&mut self, node: LabelNode) -> TaintLabel {
+        self.labels.push(node);
+        self.labels.len() as TaintLabel
+    }
+
+    fn input_label(&mut self, offset: u64) -> TaintLabel {
+        if let Some(label) = self.input_labels.get(&offset) {
+            return *label;
+        }
+        let label = self.new_label(LabelNode::Input(offset));
+        self.input_labels.insert(offset, label);
+        label
+    }
+
+    fn input_bytes(&self, label: TaintLabel) -> BTreeSet<u64> {
+        let mut out = BTreeSet::new();
+        let mut visited = BTreeSet::new();
+        let mut stack = vec![label];
+        while let Some(label) = stack.pop() {
+            if label == UNTAINTED || !visited.insert(label) {
+                continue;
+            }
+            match self.labels[label as usize - 1] {
+                LabelNode::Input(offset) => {
+                    out.insert(offset);
+                }
+                LabelNode::Union(a, b) => stack.extend([a, b]),
+            }
+        }
+        out
+    }
+
+    fn set_mem_byte(&mut self, addr: u64, label: TaintLabel) {
+        match label {
+            UNTAINTED => self.mem.remove(&addr),
+            _ => self.mem.insert(addr, label),
+        };
+    }
+
+    fn record_access(&mut self, access: ShadowAccess<TaintLabel>, is_write: bool) {
+        let ShadowAccess { pc, addr, addr_meta: label } = access;
+        self.last_access = Some(TaintedAccess { pc, addr, is_write, label });
+    }
+}
+
+impl MetaStore for TaintStore {
+    type Meta = TaintLabel;
+
+    fn combine(&mut self, a: TaintLabel, b: TaintLabel) -> TaintLabel {
+        if a == UNTAINTED || a == b {
+            return b;
+        }
+        if b == UNTAINTED {
+            return a;
+        }
+        let key = (a.min(b), a.max(b));
+        if let Some(label) = self.unions.get(&key) {
+            return *label;
+        }
+        let label = self.new_label(LabelNode::Union(key.0, key.1));
+        self.unions.insert(key, label);
+        label
+    }
+
+    fn load(&mut self, _: &mut Cpu, access: ShadowAccess<TaintLabel>, data: &mut [TaintLabel]) {
+        self.record_access(access, false);
+        for (i, label) in data.iter_mut().enumerate() {
+            let addr = access.addr.wrapping_add(i as u64);
+            *label = self.mem.get(&addr).copied().unwrap_or(UNTAINTED);
+        }
+    }
+
+    fn store(&mut self, _: &mut Cpu, access: ShadowAccess<TaintLabel>, data: &mut [TaintLabel]) {
+        self.record_access(access, true);
+        for (i, label) in data.iter().enumerate() {
+            self.set_mem_byte(access.addr.wrapping_add(i as u64), *label);
+        }
+    }
+}
+
+type TaintState = Propagator<TaintStore>;
+
+/// A handle to the taint tracker attached to a VM. Cloning the handle produces a handle to the
+/// same state.
+#[derive(Clone)]
+pub struct Taint {
+    state: Rc<RefCell<TaintState>>,
+}
+
+impl Taint {
+    /// Marks the `len` bytes of memory starting at `addr` as containing the input bytes starting at
+    /// `offset`. Should be called after the input is copied into guest memory.
+    pub fn taint_input(&self, addr: u64, len: u64, offset: u64) {
+        let store = &mut self.state.borrow_mut().store;
+        for i in 0..len {
+            let label = store.input_label(offset + i);
+            store.set_mem_byte(addr.wrapping_add(i), label);
+        }
+    }
+
+    /// Sets the label of the `len` bytes of memory starting at `addr`.
+    pub fn set_memory_label(&self, addr: u64, len: u64, label: TaintLabel) {
+        let store = &mut self.state.borrow_mut().store;
+        for i in 0..len {
+            store.set_mem_byte(addr.wrapping_add(i), label);
+        }
+    }
+
+    /// Gets the label of the byte of memory at `addr`.
+    pub fn memory_label(&self, addr: u64) -> TaintLabel {
+        self.state.borrow().store.mem.get(&addr).copied().unwrap_or(UNTAINTED)
+    }
+
+    /// Gets the combined label of all the bytes of the register `var`.
+    pub fn register_label(&self, var: VarNode) -> TaintLabel {
+        self.state.borrow_mut().value_meta(var.into())
+    }
+
+    /// Gets the offsets of the input bytes that are part of `label`.
+    pub fn input_bytes(&self, label: TaintLabel) -> Vec<u64> {
+        self.state.borrow().store.input_bytes(label).into_iter().collect()
+    }
+
+    /// Gets the most recent memory access performed by the guest.
+    pub fn last_access(&self) -> Option<TaintedAccess> {
+        self.state.borrow().store.last_access
+    }
+
+    /// If the VM exited due to an invalid memory access, gets the offsets of the input bytes that
+    /// influenced the address of the access.
+    pub fn fault_sources(&self, exit: VmExit) -> Option<Vec<u64>> {
+        let VmExit::UnhandledException((code, _)) = exit
+        else {
+            return None;
+        };
+        if !code.is_memory_error() {
+            return None;
+        }
+        let access = self.last_access()?;
+        Some(self.input_bytes(access.label))
+    }
+
+    /// Removes all taint from registers and memory.
+    pub fn clear(&self) {
+        let mut state = self.state.borrow_mut();
+        state.clear_regs();
+        state.store.mem.clear();
+        state.store.last_access = None;
+    }
+}
+
+/// Attaches a taint tracker to the VM. Only code lifted after the tracker is attached propagates
+/// taint, so this should be called before the VM starts executing.
+pub fn attach(vm: &mut Vm) -> Taint {
+    let state = Rc::new(RefCell::new(TaintState::new(TaintStore::default())));
+
+    let hook_state = state.clone();
+    shadow::attach_op_hook(vm, "taint.op", false, move |cpu, inst| {
+        hook_state.borrow_mut().propagate(cpu, inst)
+    })
+    .expect("taint tracking has already been attached");
+
+    Taint { state }
+}
@@ -0,0 +1,198 @@
+//! Exports execution traces in the text format used by the Tenet trace explorer.
+//!
+//! Each line of a Tenet trace describes a single executed instruction: the registers that changed
+//! since the previous line (the first line contains all registers), the address of the
+//! instruction, and the memory accessed by the instruction, e.g.:
+//!
+//! ```text
+//! rax=0x1,rsp=0x7fff0000,rip=0x401000,mr=0x7fff0000:efbeadde
+//! ```
+//!
+//! Register values are captured with a hook injected before every instruction, and memory accesses
+//! are captured with memory hooks covering the entire address space. Accesses made by the
+//! environment (e.g. a syscall writing to a buffer) are attributed to the current instruction.
+
+use std::{cell::RefCell, fmt::Write as _, io::Write, rc::Rc};
+
+use icicle_cpu::{
+    BlockGroup, BlockTable, Cpu,
+    mem::{Mmu, ReadAfterHook},
+};
+
+use crate::{Vm, injector::CodeInjector};
+
+/// Gets the name used for the program counter in the trace.
+fn pc_name(cpu: &Cpu) -> &'static str {
+    use target_lexicon::Architecture;
+
+    match cpu.arch.triple.architecture {
+        Architecture::X86_64 => "rip",
+        Architecture::X86_32(_) => "eip",
+        _ => "pc",
+    }
+}
+
+/// Gets the registers (and their names in the trace) that are included in the trace, excluding
+/// the program counter.
+fn tenet_regs(cpu: &Cpu) -> Vec<(String, pcode::VarNode)> {
+    use target_lexicon::Architecture;
+
+    let sleigh = &cpu.arch.sleigh;
+    let names: &[&str] = match cpu.arch.triple.architecture {
+        Architecture::X86_64 => &[
+            "RAX", "RBX", "RCX", "RDX", "RBP", "RSP", "RSI", "RDI", "R8", "R9", "R10", "R11", "R12",
+            "R13", "R14", "R15",
+        ],
+        Architecture::X86_32(_) => &["EAX", "EBX", "ECX", "EDX", "EBP", "ESP", "ESI", "EDI"],
+        Architecture::Aarch64(_) => &[
+            "x0", "x1", "x2", "x3", "x4", "x5", "x6", "x7", "x8", "x9", "x10", "x11", "x12", "x13",
+            "x14", "x15", "x16", "x17", "x18", "x19", "x20", "x21", "x22", "x23", "x24", "x25",
+            "x26", "x27", "x28", "x29", "x30", "sp",
+        ],
+        _ => {
+            // Tenet only defines registers for x86 and AArch64, for other architectures we fall
+            // back to the registers shown by the debugger.
+            return crate::debug::get_debug_regs(cpu)
+                .into_iter()
+                .filter_map(|var| Some((sleigh.name_of_varnode(var)?.to_lowercase(), var)))
+                .filter(|(name, _)| name != pc_name(cpu))
+                .collect();
+        }
+    };
+    names.iter().map(|name| (name.to_lowercase(), sleigh.get_varnode(name).unwrap())).collect()
+}
+
+struct TenetState {
+    out: Box<dyn Write>,
+    regs: Vec<(String, pcode::VarNode)>,
+    pc_name: &'static str,
+
+    /// The values of each register the last time they were written to the trace.
+    last: Vec<Option<u64>>,
+
+    /// The line for the instruction currently being executed.
+    line: Option<String>,
+
+    /// The first error that occurred while writing the trace.
+    error: Option<std::io::Error>,
+}
+
+impl TenetState {
+    fn flush_line(&mut self) {
+        let Some(line) = self.line.take()
+        else {
+            return;
+        };
+        if self.error.is_none() {
+            self.error = writeln!(self.out, "{line}").err();
+        }
+    }
+
+    fn start_instruction(&mut self, cpu: &mut Cpu, pc: u64) {
+        self.flush_line();
+
+        let mut line = String::new();
+        for (i, (name, var)) in self.regs.iter().enumerate() {
+            let value = cpu.read_reg(*var);
+            if self.last[i] != Some(value) {
+                self.last[i] = Some(value);
+                write!(line, "{name}={value:#x},").unwrap();
+            }
+        }
+        // The address of the instruction is included in every line, even if it is unchanged.
+        write!(line, "{}={pc:#x}", self.pc_name).unwrap();
+        self.line = Some(line);
+    }
+
+    fn add_access(&mut self, kind: &str, addr: u64, value: &[u8]) {
+        let Some(line) = self.line.as_mut()
+        else {
+            // Ignore accesses that occur before the first instruction is executed.
+            return;
+        };
+        write!(line, ",{kind}={addr:#x}:").unwrap();
+        for byte in value {
+            write!(line, "{byte:02x}").unwrap();
+        }
+    }
+}
+
+/// A handle to a Tenet tracer attached to a VM.
+#[derive(Clone)]
+pub struct TenetTracer {
+    state: Rc<RefCell<TenetState>>,
+}
+
+impl TenetTracer {
+    /// Writes the line for the last executed instruction and flushes the output. Returns the first
+    /// error that occurred while writing the trace.
+    pub fn finish(&self) -> std::io::Result<()> {
+        let mut state = self.state.borrow_mut();
+        state.flush_line();
+        if let Some(e) = state.error.take() {
+            return Err(e);
+        }
+        state.out.flush()
+    }
+}
+
+struct ReadTracer(Rc<RefCell<TenetState>>);
+
+impl ReadAfterHook for ReadTracer {
+    fn read(&mut self, _mem: &mut Mmu, addr: u64, value: &[u8]) {
+        self.0.borrow_mut().add_access("mr", addr, value);
+    }
+}
+
+/// Attaches a tracer to the VM that writes a Tenet trace to `out`. Only code lifted after the
+/// tracer is attached is traced, so this should be called before the VM starts executing.
+pub fn add_tenet_tracer(vm: &mut Vm, out: impl Write + 'static) -> TenetTracer {
+    let regs = tenet_regs(&vm.cpu);
+    let state = Rc::new(RefCell::new(TenetState {
+        out: Box::new(out),
+        last: vec![None; regs.len()],
+        regs,
+        pc_name: pc_name(&vm.cpu),
+        line: None,
+        error: None,
+    }));
+
+    let hook_state = state.clone();
+    let hook = vm.cpu.add_hook(move |cpu: &mut Cpu, addr: u64| {
+        hook_state.borrow_mut().start_instruction(cpu, addr);
+    });
+    vm.add_injector(TenetInjector { hook });
+
+    vm.cpu.mem.add_read_after_hook(0, u64::MAX, Box::new(ReadTracer(state.clone())));
+    let write_state = state.clone();
+    vm.cpu.mem.add_write_hook(
+        0,
+        u64::MAX,
+        Box::new(move |_mem: &mut Mmu, addr: u64, value: &[u8]| {
+            write_state.borrow_mut().add_access("mw", addr, value);
+        }),
+    );
+
+    TenetTracer { state }
+}
+
+/// Inserts a call to the tracer hook at the start of every instruction.
+struct TenetInjector {
+    hook: pcode::HookId,
+}
+
+impl CodeInjector for TenetInjector {
+    fn inject(&mut self, _cpu: &mut Cpu, group: &BlockGroup, code: &mut BlockTable) {
+        for id in group.range() {
+            let block = &mut code.blocks[id];
+            let instructions = std::mem::take(&mut block.pcode.instructions);
+            for inst in instructions {
+                block.pcode.push(inst);
+                if let pcode::Op::InstructionMarker = inst.op {
+                    block.pcode.push(pcode::Op::Hook(self.hook));
+                }
+            }
+            code.modified.insert(id);
+        }
+    }
+}
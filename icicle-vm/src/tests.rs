@@ -108,6 +108,49 @@ fn build_arm() {
     let _ = crate::build(&Config::from_target_triple("arm-none")).unwrap();
 }
 
+#[test]
+fn arm_cp15_register_accesses() {
+    let mut vm = crate::build(&Config::from_target_triple("arm-none")).unwrap();
+    vm.cpu.mem.map_memory_len(0x1000, 0x100, Mapping { perm: perm::READ | perm::EXEC, value: 0 });
+
+    static CODE: &[u8] = &[
+        0x10, 0x0f, 0x10, 0xee, // mrc p15, #0, r0, c0, c0, #0 (MIDR)
+        0x50, 0x1f, 0x0d, 0xee, // mcr p15, #0, r1, c13, c0, #2 (TPIDRURW)
+        0x50, 0x2f, 0x1d, 0xee, // mrc p15, #0, r2, c13, c0, #2 (TPIDRURW)
+        0x15, 0x1f, 0x07, 0xee, // mcr p15, #0, r1, c7, c5, #0 (ICIALLU)
+        0x18, 0x3f, 0x07, 0xee, // mcr p15, #0, r3, c7, c8, #0 (ATS1CPR)
+        0x14, 0x4f, 0x17, 0xee, // mrc p15, #0, r4, c7, c4, #0 (PAR)
+        0x10, 0x1f, 0x00, 0xee, // mcr p15, #0, r1, c0, c0, #0 (MIDR)
+        0x10, 0x5f, 0x10, 0xee, // mrc p15, #0, r5, c0, c0, #0 (MIDR)
+        0x91, 0x6f, 0x10, 0xee, // mrc p15, #0, r6, c0, c1, #4 (ID_MMFR0)
+        0xfe, 0xff, 0xff, 0xea, // b .
+    ];
+    vm.cpu.mem.write_bytes(0x1000, CODE, perm::NONE).unwrap();
+
+    let reg = |vm: &crate::Vm, name: &str| vm.cpu.arch.sleigh.get_varnode(name).unwrap();
+    let [r0, r1, r2, r3, r4, r5, r6] =
+        ["r0", "r1", "r2", "r3", "r4", "r5", "r6"].map(|x| reg(&vm, x));
+    vm.cpu.write_reg(r1, 0x1234_5678);
+    vm.cpu.write_reg(r3, 0x8000_1abc);
+    vm.cpu.write_pc(0x1000);
+
+    vm.icount_limit = 10;
+    assert_eq!(vm.run(), VmExit::InstructionLimit);
+    assert_eq!(vm.cpu.read_pc(), 0x1024);
+
+    // ID registers are constant, and ignore writes.
+    assert_eq!(vm.cpu.read_reg(r0), 0x413f_c090);
+    assert_eq!(vm.cpu.read_reg(r5), 0x413f_c090);
+    assert_eq!(vm.cpu.read_reg(r6), 0x0010_0103);
+
+    // System registers are backed by custom registers.
+    assert_eq!(vm.cpu.read_reg(r2), 0x1234_5678);
+    assert_eq!(vm.cpu.read_reg(reg(&vm, "TPIDRURW")), 0x1234_5678);
+
+    // Address translation maps addresses to themselves, storing the result in PAR.
+    assert_eq!(vm.cpu.read_reg(r4), 0x8000_1000);
+}
+
 #[test]
 fn build_aarch64() {
     let _ = crate::build(&Config::from_target_triple("aarch64-none")).unwrap();
@@ -142,3 +185,4054 @@ fn build_riscv64() {
 fn build_x86_64() {
     let _ = crate::build(&Config::from_target_triple("x86_64-none")).unwrap();
 }
+
+#[test]
+fn compose_detects_conflicts() {
+    use icicle_cpu::Environment;
+
+    let mut vm =
+        crate::build(&Config { triple: "riscv64-none".parse().unwrap(), ..Config::default() })
+            .unwrap();
+    let mut env = crate::compose::Composition::new();
+
+    let rw = perm::READ | perm::WRITE;
+    env.add_blob(&mut vm.cpu, "config", None, 0x2000, &[0xaa; 0x100], rw).unwrap();
+    env.add_blob(&mut vm.cpu, "blob", None, 0x2100, &[0xbb; 0x10], rw).unwrap();
+    assert!(env.add_blob(&mut vm.cpu, "overlap", None, 0x20f0, &[0xcc; 0x20], rw).is_err());
+
+    assert_eq!(env.image_at(0x2000).unwrap().name, "config");
+    assert_eq!(env.image_at(0x2105).unwrap().name, "blob");
+    assert!(env.image_at(0x2110).is_none());
+    assert_eq!(vm.cpu.mem.read_u8(0x20ff, perm::READ).unwrap(), 0xaa);
+    assert_eq!(vm.cpu.mem.read_u8(0x2100, perm::READ).unwrap(), 0xbb);
+
+    // Images loaded after a snapshot are removed when the snapshot is restored.
+    let snapshot = env.snapshot();
+    env.add_blob(&mut vm.cpu, "late", None, 0x3000, &[0xdd; 0x10], rw).unwrap();
+    env.restore(&snapshot);
+    assert!(env.image_at(0x3000).is_none());
+    assert_eq!(env.image_at(0x2105).unwrap().name, "blob");
+    env.add_blob(&mut vm.cpu, "late", None, 0x3000, &[0xdd; 0x10], rw).unwrap();
+}
+
+#[test]
+fn rdrand_is_deterministic() {
+    let mut vm = crate::build(&Config::from_target_triple("x86_64-none")).unwrap();
+    vm.cpu.mem.map_memory_len(0x1000, 0x100, Mapping { perm: perm::READ | perm::EXEC, value: 0 });
+    vm.cpu.mem.write_bytes(0x1000, &[0x48, 0x0f, 0xc7, 0xf0], perm::NONE).unwrap(); // rdrand rax
+    let rax = vm.cpu.arch.sleigh.get_varnode("RAX").unwrap();
+
+    let run = |vm: &mut crate::Vm, seed: u64| {
+        vm.reseed_entropy(seed);
+        vm.cpu.write_pc(0x1000);
+        vm.step(1);
+        vm.cpu.read_reg(rax)
+    };
+    let first = run(&mut vm, 1);
+    assert_eq!(run(&mut vm, 1), first);
+    assert_ne!(run(&mut vm, 2), first);
+    assert_eq!(crate::x86::eflags(&vm.cpu) & 1, 1);
+}
+
+#[test]
+fn entropy_is_reseeded_on_reset_and_restore() {
+    let mut config = Config::from_target_triple("x86_64-none");
+    config.entropy_seed = 7;
+    let mut vm = crate::build(&config).unwrap();
+    let first = vm.cpu.entropy.next_u64();
+    vm.reset();
+    assert_eq!(vm.cpu.entropy.next_u64(), first);
+
+    let snapshot = vm.snapshot();
+    let next = vm.cpu.entropy.next_u64();
+    vm.cpu.entropy.next_u64();
+    vm.restore(&snapshot);
+    assert_eq!(vm.cpu.entropy.next_u64(), next);
+
+    // Reseeding at runtime changes the seed used by later resets.
+    vm.reseed_entropy(8);
+    let reseeded = vm.cpu.entropy.next_u64();
+    assert_ne!(reseeded, first);
+    vm.reset();
+    assert_eq!(vm.cpu.entropy.next_u64(), reseeded);
+}
+
+#[test]
+fn restore_discards_code_lifted_after_snapshot() {
+    let mut vm = crate::build(&Config::from_target_triple("x86_64-none")).unwrap();
+    vm.cpu.mem.map_memory_len(0x1000, 0x100, Mapping { perm: perm::READ | perm::EXEC, value: 0 });
+    // mov eax, 1
+    vm.cpu.mem.write_bytes(0x1000, &[0xb8, 0x01, 0x00, 0x00, 0x00], perm::NONE).unwrap();
+    let rax = vm.cpu.arch.sleigh.get_varnode("RAX").unwrap();
+    vm.cpu.write_pc(0x1000);
+    let snapshot = vm.snapshot();
+
+    vm.cpu.mem.write_bytes(0x1001, &[0x02], perm::NONE).unwrap(); // mov eax, 2
+    vm.step(1);
+    assert_eq!(vm.cpu.read_reg(rax), 2);
+
+    // The code lifted after the snapshot no longer matches memory, so it must be relifted.
+    vm.restore(&snapshot);
+    vm.step(1);
+    assert_eq!(vm.cpu.read_reg(rax), 1);
+
+    // Code that is still valid is kept after restoring.
+    let blocks = vm.code.blocks.len();
+    vm.restore(&snapshot);
+    vm.step(1);
+    assert_eq!(vm.cpu.read_reg(rax), 1);
+    assert_eq!(vm.code.blocks.len(), blocks);
+}
+
+#[test]
+fn lift_callback_instruments_blocks() {
+    let mut vm = crate::build(&Config::from_target_triple("x86_64-none")).unwrap();
+    vm.cpu.mem.map_memory_len(0x1000, 0x100, Mapping { perm: perm::READ | perm::EXEC, value: 0 });
+    // loop: inc eax; jmp loop
+    vm.cpu.mem.write_bytes(0x1000, &[0xff, 0xc0, 0xeb, 0xfc], perm::NONE).unwrap();
+    let eax = vm.cpu.arch.sleigh.get_varnode("EAX").unwrap();
+
+    // Count the number of times each block is entered.
+    let counter = vm.cpu.arch.sleigh.add_custom_reg("block_counter", 4).unwrap();
+    vm.add_lift_callback(move |group, code| {
+        for block in &mut code.blocks[group.range()] {
+            let inc = (counter, pcode::Op::IntAdd, (counter, 1_u32)).into();
+            block.pcode.instructions.insert(0, inc);
+        }
+    });
+
+    vm.cpu.write_pc(0x1000);
+    vm.icount_limit = 10;
+    assert_eq!(vm.run(), VmExit::InstructionLimit);
+    assert!(vm.cpu.read_reg(eax) > 1);
+    assert_eq!(vm.cpu.read_reg(counter), vm.cpu.read_reg(eax));
+}
+
+#[test]
+fn annotations_survive_relifting() {
+    use crate::annotations::{AnnotationTarget, AnnotationValue};
+
+    let mut vm = crate::build(&Config::from_target_triple("x86_64-none")).unwrap();
+    vm.cpu.mem.map_memory_len(0x1000, 0x100, Mapping { perm: perm::READ | perm::EXEC, value: 0 });
+    // loop: inc eax; jmp loop
+    vm.cpu.mem.write_bytes(0x1000, &[0xff, 0xc0, 0xeb, 0xfc], perm::NONE).unwrap();
+    let group = vm.lift(0x1000).unwrap();
+    let block = AnnotationTarget::for_block(&vm.code, group.blocks.0).unwrap();
+    vm.annotations.set(block, "loop_header", AnnotationValue::Flag);
+    vm.annotations.set(AnnotationTarget::Instruction(0x1002), "comment", "back edge");
+
+    // Relifting the code keeps annotations attached to the same address.
+    vm.code.flush_code();
+    let group = vm.lift(0x1000).unwrap();
+    assert_eq!(AnnotationTarget::for_block(&vm.code, group.blocks.0), Some(block));
+    assert_eq!(vm.annotations.find("loop_header"), vec![(block, AnnotationValue::Flag)]);
+
+    // Invalidating the code discards any annotations attached to it.
+    vm.invalidate_code_range(0x1002, 2);
+    assert!(!vm.annotations.contains(block, "loop_header"));
+    assert!(vm.annotations.get(AnnotationTarget::Instruction(0x1002), "comment").is_none());
+}
+
+#[test]
+fn loop_iteration_limit() {
+    let mut vm = crate::build(&Config::from_target_triple("x86_64-none")).unwrap();
+    vm.cpu.mem.map_memory_len(0x1000, 0x100, Mapping { perm: perm::READ | perm::EXEC, value: 0 });
+    // loop: inc eax; jmp loop
+    vm.cpu.mem.write_bytes(0x1000, &[0xff, 0xc0, 0xeb, 0xfc], perm::NONE).unwrap();
+    let eax = vm.cpu.arch.sleigh.get_varnode("EAX").unwrap();
+
+    let loops = crate::loops::attach(&mut vm);
+    loops.set_limit(0x1000, 5);
+
+    vm.cpu.write_pc(0x1000);
+    vm.icount_limit = 1000;
+    assert_eq!(vm.run(), VmExit::LoopLimit(0x1000));
+    assert_eq!(vm.cpu.read_reg(eax), 6);
+
+    let info = loops.get(0x1000).unwrap();
+    assert_eq!(info.back_edges, vec![0x1002]);
+    assert_eq!(info.iterations, 6);
+}
+
+#[test]
+fn step_back_from_recording_checkpoints() {
+    let mut vm = crate::build(&Config::from_target_triple("x86_64-none")).unwrap();
+    vm.cpu.mem.map_memory_len(0x1000, 0x100, Mapping { perm: perm::READ | perm::EXEC, value: 0 });
+    // loop: inc eax; jmp loop
+    vm.cpu.mem.write_bytes(0x1000, &[0xff, 0xc0, 0xeb, 0xfc], perm::NONE).unwrap();
+    let eax = vm.cpu.arch.sleigh.get_varnode("EAX").unwrap();
+    vm.cpu.write_pc(0x1000);
+
+    vm.start_recording(crate::record::RecordConfig { checkpoint_interval: 10, max_checkpoints: 4 });
+    vm.icount_limit = 100;
+    assert_eq!(vm.run(), VmExit::InstructionLimit);
+    assert_eq!(vm.cpu.read_reg(eax), 50);
+    assert!(vm.snapshots.len() <= 4);
+
+    assert_eq!(vm.step_back(25), Some(VmExit::InstructionLimit));
+    assert_eq!(vm.cpu.icount(), 75);
+    assert_eq!(vm.cpu.read_reg(eax), 38);
+}
+
+/// An environment with a nondeterministic syscall, that returns a different value (and writes a
+/// different value to memory) every time it is called, even after a snapshot is restored.
+struct CounterEnv(u64);
+
+impl icicle_cpu::Environment for CounterEnv {
+    fn load(&mut self, _: &mut icicle_cpu::Cpu, _: &[u8]) -> Result<(), String> {
+        Err("unsupported".into())
+    }
+    fn handle_exception(&mut self, cpu: &mut icicle_cpu::Cpu) -> Option<VmExit> {
+        if ExceptionCode::from_u32(cpu.exception.code) != ExceptionCode::Syscall {
+            return None;
+        }
+        self.0 += 1;
+        let rax = cpu.arch.sleigh.get_varnode("RAX").unwrap();
+        cpu.write_reg(rax, self.0);
+        cpu.mem.write_u64(0x2000, self.0 * 0x100, perm::NONE).unwrap();
+        let next_pc = cpu.read_reg(cpu.arch.reg_next_pc);
+        cpu.exception = icicle_cpu::Exception::new(ExceptionCode::ExternalAddr, next_pc);
+        None
+    }
+    fn snapshot(&mut self) -> Box<dyn std::any::Any> {
+        Box::new(())
+    }
+    fn restore(&mut self, _: &Box<dyn std::any::Any>) {}
+}
+
+#[test]
+fn replay_recorded_syscalls() {
+    static CODE: &[u8] = &[
+        0x0f, 0x05, // syscall
+        0x48, 0x8b, 0x1c, 0x25, 0x00, 0x20, 0x00, 0x00, // mov rbx, qword ptr [0x2000]
+        0x0f, 0x05, // syscall
+        0xeb, 0xfe, // jmp $
+    ];
+    let mut vm = crate::build(&Config::from_target_triple("x86_64-none")).unwrap();
+    vm.set_env(CounterEnv(0));
+    vm.cpu.mem.map_memory_len(0x1000, 0x100, Mapping { perm: perm::READ | perm::EXEC, value: 0 });
+    vm.cpu.mem.map_memory_len(0x2000, 0x100, Mapping { perm: perm::READ | perm::WRITE, value: 0 });
+    vm.cpu.mem.write_bytes(0x1000, CODE, perm::NONE).unwrap();
+    let rax = vm.cpu.arch.sleigh.get_varnode("RAX").unwrap();
+    let rbx = vm.cpu.arch.sleigh.get_varnode("RBX").unwrap();
+    vm.cpu.write_pc(0x1000);
+
+    let config = crate::record::RecordConfig { checkpoint_interval: 1000, max_checkpoints: 4 };
+    vm.start_recording(config);
+    vm.icount_limit = 10;
+    assert_eq!(vm.run(), VmExit::InstructionLimit);
+    assert_eq!((vm.cpu.read_reg(rax), vm.cpu.read_reg(rbx)), (2, 0x100));
+
+    let events: Vec<_> = vm.recording.as_ref().unwrap().events.keys().copied().collect();
+    assert_eq!(events.len(), 2);
+    let memory = &vm.recording.as_ref().unwrap().events[&events[0]].memory;
+    assert_eq!(memory.iter().map(|(addr, _)| *addr).collect::<Vec<_>>(), [0x2000]);
+
+    // Re-executing the syscalls calls the environment again, but the register and memory state
+    // that was recorded is used instead of the new results.
+    assert!(vm.goto_icount(events[1]).is_some());
+    assert_eq!(vm.cpu.read_reg(rbx), 0x100);
+    assert!(vm.goto_icount(10).is_some());
+    assert_eq!((vm.cpu.read_reg(rax), vm.cpu.read_reg(rbx)), (2, 0x100));
+    assert_eq!(vm.cpu.mem.read_u64(0x2000, perm::NONE).unwrap(), 0x200);
+    assert_eq!(vm.recording.as_ref().unwrap().divergences, 0);
+    assert_eq!(vm.env_ref::<CounterEnv>().unwrap().0, 4);
+}
+
+#[test]
+fn taint_tracks_input_bytes_to_faulting_address() {
+    let mut vm = crate::build(&Config::from_target_triple("x86_64-none")).unwrap();
+    vm.cpu.mem.map_memory_len(0x1000, 0x100, Mapping { perm: perm::READ | perm::EXEC, value: 0 });
+    vm.cpu.mem.map_memory_len(0x2000, 0x100, Mapping { perm: perm::READ | perm::WRITE, value: 0 });
+    // movzx eax, byte ptr [0x2001]; mov rbx, qword ptr [rax]
+    let code = [0x0f, 0xb6, 0x04, 0x25, 0x01, 0x20, 0x00, 0x00, 0x48, 0x8b, 0x18];
+    vm.cpu.mem.write_bytes(0x1000, &code, perm::NONE).unwrap();
+    vm.cpu.mem.write_bytes(0x2000, b"AB", perm::NONE).unwrap();
+    let eax = vm.cpu.arch.sleigh.get_varnode("EAX").unwrap();
+
+    let taint = crate::taint::attach(&mut vm);
+    taint.taint_input(0x2000, 2, 0);
+
+    vm.cpu.write_pc(0x1000);
+    vm.icount_limit = 100;
+    let exit = vm.run();
+    assert_eq!(exit, VmExit::UnhandledException((ExceptionCode::ReadUnmapped, 0x42)));
+    assert_eq!(taint.input_bytes(taint.register_label(eax)), vec![1]);
+    assert_eq!(taint.fault_sources(exit), Some(vec![1]));
+}
+
+#[test]
+fn compressed_trace_range_query() {
+    use crate::trace_file::{TraceReader, TraceWriter};
+
+    let mut writer = TraceWriter::with_frame_len(std::io::Cursor::new(vec![]), 4).unwrap();
+    for i in 0..10 {
+        writer.push(0x1000 + i, i * 10).unwrap();
+    }
+    let data = writer.finish().unwrap().into_inner();
+
+    let mut reader = TraceReader::new(std::io::Cursor::new(data)).unwrap();
+    assert_eq!(reader.len(), 10);
+    assert_eq!(reader.frames().len(), 3);
+    assert_eq!(reader.read_range(35, 65).unwrap(), vec![(0x1004, 40), (0x1005, 50), (0x1006, 60)]);
+    assert_eq!(reader.read_frame(2).unwrap(), vec![(0x1008, 80), (0x1009, 90)]);
+}
+
+#[test]
+fn restore_does_not_reactivate_invalidated_code() {
+    let mut vm = crate::build(&Config::from_target_triple("x86_64-none")).unwrap();
+    vm.cpu.mem.map_memory_len(0x1000, 0x100, Mapping { perm: perm::READ | perm::EXEC, value: 0 });
+    // mov eax, 1
+    vm.cpu.mem.write_bytes(0x1000, &[0xb8, 0x01, 0x00, 0x00, 0x00], perm::NONE).unwrap();
+    vm.cpu.write_pc(0x1000);
+    vm.step(1);
+    vm.cpu.write_pc(0x1000);
+    let lifted = |vm: &crate::Vm| vm.code.map.keys().any(|key| key.vaddr == 0x1000);
+    assert!(lifted(&vm));
+
+    // Code that was active at the time of the snapshot is not reactivated if it was explicitly
+    // invalidated after the snapshot.
+    let snapshot = vm.snapshot();
+    vm.invalidate_code_range(0x1000, 1);
+    vm.restore(&snapshot);
+    assert!(!lifted(&vm));
+
+    // Code lifted again after the snapshot is kept, and the code cache is unchanged when restoring
+    // the same snapshot again.
+    vm.step(1);
+    let blocks = vm.code.blocks.len();
+    vm.restore(&snapshot);
+    vm.restore(&snapshot);
+    assert!(lifted(&vm));
+    vm.step(1);
+    assert_eq!(vm.code.blocks.len(), blocks);
+}
+
+#[test]
+fn seh_dispatch_round_trip() {
+    use crate::windows::seh;
+
+    let mut vm = crate::build(&Config::from_target_triple("x86_64-none")).unwrap();
+    vm.cpu.mem.map_memory_len(0x1000, 0x100, Mapping { perm: perm::READ | perm::EXEC, value: 0 });
+    vm.cpu.mem.map_memory_len(0x8000, 0x2000, Mapping { perm: perm::READ | perm::WRITE, value: 0 });
+
+    let rsp = vm.cpu.arch.sleigh.get_varnode("RSP").unwrap();
+    let rax = vm.cpu.arch.sleigh.get_varnode("RAX").unwrap();
+    vm.cpu.write_reg(rsp, 0xa000);
+    vm.cpu.write_reg(rax, 0x1234);
+
+    static CODE: &[u8] = &[
+        0x48, 0x8b, 0x04, 0x25, 0x00, 0x00, 0x02, 0x00, // mov rax, [0x20000]
+    ];
+    vm.cpu.mem.write_bytes(0x1000, CODE, perm::NONE).unwrap();
+    vm.cpu.write_pc(0x1000);
+    assert_eq!(vm.step(1), VmExit::UnhandledException((ExceptionCode::ReadUnmapped, 0x20000)));
+
+    let mut dispatcher = seh::SehDispatcher::new(0x1080);
+    assert_eq!(dispatcher.handle_exception(&mut vm.cpu), Some(VmExit::Running));
+    assert_eq!(vm.cpu.read_pc(), 0x1080);
+
+    let frame = vm.cpu.read_reg(rsp);
+    assert_eq!(frame % 16, 0);
+    let record = frame + 0x4f0;
+    assert_eq!(vm.cpu.mem.read_u32(record, perm::READ).unwrap(), seh::STATUS_ACCESS_VIOLATION);
+    assert_eq!(vm.cpu.mem.read_u64(record + 0x28, perm::READ).unwrap(), 0x20000);
+
+    vm.cpu.write_reg(rax, 0);
+    seh::restore_context(&mut vm.cpu, frame).unwrap();
+    assert_eq!(vm.cpu.read_pc(), 0x1000);
+    assert_eq!(vm.cpu.read_reg(rsp), 0xa000);
+    assert_eq!(vm.cpu.read_reg(rax), 0x1234);
+}
+
+/// Builds a minimal PE32+ executable with a single section at 0x401000 containing `code`, that
+/// imports functions from each DLL in `imports`. The IAT entries are consecutive starting at
+/// `0x401800`, with a null entry after the imports of each DLL.
+fn build_pe64(code: &[u8], imports: &[(&str, &[&str])]) -> Vec<u8> {
+    fn put(data: &mut [u8], offset: usize, bytes: &[u8]) {
+        data[offset..offset + bytes.len()].copy_from_slice(bytes);
+    }
+
+    let mut data = vec![0; 0x1200];
+    put(&mut data, 0x0, b"MZ");
+    put(&mut data, 0x3c, &0x40_u32.to_le_bytes());
+    put(&mut data, 0x40, b"PE\0\0");
+
+    // IMAGE_FILE_HEADER
+    put(&mut data, 0x44, &0x8664_u16.to_le_bytes());
+    put(&mut data, 0x46, &1_u16.to_le_bytes());
+    put(&mut data, 0x54, &0xf0_u16.to_le_bytes());
+    put(&mut data, 0x56, &0x22_u16.to_le_bytes());
+
+    // IMAGE_OPTIONAL_HEADER64
+    let opt = 0x58;
+    put(&mut data, opt, &0x20b_u16.to_le_bytes());
+    put(&mut data, opt + 0x10, &0x1000_u32.to_le_bytes()); // AddressOfEntryPoint
+    put(&mut data, opt + 0x18, &0x40_0000_u64.to_le_bytes()); // ImageBase
+    put(&mut data, opt + 0x20, &0x1000_u32.to_le_bytes()); // SectionAlignment
+    put(&mut data, opt + 0x24, &0x200_u32.to_le_bytes()); // FileAlignment
+    put(&mut data, opt + 0x38, &0x2000_u32.to_le_bytes()); // SizeOfImage
+    put(&mut data, opt + 0x3c, &0x200_u32.to_le_bytes()); // SizeOfHeaders
+    put(&mut data, opt + 0x44, &3_u16.to_le_bytes()); // Subsystem
+    put(&mut data, opt + 0x6c, &16_u32.to_le_bytes()); // NumberOfRvaAndSizes
+    put(&mut data, opt + 0x78, &0x1a00_u32.to_le_bytes()); // Import directory
+    put(&mut data, opt + 0x7c, &(20 * (imports.len() as u32 + 1)).to_le_bytes());
+
+    // IMAGE_SECTION_HEADER
+    let section = opt + 0xf0;
+    put(&mut data, section, b".text");
+    put(&mut data, section + 0x08, &0x1000_u32.to_le_bytes()); // VirtualSize
+    put(&mut data, section + 0x0c, &0x1000_u32.to_le_bytes()); // VirtualAddress
+    put(&mut data, section + 0x10, &0x1000_u32.to_le_bytes()); // SizeOfRawData
+    put(&mut data, section + 0x14, &0x200_u32.to_le_bytes()); // PointerToRawData
+    put(&mut data, section + 0x24, &0xe000_0020_u32.to_le_bytes()); // Characteristics
+
+    // Converts an RVA in the section to a file offset.
+    let file = |rva: usize| rva - 0x1000 + 0x200;
+    put(&mut data, file(0x1000), code);
+    let mut entry = 0;
+    for (i, (dll, names)) in imports.iter().enumerate() {
+        let (iat, ilt, dll_name) = (0x1800 + entry * 8, 0x1900 + entry * 8, 0x1b00 + i * 0x20);
+        for name in names.iter() {
+            let hint_name = 0x1c00 + entry * 0x20;
+            put(&mut data, file(hint_name) + 2, name.as_bytes());
+            put(&mut data, file(0x1800 + entry * 8), &(hint_name as u64).to_le_bytes()); // IAT
+            put(&mut data, file(0x1900 + entry * 8), &(hint_name as u64).to_le_bytes()); // ILT
+            entry += 1;
+        }
+        entry += 1;
+        put(&mut data, file(dll_name), dll.as_bytes());
+
+        // IMAGE_IMPORT_DESCRIPTOR
+        let desc = 0x1a00 + i * 20;
+        put(&mut data, file(desc), &(ilt as u32).to_le_bytes()); // OriginalFirstThunk
+        put(&mut data, file(desc + 0x0c), &(dll_name as u32).to_le_bytes()); // Name
+        put(&mut data, file(desc + 0x10), &(iat as u32).to_le_bytes()); // FirstThunk
+    }
+
+    data
+}
+
+#[test]
+fn windows_virtual_alloc() {
+    use crate::windows::env::WindowsEnvironment;
+
+    static CODE: &[u8] = &[
+        0xb9, 0x00, 0x00, 0x00, 0x70, // mov ecx, 0x70000000
+        0xba, 0x00, 0x20, 0x00, 0x00, // mov edx, 0x2000
+        0x41, 0xb8, 0x00, 0x30, 0x00, 0x00, // mov r8d, MEM_COMMIT | MEM_RESERVE
+        0x41, 0xb9, 0x02, 0x00, 0x00, 0x00, // mov r9d, PAGE_READONLY
+        0xff, 0x14, 0x25, 0x00, 0x18, 0x40, 0x00, // call [VirtualAlloc]
+        0x48, 0x89, 0xc3, // mov rbx, rax
+        0x31, 0xc9, // xor ecx, ecx
+        0xba, 0x00, 0x10, 0x00, 0x00, // mov edx, 0x1000
+        0x41, 0xb8, 0x00, 0x30, 0x00, 0x00, // mov r8d, MEM_COMMIT | MEM_RESERVE
+        0x41, 0xb9, 0x04, 0x00, 0x00, 0x00, // mov r9d, PAGE_READWRITE
+        0xff, 0x14, 0x25, 0x00, 0x18, 0x40, 0x00, // call [VirtualAlloc]
+        0x48, 0x89, 0xc5, // mov rbp, rax
+        0xb9, 0x2a, 0x00, 0x00, 0x00, // mov ecx, 42
+        0xff, 0x14, 0x25, 0x08, 0x18, 0x40, 0x00, // call [ExitProcess]
+    ];
+    let pe = build_pe64(CODE, &[("KERNEL32.dll", &["VirtualAlloc", "ExitProcess"])]);
+    let path = std::env::temp_dir().join(format!("icicle-windows-{}.exe", std::process::id()));
+    std::fs::write(&path, pe).unwrap();
+
+    // Windows targets are dispatched to the Windows environment.
+    let mut vm = crate::build(&Config::from_target_triple("x86_64-pc-windows-msvc")).unwrap();
+    vm.env = crate::env::build_auto(&mut vm).unwrap();
+    vm.env.load(&mut vm.cpu, path.to_str().unwrap().as_bytes()).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    // The stack is misaligned by the return address pushed for the entry point.
+    let rsp = vm.cpu.arch.sleigh.get_varnode("RSP").unwrap();
+    let layout = vm.env_ref::<WindowsEnvironment>().unwrap().layout();
+    assert_eq!(vm.cpu.read_reg(rsp), layout.stack_base - 0x108);
+    assert_eq!(vm.cpu.read_pc(), 0x401000);
+
+    assert_eq!(vm.run(), VmExit::Halt);
+    assert_eq!(vm.env_ref::<WindowsEnvironment>().unwrap().exit_code(), Some(42));
+
+    // The requested address and protection are used.
+    let rwx = perm::READ | perm::WRITE | perm::EXEC;
+    let rbx = vm.cpu.read_reg(vm.cpu.arch.sleigh.get_varnode("RBX").unwrap());
+    assert_eq!(rbx, 0x7000_0000);
+    assert_eq!(vm.cpu.mem.get_perm(0x7000_1000) & rwx, perm::READ);
+
+    let rbp = vm.cpu.read_reg(vm.cpu.arch.sleigh.get_varnode("RBP").unwrap());
+    assert!(rbp != 0 && rbp.is_multiple_of(0x1_0000));
+    assert_eq!(vm.cpu.mem.get_perm(rbp) & rwx, perm::READ | perm::WRITE);
+}
+
+#[test]
+fn windows_seh_except_handler() {
+    use crate::windows::env::WindowsEnvironment;
+
+    #[rustfmt::skip]
+    static CODE: &[u8] = &[
+        // main:
+        0x48, 0x83, 0xec, 0x28,                   // sub rsp, 0x28
+        0xe8, 0x37, 0x00, 0x00, 0x00,             // call fault (inside of `__try`)
+        0x90,                                     // nop
+        0xb9, 0x01, 0x00, 0x00, 0x00,             // mov ecx, 1
+        0xff, 0x14, 0x25, 0x00, 0x18, 0x40, 0x00, // call [ExitProcess]
+        // __except block:
+        0x89, 0xc1,                               // mov ecx, eax
+        0xff, 0x14, 0x25, 0x00, 0x18, 0x40, 0x00, // call [ExitProcess]
+    ];
+    #[rustfmt::skip]
+    static FAULT: &[u8] = &[
+        0x48, 0x8b, 0x04, 0x25, 0x10, 0x00, 0x00, 0x00, // mov rax, [0x10]
+        0xc3,                                           // ret
+    ];
+    // jmp [__C_specific_handler]
+    static THUNK: &[u8] = &[0xff, 0x25, 0x22, 0x07, 0x00, 0x00];
+    #[rustfmt::skip]
+    static FILTER: &[u8] = &[
+        0x48, 0x8b, 0x01,                   // mov rax, [rcx] (ExceptionRecord)
+        0x81, 0x38, 0x05, 0x00, 0x00, 0xc0, // cmp dword [rax], STATUS_ACCESS_VIOLATION
+        0x0f, 0x94, 0xc0,                   // sete al
+        0x0f, 0xb6, 0xc0,                   // movzx eax, al
+        0xc3,                               // ret
+    ];
+    // A language specific handler that unwinds to the `__except` block, returning 0x77.
+    #[rustfmt::skip]
+    static UNWIND_HANDLER: &[u8] = &[
+        0x48, 0x83, 0xec, 0x38,                               // sub rsp, 0x38
+        0x49, 0x89, 0xc8,                                     // mov r8, rcx
+        0x48, 0x89, 0xd1,                                     // mov rcx, rdx
+        0xba, 0x16, 0x10, 0x40, 0x00,                         // mov edx, 0x401016
+        0x41, 0xb9, 0x77, 0x00, 0x00, 0x00,                   // mov r9d, 0x77
+        0x48, 0xc7, 0x44, 0x24, 0x20, 0x00, 0x00, 0x00, 0x00, // mov qword [rsp+0x20], 0
+        0x48, 0xc7, 0x44, 0x24, 0x28, 0x00, 0x00, 0x00, 0x00, // mov qword [rsp+0x28], 0
+        0xff, 0x14, 0x25, 0x10, 0x18, 0x40, 0x00,             // call [RtlUnwindEx]
+    ];
+    // A language specific handler that skips the faulting instruction.
+    #[rustfmt::skip]
+    static CONTINUE_HANDLER: &[u8] = &[
+        0x49, 0x83, 0x80, 0xf8, 0x00, 0x00, 0x00, 0x08, // add qword [r8+0xf8], 8 (Rip)
+        0x4c, 0x89, 0xc1,                               // mov rcx, r8
+        0x48, 0x83, 0xec, 0x28,                         // sub rsp, 0x28
+        0xff, 0x14, 0x25, 0x18, 0x18, 0x40, 0x00,       // call [NtContinue]
+    ];
+
+    fn put(code: &mut [u8], rva: usize, bytes: &[u8]) {
+        code[rva - 0x1000..][..bytes.len()].copy_from_slice(bytes);
+    }
+
+    let mut code = vec![0xcc; 0x800];
+    put(&mut code, 0x1000, CODE);
+    put(&mut code, 0x1040, FAULT);
+    put(&mut code, 0x1100, THUNK);
+    put(&mut code, 0x1120, FILTER);
+    put(&mut code, 0x1140, UNWIND_HANDLER);
+    put(&mut code, 0x1180, CONTINUE_HANDLER);
+
+    // RUNTIME_FUNCTION for `main`.
+    put(&mut code, 0x1600, &[0x1000_u32, 0x101f, 0x1610].map(u32::to_le_bytes).concat());
+    // UNWIND_INFO: version 1, UNW_FLAG_EHANDLER, `sub rsp, 0x28` (UWOP_ALLOC_SMALL) in the prolog.
+    put(&mut code, 0x1610, &[0x09, 0x04, 0x01, 0x00, 0x04, 0x42, 0x00, 0x00]);
+    // C_SCOPE_TABLE: __try { call fault } __except (filter) { ... }
+    let scope_table = [1_u32, 0x1004, 0x100a, 0x1120, 0x1016];
+    put(&mut code, 0x161c, &scope_table.map(u32::to_le_bytes).concat());
+
+    let imports: &[(&str, &[&str])] = &[
+        ("KERNEL32.dll", &["ExitProcess"]),
+        ("ntdll.dll", &["RtlUnwindEx", "NtContinue"]),
+        ("VCRUNTIME140.dll", &["__C_specific_handler"]),
+    ];
+    let path = std::env::temp_dir().join(format!("icicle-seh-{}.exe", std::process::id()));
+    let mut run = |handler: u32| {
+        put(&mut code, 0x1618, &handler.to_le_bytes());
+        let mut pe = build_pe64(&code, imports);
+        // Exception directory.
+        pe[0x58 + 0x88..][..8].copy_from_slice(&[0x00, 0x16, 0, 0, 12, 0, 0, 0]);
+        std::fs::write(&path, pe).unwrap();
+
+        let mut vm = crate::build(&Config::from_target_triple("x86_64-pc-windows-msvc")).unwrap();
+        vm.env = crate::env::build_auto(&mut vm).unwrap();
+        vm.env.load(&mut vm.cpu, path.to_str().unwrap().as_bytes()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(vm.run(), VmExit::Halt);
+        vm.env_ref::<WindowsEnvironment>().unwrap().exit_code()
+    };
+
+    // The filter accepts the access violation, so the `__except` block is executed with the
+    // exception code.
+    assert_eq!(run(0x1100), Some(0xC000_0005));
+    // The handler unwinds to the `__except` block itself.
+    assert_eq!(run(0x1140), Some(0x77));
+    // The handler resumes execution after the faulting instruction.
+    assert_eq!(run(0x1180), Some(1));
+}
+
+#[test]
+fn parse_ltrace_prototypes() {
+    use crate::ltrace::{ArgType, parse_prototypes};
+
+    let prototypes = parse_prototypes(crate::ltrace::DEFAULT_PROTOTYPES).unwrap();
+    assert_eq!(prototypes["memcpy"].args, [ArgType::Addr, ArgType::Addr, ArgType::Uint]);
+    assert_eq!(prototypes["free"].ret, None);
+
+    let prototypes = parse_prototypes("# comment\nint foo(string, void);").unwrap();
+    assert_eq!(prototypes["foo"].args, [ArgType::String]);
+    assert!(parse_prototypes("int bar(unknown);").is_err());
+}
+
+#[test]
+fn ltrace_logs_return_values() {
+    let mut vm = crate::build(&Config::from_target_triple("x86_64-none")).unwrap();
+    let code = Mapping { perm: perm::READ | perm::EXEC, value: 0 };
+    vm.cpu.mem.map_memory_len(0x1000, 0x200, code);
+    let data = Mapping { perm: perm::READ | perm::WRITE, value: 0 };
+    vm.cpu.mem.map_memory_len(0x7000, 0x1000, data);
+    #[rustfmt::skip]
+    let code = [
+        0xbf, 0x00, 0x70, 0x00, 0x00, // mov edi, 0x7000
+        0xe8, 0xf6, 0x00, 0x00, 0x00, // call 0x1100
+        0x31, 0xff,                   // xor edi, edi
+        0xe8, 0xff, 0x00, 0x00, 0x00, // call 0x1110
+        0xbf, 0xed, 0x01, 0x00, 0x00, // mov edi, 0o755
+        0xe8, 0x05, 0x01, 0x00, 0x00, // call 0x1120
+        0xeb, 0xfe,                   // jmp $
+    ];
+    vm.cpu.mem.write_bytes(0x1000, &code, perm::NONE).unwrap();
+    // mov eax, 2; ret
+    vm.cpu.mem.write_bytes(0x1100, &[0xb8, 0x02, 0x00, 0x00, 0x00, 0xc3], perm::NONE).unwrap();
+    // ret
+    vm.cpu.mem.write_bytes(0x1110, &[0xc3], perm::NONE).unwrap();
+    // mov eax, edi; ret
+    vm.cpu.mem.write_bytes(0x1120, &[0x89, 0xf8, 0xc3], perm::NONE).unwrap();
+    vm.cpu.mem.write_bytes(0x7000, b"hi\0", perm::NONE).unwrap();
+
+    let output = SharedWriter::default();
+    let mut ltrace = crate::ltrace::Ltrace::new().with_output(output.clone());
+    ltrace.add_prototypes("octal umask(octal);").unwrap();
+    let entries =
+        vec![(0x1100, "strlen".into()), (0x1110, "free".into()), (0x1120, "umask".into())];
+    ltrace.attach(&mut vm, entries);
+
+    let rsp = vm.cpu.arch.sleigh.get_varnode("RSP").unwrap();
+    vm.cpu.write_reg(rsp, 0x8000);
+    vm.cpu.write_pc(0x1000);
+    vm.icount_limit = 20;
+    assert_eq!(vm.run(), VmExit::InstructionLimit);
+
+    let output = String::from_utf8(output.0.borrow().clone()).unwrap();
+    assert_eq!(output, "strlen(\"hi\") = 2\nfree(nil)\numask(0755) = 0755\n");
+}
+
+#[test]
+fn libc_heap_model() {
+    use crate::libc_models::{HeapError, HeapModel};
+
+    let mut vm = crate::build(&Config::from_target_triple("x86_64-none")).unwrap();
+    let mut heap = HeapModel::new(&mut vm.cpu, 0x10000).unwrap();
+
+    let a = heap.alloc(0x20).unwrap();
+    let b = heap.alloc(0x20).unwrap();
+    assert!(b >= a + 0x20 && heap.contains(b));
+    assert_eq!(heap.find(a + 0x1f).map(|x| x.addr), Some(a));
+    assert_eq!(heap.find(a + 0x20), None);
+
+    assert_eq!(heap.free(a), Ok(0x20));
+    assert_eq!(heap.free(a), Err(HeapError::DoubleFree(a)));
+    assert_eq!(heap.free(a + 1), Err(HeapError::InvalidFree(a + 1)));
+    assert!(heap.find(a).unwrap().freed);
+    assert_eq!(heap.live().count(), 1);
+    assert_eq!(heap.alloc(0x20000), None);
+}
+
+#[test]
+fn libc_memory_models_copy_in_chunks() {
+    use crate::libc_models::{LibcModelOptions, LibcModels};
+
+    static CODE: &[u8] = &[
+        0xbf, 0x10, 0x80, 0x00, 0x00, // mov edi, 0x8010
+        0xbe, 0x00, 0x80, 0x00, 0x00, // mov esi, 0x8000
+        0xba, 0x00, 0x20, 0x00, 0x00, // mov edx, 0x2000
+        0xe8, 0xec, 0x00, 0x00, 0x00, // call memmove
+        0xbf, 0x00, 0x80, 0x00, 0x00, // mov edi, 0x8000
+        0xbe, 0xff, 0x00, 0x00, 0x00, // mov esi, 0xff
+        0x48, 0xba, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, // mov rdx, 1 << 48
+        0xe8, 0xe3, 0x00, 0x00, 0x00, // call memset
+        0x48, 0x89, 0xc3, // mov rbx, rax
+        0xeb, 0xfe, // jmp $
+    ];
+    // The guest implementation of each function just returns 0.
+    static STUB: &[u8] = &[0x31, 0xc0, 0xc3]; // xor eax, eax; ret
+
+    let mut vm = crate::build(&Config::from_target_triple("x86_64-none")).unwrap();
+    let rw = Mapping { perm: perm::READ | perm::WRITE, value: 0 };
+    vm.cpu.mem.map_memory_len(0x1000, 0x200, Mapping { perm: perm::READ | perm::EXEC, value: 0 });
+    vm.cpu.mem.map_memory_len(0x8000, 0x3000, rw);
+    vm.cpu.mem.map_memory_len(0x20000, 0x1000, rw);
+    vm.cpu.mem.write_bytes(0x1000, CODE, perm::NONE).unwrap();
+    vm.cpu.mem.write_bytes(0x1100, STUB, perm::NONE).unwrap();
+    vm.cpu.mem.write_bytes(0x1110, STUB, perm::NONE).unwrap();
+    let pattern: Vec<u8> = (0..0x2000).map(|i| (i % 251) as u8).collect();
+    vm.cpu.mem.write_bytes(0x8000, &pattern, perm::NONE).unwrap();
+
+    let options = LibcModelOptions { heap: false, ..LibcModelOptions::default() };
+    let entries = vec![(0x1100, "memmove".to_string()), (0x1110, "memset".to_string())];
+    LibcModels::new(&mut vm, options).unwrap().attach(&mut vm, entries);
+
+    let rsp = vm.cpu.arch.sleigh.get_varnode("RSP").unwrap();
+    let rbx = vm.cpu.arch.sleigh.get_varnode("RBX").unwrap();
+    vm.cpu.write_reg(rsp, 0x20f00);
+    vm.cpu.write_reg(rbx, 0x1234);
+    vm.cpu.write_pc(0x1000);
+
+    // The overlapping copy is larger than a single chunk.
+    assert_eq!(vm.run_until(0x1014), VmExit::Breakpoint);
+    let mut buf = vec![0; 0x2000];
+    vm.cpu.mem.read_bytes(0x8010, &mut buf, perm::NONE).unwrap();
+    assert!(buf == pattern, "memmove corrupted overlapping data");
+
+    // A guest controlled length that runs into unmapped memory falls back to the guest
+    // implementation.
+    vm.icount_limit = vm.cpu.icount + 10;
+    assert_eq!(vm.run(), VmExit::InstructionLimit);
+    assert_eq!(vm.cpu.read_reg(rbx), 0);
+}
+
+#[test]
+fn guest_log_splits_lines_by_origin() {
+    use std::io::Write;
+
+    use crate::guest_log::{GuestLog, LogOrigin};
+
+    let log = GuestLog::new();
+    let mut stdout = log.writer(LogOrigin::Stdout);
+    let mut uart = log.writer(LogOrigin::Peripheral("uart0".into()));
+
+    stdout.write_all(b"hello ").unwrap();
+    uart.write_all(b"boot\r\n").unwrap();
+    stdout.write_all(b"world\npartial").unwrap();
+    log.flush();
+
+    let entries: Vec<_> = log.entries().into_iter().map(|x| (x.origin, x.text)).collect();
+    assert_eq!(entries, [
+        (LogOrigin::Peripheral("uart0".into()), b"boot".to_vec()),
+        (LogOrigin::Stdout, b"hello world".to_vec()),
+        (LogOrigin::Stdout, b"partial".to_vec()),
+    ]);
+}
+
+#[test]
+fn guest_log_is_gated_and_cleared_on_restore() {
+    use std::io::Write;
+
+    use crate::guest_log::LogOrigin;
+
+    // The log is disabled by default, but writers still pass output through.
+    let vm = crate::build(&Config::from_target_triple("x86_64-none")).unwrap();
+    let output = SharedWriter::default();
+    let mut stdout = vm.guest_log.writer(LogOrigin::Stdout).with_passthrough(output.clone());
+    stdout.write_all(b"ignored\n").unwrap();
+    assert!(vm.guest_log.entries().is_empty());
+    assert_eq!(output.0.borrow().as_slice(), b"ignored\n");
+
+    let config = Config { enable_guest_log: true, ..Config::from_target_triple("x86_64-none") };
+    let mut vm = crate::build(&config).unwrap();
+    let snapshot = vm.snapshot();
+    let mut stdout = vm.guest_log.writer(LogOrigin::Stdout);
+    stdout.write_all(b"first\nsecond").unwrap();
+    assert_eq!(vm.guest_log.entries().len(), 1);
+
+    // Output produced after the snapshot, including incomplete lines, is discarded on restore.
+    vm.restore(&snapshot);
+    stdout.write_all(b"third\n").unwrap();
+    let entries: Vec<_> = vm.guest_log.entries().into_iter().map(|x| x.text).collect();
+    assert_eq!(entries, [b"third".to_vec()]);
+}
+
+#[test]
+fn itm_stimulus_port_output() {
+    use crate::{
+        guest_log::LogOrigin,
+        hw::{ITM_BASE, ITM_LEN, Itm},
+    };
+
+    let config = Config { enable_guest_log: true, ..Config::from_target_triple("thumbv7m-none") };
+    let mut vm = crate::build(&config).unwrap();
+    let itm = vm.cpu.mem.register_io_handler(Itm::new(vm.guest_log.clone()));
+    assert!(vm.cpu.mem.map_memory_len(ITM_BASE, ITM_LEN, itm));
+
+    // `ITM_SendChar` waits for the port to be ready before writing.
+    assert_eq!(vm.cpu.mem.read_u32(ITM_BASE, perm::NONE).unwrap() & 1, 1);
+    for byte in b"hi\n" {
+        vm.cpu.mem.write_u8(ITM_BASE, *byte, perm::NONE).unwrap();
+    }
+    vm.cpu.mem.write_u32(ITM_BASE + 4, u32::from_le_bytes(*b"ok!\n"), perm::NONE).unwrap();
+
+    // Disabling a port in the trace enable register drops its output.
+    vm.cpu.mem.write_u32(ITM_BASE + 0xe00, 0b01, perm::NONE).unwrap();
+    vm.cpu.mem.write_u32(ITM_BASE + 4, u32::from_le_bytes(*b"no!\n"), perm::NONE).unwrap();
+
+    let entries: Vec<_> = vm.guest_log.entries().into_iter().map(|x| (x.origin, x.text)).collect();
+    assert_eq!(entries, [
+        (LogOrigin::Itm(0), b"hi".to_vec()),
+        (LogOrigin::Itm(1), b"ok!".to_vec()),
+    ]);
+}
+
+#[test]
+fn snapshot_tree_restore_any_node() {
+    use crate::snapshot_tree::SnapshotTree;
+
+    let mut vm = crate::build(&Config::from_target_triple("x86_64-none")).unwrap();
+    vm.cpu.mem.map_memory_len(0x8000, 0x4000, Mapping { perm: perm::READ | perm::WRITE, value: 0 });
+    vm.cpu.mem.write_u64(0x8000, 1, perm::NONE).unwrap();
+
+    let mut tree = SnapshotTree::new();
+    let root = tree.take(&mut vm, Some("root")).unwrap();
+
+    vm.cpu.mem.write_u64(0x8000, 2, perm::NONE).unwrap();
+    let a = tree.take(&mut vm, Some("a")).unwrap();
+    assert_eq!(tree.get(a).unwrap().parent, Some(root));
+    assert_eq!(tree.get(a).unwrap().unique_pages, 1);
+
+    // Taking a snapshot after restoring creates a sibling branch.
+    tree.restore(&mut vm, root).unwrap();
+    assert_eq!(vm.cpu.mem.read_u64(0x8000, perm::NONE).unwrap(), 1);
+    vm.cpu.mem.write_u64(0x9000, 3, perm::NONE).unwrap();
+    let b = tree.take(&mut vm, Some("b")).unwrap();
+    assert_eq!(tree.get(root).unwrap().children, [a, b]);
+
+    tree.restore_named(&mut vm, "a").unwrap();
+    assert_eq!(vm.cpu.mem.read_u64(0x8000, perm::NONE).unwrap(), 2);
+    assert_eq!(vm.cpu.mem.read_u64(0x9000, perm::NONE).unwrap(), 0);
+    assert!(tree.take(&mut vm, Some("a")).is_err());
+}
+
+#[test]
+fn boot_trace_divergence() {
+    use crate::boot_trace;
+
+    let run = |init: u64| {
+        let mut vm =
+            crate::build(&Config { triple: "riscv64-none".parse().unwrap(), ..Config::default() })
+                .unwrap();
+        let rx = Mapping { perm: perm::READ | perm::EXEC, value: 0 };
+        vm.cpu.mem.map_memory_len(0x1000, 0x100, rx);
+        static CODE: &[u8] = &[
+            0x13, 0x00, 0x00, 0x00, // nop
+            0x93, 0x80, 0x10, 0x00, // addi ra,ra,1
+            0x13, 0x00, 0x00, 0x00, // nop
+        ];
+        vm.cpu.mem.write_bytes(0x1000, CODE, perm::NONE).unwrap();
+        let ra = vm.cpu.arch.sleigh.get_varnode("ra").unwrap();
+        vm.cpu.write_reg(ra, init);
+        vm.cpu.write_pc(0x1000);
+        boot_trace::record(&mut vm, 3).0
+    };
+
+    let (a, b) = (run(0), run(0));
+    assert!(boot_trace::diff(&a, &b, 2).is_none());
+
+    let c = run(1);
+    assert_eq!(boot_trace::first_divergence(&a, &c), Some(0));
+
+    let line = a[1].to_line();
+    assert_eq!(boot_trace::TraceEntry::parse_line(&line).as_ref(), Some(&a[1]));
+}
+
+#[test]
+fn translation_cache_reused_across_vms() {
+    static CODE: &[u8] = &[
+        0x48, 0x83, 0xc0, 0x01, // add rax, 1
+        0xeb, 0xfa, // jmp 0x1000
+    ];
+    let path = std::env::temp_dir().join(format!("icicle-tcache-{}", std::process::id()));
+    let binary_hash = crate::translation_cache::hash_bytes(CODE);
+
+    let run = |path: &std::path::Path| {
+        let mut vm = crate::build(&Config::from_target_triple("x86_64-none")).unwrap();
+        vm.load_translation_cache(binary_hash, path).unwrap();
+        let mapping = Mapping { perm: perm::READ | perm::EXEC, value: 0 };
+        vm.cpu.mem.map_memory_len(0x1000, 0x100, mapping);
+        vm.cpu.mem.write_bytes(0x1000, CODE, perm::NONE).unwrap();
+        vm.cpu.write_pc(0x1000);
+        vm.icount_limit = 10;
+        assert_eq!(vm.run(), VmExit::InstructionLimit);
+
+        let rax = vm.cpu.arch.sleigh.get_varnode("RAX").unwrap();
+        assert_eq!(vm.cpu.read_reg(rax), 5);
+        vm.save_translation_cache(path).unwrap();
+        vm.translation_cache.as_ref().unwrap().hits
+    };
+
+    assert_eq!(run(&path), 0);
+    assert_eq!(run(&path), 1);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn translation_cache_bypassed_with_lift_callbacks() {
+    static CODE: &[u8] = &[
+        0x48, 0x83, 0xc0, 0x01, // add rax, 1
+        0xeb, 0xfa, // jmp 0x1000
+    ];
+    let path = std::env::temp_dir().join(format!("icicle-tcache-hooks-{}", std::process::id()));
+    let binary_hash = crate::translation_cache::hash_bytes(CODE);
+
+    let run = |path: &std::path::Path, with_callback: bool| {
+        let mut vm = crate::build(&Config::from_target_triple("x86_64-none")).unwrap();
+        let lifted = std::rc::Rc::new(std::cell::Cell::new(0));
+        if with_callback {
+            let lifted = lifted.clone();
+            let callback = move |_: &icicle_cpu::BlockGroup, _: &mut icicle_cpu::BlockTable| {
+                lifted.set(lifted.get() + 1)
+            };
+            vm.lifter.lift_callbacks.push(Box::new(callback));
+        }
+        vm.load_translation_cache(binary_hash, path).unwrap();
+        let mapping = Mapping { perm: perm::READ | perm::EXEC, value: 0 };
+        vm.cpu.mem.map_memory_len(0x1000, 0x100, mapping);
+        vm.cpu.mem.write_bytes(0x1000, CODE, perm::NONE).unwrap();
+        vm.cpu.write_pc(0x1000);
+        vm.icount_limit = 10;
+        assert_eq!(vm.run(), VmExit::InstructionLimit);
+        vm.save_translation_cache(path).unwrap();
+        (vm.translation_cache.as_ref().unwrap().hits, lifted.get())
+    };
+
+    // Populate the cache without any hooks, then check that the callback still runs for code that
+    // is in the cache.
+    assert_eq!(run(&path, false), (0, 0));
+    assert_eq!(run(&path, true), (0, 1));
+    assert_eq!(run(&path, false), (1, 0));
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn translation_cache_bypassed_with_lift_time_limit() {
+    static CODE: &[u8] = &[
+        0x48, 0x83, 0xc0, 0x01, // add rax, 1
+        0xeb, 0xfa, // jmp 0x1000
+    ];
+    let path = std::env::temp_dir().join(format!("icicle-tcache-time-{}", std::process::id()));
+    let binary_hash = crate::translation_cache::hash_bytes(CODE);
+
+    let run = |path: &std::path::Path| {
+        let mut config = Config::from_target_triple("x86_64-none");
+        config.max_lift_time = Some(std::time::Duration::from_secs(60));
+        let mut vm = crate::build(&config).unwrap();
+        vm.load_translation_cache(binary_hash, path).unwrap();
+        let mapping = Mapping { perm: perm::READ | perm::EXEC, value: 0 };
+        vm.cpu.mem.map_memory_len(0x1000, 0x100, mapping);
+        vm.cpu.mem.write_bytes(0x1000, CODE, perm::NONE).unwrap();
+        vm.cpu.write_pc(0x1000);
+        vm.icount_limit = 10;
+        assert_eq!(vm.run(), VmExit::InstructionLimit);
+        vm.save_translation_cache(path).unwrap();
+        vm.translation_cache.as_ref().unwrap().hits
+    };
+
+    // Blocks lifted with a time limit depend on host timing, so are never stored or reused.
+    assert_eq!(run(&path), 0);
+    assert_eq!(run(&path), 0);
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn breakpoint_ignore_and_temporary() {
+    static CODE: &[u8] = &[
+        0x48, 0x83, 0xc0, 0x01, // add rax, 1
+        0xeb, 0xfa, // jmp 0x1000
+    ];
+    let mut vm = crate::build(&Config::from_target_triple("x86_64-none")).unwrap();
+    vm.cpu.mem.map_memory_len(0x1000, 0x100, Mapping { perm: perm::READ | perm::EXEC, value: 0 });
+    vm.cpu.mem.write_bytes(0x1000, CODE, perm::NONE).unwrap();
+    vm.cpu.write_pc(0x1000);
+    vm.icount_limit = 100;
+    let rax = vm.cpu.arch.sleigh.get_varnode("RAX").unwrap();
+
+    vm.set_breakpoint(0x1004, crate::breakpoints::Breakpoint::with_ignore_count(2));
+    assert_eq!(vm.run(), VmExit::Breakpoint);
+    assert_eq!(vm.cpu.read_reg(rax), 3);
+    assert_eq!(vm.breakpoint(0x1004).unwrap().hit_count, 3);
+
+    vm.remove_breakpoint(0x1004);
+    vm.add_temporary_breakpoint(0x1000);
+    assert_eq!(vm.run(), VmExit::Breakpoint);
+    assert!(vm.breakpoint(0x1000).is_none());
+    assert_eq!(vm.run(), VmExit::InstructionLimit);
+
+    // Adding a breakpoint at an address that is already armed still tracks its metadata.
+    vm.arm_breakpoint(0x1004);
+    assert!(!vm.add_breakpoint(0x1004));
+    assert_eq!(vm.breakpoint(0x1004), Some(&crate::breakpoints::Breakpoint::default()));
+}
+
+#[test]
+fn drcov_export() {
+    static CODE: &[u8] = &[
+        0x48, 0x83, 0xc0, 0x01, // add rax, 1
+        0xeb, 0xfa, // jmp 0x1010
+    ];
+    let mut vm = crate::build(&Config::from_target_triple("x86_64-none")).unwrap();
+    vm.cpu.mem.map_memory_len(0x1000, 0x100, Mapping { perm: perm::READ | perm::EXEC, value: 0 });
+    vm.cpu.mem.write_bytes(0x1010, CODE, perm::NONE).unwrap();
+    vm.cpu.write_pc(0x1010);
+    vm.icount_limit = 10;
+    assert_eq!(vm.run(), VmExit::InstructionLimit);
+
+    let mut writer = crate::drcov::DrcovWriter::new();
+    writer.add_module("code.bin", 0x1000, 0x1100);
+    let mut out = vec![];
+    assert_eq!(writer.write(&vm, &mut out).unwrap(), 1);
+
+    let header_end = out.len() - 8;
+    let header = std::str::from_utf8(&out[..header_end]).unwrap();
+    assert!(header.contains("Module Table: version 2, count 1\n"));
+    assert!(header.contains("  0, 0x0000000000001000, 0x0000000000001100,"));
+    assert!(header.ends_with("BB Table: 1 bbs\n"));
+    assert_eq!(&out[header_end..], &[0x10, 0, 0, 0, 6, 0, 0, 0]);
+}
+
+#[test]
+fn run_control_pause_resume() {
+    use crate::run_control::{RunControl, RunState};
+
+    let (tx, rx) = std::sync::mpsc::channel();
+    let vm_thread = std::thread::spawn(move || {
+        let mut vm = crate::build(&Config::from_target_triple("x86_64-none")).unwrap();
+        let mapping = Mapping { perm: perm::READ | perm::EXEC, value: 0 };
+        vm.cpu.mem.map_memory_len(0x1000, 0x100, mapping);
+        vm.cpu.mem.write_bytes(0x1000, &[0xeb, 0xfe], perm::NONE).unwrap(); // jmp 0x1000
+        vm.cpu.write_pc(0x1000);
+
+        let control = RunControl::new(&vm);
+        tx.send(control.clone()).unwrap();
+        vm.run_controlled(&control)
+    });
+    let control = rx.recv().unwrap();
+    assert_eq!(control.state(), RunState::Paused(None));
+    assert!(!control.pause());
+
+    assert!(control.step(10));
+    assert_eq!(control.wait(None), RunState::Paused(Some(VmExit::InstructionLimit)));
+
+    assert!(control.resume());
+    assert!(control.pause());
+    assert_eq!(control.wait(None), RunState::Paused(Some(VmExit::Interrupted)));
+
+    control.shutdown();
+    assert_eq!(vm_thread.join().unwrap(), VmExit::Interrupted);
+    assert_eq!(control.state(), RunState::Exited(VmExit::Interrupted));
+}
+
+#[test]
+fn tenet_trace() {
+    static CODE: &[u8] = &[
+        0x48, 0xc7, 0xc0, 0x01, 0x00, 0x00, 0x00, // mov rax, 1
+        0x89, 0x04, 0x25, 0x00, 0x20, 0x00, 0x00, // mov dword ptr [0x2000], eax
+        0xeb, 0xfe, // jmp 0x100e
+    ];
+    let path = std::env::temp_dir().join(format!("icicle-tenet-{}", std::process::id()));
+
+    let mut vm = crate::build(&Config::from_target_triple("x86_64-none")).unwrap();
+    let tracer = crate::tenet::add_tenet_tracer(&mut vm, std::fs::File::create(&path).unwrap());
+    let mapping = Mapping { perm: perm::READ | perm::WRITE | perm::EXEC, value: 0 };
+    vm.cpu.mem.map_memory_len(0x1000, 0x2000, mapping);
+    vm.cpu.mem.write_bytes(0x1000, CODE, perm::NONE).unwrap();
+    vm.cpu.write_pc(0x1000);
+    vm.icount_limit = 3;
+    assert_eq!(vm.run(), VmExit::InstructionLimit);
+    tracer.finish().unwrap();
+
+    let trace = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let lines: Vec<_> = trace.lines().collect();
+    assert!(lines[0].starts_with("rax=0x0,rbx=0x0,"));
+    assert!(lines[0].ends_with(",r15=0x0,rip=0x1000"));
+    assert_eq!(lines[1], "rax=0x1,rip=0x1007,mw=0x2000:01000000");
+    assert_eq!(lines[2], "rip=0x100e");
+}
+
+#[test]
+fn source_annotated_trace() {
+    static CODE: &[u8] = &[
+        0x48, 0xc7, 0xc0, 0x01, 0x00, 0x00, 0x00, // mov rax, 1
+        0x89, 0x04, 0x25, 0x00, 0x20, 0x00, 0x00, // mov dword ptr [0x2000], eax
+        0xeb, 0xfe, // jmp 0x100e
+    ];
+
+    let mut vm = crate::build(&Config::from_target_triple("x86_64-none")).unwrap();
+    let mapping = Mapping { perm: perm::READ | perm::WRITE | perm::EXEC, value: 0 };
+    vm.cpu.mem.map_memory_len(0x1000, 0x2000, mapping);
+    vm.cpu.mem.write_bytes(0x1000, CODE, perm::NONE).unwrap();
+    vm.cpu.write_pc(0x1000);
+    vm.icount_limit = 3;
+    assert_eq!(vm.run(), VmExit::InstructionLimit);
+
+    // Without debug info, each instruction in the block is written without annotations.
+    let mut out = vec![];
+    crate::source_trace::annotate_trace(&mut vm, [(0x1000, 0)], &mut out).unwrap();
+    let trace = String::from_utf8(out).unwrap();
+    let lines: Vec<_> = trace.lines().collect();
+    assert_eq!(lines.len(), 3);
+    assert!(lines[0].starts_with("    0x1000 [0]: "));
+    assert!(lines[1].starts_with("    0x1007 [1]: "));
+    assert!(lines[2].starts_with("    0x100e [2]: "));
+}
+
+#[test]
+fn source_annotated_trace_stops_at_early_exit() {
+    static CODE: &[u8] = &[
+        0x48, 0xc7, 0xc0, 0x01, 0x00, 0x00, 0x00, // mov rax, 1
+        0x89, 0x04, 0x25, 0x00, 0x50, 0x00, 0x00, // mov dword ptr [0x5000], eax
+        0xeb, 0xfe, // jmp 0x100e
+    ];
+
+    let mut vm = crate::build(&Config::from_target_triple("x86_64-none")).unwrap();
+    vm.cpu.mem.map_memory_len(0x1000, 0x1000, Mapping { perm: perm::READ | perm::EXEC, value: 0 });
+    vm.cpu.mem.write_bytes(0x1000, CODE, perm::NONE).unwrap();
+    vm.cpu.write_pc(0x1000);
+    vm.icount_limit = 3;
+    assert!(matches!(vm.run(), VmExit::UnhandledException((ExceptionCode::WriteUnmapped, 0x5000))));
+
+    // The trace ends at the faulting instruction, the rest of the block is not included.
+    let mut out = vec![];
+    crate::source_trace::annotate_trace(&mut vm, [(0x1000, 0)], &mut out).unwrap();
+    let trace = String::from_utf8(out).unwrap();
+    let lines: Vec<_> = trace.lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].starts_with("    0x1000 [0]: "));
+    assert!(lines[1].starts_with("    0x1007 [1]: "));
+}
+
+/// An environment that maps addresses to lines of a single source file.
+struct SourceEnv {
+    file: String,
+    lines: Vec<(u64, u32)>,
+}
+
+impl icicle_cpu::Environment for SourceEnv {
+    fn load(&mut self, _: &mut icicle_cpu::Cpu, _: &[u8]) -> Result<(), String> {
+        Err("unsupported".into())
+    }
+    fn handle_exception(&mut self, _: &mut icicle_cpu::Cpu) -> Option<VmExit> {
+        None
+    }
+    fn symbolize_addr(
+        &mut self,
+        _: &mut icicle_cpu::Cpu,
+        addr: u64,
+    ) -> Option<icicle_cpu::debug_info::SourceLocation> {
+        let (_, line) = self.lines.iter().find(|(start, _)| *start == addr)?;
+        Some(icicle_cpu::debug_info::SourceLocation {
+            function: Some(("main".into(), 0x1000)),
+            file: Some(self.file.clone()),
+            line: Some(*line),
+            ..Default::default()
+        })
+    }
+    fn snapshot(&mut self) -> Box<dyn std::any::Any> {
+        Box::new(())
+    }
+    fn restore(&mut self, _: &Box<dyn std::any::Any>) {}
+}
+
+/// A writer that can be inspected after being moved into a tracer.
+#[derive(Clone, Default)]
+struct SharedWriter(std::rc::Rc<std::cell::RefCell<Vec<u8>>>);
+
+impl std::io::Write for SharedWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn source_tracer_annotates_lines() {
+    static CODE: &[u8] = &[
+        0x48, 0xc7, 0xc0, 0x01, 0x00, 0x00, 0x00, // mov rax, 1
+        0x48, 0xff, 0xc0, // inc rax
+        0xeb, 0xfe, // jmp 0x100a
+    ];
+
+    let file = std::env::temp_dir().join(format!("icicle-source-trace-{}.c", std::process::id()));
+    std::fs::write(&file, "int x = 1;\n    x++;\nfor (;;) {}\n").unwrap();
+    let file = file.to_str().unwrap().to_owned();
+
+    let mut vm = crate::build(&Config::from_target_triple("x86_64-none")).unwrap();
+    let lines = vec![(0x1000, 1), (0x1007, 2), (0x100a, 3)];
+    vm.set_env(SourceEnv { file: file.clone(), lines });
+    vm.cpu.mem.map_memory_len(0x1000, 0x1000, Mapping { perm: perm::READ | perm::EXEC, value: 0 });
+    vm.cpu.mem.write_bytes(0x1000, CODE, perm::NONE).unwrap();
+
+    let out = SharedWriter::default();
+    let tracer = crate::source_trace::add_source_tracer(&mut vm, out.clone());
+    vm.cpu.write_pc(0x1000);
+    vm.icount_limit = 4;
+    assert_eq!(vm.run(), VmExit::InstructionLimit);
+    tracer.flush(&mut vm).unwrap();
+
+    let trace = String::from_utf8(out.0.borrow().clone()).unwrap();
+    let lines: Vec<_> = trace.lines().collect();
+    assert_eq!(lines[0], "main:");
+    assert_eq!(lines[1], format!("  {file}:1: int x = 1;"));
+    assert!(lines[2].starts_with("    0x1000 "));
+    assert_eq!(lines[3], format!("  {file}:2: x++;"));
+    assert!(lines[4].starts_with("    0x1007 "));
+    assert_eq!(lines[5], format!("  {file}:3: for (;;) {{}}"));
+    // The source line is only written again when the location changes.
+    assert!(lines[6].starts_with("    0x100a "));
+    assert!(lines[7].starts_with("    0x100a "));
+    assert_eq!(lines.len(), 8);
+
+    // The same annotations are produced for a trace of executed blocks.
+    let mut out = vec![];
+    crate::source_trace::annotate_trace(&mut vm, [(0x1000, 0)], &mut out).unwrap();
+    let trace = String::from_utf8(out).unwrap();
+    let block_lines: Vec<_> = trace.lines().collect();
+    assert_eq!(block_lines.len(), 7);
+    for i in [0, 1, 3, 5] {
+        assert_eq!(block_lines[i], lines[i]);
+    }
+    assert!(block_lines[6].starts_with("    0x100a [2]: "));
+
+    std::fs::remove_file(&file).unwrap();
+}
+
+#[test]
+fn init_abi_state_call() {
+    use icicle_cpu::abi::AbiArgs;
+
+    let mut vm = crate::build(&Config::from_target_triple("x86_64-none")).unwrap();
+    vm.cpu.mem.map_memory_len(0x1000, 0x100, Mapping { perm: perm::READ | perm::EXEC, value: 0 });
+    vm.cpu.mem.write_bytes(0x1000, &[0xc3], perm::NONE).unwrap(); // ret
+    vm.cpu.write_pc(0x1000);
+
+    let state = vm.cpu.init_abi_state(0x4000, AbiArgs::Call(&[1, 2, 3, 4, 5, 6, 7])).unwrap();
+    assert_eq!(state.stack_base - state.stack_limit, 0x4000);
+    assert_eq!(state.sp % 16, 8);
+    assert_eq!(vm.cpu.read_reg(vm.cpu.arch.reg_sp), state.sp);
+    assert_eq!(vm.cpu.read_reg(vm.cpu.arch.sleigh.get_varnode("RDI").unwrap()), 1);
+    assert_eq!(vm.cpu.read_reg(vm.cpu.arch.sleigh.get_varnode("R9").unwrap()), 6);
+    assert_eq!(vm.cpu.mem.read_u64(state.sp, perm::NONE).unwrap(), state.return_addr);
+    assert_eq!(vm.cpu.mem.read_u64(state.sp + 8, perm::NONE).unwrap(), 7);
+    assert!(vm.cpu.mem.write_u8(state.guard_page, 0, perm::WRITE).is_err());
+
+    assert_eq!(
+        vm.step(2),
+        VmExit::UnhandledException((ExceptionCode::ExecViolation, state.return_addr))
+    );
+}
+
+#[test]
+fn init_abi_state_without_stack_arg_offset() {
+    use icicle_cpu::abi::AbiArgs;
+
+    // AVR has no link register and no space reserved between the return address and the stack
+    // arguments.
+    let mut vm = crate::build(&Config::from_target_triple("avr-none")).unwrap();
+    let state = vm.cpu.init_abi_state(0x100, AbiArgs::Call(&[1, 2])).unwrap();
+    assert!(state.sp < state.stack_base);
+    assert_eq!(vm.cpu.read_reg(vm.cpu.arch.reg_sp), state.sp);
+}
+
+#[test]
+fn return_value_register_pairs() {
+    let mut vm = crate::build(&Config::from_target_triple("i686-none")).unwrap();
+    vm.cpu.write_return_value_u64(0x1122_3344_5566_7788).unwrap();
+    assert_eq!(vm.cpu.read_reg(vm.cpu.arch.sleigh.get_varnode("EAX").unwrap()), 0x5566_7788);
+    assert_eq!(vm.cpu.read_reg(vm.cpu.arch.sleigh.get_varnode("EDX").unwrap()), 0x1122_3344);
+    assert_eq!(vm.cpu.read_return_value_u64(), Some(0x1122_3344_5566_7788));
+
+    // Big-endian targets store the most significant half in the first register.
+    let mut vm = crate::build(&Config::from_target_triple("mips-none")).unwrap();
+    vm.cpu.write_return_value_u64(0x1122_3344_5566_7788).unwrap();
+    assert_eq!(vm.cpu.read_reg(vm.cpu.arch.sleigh.get_varnode("v0").unwrap()), 0x1122_3344);
+    assert_eq!(vm.cpu.read_return_value(), Some(0x1122_3344));
+}
+
+#[test]
+fn fork_and_switch() {
+    static CODE: &[u8] = &[
+        0xfe, 0x04, 0x25, 0x00, 0x28, 0x00, 0x00, // inc byte ptr [0x2800]
+        0xeb, 0xf7, // jmp 0x1000
+    ];
+    let mut vm = crate::build(&Config::from_target_triple("x86_64-none")).unwrap();
+    let mapping = Mapping { perm: perm::READ | perm::WRITE | perm::EXEC | perm::INIT, value: 0 };
+    vm.cpu.mem.map_memory_len(0x1000, 0x2000, mapping);
+    vm.cpu.mem.write_bytes(0x1000, CODE, perm::NONE).unwrap();
+    vm.cpu.write_pc(0x1000);
+
+    let root = vm.fork();
+    assert_eq!(vm.step(4), VmExit::InstructionLimit);
+    let child = vm.fork();
+    assert_eq!(vm.cpu.mem.read_u8(0x2800, perm::NONE).unwrap(), 2);
+
+    vm.switch_to(&root);
+    assert_eq!(vm.cpu.mem.read_u8(0x2800, perm::NONE).unwrap(), 0);
+    assert_eq!(vm.cpu.read_pc(), 0x1000);
+    vm.cpu.mem.write_u8(0x2800, 0x10, perm::NONE).unwrap();
+
+    vm.switch_to(&child);
+    assert_eq!(vm.cpu.mem.read_u8(0x2800, perm::NONE).unwrap(), 2);
+    assert_eq!(child.icount(), 4);
+}
+
+#[test]
+fn swapgs_and_gs_relative_access() {
+    static CODE: &[u8] = &[
+        0x0f, 0x01, 0xf8, // swapgs
+        0x65, 0x48, 0x8b, 0x04, 0x25, 0x08, 0x00, 0x00, 0x00, // mov rax, qword ptr gs:[0x8]
+    ];
+    let mut vm = crate::build(&Config::from_target_triple("x86_64-none")).unwrap();
+    vm.cpu.mem.map_memory_len(0x1000, 0x100, Mapping { perm: perm::READ | perm::EXEC, value: 0 });
+    vm.cpu.mem.map_memory_len(0x2000, 0x100, Mapping { perm: perm::READ | perm::WRITE, value: 0 });
+    vm.cpu.mem.write_bytes(0x1000, CODE, perm::NONE).unwrap();
+    vm.cpu.mem.write_u64(0x2008, 0x1234, perm::NONE).unwrap();
+
+    let kernel_gs_base = icicle_cpu::exec::helpers::x86::KERNEL_GS_BASE;
+    let kernel_gs_base = vm.cpu.arch.sleigh.get_varnode(kernel_gs_base).unwrap();
+    vm.cpu.write_reg(kernel_gs_base, 0x2000);
+    vm.cpu.write_pc(0x1000);
+    assert_eq!(vm.step(2), VmExit::InstructionLimit);
+
+    let gs_base = vm.cpu.arch.sleigh.get_varnode("GS_OFFSET").unwrap();
+    assert_eq!(vm.cpu.read_reg(gs_base), 0x2000);
+    assert_eq!(vm.cpu.read_reg(vm.cpu.arch.sleigh.get_varnode("RAX").unwrap()), 0x1234);
+    assert_eq!(vm.cpu.read_reg(kernel_gs_base), 0);
+}
+
+#[test]
+fn lift_coverage_reports_undecodable_instructions() {
+    static CODE: &[u8] = &[
+        0x90, // nop
+        0x06, 0x90, 0x90, 0x90, // push es (invalid in 64-bit mode), nop, nop, nop
+        0x06, 0x90, 0x90, 0x90, // push es (invalid in 64-bit mode), nop, nop, nop
+    ];
+    let triple = "x86_64-none".parse().unwrap();
+    let mut scanner = crate::lift_coverage::LiftScanner::new(&triple).unwrap();
+    scanner.scan(0x1000, CODE);
+    let report = scanner.finish();
+
+    assert_eq!(report.instructions, 9);
+    assert_eq!(report.lifted, 7);
+    assert_eq!(report.failures.len(), 1);
+    let failure = &report.failures[0];
+    assert_eq!(failure.kind, crate::lift_coverage::FailureKind::Decode);
+    assert_eq!(failure.encoding, "06 90 90 90");
+    assert_eq!((failure.count, failure.first_addr), (2, 0x1001));
+}
+
+#[test]
+fn prelift_branch_targets() {
+    static CODE: &[u8] = &[0x74, 0x0e]; // je 0x1010 (not taken)
+    let mut vm = crate::build(&Config::from_target_triple("x86_64-none")).unwrap();
+    let nops = Mapping { perm: perm::READ | perm::EXEC, value: 0x90 };
+    vm.cpu.mem.map_memory_len(0x1000, 0x100, nops);
+    vm.cpu.mem.write_bytes(0x1000, CODE, perm::NONE).unwrap();
+    vm.cpu.write_pc(0x1000);
+    vm.enable_prelift = true;
+
+    let discovered = std::rc::Rc::new(std::cell::RefCell::new(vec![]));
+    let discovered_ = discovered.clone();
+    vm.add_new_code_hook(move |_, group, _| discovered_.borrow_mut().push(group.start));
+
+    assert_eq!(vm.step(1), VmExit::InstructionLimit);
+    assert_eq!(vm.cpu.read_pc(), 0x1002);
+    assert_eq!(discovered.borrow()[0], 0x1000);
+
+    // The branch target has not been executed yet, so it should be lifted ahead of time.
+    assert!(vm.prelift(10) >= 1);
+    vm.cpu.write_pc(0x1010);
+    assert_eq!(vm.step(1), VmExit::InstructionLimit);
+    assert!(discovered.borrow().contains(&0x1010));
+    assert!(vm.discovery.prelift_hits >= 1);
+}
+
+#[test]
+fn save_and_load_state() {
+    static CODE: &[u8] = &[0x48, 0xff, 0xc0]; // inc rax
+    let config = Config::from_target_triple("x86_64-none");
+    let mut vm = crate::build(&config).unwrap();
+    vm.cpu.mem.map_memory_len(0x1000, 0x1000, Mapping { perm: perm::READ | perm::EXEC, value: 0 });
+    vm.cpu.mem.map_memory_len(0x4000, 0x4000, Mapping { perm: perm::READ, value: 0xaa });
+    vm.cpu.mem.write_bytes(0x1000, CODE, perm::NONE).unwrap();
+    vm.cpu.write_pc(0x1000);
+    assert_eq!(vm.step(1), VmExit::InstructionLimit);
+    vm.add_breakpoint(0x1003);
+    let disabled = crate::breakpoints::Breakpoint {
+        enabled: false,
+        hit_count: 2,
+        ignore_count: 1,
+        temporary: true,
+    };
+    vm.set_breakpoint(0x1010, disabled.clone());
+
+    let state = vm.save_state().unwrap();
+
+    // Load the state into a fresh VM and check that execution resumes from the same point.
+    let mut new_vm = crate::build(&config).unwrap();
+    new_vm.load_state(&state).unwrap();
+    let rax = new_vm.cpu.arch.sleigh.get_varnode("RAX").unwrap();
+    assert_eq!(new_vm.cpu.read_pc(), 0x1003);
+    assert_eq!(new_vm.cpu.read_reg(rax), 1);
+    assert_eq!(new_vm.cpu.icount, vm.cpu.icount);
+    assert_eq!(new_vm.cpu.mem.read_u8(0x4123, perm::READ).unwrap(), 0xaa);
+    assert!(new_vm.cpu.mem.write_u8(0x4123, 0, perm::WRITE).is_err());
+    assert_eq!(new_vm.breakpoint(0x1010), Some(&disabled));
+    assert!(!new_vm.code.breakpoints.contains(&0x1010));
+
+    new_vm.cpu.write_pc(0x1000);
+    assert_eq!(new_vm.run(), VmExit::Breakpoint);
+    assert_eq!(new_vm.cpu.read_reg(rax), 2);
+}
+
+#[test]
+fn recover_jump_table_targets() {
+    static CODE: &[u8] = &[0xff, 0x24, 0xc5, 0x00, 0x20, 0x00, 0x00]; // jmp [rax*8 + 0x2000]
+    let mut vm = crate::build(&Config::from_target_triple("x86_64-none")).unwrap();
+    let nops = Mapping { perm: perm::READ | perm::EXEC, value: 0x90 };
+    vm.cpu.mem.map_memory_len(0x1000, 0x1000, nops);
+    vm.cpu.mem.map_memory_len(0x2000, 0x1000, Mapping { perm: perm::READ, value: 0 });
+    vm.cpu.mem.write_bytes(0x1000, CODE, perm::NONE).unwrap();
+    for (i, target) in [0x1100_u64, 0x1200, 0x1100, 0x1300].iter().enumerate() {
+        vm.cpu.mem.write_u64(0x2000 + i as u64 * 8, *target, perm::NONE).unwrap();
+    }
+
+    // The table ends at the first entry that does not point to executable memory.
+    let group = vm.lift(0x1000).unwrap();
+    let targets: Vec<_> =
+        vm.code.group_jump_table_targets(&group).iter().map(|(_, addr)| *addr).collect();
+    assert_eq!(targets, [0x1100, 0x1200, 0x1300]);
+
+    let cfg = crate::debug::dump_cfg(&vm).unwrap();
+    assert!(cfg.contains("\"0x1000\" -> \"0x1200\" [label=\"switch\"]"));
+}
+
+#[test]
+fn jump_tables_are_not_read_from_io_memory() {
+    use icicle_cpu::mem::{IoMemory, MemResult};
+
+    struct CountReads(std::rc::Rc<std::cell::Cell<usize>>);
+
+    impl IoMemory for CountReads {
+        fn read(&mut self, _: u64, buf: &mut [u8]) -> MemResult<()> {
+            self.0.set(self.0.get() + 1);
+            buf.fill(0x10);
+            Ok(())
+        }
+
+        fn write(&mut self, _: u64, _: &[u8]) -> MemResult<()> {
+            Ok(())
+        }
+    }
+
+    static CODE: &[u8] = &[0xff, 0x24, 0xc5, 0x00, 0x20, 0x00, 0x00]; // jmp [rax*8 + 0x2000]
+    let mut vm = crate::build(&Config::from_target_triple("x86_64-none")).unwrap();
+    let nops = Mapping { perm: perm::READ | perm::EXEC, value: 0x90 };
+    vm.cpu.mem.map_memory_len(0x1000, 0x1000, nops);
+    vm.cpu.mem.write_bytes(0x1000, CODE, perm::NONE).unwrap();
+    let reads = std::rc::Rc::new(std::cell::Cell::new(0));
+    let io = vm.cpu.mem.register_io_handler(CountReads(reads.clone()));
+    vm.cpu.mem.map_memory_len(0x2000, 0x1000, io);
+
+    let group = vm.lift(0x1000).unwrap();
+    assert!(vm.code.group_jump_table_targets(&group).is_empty());
+    assert_eq!(reads.get(), 0);
+}
+
+#[test]
+fn input_mmio_models() {
+    use crate::mmio_input::{InputMmio, MmioModel};
+
+    let mut vm = crate::build(&Config::from_target_triple("x86_64-none")).unwrap();
+    let rw = perm::READ | perm::WRITE;
+    vm.cpu.mem.map_memory_len(0x4000_1000, 0x1000, Mapping { perm: rw, value: 0xaa });
+
+    let mut peripheral = InputMmio::new();
+    peripheral.set_model(0x4000_0000, MmioModel::Constant(0x1234));
+    peripheral.set_model(0x4000_0004, MmioModel::Passthrough { initial: 0 });
+    peripheral.set_model(0x4000_0008, MmioModel::Bitfield { mask: 0x0f00 });
+    peripheral.set_input(&[0x11, 0x22, 0x33, 0x44, 0x05, 0x66]);
+    let id = vm.map_input_mmio(0x4000_0000, 0x10000, peripheral);
+
+    let mem = &mut vm.cpu.mem;
+    assert_eq!(mem.read_u32(0x4000_0000, perm::READ).unwrap(), 0x1234);
+    mem.write_u32(0x4000_0004, 0xcafe, perm::WRITE).unwrap();
+    assert_eq!(mem.read_u32(0x4000_0004, perm::READ).unwrap(), 0xcafe);
+
+    // Unmodelled reads consume the input, and existing mappings are not replaced.
+    assert_eq!(mem.read_u16(0x4000_2000, perm::READ).unwrap(), 0x2211);
+    assert_eq!(mem.read_u8(0x4000_1000, perm::READ).unwrap(), 0xaa);
+    assert_eq!(mem.read_u8(0x4000_0010, perm::READ).unwrap(), 0x33);
+    assert_eq!(mem.read_u8(0x4000_0011, perm::READ).unwrap(), 0x44);
+    assert_eq!(mem.read_u32(0x4000_0008, perm::READ).unwrap(), 0x0500);
+    assert_eq!(mem.read_u16(0x4000_0020, perm::READ), Err(icicle_cpu::mem::MemError::ReadWatch));
+
+    let peripheral = mem.get_mmio_handler_mut::<InputMmio>(id).unwrap();
+    assert_eq!(peripheral.consumed(), 5);
+}
+
+#[test]
+fn block_group_stats() {
+    static CODE: &[u8] = &[
+        0x48, 0xff, 0xc0, // inc rax
+        0x48, 0xff, 0xc0, // inc rax
+        0xeb, 0xf8, // jmp 0x1000
+    ];
+    let mut vm = crate::build(&Config::from_target_triple("x86_64-none")).unwrap();
+    vm.cpu.mem.map_memory_len(0x1000, 0x1000, Mapping { perm: perm::READ | perm::EXEC, value: 0 });
+    vm.cpu.mem.write_bytes(0x1000, CODE, perm::NONE).unwrap();
+    vm.cpu.write_pc(0x1000);
+
+    vm.lift(0x1000).unwrap();
+    let stats = vm.get_block_stats(0x1000).unwrap();
+    assert_eq!((stats.guest_instructions, stats.guest_bytes), (3, 8));
+    assert!(stats.pcode_ops > 0);
+    assert_eq!((stats.hooks, stats.breakpoints), (0, 0));
+    assert_eq!(stats.host_code_bytes, None);
+
+    vm.icount_limit = 100;
+    assert_eq!(vm.run(), VmExit::InstructionLimit);
+    let stats = vm.get_block_stats(0x1000).unwrap();
+    assert!(stats.host_code_bytes.unwrap() > 0);
+    assert_eq!(vm.all_block_stats(), [(0x1000, stats)]);
+}
+
+#[test]
+fn cortex_m_interrupt_entry_and_return() {
+    use crate::{
+        cortex_m::{self, CortexM, IRQ_BASE},
+        env::GenericEmbedded,
+    };
+
+    let mut vm = crate::build(&Config::from_target_triple("thumbv7m-none")).unwrap();
+    vm.cpu.mem.map_memory_len(0x0, 0x1000, Mapping { perm: perm::READ | perm::EXEC, value: 0 });
+    vm.cpu.mem.map_memory_len(0x2000_0000, 0x1000, Mapping {
+        perm: perm::READ | perm::WRITE,
+        value: 0,
+    });
+
+    // Vector table: initial SP, reset handler, and a handler for IRQ 0.
+    vm.cpu.mem.write_u32(0x0, 0x2000_1000, perm::NONE).unwrap();
+    vm.cpu.mem.write_u32(0x4, 0x101, perm::NONE).unwrap();
+    vm.cpu.mem.write_u32(IRQ_BASE as u64 * 4, 0x201, perm::NONE).unwrap();
+    // movs r0, #0; b .
+    vm.cpu.mem.write_bytes(0x100, &[0x00, 0x20, 0xfe, 0xe7], perm::NONE).unwrap();
+    // adds r4, #1; bx lr
+    vm.cpu.mem.write_bytes(0x200, &[0x01, 0x34, 0x70, 0x47], perm::NONE).unwrap();
+
+    let config = cortex_m::Config { vtor: Some(0), ..cortex_m::Config::default() };
+    let mut env = CortexM::new(&mut vm.cpu, GenericEmbedded::new(), config).unwrap();
+    env.reset(&mut vm.cpu);
+    vm.set_env(env);
+
+    let r4 = vm.cpu.arch.sleigh.get_varnode("r4").unwrap();
+    let sp = vm.cpu.arch.sleigh.get_varnode("sp").unwrap();
+    assert_eq!(vm.cpu.read_pc(), 0x100);
+    assert_eq!(vm.cpu.read_reg(sp), 0x2000_1000);
+
+    // Interrupts are only taken once they are enabled in the NVIC.
+    vm.env_mut::<CortexM>().unwrap().set_irq_pending(0);
+    vm.icount_limit = 100;
+    assert_eq!(vm.run(), VmExit::InstructionLimit);
+    assert_eq!(vm.cpu.read_reg(r4), 0);
+
+    vm.cpu.mem.write_u32(cortex_m::SCS_BASE + 0x100, 0b1, perm::NONE).unwrap();
+    vm.env_mut::<CortexM>().unwrap().set_irq_pending(0);
+    vm.icount_limit = 200;
+    assert_eq!(vm.run(), VmExit::InstructionLimit);
+    assert_eq!(vm.cpu.read_reg(r4), 1);
+    assert_eq!(vm.cpu.read_pc(), 0x102);
+    assert_eq!(vm.cpu.read_reg(sp), 0x2000_1000);
+
+    let env = vm.env_mut::<CortexM>().unwrap();
+    assert_eq!(env.current_exception(), 0);
+    assert!(!env.nvic().is_pending(IRQ_BASE));
+
+    // Masked interrupts stay pending until they are unmasked.
+    let primask = vm.cpu.arch.sleigh.get_varnode(icicle_cpu::exec::helpers::arm::PRIMASK).unwrap();
+    vm.cpu.write_reg(primask, 1);
+    vm.env_mut::<CortexM>().unwrap().schedule_interrupt(250, IRQ_BASE);
+    vm.icount_limit = 300;
+    assert_eq!(vm.run(), VmExit::InstructionLimit);
+    assert_eq!(vm.cpu.read_reg(r4), 1);
+    assert!(vm.env_mut::<CortexM>().unwrap().nvic().is_pending(IRQ_BASE));
+
+    vm.cpu.write_reg(primask, 0);
+    vm.env_mut::<CortexM>().unwrap().set_pending(IRQ_BASE);
+    vm.icount_limit = 400;
+    assert_eq!(vm.run(), VmExit::InstructionLimit);
+    assert_eq!(vm.cpu.read_reg(r4), 2);
+}
+
+#[test]
+fn lift_limit_exceeded() {
+    use icicle_cpu::lifter::{DecodeError, LiftLimit};
+
+    static CODE: &[u8] = &[
+        0x48, 0xff, 0xc0, // inc rax
+        0xeb, 0xfb, // jmp 0x1000
+    ];
+    let mut vm = crate::build(&Config::from_target_triple("x86_64-none")).unwrap();
+    vm.cpu.mem.map_memory_len(0x1000, 0x1000, Mapping { perm: perm::READ | perm::EXEC, value: 0 });
+    vm.cpu.mem.write_bytes(0x1000, CODE, perm::NONE).unwrap();
+    vm.cpu.write_pc(0x1000);
+
+    vm.lifter.settings.max_pcode_ops_per_block = 1;
+    assert_eq!(
+        vm.lift(0x1000).err(),
+        Some(DecodeError::LimitExceeded { addr: 0x1000, limit: LiftLimit::PcodeOps })
+    );
+    assert_eq!(vm.step(1), VmExit::UnhandledException((ExceptionCode::LiftLimitExceeded, 0x1000)));
+
+    vm.lifter.settings.max_pcode_ops_per_block = usize::MAX;
+    vm.icount_limit = 10;
+    assert_eq!(vm.run(), VmExit::InstructionLimit);
+}
+
+#[test]
+fn queued_interrupts() {
+    use crate::{
+        cortex_m::{self, CortexM, IRQ_BASE},
+        env::GenericEmbedded,
+        interrupts::InterruptTrigger,
+    };
+
+    let mut vm = crate::build(&Config::from_target_triple("thumbv7m-none")).unwrap();
+    vm.cpu.mem.map_memory_len(0x0, 0x1000, Mapping { perm: perm::READ | perm::EXEC, value: 0 });
+    vm.cpu.mem.map_memory_len(0x2000_0000, 0x1000, Mapping {
+        perm: perm::READ | perm::WRITE,
+        value: 0,
+    });
+
+    vm.cpu.mem.write_u32(0x0, 0x2000_1000, perm::NONE).unwrap();
+    vm.cpu.mem.write_u32(0x4, 0x101, perm::NONE).unwrap();
+    vm.cpu.mem.write_u32(IRQ_BASE as u64 * 4, 0x201, perm::NONE).unwrap();
+    // movs r0, #0; adds r1, #1; b 0x102
+    vm.cpu.mem.write_bytes(0x100, &[0x00, 0x20, 0x01, 0x31, 0xfd, 0xe7], perm::NONE).unwrap();
+    // adds r4, #1; bx lr
+    vm.cpu.mem.write_bytes(0x200, &[0x01, 0x34, 0x70, 0x47], perm::NONE).unwrap();
+
+    let config = cortex_m::Config { vtor: Some(0), ..cortex_m::Config::default() };
+    let mut env = CortexM::new(&mut vm.cpu, GenericEmbedded::new(), config).unwrap();
+    env.reset(&mut vm.cpu);
+    vm.set_env(env);
+    vm.cpu.mem.write_u32(cortex_m::SCS_BASE + 0x100, 0b1, perm::NONE).unwrap();
+
+    let r4 = vm.cpu.arch.sleigh.get_varnode("r4").unwrap();
+
+    vm.queue_interrupt(InterruptTrigger::Icount(50), IRQ_BASE as u64);
+    vm.icount_limit = 49;
+    assert_eq!(vm.run(), VmExit::InstructionLimit);
+    assert_eq!(vm.cpu.read_reg(r4), 0);
+    vm.icount_limit = 100;
+    assert_eq!(vm.run(), VmExit::InstructionLimit);
+    assert_eq!(vm.cpu.read_reg(r4), 1);
+
+    vm.queue_interrupt(InterruptTrigger::Address(0x104), IRQ_BASE as u64);
+    vm.icount_limit = 200;
+    assert_eq!(vm.run(), VmExit::InstructionLimit);
+    assert_eq!(vm.cpu.read_reg(r4), 2);
+    assert!(vm.queued_interrupts().is_empty());
+    assert!(!vm.code.breakpoints.contains(&0x104));
+
+    // Interrupts that are not supported by the environment stop the VM.
+    vm.queue_interrupt(InterruptTrigger::Icount(vm.cpu.icount + 10), 0x1_0000);
+    vm.icount_limit = 300;
+    assert_eq!(vm.run(), VmExit::UnhandledException((ExceptionCode::UnknownInterrupt, 0x1_0000)));
+}
+
+#[test]
+fn shadow_memory_propagation() {
+    use crate::shadow::{self, ShadowAccess, ShadowPolicy};
+
+    #[derive(Default)]
+    struct Stores(Vec<ShadowAccess>);
+
+    impl ShadowPolicy for Stores {
+        fn on_store(&mut self, _: &mut icicle_cpu::Cpu, access: ShadowAccess, data: &mut [u8]) {
+            self.0.push(access);
+            // Mark all stored bytes as written.
+            data.iter_mut().for_each(|x| *x |= 0b10);
+        }
+    }
+
+    let mut vm = crate::build(&Config::from_target_triple("x86_64-none")).unwrap();
+    vm.cpu.mem.map_memory_len(0x1000, 0x100, Mapping { perm: perm::READ | perm::EXEC, value: 0 });
+    vm.cpu.mem.map_memory_len(0x2000, 0x100, Mapping { perm: perm::READ | perm::WRITE, value: 0 });
+    static CODE: &[u8] = &[
+        0x8a, 0x04, 0x25, 0x00, 0x20, 0x00, 0x00, // mov al, byte ptr [0x2000]
+        0x48, 0x01, 0xd8, // add rax, rbx
+        0x48, 0x89, 0x04, 0x25, 0x10, 0x20, 0x00, 0x00, // mov qword ptr [0x2010], rax
+    ];
+    vm.cpu.mem.write_bytes(0x1000, CODE, perm::NONE).unwrap();
+    let rax = vm.cpu.arch.sleigh.get_varnode("RAX").unwrap();
+
+    let shadow = shadow::attach(&mut vm, "test_shadow", 2, Stores::default());
+    shadow.set_memory(&mut vm, 0x2000, 1, 0b01);
+    let snapshot = vm.snapshot();
+
+    vm.cpu.write_pc(0x1000);
+    assert_eq!(vm.step(3), VmExit::InstructionLimit);
+    assert_eq!(shadow.register(rax), 0b01);
+    assert_eq!(shadow.memory(&vm, 0x2001), 0);
+    assert_eq!(shadow.memory(&vm, 0x2010), 0b11);
+    assert_eq!(shadow.memory(&vm, 0x2017), 0b11);
+    assert_eq!(shadow.memory(&vm, 0x2018), 0);
+    assert_eq!(shadow.policy().0.len(), 1);
+    assert_eq!(shadow.policy().0[0].addr, 0x2010);
+
+    // The metadata of memory is restored with the snapshot.
+    vm.restore(&snapshot);
+    assert_eq!(shadow.memory(&vm, 0x2000), 0b01);
+    assert_eq!(shadow.memory(&vm, 0x2010), 0);
+}
+
+#[test]
+fn secret_dependent_behaviour() {
+    use crate::secret::{self, LeakKind};
+
+    let mut vm = crate::build(&Config::from_target_triple("x86_64-none")).unwrap();
+    vm.cpu.mem.map_memory_len(0x1000, 0x100, Mapping { perm: perm::READ | perm::EXEC, value: 0 });
+    vm.cpu.mem.map_memory_len(0x2000, 0x100, Mapping { perm: perm::READ | perm::WRITE, value: 0 });
+    static CODE: &[u8] = &[
+        0x0f, 0xb6, 0x04, 0x25, 0x00, 0x20, 0x00, 0x00, // movzx eax, byte ptr [0x2000]
+        0x8a, 0x98, 0x40, 0x20, 0x00, 0x00, // mov bl, byte ptr [rax + 0x2040]
+        0x85, 0xc0, // test eax, eax
+        0x74, 0x02, // jz 0x1014
+        0x90, // nop
+        0x90, // nop
+    ];
+    vm.cpu.mem.write_bytes(0x1000, CODE, perm::NONE).unwrap();
+
+    let secrets = secret::attach(&mut vm);
+    secrets.mark_secret(&mut vm, 0x2000, 1);
+
+    vm.cpu.write_pc(0x1000);
+    assert_eq!(vm.step(4), VmExit::InstructionLimit);
+    assert_eq!(vm.cpu.read_pc(), 0x1014);
+
+    let leaks = secrets.leaks();
+    assert_eq!(leaks.len(), 2);
+    assert_eq!((leaks[0].pc, leaks[0].kind), (0x1008, LeakKind::LoadAddress));
+    assert_eq!(leaks[0].addr, 0x2040);
+    assert_eq!((leaks[1].pc, leaks[1].kind), (0x1010, LeakKind::BranchCondition));
+
+    // The value loaded from the table is not secret, since only the index was.
+    let rbx = vm.cpu.arch.sleigh.get_varnode("RBX").unwrap();
+    assert!(!secrets.is_register_secret(rbx));
+
+    secrets.clear(&mut vm);
+    assert!(secrets.leaks().is_empty());
+    assert!(!secrets.is_secret(&vm, 0x2000));
+}
+
+#[test]
+fn analytics_report() {
+    let mut vm = crate::build(&Config::from_target_triple("x86_64-none")).unwrap();
+    vm.cpu.mem.map_memory_len(0x1000, 0x100, Mapping { perm: perm::READ | perm::EXEC, value: 0 });
+    static CODE: &[u8] = &[
+        0xb9, 0x0a, 0x00, 0x00, 0x00, // mov ecx, 10
+        0xff, 0xc9, // dec ecx
+        0x75, 0xfc, // jnz 0x1005
+        0x90, // nop
+    ];
+    vm.cpu.mem.write_bytes(0x1000, CODE, perm::NONE).unwrap();
+
+    let analytics = crate::analytics::attach(&mut vm);
+    vm.cpu.write_pc(0x1000);
+    assert_eq!(vm.step(21), VmExit::InstructionLimit);
+    assert_eq!(vm.cpu.read_pc(), 0x1009);
+
+    let report = analytics.report(&mut vm, 1);
+    assert_eq!(report.icount, 21);
+    assert_eq!(report.hot_blocks.len(), 1);
+    assert_eq!((report.hot_blocks[0].addr, report.hot_blocks[0].hits), (0x1005, 9));
+    assert!(report.hot_functions.is_empty());
+    assert!(report.ops.iter().any(|(name, _)| name == "IntSub"));
+    let json: serde_json::Value = serde_json::from_str(&report.to_json()).unwrap();
+    assert_eq!(json["icount"], 21);
+    assert_eq!(json["hot_blocks"][0]["addr"], 0x1005);
+    assert!(json["hot_blocks"][0]["name"].is_null());
+    assert!(json["ops"]["IntSub"].as_u64().unwrap() >= 9);
+
+    analytics.clear();
+    assert_eq!(analytics.report(&mut vm, 1).blocks_executed, 0);
+}
+
+#[test]
+fn riscv_csrs() {
+    let mut vm = crate::build(&Config {
+        triple: "riscv32gc-none".parse().unwrap(),
+        enable_jit: false,
+        ..Config::default()
+    })
+    .unwrap();
+    vm.cpu.mem.map_memory_len(0x1000, 0x100, Mapping { perm: perm::READ | perm::EXEC, value: 0 });
+
+    static CODE: &[u8] = &[
+        0x13, 0x00, 0x00, 0x00, // nop
+        0x13, 0x00, 0x00, 0x00, // nop
+        0x73, 0x25, 0x00, 0xc0, // rdcycle a0
+        0x73, 0x90, 0x32, 0x00, // csrw fcsr, t0
+        0xf3, 0x25, 0x20, 0x00, // csrr a1, frm
+    ];
+    vm.cpu.mem.write_bytes(0x1000, CODE, perm::NONE).unwrap();
+
+    let reg = |vm: &crate::Vm, name: &str| vm.cpu.arch.sleigh.get_varnode(name).unwrap();
+    let (t0, a0, a1) = (reg(&vm, "t0"), reg(&vm, "a0"), reg(&vm, "a1"));
+    vm.cpu.write_reg(t0, (0b101 << 5) | 0b00011);
+
+    vm.cpu.write_pc(0x1000);
+    assert_eq!(vm.step(5), VmExit::InstructionLimit);
+    assert_eq!(vm.cpu.read_reg(a0), 2);
+    assert_eq!(vm.cpu.read_reg(a1), 0b101);
+    assert_eq!(vm.cpu.read_reg(reg(&vm, "fflags")), 0b00011);
+}
+
+#[test]
+fn cycle_timing() {
+    let mut vm = crate::build(&Config {
+        triple: "riscv32gc-none".parse().unwrap(),
+        enable_jit: false,
+        cycle_timing: true,
+        ..Config::default()
+    })
+    .unwrap();
+    vm.cpu.mem.map_memory_len(0x1000, 0x100, Mapping { perm: perm::READ | perm::EXEC, value: 0 });
+
+    static CODE: &[u8] = &[
+        0x33, 0x85, 0xc5, 0x02, // mul a0, a1, a2
+        0x63, 0x04, 0x00, 0x00, // beq zero, zero, 0x100c
+        0x13, 0x00, 0x00, 0x00, // nop
+        0x13, 0x00, 0x00, 0x00, // nop
+    ];
+    vm.cpu.mem.write_bytes(0x1000, CODE, perm::NONE).unwrap();
+
+    vm.cpu.write_pc(0x1000);
+    assert_eq!(vm.step(3), VmExit::InstructionLimit);
+    assert_eq!(vm.cpu.read_pc(), 0x1010);
+    assert_eq!(vm.cpu.icount(), 3);
+    // 1 cycle per instruction, +2 for `mul` and +2 for the taken branch.
+    assert_eq!(vm.cpu.clock(), 7);
+}
+
+#[test]
+fn busy_wait_skipping() {
+    use crate::busy_wait::{DelayLoop, SkippedLoop};
+
+    let mut vm = crate::build(&Config {
+        triple: "x86_64-none".parse().unwrap(),
+        enable_jit: false,
+        ..Config::default()
+    })
+    .unwrap();
+    vm.cpu.mem.map_memory_len(0x1000, 0x100, Mapping { perm: perm::READ | perm::EXEC, value: 0 });
+
+    static CODE: &[u8] = &[
+        0xb9, 0x40, 0x42, 0x0f, 0x00, // mov ecx, 1000000
+        0xff, 0xc9, // dec ecx
+        0x75, 0xfc, // jnz 0x1005
+        0x90, // nop
+    ];
+    vm.cpu.mem.write_bytes(0x1000, CODE, perm::NONE).unwrap();
+    vm.cpu.mem.write_bytes(0x1010, &[0xeb, 0xfe], perm::NONE).unwrap(); // jmp 0x1010
+
+    let busy_wait = crate::busy_wait::attach(&mut vm);
+    let rcx = vm.cpu.arch.sleigh.get_varnode("RCX").unwrap();
+    busy_wait.add_delay_loop(0x1005, DelayLoop {
+        counter: rcx,
+        exit: 0x1009,
+        instructions_per_iteration: 2,
+    });
+    busy_wait.set_spin_detection(true);
+
+    vm.cpu.write_pc(0x1000);
+    assert_eq!(vm.step(3), VmExit::InstructionLimit);
+    assert_eq!(vm.cpu.read_pc(), 0x1009);
+    assert_eq!(vm.cpu.read_reg(rcx), 0);
+    assert_eq!(vm.cpu.icount(), 2_000_002);
+
+    vm.cpu.write_pc(0x1010);
+    assert_eq!(vm.step(1000), VmExit::InstructionLimit);
+    assert_eq!(vm.cpu.icount(), 2_001_002);
+
+    let skipped = busy_wait.skipped();
+    assert_eq!(skipped.len(), 2);
+    assert_eq!(skipped[0], SkippedLoop { header: 0x1005, count: 1, skipped: 2_000_000 });
+    assert_eq!(skipped[1].header, 0x1010);
+}
+
+static TOY_SLASPEC: &str = r#"
+define endian=little;
+define alignment=2;
+
+define space ram type=ram_space size=4 default;
+define space register type=register_space size=4;
+
+define register offset=0 size=4 [ pc sp r0 r1 ];
+
+define token instr(16)
+    op = (8, 15)
+    reg = (7, 7)
+    imm = (0, 6)
+;
+attach variables reg [ r0 r1 ];
+
+:li reg, imm is op=1 & reg & imm { reg = imm; }
+:add reg is op=2 & reg { r0 = r0 + reg; }"#;
+
+static TOY_PSPEC: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<processor_spec>
+  <programcounter register="pc"/>
+</processor_spec>"#;
+
+#[test]
+fn runtime_processor_spec() {
+    let dir = std::env::temp_dir().join(format!("icicle-toy-spec-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("toy.slaspec"), TOY_SLASPEC).unwrap();
+    std::fs::write(dir.join("toy.pspec"), TOY_PSPEC).unwrap();
+
+    let mut spec = crate::ProcessorSpec::new(dir.join("toy.slaspec"), dir.join("toy.pspec"));
+    spec.boot_values.push(("r1".into(), 0x20));
+    let mut vm = crate::build_with_processor(&Config::default(), &spec).unwrap();
+    let _ = std::fs::remove_dir_all(&dir);
+
+    let reg = |vm: &crate::Vm, name: &str| vm.cpu.arch.sleigh.get_varnode(name).unwrap();
+    let (r0, r1) = (reg(&vm, "r0"), reg(&vm, "r1"));
+    assert_eq!(vm.cpu.arch.reg_sp, reg(&vm, "sp"));
+
+    vm.cpu.mem.map_memory_len(0x1000, 0x100, Mapping { perm: perm::READ | perm::EXEC, value: 0 });
+    static CODE: &[u8] = &[
+        0x05, 0x01, // li r0, 5
+        0x87, 0x01, // li r1, 7
+        0x80, 0x02, // add r1
+    ];
+    vm.cpu.mem.write_bytes(0x1000, CODE, perm::NONE).unwrap();
+
+    vm.cpu.reset();
+    assert_eq!(vm.cpu.read_reg(r1), 0x20);
+
+    vm.cpu.write_pc(0x1000);
+    assert_eq!(vm.step(3), VmExit::InstructionLimit);
+    assert_eq!(vm.cpu.read_pc(), 0x1006);
+    assert_eq!(vm.cpu.read_reg(r0), 12);
+    assert_eq!(vm.cpu.read_reg(r1), 7);
+
+    let err = crate::build_with_processor(&Config::default(), &crate::ProcessorSpec::default());
+    assert!(matches!(err, Err(crate::BuildError::SpecNotFound(_))));
+}
+
+#[test]
+fn mmio_poll_resolution() {
+    use icicle_cpu::mem::{MemResult, MmioHandler};
+
+    /// A PLL that only becomes ready once the guest waits for it.
+    struct Pll {
+        ready: bool,
+    }
+
+    impl MmioHandler for Pll {
+        fn load(&mut self, _: u64, _: u8) -> MemResult<u64> {
+            Ok(self.ready as u64)
+        }
+
+        fn store(&mut self, _: u64, _: u8, _: u64) -> MemResult<()> {
+            Ok(())
+        }
+
+        fn resolve_poll(&mut self, _: u64, _: u8) -> Option<u64> {
+            self.ready = true;
+            Some(500)
+        }
+    }
+
+    let mut vm = crate::build(&Config {
+        triple: "x86_64-none".parse().unwrap(),
+        enable_jit: false,
+        ..Config::default()
+    })
+    .unwrap();
+    vm.cpu.mem.map_memory_len(0x1000, 0x100, Mapping { perm: perm::READ | perm::EXEC, value: 0 });
+    let pll = vm.cpu.mem.map_mmio(0x2000, 0x1000, Pll { ready: false }).unwrap();
+
+    static CODE: &[u8] = &[
+        0x8b, 0x04, 0x25, 0x00, 0x20, 0x00, 0x00, // mov eax, dword ptr [0x2000]
+        0xa8, 0x01, // test al, 1
+        0x74, 0xf5, // jz 0x1000
+        0x90, // nop
+    ];
+    vm.cpu.mem.write_bytes(0x1000, CODE, perm::NONE).unwrap();
+
+    let busy_wait = crate::busy_wait::attach(&mut vm);
+    busy_wait.set_spin_detection(true);
+
+    vm.cpu.write_pc(0x1000);
+    assert_eq!(vm.step(100), VmExit::InstructionLimit);
+    assert!(vm.cpu.mem.get_mmio_handler_mut::<Pll>(pll).unwrap().ready);
+    assert_eq!(vm.cpu.icount(), 9 + 500);
+
+    assert_eq!(vm.step(4), VmExit::InstructionLimit);
+    assert_eq!(vm.cpu.read_pc(), 0x100c);
+    assert_eq!(busy_wait.skipped()[0].skipped, 500);
+}
+
+#[test]
+fn sleigh_spec_cache() {
+    use sleigh_compile::ldef::SleighLanguage;
+
+    let dir = std::env::temp_dir().join(format!("icicle-sleigh-cache-{}", std::process::id()));
+    let (spec_dir, cache_dir) = (dir.join("spec"), dir.join("cache"));
+    std::fs::create_dir_all(&spec_dir).unwrap();
+    std::fs::write(spec_dir.join("toy.slaspec"), TOY_SLASPEC).unwrap();
+    std::fs::write(spec_dir.join("toy.pspec"), TOY_PSPEC).unwrap();
+
+    let spec = crate::ProcessorSpec::new(spec_dir.join("toy.slaspec"), spec_dir.join("toy.pspec"));
+    let load = |compile: &dyn Fn() -> Result<SleighLanguage, crate::BuildError>| {
+        let paths = [spec_dir.as_path()];
+        crate::sleigh_cache::load_or_compile_in(&cache_dir, &paths, "toy", compile).unwrap()
+    };
+
+    let compiled = load(&|| spec.compile());
+    let cached = load(&|| panic!("cached spec was not used"));
+    assert_eq!(cached.pc, compiled.pc);
+    assert_eq!(cached.sleigh.constructors.len(), compiled.sleigh.constructors.len());
+    assert_eq!(cached.sleigh.get_varnode("r1"), compiled.sleigh.get_varnode("r1"));
+
+    // Modifying the specification should invalidate the cached copy.
+    std::fs::write(spec_dir.join("toy.slaspec"), format!("{TOY_SLASPEC}\n")).unwrap();
+    let compiles = std::cell::Cell::new(0);
+    load(&|| {
+        compiles.set(compiles.get() + 1);
+        spec.compile()
+    });
+    assert_eq!(compiles.get(), 1);
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn reload_peripheral() {
+    use std::any::Any;
+
+    use icicle_cpu::mem::{MemResult, MmioHandler};
+
+    struct Register {
+        value: u64,
+        writes: u64,
+    }
+
+    impl MmioHandler for Register {
+        fn load(&mut self, _: u64, _: u8) -> MemResult<u64> {
+            Ok(self.value)
+        }
+
+        fn store(&mut self, _: u64, _: u8, _: u64) -> MemResult<()> {
+            self.writes += 1;
+            Ok(())
+        }
+
+        fn snapshot(&mut self) -> Box<dyn Any> {
+            Box::new(self.writes)
+        }
+
+        fn restore(&mut self, snapshot: &Box<dyn Any>) {
+            self.writes = *snapshot.downcast_ref().unwrap();
+        }
+    }
+
+    struct Constant(u64);
+
+    impl MmioHandler for Constant {
+        fn load(&mut self, _: u64, _: u8) -> MemResult<u64> {
+            Ok(self.0)
+        }
+
+        fn store(&mut self, _: u64, _: u8, _: u64) -> MemResult<()> {
+            Ok(())
+        }
+
+        fn restore(&mut self, snapshot: &Box<dyn Any>) {
+            let () = *snapshot.downcast_ref().unwrap();
+        }
+    }
+
+    let mut vm = crate::build(&Config::from_target_triple("x86_64-none")).unwrap();
+    vm.cpu.mem.map_memory_len(0x1000, 0x100, Mapping { perm: perm::READ | perm::EXEC, value: 0 });
+    static CODE: &[u8] = &[0x8b, 0x04, 0x25, 0x00, 0x20, 0x00, 0x00]; // mov eax, dword ptr [0x2000]
+    vm.cpu.mem.write_bytes(0x1000, CODE, perm::NONE).unwrap();
+    let id = vm.cpu.mem.map_mmio(0x2000, 0x1000, Register { value: 1, writes: 0 }).unwrap();
+    let eax = vm.cpu.arch.sleigh.get_varnode("EAX").unwrap();
+
+    let read_register = |vm: &mut crate::Vm| {
+        vm.cpu.write_pc(0x1000);
+        vm.step(1);
+        vm.cpu.read_reg(eax)
+    };
+    let snapshot = vm.snapshot();
+    assert_eq!(read_register(&mut vm), 1);
+
+    assert!(vm.reconfigure_peripheral::<Register>(id, |reg| reg.value = 2));
+    assert!(!vm.reconfigure_peripheral::<Constant>(id, |_| {}));
+    assert_eq!(read_register(&mut vm), 2);
+
+    vm.reload_mmio_peripheral(id, Constant(3));
+    assert_eq!(read_register(&mut vm), 3);
+
+    // Snapshots taken before the reload do not modify the new peripheral.
+    vm.restore(&snapshot);
+    assert_eq!(read_register(&mut vm), 3);
+
+    vm.set_env(crate::env::GenericEmbedded::new());
+    assert!(vm.reconfigure_env::<crate::env::GenericEmbedded>(|_, _| {}));
+    assert!(!vm.reconfigure_env::<crate::cortex_m::CortexM>(|_, _| {}));
+}
+
+#[test]
+fn core_dump_contains_registers_and_memory() {
+    use object::read::elf::{FileHeader, ProgramHeader};
+
+    static CODE: &[u8] = &[0x8b, 0x04, 0x25, 0x00, 0x30, 0x00, 0x00]; // mov eax, dword ptr [0x3000]
+    let mut vm = crate::build(&Config::from_target_triple("x86_64-none")).unwrap();
+    vm.cpu.mem.map_memory_len(0x1000, 0x1000, Mapping { perm: perm::READ | perm::EXEC, value: 0 });
+    vm.cpu.mem.write_bytes(0x1000, CODE, perm::NONE).unwrap();
+    vm.cpu.write_pc(0x1000);
+    let exit = vm.run();
+    assert_eq!(exit, VmExit::UnhandledException((ExceptionCode::ReadUnmapped, 0x3000)));
+
+    let data = crate::core_dump::build_core_dump(&mut vm, ExceptionCode::ReadUnmapped).unwrap();
+    let header = object::elf::FileHeader64::<object::Endianness>::parse(&data[..]).unwrap();
+    let endian = header.endian().unwrap();
+    assert_eq!(header.e_type(endian), object::elf::ET_CORE);
+
+    let segments = header.program_headers(endian, &data[..]).unwrap();
+    let code = segments.iter().find(|x| x.p_vaddr(endian) == 0x1000).unwrap();
+    assert_eq!(&code.data(endian, &data[..]).unwrap()[..CODE.len()], CODE);
+
+    let notes = segments.iter().find(|x| x.p_type(endian) == object::elf::PT_NOTE).unwrap();
+    let mut notes = notes.notes(endian, &data[..]).unwrap().unwrap();
+    let prstatus = notes.next().unwrap().unwrap();
+    assert_eq!(prstatus.n_type(endian), object::elf::NT_PRSTATUS);
+    let desc = prstatus.desc();
+    assert_eq!(desc.len(), 336);
+    // si_signo == SIGSEGV
+    assert_eq!(desc[0], 11);
+    // pr_reg.rip
+    assert_eq!(u64::from_le_bytes(desc[112 + 16 * 8..][..8].try_into().unwrap()), 0x1000);
+}
+
+#[test]
+fn heap_sanitizer_reports() {
+    use crate::heap_sanitizer::HeapSanitizerOptions;
+
+    static CODE: &[u8] = &[
+        0x48, 0x8b, 0x47, 0x20, // mov rax, qword ptr [rdi + 0x20]
+    ];
+
+    let mut vm = crate::build(&Config::from_target_triple("x86_64-none")).unwrap();
+    vm.cpu.mem.map_memory_len(0x1000, 0x100, Mapping { perm: perm::READ | perm::EXEC, value: 0 });
+    vm.cpu.mem.write_bytes(0x1000, CODE, perm::NONE).unwrap();
+    let options = HeapSanitizerOptions { heap_size: 0x10000, redzone: 16 };
+    let heap = crate::heap_sanitizer::attach(&mut vm, vec![], options).unwrap();
+    let rdi = vm.cpu.arch.sleigh.get_varnode("RDI").unwrap();
+
+    // Reading past the end of an allocation hits the redzone.
+    let a = heap.alloc(&mut vm.cpu, 0x20).unwrap();
+    let b = heap.alloc(&mut vm.cpu, 0x40).unwrap();
+    assert!(b >= a + 0x20 + 16);
+    vm.cpu.write_reg(rdi, a);
+    vm.cpu.write_pc(0x1000);
+    assert_eq!(vm.run(), VmExit::UnhandledException((ExceptionCode::SanitizerError, a + 0x20)));
+    let report = vm.sanitizer_report().unwrap();
+    assert_eq!(report.bug_type.as_deref(), Some("heap-buffer-overflow"));
+    assert!(!report.access.unwrap().is_write);
+    assert!(report.details.unwrap().contains("0 bytes after 32-byte region"));
+
+    // Freed memory is poisoned.
+    heap.free(&mut vm.cpu, b).unwrap();
+    vm.cpu.write_reg(rdi, b);
+    vm.cpu.write_pc(0x1000);
+    assert_eq!(vm.run(), VmExit::UnhandledException((ExceptionCode::SanitizerError, b + 0x20)));
+    let report = heap.take_report().unwrap();
+    assert_eq!(report.bug_type.as_deref(), Some("heap-use-after-free"));
+    assert!(report.details.unwrap().contains("freed by:"));
+
+    assert_eq!(heap.free(&mut vm.cpu, b), None);
+    assert_eq!(heap.last_report().unwrap().bug_type.as_deref(), Some("double-free"));
+    assert_eq!(heap.live().len(), 1);
+}
+
+#[test]
+fn asan_reports_errors_with_summary() {
+    use icicle_cpu::debug_info::{DebugInfo, SymbolKind, SymbolTable};
+
+    use crate::{
+        asan::{AsanAccess, ShadowLayout},
+        guest_log::LogOrigin,
+    };
+
+    let layout = ShadowLayout::for_arch(target_lexicon::Architecture::X86_64).unwrap();
+    assert_eq!(layout.shadow_addr(0x1000), 0x7fff_8200);
+    assert_eq!(layout.region(), 0x7fff_8000..0x1000_7fff_8000);
+
+    let mut code = vec![
+        0xbf, 0x40, 0x20, 0x00, 0x00, // mov edi, 0x2040
+        0xe8, 0xf6, 0x00, 0x00, 0x00, // call __asan_report_load4
+        0xe8, 0xf2, 0x00, 0x00, 0x00, // call __asan_on_error
+        0x90, // nop (the report is printed here)
+        0xe8, 0xed, 0x00, 0x00, 0x00, // call __sanitizer::Die
+        0xeb, 0xfe, // jmp $
+    ];
+    code.resize(0x100, 0x90);
+    code.extend_from_slice(&[0xc3; 4]); // ret
+    let mut vm = linux_vm(&crate::linux::KernelConfig::default(), &code);
+
+    // Binaries without the ASAN runtime are ignored.
+    assert!(crate::asan::attach(&mut vm).is_none());
+    assert!(!vm.guest_log.is_enabled());
+
+    let mut symbols = SymbolTable::default();
+    for (i, name) in
+        ["__asan_report_load4", "__asan_on_error", "_ZN11__sanitizer3DieEv", "__asan_init"]
+            .into_iter()
+            .enumerate()
+    {
+        symbols.insert(name.into(), 0x1100 + i as u64, 1, SymbolKind::Function);
+    }
+    let kernel = vm.env_mut::<crate::linux::Kernel>().unwrap();
+    let mut debug_info = DebugInfo::default();
+    debug_info.symbols = std::rc::Rc::new(symbols);
+    kernel.process.debug_info = Some(debug_info);
+
+    let asan = crate::asan::attach(&mut vm).unwrap();
+    assert!(vm.guest_log.is_enabled());
+    let kernel = vm.env_ref::<crate::linux::Kernel>().unwrap();
+    assert_eq!(kernel.shadow_regions, [layout.region()]);
+
+    let log = vm.guest_log.clone();
+    vm.hook_address(0x100f, move |_, _| {
+        let summary = b"SUMMARY: AddressSanitizer: heap-buffer-overflow test.c:3 in main\n";
+        log.write(&LogOrigin::Stderr, summary);
+    });
+
+    vm.icount_limit = 100;
+    assert_eq!(vm.run(), VmExit::UnhandledException((ExceptionCode::SanitizerError, 0x2040)));
+    let report = asan.last_report().unwrap();
+    assert_eq!(report.bug_type.as_deref(), Some("heap-buffer-overflow"));
+    let access = AsanAccess { addr: 0x2040, size: 4, is_write: false, pc: 0x100a };
+    assert_eq!(report.access, Some(access));
+    assert!(report.summary.unwrap().contains("heap-buffer-overflow test.c:3 in main"));
+    assert_eq!(
+        asan.take_report().unwrap().to_string(),
+        "heap-buffer-overflow: READ of size 4 at 0x2040 (pc=0x100a)"
+    );
+    assert!(asan.last_report().is_none());
+}
+
+#[test]
+fn sanitizer_reports_cleared_on_restore() {
+    use crate::heap_sanitizer::HeapSanitizerOptions;
+
+    let mut vm = crate::build(&Config::from_target_triple("x86_64-none")).unwrap();
+    let options = HeapSanitizerOptions { heap_size: 0x10000, redzone: 16 };
+    let heap = crate::heap_sanitizer::attach(&mut vm, vec![], options).unwrap();
+    let snapshot = vm.snapshot();
+
+    let a = heap.alloc(&mut vm.cpu, 0x20).unwrap();
+    heap.free(&mut vm.cpu, a).unwrap();
+    assert_eq!(heap.free(&mut vm.cpu, a), None);
+    assert_eq!(vm.sanitizer_report().unwrap().bug_type.as_deref(), Some("double-free"));
+
+    // Errors detected before the snapshot was restored are not reported for later executions.
+    vm.restore(&snapshot);
+    assert!(vm.sanitizer_report().is_none());
+    assert_eq!(heap.free(&mut vm.cpu, 0x1234), None);
+    assert_eq!(vm.sanitizer_report().unwrap().bug_type.as_deref(), Some("bad-free"));
+}
+
+#[test]
+fn heap_sanitizer_interposes_allocator() {
+    use crate::heap_sanitizer::HeapSanitizerOptions;
+
+    static CODE: &[u8] = &[
+        // 0x1000: use after free
+        0xbf, 0x20, 0x00, 0x00, 0x00, // mov edi, 0x20
+        0xe8, 0xf6, 0x00, 0x00, 0x00, // call malloc
+        0x48, 0x89, 0xc3, // mov rbx, rax
+        0x48, 0x89, 0xc7, // mov rdi, rax
+        0xe8, 0xfb, 0x00, 0x00, 0x00, // call free
+        0x48, 0x8b, 0x03, // mov rax, qword ptr [rbx]
+        0x90, 0x90, 0x90, 0x90, 0x90, 0x90, 0x90, 0x90, // padding
+        // 0x1020: overflow of a reallocated buffer
+        0xbf, 0x02, 0x00, 0x00, 0x00, // mov edi, 2
+        0xbe, 0x10, 0x00, 0x00, 0x00, // mov esi, 0x10
+        0xe8, 0xf1, 0x00, 0x00, 0x00, // call calloc
+        0x48, 0x89, 0xc7, // mov rdi, rax
+        0xbe, 0x40, 0x00, 0x00, 0x00, // mov esi, 0x40
+        0xe8, 0xf4, 0x00, 0x00, 0x00, // call realloc
+        0x48, 0x8b, 0x40, 0x40, // mov rax, qword ptr [rax + 0x40]
+    ];
+    // The guest implementation of each allocator function just returns 0.
+    static STUB: &[u8] = &[0x31, 0xc0, 0xc3]; // xor eax, eax; ret
+
+    let mut vm = crate::build(&Config::from_target_triple("x86_64-none")).unwrap();
+    vm.cpu.mem.map_memory_len(0x1000, 0x200, Mapping { perm: perm::READ | perm::EXEC, value: 0 });
+    vm.cpu.mem.map_memory_len(0x8000, 0x1000, Mapping { perm: perm::READ | perm::WRITE, value: 0 });
+    vm.cpu.mem.write_bytes(0x1000, CODE, perm::NONE).unwrap();
+    let entries: Vec<_> = ["malloc", "free", "calloc", "realloc"]
+        .iter()
+        .enumerate()
+        .map(|(i, name)| (0x1100 + i as u64 * 0x10, name.to_string()))
+        .collect();
+    for (addr, _) in &entries {
+        vm.cpu.mem.write_bytes(*addr, STUB, perm::NONE).unwrap();
+    }
+    let options = HeapSanitizerOptions { heap_size: 0x10000, redzone: 16 };
+    let heap = crate::heap_sanitizer::attach(&mut vm, entries, options).unwrap();
+
+    let rsp = vm.cpu.arch.sleigh.get_varnode("RSP").unwrap();
+    vm.cpu.write_reg(rsp, 0x9000);
+    let snapshot = vm.snapshot();
+
+    let run = |vm: &mut crate::Vm, pc: u64| {
+        vm.restore(&snapshot);
+        vm.cpu.write_pc(pc);
+        let VmExit::UnhandledException((ExceptionCode::SanitizerError, addr)) = vm.run()
+        else {
+            panic!("expected a sanitizer error");
+        };
+        (addr, vm.sanitizer_report().unwrap().bug_type.unwrap())
+    };
+
+    let (addr, bug_type) = run(&mut vm, 0x1000);
+    assert!(heap.contains(addr));
+    assert_eq!(bug_type, "heap-use-after-free");
+    assert!(heap.live().is_empty());
+
+    // Restoring the snapshot resets the heap, so the same memory is reused.
+    assert_eq!(run(&mut vm, 0x1000), (addr, bug_type));
+
+    // `calloc` and `realloc` are also interposed: the original allocation is freed and only the
+    // new size is accessible.
+    let (addr, bug_type) = run(&mut vm, 0x1020);
+    assert_eq!(bug_type, "heap-buffer-overflow");
+    let live = heap.live();
+    assert_eq!(live.len(), 1);
+    assert_eq!((live[0].addr + live[0].size, live[0].size), (addr, 0x40));
+}
+
+#[test]
+fn coverage_export_formats() {
+    use crate::coverage_export::{CallEvent, CoverageExporter, CoverageFormat, call_trace};
+
+    static CODE: &[u8] = &[
+        0xe8, 0x0b, 0x00, 0x00, 0x00, // call 0x1020
+    ];
+    let mut vm = crate::build(&Config::from_target_triple("x86_64-none")).unwrap();
+    vm.cpu.mem.map_memory_len(0x1000, 0x100, Mapping { perm: perm::READ | perm::EXEC, value: 0 });
+    vm.cpu.mem.map_memory_len(0x8000, 0x1000, Mapping { perm: perm::READ | perm::WRITE, value: 0 });
+    vm.cpu.mem.write_bytes(0x1010, CODE, perm::NONE).unwrap();
+    vm.cpu.mem.write_bytes(0x1020, &[0xeb, 0xfe], perm::NONE).unwrap(); // jmp 0x1020
+    let rsp = vm.cpu.arch.sleigh.get_varnode("RSP").unwrap();
+    vm.cpu.write_reg(rsp, 0x9000);
+    vm.cpu.write_pc(0x1010);
+    vm.icount_limit = 3;
+    assert_eq!(vm.run(), VmExit::InstructionLimit);
+
+    let calls = call_trace(&vm, [(0x1010, 0), (0x1020, 1), (0x1020, 2)]);
+    assert_eq!(calls, [CallEvent { icount: 1, site: 0x1010, target: 0x1020 }]);
+
+    let mut exporter = CoverageExporter::new();
+    exporter.add_module("/bin/code.bin", 0x1000, 0x1100);
+    exporter.set_calls(calls);
+    let write = |format| {
+        let mut out = vec![];
+        exporter.write(&vm, format, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    };
+
+    assert_eq!(write(CoverageFormat::ModuleOffset), "code.bin+0x10\ncode.bin+0x20\n");
+    assert_eq!(write(CoverageFormat::Addresses), "0x1010\n0x1020\n");
+    assert_eq!(
+        write(CoverageFormat::Ezcov),
+        "EZCOV VERSION: 1\n0x00000010, 5, [ code.bin ]\n0x00000020, 2, [ code.bin ]\n"
+    );
+
+    let json = write("json".parse().unwrap());
+    assert!(json.contains("\"site_label\": \"code.bin+0x10\""));
+    assert!(json.contains("\"target_label\": \"code.bin+0x20\""));
+
+    let mut out = vec![];
+    exporter.write_call_trace(&mut out).unwrap();
+    assert_eq!(out, b"1: code.bin+0x10 -> code.bin+0x20\n");
+}
+
+#[test]
+fn unreachable_code_exits() {
+    let mut vm = crate::build(&Config::from_target_triple("x86_64-none")).unwrap();
+    vm.cpu.mem.map_memory_len(0x1000, 0x100, Mapping { perm: perm::READ | perm::EXEC, value: 0 });
+    // inc eax; inc eax; inc eax; jmp $
+    let code = [0xff, 0xc0, 0xff, 0xc0, 0xff, 0xc0, 0xeb, 0xfe];
+    vm.cpu.mem.write_bytes(0x1000, &code, perm::NONE).unwrap();
+    let eax = vm.cpu.arch.sleigh.get_varnode("EAX").unwrap();
+
+    let unreachable = crate::unreachable::attach(&mut vm);
+    unreachable.add_range(0x1002, 2);
+    assert!(unreachable.contains(0x1003));
+    assert!(!unreachable.contains(0x1004));
+
+    vm.cpu.write_pc(0x1000);
+    vm.icount_limit = 100;
+    assert_eq!(vm.run(), VmExit::Unreachable(0x1002));
+    assert_eq!(vm.cpu.read_reg(eax), 1);
+
+    // Removing the range only affects code lifted afterwards.
+    unreachable.remove_range(0x1002);
+    vm.invalidate_code_range(0x1000, 0x100);
+    vm.cpu.write_pc(0x1000);
+    assert_eq!(vm.run(), VmExit::InstructionLimit);
+    assert_eq!(vm.cpu.read_reg(eax), 4);
+}
+
+/// An environment that only provides debug info.
+struct DebugEnv(icicle_cpu::debug_info::DebugInfo);
+
+impl icicle_cpu::Environment for DebugEnv {
+    fn load(&mut self, _: &mut icicle_cpu::Cpu, _: &[u8]) -> Result<(), String> {
+        Err("unsupported".into())
+    }
+    fn handle_exception(&mut self, _: &mut icicle_cpu::Cpu) -> Option<VmExit> {
+        None
+    }
+    fn debug_info(&self) -> Option<&icicle_cpu::debug_info::DebugInfo> {
+        Some(&self.0)
+    }
+    fn snapshot(&mut self) -> Box<dyn std::any::Any> {
+        Box::new(())
+    }
+    fn restore(&mut self, _: &Box<dyn std::any::Any>) {}
+}
+
+#[test]
+fn cfi_backtrace() {
+    use icicle_cpu::debug_info::DebugInfo;
+    use object::write::{Object, Symbol, SymbolSection};
+
+    // A CIE with `CFA = rsp + 8` and the return address saved at `CFA - 8` (i.e. the state at the
+    // start of a function), and an FDE using it for all code in `0x1000..0x3000`.
+    #[rustfmt::skip]
+    static DEBUG_FRAME: &[u8] = &[
+        0x14, 0x00, 0x00, 0x00, 0xff, 0xff, 0xff, 0xff, // length, CIE id
+        0x01, 0x00, 0x01, 0x78, 0x10,                   // version, augmentation, alignment, ra
+        0x0c, 0x07, 0x08,                               // DW_CFA_def_cfa rsp+8
+        0x90, 0x01,                                     // DW_CFA_offset rip, -8
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00,             // padding
+        0x14, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // length, CIE pointer
+        0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // initial location
+        0x00, 0x20, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // address range
+    ];
+
+    let mut obj = Object::new(
+        object::BinaryFormat::Elf,
+        object::Architecture::X86_64,
+        object::Endianness::Little,
+    );
+    let text = obj.add_section(vec![], b".text".to_vec(), object::SectionKind::Text);
+    let debug_frame = obj.add_section(vec![], b".debug_frame".to_vec(), object::SectionKind::Debug);
+    obj.append_section_data(debug_frame, DEBUG_FRAME, 8);
+    for (name, value) in [("func", 0x1000), ("main", 0x2000)] {
+        obj.add_symbol(Symbol {
+            name: name.as_bytes().to_vec(),
+            value,
+            size: 0x100,
+            kind: object::SymbolKind::Text,
+            scope: object::SymbolScope::Linkage,
+            weak: false,
+            section: SymbolSection::Section(text),
+            flags: object::SymbolFlags::None,
+        });
+    }
+    let mut debug_info = DebugInfo::default();
+    debug_info.add_file(&obj.write().unwrap(), 0).unwrap();
+
+    let mut vm = crate::build(&Config::from_target_triple("x86_64-none")).unwrap();
+    vm.set_env(DebugEnv(debug_info));
+    vm.cpu.mem.map_memory_len(0x1000, 0x2000, Mapping { perm: perm::READ | perm::EXEC, value: 0 });
+    vm.cpu.mem.map_memory_len(0x7000, 0x2000, Mapping { perm: perm::READ | perm::WRITE, value: 0 });
+    // func: jmp $
+    vm.cpu.mem.write_bytes(0x1000, &[0xeb, 0xfe], perm::NONE).unwrap();
+    // main: call func; jmp $
+    let main = [0xe8, 0xfb, 0xef, 0xff, 0xff, 0xeb, 0xfe];
+    vm.cpu.mem.write_bytes(0x2000, &main, perm::NONE).unwrap();
+
+    let rsp = vm.cpu.arch.sleigh.get_varnode("RSP").unwrap();
+    vm.cpu.write_reg(rsp, 0x8000);
+    vm.cpu.write_pc(0x2000);
+    vm.icount_limit = 10;
+    assert_eq!(vm.run(), VmExit::InstructionLimit);
+
+    let frames = vm.backtrace();
+    let pcs: Vec<_> = frames.iter().map(|x| x.frame.pc).collect();
+    assert_eq!(pcs, [0x1000, 0x2005]);
+    let labels: Vec<_> = frames.iter().map(|x| x.location.as_ref().unwrap().label()).collect();
+    assert_eq!(labels, [Some("func".into()), Some("main+0x4".into())]);
+}
+
+#[test]
+fn stack_sanitizer_detects_overflow() {
+    use icicle_cpu::debug_info::DebugInfo;
+    use object::write::Object;
+
+    // Abbreviations for a compile unit, a function (with a frame base), a variable and a base type.
+    #[rustfmt::skip]
+    static DEBUG_ABBREV: &[u8] = &[
+        0x01, 0x11, 0x01, 0x11, 0x01, 0x12, 0x06, 0x00, 0x00,
+        0x02, 0x2e, 0x01, 0x03, 0x08, 0x11, 0x01, 0x12, 0x06, 0x40, 0x18, 0x00, 0x00,
+        0x03, 0x34, 0x00, 0x03, 0x08, 0x49, 0x13, 0x02, 0x18, 0x00, 0x00,
+        0x04, 0x24, 0x00, 0x03, 0x08, 0x0b, 0x0b, 0x3e, 0x0b, 0x00, 0x00,
+        0x00,
+    ];
+
+    // `func` at `0x1000..0x1020` with `DW_OP_reg6 (rbp)` as the frame base, and the variables
+    // `buf` (16 bytes at `fbreg -0x20`) and `x` (4 bytes at `fbreg -0x10`).
+    #[rustfmt::skip]
+    static DEBUG_INFO: &[u8] = &[
+        0x50, 0x00, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0x08, // header
+        0x01, 0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00,
+        0x02, b'f', b'u', b'n', b'c', 0x00, 0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x20, 0x00, 0x00, 0x00, 0x01, 0x56,
+        0x03, b'b', b'u', b'f', 0x00, 0x43, 0x00, 0x00, 0x00, 0x02, 0x91, 0x60,
+        0x03, b'x', 0x00, 0x4c, 0x00, 0x00, 0x00, 0x02, 0x91, 0x70,
+        0x00,
+        0x04, b'b', b'u', b'f', b'_', b't', 0x00, 0x10, 0x08,
+        0x04, b'i', b'n', b't', 0x00, 0x04, 0x05,
+        0x00,
+    ];
+
+    let mut obj = Object::new(
+        object::BinaryFormat::Elf,
+        object::Architecture::X86_64,
+        object::Endianness::Little,
+    );
+    for (name, data) in [(".debug_abbrev", DEBUG_ABBREV), (".debug_info", DEBUG_INFO)] {
+        let section =
+            obj.add_section(vec![], name.as_bytes().to_vec(), object::SectionKind::Debug);
+        obj.append_section_data(section, data, 1);
+    }
+    let mut debug_info = DebugInfo::default();
+    debug_info.add_file(&obj.write().unwrap(), 0).unwrap();
+
+    let mut vm = crate::build(&Config::from_target_triple("x86_64-none")).unwrap();
+    vm.set_env(DebugEnv(debug_info));
+    let sanitizer = crate::stack_sanitizer::attach(&mut vm).unwrap();
+
+    vm.cpu.mem.map_memory_len(0x1000, 0x100, Mapping { perm: perm::READ | perm::EXEC, value: 0 });
+    vm.cpu.mem.map_memory_len(0x7000, 0x1000, Mapping { perm: perm::READ | perm::WRITE, value: 0 });
+    #[rustfmt::skip]
+    let code = [
+        0x55,                         // push rbp
+        0x48, 0x89, 0xe5,             // mov rbp, rsp
+        0x31, 0xc9,                   // xor ecx, ecx
+        0xc6, 0x44, 0x0d, 0xe0, 0x41, // mov byte [rbp + rcx - 0x20], 0x41
+        0xff, 0xc1,                   // inc ecx
+        0x83, 0xf9, 0x14,             // cmp ecx, 0x14
+        0x75, 0xf4,                   // jne 0x1006
+        0xeb, 0xfe,                   // jmp $
+    ];
+    vm.cpu.mem.write_bytes(0x1000, &code, perm::NONE).unwrap();
+
+    let rsp = vm.cpu.arch.sleigh.get_varnode("RSP").unwrap();
+    let ecx = vm.cpu.arch.sleigh.get_varnode("ECX").unwrap();
+    vm.cpu.write_reg(rsp, 0x8000);
+    vm.cpu.write_pc(0x1000);
+    vm.icount_limit = 1000;
+
+    // `buf` is at `0x7fd8..0x7fe8`, the write of `buf[16]` overflows into `x`.
+    assert_eq!(vm.run(), VmExit::UnhandledException((ExceptionCode::StackBufferOverflow, 0x7fe8)));
+    assert_eq!(vm.cpu.read_reg(ecx), 0x10);
+    assert!(sanitizer.check_count() > 0);
+
+    let report = vm.sanitizer_report().unwrap();
+    assert_eq!(report.bug_type.as_deref(), Some("stack-buffer-overflow"));
+    assert_eq!(report.access.unwrap().pc, 0x1006);
+    let details = report.details.unwrap();
+    assert!(details.contains("0 bytes after 16-byte object `buf`"), "{details}");
+    assert!(details.contains("`func`"), "{details}");
+}
+
+#[test]
+fn function_hooks() {
+    let run = |hook: fn(&mut crate::function_hooks::FunctionCall)| {
+        let mut vm = crate::build(&Config::from_target_triple("x86_64-none")).unwrap();
+        let code = Mapping { perm: perm::READ | perm::EXEC, value: 0 };
+        vm.cpu.mem.map_memory_len(0x1000, 0x200, code);
+        let stack = Mapping { perm: perm::READ | perm::WRITE, value: 0 };
+        vm.cpu.mem.map_memory_len(0x7000, 0x1000, stack);
+        #[rustfmt::skip]
+        let code = [
+            0xbf, 0x05, 0x00, 0x00, 0x00, // mov edi, 5
+            0xbe, 0x07, 0x00, 0x00, 0x00, // mov esi, 7
+            0xe8, 0xf1, 0x00, 0x00, 0x00, // call 0x1100
+            0xeb, 0xfe,                   // jmp $
+        ];
+        vm.cpu.mem.write_bytes(0x1000, &code, perm::NONE).unwrap();
+        // lea eax, [rdi + rsi]; ret
+        vm.cpu.mem.write_bytes(0x1100, &[0x8d, 0x04, 0x37, 0xc3], perm::NONE).unwrap();
+        crate::function_hooks::hook_function(&mut vm, 0x1100, hook);
+
+        let rsp = vm.cpu.arch.sleigh.get_varnode("RSP").unwrap();
+        vm.cpu.write_reg(rsp, 0x8000);
+        vm.cpu.write_pc(0x1000);
+        vm.icount_limit = 20;
+        assert_eq!(vm.run(), VmExit::InstructionLimit);
+        assert_eq!(vm.cpu.read_pc(), 0x100f);
+        assert_eq!(vm.cpu.read_reg(rsp), 0x8000);
+        vm.cpu.read_reg(vm.cpu.arch.sleigh.get_varnode("RAX").unwrap())
+    };
+
+    // Observe the arguments without changing the behaviour of the function.
+    assert_eq!(
+        run(|call| {
+            assert_eq!((call.arg::<i32>(0), call.arg::<u64>(1)), (5, 7));
+            assert_eq!(call.return_addr(), Some(0x100f));
+        }),
+        12
+    );
+
+    // Modify an argument before the function executes.
+    assert_eq!(run(|call| call.set_arg(1, -2_i32).unwrap()), 3);
+
+    // Skip the function and return a different value to the caller.
+    let skip = |call: &mut crate::function_hooks::FunctionCall| {
+        let (a, b) = (call.arg::<u32>(0), call.arg::<u32>(1));
+        call.skip(a * b);
+    };
+    assert_eq!(run(skip), 35);
+}
+
+#[test]
+fn function_return_hooks() {
+    let mut vm = crate::build(&Config::from_target_triple("x86_64-none")).unwrap();
+    let code = Mapping { perm: perm::READ | perm::EXEC, value: 0 };
+    vm.cpu.mem.map_memory_len(0x1000, 0x200, code);
+    let stack = Mapping { perm: perm::READ | perm::WRITE, value: 0 };
+    vm.cpu.mem.map_memory_len(0x7000, 0x1000, stack);
+    #[rustfmt::skip]
+    let code = [
+        0xbf, 0x05, 0x00, 0x00, 0x00, // mov edi, 5
+        0xbe, 0x07, 0x00, 0x00, 0x00, // mov esi, 7
+        0xe8, 0xf1, 0x00, 0x00, 0x00, // call 0x1100
+        0xeb, 0xfe,                   // jmp $
+    ];
+    vm.cpu.mem.write_bytes(0x1000, &code, perm::NONE).unwrap();
+    // lea eax, [rdi + rsi]; ret
+    vm.cpu.mem.write_bytes(0x1100, &[0x8d, 0x04, 0x37, 0xc3], perm::NONE).unwrap();
+
+    let returns = std::rc::Rc::new(std::cell::RefCell::new(vec![]));
+    let returns_ = returns.clone();
+    crate::function_hooks::hook_function_return(&mut vm, 0x1100, move |ret| {
+        let value = ret.return_value::<u32>().unwrap();
+        returns_.borrow_mut().push((ret.addr, ret.return_addr, value));
+        ret.set_return_value(value * 10).unwrap();
+    });
+
+    let rsp = vm.cpu.arch.sleigh.get_varnode("RSP").unwrap();
+    vm.cpu.write_reg(rsp, 0x8000);
+    vm.cpu.write_pc(0x1000);
+    vm.icount_limit = 20;
+    assert_eq!(vm.run(), VmExit::InstructionLimit);
+    assert_eq!(vm.cpu.read_pc(), 0x100f);
+
+    // The hook sees the value returned by the original function, and can replace it.
+    assert_eq!(returns.borrow().as_slice(), &[(0x1100, 0x100f, 12)]);
+    assert_eq!(vm.cpu.read_reg(vm.cpu.arch.sleigh.get_varnode("RAX").unwrap()), 120);
+}
+
+#[test]
+fn provenance_checker_detects_adjacent_access() {
+    let mut vm = crate::build(&Config::from_target_triple("x86_64-none")).unwrap();
+    vm.cpu.mem.map_memory_len(0x1000, 0x100, Mapping { perm: perm::READ | perm::EXEC, value: 0 });
+
+    let mut heap = crate::libc_models::HeapModel::new(&mut vm.cpu, 0x10000).unwrap();
+    let a = heap.alloc(16).unwrap();
+    let b = heap.alloc(16).unwrap();
+    assert_eq!(b, a + 0x20);
+    let heap = std::rc::Rc::new(std::cell::RefCell::new(heap));
+    let checker = crate::provenance::attach(&mut vm, heap).unwrap();
+
+    // The pointer is passed in a register, since the lifter would constant fold `mov rdi, a`.
+    let code = [
+        0x8b, 0x47, 0x08, // mov eax, [rdi + 0x8]
+        0x8b, 0x47, 0x20, // mov eax, [rdi + 0x20]
+        0xeb, 0xfe, // jmp $
+    ];
+    vm.cpu.mem.write_bytes(0x1000, &code, perm::NONE).unwrap();
+    vm.cpu.write_reg(vm.cpu.arch.sleigh.get_varnode("RDI").unwrap(), a);
+    vm.cpu.write_pc(0x1000);
+    vm.icount_limit = 10;
+
+    // The first access is inside of `a`, the second is inside of `b` but derived from `a`.
+    assert_eq!(vm.run(), VmExit::UnhandledException((ExceptionCode::SanitizerError, b)));
+    assert_eq!(checker.check_count(), 2);
+
+    let report = vm.sanitizer_report().unwrap();
+    assert_eq!(report.bug_type.as_deref(), Some("heap-buffer-overflow"));
+    let access = report.access.unwrap();
+    assert_eq!((access.pc, access.size, access.is_write), (0x1003, 4, false));
+    let details = report.details.unwrap();
+    assert!(details.contains("16 bytes after 16-byte region"), "{details}");
+    assert!(details.contains(&format!("inside of 16-byte region [{b:#x},")), "{details}");
+}
+
+#[test]
+fn provenance_checker_uses_restored_heap_state() {
+    use crate::heap_sanitizer::HeapSanitizerOptions;
+
+    let mut vm = crate::build(&Config::from_target_triple("x86_64-none")).unwrap();
+    vm.cpu.mem.map_memory_len(0x1000, 0x100, Mapping { perm: perm::READ | perm::EXEC, value: 0 });
+    let options = HeapSanitizerOptions { heap_size: 0x10000, redzone: 16 };
+    let heap = crate::heap_sanitizer::attach(&mut vm, vec![], options).unwrap();
+    crate::provenance::attach(&mut vm, heap.clone()).unwrap();
+    let snapshot = vm.snapshot();
+
+    let a = heap.alloc(&mut vm.cpu, 0x10).unwrap();
+    let b = heap.alloc(&mut vm.cpu, 0x10).unwrap();
+    assert_eq!(b, a + 0x20);
+
+    let code = [0x8b, 0x47, 0x20]; // mov eax, [rdi + 0x20]
+    let rdi = vm.cpu.arch.sleigh.get_varnode("RDI").unwrap();
+    vm.cpu.mem.write_bytes(0x1000, &code, perm::NONE).unwrap();
+    vm.cpu.write_reg(rdi, a);
+    vm.cpu.write_pc(0x1000);
+    assert_eq!(vm.run(), VmExit::UnhandledException((ExceptionCode::SanitizerError, b)));
+    let report = vm.sanitizer_report().unwrap();
+    assert!(report.summary.unwrap().contains("ProvenanceChecker"));
+
+    // After restoring the snapshot neither allocation exists, so the access is reported by the heap
+    // sanitizer instead of by the provenance checker.
+    vm.restore(&snapshot);
+    assert!(vm.sanitizer_report().is_none());
+    vm.cpu.mem.write_bytes(0x1000, &code, perm::NONE).unwrap();
+    vm.cpu.write_reg(rdi, a);
+    vm.cpu.write_pc(0x1000);
+    assert_eq!(vm.run(), VmExit::UnhandledException((ExceptionCode::SanitizerError, b)));
+    let report = vm.sanitizer_report().unwrap();
+    assert!(report.summary.unwrap().contains("HeapSanitizer"));
+    assert_eq!(report.bug_type.as_deref(), Some("wild-heap-access"));
+}
+
+#[test]
+fn dump_memory_writes_regions_and_manifest() {
+    let mut vm = crate::build(&Config::from_target_triple("x86_64-none")).unwrap();
+    vm.cpu.mem.map_memory_len(0x1000, 0x1000, Mapping { perm: perm::READ | perm::EXEC, value: 0 });
+    vm.cpu.mem.write_bytes(0x1010, b"hello", perm::NONE).unwrap();
+    vm.cpu.mem.map_memory_len(0x4000, 0x2000, Mapping { perm: perm::READ | perm::WRITE, value: 0 });
+    vm.cpu.write_pc(0x1010);
+
+    let dir = std::env::temp_dir().join(format!("icicle-memory-dump-{}", std::process::id()));
+    vm.dump_memory(&dir).unwrap();
+
+    let manifest = crate::memory_dump::read_manifest(&dir).unwrap();
+    assert_eq!(manifest.triple, "x86_64-unknown-none");
+    assert_eq!((manifest.pointer_size, manifest.pc), (8, 0x1010));
+
+    let regions: Vec<_> = manifest
+        .regions
+        .iter()
+        .map(|x| (x.start, x.end, x.perm.as_str(), x.file.is_some()))
+        .collect();
+    assert_eq!(regions, [(0x1000, 0x2000, "r-x", true), (0x4000, 0x6000, "rw-", false)]);
+
+    let data = std::fs::read(dir.join(manifest.regions[0].file.as_ref().unwrap())).unwrap();
+    assert_eq!(data.len(), 0x1000);
+    assert_eq!(&data[0x10..0x15], b"hello");
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn memory_range_hooks_rewrite_values() {
+    let mut vm = crate::build(&Config::from_target_triple("x86_64-none")).unwrap();
+    vm.cpu.mem.map_memory_len(0x1000, 0x100, Mapping { perm: perm::READ | perm::EXEC, value: 0 });
+    vm.cpu.mem.map_memory_len(0x2000, 0x1000, Mapping { perm: perm::READ | perm::WRITE, value: 0 });
+    #[rustfmt::skip]
+    let code = [
+        0xc7, 0x07, 0x05, 0x00, 0x00, 0x00, // mov dword [rdi], 5
+        0x8b, 0x47, 0x04,                   // mov eax, [rdi + 4]
+        0x8b, 0x4f, 0x10,                   // mov ecx, [rdi + 0x10]
+        0xeb, 0xfe,                         // jmp $
+    ];
+    vm.cpu.mem.write_bytes(0x1000, &code, perm::NONE).unwrap();
+
+    let log = std::rc::Rc::new(std::cell::RefCell::new(vec![]));
+    let hook_log = log.clone();
+    let id = vm
+        .hook_memory_range(0x2000, 0x2008, move |access| {
+            let crate::memory_hooks::MemAccess { addr, size, value, pc, is_write } = *access;
+            hook_log.borrow_mut().push((pc, addr, size, value, is_write));
+            access.value = if is_write { value + 1 } else { 0x1234 };
+        })
+        .unwrap();
+
+    let rdi = vm.cpu.arch.sleigh.get_varnode("RDI").unwrap();
+    vm.cpu.write_reg(rdi, 0x2000);
+    vm.cpu.write_pc(0x1000);
+    vm.icount_limit = 10;
+    assert_eq!(vm.run(), VmExit::InstructionLimit);
+
+    // The access at `rdi + 0x10` is outside of the hooked range.
+    assert_eq!(*log.borrow(), [(0x1000, 0x2000, 4, 5, true), (0x1006, 0x2004, 4, 0, false)]);
+    assert_eq!(vm.cpu.mem.read_u32(0x2000, perm::NONE).unwrap(), 6);
+    assert_eq!(vm.cpu.read_reg(vm.cpu.arch.sleigh.get_varnode("RAX").unwrap()), 0x1234);
+    assert!(vm.remove_memory_range_hook(id));
+}
+
+#[test]
+fn wx_audit_reports_exec_from_writable_memory() {
+    use crate::wx_audit::{WxAuditOptions, WxEvent};
+
+    let run = |enforce: bool| {
+        let mut vm = crate::build(&Config::from_target_triple("x86_64-none")).unwrap();
+        let code = Mapping { perm: perm::READ | perm::EXEC, value: 0 };
+        vm.cpu.mem.map_memory_len(0x1000, 0x1000, code);
+        // jmp 0x2000
+        vm.cpu.mem.write_bytes(0x1000, &[0xe9, 0xfb, 0x0f, 0x00, 0x00], perm::NONE).unwrap();
+
+        let audit = crate::wx_audit::attach(&mut vm, WxAuditOptions { enforce });
+        let listened = std::rc::Rc::new(std::cell::Cell::new(0));
+        let counter = listened.clone();
+        audit.on_event(move |_| counter.set(counter.get() + 1));
+
+        // Code written to writable memory, which is later made executable.
+        let data = Mapping { perm: perm::READ | perm::WRITE, value: 0 };
+        vm.cpu.mem.map_memory_len(0x2000, 0x1000, data);
+        // mov eax, 1; jmp $
+        let jit_code = [0xb8, 0x01, 0x00, 0x00, 0x00, 0xeb, 0xfe];
+        vm.cpu.mem.write_bytes(0x2000, &jit_code, perm::NONE).unwrap();
+        vm.cpu.mem.update_perm(0x2000, 0x1000, perm::READ | perm::EXEC).unwrap();
+
+        let rwx = Mapping { perm: perm::READ | perm::WRITE | perm::EXEC, value: 0 };
+        vm.cpu.mem.map_memory_len(0x3000, 0x1000, rwx);
+
+        vm.cpu.write_pc(0x1000);
+        vm.icount_limit = 10;
+        let exit = vm.run();
+
+        assert!(!audit.was_writable(0x1000));
+        assert_eq!(audit.writable_ranges(), [(0x2000, 0x3fff)]);
+        let events = audit.take_events();
+        assert_eq!(listened.get(), events.len());
+        (exit, events)
+    };
+
+    let (exit, events) = run(false);
+    assert_eq!(exit, VmExit::InstructionLimit);
+    assert!(matches!(events[0], WxEvent::WritableExecutable { start: 0x3000, len: 0x1000, .. }));
+    // Each block executed from writable memory is reported once.
+    let exec = |addr| WxEvent::ExecFromWritable { addr };
+    assert_eq!(events[1..], [exec(0x2000), exec(0x2005)]);
+
+    let (exit, events) = run(true);
+    assert_eq!(exit, VmExit::UnhandledException((ExceptionCode::ExecViolation, 0x2000)));
+    assert_eq!(events.len(), 2);
+}
+
+#[test]
+fn wx_audit_invalidates_code_that_becomes_writable() {
+    use crate::wx_audit::{WxAuditOptions, WxEvent};
+
+    let mut vm = crate::build(&Config::from_target_triple("x86_64-none")).unwrap();
+    let code = Mapping { perm: perm::READ | perm::EXEC, value: 0 };
+    vm.cpu.mem.map_memory_len(0x1000, 0x1000, code);
+    // jmp $
+    vm.cpu.mem.write_bytes(0x1000, &[0xeb, 0xfe], perm::NONE).unwrap();
+    let audit = crate::wx_audit::attach(&mut vm, WxAuditOptions::default());
+
+    let run = |vm: &mut crate::Vm| {
+        vm.icount_limit = vm.cpu.icount() + 5;
+        assert_eq!(vm.run(), VmExit::InstructionLimit);
+        audit.take_events().into_iter().filter(|x| matches!(x, WxEvent::ExecFromWritable { .. }))
+    };
+
+    vm.cpu.write_pc(0x1000);
+    assert_eq!(run(&mut vm).count(), 0);
+    let snapshot = vm.snapshot();
+
+    // The code was lifted before the memory was writable, so must be lifted again to be reported.
+    let rwx = perm::READ | perm::WRITE | perm::EXEC;
+    vm.cpu.mem.update_perm(0x1000, 0x1000, rwx).unwrap();
+    assert_eq!(run(&mut vm).collect::<Vec<_>>(), [WxEvent::ExecFromWritable { addr: 0x1000 }]);
+
+    // The memory that was writable, and the code that was reported is restored with the VM.
+    vm.restore(&snapshot);
+    assert!(!audit.was_writable(0x1000));
+    assert_eq!(run(&mut vm).count(), 0);
+    vm.cpu.mem.update_perm(0x1000, 0x1000, rwx).unwrap();
+    assert_eq!(run(&mut vm).count(), 1);
+}
+
+#[test]
+fn register_write_hooks() {
+    let mut vm = crate::build(&Config::from_target_triple("x86_64-none")).unwrap();
+    let code = Mapping { perm: perm::READ | perm::EXEC, value: 0 };
+    vm.cpu.mem.map_memory_len(0x1000, 0x1000, code);
+    let instructions = [
+        0xb8, 0x00, 0x50, 0x00, 0x00, // mov eax, 0x5000
+        0x0f, 0x22, 0xd8, // mov cr3, rax
+        0xbb, 0x01, 0x00, 0x00, 0x00, // mov ebx, 1
+        0x0f, 0x22, 0xdb, // mov cr3, rbx
+        0xeb, 0xfe, // jmp $
+    ];
+    vm.cpu.mem.write_bytes(0x1000, &instructions, perm::NONE).unwrap();
+
+    let writes = std::rc::Rc::new(std::cell::RefCell::new(vec![]));
+    let cr3 = vm.cpu.arch.sleigh.get_varnode("CR3").unwrap();
+    let cr3_writes = writes.clone();
+    vm.hook_register_write(cr3, move |cpu, value| {
+        cr3_writes.borrow_mut().push(("CR3", cpu.read_pc(), value));
+    })
+    .unwrap();
+    let rax = vm.cpu.arch.sleigh.get_varnode("RAX").unwrap();
+    let rax_writes = writes.clone();
+    vm.hook_register_write(rax, move |cpu, value| {
+        rax_writes.borrow_mut().push(("RAX", cpu.read_pc(), value));
+    })
+    .unwrap();
+
+    vm.cpu.write_pc(0x1000);
+    vm.icount_limit = 10;
+    assert_eq!(vm.run(), VmExit::InstructionLimit);
+    assert_eq!(writes.borrow()[..], [
+        ("RAX", 0x1000, 0x5000),
+        ("CR3", 0x1005, 0x5000),
+        ("CR3", 0x100d, 1),
+    ]);
+}
+
+#[test]
+fn register_write_hooks_skip_predicated_writes() {
+    let mut vm = crate::build(&Config::from_target_triple("x86_64-none")).unwrap();
+    let code = Mapping { perm: perm::READ | perm::EXEC, value: 0 };
+    vm.cpu.mem.map_memory_len(0x1000, 0x1000, code);
+    let instructions = [
+        0xbb, 0x05, 0x00, 0x00, 0x00, // mov ebx, 5
+        0x83, 0xfb, 0x00, // cmp ebx, 0
+        0x48, 0x0f, 0x44, 0xc3, // cmovz rax, rbx (not taken)
+        0x83, 0xfb, 0x05, // cmp ebx, 5
+        0x48, 0x0f, 0x44, 0xc3, // cmovz rax, rbx (taken)
+        0xeb, 0xfe, // jmp $
+    ];
+    vm.cpu.mem.write_bytes(0x1000, &instructions, perm::NONE).unwrap();
+
+    let writes = std::rc::Rc::new(std::cell::RefCell::new(vec![]));
+    let rax = vm.cpu.arch.sleigh.get_varnode("RAX").unwrap();
+    let rax_writes = writes.clone();
+    let hook = vm
+        .hook_register_write(rax, move |cpu, value| {
+            rax_writes.borrow_mut().push((cpu.read_pc(), value));
+        })
+        .unwrap();
+
+    vm.cpu.write_pc(0x1000);
+    vm.icount_limit = 10;
+    assert_eq!(vm.run(), VmExit::InstructionLimit);
+    assert_eq!(writes.borrow()[..], [(0x100f, 5)]);
+
+    // Removing the hook must also remove it from code that was already translated.
+    assert!(vm.remove_register_write_hook(hook));
+    assert!(!vm.remove_register_write_hook(hook));
+    writes.borrow_mut().clear();
+    vm.cpu.write_pc(0x1000);
+    vm.icount_limit += 10;
+    assert_eq!(vm.run(), VmExit::InstructionLimit);
+    assert!(writes.borrow().is_empty());
+}
+
+/// An environment that records the register writes that it is notified of (and the instruction
+/// count at the time of the notification).
+#[derive(Default)]
+struct RegWriteEnv(Vec<(u64, u64)>);
+
+impl icicle_cpu::Environment for RegWriteEnv {
+    fn load(&mut self, _: &mut icicle_cpu::Cpu, _: &[u8]) -> Result<(), String> {
+        Err("unsupported".into())
+    }
+    fn handle_exception(&mut self, cpu: &mut icicle_cpu::Cpu) -> Option<VmExit> {
+        if ExceptionCode::from_u32(cpu.exception.code) != ExceptionCode::CpuStateChanged {
+            return None;
+        }
+        self.0.push((cpu.exception.value, cpu.icount()));
+        cpu.exception.clear();
+        None
+    }
+    fn snapshot(&mut self) -> Box<dyn std::any::Any> {
+        Box::new(())
+    }
+    fn restore(&mut self, _: &Box<dyn std::any::Any>) {}
+}
+
+#[test]
+fn register_write_notifies_env() {
+    let mut vm = crate::build(&Config::from_target_triple("x86_64-none")).unwrap();
+    let code = Mapping { perm: perm::READ | perm::EXEC, value: 0 };
+    vm.cpu.mem.map_memory_len(0x1000, 0x1000, code);
+    let instructions = [
+        0xb8, 0x00, 0x50, 0x00, 0x00, // mov eax, 0x5000
+        0x0f, 0x22, 0xd8, // mov cr3, rax
+        0xbb, 0x01, 0x00, 0x00, 0x00, // mov ebx, 1
+        0xeb, 0xfe, // jmp $
+    ];
+    vm.cpu.mem.write_bytes(0x1000, &instructions, perm::NONE).unwrap();
+    vm.set_env(RegWriteEnv::default());
+
+    let cr3 = vm.cpu.arch.sleigh.get_varnode("CR3").unwrap();
+    let rbx = vm.cpu.arch.sleigh.get_varnode("RBX").unwrap();
+    vm.notify_register_write(cr3).unwrap();
+
+    vm.cpu.write_pc(0x1000);
+    vm.icount_limit = 10;
+    assert_eq!(vm.run(), VmExit::InstructionLimit);
+    assert_eq!(vm.cpu.read_reg(rbx), 1);
+    let env = vm.env.as_any().downcast_ref::<RegWriteEnv>().unwrap();
+    assert_eq!(env.0.len(), 1);
+    assert_eq!(env.0[0].0, cr3.id as u64);
+}
+
+#[test]
+fn jit_regions_allow_generated_code() {
+    let run = |register: bool| {
+        let mut vm = crate::build(&Config::from_target_triple("x86_64-none")).unwrap();
+        let code = Mapping { perm: perm::READ | perm::EXEC, value: 0 };
+        vm.cpu.mem.map_memory_len(0x1000, 0x1000, code);
+        let instructions = [
+            0xe8, 0xfb, 0x0f, 0x00, 0x00, // call 0x2000
+            0x89, 0xc3, // mov ebx, eax
+            0xc6, 0x04, 0x25, 0x01, 0x20, 0x00, 0x00, 0x02, // mov byte ptr [0x2001], 2
+            0xbf, 0x00, 0x20, 0x00, 0x00, // mov edi, 0x2000
+            0xbe, 0x06, 0x20, 0x00, 0x00, // mov esi, 0x2006
+            0xe8, 0xe2, 0x00, 0x00, 0x00, // call 0x1100
+            0xe8, 0xdd, 0x0f, 0x00, 0x00, // call 0x2000
+            0xeb, 0xfe, // jmp $
+        ];
+        vm.cpu.mem.write_bytes(0x1000, &instructions, perm::NONE).unwrap();
+        // ret
+        vm.cpu.mem.write_bytes(0x1100, &[0xc3], perm::NONE).unwrap();
+
+        // Generated code: mov eax, 1; ret
+        let jit = Mapping { perm: perm::READ | perm::WRITE | perm::EXEC, value: 0 };
+        vm.cpu.mem.map_memory_len(0x2000, 0x1000, jit);
+        vm.cpu.mem.write_bytes(0x2000, &[0xb8, 0x01, 0x00, 0x00, 0x00, 0xc3], perm::NONE).unwrap();
+
+        let stack = Mapping { perm: perm::READ | perm::WRITE, value: 0 };
+        vm.cpu.mem.map_memory_len(0x8000, 0x1000, stack);
+        vm.cpu.write_reg(vm.cpu.arch.reg_sp, 0x8800);
+
+        if register {
+            assert_eq!(vm.register_jit_region(0x2000, 0x1000, &[0x2000]), 1);
+            crate::jit_regions::hook_notifier(&mut vm, 0x1100);
+        }
+
+        vm.cpu.write_pc(0x1000);
+        vm.icount_limit = 30;
+        (vm.run(), vm)
+    };
+
+    let (exit, _) = run(false);
+    assert!(
+        matches!(exit, VmExit::UnhandledException((ExceptionCode::SelfModifyingCode, _))),
+        "{exit:?}"
+    );
+
+    let (exit, mut vm) = run(true);
+    assert_eq!(exit, VmExit::InstructionLimit);
+    assert_eq!(vm.cpu.read_pc(), 0x1023);
+    let rax = vm.cpu.arch.sleigh.get_varnode("RAX").unwrap();
+    let rbx = vm.cpu.arch.sleigh.get_varnode("RBX").unwrap();
+    assert_eq!(vm.cpu.read_reg(rbx), 1);
+    // The second call executes the code generated by the guest.
+    assert_eq!(vm.cpu.read_reg(rax), 2);
+    assert!(vm.discovery.prelift_hits >= 1);
+    assert!(vm.jit_regions.is_empty());
+}
+
+#[test]
+fn icache_invalidation_follows_smc_policy() {
+    use icicle_cpu::SmcPolicy;
+
+    #[rustfmt::skip]
+    let code = [
+        0xef, 0x10, 0x00, 0x00, // jal ra, 0x2000
+        0x13, 0x04, 0x05, 0x00, // mv s0, a0
+        0xb7, 0x22, 0x00, 0x00, // lui t0, 0x2
+        0x37, 0x03, 0x20, 0x00, // lui t1, 0x200
+        0x13, 0x03, 0x33, 0x51, // addi t1, t1, 0x513
+        0x23, 0xa0, 0x62, 0x00, // sw t1, 0(t0) (replaces the first instruction with `li a0, 2`)
+        0x0f, 0x10, 0x00, 0x00, // fence.i
+        0xef, 0x00, 0x50, 0x7e, // jal ra, 0x2000
+        0x6f, 0x00, 0x00, 0x00, // j .
+    ];
+    let mut unmodified = code;
+    unmodified[20..24].copy_from_slice(&[0x13, 0x00, 0x00, 0x00]); // nop
+
+    let run = |smc_policy, code: &[u8]| {
+        let triple = "riscv64-none".parse().unwrap();
+        let mut vm = crate::build(&Config { triple, smc_policy, ..Config::default() }).unwrap();
+        let rwx = Mapping { perm: perm::READ | perm::WRITE | perm::EXEC, value: 0 };
+        vm.cpu.mem.map_memory_len(0x1000, 0x2000, rwx);
+        vm.cpu.mem.write_bytes(0x1000, code, perm::NONE).unwrap();
+        // li a0, 1; ret
+        let func = [0x13, 0x05, 0x10, 0x00, 0x67, 0x80, 0x00, 0x00];
+        vm.cpu.mem.write_bytes(0x2000, &func, perm::NONE).unwrap();
+        vm.cpu.write_pc(0x1000);
+        vm.icount_limit = 20;
+        (vm.run(), vm)
+    };
+    let reg = |vm: &mut crate::Vm, name: &str| {
+        let var = vm.cpu.arch.sleigh.get_varnode(name).unwrap();
+        vm.cpu.read_reg(var)
+    };
+
+    // By default, invalidating the instruction cache without modifying code has no effect, and
+    // does not end the block.
+    let (exit, mut vm) = run(SmcPolicy::Exit, &unmodified);
+    assert_eq!(exit, VmExit::InstructionLimit);
+    assert_eq!(vm.cpu.read_pc(), 0x1020);
+    assert_eq!((reg(&mut vm, "s0"), reg(&mut vm, "a0")), (1, 1));
+    let has_exception = vm.code.blocks.iter().any(|block| {
+        block.pcode.instructions.iter().any(|inst| matches!(inst.op, pcode::Op::Exception))
+    });
+    assert!(!has_exception);
+
+    // Only modifying translated code causes an exit.
+    let (exit, _) = run(SmcPolicy::Exit, &code);
+    assert!(
+        matches!(exit, VmExit::UnhandledException((ExceptionCode::SelfModifyingCode, _))),
+        "{exit:?}"
+    );
+
+    // The invalidation causes the modified code to be translated again.
+    let (exit, mut vm) = run(SmcPolicy::InvalidateOnHint, &code);
+    assert_eq!(exit, VmExit::InstructionLimit);
+    assert_eq!(vm.cpu.read_pc(), 0x1020);
+    assert_eq!((reg(&mut vm, "s0"), reg(&mut vm, "a0")), (1, 2));
+}
+
+#[test]
+fn x86_cpuid_and_msr_handlers() {
+    use icicle_cpu::{
+        Cpu, X86Config,
+        exec::helpers::x86::{MsrHandler, msr},
+    };
+
+    struct TestMsr;
+
+    impl MsrHandler for TestMsr {
+        fn rdmsr(&mut self, _: &mut Cpu, msr: u32) -> Option<u64> {
+            (msr == 0x123).then_some(0xaaaa_bbbb_cccc_dddd)
+        }
+
+        fn wrmsr(&mut self, _: &mut Cpu, _: u32, _: u64) -> bool {
+            false
+        }
+    }
+
+    let mut vm = crate::build(&Config {
+        x86: X86Config { vendor: *b"AuthenticAMD", ..X86Config::default() },
+        ..Config::from_target_triple("x86_64-none")
+    })
+    .unwrap();
+    let code = Mapping { perm: perm::READ | perm::EXEC, value: 0 };
+    vm.cpu.mem.map_memory_len(0x1000, 0x1000, code);
+    let instructions = [
+        0xb8, 0x00, 0x00, 0x00, 0x00, // mov eax, 0
+        0x0f, 0xa2, // cpuid
+        0x41, 0x89, 0xd8, // mov r8d, ebx
+        0xb8, 0x08, 0x00, 0x00, 0x80, // mov eax, 0x80000008
+        0x0f, 0xa2, // cpuid
+        0x41, 0x89, 0xc1, // mov r9d, eax
+        0xb9, 0x00, 0x01, 0x00, 0xc0, // mov ecx, IA32_FS_BASE
+        0xb8, 0x34, 0x12, 0x00, 0x00, // mov eax, 0x1234
+        0x31, 0xd2, // xor edx, edx
+        0x0f, 0x30, // wrmsr
+        0xb9, 0x23, 0x01, 0x00, 0x00, // mov ecx, 0x123
+        0x0f, 0x32, // rdmsr
+        0x41, 0x89, 0xc2, // mov r10d, eax
+        0x41, 0x89, 0xd3, // mov r11d, edx
+        0xb9, 0x56, 0x04, 0x00, 0x00, // mov ecx, 0x456
+        0x0f, 0x32, // rdmsr
+    ];
+    vm.cpu.mem.write_bytes(0x1000, &instructions, perm::NONE).unwrap();
+
+    let model = vm.cpu.x86.as_mut().unwrap();
+    model.add_cpuid_handler(Box::new(|_: &mut Cpu, leaf: u32, _: u32| {
+        (leaf == 0x8000_0008).then_some([0x3030, 0, 0, 0])
+    }));
+    model.add_msr_handler(Box::new(TestMsr));
+
+    vm.cpu.write_pc(0x1000);
+    assert_eq!(vm.run(), VmExit::UnhandledException((ExceptionCode::UnknownMsr, 0x456)));
+
+    let reg = |vm: &mut crate::Vm, name: &str| {
+        let var = vm.cpu.arch.sleigh.get_varnode(name).unwrap();
+        vm.cpu.read_reg(var)
+    };
+    assert_eq!(reg(&mut vm, "R8"), u32::from_le_bytes(*b"Auth") as u64);
+    assert_eq!(reg(&mut vm, "R9"), 0x3030);
+    assert_eq!(reg(&mut vm, "FS_OFFSET"), 0x1234);
+    assert_eq!(reg(&mut vm, "R10"), 0xcccc_dddd);
+    assert_eq!(reg(&mut vm, "R11"), 0xaaaa_bbbb);
+
+    let mut model = vm.cpu.x86.take().unwrap();
+    assert_eq!(model.rdmsr(&mut vm.cpu, msr::IA32_FS_BASE), Some(0x1234));
+}
+
+#[test]
+fn x86_default_cpuid_leaves() {
+    use icicle_cpu::{X86Config, exec::helpers::x86::X86Model};
+
+    let mut config = X86Config::default();
+    assert_ne!(config.tsc_scale, 0);
+    config.cpuid.insert((0x8000_0008, None), [0x3030, 0, 0, 0]);
+    let model = X86Model::new(config);
+
+    // The highest basic leaf is the structured extended feature leaf, and the highest extended
+    // leaf includes the leaves overridden in the config.
+    assert_eq!(model.default_cpuid(0x0, 0).unwrap()[0], 0x7);
+    assert_eq!(model.default_cpuid(0x8000_0000, 0).unwrap()[0], 0x8000_0008);
+
+    // SSE3 is reported in ECX.
+    let [_, _, ecx, edx] = model.default_cpuid(0x1, 0).unwrap();
+    assert_eq!((ecx & 1, edx), (1, 0));
+}
+
+#[test]
+fn x86_reported_cpuid_leaves_are_handled() {
+    let mut vm = crate::build(&Config::from_target_triple("x86_64-none")).unwrap();
+    vm.cpu.mem.map_memory_len(0x1000, 0x1000, Mapping { perm: perm::READ | perm::EXEC, value: 0 });
+    vm.cpu.mem.write_bytes(0x1000, &[0x0f, 0xa2], perm::NONE).unwrap(); // cpuid
+
+    let rax = vm.cpu.arch.sleigh.get_varnode("RAX").unwrap();
+    let rcx = vm.cpu.arch.sleigh.get_varnode("RCX").unwrap();
+    let cpuid = |vm: &mut crate::Vm, leaf: u32| {
+        vm.cpu.write_reg(rax, leaf as u64);
+        vm.cpu.write_reg(rcx, 0);
+        vm.cpu.write_pc(0x1000);
+        assert_eq!(vm.step(1), VmExit::InstructionLimit, "cpuid leaf {leaf:#x}");
+        vm.cpu.read_reg(rax) as u32
+    };
+
+    // Every leaf up to the highest basic and extended leaf must be handled (e.g. glibc queries
+    // leaves 2 and 4 for cache information).
+    let max_basic = cpuid(&mut vm, 0x0);
+    for leaf in 0x1..=max_basic {
+        cpuid(&mut vm, leaf);
+    }
+    let max_extended = cpuid(&mut vm, 0x8000_0000);
+    for leaf in 0x8000_0001..=max_extended {
+        cpuid(&mut vm, leaf);
+    }
+}
+
+/// Creates a VM running the Linux environment, with `code` mapped at 0x1000 and a read-write
+/// region at 0x2000..0x4000 (used for data and stacks).
+fn linux_vm(config: &crate::linux::KernelConfig, code: &[u8]) -> crate::Vm {
+    let mut vm = crate::build(&Config::from_target_triple("x86_64-linux")).unwrap();
+    let kernel = crate::linux::Kernel::new(&vm.cpu.arch, config);
+    vm.set_env(kernel);
+
+    vm.cpu.mem.map_memory_len(0x1000, 0x1000, Mapping { perm: perm::READ | perm::EXEC, value: 0 });
+    vm.cpu.mem.map_memory_len(0x2000, 0x2000, Mapping {
+        perm: perm::READ | perm::WRITE | perm::INIT,
+        value: 0,
+    });
+    vm.cpu.mem.write_bytes(0x1000, code, perm::NONE).unwrap();
+    let rsp = vm.cpu.arch.sleigh.get_varnode("RSP").unwrap();
+    vm.cpu.write_reg(rsp, 0x3f00);
+    vm.cpu.write_pc(0x1000);
+    vm
+}
+
+#[test]
+fn linux_threads_are_preempted_and_restored() {
+    static CODE: &[u8] = &[
+        0xb8, 0x38, 0x00, 0x00, 0x00, // mov eax, SYS_clone
+        0xbf, 0x00, 0x0f, 0x05, 0x00, // mov edi, CLONE_VM|FS|FILES|SIGHAND|THREAD|SYSVSEM
+        0xbe, 0x00, 0x30, 0x00, 0x00, // mov esi, 0x3000
+        0x31, 0xd2, // xor edx, edx
+        0x45, 0x31, 0xd2, // xor r10d, r10d
+        0x45, 0x31, 0xc0, // xor r8d, r8d
+        0x0f, 0x05, // syscall
+        0x85, 0xc0, // test eax, eax
+        0x74, 0x0a, // jz child
+        // parent:
+        0x48, 0xff, 0x04, 0x25, 0x00, 0x20, 0x00, 0x00, // inc qword ptr [0x2000]
+        0xeb, 0xf6, // jmp parent
+        // child:
+        0x48, 0xff, 0x04, 0x25, 0x08, 0x20, 0x00, 0x00, // inc qword ptr [0x2008]
+        0xeb, 0xf6, // jmp child
+    ];
+    let config = crate::linux::KernelConfig { thread_quantum: 100, ..Default::default() };
+    let mut vm = linux_vm(&config, CODE);
+    let counters = |vm: &mut crate::Vm| {
+        let parent = vm.cpu.mem.read_u64(0x2000, perm::NONE).unwrap();
+        let child = vm.cpu.mem.read_u64(0x2008, perm::NONE).unwrap();
+        (parent, child)
+    };
+
+    // Both threads make progress, even though neither of them ever yields.
+    vm.icount_limit = 2000;
+    assert_eq!(vm.run(), VmExit::InstructionLimit);
+    let (parent, child) = counters(&mut vm);
+    assert!(parent > 0 && child > 0, "parent={parent}, child={child}");
+
+    let kernel = vm.env_ref::<crate::linux::Kernel>().unwrap();
+    let (pid, parked) = (kernel.process.pid, kernel.process_manager.parked.len());
+    assert_eq!(parked, 1);
+
+    // Restoring a snapshot restores the parked thread and the time slice of the active thread, so
+    // execution after the snapshot is repeatable.
+    let snapshot = vm.snapshot();
+    vm.icount_limit = 3000;
+    assert_eq!(vm.run(), VmExit::InstructionLimit);
+    let expected = counters(&mut vm);
+
+    vm.restore(&snapshot);
+    let kernel = vm.env_ref::<crate::linux::Kernel>().unwrap();
+    assert_eq!((kernel.process.pid, kernel.process_manager.parked.len()), (pid, parked));
+
+    // Threads created with `CLONE_FILES | CLONE_SIGHAND` still share their file table and signal
+    // handlers after the snapshot is restored.
+    let thread = &kernel.process_manager.parked[0].process;
+    assert!(kernel.process.file_table.ptr_eq(&thread.file_table));
+    assert!(kernel.process.signal_handlers.ptr_eq(&thread.signal_handlers));
+    assert!(kernel.process.mmap_regions.ptr_eq(&thread.mmap_regions));
+
+    assert_eq!(vm.run(), VmExit::InstructionLimit);
+    assert_eq!(counters(&mut vm), expected);
+}
+
+#[test]
+fn linux_strace_labels_syscalls_that_switch_process() {
+    use crate::linux::sys::strace::{SyscallTracer, TraceFormat};
+
+    static CODE: &[u8] = &[
+        0xb8, 0x38, 0x00, 0x00, 0x00, // mov eax, SYS_clone
+        0xbf, 0x00, 0x0f, 0x05, 0x00, // mov edi, CLONE_VM|FS|FILES|SIGHAND|THREAD|SYSVSEM
+        0xbe, 0x00, 0x30, 0x00, 0x00, // mov esi, 0x3000
+        0x31, 0xd2, // xor edx, edx
+        0x45, 0x31, 0xd2, // xor r10d, r10d
+        0x45, 0x31, 0xc0, // xor r8d, r8d
+        0x0f, 0x05, // syscall
+        0x85, 0xc0, // test eax, eax
+        0x74, 0x0a, // jz child
+        // parent:
+        0x48, 0xff, 0x04, 0x25, 0x00, 0x20, 0x00, 0x00, // inc qword ptr [0x2000]
+        0xeb, 0xf6, // jmp parent
+        // child:
+        0xb8, 0x3c, 0x00, 0x00, 0x00, // mov eax, SYS_exit
+        0x31, 0xff, // xor edi, edi
+        0x0f, 0x05, // syscall
+        0xeb, 0xfe, // jmp $
+    ];
+    let run = |format| {
+        let config = crate::linux::KernelConfig { thread_quantum: 100, ..Default::default() };
+        let mut vm = linux_vm(&config, CODE);
+        let out = SharedWriter::default();
+        let kernel = vm.env_mut::<crate::linux::Kernel>().unwrap();
+        kernel.syscall_tracer = Some(SyscallTracer::new(format, out.clone()));
+        let parent = kernel.process.pid;
+
+        vm.icount_limit = 1000;
+        assert_eq!(vm.run(), VmExit::InstructionLimit);
+        let output = out.0.borrow().clone();
+        (parent, String::from_utf8(output).unwrap())
+    };
+
+    // The exit is attributed to the thread that exited, not the thread that is resumed.
+    let (parent, trace) = run(TraceFormat::Text);
+    let lines: Vec<_> = trace.lines().collect();
+    assert_eq!(lines.len(), 2, "{trace}");
+    let (clone, child) = lines[0].rsplit_once(" = ").unwrap();
+    assert!(clone.starts_with(&format!("[pid {parent}] clone(")), "{trace}");
+    assert_eq!(lines[1], format!("[pid {child}] exit(0) = 0"));
+
+    let (_, trace) = run(TraceFormat::Json);
+    let entries: Vec<serde_json::Value> =
+        trace.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+    assert_eq!(entries.len(), 2, "{trace}");
+    assert_eq!(entries[0]["syscall"], "clone");
+    assert_eq!(entries[0]["pid"], parent);
+    assert_eq!(entries[0]["nr"], 56);
+    assert_eq!(entries[1]["syscall"], "exit");
+    assert_eq!(entries[1]["pid"].to_string(), child);
+    assert_eq!(entries[1]["args"], serde_json::json!(["0"]));
+    assert_eq!(entries[1]["ret"], 0);
+}
+
+#[test]
+fn process_tree_records_ancestry() {
+    use crate::linux::{
+        TerminationReason,
+        process_tree::{ProcessState, ProcessTree},
+    };
+
+    let mut tree = ProcessTree::default();
+    tree.reset(100, b"/bin/target");
+    tree.fork(100, 101, false);
+    tree.fork(100, 102, true);
+    tree.fork(101, 103, false);
+    tree.exec(103, b"/bin/sh");
+    tree.exec(103, b"/usr/bin/crash");
+
+    let node = tree.get(103).unwrap();
+    assert_eq!((node.parent_pid, node.exec_count), (101, 2));
+    assert_eq!(node.image, b"/usr/bin/crash");
+    assert_eq!(tree.get(101).unwrap().image, b"/bin/target");
+    assert_eq!(tree.get(100).unwrap().children, [101, 102]);
+    assert!(tree.get(102).unwrap().is_thread);
+
+    // Processes are kept after they terminate.
+    tree.set_state(101, ProcessState::Terminated(TerminationReason::Exit(0)));
+    tree.set_state(102, ProcessState::Abandoned);
+    assert_eq!(tree.running(), 2);
+    assert_eq!(tree.iter().map(|node| node.pid).collect::<Vec<_>>(), [100, 101, 102, 103]);
+
+    let ancestors: Vec<_> = tree.ancestors(103).iter().map(|node| node.pid).collect();
+    assert_eq!(ancestors, [103, 101, 100]);
+    assert_eq!(tree.describe(103), "crash[103] <- target[101] <- target[100]");
+    assert_eq!(tree.describe(999), "[999]");
+
+    // Pid reuse can create a cycle, which must not cause `ancestors` to loop forever.
+    tree.fork(103, 100, false);
+    assert!(tree.ancestors(101).len() <= 5);
+
+    tree.reset(200, b"/bin/other");
+    assert_eq!(tree.iter().count(), 1);
+    assert_eq!(tree.describe(200), "other[200]");
+}
+
+#[test]
+fn linux_process_tree_tracks_clone_and_exit() {
+    use crate::linux::{TerminationReason, process_tree::ProcessState};
+
+    static CODE: &[u8] = &[
+        0xb8, 0x38, 0x00, 0x00, 0x00, // mov eax, SYS_clone
+        0xbf, 0x00, 0x0f, 0x05, 0x00, // mov edi, CLONE_VM|FS|FILES|SIGHAND|THREAD|SYSVSEM
+        0xbe, 0x00, 0x30, 0x00, 0x00, // mov esi, 0x3000
+        0x31, 0xd2, // xor edx, edx
+        0x45, 0x31, 0xd2, // xor r10d, r10d
+        0x45, 0x31, 0xc0, // xor r8d, r8d
+        0x0f, 0x05, // syscall
+        0x85, 0xc0, // test eax, eax
+        0x74, 0x0a, // jz child
+        // parent:
+        0x48, 0xff, 0x04, 0x25, 0x00, 0x20, 0x00, 0x00, // inc qword ptr [0x2000]
+        0xeb, 0xf6, // jmp parent
+        // child:
+        0xb8, 0x3c, 0x00, 0x00, 0x00, // mov eax, SYS_exit
+        0xbf, 0x07, 0x00, 0x00, 0x00, // mov edi, 7
+        0x0f, 0x05, // syscall
+        0xeb, 0xfe, // jmp $
+    ];
+    let config = crate::linux::KernelConfig { thread_quantum: 100, ..Default::default() };
+    let mut vm = linux_vm(&config, CODE);
+    let kernel = vm.env_mut::<crate::linux::Kernel>().unwrap();
+    let parent = kernel.process.pid;
+    kernel.process_tree.reset(parent, b"/bin/target");
+
+    vm.icount_limit = 1000;
+    assert_eq!(vm.run(), VmExit::InstructionLimit);
+
+    let kernel = vm.env_ref::<crate::linux::Kernel>().unwrap();
+    let tree = &kernel.process_tree;
+    let children = &tree.get(parent).unwrap().children;
+    assert_eq!(children.len(), 1);
+    let child = tree.get(children[0]).unwrap();
+    assert_eq!(child.parent_pid, parent);
+    assert!(child.is_thread);
+    assert_eq!(child.image, b"/bin/target");
+    assert_eq!(child.state, ProcessState::Terminated(TerminationReason::Exit(7)));
+    assert_eq!(tree.get(parent).unwrap().state, ProcessState::Running);
+    assert_eq!(tree.running(), 1);
+}
+
+#[test]
+fn linux_exec_resets_registers_and_keeps_open_files() {
+    const BASE: u32 = 0x40_0000;
+    const CODE: u32 = BASE + 64 + 56;
+    const PATH: u32 = CODE + 70;
+    const ARGV: u32 = CODE + 80;
+
+    let imm = |op: &[u8], value: u32| [op, &value.to_le_bytes()].concat();
+    let mut code = [
+        &[0x48, 0x8b, 0x04, 0x24][..], // mov rax, [rsp] (argc)
+        &[0x48, 0x85, 0xc0],           // test rax, rax
+        &[0x75, 0x3b],                 // jnz done
+        &imm(&[0x41, 0xbc], 0x1234),   // mov r12d, 0x1234
+        &imm(&[0xb8], 2),              // mov eax, SYS_open
+        &imm(&[0xbf], PATH),           // mov edi, PATH
+        &[0x31, 0xf6],                 // xor esi, esi (O_RDONLY)
+        &[0x0f, 0x05],                 // syscall
+        &imm(&[0xb8], 2),              // mov eax, SYS_open
+        &imm(&[0xbf], PATH),           // mov edi, PATH
+        &imm(&[0xbe], 0x80000),        // mov esi, O_CLOEXEC
+        &[0x0f, 0x05],                 // syscall
+        &imm(&[0xb8], 59),             // mov eax, SYS_execve
+        &imm(&[0xbf], PATH),           // mov edi, PATH
+        &imm(&[0xbe], ARGV),           // mov esi, ARGV
+        &imm(&[0xba], ARGV + 8),       // mov edx, ARGV + 8 (empty envp)
+        &[0x0f, 0x05],                 // syscall
+        &[0xeb, 0xfe],                 // done: jmp done
+    ]
+    .concat();
+    assert_eq!(code.len(), 70);
+    code.extend_from_slice(b"/prog\0\0\0\0\0");
+    code.extend_from_slice(&(PATH as u64).to_le_bytes());
+    code.extend_from_slice(&0_u64.to_le_bytes());
+
+    // A static executable with a single segment containing the headers and the code.
+    let mut elf = vec![0x7f, b'E', b'L', b'F', 2, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    elf.extend_from_slice(&2_u16.to_le_bytes()); // e_type = ET_EXEC
+    elf.extend_from_slice(&62_u16.to_le_bytes()); // e_machine = EM_X86_64
+    elf.extend_from_slice(&1_u32.to_le_bytes()); // e_version
+    for value in [CODE as u64, 64, 0] {
+        // e_entry, e_phoff, e_shoff
+        elf.extend_from_slice(&value.to_le_bytes());
+    }
+    elf.extend_from_slice(&0_u32.to_le_bytes()); // e_flags
+    for value in [64_u16, 56, 1, 64, 0, 0] {
+        // e_ehsize, e_phentsize, e_phnum, e_shentsize, e_shnum, e_shstrndx
+        elf.extend_from_slice(&value.to_le_bytes());
+    }
+    let len = (64 + 56 + code.len()) as u64;
+    elf.extend_from_slice(&1_u32.to_le_bytes()); // p_type = PT_LOAD
+    elf.extend_from_slice(&5_u32.to_le_bytes()); // p_flags = PF_R | PF_X
+    for value in [0, BASE as u64, BASE as u64, len, len, 0x1000] {
+        // p_offset, p_vaddr, p_paddr, p_filesz, p_memsz, p_align
+        elf.extend_from_slice(&value.to_le_bytes());
+    }
+    elf.extend_from_slice(&code);
+
+    let sysroot = std::env::temp_dir().join(format!("icicle-exec-{}", std::process::id()));
+    std::fs::create_dir_all(&sysroot).unwrap();
+    std::fs::write(sysroot.join("prog"), &elf).unwrap();
+
+    let mut vm = crate::build(&Config::from_target_triple("x86_64-linux")).unwrap();
+    let mut kernel =
+        crate::linux::Kernel::new(&vm.cpu.arch, &crate::linux::KernelConfig::default());
+    kernel.init_vfs(sysroot.clone()).unwrap();
+    kernel.set_env(&[], &[]);
+    vm.set_env(kernel);
+    vm.env.load(&mut vm.cpu, b"/prog").unwrap();
+    std::fs::remove_dir_all(&sysroot).unwrap();
+
+    vm.icount_limit = 100;
+    assert_eq!(vm.run(), VmExit::InstructionLimit);
+
+    // The new image starts with a fresh register state.
+    let r12 = vm.cpu.arch.sleigh.get_varnode("R12").unwrap();
+    assert_eq!(vm.cpu.read_reg(r12), 0);
+
+    // The standard streams and files that are not close-on-exec stay open.
+    let kernel = vm.env_ref::<crate::linux::Kernel>().unwrap();
+    let file_table = kernel.process.file_table.borrow();
+    let files = &file_table.files;
+    let open: Vec<_> = (0..5).map(|fd| files.get(fd).is_some_and(|file| file.is_some())).collect();
+    assert_eq!(open, [true, true, true, true, false]);
+
+    // The exec is recorded in the process tree.
+    let node = kernel.process_tree.get(kernel.process.pid).unwrap();
+    assert_eq!((node.image.as_slice(), node.exec_count), (&b"/prog"[..], 1));
+}
+
+#[test]
+fn linux_futex_rejects_invalid_timeout() {
+    static CODE: &[u8] = &[
+        0xb8, 0xca, 0x00, 0x00, 0x00, // mov eax, SYS_futex
+        0xbf, 0x00, 0x20, 0x00, 0x00, // mov edi, 0x2000
+        0x31, 0xf6, // xor esi, esi (FUTEX_WAIT)
+        0x31, 0xd2, // xor edx, edx
+        0x41, 0xba, 0x10, 0x20, 0x00, 0x00, // mov r10d, 0x2010
+        0x0f, 0x05, // syscall
+        0xeb, 0xfe, // jmp $
+    ];
+    let mut vm = linux_vm(&crate::linux::KernelConfig::default(), CODE);
+    let rax = vm.cpu.arch.sleigh.get_varnode("RAX").unwrap();
+    let snapshot = vm.snapshot();
+
+    // Timeouts with a negative `tv_sec` or with `tv_nsec` out of range.
+    for (secs, nanos) in [(0, 2_000_000_000), (-1_i64 as u64, 0), (0, -1_i64 as u64)] {
+        vm.restore(&snapshot);
+        vm.cpu.mem.write_u64(0x2010, secs, perm::NONE).unwrap();
+        vm.cpu.mem.write_u64(0x2018, nanos, perm::NONE).unwrap();
+        vm.icount_limit = vm.cpu.icount + 20;
+        assert_eq!(vm.run(), VmExit::InstructionLimit);
+        assert_eq!(vm.cpu.read_reg(rax) as i64, -22, "secs={secs:#x}, nanos={nanos:#x}");
+    }
+}
+
+#[test]
+fn linux_syscall_hooks() {
+    static CODE: &[u8] = &[
+        0xb8, 0x03, 0x00, 0x00, 0x00, // mov eax, SYS_close
+        0xbf, 0x64, 0x00, 0x00, 0x00, // mov edi, 100
+        0x0f, 0x05, // syscall
+        0xb8, 0x27, 0x00, 0x00, 0x00, // mov eax, SYS_getpid
+        0x0f, 0x05, // syscall
+        0x48, 0x89, 0x04, 0x25, 0x00, 0x20, 0x00, 0x00, // mov qword ptr [0x2000], rax
+        0xb8, 0x27, 0x00, 0x00, 0x00, // mov eax, SYS_getpid
+        0x0f, 0x05, // syscall
+        0x48, 0x89, 0x04, 0x25, 0x08, 0x20, 0x00, 0x00, // mov qword ptr [0x2008], rax
+        0xeb, 0xfe, // jmp $
+    ];
+    let mut vm = linux_vm(&crate::linux::KernelConfig::default(), CODE);
+    let kernel = vm.env_mut::<crate::linux::Kernel>().unwrap();
+
+    // Modify the arguments passed to the built-in handler.
+    assert!(kernel.hook_syscall_by_name("close", |ctx| {
+        ctx.args[0] = 200;
+        crate::linux::sys::hook::SyscallAction::Continue
+    }));
+
+    // A hook that only runs once.
+    assert!(kernel.hook_syscall_by_name("getpid", |ctx| {
+        assert!(ctx.kernel.remove_syscall_hook(ctx.id));
+        crate::linux::sys::hook::SyscallAction::Return(Ok(1234))
+    }));
+
+    vm.icount_limit = 20;
+    assert_eq!(vm.run(), VmExit::InstructionLimit);
+    let pid = vm.env_ref::<crate::linux::Kernel>().unwrap().process.tgid;
+    assert_eq!(vm.cpu.mem.read_u64(0x2000, perm::NONE).unwrap(), 1234);
+    assert_eq!(vm.cpu.mem.read_u64(0x2008, perm::NONE).unwrap(), pid);
+
+    let kernel = vm.env_mut::<crate::linux::Kernel>().unwrap();
+    assert!(kernel.syscall_hooks.contains_key(&kernel.syscall_id("close").unwrap()));
+    assert!(!kernel.syscall_hooks.contains_key(&kernel.syscall_id("getpid").unwrap()));
+
+    // The history contains the arguments seen by the handler and the result of each syscall.
+    let context = kernel.crash_context();
+    assert_eq!(context.pid, kernel.process.pid);
+    assert_eq!(context.cwd, b"/");
+    let syscalls: Vec<_> = context.syscalls.iter().map(|x| (x.name, x.result)).collect();
+    assert_eq!(syscalls.len(), 3);
+    assert_eq!(context.syscalls[0].args()[0], 200);
+    assert!(matches!(syscalls[0], ("close", Some(Err(crate::linux::LinuxError::Error(_))))));
+    assert!(matches!(syscalls[1], ("getpid", Some(Ok(1234)))));
+    assert!(matches!(syscalls[2], ("getpid", Some(Ok(x))) if x == pid));
+
+    let report = context.to_string();
+    assert!(report.contains("recent syscalls:"), "{report}");
+    assert!(report.contains("getpid([]) = 0x4d2"), "{report}");
+}
+
+#[test]
+fn linux_syscall_history_is_bounded() {
+    let mut history = crate::linux::crash_context::SyscallHistory::new(2);
+    for i in 0..3 {
+        history.push("read", &[i, 0x1000, 1, 2, 3, 4, 5, 6], i);
+        history.set_result(Ok(i));
+    }
+    history.push("write", &[1], 3);
+
+    // Only the most recent syscalls are kept, with the arguments truncated to the maximum.
+    let records: Vec<_> = history.iter().map(|x| (x.name, x.args().len(), x.result)).collect();
+    assert!(matches!(records[..], [("read", 6, Some(Ok(2))), ("write", 1, None)]));
+
+    // A history with a capacity of zero is disabled.
+    let mut history = crate::linux::crash_context::SyscallHistory::new(0);
+    history.push("read", &[0], 0);
+    assert_eq!(history.iter().count(), 0);
+}
+
+#[test]
+fn linux_tls_init_and_get_addr() {
+    use icicle_cpu::elf::{TlsImage, TlsLayout};
+
+    static CODE: &[u8] = &[
+        0xbf, 0x00, 0x21, 0x00, 0x00, // mov edi, 0x2100
+        0xe8, 0xf6, 0x3f, 0x00, 0x00, // call __tls_get_addr
+        0x48, 0x89, 0x04, 0x25, 0x00, 0x22, 0x00, 0x00, // mov qword ptr [0x2200], rax
+        0xeb, 0xfe, // jmp $
+    ];
+    let mut vm = linux_vm(&crate::linux::KernelConfig::default(), CODE);
+
+    // The TLS area is initialized from the image, with the rest of the block zeroed.
+    let image = TlsImage { addr: 0x2000, file_size: 0x8, mem_size: 0x14, align: 0x8 };
+    vm.cpu.mem.write_u64(0x2000, 0x1122_3344_5566_7788, perm::NONE).unwrap();
+    vm.cpu.mem.fill_mem(0x3000, 0x1000, 0xaa).unwrap();
+    let tls = TlsLayout::new(object::elf::EM_X86_64, 8, &[image]).unwrap();
+    let tp = tls.init(&mut vm.cpu, 0x3000).unwrap();
+    assert_eq!(tp, 0x3018);
+    assert_eq!(vm.cpu.mem.read_u64(0x3000, perm::NONE).unwrap(), 0x1122_3344_5566_7788);
+    assert_eq!(vm.cpu.mem.read_u64(0x3008, perm::NONE).unwrap(), 0);
+    assert_eq!(vm.cpu.mem.read_u32(0x3010, perm::NONE).unwrap(), 0);
+    // The TCB starts with a pointer to itself.
+    assert_eq!(vm.cpu.mem.read_u64(tp, perm::NONE).unwrap(), tp);
+    assert_eq!(vm.cpu.mem.read_u64(tp + 16, perm::NONE).unwrap(), tp);
+
+    // Calls to the (non-executable) `__tls_get_addr` stub return the address of the variable
+    // described by the `tls_index` argument.
+    vm.cpu.mem.map_memory_len(0x5000, 0x1000, Mapping { perm: perm::READ, value: 0 });
+    vm.cpu.mem.write_u64(0x2100, 1, perm::NONE).unwrap();
+    vm.cpu.mem.write_u64(0x2108, 0x4, perm::NONE).unwrap();
+    let kernel = vm.env_mut::<crate::linux::Kernel>().unwrap();
+    kernel.process.image.tls_get_addr = 0x5000;
+    kernel.process.tls = Some(tls);
+    let reg_tls = kernel.arch.reg_tls.unwrap();
+    vm.cpu.write_reg(reg_tls, tp);
+
+    vm.icount_limit = 10;
+    assert_eq!(vm.run(), VmExit::InstructionLimit);
+    assert_eq!(vm.cpu.read_pc(), 0x1012);
+    assert_eq!(vm.cpu.mem.read_u64(0x2200, perm::NONE).unwrap(), 0x3004);
+    assert_eq!(vm.cpu.read_reg(vm.cpu.arch.sleigh.get_varnode("RSP").unwrap()), 0x3f00);
+}
+
+#[test]
+fn linux_rlimits() {
+    static CODE: &[u8] = &[
+        0xb8, 0x61, 0x00, 0x00, 0x00, // mov eax, SYS_getrlimit
+        0xbf, 0x07, 0x00, 0x00, 0x00, // mov edi, RLIMIT_NOFILE
+        0xbe, 0x00, 0x20, 0x00, 0x00, // mov esi, 0x2000
+        0x0f, 0x05, // syscall
+        0x48, 0x89, 0x04, 0x25, 0x00, 0x21, 0x00, 0x00, // mov qword ptr [0x2100], rax
+        0xb8, 0xa0, 0x00, 0x00, 0x00, // mov eax, SYS_setrlimit
+        0xbf, 0x07, 0x00, 0x00, 0x00, // mov edi, RLIMIT_NOFILE
+        0xbe, 0x10, 0x20, 0x00, 0x00, // mov esi, 0x2010
+        0x0f, 0x05, // syscall
+        0x48, 0x89, 0x04, 0x25, 0x08, 0x21, 0x00, 0x00, // mov qword ptr [0x2108], rax
+        0xb8, 0x2e, 0x01, 0x00, 0x00, // mov eax, SYS_prlimit64
+        0x31, 0xff, // xor edi, edi
+        0xbe, 0x07, 0x00, 0x00, 0x00, // mov esi, RLIMIT_NOFILE
+        0xba, 0x20, 0x20, 0x00, 0x00, // mov edx, 0x2020
+        0x45, 0x31, 0xd2, // xor r10d, r10d
+        0x0f, 0x05, // syscall
+        0x48, 0x89, 0x04, 0x25, 0x10, 0x21, 0x00, 0x00, // mov qword ptr [0x2110], rax
+        0xb8, 0x2e, 0x01, 0x00, 0x00, // mov eax, SYS_prlimit64
+        0x31, 0xff, // xor edi, edi
+        0xbe, 0x07, 0x00, 0x00, 0x00, // mov esi, RLIMIT_NOFILE
+        0xba, 0x30, 0x20, 0x00, 0x00, // mov edx, 0x2030
+        0x45, 0x31, 0xd2, // xor r10d, r10d
+        0x0f, 0x05, // syscall
+        0x48, 0x89, 0x04, 0x25, 0x18, 0x21, 0x00, 0x00, // mov qword ptr [0x2118], rax
+        0xb8, 0x2e, 0x01, 0x00, 0x00, // mov eax, SYS_prlimit64
+        0x31, 0xff, // xor edi, edi
+        0xbe, 0x07, 0x00, 0x00, 0x00, // mov esi, RLIMIT_NOFILE
+        0x31, 0xd2, // xor edx, edx
+        0x41, 0xba, 0x40, 0x20, 0x00, 0x00, // mov r10d, 0x2040
+        0x0f, 0x05, // syscall
+        0x48, 0x89, 0x04, 0x25, 0x20, 0x21, 0x00, 0x00, // mov qword ptr [0x2120], rax
+        0xeb, 0xfe, // jmp $
+    ];
+    // The default file limit is finite, even for file tables created outside of a kernel.
+    assert_eq!(crate::linux::fs::FileTable::default().max_files, 1024);
+
+    let mut vm = linux_vm(&crate::linux::KernelConfig::default(), CODE);
+    let mut write_limit = |addr: u64, cur: u64, max: u64| {
+        vm.cpu.mem.write_u64(addr, cur, perm::NONE).unwrap();
+        vm.cpu.mem.write_u64(addr + 8, max, perm::NONE).unwrap();
+    };
+    // Lower the soft limit independently of the hard limit.
+    write_limit(0x2010, 16, 32);
+    // Soft limit above the hard limit.
+    write_limit(0x2020, 64, 16);
+    // Raising the hard limit.
+    write_limit(0x2030, 8, 64);
+
+    vm.icount_limit = 100;
+    assert_eq!(vm.run(), VmExit::InstructionLimit);
+
+    let read = |vm: &mut crate::Vm, addr: u64| vm.cpu.mem.read_u64(addr, perm::NONE).unwrap();
+    let results: Vec<_> = (0..5).map(|i| read(&mut vm, 0x2100 + i * 8) as i64).collect();
+    assert_eq!(results, [0, 0, -22, -1, 0]);
+    assert_eq!((read(&mut vm, 0x2000), read(&mut vm, 0x2008)), (1024, 1024));
+    assert_eq!((read(&mut vm, 0x2040), read(&mut vm, 0x2048)), (16, 32));
+
+    // The soft limit is the one enforced by the file table.
+    let kernel = vm.env_ref::<crate::linux::Kernel>().unwrap();
+    assert_eq!(kernel.process.rlimits.nofile, crate::linux::RLimit { cur: 16, max: 32 });
+    assert_eq!(kernel.process.file_table.borrow().max_files, 16);
+}
+
+#[test]
+fn linux_riscv32_syscall_table() {
+    let syscall_names = |triple: &str, ids: &[u64]| {
+        let mut vm = crate::build(&Config::from_target_triple(triple)).unwrap();
+        let kernel =
+            crate::linux::Kernel::new(&vm.cpu.arch, &crate::linux::KernelConfig::default());
+        let a7 = vm.cpu.arch.sleigh.get_varnode("a7").unwrap();
+        ids.iter()
+            .map(|id| {
+                vm.cpu.write_reg(a7, *id);
+                kernel.arch.get_syscall_name(&mut *vm.cpu)
+            })
+            .collect::<Vec<_>>()
+    };
+
+    // RV32 passes 64-bit file offsets in register pairs, and maps files using page offsets.
+    assert_eq!(
+        syscall_names("riscv32gc-linux", &[62, 222, 422]),
+        ["llseek", "mmap2", "futex_time64"]
+    );
+    assert_eq!(syscall_names("riscv64-linux", &[62, 222, 98]), ["lseek", "mmap", "futex"]);
+
+    // Syscalls using 32-bit time values do not exist on RV32.
+    assert_eq!(syscall_names("riscv32gc-linux", &[98, 113]), ["unknown", "unknown"]);
+}
+
+#[test]
+fn linux_mips_sigprocmask() {
+    static CODE: &[u32] = &[
+        0x24021063, // addiu v0, zero, SYS_rt_sigprocmask
+        0x24040001, // addiu a0, zero, SIG_BLOCK (MIPS numbers `how` from 1)
+        0x24052000, // addiu a1, zero, 0x2000
+        0x24062010, // addiu a2, zero, 0x2010
+        0x24070010, // addiu a3, zero, 16
+        0x0000000c, // syscall
+        0x1000ffff, // b .
+        0x00000000, // nop
+    ];
+    let mut vm = crate::build(&Config::from_target_triple("mips-linux")).unwrap();
+    let kernel = crate::linux::Kernel::new(&vm.cpu.arch, &crate::linux::KernelConfig::default());
+    vm.set_env(kernel);
+
+    vm.cpu.mem.map_memory_len(0x1000, 0x1000, Mapping { perm: perm::READ | perm::EXEC, value: 0 });
+    vm.cpu.mem.map_memory_len(0x2000, 0x1000, Mapping { perm: perm::READ | perm::WRITE, value: 0 });
+    let code: Vec<u8> = CODE.iter().flat_map(|x| x.to_be_bytes()).collect();
+    vm.cpu.mem.write_bytes(0x1000, &code, perm::NONE).unwrap();
+    vm.cpu.write_pc(0x1000);
+
+    // The set is made up of big-endian 32-bit words, with signals 1..=32 in the first word.
+    let kernel = vm.env_mut::<crate::linux::Kernel>().unwrap();
+    let signals = kernel.arch.signals;
+    assert_eq!((signals.sigbus, signals.sigchld, signals.sigstop), (10, 18, 23));
+    kernel.process.signal_mask = 1 << 32 | 1;
+    let set = (1_u32 << (signals.sigbus - 1)) | (1 << (signals.sigstop - 1));
+    let words = [set.to_be_bytes(), 2_u32.to_be_bytes()].concat();
+    vm.cpu.mem.write_bytes(0x2000, &words, perm::NONE).unwrap();
+
+    vm.icount_limit = 20;
+    assert_eq!(vm.run(), VmExit::InstructionLimit);
+
+    // `SIGSTOP` cannot be blocked.
+    let kernel = vm.env_ref::<crate::linux::Kernel>().unwrap();
+    assert_eq!(kernel.process.signal_mask, 0b11 << 32 | 1 << (signals.sigbus - 1) | 1);
+
+    let mut oldset = [0; 8];
+    vm.cpu.mem.read_bytes(0x2010, &mut oldset, perm::NONE).unwrap();
+    assert_eq!(oldset, [0, 0, 0, 1, 0, 0, 0, 1]);
+}
+
+fn avr_vm(path: &std::path::Path) -> crate::Vm {
+    let mut vm = crate::build(&Config::from_target_triple("avr-none")).unwrap();
+    vm.set_env(crate::avr::Avr::new(crate::avr::Config::default()));
+    let path = path.to_str().unwrap().as_bytes().to_vec();
+    vm.env.load(&mut vm.cpu, &path).unwrap();
+    vm
+}
+
+#[test]
+fn avr_ihex_loader_and_decode() {
+    use crate::avr::data_addr;
+
+    static CODE: &[u8] = &[
+        0x02, 0xe4, // ldi r16, 0x42
+        0x00, 0x93, 0x00, 0x01, // sts 0x0100, r16
+        0xff, 0xcf, // rjmp .-2
+    ];
+    let records =
+        [ihex::Record::Data { offset: 0x0, value: CODE.to_vec() }, ihex::Record::EndOfFile];
+    let path = std::env::temp_dir().join(format!("icicle-avr-{}.hex", std::process::id()));
+    std::fs::write(&path, ihex::create_object_file_representation(&records).unwrap()).unwrap();
+    let mut vm = avr_vm(&path);
+    std::fs::remove_file(&path).unwrap();
+
+    // The PC is in words, while flash is stored as bytes.
+    assert_eq!(vm.cpu.read_pc(), 0);
+    assert_eq!(vm.step(2), VmExit::InstructionLimit);
+    assert_eq!(vm.cpu.read_pc(), 3);
+    assert_eq!(vm.cpu.mem.read_u8(data_addr(0x100), perm::NONE).unwrap(), 0x42);
+    assert_eq!(vm.cpu.mem.read_u8(CODE.len() as u64, perm::NONE).unwrap(), 0xff);
+
+    // Invalidating the bytes of the first instruction removes the translation at word 0.
+    assert!(vm.code.map.keys().any(|key| key.vaddr == 0));
+    vm.invalidate_memory_range(0, 2);
+    assert!(!vm.code.map.keys().any(|key| key.vaddr == 0));
+}
+
+#[test]
+fn avr_elf_loader() {
+    use crate::avr::{ELF_DATA_BASE, ELF_EEPROM_BASE, data_addr, eeprom_addr};
+
+    static CODE: &[u8] = &[
+        0xff, 0xff, // (skipped)
+        0x00, 0x91, 0x00, 0x01, // lds r16, 0x0100
+        0x00, 0x93, 0x01, 0x01, // sts 0x0101, r16
+    ];
+    let segments: [(u64, &[u8]); 3] =
+        [(0, CODE), (ELF_DATA_BASE + 0x100, &[0x5a]), (ELF_EEPROM_BASE, &[0x77])];
+
+    // A minimal ELF file with one program header for each segment and an entry point of byte 2.
+    let mut elf = vec![0x7f, b'E', b'L', b'F', 1, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    elf.extend_from_slice(&2_u16.to_le_bytes()); // e_type = ET_EXEC
+    elf.extend_from_slice(&83_u16.to_le_bytes()); // e_machine = EM_AVR
+    for value in [1_u32, 2, 52, 0, 0] {
+        // e_version, e_entry, e_phoff, e_shoff, e_flags
+        elf.extend_from_slice(&value.to_le_bytes());
+    }
+    for value in [52_u16, 32, segments.len() as u16, 40, 0, 0] {
+        // e_ehsize, e_phentsize, e_phnum, e_shentsize, e_shnum, e_shstrndx
+        elf.extend_from_slice(&value.to_le_bytes());
+    }
+    let mut offset = 52 + 32 * segments.len() as u32;
+    for (addr, data) in segments {
+        let len = data.len() as u32;
+        for value in [1, offset, addr as u32, addr as u32, len, len, 7, 1] {
+            elf.extend_from_slice(&value.to_le_bytes());
+        }
+        offset += len;
+    }
+    segments.iter().for_each(|(_, data)| elf.extend_from_slice(data));
+
+    let path = std::env::temp_dir().join(format!("icicle-avr-{}.elf", std::process::id()));
+    std::fs::write(&path, &elf).unwrap();
+    let mut vm = avr_vm(&path);
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(vm.cpu.read_pc(), 1);
+    assert_eq!(vm.cpu.mem.read_u8(eeprom_addr(0), perm::NONE).unwrap(), 0x77);
+    assert_eq!(vm.step(2), VmExit::InstructionLimit);
+    assert_eq!(vm.cpu.read_pc(), 5);
+    assert_eq!(vm.cpu.mem.read_u8(data_addr(0x101), perm::NONE).unwrap(), 0x5a);
+}
+
+#[test]
+fn pe_unresolved_imports_are_stubbed() {
+    use icicle_cpu::pe::resolve_api_set;
+
+    assert_eq!(resolve_api_set("API-MS-WIN-CRT-runtime-l1-1-0.dll"), Some("ucrtbase.dll"));
+    assert_eq!(resolve_api_set("api-ms-win-core-synch-l1-2-0.dll"), Some("kernelbase.dll"));
+    assert_eq!(resolve_api_set("kernel32.dll"), None);
+
+    const BASE: u64 = 0x1_4000_0000;
+    const TEXT: u32 = 0x1000;
+    const IMPORTS: u32 = TEXT + 0x100;
+    const ILT: u32 = TEXT + 0x140;
+    const IAT: u32 = TEXT + 0x160;
+    const HINT_NAME: u32 = TEXT + 0x180;
+    const DLL_NAME: u32 = TEXT + 0x1a0;
+
+    // A single section containing the code and the import table.
+    let mut text = vec![0; 0x200];
+    let code = [
+        &[0xb9, 0x11, 0x00, 0x00, 0x00][..],   // mov ecx, 0x11
+        &[0xba, 0x22, 0x00, 0x00, 0x00],       // mov edx, 0x22
+        &[0xff, 0x15, 0x50, 0x01, 0x00, 0x00], // call qword ptr [rip + 0x150] (IAT[0])
+        &[0xeb, 0xfe],                         // jmp $
+    ]
+    .concat();
+    text[..code.len()].copy_from_slice(&code);
+    let mut put = |rva: u32, bytes: &[u8]| {
+        let offset = (rva - TEXT) as usize;
+        text[offset..offset + bytes.len()].copy_from_slice(bytes);
+    };
+    // OriginalFirstThunk, TimeDateStamp, ForwarderChain, Name, FirstThunk
+    let descriptor: Vec<u8> =
+        [ILT, 0, 0, DLL_NAME, IAT].iter().flat_map(|x| x.to_le_bytes()).collect();
+    put(IMPORTS, &descriptor);
+    // Import by name (`Sleep`) and by ordinal (#7).
+    let thunks: Vec<u8> =
+        [HINT_NAME as u64, (1 << 63) | 7, 0].iter().flat_map(|x| x.to_le_bytes()).collect();
+    put(ILT, &thunks);
+    put(IAT, &thunks);
+    put(HINT_NAME, b"\0\0Sleep\0");
+    put(DLL_NAME, b"api-ms-win-core-synch-l1-2-0.dll\0");
+
+    let mut pe = vec![0; 0x40];
+    pe[..2].copy_from_slice(b"MZ");
+    pe[0x3c..0x40].copy_from_slice(&0x40_u32.to_le_bytes()); // e_lfanew
+    pe.extend_from_slice(b"PE\0\0");
+    pe.extend_from_slice(&0x8664_u16.to_le_bytes()); // Machine = IMAGE_FILE_MACHINE_AMD64
+    pe.extend_from_slice(&1_u16.to_le_bytes()); // NumberOfSections
+    pe.extend_from_slice(&[0; 12]); // TimeDateStamp, PointerToSymbolTable, NumberOfSymbols
+    pe.extend_from_slice(&0xf0_u16.to_le_bytes()); // SizeOfOptionalHeader
+    pe.extend_from_slice(&0x22_u16.to_le_bytes()); // Characteristics
+    pe.extend_from_slice(&0x20b_u16.to_le_bytes()); // Magic = PE32+
+    pe.extend_from_slice(&[0; 14]); // Linker version, SizeOfCode, SizeOf(Un)InitializedData
+    pe.extend_from_slice(&TEXT.to_le_bytes()); // AddressOfEntryPoint
+    pe.extend_from_slice(&TEXT.to_le_bytes()); // BaseOfCode
+    pe.extend_from_slice(&BASE.to_le_bytes()); // ImageBase
+    for value in [0x1000_u32, 0x200] {
+        // SectionAlignment, FileAlignment
+        pe.extend_from_slice(&value.to_le_bytes());
+    }
+    for value in [6_u16, 0, 0, 0, 6, 0] {
+        // Operating system, image and subsystem versions
+        pe.extend_from_slice(&value.to_le_bytes());
+    }
+    for value in [0_u32, 0x2000, 0x200, 0] {
+        // Win32VersionValue, SizeOfImage, SizeOfHeaders, CheckSum
+        pe.extend_from_slice(&value.to_le_bytes());
+    }
+    pe.extend_from_slice(&3_u16.to_le_bytes()); // Subsystem = IMAGE_SUBSYSTEM_WINDOWS_CUI
+    pe.extend_from_slice(&0_u16.to_le_bytes()); // DllCharacteristics
+    pe.extend_from_slice(&[0; 32]); // Stack and heap sizes
+    pe.extend_from_slice(&0_u32.to_le_bytes()); // LoaderFlags
+    pe.extend_from_slice(&16_u32.to_le_bytes()); // NumberOfRvaAndSizes
+    for i in 0..16 {
+        // Data directories
+        let (rva, size) = if i == 1 { (IMPORTS, 40_u32) } else { (0, 0) };
+        pe.extend_from_slice(&rva.to_le_bytes());
+        pe.extend_from_slice(&size.to_le_bytes());
+    }
+    pe.extend_from_slice(b".text\0\0\0");
+    for value in [0x1000, TEXT, 0x200, 0x200, 0, 0, 0] {
+        // VirtualSize, VirtualAddress, SizeOfRawData, PointerToRawData, PointerToRelocations,
+        // PointerToLinenumbers, NumberOfRelocations and NumberOfLinenumbers
+        pe.extend_from_slice(&value.to_le_bytes());
+    }
+    pe.extend_from_slice(&0xe000_0020_u32.to_le_bytes()); // CODE | EXECUTE | READ | WRITE
+    pe.resize(0x200, 0);
+    pe.extend_from_slice(&text);
+
+    let path = std::env::temp_dir().join(format!("icicle-pe-{}.exe", std::process::id()));
+    std::fs::write(&path, &pe).unwrap();
+    let mut vm = crate::build(&Config::from_target_triple("x86_64-none")).unwrap();
+    vm.set_env(crate::env::GenericEmbedded::new());
+    let result = vm.env.load(&mut vm.cpu, path.to_str().unwrap().as_bytes());
+    std::fs::remove_file(&path).unwrap();
+    result.unwrap();
+
+    let env = vm.env_ref::<crate::env::GenericEmbedded>().unwrap();
+    let stubs: Vec<_> = env.import_stubs().stubs.clone();
+    let names: Vec<_> = stubs.iter().map(|x| (x.dll.as_str(), x.name.as_str())).collect();
+    assert_eq!(names, [("kernelbase.dll", "Sleep"), ("kernelbase.dll", "#7")]);
+    for (i, stub) in stubs.iter().enumerate() {
+        let entry = BASE + IAT as u64 + i as u64 * 8;
+        assert_eq!(vm.cpu.mem.read_u64(entry, perm::NONE).unwrap(), stub.addr);
+    }
+
+    // Calling an unresolved import exits with the details of the call.
+    let stack = Mapping { perm: perm::READ | perm::WRITE, value: 0 };
+    vm.cpu.mem.map_memory_len(0x10000, 0x1000, stack);
+    let rsp = vm.cpu.arch.sleigh.get_varnode("RSP").unwrap();
+    vm.cpu.write_reg(rsp, 0x10800);
+    vm.icount_limit = 10;
+    let exit = vm.run();
+    assert_eq!(exit, VmExit::UnhandledException((ExceptionCode::UnimplementedApi, stubs[0].addr)));
+
+    let env = vm.env_ref::<crate::env::GenericEmbedded>().unwrap();
+    let call = env.last_unimplemented_call().unwrap();
+    assert_eq!((call.dll.as_str(), call.name.as_str()), ("kernelbase.dll", "Sleep"));
+    assert_eq!(call.args[..2], [0x11, 0x22]);
+    assert_eq!(call.return_addr, BASE + TEXT as u64 + 0x10);
+    let message = call.to_string();
+    assert!(message.starts_with("unimplemented API called: kernelbase.dll!Sleep(0x11, 0x22, "));
+    assert!(message.ends_with(") from 0x140001010"), "{message}");
+}
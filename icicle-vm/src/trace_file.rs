@@ -0,0 +1,318 @@
+//! A compressed, seekable file format for block traces.
+//!
+//! A trace is a sequence of `(addr, icount)` entries, recording the address of each block executed
+//! and the instruction count at the start of the block. Entries are grouped into fixed size frames
+//! that are compressed independently with zstd, and an index of the frames (with the range of
+//! instruction counts covered by each frame) is written at the end of the file. This allows a
+//! reader to extract the entries for a range of time by only decompressing the frames that overlap
+//! the range, and allows the writer to stream arbitrarily long traces to disk with bounded memory.
+//!
+//! Layout (all integers are little-endian):
+//!
+//! ```text
+//! header: MAGIC, version: u32, frame_len: u32
+//! frames: zstd compressed entries (addr: u64, icount: u64)
+//! index:  (offset: u64, compressed_len: u32, count: u32, first_icount: u64, last_icount: u64)
+//! footer: index_offset: u64, frame_count: u64, INDEX_MAGIC
+//! ```
+
+use std::{
+    cell::RefCell,
+    io::{Read, Seek, SeekFrom, Write},
+    path::Path,
+    rc::Rc,
+};
+
+use anyhow::Context;
+use icicle_cpu::Cpu;
+
+use crate::Vm;
+
+const MAGIC: &[u8; 8] = b"ICLTRACE";
+const INDEX_MAGIC: &[u8; 8] = b"ICLTRIDX";
+const VERSION: u32 = 1;
+
+const HEADER_SIZE: u64 = 16;
+const FOOTER_SIZE: u64 = 24;
+const ENTRY_SIZE: usize = 16;
+const INDEX_ENTRY_SIZE: usize = 32;
+
+/// The default number of entries in each frame.
+pub const DEFAULT_FRAME_LEN: u32 = 0x10000;
+
+/// The zstd compression level used for frames.
+const COMPRESSION_LEVEL: i32 = 3;
+
+/// An entry in the frame index.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FrameInfo {
+    /// The offset of the compressed frame from the start of the file.
+    pub offset: u64,
+    pub compressed_len: u32,
+
+    /// The number of entries in the frame.
+    pub count: u32,
+
+    /// The instruction count of the first and last entries in the frame.
+    pub first_icount: u64,
+    pub last_icount: u64,
+}
+
+pub struct TraceWriter<W: Write> {
+    inner: W,
+    frame_len: u32,
+    frame: Vec<u8>,
+    frame_count: u32,
+    first_icount: u64,
+    last_icount: u64,
+    offset: u64,
+    index: Vec<FrameInfo>,
+}
+
+impl<W: Write> TraceWriter<W> {
+    pub fn new(inner: W) -> std::io::Result<Self> {
+        Self::with_frame_len(inner, DEFAULT_FRAME_LEN)
+    }
+
+    /// Creates a writer that stores `frame_len` entries in each frame. Smaller frames allow more
+    /// precise seeking at the cost of a worse compression ratio.
+    pub fn with_frame_len(mut inner: W, frame_len: u32) -> std::io::Result<Self> {
+        let frame_len = frame_len.max(1);
+        inner.write_all(MAGIC)?;
+        inner.write_all(&VERSION.to_le_bytes())?;
+        inner.write_all(&frame_len.to_le_bytes())?;
+        Ok(Self {
+            inner,
+            frame_len,
+            frame: Vec::with_capacity(frame_len as usize * ENTRY_SIZE),
+            frame_count: 0,
+            first_icount: 0,
+            last_icount: 0,
+            offset: HEADER_SIZE,
+            index: vec![],
+        })
+    }
+
+    pub fn push(&mut self, addr: u64, icount: u64) -> std::io::Result<()> {
+        if self.frame_count == 0 {
+            self.first_icount = icount;
+        }
+        self.last_icount = icount;
+        self.frame.extend_from_slice(&addr.to_le_bytes());
+        self.frame.extend_from_slice(&icount.to_le_bytes());
+        self.frame_count += 1;
+
+        if self.frame_count >= self.frame_len {
+            self.flush_frame()?;
+        }
+        Ok(())
+    }
+
+    fn flush_frame(&mut self) -> std::io::Result<()> {
+        if self.frame_count == 0 {
+            return Ok(());
+        }
+
+        let compressed = zstd::bulk::compress(&self.frame, COMPRESSION_LEVEL)?;
+        self.inner.write_all(&compressed)?;
+        self.index.push(FrameInfo {
+            offset: self.offset,
+            compressed_len: compressed.len() as u32,
+            count: self.frame_count,
+            first_icount: self.first_icount,
+            last_icount: self.last_icount,
+        });
+        self.offset += compressed.len() as u64;
+
+        self.frame.clear();
+        self.frame_count = 0;
+        Ok(())
+    }
+
+    /// The total number of entries written to the trace.
+    pub fn len(&self) -> u64 {
+        self.index.iter().map(|x| x.count as u64).sum::<u64>() + self.frame_count as u64
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Writes the final frame and the frame index, returning the underlying writer.
+    pub fn finish(mut self) -> std::io::Result<W> {
+        self.flush_frame()?;
+
+        let index_offset = self.offset;
+        for frame in &self.index {
+            self.inner.write_all(&frame.offset.to_le_bytes())?;
+            self.inner.write_all(&frame.compressed_len.to_le_bytes())?;
+            self.inner.write_all(&frame.count.to_le_bytes())?;
+            self.inner.write_all(&frame.first_icount.to_le_bytes())?;
+            self.inner.write_all(&frame.last_icount.to_le_bytes())?;
+        }
+        self.inner.write_all(&index_offset.to_le_bytes())?;
+        self.inner.write_all(&(self.index.len() as u64).to_le_bytes())?;
+        self.inner.write_all(INDEX_MAGIC)?;
+        self.inner.flush()?;
+
+        Ok(self.inner)
+    }
+}
+
+pub struct TraceReader<R: Read + Seek> {
+    inner: R,
+    frame_len: u32,
+    index: Vec<FrameInfo>,
+}
+
+impl<R: Read + Seek> TraceReader<R> {
+    pub fn new(mut inner: R) -> anyhow::Result<Self> {
+        let mut header = [0; HEADER_SIZE as usize];
+        inner.read_exact(&mut header).context("failed to read trace header")?;
+        if &header[..8] != MAGIC {
+            anyhow::bail!("not a compressed trace file");
+        }
+        let version = u32::from_le_bytes(header[8..12].try_into().unwrap());
+        if version != VERSION {
+            anyhow::bail!("unsupported trace version: {version}");
+        }
+        let frame_len = u32::from_le_bytes(header[12..16].try_into().unwrap());
+
+        let mut footer = [0; FOOTER_SIZE as usize];
+        inner.seek(SeekFrom::End(-(FOOTER_SIZE as i64)))?;
+        inner.read_exact(&mut footer).context("failed to read trace footer")?;
+        if &footer[16..] != INDEX_MAGIC {
+            anyhow::bail!("trace index is missing (was the trace finished?)");
+        }
+        let index_offset = u64::from_le_bytes(footer[..8].try_into().unwrap());
+        let frame_count = u64::from_le_bytes(footer[8..16].try_into().unwrap());
+
+        let mut data = vec![0; frame_count as usize * INDEX_ENTRY_SIZE];
+        inner.seek(SeekFrom::Start(index_offset))?;
+        inner.read_exact(&mut data).context("failed to read trace index")?;
+        let index = data
+            .chunks_exact(INDEX_ENTRY_SIZE)
+            .map(|x| FrameInfo {
+                offset: u64::from_le_bytes(x[0..8].try_into().unwrap()),
+                compressed_len: u32::from_le_bytes(x[8..12].try_into().unwrap()),
+                count: u32::from_le_bytes(x[12..16].try_into().unwrap()),
+                first_icount: u64::from_le_bytes(x[16..24].try_into().unwrap()),
+                last_icount: u64::from_le_bytes(x[24..32].try_into().unwrap()),
+            })
+            .collect();
+
+        Ok(Self { inner, frame_len, index })
+    }
+
+    pub fn frame_len(&self) -> u32 {
+        self.frame_len
+    }
+
+    pub fn frames(&self) -> &[FrameInfo] {
+        &self.index
+    }
+
+    /// The total number of entries in the trace.
+    pub fn len(&self) -> u64 {
+        self.index.iter().map(|x| x.count as u64).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Decompresses the entries stored in the `n`th frame.
+    pub fn read_frame(&mut self, n: usize) -> anyhow::Result<Vec<(u64, u64)>> {
+        let frame = *self.index.get(n).context("frame out of bounds")?;
+
+        let mut compressed = vec![0; frame.compressed_len as usize];
+        self.inner.seek(SeekFrom::Start(frame.offset))?;
+        self.inner.read_exact(&mut compressed)?;
+
+        let data = zstd::bulk::decompress(&compressed, frame.count as usize * ENTRY_SIZE)?;
+        Ok(data
+            .chunks_exact(ENTRY_SIZE)
+            .map(|x| {
+                let addr = u64::from_le_bytes(x[0..8].try_into().unwrap());
+                let icount = u64::from_le_bytes(x[8..16].try_into().unwrap());
+                (addr, icount)
+            })
+            .collect())
+    }
+
+    /// Reads all entries with an instruction count in the range `start..end`, only decompressing
+    /// the frames that overlap the range.
+    pub fn read_range(&mut self, start: u64, end: u64) -> anyhow::Result<Vec<(u64, u64)>> {
+        let mut out = vec![];
+        for n in 0..self.index.len() {
+            let frame = self.index[n];
+            if frame.last_icount < start || frame.first_icount >= end {
+                continue;
+            }
+            let entries = self.read_frame(n)?;
+            out.extend(entries.into_iter().filter(|(_, icount)| (start..end).contains(icount)));
+        }
+        Ok(out)
+    }
+}
+
+impl TraceReader<std::io::BufReader<std::fs::File>> {
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let file = std::fs::File::open(path)
+            .with_context(|| format!("failed to open: {}", path.display()))?;
+        Self::new(std::io::BufReader::new(file))
+    }
+}
+
+type FileWriter = TraceWriter<std::io::BufWriter<std::fs::File>>;
+
+struct RecorderState {
+    writer: Option<FileWriter>,
+    error: Option<std::io::Error>,
+}
+
+/// A handle to a recorder that streams the blocks executed by the VM to a compressed trace file.
+/// Cloning the handle produces a handle to the same recorder.
+#[derive(Clone)]
+pub struct TraceRecorder {
+    state: Rc<RefCell<RecorderState>>,
+}
+
+impl TraceRecorder {
+    /// Writes the remaining entries and the frame index to the trace file, returning the number of
+    /// entries in the trace. Blocks executed after this is called are no longer recorded.
+    pub fn finish(&self) -> anyhow::Result<u64> {
+        let mut state = self.state.borrow_mut();
+        if let Some(e) = state.error.take() {
+            return Err(e).context("error writing trace");
+        }
+        let writer = state.writer.take().context("trace already finished")?;
+        let len = writer.len();
+        writer.finish().context("error writing trace")?;
+        Ok(len)
+    }
+}
+
+/// Starts recording every block executed by the VM to a compressed trace file at `path`.
+pub fn record_to_file(vm: &mut Vm, path: &Path) -> anyhow::Result<TraceRecorder> {
+    let file = std::fs::File::create(path)
+        .with_context(|| format!("failed to create: {}", path.display()))?;
+    let writer = TraceWriter::new(std::io::BufWriter::new(file))?;
+    let state = Rc::new(RefCell::new(RecorderState { writer: Some(writer), error: None }));
+
+    let hook_state = state.clone();
+    let hook = vm.cpu.add_hook(move |cpu: &mut Cpu, addr: u64| {
+        let mut state = hook_state.borrow_mut();
+        let Some(writer) = state.writer.as_mut()
+        else {
+            return;
+        };
+        if let Err(e) = writer.push(addr, cpu.icount()) {
+            state.writer = None;
+            state.error = Some(e);
+        }
+    });
+    crate::injector::register_block_hook_injector(vm, 0, u64::MAX, hook);
+
+    Ok(TraceRecorder { state })
+}
@@ -0,0 +1,379 @@
+//! A persistent, on-disk cache of lifted code.
+//!
+//! Decoding instructions with SLEIGH (and running the lifter optimizations) is one of the main
+//! costs of starting a new VM for a large target. The cache stores the blocks produced by the
+//! lifter for each group, so a later run of the same binary can reuse the translations instead of
+//! lifting the code again.
+//!
+//! The cache is keyed by a hash of the binary and a fingerprint of the lifter configuration, and
+//! each group is keyed by its address and ISA mode. A hash of the guest code the group was lifted
+//! from is stored alongside it, and cached groups are only used if the code in memory is unchanged
+//! (e.g. the cache remains correct for code that is patched or relocated at runtime).
+//!
+//! Translations are captured before breakpoints and code injectors are applied, so these continue
+//! to work with cached code. However, groups containing hooks inserted during lifting are never
+//! cached, since hook IDs are not stable across runs. The cache is also bypassed entirely if any
+//! lifter patchers, lift callbacks or op injectors were registered after the VM was built, since
+//! their effect on the lifted code cannot be captured by the fingerprint, or if a lifting time
+//! limit is configured, since the blocks produced then depend on host timing.
+
+use std::{collections::HashMap, path::Path};
+
+use anyhow::Context;
+use icicle_cpu::{
+    BlockKey, BlockTable, Cpu,
+    lifter::{self, BlockExit, Target},
+    mem::perm,
+};
+
+use crate::Vm;
+
+const VERSION: u32 = 1;
+
+#[derive(Clone, Copy, serde::Serialize, serde::Deserialize)]
+enum CachedTarget {
+    /// A block in the same group, relative to the first block of the group.
+    Internal(usize),
+    External(pcode::Value),
+}
+
+#[derive(Clone, Copy, serde::Serialize, serde::Deserialize)]
+enum CachedExit {
+    Jump { target: CachedTarget },
+    Branch { cond: pcode::Value, target: CachedTarget, fallthrough: CachedTarget },
+    Call { target: pcode::Value, fallthrough: u64 },
+    Return { target: pcode::Value },
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct CachedBlock {
+    pcode: pcode::Block,
+    entry: Option<u64>,
+    start: u64,
+    end: u64,
+    context: u64,
+    exit: CachedExit,
+    num_instructions: u32,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct CachedGroup {
+    start: u64,
+    end: u64,
+
+    /// A hash of the guest code in `start..end` when the group was lifted.
+    code_hash: u64,
+
+    blocks: Vec<CachedBlock>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct CacheFile<G> {
+    version: u32,
+    binary_hash: u64,
+    fingerprint: u64,
+    groups: Vec<((u64, u64), G)>,
+}
+
+pub struct TranslationCache {
+    binary_hash: u64,
+    fingerprint: u64,
+    groups: HashMap<BlockKey, CachedGroup>,
+
+    /// Whether new groups have been added since the cache was loaded.
+    dirty: bool,
+
+    /// The number of groups that were restored from the cache.
+    pub hits: u64,
+
+    /// The number of groups that had to be lifted.
+    pub misses: u64,
+}
+
+impl TranslationCache {
+    /// Creates an empty cache for the binary with hash `binary_hash` (see [hash_bytes]).
+    pub fn new(vm: &Vm, binary_hash: u64) -> Self {
+        Self {
+            binary_hash,
+            fingerprint: fingerprint(vm),
+            groups: HashMap::new(),
+            dirty: false,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// Loads a cache previously saved with [TranslationCache::save]. If the file does not exist,
+    /// or was created for a different binary or lifter configuration, an empty cache is returned.
+    pub fn load(vm: &Vm, binary_hash: u64, path: &Path) -> anyhow::Result<Self> {
+        let mut cache = Self::new(vm, binary_hash);
+        let compressed = match std::fs::read(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(cache),
+            Err(e) => return Err(e).with_context(|| format!("failed to read: {}", path.display())),
+        };
+
+        let data = zstd::decode_all(&compressed[..])
+            .with_context(|| format!("failed to decompress: {}", path.display()))?;
+        let file: CacheFile<CachedGroup> = ron::de::from_bytes(&data)
+            .with_context(|| format!("error parsing translation cache: {}", path.display()))?;
+
+        if file.version != VERSION
+            || file.binary_hash != cache.binary_hash
+            || file.fingerprint != cache.fingerprint
+        {
+            tracing::info!("translation cache is stale, ignoring: {}", path.display());
+            return Ok(cache);
+        }
+
+        cache.groups = file
+            .groups
+            .into_iter()
+            .map(|((vaddr, isa_mode), group)| (BlockKey { vaddr, isa_mode }, group))
+            .collect();
+        tracing::debug!("loaded {} cached groups from: {}", cache.groups.len(), path.display());
+
+        Ok(cache)
+    }
+
+    /// Saves the cache to `path`.
+    pub fn save(&mut self, path: &Path) -> anyhow::Result<()> {
+        let file = CacheFile {
+            version: VERSION,
+            binary_hash: self.binary_hash,
+            fingerprint: self.fingerprint,
+            groups: self
+                .groups
+                .iter()
+                .map(|(key, group)| ((key.vaddr, key.isa_mode), group))
+                .collect(),
+        };
+        let data = ron::to_string(&file)?;
+
+        let compressed = zstd::encode_all(data.as_bytes(), 3)?;
+        std::fs::write(path, compressed)
+            .with_context(|| format!("failed to write: {}", path.display()))?;
+        self.dirty = false;
+
+        Ok(())
+    }
+
+    /// Returns whether new groups have been added since the cache was loaded or saved.
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    /// The number of groups in the cache.
+    pub fn len(&self) -> usize {
+        self.groups.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.groups.is_empty()
+    }
+
+    /// Adds the blocks for a newly lifted `group` to `code` if `key` is in the cache and the code
+    /// in memory is unchanged.
+    pub(crate) fn restore(
+        &mut self,
+        key: BlockKey,
+        cpu: &mut Cpu,
+        code: &mut BlockTable,
+    ) -> Option<lifter::BlockGroup> {
+        let cached = self.groups.get(&key)?;
+
+        // Note: `ensure_executable` also marks the code for self-modifying code detection, as is
+        // done when reading instructions during lifting.
+        let (start, end) = code_bytes(cpu, cached.start, cached.end);
+        let len = end.checked_sub(start).filter(|len| *len > 0)?;
+        if !cpu.mem.ensure_executable(start, len)
+            || hash_code(cpu, cached.start, cached.end) != Some(cached.code_hash)
+        {
+            return None;
+        }
+
+        let base = code.blocks.len();
+        let target = |target: CachedTarget| match target {
+            CachedTarget::Internal(id) => Target::Internal(base + id),
+            CachedTarget::External(value) => Target::External(value),
+        };
+        for block in &cached.blocks {
+            let exit = match block.exit {
+                CachedExit::Jump { target: t } => BlockExit::Jump { target: target(t) },
+                CachedExit::Branch { cond, target: t, fallthrough } => {
+                    BlockExit::Branch { cond, target: target(t), fallthrough: target(fallthrough) }
+                }
+                CachedExit::Call { target, fallthrough } => BlockExit::Call { target, fallthrough },
+                CachedExit::Return { target } => BlockExit::Return { target },
+            };
+            code.blocks.push(lifter::Block {
+                pcode: block.pcode.clone(),
+                entry: block.entry,
+                start: block.start,
+                end: block.end,
+                context: block.context,
+                exit,
+                breakpoints: 0,
+                num_instructions: block.num_instructions,
+            });
+        }
+
+        self.hits += 1;
+        // Jump tables are stored outside of the code, so the targets are recovered again.
+        let no_targets = code.jump_table_targets.len();
+        Some(lifter::BlockGroup {
+            blocks: (base, code.blocks.len()),
+            start: cached.start,
+            end: cached.end,
+            jump_table_targets: (no_targets, no_targets),
+        })
+    }
+
+    /// Adds a newly lifted `group` to the cache.
+    pub(crate) fn insert(
+        &mut self,
+        key: BlockKey,
+        cpu: &mut Cpu,
+        code: &BlockTable,
+        group: lifter::BlockGroup,
+    ) {
+        self.misses += 1;
+
+        let base = group.blocks.0;
+        let target = |target: Target| match target {
+            Target::Internal(id) if group.range().contains(&id) => {
+                Some(CachedTarget::Internal(id - base))
+            }
+            Target::Internal(_) => None,
+            Target::External(value) => Some(CachedTarget::External(value)),
+            Target::Invalid(..) => None,
+        };
+
+        let mut blocks = vec![];
+        for block in &code.blocks[group.range()] {
+            let is_hook = |inst: &pcode::Instruction| {
+                matches!(inst.op, pcode::Op::Hook(_) | pcode::Op::HookIf(_))
+            };
+            if block.pcode.instructions.iter().any(is_hook) {
+                return;
+            }
+
+            let exit = match block.exit {
+                BlockExit::Jump { target: t } => {
+                    let Some(t) = target(t)
+                    else {
+                        return;
+                    };
+                    CachedExit::Jump { target: t }
+                }
+                BlockExit::Branch { cond, target: t, fallthrough } => {
+                    let (Some(t), Some(fallthrough)) = (target(t), target(fallthrough))
+                    else {
+                        return;
+                    };
+                    CachedExit::Branch { cond, target: t, fallthrough }
+                }
+                BlockExit::Call { target, fallthrough } => CachedExit::Call { target, fallthrough },
+                BlockExit::Return { target } => CachedExit::Return { target },
+            };
+            blocks.push(CachedBlock {
+                pcode: block.pcode.clone(),
+                entry: block.entry,
+                start: block.start,
+                end: block.end,
+                context: block.context,
+                exit,
+                num_instructions: block.num_instructions,
+            });
+        }
+
+        let Some(code_hash) = hash_code(cpu, group.start, group.end)
+        else {
+            return;
+        };
+        let group = CachedGroup { start: group.start, end: group.end, code_hash, blocks };
+        self.groups.insert(key, group);
+        self.dirty = true;
+    }
+}
+
+/// Computes a hash of `data` that is stable across runs (e.g. for identifying the binary a cache
+/// was created for).
+pub fn hash_bytes(data: &[u8]) -> u64 {
+    // FNV-1a
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+/// Converts the code addresses `start..end` to the range of bytes in memory that contain the code
+/// (these differ for word addressed targets, e.g. AVR).
+fn code_bytes(cpu: &Cpu, start: u64, end: u64) -> (u64, u64) {
+    let word_size = cpu.arch.sleigh.word_size();
+    (start.saturating_mul(word_size), end.saturating_mul(word_size))
+}
+
+fn hash_code(cpu: &mut Cpu, start: u64, end: u64) -> Option<u64> {
+    let (start, end) = code_bytes(cpu, start, end);
+    let mut buf = vec![0; end.checked_sub(start)? as usize];
+    cpu.mem.read_bytes(start, &mut buf, perm::NONE).ok()?;
+    Some(hash_bytes(&buf))
+}
+
+/// Computes a fingerprint of the parts of the VM configuration that affect the code produced by
+/// the lifter.
+fn fingerprint(vm: &Vm) -> u64 {
+    let sleigh = &vm.cpu.arch.sleigh;
+    let config = format!(
+        "{}:{}:{}:{}:{}:{}:{}:{:?}:{}:{}",
+        vm.cpu.arch.triple,
+        sleigh.constructors.len(),
+        sleigh.named_registers.len(),
+        sleigh.user_ops.len(),
+        vm.lifter.settings.optimize_block,
+        vm.lifter.settings.max_instructions_per_block,
+        vm.lifter.settings.max_pcode_ops_per_block,
+        vm.lifter.settings.max_lift_time,
+        vm.builtin_lifter_hooks.patchers,
+        vm.builtin_lifter_hooks.lift_callbacks + vm.builtin_lifter_hooks.op_injectors,
+    );
+    hash_bytes(config.as_bytes())
+}
+
+/// The number of hooks registered with the lifter that modify the code it produces.
+///
+/// The hooks registered by the VM builder are determined by the configuration, so they are covered
+/// by the fingerprint of the cache. Any other hooks disable the cache.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LifterHooks {
+    pub patchers: usize,
+    pub lift_callbacks: usize,
+    pub op_injectors: usize,
+}
+
+impl LifterHooks {
+    pub fn of(vm: &Vm) -> Self {
+        Self {
+            patchers: vm.lifter.patchers.len(),
+            lift_callbacks: vm.lifter.lift_callbacks.len(),
+            op_injectors: vm.lifter.op_injectors.len(),
+        }
+    }
+}
+
+impl Vm {
+    /// Enables the translation cache, loading cached translations from `path` if they were created
+    /// for the same binary (identified by `binary_hash`, see [hash_bytes]).
+    pub fn load_translation_cache(&mut self, binary_hash: u64, path: &Path) -> anyhow::Result<()> {
+        self.translation_cache = Some(TranslationCache::load(self, binary_hash, path)?);
+        Ok(())
+    }
+
+    /// Saves the translation cache to `path` if any new code has been lifted since it was loaded.
+    pub fn save_translation_cache(&mut self, path: &Path) -> anyhow::Result<()> {
+        match self.translation_cache.as_mut() {
+            Some(cache) if cache.is_dirty() => cache.save(path),
+            _ => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,111 @@
+//! Exclusion of code that should never be executed.
+//!
+//! Fuzzing campaigns often waste time on paths that are known to be uninteresting, e.g. error
+//! handlers that log a message and call `exit`. Address ranges can be marked as must-not-execute
+//! with [UnreachableCode::add_range], and reaching any instruction in these ranges immediately
+//! stops the VM with [VmExit::Unreachable](crate::VmExit::Unreachable) (similar to an execute
+//! watchpoint), which fuzzers can treat as a distinct, uninteresting exit.
+//!
+//! The check is patched into the code as it is lifted: an exception is raised at the start of each
+//! instruction inside of a marked range, so there is no cost for code outside of these ranges.
+//!
+//! Note: ranges should be configured before the VM starts executing, or existing translations of
+//! the range should be removed with [Vm::invalidate_code_range].
+
+use std::{cell::RefCell, collections::BTreeMap, rc::Rc};
+
+use icicle_cpu::{BlockGroup, BlockTable, Cpu, ExceptionCode};
+use pcode::Op;
+
+use crate::{Vm, injector::CodeInjector};
+
+#[derive(Default)]
+struct UnreachableState {
+    /// Maps the start of each range to the (inclusive) end of the range.
+    ranges: BTreeMap<u64, u64>,
+}
+
+impl UnreachableState {
+    fn contains(&self, addr: u64) -> bool {
+        self.ranges.range(..=addr).any(|(_, end)| addr <= *end)
+    }
+}
+
+/// A handle to the set of unreachable ranges attached to a VM. Cloning the handle produces a
+/// handle to the same state.
+#[derive(Clone)]
+pub struct UnreachableCode {
+    state: Rc<RefCell<UnreachableState>>,
+}
+
+impl UnreachableCode {
+    /// Marks the `len` bytes starting at `start` as unreachable.
+    pub fn add_range(&self, start: u64, len: u64) {
+        if len == 0 {
+            return;
+        }
+        let end = start.saturating_add(len - 1);
+        let mut state = self.state.borrow_mut();
+        let end = state.ranges.get(&start).map_or(end, |prev| end.max(*prev));
+        state.ranges.insert(start, end);
+    }
+
+    /// Removes the range starting at `start` (previously added by [UnreachableCode::add_range]).
+    pub fn remove_range(&self, start: u64) {
+        self.state.borrow_mut().ranges.remove(&start);
+    }
+
+    /// Returns whether `addr` is inside of a range marked as unreachable.
+    pub fn contains(&self, addr: u64) -> bool {
+        self.state.borrow().contains(addr)
+    }
+
+    /// Gets the `(start, end)` address (inclusive) of every unreachable range, ordered by start
+    /// address.
+    pub fn ranges(&self) -> Vec<(u64, u64)> {
+        self.state.borrow().ranges.iter().map(|(start, end)| (*start, *end)).collect()
+    }
+}
+
+/// Attaches the unreachable code checker to the VM. Only code lifted after the checker is attached
+/// is checked.
+pub fn attach(vm: &mut Vm) -> UnreachableCode {
+    let state = Rc::new(RefCell::new(UnreachableState::default()));
+    vm.add_injector(UnreachableInjector { state: state.clone() });
+    UnreachableCode { state }
+}
+
+struct UnreachableInjector {
+    state: Rc<RefCell<UnreachableState>>,
+}
+
+impl CodeInjector for UnreachableInjector {
+    fn inject(&mut self, _cpu: &mut Cpu, group: &BlockGroup, code: &mut BlockTable) {
+        let state = self.state.borrow();
+        if state.ranges.is_empty() {
+            return;
+        }
+
+        for id in group.range() {
+            let block = &mut code.blocks[id];
+            let Some((i, addr)) = block
+                .pcode
+                .instructions
+                .iter()
+                .enumerate()
+                .filter(|(_, x)| x.op == Op::InstructionMarker)
+                .map(|(i, x)| (i, x.inputs.first().as_u64()))
+                .find(|(_, addr)| state.contains(*addr))
+            else {
+                continue;
+            };
+
+            // The exception stops execution, so only the first unreachable instruction in the block
+            // needs to be patched.
+            tracing::debug!("unreachable instruction lifted at {addr:#x}");
+            let exception = (Op::Exception, (ExceptionCode::Unreachable as u32, addr));
+            block.pcode.instructions.insert(i + 1, exception.into());
+            code.modified.insert(id);
+        }
+    }
+}
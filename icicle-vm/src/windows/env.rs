@@ -0,0 +1,687 @@
+//! A minimal environment for running Windows user-mode PE files.
+//!
+//! The environment loads a single PE file, then sets up a stack, a heap, and the TEB/PEB
+//! structures that code compiled for Windows expects to find at `gs:[0]` (x86-64) or `fs:[0]`
+//! (x86). No system DLLs are loaded: every import is replaced by a stub, and calls to a stub are
+//! dispatched to a handler registered with [WindowsEnvironment::register_api]. A small set of
+//! kernel32/ntdll functions are handled by default (see [WindowsEnvironment::add_default_apis]).
+//! Calls to imports without a handler exit with [ExceptionCode::UnimplementedApi].
+//!
+//! Handlers are called with the arguments of the call (using the Microsoft x64 calling convention,
+//! or `stdcall` for 32-bit targets), and their return value is written to the return register
+//! before returning to the caller.
+//!
+//! For x86-64 images with a function table, faults raised in the guest are dispatched to the
+//! guest's structured exception handlers (see [SehDispatcher]), and handlers can resume execution
+//! using `NtContinue` or `RtlUnwindEx`. Exceptions in 32-bit images are not dispatched to the
+//! guest.
+
+use std::{
+    any::Any,
+    collections::{BTreeMap, HashMap},
+};
+
+use icicle_cpu::{
+    debug_info::DebugInfo,
+    mem::{perm, AllocLayout, Mapping},
+    pe::{ImportStubs, PeLoader, UnimplementedApiCall},
+    Cpu, Environment, Exception, ExceptionCode, VmExit,
+};
+use object::read::FileKind;
+
+use crate::{
+    libc_models::HeapModel,
+    windows::{
+        seh::{SehDispatcher, SehState},
+        unwind::{FrameContext, FunctionTable, RSP},
+    },
+};
+
+/// Error codes returned by `GetLastError`.
+pub const ERROR_NOT_ENOUGH_MEMORY: u32 = 8;
+pub const ERROR_INVALID_PARAMETER: u32 = 87;
+pub const ERROR_INVALID_ADDRESS: u32 = 487;
+
+/// The flag passed to `HeapAlloc` to request zero-initialized memory.
+const HEAP_ZERO_MEMORY: u64 = 0x8;
+
+/// Free types accepted by `VirtualFree`.
+const MEM_DECOMMIT: u64 = 0x4000;
+const MEM_RELEASE: u64 = 0x8000;
+
+/// The lowest address returned by `VirtualAlloc` when no address is requested.
+const VIRTUAL_ALLOC_BASE: u64 = 0x1_0000;
+
+/// The alignment of regions allocated by `VirtualAlloc` when no address is requested.
+const ALLOCATION_GRANULARITY: u64 = 0x1_0000;
+
+/// Values used for the process and thread IDs of the emulated process.
+const PROCESS_ID: u64 = 0x1000;
+const THREAD_ID: u64 = 0x1004;
+
+/// A fake handle returned by `GetProcessHeap`.
+const PROCESS_HEAP_HANDLE: u64 = 0x0001_0000;
+
+#[derive(Clone, Copy, Debug)]
+pub struct WindowsConfig {
+    /// The size of the stack of the main thread.
+    pub stack_size: u64,
+
+    /// The size of the memory region reserved for the heap.
+    pub heap_size: u64,
+}
+
+impl Default for WindowsConfig {
+    fn default() -> Self {
+        Self { stack_size: 0x10_0000, heap_size: 0x400_0000 }
+    }
+}
+
+/// The state passed to API handlers.
+pub struct ApiContext<'a> {
+    pub cpu: &'a mut Cpu,
+
+    /// The arguments passed to the function.
+    pub args: &'a [u64],
+
+    pub heap: &'a mut HeapModel,
+
+    /// The value returned by `GetLastError`.
+    pub last_error: &'a mut u32,
+
+    /// The exit code of the process, set by `ExitProcess`.
+    pub exit_code: &'a mut Option<u32>,
+
+    /// The start address and length of each region allocated by `VirtualAlloc`.
+    pub regions: &'a mut BTreeMap<u64, u64>,
+
+    /// The address the function returns to.
+    pub return_addr: u64,
+
+    /// The exception dispatcher of the process (only available for x86-64 images).
+    pub seh: Option<&'a mut SehDispatcher>,
+
+    /// If set by the handler, the VM exits instead of returning to the caller.
+    pub exit: Option<VmExit>,
+
+    /// Set by handlers that transfer control somewhere other than the caller (e.g.
+    /// `NtContinue`), in which case the return value is ignored.
+    pub redirected: bool,
+}
+
+pub type ApiHandler = Box<dyn FnMut(&mut ApiContext) -> u64>;
+
+struct RegisteredApi {
+    /// The number of arguments the function takes, used to clean up the stack for `stdcall`
+    /// functions.
+    arg_count: usize,
+    handler: ApiHandler,
+}
+
+/// The addresses of the structures allocated for the process.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ProcessLayout {
+    pub image_base: u64,
+    pub teb: u64,
+    pub peb: u64,
+    pub stack_base: u64,
+    pub stack_limit: u64,
+
+    /// Returning to this address exits the process (used as the return address of the entry
+    /// point).
+    pub exit_addr: u64,
+}
+
+impl ProcessLayout {
+    /// Execution reaching this address dispatches the exception on the top of the stack to the
+    /// guest's exception handlers.
+    pub fn exception_dispatcher(&self) -> u64 {
+        self.exit_addr + 0x10
+    }
+
+    /// The return address of exception handlers called by the exception dispatcher.
+    pub fn handler_return(&self) -> u64 {
+        self.exit_addr + 0x20
+    }
+}
+
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+struct ProcessState {
+    heap: HeapModel,
+    last_error: u32,
+    exit_code: Option<u32>,
+    #[serde(default)]
+    regions: BTreeMap<u64, u64>,
+}
+
+pub struct WindowsEnvironment {
+    config: WindowsConfig,
+    debug_info: DebugInfo,
+    import_stubs: ImportStubs,
+    apis: HashMap<(String, String), RegisteredApi>,
+    layout: ProcessLayout,
+    state: ProcessState,
+    seh: Option<SehDispatcher>,
+    last_unimplemented_call: Option<UnimplementedApiCall>,
+}
+
+#[derive(Clone)]
+struct Snapshot {
+    state: ProcessState,
+    seh: Option<SehState>,
+}
+
+impl WindowsEnvironment {
+    pub fn new(config: WindowsConfig) -> Self {
+        let mut env = Self {
+            config,
+            debug_info: DebugInfo::default(),
+            import_stubs: ImportStubs::default(),
+            apis: HashMap::new(),
+            layout: ProcessLayout::default(),
+            state: ProcessState {
+                heap: HeapModel::default(),
+                last_error: 0,
+                exit_code: None,
+                regions: BTreeMap::new(),
+            },
+            seh: None,
+            last_unimplemented_call: None,
+        };
+        env.add_default_apis();
+        env
+    }
+
+    /// Registers `handler` to be called whenever the guest calls `dll!name`. `arg_count` is the
+    /// number of arguments the function takes. DLL names are case insensitive, and handlers
+    /// registered for `kernel32.dll` also handle calls to `kernelbase.dll` (and vice versa).
+    pub fn register_api(
+        &mut self,
+        dll: &str,
+        name: &str,
+        arg_count: usize,
+        handler: impl FnMut(&mut ApiContext) -> u64 + 'static,
+    ) {
+        let key = (normalize_dll(dll), name.to_owned());
+        self.apis.insert(key, RegisteredApi { arg_count, handler: Box::new(handler) });
+    }
+
+    /// Registers handlers for a small set of commonly used kernel32 and ntdll functions.
+    pub fn add_default_apis(&mut self) {
+        let k32 = "kernel32.dll";
+        self.register_api(k32, "GetProcessHeap", 0, |_| PROCESS_HEAP_HANDLE);
+        self.register_api(k32, "HeapAlloc", 3, heap_alloc);
+        self.register_api(k32, "HeapFree", 3, |ctx| {
+            let addr = ctx.args[2];
+            free(ctx, addr)
+        });
+        self.register_api(k32, "GetLastError", 0, |ctx| *ctx.last_error as u64);
+        self.register_api(k32, "SetLastError", 1, |ctx| {
+            *ctx.last_error = ctx.args[0] as u32;
+            0
+        });
+        self.register_api(k32, "GetCurrentProcessId", 0, |_| PROCESS_ID);
+        self.register_api(k32, "GetCurrentThreadId", 0, |_| THREAD_ID);
+        self.register_api(k32, "ExitProcess", 1, |ctx| {
+            *ctx.exit_code = Some(ctx.args[0] as u32);
+            ctx.exit = Some(VmExit::Halt);
+            0
+        });
+        self.register_api(k32, "VirtualAlloc", 4, virtual_alloc);
+        self.register_api(k32, "VirtualFree", 3, virtual_free);
+
+        let ntdll = "ntdll.dll";
+        self.register_api(ntdll, "RtlAllocateHeap", 3, heap_alloc);
+        self.register_api(ntdll, "RtlFreeHeap", 3, |ctx| {
+            let addr = ctx.args[2];
+            free(ctx, addr)
+        });
+        self.register_api(ntdll, "NtContinue", 2, nt_continue);
+        for dll in [k32, ntdll] {
+            self.register_api(dll, "RtlUnwind", 4, rtl_unwind);
+            self.register_api(dll, "RtlUnwindEx", 6, rtl_unwind);
+        }
+    }
+
+    /// Gets the addresses of the structures allocated for the process.
+    pub fn layout(&self) -> ProcessLayout {
+        self.layout
+    }
+
+    /// Gets the exit code passed to `ExitProcess` (if the process has exited).
+    pub fn exit_code(&self) -> Option<u32> {
+        self.state.exit_code
+    }
+
+    pub fn heap(&self) -> &HeapModel {
+        &self.state.heap
+    }
+
+    /// Gets the stubs generated for the imports of the loaded PE file.
+    pub fn import_stubs(&self) -> &ImportStubs {
+        &self.import_stubs
+    }
+
+    /// Gets the details of the call that caused the most recent `UnimplementedApi` exit.
+    pub fn last_unimplemented_call(&self) -> Option<&UnimplementedApiCall> {
+        self.last_unimplemented_call.as_ref()
+    }
+
+    /// Allocates the stack, heap, TEB and PEB for the process, and configures the CPU to start
+    /// executing at `entry`.
+    #[allow(clippy::identity_op)]
+    fn init_process(&mut self, cpu: &mut Cpu, image_base: u64, entry: u64) -> Result<(), String> {
+        let is_64bit = self.import_stubs.ptr_size == 8;
+
+        let alloc_region = |cpu: &mut Cpu, size: u64, perm: u8| {
+            let layout = AllocLayout { addr: None, size, align: 0x1000 };
+            cpu.mem
+                .alloc_memory(layout, Mapping { perm: perm::MAP | perm, value: 0 })
+                .map_err(|e| format!("failed to allocate {size:#x} bytes: {e:?}"))
+        };
+
+        let stack_limit = alloc_region(cpu, self.config.stack_size, perm::READ | perm::WRITE)?;
+        let stack_base = stack_limit + self.config.stack_size;
+        let teb = alloc_region(cpu, 0x2000, perm::READ | perm::WRITE)?;
+        let peb = teb + 0x1000;
+
+        // A non-executable page that exits the process when the entry point returns to it.
+        let exit_addr = alloc_region(cpu, 0x1000, perm::READ)?;
+
+        self.state.heap = HeapModel::new(cpu, self.config.heap_size)
+            .map_err(|e| format!("failed to allocate heap: {e:?}"))?;
+
+        let write_ptr = |cpu: &mut Cpu, addr: u64, value: u64| {
+            let result = match is_64bit {
+                true => cpu.mem.write_u64(addr, value, perm::NONE),
+                false => cpu.mem.write_u32(addr, value as u32, perm::NONE),
+            };
+            result.map_err(|e| format!("failed to write process structures: {e:?}"))
+        };
+
+        if is_64bit {
+            write_ptr(cpu, teb + 0x00, u64::MAX)?; // NtTib.ExceptionList
+            write_ptr(cpu, teb + 0x08, stack_base)?; // NtTib.StackBase
+            write_ptr(cpu, teb + 0x10, stack_limit)?; // NtTib.StackLimit
+            write_ptr(cpu, teb + 0x30, teb)?; // NtTib.Self
+            write_ptr(cpu, teb + 0x40, PROCESS_ID)?; // ClientId.UniqueProcess
+            write_ptr(cpu, teb + 0x48, THREAD_ID)?; // ClientId.UniqueThread
+            write_ptr(cpu, teb + 0x60, peb)?; // ProcessEnvironmentBlock
+            write_ptr(cpu, peb + 0x10, image_base)?; // ImageBaseAddress
+            write_ptr(cpu, peb + 0x30, PROCESS_HEAP_HANDLE)?; // ProcessHeap
+        }
+        else {
+            write_ptr(cpu, teb + 0x00, u32::MAX as u64)?; // NtTib.ExceptionList
+            write_ptr(cpu, teb + 0x04, stack_base)?; // NtTib.StackBase
+            write_ptr(cpu, teb + 0x08, stack_limit)?; // NtTib.StackLimit
+            write_ptr(cpu, teb + 0x18, teb)?; // NtTib.Self
+            write_ptr(cpu, teb + 0x20, PROCESS_ID)?; // ClientId.UniqueProcess
+            write_ptr(cpu, teb + 0x24, THREAD_ID)?; // ClientId.UniqueThread
+            write_ptr(cpu, teb + 0x30, peb)?; // ProcessEnvironmentBlock
+            write_ptr(cpu, peb + 0x08, image_base)?; // ImageBaseAddress
+            write_ptr(cpu, peb + 0x18, PROCESS_HEAP_HANDLE)?; // ProcessHeap
+        }
+
+        (cpu.arch.on_boot)(cpu, entry);
+
+        let segment_base = if is_64bit { "GS_OFFSET" } else { "FS_OFFSET" };
+        let var = cpu.arch.sleigh.get_varnode(segment_base).ok_or("missing segment register")?;
+        cpu.write_reg(var, teb);
+
+        // Leave space for the shadow space (x64) then push the return address of the entry point,
+        // so the stack is misaligned by one pointer like it is after a `call` instruction.
+        let sp = stack_base - 0x108;
+        write_ptr(cpu, sp, exit_addr)?;
+        cpu.write_reg(cpu.arch.reg_sp, sp);
+
+        self.layout = ProcessLayout { image_base, teb, peb, stack_base, stack_limit, exit_addr };
+        Ok(())
+    }
+
+    /// Calls the handler registered for the import stub at `addr` then returns to the caller.
+    fn call_api(&mut self, cpu: &mut Cpu, addr: u64) -> Option<VmExit> {
+        let stub = self.import_stubs.get(addr)?;
+        let key = (normalize_dll(&stub.dll), stub.name.clone());
+        let api = self.apis.get_mut(&key)?;
+
+        let ptr_size = self.import_stubs.ptr_size;
+        let sp = cpu.read_reg(cpu.arch.reg_sp);
+        let read_ptr = |cpu: &mut Cpu, addr: u64| match ptr_size {
+            8 => cpu.mem.read_u64(addr, perm::READ).ok(),
+            _ => cpu.mem.read_u32(addr, perm::READ).ok().map(|x| x as u64),
+        };
+        let return_addr = read_ptr(cpu, sp)?;
+
+        let mut args = Vec::with_capacity(api.arg_count);
+        for i in 0..api.arg_count {
+            let value = match ptr_size {
+                8 if i < 4 => {
+                    let name = ["RCX", "RDX", "R8", "R9"][i];
+                    cpu.read_reg(cpu.arch.sleigh.get_varnode(name)?)
+                }
+                8 => read_ptr(cpu, sp + 0x28 + (i as u64 - 4) * 8)?,
+                _ => read_ptr(cpu, sp + 4 + i as u64 * 4)?,
+            };
+            args.push(value);
+        }
+
+        tracing::debug!("{}!{}{args:#x?}", stub.dll, stub.name);
+        let mut ctx = ApiContext {
+            cpu,
+            args: &args,
+            heap: &mut self.state.heap,
+            last_error: &mut self.state.last_error,
+            exit_code: &mut self.state.exit_code,
+            regions: &mut self.state.regions,
+            return_addr,
+            seh: self.seh.as_mut(),
+            exit: None,
+            redirected: false,
+        };
+        let result = (api.handler)(&mut ctx);
+        if let Some(exit) = ctx.exit {
+            return Some(exit);
+        }
+        if ctx.redirected {
+            cpu.exception.clear();
+            return Some(VmExit::Running);
+        }
+
+        cpu.write_return_value(result)?;
+
+        // `stdcall` functions remove their arguments from the stack.
+        let cleanup = if ptr_size == 8 { 0 } else { api.arg_count as u64 * 4 };
+        cpu.write_reg(cpu.arch.reg_sp, sp + ptr_size + cleanup);
+        cpu.exception.clear();
+        cpu.write_pc(return_addr);
+        Some(VmExit::Running)
+    }
+}
+
+impl Default for WindowsEnvironment {
+    fn default() -> Self {
+        Self::new(WindowsConfig::default())
+    }
+}
+
+impl PeLoader for WindowsEnvironment {}
+
+fn normalize_dll(dll: &str) -> String {
+    match dll.to_ascii_lowercase() {
+        dll if dll == "kernelbase.dll" => "kernel32.dll".into(),
+        dll => dll,
+    }
+}
+
+fn heap_alloc(ctx: &mut ApiContext) -> u64 {
+    let (flags, size) = (ctx.args[1], ctx.args[2]);
+    alloc(ctx, size, flags & HEAP_ZERO_MEMORY != 0)
+}
+
+fn alloc(ctx: &mut ApiContext, size: u64, zeroed: bool) -> u64 {
+    let Some(addr) = ctx.heap.alloc(size)
+    else {
+        *ctx.last_error = ERROR_NOT_ENOUGH_MEMORY;
+        return 0;
+    };
+    if zeroed {
+        if let Err(e) = ctx.cpu.mem.fill_mem(addr, size, 0) {
+            tracing::warn!("failed to zero allocation at {addr:#x}: {e:?}");
+        }
+    }
+    addr
+}
+
+/// Converts a `PAGE_*` memory protection constant to permissions.
+fn page_protection(protect: u64) -> Option<u8> {
+    Some(match protect & 0xff {
+        0x01 => perm::NONE,                                   // PAGE_NOACCESS
+        0x02 => perm::READ,                                   // PAGE_READONLY
+        0x04 | 0x08 => perm::READ | perm::WRITE,              // PAGE_READWRITE, PAGE_WRITECOPY
+        0x10 => perm::EXEC,                                   // PAGE_EXECUTE
+        0x20 => perm::READ | perm::EXEC,                      // PAGE_EXECUTE_READ
+        0x40 | 0x80 => perm::READ | perm::WRITE | perm::EXEC, // PAGE_EXECUTE_(READWRITE|WRITECOPY)
+        _ => return None,
+    })
+}
+
+fn virtual_alloc(ctx: &mut ApiContext) -> u64 {
+    let (addr, size, protect) = (ctx.args[0], ctx.args[1], ctx.args[3]);
+    let Some(perm) = page_protection(protect).filter(|_| size != 0)
+    else {
+        *ctx.last_error = ERROR_INVALID_PARAMETER;
+        return 0;
+    };
+
+    let start = addr & !0xfff;
+    let Some(end) = addr.checked_add(size).and_then(|end| end.checked_next_multiple_of(0x1000))
+    else {
+        *ctx.last_error = ERROR_INVALID_PARAMETER;
+        return 0;
+    };
+    let len = end - start;
+
+    // Committing (or changing the protection of) part of a region that was already allocated.
+    let existing = ctx.regions.range(..=start).next_back();
+    if addr != 0 && existing.is_some_and(|(base, base_len)| end <= base + base_len) {
+        if let Err(e) = ctx.cpu.mem.update_perm(start, len, perm) {
+            tracing::warn!("failed to update permissions at {start:#x}: {e:?}");
+            *ctx.last_error = ERROR_INVALID_ADDRESS;
+            return 0;
+        }
+        return start;
+    }
+
+    let (hint, align) = match addr {
+        0 => (VIRTUAL_ALLOC_BASE, ALLOCATION_GRANULARITY),
+        _ => (start, 0x1000),
+    };
+    let layout = AllocLayout { addr: Some(hint), size: len, align };
+    let base = match ctx.cpu.mem.find_free_memory(layout) {
+        Ok(base) if addr == 0 || base == start => base,
+        Ok(_) => {
+            *ctx.last_error = ERROR_INVALID_ADDRESS;
+            return 0;
+        }
+        Err(_) => {
+            *ctx.last_error = ERROR_NOT_ENOUGH_MEMORY;
+            return 0;
+        }
+    };
+    let mapping = Mapping { perm: perm::MAP | perm::INIT | perm, value: 0 };
+    if !ctx.cpu.mem.map_memory_len(base, len, mapping) {
+        *ctx.last_error = ERROR_NOT_ENOUGH_MEMORY;
+        return 0;
+    }
+    ctx.regions.insert(base, len);
+    base
+}
+
+fn virtual_free(ctx: &mut ApiContext) -> u64 {
+    let (addr, size, free_type) = (ctx.args[0], ctx.args[1], ctx.args[2]);
+    let region = ctx.regions.range(..=addr).next_back().map(|(base, len)| (*base, *len));
+    match (free_type, region) {
+        (MEM_RELEASE, Some((base, len))) if base == addr && size == 0 => {
+            ctx.cpu.mem.unmap_memory_len(base, len);
+            ctx.regions.remove(&base);
+            1
+        }
+        (MEM_DECOMMIT, Some((base, len))) => {
+            let start = addr & !0xfff;
+            let end = match size {
+                0 => base + len,
+                _ => addr.saturating_add(size).next_multiple_of(0x1000).min(base + len),
+            };
+            if start >= end || ctx.cpu.mem.update_perm(start, end - start, perm::NONE).is_err() {
+                *ctx.last_error = ERROR_INVALID_PARAMETER;
+                return 0;
+            }
+            1
+        }
+        _ => {
+            tracing::warn!("invalid VirtualFree({addr:#x}, {size:#x}, {free_type:#x})");
+            *ctx.last_error = ERROR_INVALID_PARAMETER;
+            0
+        }
+    }
+}
+
+fn nt_continue(ctx: &mut ApiContext) -> u64 {
+    const STATUS_INVALID_PARAMETER: u64 = 0xC000_000D;
+
+    let Some(seh) = ctx.seh.as_mut()
+    else {
+        return STATUS_INVALID_PARAMETER;
+    };
+    if let Err(e) = crate::windows::seh::restore_context(ctx.cpu, ctx.args[0]) {
+        tracing::warn!("NtContinue failed: {e}");
+        return STATUS_INVALID_PARAMETER;
+    }
+    seh.end_dispatch();
+    ctx.redirected = true;
+    0
+}
+
+/// Handles `RtlUnwind(TargetFrame, TargetIp, ExceptionRecord, ReturnValue)` and `RtlUnwindEx`
+/// (which takes the same first four arguments).
+fn rtl_unwind(ctx: &mut ApiContext) -> u64 {
+    let (target_frame, target_ip, return_value) = (ctx.args[0], ctx.args[1], ctx.args[3]);
+    let Some(seh) = ctx.seh.as_mut()
+    else {
+        tracing::warn!("RtlUnwind called without a function table");
+        return 0;
+    };
+
+    // The context of the caller of `RtlUnwind`, used if no exception is being dispatched.
+    let caller = FrameContext::from_cpu(ctx.cpu).map(|mut caller| {
+        caller.gprs[RSP] += 8;
+        caller.rip = ctx.return_addr;
+        caller
+    });
+    match caller
+        .and_then(|caller| seh.unwind(ctx.cpu, target_frame, target_ip, return_value, caller))
+    {
+        Ok(()) => ctx.redirected = true,
+        Err(e) => tracing::warn!("RtlUnwind failed: {e}"),
+    }
+    0
+}
+
+fn free(ctx: &mut ApiContext, addr: u64) -> u64 {
+    if addr == 0 {
+        return 1;
+    }
+    match ctx.heap.free(addr) {
+        Ok(_) => 1,
+        Err(e) => {
+            tracing::warn!("invalid free: {e:?}");
+            *ctx.last_error = ERROR_INVALID_PARAMETER;
+            0
+        }
+    }
+}
+
+impl Environment for WindowsEnvironment {
+    fn load(&mut self, cpu: &mut Cpu, path: &[u8]) -> Result<(), String> {
+        let path = std::str::from_utf8(path)
+            .map_err(|e| format!("@fixme: only utf-8 paths are supported: {e}"))?;
+        let data = std::fs::read(path).map_err(|e| format!("Failed to read {path}: {e}"))?;
+
+        let loaded = match FileKind::parse(&data[..]) {
+            Ok(FileKind::Pe32) => self.load_pe32(cpu, &data)?,
+            Ok(FileKind::Pe64) => self.load_pe64(cpu, &data)?,
+            Ok(other) => return Err(format!("unsupported file type: {other:?}")),
+            Err(e) => return Err(format!("failed to parse file: {e}")),
+        };
+
+        self.debug_info = loaded.debug_info;
+        self.debug_info.entry_ptr = loaded.binary.entry_ptr;
+        self.import_stubs = loaded.import_stubs;
+        self.init_process(cpu, loaded.binary.base_ptr, loaded.binary.entry_ptr)?;
+
+        self.seh = None;
+        if let (8, Some((addr, len))) =
+            (self.import_stubs.ptr_size, loaded.binary.exception_directory)
+        {
+            let mut seh = SehDispatcher::new(self.layout.exception_dispatcher());
+            seh.callback_return = self.layout.handler_return();
+            seh.functions = Some(FunctionTable { image_base: loaded.binary.base_ptr, addr, len });
+            seh.stack = (self.layout.stack_limit, self.layout.stack_base);
+            self.seh = Some(seh);
+        }
+        Ok(())
+    }
+
+    fn handle_exception(&mut self, cpu: &mut Cpu) -> Option<VmExit> {
+        match ExceptionCode::from_u32(cpu.exception.code) {
+            ExceptionCode::ExecViolation => {}
+            // Breakpoints are left for the debugger.
+            ExceptionCode::SoftwareBreakpoint => return None,
+            _ => return self.seh.as_mut()?.handle_exception(cpu),
+        }
+
+        let addr = cpu.exception.value;
+        if addr == self.layout.exit_addr {
+            self.state.exit_code = Some(cpu.read_return_value()? as u32);
+            return Some(VmExit::Halt);
+        }
+        if let Some(seh) = self.seh.as_mut() {
+            if addr == self.layout.exception_dispatcher() {
+                return seh.run_dispatcher(cpu, &self.import_stubs);
+            }
+            if addr == self.layout.handler_return() {
+                return seh.resume(cpu, &self.import_stubs);
+            }
+        }
+
+        if let Some(exit) = self.call_api(cpu, addr) {
+            return Some(exit);
+        }
+
+        if let Some(call) = self.import_stubs.describe_call(cpu, addr) {
+            tracing::error!("{call}");
+            self.last_unimplemented_call = Some(call);
+            cpu.exception = Exception::new(ExceptionCode::UnimplementedApi, addr);
+            return Some(VmExit::UnhandledException((ExceptionCode::UnimplementedApi, addr)));
+        }
+
+        self.seh.as_mut()?.handle_exception(cpu)
+    }
+
+    fn debug_info(&self) -> Option<&DebugInfo> {
+        Some(&self.debug_info)
+    }
+
+    fn entry_point(&mut self) -> u64 {
+        self.debug_info.entry_ptr
+    }
+
+    fn snapshot(&mut self) -> Box<dyn Any> {
+        let seh = self.seh.as_ref().map(SehDispatcher::snapshot);
+        Box::new(Snapshot { state: self.state.clone(), seh })
+    }
+
+    fn restore(&mut self, snapshot: &Box<dyn Any>) {
+        let snapshot = snapshot.downcast_ref::<Snapshot>().unwrap();
+        self.state = snapshot.state.clone();
+        if let (Some(seh), Some(state)) = (self.seh.as_mut(), snapshot.seh.as_ref()) {
+            seh.restore(state);
+        }
+    }
+
+    fn save_state(&mut self) -> Result<Vec<u8>, String> {
+        ron::to_string(&self.state)
+            .map(String::into_bytes)
+            .map_err(|e| format!("failed to serialize Windows state: {e}"))
+    }
+
+    fn load_state(&mut self, data: &[u8]) -> Result<(), String> {
+        self.state =
+            ron::de::from_bytes(data).map_err(|e| format!("invalid Windows state: {e}"))?;
+        // The state of an exception being dispatched is not saved.
+        if let Some(seh) = self.seh.as_mut() {
+            seh.end_dispatch();
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,5 @@
+//! Support code for emulating Windows user-mode targets.
+
+pub mod env;
+pub mod seh;
+pub mod unwind;
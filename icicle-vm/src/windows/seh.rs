@@ -0,0 +1,750 @@
+//! Structured exception handling (SEH) dispatch for x86-64 Windows targets.
+//!
+//! This implements the kernel side of user-mode exception dispatch: faults raised by the emulator
+//! are converted to an `EXCEPTION_RECORD` and `CONTEXT` that are written to the guest stack, and
+//! execution is redirected to `ntdll!KiUserExceptionDispatcher`. The guest's own ntdll then
+//! performs the rest of the dispatch (vectored handlers, `RtlDispatchException`, unwinding) and
+//! eventually resumes execution by calling `NtContinue`, which should be handled by calling
+//! [restore_context].
+//!
+//! When there is no ntdll in the guest (e.g. for [WindowsEnvironment]), the dispatcher address
+//! can instead be handled by the environment by calling [SehDispatcher::run_dispatcher], which
+//! emulates `RtlDispatchException`: the frames on the stack are walked using the function table of
+//! the image, and each frame's exception handler is called in the guest until one of them handles
+//! the exception. `__C_specific_handler` (used by MSVC for `__try`/`__except`) is emulated
+//! directly, calling the guest's `__except` filters. Termination handlers (`__finally`) are not
+//! run when unwinding to a handler.
+//!
+//! [WindowsEnvironment]: super::env::WindowsEnvironment
+
+use icicle_cpu::{mem::perm, pe::ImportStubs, Cpu, Exception, ExceptionCode, ValueSource, VmExit};
+
+use super::unwind::{self, FrameContext, FunctionTable, LanguageHandler};
+
+pub const STATUS_ACCESS_VIOLATION: u32 = 0xC000_0005;
+pub const STATUS_DATATYPE_MISALIGNMENT: u32 = 0x8000_0002;
+pub const STATUS_BREAKPOINT: u32 = 0x8000_0003;
+pub const STATUS_ILLEGAL_INSTRUCTION: u32 = 0xC000_001D;
+pub const STATUS_INTEGER_DIVIDE_BY_ZERO: u32 = 0xC000_0094;
+
+/// Access types stored in the first parameter of an access violation.
+const ACCESS_READ: u64 = 0;
+const ACCESS_WRITE: u64 = 1;
+const ACCESS_EXECUTE: u64 = 8;
+
+const CONTEXT_AMD64: u32 = 0x0010_0000;
+const CONTEXT_CONTROL: u32 = CONTEXT_AMD64 | 0x1;
+const CONTEXT_INTEGER: u32 = CONTEXT_AMD64 | 0x2;
+const CONTEXT_SEGMENTS: u32 = CONTEXT_AMD64 | 0x4;
+const CONTEXT_FLOATING_POINT: u32 = CONTEXT_AMD64 | 0x8;
+const CONTEXT_FULL: u32 =
+    CONTEXT_CONTROL | CONTEXT_INTEGER | CONTEXT_SEGMENTS | CONTEXT_FLOATING_POINT;
+
+const CONTEXT_SIZE: u64 = 0x4d0;
+const CONTEXT_EX_SIZE: u64 = 0x20;
+const EXCEPTION_RECORD_SIZE: u64 = 0x98;
+const MACHINE_FRAME_SIZE: u64 = 0x28;
+
+/// Offset of the exception record relative to the stack pointer on entry to
+/// `KiUserExceptionDispatcher`.
+const RECORD_OFFSET: u64 = CONTEXT_SIZE + CONTEXT_EX_SIZE;
+const MACHINE_FRAME_OFFSET: u64 = RECORD_OFFSET + EXCEPTION_RECORD_SIZE + 0x8;
+const FRAME_SIZE: u64 = MACHINE_FRAME_OFFSET + MACHINE_FRAME_SIZE;
+
+/// Layout of the space reserved on the stack for the arguments passed to handlers by
+/// [SehDispatcher::run_dispatcher].
+const EXCEPTION_POINTERS_OFFSET: u64 = 0x0;
+const DISPATCHER_CONTEXT_OFFSET: u64 = 0x10;
+const DISPATCHER_CONTEXT_SIZE: u64 = 0x50;
+const HANDLER_CONTEXT_OFFSET: u64 = 0x60;
+const SCRATCH_SIZE: u64 = HANDLER_CONTEXT_OFFSET + CONTEXT_SIZE;
+
+/// The maximum number of frames that are searched for a handler.
+const MAX_FRAMES: usize = 256;
+
+/// Return values of language specific handlers (`EXCEPTION_DISPOSITION`).
+const EXCEPTION_CONTINUE_EXECUTION: u64 = 0;
+const EXCEPTION_CONTINUE_SEARCH: u64 = 1;
+
+/// The value used in a scope table entry to execute the handler without calling a filter.
+const EXCEPTION_EXECUTE_HANDLER: u32 = 1;
+
+/// The size of a `C_SCOPE_TABLE` entry.
+const SCOPE_ENTRY_SIZE: u64 = 16;
+
+/// Offsets of general purpose registers within `CONTEXT`.
+const GPR_OFFSETS: &[(&str, usize)] = &[
+    ("RAX", 0x78),
+    ("RCX", 0x80),
+    ("RDX", 0x88),
+    ("RBX", 0x90),
+    ("RSP", 0x98),
+    ("RBP", 0xa0),
+    ("RSI", 0xa8),
+    ("RDI", 0xb0),
+    ("R8", 0xb8),
+    ("R9", 0xc0),
+    ("R10", 0xc8),
+    ("R11", 0xd0),
+    ("R12", 0xd8),
+    ("R13", 0xe0),
+    ("R14", 0xe8),
+    ("R15", 0xf0),
+    ("RIP", 0xf8),
+];
+
+/// Offsets of segment registers within `CONTEXT`.
+const SEGMENT_OFFSETS: &[(&str, usize)] =
+    &[("CS", 0x38), ("DS", 0x3a), ("ES", 0x3c), ("FS", 0x3e), ("GS", 0x40), ("SS", 0x42)];
+
+const CONTEXT_FLAGS_OFFSET: usize = 0x30;
+const CONTEXT_MXCSR_OFFSET: usize = 0x34;
+const CONTEXT_EFLAGS_OFFSET: usize = 0x44;
+const CONTEXT_FLTSAVE_OFFSET: usize = 0x100;
+const FLTSAVE_MXCSR_OFFSET: usize = 0x18;
+const FLTSAVE_XMM_OFFSET: usize = 0xa0;
+
+/// A Windows exception record (`EXCEPTION_RECORD64`).
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ExceptionRecord {
+    pub code: u32,
+    pub flags: u32,
+    pub address: u64,
+    pub params: Vec<u64>,
+}
+
+impl ExceptionRecord {
+    /// Converts the exception currently pending on the CPU into an exception record, returning
+    /// `None` if the exception has no Windows equivalent.
+    pub fn from_cpu(cpu: &Cpu) -> Option<Self> {
+        let address = cpu.read_pc();
+        let value = cpu.exception.value;
+        let access_violation = |access: u64| Self {
+            code: STATUS_ACCESS_VIOLATION,
+            flags: 0,
+            address,
+            params: vec![access, value],
+        };
+
+        Some(match ExceptionCode::from_u32(cpu.exception.code) {
+            ExceptionCode::ReadUnmapped
+            | ExceptionCode::ReadPerm
+            | ExceptionCode::ReadUninitialized => access_violation(ACCESS_READ),
+            ExceptionCode::WriteUnmapped | ExceptionCode::WritePerm => {
+                access_violation(ACCESS_WRITE)
+            }
+            ExceptionCode::ExecViolation => access_violation(ACCESS_EXECUTE),
+            ExceptionCode::ReadUnaligned | ExceptionCode::WriteUnaligned => {
+                Self { code: STATUS_DATATYPE_MISALIGNMENT, flags: 0, address, params: vec![] }
+            }
+            ExceptionCode::InvalidInstruction => {
+                Self { code: STATUS_ILLEGAL_INSTRUCTION, flags: 0, address, params: vec![] }
+            }
+            ExceptionCode::DivisionException => {
+                Self { code: STATUS_INTEGER_DIVIDE_BY_ZERO, flags: 0, address, params: vec![] }
+            }
+            ExceptionCode::SoftwareBreakpoint => {
+                Self { code: STATUS_BREAKPOINT, flags: 0, address, params: vec![0] }
+            }
+            _ => return None,
+        })
+    }
+
+    fn encode(&self, buf: &mut [u8]) {
+        write_u32(buf, 0x00, self.code);
+        write_u32(buf, 0x04, self.flags);
+        write_u64(buf, 0x10, self.address);
+        let params = &self.params[..self.params.len().min(15)];
+        write_u32(buf, 0x18, params.len() as u32);
+        for (i, param) in params.iter().enumerate() {
+            write_u64(buf, 0x20 + i * 8, *param);
+        }
+    }
+}
+
+pub struct SehDispatcher {
+    /// The address of `ntdll!KiUserExceptionDispatcher` in the guest.
+    pub dispatcher: u64,
+
+    /// The return address used when calling handlers from [SehDispatcher::run_dispatcher].
+    /// Execution reaching this address should be handled by calling [SehDispatcher::resume].
+    pub callback_return: u64,
+
+    /// The function table used to find the handlers of each frame.
+    pub functions: Option<FunctionTable>,
+
+    /// The limit and base of the stack, frames outside of this range stop the search for a
+    /// handler.
+    pub stack: (u64, u64),
+
+    state: SehState,
+}
+
+/// The state of the exception currently being dispatched.
+#[derive(Clone, Default)]
+pub struct SehState {
+    /// The exception that was dispatched to the guest.
+    raised: Option<Exception>,
+
+    /// The state of [SehDispatcher::run_dispatcher] while a handler is running.
+    active: Option<Dispatch>,
+}
+
+#[derive(Clone)]
+struct Dispatch {
+    code: u32,
+
+    /// The address of the `EXCEPTION_RECORD` on the guest stack.
+    record: u64,
+
+    /// The address of the `CONTEXT` of the exception on the guest stack.
+    context: u64,
+
+    /// Space reserved on the guest stack for the arguments passed to handlers.
+    scratch: u64,
+
+    /// The context of the frame being searched.
+    frame: FrameContext,
+
+    /// The context of the caller of `frame`.
+    caller: FrameContext,
+
+    /// The establisher frame of `frame`.
+    establisher: u64,
+
+    /// The number of frames that have been searched.
+    depth: usize,
+
+    /// The handler (or filter) running in the guest.
+    pending: Option<Pending>,
+}
+
+#[derive(Clone, Copy)]
+enum Pending {
+    /// The filter of entry `index` of the scope table of a `__C_specific_handler` frame.
+    Filter { scope_table: u64, index: u32 },
+
+    /// A language specific handler.
+    Handler,
+}
+
+impl SehDispatcher {
+    pub fn new(dispatcher: u64) -> Self {
+        Self {
+            dispatcher,
+            callback_return: 0,
+            functions: None,
+            stack: (0, u64::MAX),
+            state: SehState::default(),
+        }
+    }
+
+    pub fn snapshot(&self) -> SehState {
+        self.state.clone()
+    }
+
+    pub fn restore(&mut self, state: &SehState) {
+        self.state = state.clone();
+    }
+
+    /// Handles the exception currently pending on the CPU by dispatching it to the guest.
+    ///
+    /// Returns `None` if the exception cannot be handled by the guest, allowing it to be handled
+    /// by the VM instead.
+    pub fn handle_exception(&mut self, cpu: &mut Cpu) -> Option<VmExit> {
+        if self.state.active.is_some() {
+            // An exception inside of a handler called by `run_dispatcher` (nested exceptions are
+            // not supported).
+            return None;
+        }
+
+        let record = ExceptionRecord::from_cpu(cpu)?;
+        let exception = cpu.exception;
+        match self.dispatch(cpu, &record) {
+            Ok(()) => {
+                self.state.raised = Some(exception);
+                Some(VmExit::Running)
+            }
+            Err(e) => {
+                // The exception frame could not be written (e.g. due to a stack overflow), on a
+                // real system this would terminate the process.
+                tracing::warn!("failed to dispatch exception {:#x}: {e}", record.code);
+                None
+            }
+        }
+    }
+
+    /// Writes `record` and the current CPU context to the guest stack then redirects execution to
+    /// the exception dispatcher.
+    pub fn dispatch(&mut self, cpu: &mut Cpu, record: &ExceptionRecord) -> Result<(), String> {
+        let rsp = read_reg(cpu, "RSP")?;
+        let frame_addr = rsp.wrapping_sub(FRAME_SIZE) & !0xf;
+
+        let mut frame = vec![0; FRAME_SIZE as usize];
+        save_context(cpu, &mut frame[..CONTEXT_SIZE as usize])?;
+
+        // CONTEXT_EX: describes the layout of the context (which has no extended state).
+        let context_ex = &mut frame[CONTEXT_SIZE as usize..];
+        write_u32(context_ex, 0x00, (CONTEXT_SIZE as i32).wrapping_neg() as u32);
+        write_u32(context_ex, 0x04, (CONTEXT_SIZE + CONTEXT_EX_SIZE) as u32);
+        write_u32(context_ex, 0x08, (CONTEXT_SIZE as i32).wrapping_neg() as u32);
+        write_u32(context_ex, 0x0c, CONTEXT_SIZE as u32);
+        write_u32(context_ex, 0x10, CONTEXT_EX_SIZE as u32);
+        write_u32(context_ex, 0x14, 0);
+
+        record.encode(&mut frame[RECORD_OFFSET as usize..]);
+
+        // The machine frame allows unwinding through the dispatcher back to the faulting frame.
+        let machine_frame = &mut frame[MACHINE_FRAME_OFFSET as usize..];
+        write_u64(machine_frame, 0x00, record.address);
+        write_u64(machine_frame, 0x08, read_reg(cpu, "CS")?);
+        write_u64(machine_frame, 0x10, crate::x86::eflags(cpu) as u64);
+        write_u64(machine_frame, 0x18, rsp);
+        write_u64(machine_frame, 0x20, read_reg(cpu, "SS")?);
+
+        cpu.mem
+            .write_bytes_large(frame_addr, &frame, perm::WRITE)
+            .map_err(|e| format!("failed to write exception frame at {frame_addr:#x}: {e:?}"))?;
+
+        cpu.exception.clear();
+        write_reg(cpu, "RSP", frame_addr)?;
+        cpu.write_pc(self.dispatcher);
+        Ok(())
+    }
+
+    /// Emulates `KiUserExceptionDispatcher` for the exception frame at the top of the stack,
+    /// calling the exception handler of each frame until the exception is handled.
+    ///
+    /// `stubs` are used to identify calls to `__C_specific_handler`.
+    pub fn run_dispatcher(&mut self, cpu: &mut Cpu, stubs: &ImportStubs) -> Option<VmExit> {
+        let result = self.start_search(cpu).and_then(|_| self.search(cpu, stubs));
+        self.finish(cpu, result)
+    }
+
+    /// Called when a handler called by [SehDispatcher::run_dispatcher] returns, to act on the
+    /// result of the handler.
+    pub fn resume(&mut self, cpu: &mut Cpu, stubs: &ImportStubs) -> Option<VmExit> {
+        let pending = self.state.active.as_mut()?.pending.take()?;
+        let result = read_reg(cpu, "RAX").and_then(|value| match pending {
+            Pending::Filter { scope_table, index } => match value as i32 {
+                0 => match self.search_scope_table(cpu, scope_table, index + 1)? {
+                    true => Ok(true),
+                    false => self.next_frame().and_then(|_| self.search(cpu, stubs)),
+                },
+                x if x > 0 => self.execute_handler(cpu, scope_table, index).map(|_| true),
+                _ => self.continue_execution(cpu).map(|_| true),
+            },
+            Pending::Handler => match value {
+                EXCEPTION_CONTINUE_EXECUTION => self.continue_execution(cpu).map(|_| true),
+                EXCEPTION_CONTINUE_SEARCH => {
+                    self.next_frame().and_then(|_| self.search(cpu, stubs))
+                }
+                _ => Ok(false),
+            },
+        });
+        self.finish(cpu, result)
+    }
+
+    /// Ends the dispatch of the current exception (e.g. after the guest calls `NtContinue`).
+    pub fn end_dispatch(&mut self) {
+        self.state = SehState::default();
+    }
+
+    /// Unwinds to the frame with an establisher frame of `target_frame` then continues execution
+    /// at `target_ip` (i.e. the behaviour of `RtlUnwindEx`). If an exception is being dispatched,
+    /// unwinding starts from the frame that raised the exception, otherwise it starts from
+    /// `caller`.
+    pub fn unwind(
+        &mut self,
+        cpu: &mut Cpu,
+        target_frame: u64,
+        target_ip: u64,
+        return_value: u64,
+        caller: FrameContext,
+    ) -> Result<(), String> {
+        let functions = self.functions.ok_or("no function table")?;
+        let mut ctx = match &self.state.active {
+            Some(dispatch) => read_frame_context(cpu, dispatch.context)?,
+            None => caller,
+        };
+        for _ in 0..MAX_FRAMES {
+            let mut next = ctx;
+            let frame = unwind::virtual_unwind(cpu, &functions, &mut next)?;
+            if frame.establisher == target_frame {
+                ctx.rip = target_ip;
+                ctx.gprs[unwind::RAX] = return_value;
+                ctx.apply(cpu)?;
+                self.end_dispatch();
+                return Ok(());
+            }
+            if !self.is_valid_caller(&ctx, &next) {
+                break;
+            }
+            ctx = next;
+        }
+        Err(format!("failed to find target frame: {target_frame:#x}"))
+    }
+
+    fn start_search(&mut self, cpu: &mut Cpu) -> Result<(), String> {
+        let context = read_reg(cpu, "RSP")?;
+        let record = context + RECORD_OFFSET;
+        let code = cpu
+            .mem
+            .read_u32(record, perm::READ)
+            .map_err(|e| format!("failed to read exception record at {record:#x}: {e:?}"))?;
+        let frame = read_frame_context(cpu, context)?;
+        self.state.active = Some(Dispatch {
+            code,
+            record,
+            context,
+            scratch: context.wrapping_sub(SCRATCH_SIZE) & !0xf,
+            frame,
+            caller: frame,
+            establisher: 0,
+            depth: 0,
+            pending: None,
+        });
+        Ok(())
+    }
+
+    /// Searches for a handler starting from the current frame, returning `false` if no handler was
+    /// found.
+    fn search(&mut self, cpu: &mut Cpu, stubs: &ImportStubs) -> Result<bool, String> {
+        let functions = self.functions.ok_or("no function table")?;
+        loop {
+            let dispatch = self.state.active.as_mut().ok_or("no active exception")?;
+            if dispatch.depth >= MAX_FRAMES {
+                return Ok(false);
+            }
+            dispatch.depth += 1;
+
+            dispatch.caller = dispatch.frame;
+            let frame = unwind::virtual_unwind(cpu, &functions, &mut dispatch.caller)?;
+            dispatch.establisher = frame.establisher;
+
+            if let Some(handler) = frame.handler {
+                let is_c_handler = stubs
+                    .resolve(cpu, handler.addr)
+                    .is_some_and(|stub| stub.name == "__C_specific_handler");
+                if !is_c_handler {
+                    let function_addr = frame.function.map_or(0, |(_, addr)| addr);
+                    self.call_handler(cpu, handler, functions.image_base, function_addr)?;
+                    return Ok(true);
+                }
+                if self.search_scope_table(cpu, handler.data, 0)? {
+                    return Ok(true);
+                }
+            }
+
+            if !self.next_frame()? {
+                return Ok(false);
+            }
+        }
+    }
+
+    /// Moves the search to the caller of the current frame, returning `false` if the caller is
+    /// not a valid frame.
+    fn next_frame(&mut self) -> Result<bool, String> {
+        let dispatch = self.state.active.as_ref().ok_or("no active exception")?;
+        if !self.is_valid_caller(&dispatch.frame, &dispatch.caller) {
+            return Ok(false);
+        }
+        let dispatch = self.state.active.as_mut().unwrap();
+        dispatch.frame = dispatch.caller;
+        Ok(true)
+    }
+
+    fn is_valid_caller(&self, frame: &FrameContext, caller: &FrameContext) -> bool {
+        let (limit, base) = self.stack;
+        caller.rip != 0 && caller.rsp() > frame.rsp() && (limit..base).contains(&caller.rsp())
+    }
+
+    /// Emulates `__C_specific_handler` for the current frame starting at entry `start` of the scope
+    /// table at `scope_table`. Returns `false` if no entry handles the exception.
+    fn search_scope_table(
+        &mut self,
+        cpu: &mut Cpu,
+        scope_table: u64,
+        start: u32,
+    ) -> Result<bool, String> {
+        let dispatch = self.state.active.as_mut().ok_or("no active exception")?;
+        let image_base = self.functions.map_or(0, |table| table.image_base);
+        let control_pc = dispatch.frame.rip.wrapping_sub(image_base);
+
+        let count = read_u32_mem(cpu, scope_table)?;
+        for index in start..count {
+            let [begin, end, filter, target] = read_scope_entry(cpu, scope_table, index)?;
+            // Entries without a jump target are termination handlers.
+            if target == 0 || !(begin as u64..end as u64).contains(&control_pc) {
+                continue;
+            }
+            if filter == EXCEPTION_EXECUTE_HANDLER {
+                self.execute_handler(cpu, scope_table, index)?;
+                return Ok(true);
+            }
+
+            let pointers = dispatch.scratch + EXCEPTION_POINTERS_OFFSET;
+            let mut buf = [0; 16];
+            write_u64(&mut buf, 0, dispatch.record);
+            write_u64(&mut buf, 8, dispatch.context);
+            write_mem(cpu, pointers, &buf)?;
+
+            let args = [pointers, dispatch.establisher, 0, 0];
+            let filter = image_base + filter as u64;
+            call_guest(cpu, filter, args, dispatch.scratch, self.callback_return)?;
+            dispatch.pending = Some(Pending::Filter { scope_table, index });
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    /// Calls the language specific handler of the current frame.
+    fn call_handler(
+        &mut self,
+        cpu: &mut Cpu,
+        handler: LanguageHandler,
+        image_base: u64,
+        function_addr: u64,
+    ) -> Result<(), String> {
+        let dispatch = self.state.active.as_mut().ok_or("no active exception")?;
+
+        // The handler is passed a copy of the context of the frame being searched.
+        let handler_context = dispatch.scratch + HANDLER_CONTEXT_OFFSET;
+        let mut context = vec![0; CONTEXT_SIZE as usize];
+        read_mem(cpu, dispatch.context, &mut context)?;
+        encode_frame_context(&dispatch.frame, &mut context);
+        write_mem(cpu, handler_context, &context)?;
+
+        let dispatcher_context = dispatch.scratch + DISPATCHER_CONTEXT_OFFSET;
+        let mut buf = [0; DISPATCHER_CONTEXT_SIZE as usize];
+        write_u64(&mut buf, 0x00, dispatch.frame.rip); // ControlPc
+        write_u64(&mut buf, 0x08, image_base); // ImageBase
+        write_u64(&mut buf, 0x10, function_addr); // FunctionEntry
+        write_u64(&mut buf, 0x18, dispatch.establisher); // EstablisherFrame
+        write_u64(&mut buf, 0x20, 0); // TargetIp
+        write_u64(&mut buf, 0x28, handler_context); // ContextRecord
+        write_u64(&mut buf, 0x30, handler.addr); // LanguageHandler
+        write_u64(&mut buf, 0x38, handler.data); // HandlerData
+        write_mem(cpu, dispatcher_context, &buf)?;
+
+        let args = [dispatch.record, dispatch.establisher, dispatch.context, dispatcher_context];
+        call_guest(cpu, handler.addr, args, dispatch.scratch, self.callback_return)?;
+        dispatch.pending = Some(Pending::Handler);
+        Ok(())
+    }
+
+    /// Transfers control to the `__except` block of entry `index` in the scope table.
+    fn execute_handler(
+        &mut self,
+        cpu: &mut Cpu,
+        scope_table: u64,
+        index: u32,
+    ) -> Result<(), String> {
+        let dispatch = self.state.active.as_ref().ok_or("no active exception")?;
+        let image_base = self.functions.map_or(0, |table| table.image_base);
+        let [_, _, _, target] = read_scope_entry(cpu, scope_table, index)?;
+
+        let mut ctx = dispatch.frame;
+        ctx.rip = image_base + target as u64;
+        ctx.gprs[unwind::RAX] = dispatch.code as u64;
+        ctx.apply(cpu)?;
+        self.end_dispatch();
+        Ok(())
+    }
+
+    /// Resumes execution using the (possibly modified) context of the exception.
+    fn continue_execution(&mut self, cpu: &mut Cpu) -> Result<(), String> {
+        let dispatch = self.state.active.as_ref().ok_or("no active exception")?;
+        restore_context(cpu, dispatch.context)?;
+        self.end_dispatch();
+        Ok(())
+    }
+
+    fn finish(&mut self, cpu: &mut Cpu, result: Result<bool, String>) -> Option<VmExit> {
+        match result {
+            Ok(true) => {
+                cpu.exception.clear();
+                Some(VmExit::Running)
+            }
+            Ok(false) | Err(_) => {
+                if let Err(e) = result {
+                    tracing::warn!("failed to dispatch exception: {e}");
+                }
+
+                // The exception is unhandled, so restore the state of the CPU at the exception and
+                // let the VM handle it.
+                if let Some(dispatch) = self.state.active.take() {
+                    if let Err(e) = restore_context(cpu, dispatch.context) {
+                        tracing::warn!("failed to restore context: {e}");
+                    }
+                }
+                if let Some(exception) = self.state.raised.take() {
+                    cpu.exception = exception;
+                }
+                None
+            }
+        }
+    }
+}
+
+/// Reads the integer registers from the `CONTEXT` structure at `addr`.
+pub fn read_frame_context(cpu: &mut Cpu, addr: u64) -> Result<FrameContext, String> {
+    let mut context = vec![0; CONTEXT_SIZE as usize];
+    read_mem(cpu, addr, &mut context)?;
+    let mut ctx = FrameContext { gprs: [0; 16], rip: read_u64(&context, 0xf8) };
+    for (value, &(_, offset)) in ctx.gprs.iter_mut().zip(GPR_OFFSETS) {
+        *value = read_u64(&context, offset);
+    }
+    Ok(ctx)
+}
+
+fn encode_frame_context(ctx: &FrameContext, buf: &mut [u8]) {
+    for (value, &(_, offset)) in ctx.gprs.iter().zip(GPR_OFFSETS) {
+        write_u64(buf, offset, *value);
+    }
+    write_u64(buf, 0xf8, ctx.rip);
+}
+
+/// Calls `func` in the guest with `args`, using the stack below `stack` and returning to
+/// `return_addr`.
+fn call_guest(
+    cpu: &mut Cpu,
+    func: u64,
+    args: [u64; 4],
+    stack: u64,
+    return_addr: u64,
+) -> Result<(), String> {
+    // Reserve the shadow space for the arguments then push the return address.
+    let rsp = (stack & !0xf) - 0x28;
+    write_mem(cpu, rsp, &return_addr.to_le_bytes())?;
+    for (name, value) in ["RCX", "RDX", "R8", "R9"].into_iter().zip(args) {
+        write_reg(cpu, name, value)?;
+    }
+    write_reg(cpu, "RSP", rsp)?;
+    // Treat the call as a regular call so the shadow stack accepts the return from `func`.
+    if cpu.enable_shadow_stack {
+        cpu.push_shadow_stack(return_addr);
+    }
+    cpu.write_pc(func);
+    Ok(())
+}
+
+fn read_scope_entry(cpu: &mut Cpu, scope_table: u64, index: u32) -> Result<[u32; 4], String> {
+    let mut buf = [0; SCOPE_ENTRY_SIZE as usize];
+    read_mem(cpu, scope_table + 4 + index as u64 * SCOPE_ENTRY_SIZE, &mut buf)?;
+    Ok(std::array::from_fn(|i| read_u32(&buf, i * 4)))
+}
+
+/// Restores the CPU state from the `CONTEXT` structure at `addr` (i.e. the behaviour of
+/// `NtContinue`).
+pub fn restore_context(cpu: &mut Cpu, addr: u64) -> Result<(), String> {
+    let mut context = vec![0; CONTEXT_SIZE as usize];
+    cpu.mem
+        .read_bytes_large(addr, &mut context, perm::READ)
+        .map_err(|e| format!("failed to read context at {addr:#x}: {e:?}"))?;
+
+    let flags = read_u32(&context, CONTEXT_FLAGS_OFFSET);
+    if flags & CONTEXT_INTEGER == CONTEXT_INTEGER {
+        let gprs = GPR_OFFSETS.iter().filter(|(name, _)| !matches!(*name, "RSP" | "RIP"));
+        for &(name, offset) in gprs {
+            write_reg(cpu, name, read_u64(&context, offset))?;
+        }
+    }
+    if flags & CONTEXT_SEGMENTS == CONTEXT_SEGMENTS {
+        for &(name, offset) in SEGMENT_OFFSETS {
+            write_reg(cpu, name, read_u16(&context, offset) as u64)?;
+        }
+    }
+    if flags & CONTEXT_FLOATING_POINT == CONTEXT_FLOATING_POINT {
+        write_reg(cpu, "MXCSR", read_u32(&context, CONTEXT_MXCSR_OFFSET) as u64)?;
+        for i in 0..16 {
+            let offset = CONTEXT_FLTSAVE_OFFSET + FLTSAVE_XMM_OFFSET + i * 16;
+            let value: [u8; 16] = context[offset..offset + 16].try_into().unwrap();
+            let var = get_varnode(cpu, &format!("XMM{i}"))?;
+            cpu.write_var(var, value);
+        }
+    }
+    if flags & CONTEXT_CONTROL == CONTEXT_CONTROL {
+        crate::x86::set_eflags(cpu, read_u32(&context, CONTEXT_EFLAGS_OFFSET));
+        write_reg(cpu, "RSP", read_u64(&context, 0x98))?;
+        cpu.write_pc(read_u64(&context, 0xf8));
+    }
+
+    Ok(())
+}
+
+fn save_context(cpu: &mut Cpu, buf: &mut [u8]) -> Result<(), String> {
+    write_u32(buf, CONTEXT_FLAGS_OFFSET, CONTEXT_FULL);
+
+    for &(name, offset) in GPR_OFFSETS {
+        write_u64(buf, offset, read_reg(cpu, name)?);
+    }
+    for &(name, offset) in SEGMENT_OFFSETS {
+        buf[offset..offset + 2].copy_from_slice(&(read_reg(cpu, name)? as u16).to_le_bytes());
+    }
+    write_u32(buf, CONTEXT_EFLAGS_OFFSET, crate::x86::eflags(cpu));
+
+    let mxcsr = read_reg(cpu, "MXCSR")? as u32;
+    write_u32(buf, CONTEXT_MXCSR_OFFSET, mxcsr);
+    write_u32(buf, CONTEXT_FLTSAVE_OFFSET + FLTSAVE_MXCSR_OFFSET, mxcsr);
+    for i in 0..16 {
+        let var = get_varnode(cpu, &format!("XMM{i}"))?;
+        let offset = CONTEXT_FLTSAVE_OFFSET + FLTSAVE_XMM_OFFSET + i * 16;
+        buf[offset..offset + 16].copy_from_slice(&cpu.read_var::<[u8; 16]>(var));
+    }
+
+    Ok(())
+}
+
+fn get_varnode(cpu: &Cpu, name: &str) -> Result<pcode::VarNode, String> {
+    cpu.arch.sleigh.get_varnode(name).ok_or_else(|| format!("unknown register: {name}"))
+}
+
+fn read_reg(cpu: &mut Cpu, name: &str) -> Result<u64, String> {
+    let var = get_varnode(cpu, name)?;
+    Ok(cpu.read_reg(var))
+}
+
+fn write_reg(cpu: &mut Cpu, name: &str, value: u64) -> Result<(), String> {
+    let var = get_varnode(cpu, name)?;
+    cpu.write_reg(var, value);
+    Ok(())
+}
+
+fn read_mem(cpu: &mut Cpu, addr: u64, buf: &mut [u8]) -> Result<(), String> {
+    cpu.mem
+        .read_bytes_large(addr, buf, perm::READ)
+        .map_err(|e| format!("failed to read memory at {addr:#x}: {e:?}"))
+}
+
+fn read_u32_mem(cpu: &mut Cpu, addr: u64) -> Result<u32, String> {
+    let mut buf = [0; 4];
+    read_mem(cpu, addr, &mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn write_mem(cpu: &mut Cpu, addr: u64, buf: &[u8]) -> Result<(), String> {
+    cpu.mem
+        .write_bytes_large(addr, buf, perm::WRITE)
+        .map_err(|e| format!("failed to write memory at {addr:#x}: {e:?}"))
+}
+
+fn read_u16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(buf[offset..offset + 2].try_into().unwrap())
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(buf: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+fn write_u32(buf: &mut [u8], offset: usize, value: u32) {
+    buf[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+fn write_u64(buf: &mut [u8], offset: usize, value: u64) {
+    buf[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+}
@@ -0,0 +1,299 @@
+//! Virtual unwinding of x86-64 stack frames using the function table (`.pdata`) of a PE image.
+//!
+//! Based on the unwind data format documented at:
+//! https://learn.microsoft.com/en-us/cpp/build/exception-handling-x64
+//!
+//! Epilogs are not detected, so a frame that is interrupted inside of an epilog is unwound as if
+//! it was interrupted in the body of the function.
+
+use icicle_cpu::{mem::perm, Cpu};
+
+const UWOP_PUSH_NONVOL: u8 = 0;
+const UWOP_ALLOC_LARGE: u8 = 1;
+const UWOP_ALLOC_SMALL: u8 = 2;
+const UWOP_SET_FPREG: u8 = 3;
+const UWOP_SAVE_NONVOL: u8 = 4;
+const UWOP_SAVE_NONVOL_FAR: u8 = 5;
+const UWOP_SAVE_XMM128: u8 = 8;
+const UWOP_SAVE_XMM128_FAR: u8 = 9;
+const UWOP_PUSH_MACHFRAME: u8 = 10;
+
+const UNW_FLAG_EHANDLER: u8 = 0x1;
+const UNW_FLAG_CHAININFO: u8 = 0x4;
+
+/// The size of a `RUNTIME_FUNCTION` entry.
+pub const RUNTIME_FUNCTION_SIZE: u64 = 12;
+
+/// Names of the general purpose registers, in the order used for register numbers in unwind
+/// codes.
+pub const GPR_NAMES: [&str; 16] = [
+    "RAX", "RCX", "RDX", "RBX", "RSP", "RBP", "RSI", "RDI", "R8", "R9", "R10", "R11", "R12", "R13",
+    "R14", "R15",
+];
+
+pub const RAX: usize = 0;
+pub const RSP: usize = 4;
+
+/// An entry in the function table.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RuntimeFunction {
+    pub begin: u32,
+    pub end: u32,
+    pub unwind_info: u32,
+}
+
+/// The function table of a loaded image.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FunctionTable {
+    pub image_base: u64,
+
+    /// The address of the first `RUNTIME_FUNCTION` entry.
+    pub addr: u64,
+
+    /// The size of the table in bytes.
+    pub len: u64,
+}
+
+impl FunctionTable {
+    /// Finds the entry for the function containing `pc`, returning the entry and its address.
+    pub fn lookup(&self, cpu: &mut Cpu, pc: u64) -> Result<Option<(RuntimeFunction, u64)>, String> {
+        let Some(rva) = pc.checked_sub(self.image_base).and_then(|x| u32::try_from(x).ok())
+        else {
+            return Ok(None);
+        };
+
+        // Entries are sorted by address, so use a binary search to find the function.
+        let (mut lo, mut hi) = (0, self.len / RUNTIME_FUNCTION_SIZE);
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            let addr = self.addr + mid * RUNTIME_FUNCTION_SIZE;
+            let entry = read_runtime_function(cpu, addr)?;
+            if rva < entry.begin {
+                hi = mid;
+            }
+            else if rva >= entry.end {
+                lo = mid + 1;
+            }
+            else {
+                return Ok(Some((entry, addr)));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// The values of the integer registers in a frame.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FrameContext {
+    pub gprs: [u64; 16],
+    pub rip: u64,
+}
+
+impl FrameContext {
+    pub fn from_cpu(cpu: &mut Cpu) -> Result<Self, String> {
+        let mut ctx = Self { gprs: [0; 16], rip: cpu.read_pc() };
+        for (value, name) in ctx.gprs.iter_mut().zip(GPR_NAMES) {
+            *value = cpu.read_reg(get_varnode(cpu, name)?);
+        }
+        Ok(ctx)
+    }
+
+    /// Updates the registers of the CPU to match this context.
+    pub fn apply(&self, cpu: &mut Cpu) -> Result<(), String> {
+        for (value, name) in self.gprs.iter().zip(GPR_NAMES) {
+            cpu.write_reg(get_varnode(cpu, name)?, *value);
+        }
+        cpu.write_pc(self.rip);
+        Ok(())
+    }
+
+    pub fn rsp(&self) -> u64 {
+        self.gprs[RSP]
+    }
+}
+
+/// The exception handler registered for a function.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LanguageHandler {
+    pub addr: u64,
+
+    /// The address of the language specific data passed to the handler.
+    pub data: u64,
+}
+
+/// Information about a frame found by [virtual_unwind].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Frame {
+    /// The function table entry for the frame, and its address (`None` for leaf functions).
+    pub function: Option<(RuntimeFunction, u64)>,
+
+    /// The establisher frame pointer of the frame.
+    pub establisher: u64,
+
+    /// The exception handler for the frame. This is `None` if the function has no exception
+    /// handler or if the frame was interrupted inside of the function's prolog.
+    pub handler: Option<LanguageHandler>,
+}
+
+/// Unwinds the frame described by `ctx`, updating `ctx` to the context of the caller (i.e. the
+/// equivalent of `RtlVirtualUnwind`).
+pub fn virtual_unwind(
+    cpu: &mut Cpu,
+    table: &FunctionTable,
+    ctx: &mut FrameContext,
+) -> Result<Frame, String> {
+    let read_u64 = |cpu: &mut Cpu, addr: u64| {
+        cpu.mem
+            .read_u64(addr, perm::READ)
+            .map_err(|e| format!("failed to read stack at {addr:#x}: {e:?}"))
+    };
+
+    let Some((function, function_addr)) = table.lookup(cpu, ctx.rip)?
+    else {
+        // Leaf functions have no function table entry and do not modify the stack pointer, so
+        // the return address is at the top of the stack.
+        let rsp = ctx.rsp();
+        ctx.rip = read_u64(cpu, rsp)?;
+        ctx.gprs[RSP] = rsp + 8;
+        return Ok(Frame { function: None, establisher: rsp, handler: None });
+    };
+
+    let base = table.image_base;
+    let pc = ctx.rip;
+    let mut establisher = ctx.rsp();
+    let mut entry = function;
+    let mut is_primary = true;
+    let mut in_prolog = false;
+    let mut machine_frame = false;
+
+    let (flags, handler_addr) = loop {
+        let info = base + entry.unwind_info as u64;
+        let mut header = [0; 4];
+        cpu.mem
+            .read_bytes(info, &mut header, perm::READ)
+            .map_err(|e| format!("failed to read unwind info at {info:#x}: {e:?}"))?;
+        let (version, flags) = (header[0] & 0x7, header[0] >> 3);
+        if version != 1 && version != 2 {
+            return Err(format!("unsupported unwind info version {version} at {info:#x}"));
+        }
+        let (prolog_size, code_count) = (header[1] as u64, header[2] as usize);
+        let frame_reg = (header[3] & 0xf) as usize;
+        let frame_offset = (header[3] >> 4) as u64 * 16;
+
+        let mut codes = vec![0; code_count * 2];
+        cpu.mem
+            .read_bytes(info + 4, &mut codes, perm::READ)
+            .map_err(|e| format!("failed to read unwind codes at {info:#x}: {e:?}"))?;
+        let code = |i: usize| (codes[i * 2], codes[i * 2 + 1] & 0xf, codes[i * 2 + 1] >> 4);
+        let slot_u16 = |i: usize| u16::from_le_bytes([codes[i * 2], codes[i * 2 + 1]]) as u64;
+        let slot_u32 = |i: usize| (slot_u16(i + 1) << 16) | slot_u16(i);
+
+        // Unwind codes for instructions in the prolog that have not been executed yet are skipped.
+        let prolog_offset = match pc.checked_sub(base + entry.begin as u64) {
+            Some(offset) if is_primary && offset < prolog_size => {
+                in_prolog = true;
+                offset
+            }
+            _ => u64::MAX,
+        };
+
+        // The frame pointer is only valid once the `SET_FPREG` operation has been executed.
+        let fpreg_set = (0..code_count).any(|i| {
+            let (offset, op, _) = code(i);
+            op == UWOP_SET_FPREG && (offset as u64) <= prolog_offset
+        });
+        let frame = match frame_reg {
+            0 => ctx.rsp(),
+            _ if !is_primary || fpreg_set => ctx.gprs[frame_reg].wrapping_sub(frame_offset),
+            _ => ctx.rsp(),
+        };
+
+        let mut i = 0;
+        while i < code_count {
+            let (offset, op, op_info) = code(i);
+            let size = match op {
+                UWOP_ALLOC_LARGE if op_info == 0 => 2,
+                UWOP_ALLOC_LARGE => 3,
+                UWOP_SAVE_NONVOL | UWOP_SAVE_XMM128 => 2,
+                UWOP_SAVE_NONVOL_FAR | UWOP_SAVE_XMM128_FAR => 3,
+                _ => 1,
+            };
+            if (offset as u64) > prolog_offset {
+                i += size;
+                continue;
+            }
+
+            let rsp = ctx.rsp();
+            match op {
+                UWOP_PUSH_NONVOL => {
+                    ctx.gprs[op_info as usize] = read_u64(cpu, rsp)?;
+                    ctx.gprs[RSP] = rsp + 8;
+                }
+                UWOP_ALLOC_LARGE if op_info == 0 => ctx.gprs[RSP] = rsp + slot_u16(i + 1) * 8,
+                UWOP_ALLOC_LARGE => ctx.gprs[RSP] = rsp + slot_u32(i + 1),
+                UWOP_ALLOC_SMALL => ctx.gprs[RSP] = rsp + op_info as u64 * 8 + 8,
+                UWOP_SET_FPREG => {
+                    ctx.gprs[RSP] = frame;
+                    if is_primary {
+                        establisher = frame;
+                    }
+                }
+                UWOP_SAVE_NONVOL => {
+                    ctx.gprs[op_info as usize] = read_u64(cpu, frame + slot_u16(i + 1) * 8)?;
+                }
+                UWOP_SAVE_NONVOL_FAR => {
+                    ctx.gprs[op_info as usize] = read_u64(cpu, frame + slot_u32(i + 1))?;
+                }
+                // Only the integer registers are tracked.
+                UWOP_SAVE_XMM128 | UWOP_SAVE_XMM128_FAR => {}
+                UWOP_PUSH_MACHFRAME => {
+                    let frame_addr = if op_info != 0 { rsp + 8 } else { rsp };
+                    ctx.rip = read_u64(cpu, frame_addr)?;
+                    ctx.gprs[RSP] = read_u64(cpu, frame_addr + 24)?;
+                    machine_frame = true;
+                }
+                _ => return Err(format!("unsupported unwind operation {op} at {info:#x}")),
+            }
+            i += size;
+        }
+
+        // The handler (or the chained function entry) follows the unwind codes, which are padded
+        // to an even number of slots.
+        let next = info + 4 + (code_count as u64).next_multiple_of(2) * 2;
+        if flags & UNW_FLAG_CHAININFO == 0 {
+            break (flags, next);
+        }
+        entry = read_runtime_function(cpu, next)?;
+        is_primary = false;
+    };
+
+    if !machine_frame {
+        let rsp = ctx.rsp();
+        ctx.rip = read_u64(cpu, rsp)?;
+        ctx.gprs[RSP] = rsp + 8;
+    }
+
+    let mut handler = None;
+    if flags & UNW_FLAG_EHANDLER != 0 && !in_prolog {
+        let rva = cpu
+            .mem
+            .read_u32(handler_addr, perm::READ)
+            .map_err(|e| format!("failed to read handler at {handler_addr:#x}: {e:?}"))?;
+        handler = Some(LanguageHandler { addr: base + rva as u64, data: handler_addr + 4 });
+    }
+
+    Ok(Frame { function: Some((function, function_addr)), establisher, handler })
+}
+
+fn read_runtime_function(cpu: &mut Cpu, addr: u64) -> Result<RuntimeFunction, String> {
+    let mut buf = [0; RUNTIME_FUNCTION_SIZE as usize];
+    cpu.mem
+        .read_bytes(addr, &mut buf, perm::READ)
+        .map_err(|e| format!("failed to read function table entry at {addr:#x}: {e:?}"))?;
+    let field = |i: usize| u32::from_le_bytes(buf[i * 4..i * 4 + 4].try_into().unwrap());
+    Ok(RuntimeFunction { begin: field(0), end: field(1), unwind_info: field(2) })
+}
+
+fn get_varnode(cpu: &Cpu, name: &str) -> Result<pcode::VarNode, String> {
+    cpu.arch.sleigh.get_varnode(name).ok_or_else(|| format!("unknown register: {name}"))
+}
@@ -0,0 +1,277 @@
+//! Auditing of W^X (write xor execute) violations.
+//!
+//! Code that is executed from memory that the guest could write to is a prerequisite for most code
+//! injection attacks, and mappings that are writable and executable at the same time make them
+//! trivial. When attached, the auditor records every change to the permissions of memory and
+//! reports:
+//!
+//! - [WxEvent::WritableExecutable]: a region of memory that was mapped writable and executable.
+//! - [WxEvent::ExecFromWritable]: code executed from memory that was writable at some point (e.g.
+//!   code that was written by the guest then made executable with `mprotect`).
+//!
+//! Events are recorded by the [WxAudit] handle (which keeps the most recent [MAX_EVENTS]), and can
+//! be observed as they occur with [WxAudit::on_event]. If [WxAuditOptions::enforce] is set,
+//! executing code from memory that was writable at some point also stops the VM with
+//! [ExceptionCode::ExecViolation] (similar to NX).
+//!
+//! Executions are checked as code is lifted, so whenever memory becomes writable any existing
+//! translations of it are invalidated. This happens the next time the VM handles an exception, so
+//! code that is executed immediately after its permissions are changed from a hook (without
+//! exiting the VM) may not be reported. The memory that was writable at some point is saved and
+//! restored with the VM (see [Vm::add_snapshot_hook]).
+
+use std::{
+    any::Any,
+    cell::RefCell,
+    collections::{BTreeMap, HashSet},
+    rc::Rc,
+};
+
+use icicle_cpu::{
+    BlockGroup, BlockTable, Cpu, Exception, ExceptionCode,
+    mem::{MemoryMapping, perm},
+};
+use pcode::Op;
+
+use crate::{SnapshotHook, Vm, injector::CodeInjector};
+
+/// The maximum number of events kept by the auditor, older events are discarded once the limit is
+/// reached (but are still passed to listeners).
+pub const MAX_EVENTS: usize = 1024;
+
+/// An event reported by the W^X auditor.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum WxEvent {
+    /// The `len` bytes starting at `start` were mapped with `perm`, which is both writable and
+    /// executable.
+    WritableExecutable { start: u64, len: u64, perm: u8 },
+
+    /// The instruction at `addr` was executed from memory that was writable at some point.
+    ExecFromWritable { addr: u64 },
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct WxAuditOptions {
+    /// Stop the VM with [ExceptionCode::ExecViolation] when code is executed from memory that was
+    /// writable at some point.
+    pub enforce: bool,
+}
+
+type WxListener = Box<dyn FnMut(&WxEvent)>;
+
+struct WxState {
+    options: WxAuditOptions,
+
+    /// Maps the start of each range of memory that was writable at some point to the (inclusive)
+    /// end of the range.
+    writable: BTreeMap<u64, u64>,
+
+    /// The addresses that have already been reported as [WxEvent::ExecFromWritable].
+    reported: HashSet<u64>,
+
+    events: Vec<WxEvent>,
+    listeners: Vec<WxListener>,
+
+    /// Ranges that have become writable, and need to have their code invalidated by the VM.
+    pending_invalidate: Vec<(u64, u64)>,
+}
+
+impl WxState {
+    fn emit(&mut self, event: WxEvent) {
+        tracing::warn!("W^X audit: {event:x?}");
+        for listener in &mut self.listeners {
+            listener(&event);
+        }
+        if self.events.len() >= MAX_EVENTS {
+            self.events.remove(0);
+        }
+        self.events.push(event);
+    }
+
+    fn perm_changed(&mut self, start: u64, len: u64, perm: u8) {
+        if len == 0 || perm & perm::WRITE == 0 {
+            return;
+        }
+        let end = start.saturating_add(len - 1);
+        if self.writable.range(..=start).next_back().is_none_or(|(_, prev_end)| end > *prev_end) {
+            // Code lifted before the memory was writable was not checked by the injector.
+            self.pending_invalidate.push((start, end));
+        }
+        self.add_writable(start, end);
+        if perm & perm::EXEC != 0 {
+            self.emit(WxEvent::WritableExecutable { start, len, perm });
+        }
+    }
+
+    /// Marks `start..=end` as writable, merging it with any overlapping or adjacent ranges.
+    fn add_writable(&mut self, mut start: u64, mut end: u64) {
+        let merged: Vec<(u64, u64)> = self
+            .writable
+            .range(..=end.saturating_add(1))
+            .rev()
+            .take_while(|(_, prev_end)| **prev_end >= start.saturating_sub(1))
+            .map(|(start, end)| (*start, *end))
+            .collect();
+        for (prev_start, prev_end) in merged {
+            self.writable.remove(&prev_start);
+            start = start.min(prev_start);
+            end = end.max(prev_end);
+        }
+        self.writable.insert(start, end);
+    }
+
+    fn was_writable(&self, addr: u64) -> bool {
+        self.writable.range(..=addr).next_back().is_some_and(|(_, end)| addr <= *end)
+    }
+}
+
+/// A handle to the W^X auditor attached to a VM. Cloning the handle produces a handle to the same
+/// state.
+#[derive(Clone)]
+pub struct WxAudit {
+    state: Rc<RefCell<WxState>>,
+}
+
+impl WxAudit {
+    /// Gets every event reported since the auditor was attached (or since the events were last
+    /// taken).
+    pub fn events(&self) -> Vec<WxEvent> {
+        self.state.borrow().events.clone()
+    }
+
+    /// Removes and returns all reported events.
+    pub fn take_events(&self) -> Vec<WxEvent> {
+        std::mem::take(&mut self.state.borrow_mut().events)
+    }
+
+    /// Registers `listener` to be called for every new event.
+    pub fn on_event(&self, listener: impl FnMut(&WxEvent) + 'static) {
+        self.state.borrow_mut().listeners.push(Box::new(listener));
+    }
+
+    /// Returns whether `addr` was writable at any point since the auditor was attached.
+    pub fn was_writable(&self, addr: u64) -> bool {
+        self.state.borrow().was_writable(addr)
+    }
+
+    /// Gets the `(start, end)` address (inclusive) of every range of memory that was writable at
+    /// some point, ordered by start address.
+    pub fn writable_ranges(&self) -> Vec<(u64, u64)> {
+        self.state.borrow().writable.iter().map(|(start, end)| (*start, *end)).collect()
+    }
+}
+
+struct WxSnapshot {
+    writable: BTreeMap<u64, u64>,
+    reported: HashSet<u64>,
+}
+
+impl SnapshotHook for WxAudit {
+    fn snapshot(&mut self) -> Box<dyn Any> {
+        let state = self.state.borrow();
+        Box::new(WxSnapshot { writable: state.writable.clone(), reported: state.reported.clone() })
+    }
+
+    fn restore(&mut self, snapshot: &Box<dyn Any>) {
+        let snapshot = snapshot.downcast_ref::<WxSnapshot>().unwrap();
+        let mut state = self.state.borrow_mut();
+        // Translations are restored with the rest of the VM, so do not need to be invalidated.
+        state.writable.clone_from(&snapshot.writable);
+        state.reported.clone_from(&snapshot.reported);
+    }
+}
+
+impl Vm {
+    /// Invalidates the code in any memory that became writable since the last call, so that it is
+    /// checked by the W^X auditor when it is lifted again.
+    pub(crate) fn invalidate_wx_writable_code(&mut self) {
+        let Some(audit) = self.wx_audit.as_ref()
+        else {
+            return;
+        };
+        let pending = std::mem::take(&mut audit.state.borrow_mut().pending_invalidate);
+        for (start, end) in pending {
+            self.invalidate_memory_range(start, (end - start).saturating_add(1));
+        }
+    }
+}
+
+/// Attaches the W^X auditor to the VM. Memory that is currently mapped is audited as if it was
+/// mapped when the auditor is attached.
+pub fn attach(vm: &mut Vm, options: WxAuditOptions) -> WxAudit {
+    let state = Rc::new(RefCell::new(WxState {
+        options,
+        writable: BTreeMap::new(),
+        reported: HashSet::new(),
+        events: vec![],
+        listeners: vec![],
+        pending_invalidate: vec![],
+    }));
+
+    // Note: `end` is inclusive.
+    let regions: Vec<(u64, u64)> = vm
+        .cpu
+        .mem
+        .get_mapping()
+        .iter()
+        .filter(|(_, _, entry)| {
+            matches!(entry, MemoryMapping::Physical(_) | MemoryMapping::Unallocated(_))
+        })
+        .map(|(start, end, _)| (start, end))
+        .collect();
+    for (start, end) in regions {
+        let perm = vm.cpu.mem.get_perm(start);
+        state.borrow_mut().perm_changed(start, end - start + 1, perm);
+    }
+
+    let perm_state = state.clone();
+    vm.cpu.mem.add_perm_hook(Box::new(move |start: u64, len: u64, perm: u8| {
+        perm_state.borrow_mut().perm_changed(start, len, perm);
+    }));
+
+    let hook_state = state.clone();
+    let hook = vm.cpu.add_hook(move |cpu: &mut Cpu, addr: u64| {
+        let mut state = hook_state.borrow_mut();
+        if state.reported.insert(addr) {
+            state.emit(WxEvent::ExecFromWritable { addr });
+        }
+        if state.options.enforce {
+            cpu.exception = Exception::new(ExceptionCode::ExecViolation, addr);
+        }
+    });
+    vm.add_injector(WxInjector { hook, state: state.clone() });
+
+    let audit = WxAudit { state };
+    vm.add_snapshot_hook(audit.clone());
+    vm.wx_audit = Some(audit.clone());
+    audit
+}
+
+struct WxInjector {
+    hook: pcode::HookId,
+    state: Rc<RefCell<WxState>>,
+}
+
+impl CodeInjector for WxInjector {
+    fn inject(&mut self, _cpu: &mut Cpu, group: &BlockGroup, code: &mut BlockTable) {
+        let state = self.state.borrow();
+        if state.writable.is_empty() {
+            return;
+        }
+
+        for id in group.range() {
+            let block = &mut code.blocks[id];
+            let Some(i) = block.pcode.instructions.iter().position(|x| {
+                x.op == Op::InstructionMarker && state.was_writable(x.inputs.first().as_u64())
+            })
+            else {
+                continue;
+            };
+
+            // Only the first instruction from writable memory in each block is reported, which is
+            // enough to stop execution if the policy is enforced.
+            block.pcode.instructions.insert(i + 1, Op::Hook(self.hook).into());
+            code.modified.insert(id);
+        }
+    }
+}
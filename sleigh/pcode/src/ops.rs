@@ -35,8 +35,13 @@ pub const REGISTER_SPACE: MemId = 1;
 /// The memory ID after all reserved spaces.
 pub const RESERVED_SPACE_END: MemId = 2;
 
+/// The offset within the RAM space that a secondary RAM space is mapped at. Used for Harvard
+/// architectures (e.g. AVR) where code (the default space) and data are in separate address spaces.
+pub const DATA_SPACE_BASE: u64 = 0x1_0000_0000;
+
 /// Represents a reference to a slice of a P-code variable.
 #[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct VarNode {
     pub id: VarId,
     pub offset: VarOffset,
@@ -122,6 +127,7 @@ impl Default for VarNode {
 
 /// A value that can be used as an input to a P-code operand.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Value {
     Var(VarNode),
     Const(u64, u8),
@@ -212,6 +218,7 @@ macro_rules! impl_value_from {
 impl_value_from!(u8, u16, u32, u64, i8, i16, i32, i64);
 
 /// Represents a sequence of P-code operations.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Block {
     pub instructions: Vec<Instruction>,
     pub next_tmp: VarId,
@@ -370,6 +377,7 @@ pub type ConstValue = (u64, u8);
 /// Internal representation of the operands for a P-Code operation. Representing them in this way
 /// allows the enum tags for the inputs to be merged.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 enum InputsImpl {
     _0(u64, u8, u64, u8), // Const, Const
     _1(u64, u8, VarNode), // Const, Var
@@ -378,6 +386,7 @@ enum InputsImpl {
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Inputs(InputsImpl);
 
 impl Inputs {
@@ -462,6 +471,7 @@ impl<'a> From<&'a [Value]> for Inputs {
 
 /// Represents a full P-code instruction.
 #[derive(Copy, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Instruction {
     pub op: Op,
     pub inputs: Inputs,
@@ -514,6 +524,7 @@ impl From<Op> for Instruction {
 
 /// P-code operations.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Op {
     Copy,
     Select(VarId),
@@ -740,6 +751,7 @@ impl Op {
 }
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum BranchHint {
     Jump,
     Call,
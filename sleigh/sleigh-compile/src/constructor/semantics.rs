@@ -1047,7 +1047,7 @@ impl<'a, 'b> Builder<'a, 'b> {
     fn resolve_space(
         &mut self,
         space: &Option<ast::Ident>,
-    ) -> Result<(pcode::MemId, ValueSize, ValueSize), String> {
+    ) -> Result<(pcode::MemId, ValueSize, ValueSize, u64), String> {
         let space = match space {
             Some(ident) => self.scope.globals.lookup_kind(*ident, SymbolKind::Space)?,
             None => self.scope.globals.default_space.ok_or("no default space")?,
@@ -1055,7 +1055,26 @@ impl<'a, 'b> Builder<'a, 'b> {
         let id = self.scope.globals.spaces[space as usize].space_id;
         let word_size = self.scope.globals.spaces[space as usize].word_size;
         let addr_size = self.scope.globals.spaces[space as usize].size;
-        Ok((id, addr_size, word_size))
+        let base = self.scope.globals.spaces[space as usize].base;
+        Ok((id, addr_size, word_size, base))
+    }
+
+    /// Converts a pointer into a RAM space to the byte address it is mapped to within the
+    /// emulator's RAM space.
+    fn map_ram_pointer(&mut self, pointer: Value, word_size: ValueSize, base: u64) -> Value {
+        if word_size <= 1 && base == 0 {
+            return pointer;
+        }
+        let mapped: Value = self.scope.add_tmp(Some(8)).into();
+        self.op(pcode::Op::ZeroExtend, &[pointer], Some(mapped));
+        if word_size > 1 {
+            let scale = Value::constant(word_size as u64);
+            self.op(pcode::Op::IntMul, &[mapped, scale], Some(mapped));
+        }
+        if base != 0 {
+            self.op(pcode::Op::IntAdd, &[mapped, Value::constant(base)], Some(mapped));
+        }
+        mapped
     }
 
     fn resolve_address(
@@ -1076,12 +1095,21 @@ impl<'a, 'b> Builder<'a, 'b> {
             };
         }
 
-        let (space_id, _addr_size, _word_size) = self.resolve_space(space)?;
+        let (space_id, _addr_size, word_size, base) = self.resolve_space(space)?;
         let pointer = self.read_value(pointer, None)?;
 
         Ok(match space_id {
             pcode::REGISTER_SPACE => ExprValue::RegisterRef(pointer, size.unwrap_or(0)),
-            pcode::RAM_SPACE => ExprValue::RamRef(pointer, size.unwrap_or(0)),
+            pcode::RAM_SPACE => {
+                // Exported references to word addressed spaces are used as branch targets, which
+                // are expressed in words, so the pointer is only scaled for direct accesses.
+                let word_size = match self.current_statement {
+                    ast::Statement::Export { .. } => 1,
+                    _ => word_size,
+                };
+                let pointer = self.map_ram_pointer(pointer, word_size, base);
+                ExprValue::RamRef(pointer, size.unwrap_or(0))
+            }
             _ => panic!("unknown space_id: {space_id}"),
         })
     }
@@ -130,6 +130,67 @@ impl SleighLanguageBuilder {
     }
 }
 
+/// Builder for languages defined directly by the paths to their specification files instead of an
+/// entry in an ldefs file, e.g. for processors that are not part of Ghidra.
+pub struct SleighSpecBuilder {
+    pub slaspec_path: PathBuf,
+    pub pspec_path: PathBuf,
+    pub cspec_path: Option<PathBuf>,
+    pub verbose: bool,
+    pub defines: Vec<String>,
+}
+
+impl SleighSpecBuilder {
+    pub fn new(slaspec_path: impl Into<PathBuf>, pspec_path: impl Into<PathBuf>) -> Self {
+        Self {
+            slaspec_path: slaspec_path.into(),
+            pspec_path: pspec_path.into(),
+            cspec_path: None,
+            verbose: false,
+            defines: vec!["ICICLE".into()],
+        }
+    }
+
+    /// Sets the compiler specification used to find the stack pointer and calling convention.
+    pub fn set_cspec_path(mut self, cspec_path: impl Into<PathBuf>) -> Self {
+        self.cspec_path = Some(cspec_path.into());
+        self
+    }
+
+    pub fn set_verbose(mut self, verbose: bool) -> Self {
+        self.verbose = verbose;
+        self
+    }
+
+    pub fn clear_defines(mut self) -> Self {
+        self.defines.clear();
+        self
+    }
+
+    pub fn define(mut self, define: impl Into<String>) -> Self {
+        self.defines.push(define.into());
+        self
+    }
+
+    /// Compiles the specification. Since there is no language description, the processor name is
+    /// taken from the name of the `.slaspec` file, and the size from the program counter.
+    pub fn build(self) -> Result<SleighLanguage, Error> {
+        let Self { slaspec_path, pspec_path, cspec_path, verbose, defines } = self;
+        let file_stem =
+            |path: &Path| path.file_stem().map_or(String::new(), |x| x.to_string_lossy().into());
+
+        let cspec = cspec_path.map(|path| (file_stem(&path), path));
+        let mut lang = build_language(&slaspec_path, &pspec_path, cspec, &defines, verbose)?;
+        lang.processor = file_stem(&slaspec_path);
+        lang.endian = match lang.sleigh.big_endian {
+            true => Endianness::Big,
+            false => Endianness::Little,
+        };
+        lang.size = lang.pc.size as u32 * 8;
+        Ok(lang)
+    }
+}
+
 fn build_inner(
     SleighLanguageBuilder { ldef_path, lang_id, cspec_id, verbose, defines }: SleighLanguageBuilder,
 ) -> Result<SleighLanguage, Error> {
@@ -138,12 +199,29 @@ fn build_inner(
         ldef.find_match(&lang_id).ok_or_else(|| Error::LanguageNotFound(lang_id.clone()))?;
 
     let pspec_path = language.pspec_path(&ldef_path).ok_or(Error::InvalidPath)?;
+    let slaspec_path = language.slaspec_path(&ldef_path).ok_or(Error::InvalidPath)?;
+    let cspec = language.cspec_path(&ldef_path, cspec_id.as_deref());
+
+    let mut lang = build_language(&slaspec_path, &pspec_path, cspec, &defines, verbose)?;
+    lang.processor = language.processor.clone();
+    lang.endian = language.endian;
+    lang.size = language.size;
+    Ok(lang)
+}
+
+/// Compiles the SLEIGH specification at `slaspec_path`, using the processor specification and
+/// (optional) named compiler specification to resolve the initial context and register conventions.
+fn build_language(
+    slaspec_path: &Path,
+    pspec_path: &Path,
+    cspec: Option<(String, PathBuf)>,
+    defines: &[String],
+    verbose: bool,
+) -> Result<SleighLanguage, Error> {
     let pspec: PSpec = serde_xml_rs::from_reader(BufReader::new(
-        File::open(&pspec_path).map_err(|err| Error::Io(pspec_path.clone(), err))?,
+        File::open(pspec_path).map_err(|err| Error::Io(pspec_path.to_owned(), err))?,
     ))
-    .map_err(|err| Error::ParseError(pspec_path, err.to_string()))?;
-
-    let slaspec_path = language.slaspec_path(&ldef_path).ok_or(Error::InvalidPath)?;
+    .map_err(|err| Error::ParseError(pspec_path.to_owned(), err.to_string()))?;
 
     let root = slaspec_path.parent().ok_or(Error::InvalidPath)?;
     let initial_file =
@@ -151,9 +229,10 @@ fn build_inner(
 
     let input_source = sleigh_parse::FileLoader::new(root.to_owned());
 
-    let mut parser = sleigh_parse::Parser::new(input_source, &defines);
+    let mut parser = sleigh_parse::Parser::new(input_source, defines);
     if let Err(err) = parser.include_file(initial_file) {
-        return Err(Error::ParseError(slaspec_path, parser.error_formatter(err).to_string()));
+        let err = parser.error_formatter(err).to_string();
+        return Err(Error::ParseError(slaspec_path.to_owned(), err));
     }
     let sleigh = crate::build_inner(parser, verbose).map_err(Error::CompileError)?;
 
@@ -180,7 +259,7 @@ fn build_inner(
     let mut default_calling_cov = CallingCov::default();
 
     let mut compiler = None;
-    if let Some((name, path)) = language.cspec_path(&ldef_path, cspec_id.as_deref()) {
+    if let Some((name, path)) = cspec {
         compiler = Some(name);
 
         // If we have a compiler spec, we can obtain additional information about the target
@@ -205,9 +284,9 @@ fn build_inner(
     }
 
     Ok(SleighLanguage {
-        processor: language.processor.clone(),
-        endian: language.endian,
-        size: language.size,
+        processor: String::new(),
+        endian: Endianness::Little,
+        size: 0,
         compiler,
         sleigh,
         initial_ctx,
@@ -59,6 +59,8 @@ pub fn build_inner(mut parser: Parser, verbose: bool) -> Result<SleighData, Stri
 
     ctx.data.default_space_size =
         symbols.default_space.map(|i| symbols.spaces[i as usize].size).unwrap_or(8);
+    ctx.data.default_space_word_size =
+        symbols.default_space.map(|i| symbols.spaces[i as usize].word_size).unwrap_or(1);
 
     for entry in &symbols.context_fields {
         let name_str = symbols.parser.get_ident_str(entry.name);
@@ -294,7 +296,10 @@ fn resolve_item(ctx: &mut Context, syms: &mut SymbolTable, item: ast::Item) -> R
                 }
 
                 ast::SpaceKind::RamSpace => {
-                    check_not_defined!(ctx.has_ram_space, "multiple ram spaces not supported");
+                    // Non-default spaces are mapped into the RAM space (see `define_space`).
+                    if space.default {
+                        check_not_defined!(ctx.has_ram_space, "multiple ram spaces");
+                    }
                     syms.define_space(space)?;
                 }
                 ast::SpaceKind::RomSpace => return Err("rom space not implemented".into()),
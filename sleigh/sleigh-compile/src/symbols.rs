@@ -183,10 +183,20 @@ impl SymbolTable {
             ast::SpaceKind::RomSpace => return Err("only ROM space not supported".into()),
         };
 
+        // Harvard architectures (e.g. AVR) define a separate RAM space for data, which is mapped
+        // at `pcode::DATA_SPACE_BASE`. Any other non-default RAM spaces are views of the default
+        // space with a different word size (e.g. `codebyte` for AVR).
+        let has_data_space = self.spaces.iter().any(|x| x.base != 0);
+        let base = match (space.kind, space.default) {
+            (ast::SpaceKind::RamSpace, false) if !has_data_space => pcode::DATA_SPACE_BASE,
+            _ => 0,
+        };
+
         let sym = insert_and_map!(self, spaces, space.name, SymbolKind::Space, RamSpace {
             space_id: id,
             size: space.size,
             word_size: space.word_size.unwrap_or(1),
+            base,
         })?;
 
         if space.default {
@@ -201,12 +211,15 @@ impl SymbolTable {
 
     /// Handles the association of identifiers to VarNodes within a space.
     ///
-    /// Returns an error if the space is not the `register` space (defining names for VarNodes in
-    /// other spaces is not currently supported), or if any identifier overlaps with an existing
+    /// Names defined in a RAM space (e.g. memory-mapped IO registers for AVR) are treated as
+    /// registers at the same offset in the `register` space, so they do not alias the underlying
+    /// memory.
+    ///
+    /// Returns an error if the space does not exist, or if any identifier overlaps with an existing
     /// identifier.
     pub fn define_register_names(&mut self, def: ast::SpaceNameDef) -> Result<(), String> {
         if def.space != self.register_space_ident {
-            return Err("Can only name offsets within a register_space".into());
+            self.lookup_kind(def.space, SymbolKind::Space)?;
         }
 
         for (i, ident) in def.names.into_iter().enumerate() {
@@ -470,6 +483,9 @@ pub(crate) struct RamSpace {
 
     /// Represents the size of a memory location associated with a single address
     pub word_size: ValueSize,
+
+    /// The offset the space is mapped at within the emulator's RAM space.
+    pub base: u64,
 }
 
 #[derive(Clone, Debug)]
@@ -60,6 +60,7 @@ impl ParserDisplay for Item {
 }
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Ident(pub StrIndex);
 
 impl ParserDisplay for Ident {
@@ -320,6 +321,7 @@ impl ParserDisplay for ConstraintExpr {
 }
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum ConstraintCmp {
     Equal,
     NotEqual,
@@ -343,6 +345,7 @@ impl std::fmt::Display for ConstraintCmp {
 }
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum ConstraintOp {
     And,
     Or,
@@ -430,6 +433,7 @@ impl ParserDisplay for PatternExpr {
 }
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum PatternOp {
     Add,
     Sub,
@@ -138,7 +138,7 @@ impl Decoder {
         }
 
         inst.inst_start = self.base_addr;
-        inst.inst_next = self.base_addr + self.next_offset as u64;
+        inst.inst_next = self.base_addr + self.next_offset as u64 / sleigh.word_size();
 
         if !self.is_valid {
             return None;
@@ -415,6 +415,9 @@ impl Instruction {
     }
 
     /// Returns the length of the instruction in bytes.
+    ///
+    /// Note: for word addressed spaces this is the number of addresses the instruction occupies,
+    /// see [crate::SleighData::word_size].
     pub fn num_bytes(&self) -> u64 {
         self.inst_next.saturating_sub(self.inst_start)
     }
@@ -625,6 +628,7 @@ impl<'a, 'b> SubtableCtxMut<'a, 'b> {
 }
 
 #[derive(Debug, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum ContextModValue {
     TokenField(Token, Field),
     ContextField(Field),
@@ -649,6 +653,7 @@ impl EvalPatternValue for &'_ Decoder {
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum DisasmConstantValue {
     LocalField(u32),
     ContextField(Field),
@@ -4,6 +4,7 @@ pub use sleigh_parse::ast::PatternOp;
 
 /// Encodes an operation that is part of a pattern expression.
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum PatternExprOp<T> {
     Value(T),
     Constant(u64),
@@ -122,6 +122,7 @@ pub type ConstructorId = u32;
 pub type AttachmentId = u32;
 
 #[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Token {
     /// Token could overwrite the global endian
     pub big_endian: bool,
@@ -146,6 +147,7 @@ impl Token {
 }
 
 #[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Field {
     /// The bit offset of the field within the parent value.
     pub offset: u16,
@@ -210,6 +212,7 @@ impl TokenField {
 }
 
 #[derive(Debug, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ContextField {
     /// Describes how the field is encoded within the context register.
     pub field: Field,
@@ -223,11 +226,13 @@ pub type LocalIndex = u32;
 pub type SubtableIndex = u32;
 
 /// Represents a group of constructors that are disambiguated by constraint expression.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Table {
     /// The index of the initial matcher to use.
     pub matcher: MatcherIndex,
 }
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Constructor {
     /// The ID of the table that this constructor belongs to.
     pub table: TableId,
@@ -267,6 +272,7 @@ pub struct Constructor {
 }
 
 #[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum EvalKind {
     ContextField(Field),
     TokenField(Token, Field),
@@ -274,6 +280,7 @@ pub enum EvalKind {
 
 /// An action for the decoder to perform.
 #[derive(Debug, Clone, Hash, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum DecodeAction {
     /// Modifies the context register.
     ModifyContext(Field, PatternExprRange),
@@ -308,6 +315,7 @@ pub type RegId = u32;
 pub type NamedRegIndex = u32;
 
 #[derive(Default, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct NamedRegister {
     /// The name of the register.
     pub name: StrIndex,
@@ -370,6 +378,7 @@ impl NamedRegister {
 }
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct RegisterAlias {
     /// The offset (in bytes) from the start of the full-register.
     pub offset: u16,
@@ -379,6 +388,7 @@ pub struct RegisterAlias {
     pub name: StrIndex,
 }
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct RegisterInfo {
     /// The name of the full-register used as a fallback if there is no exact match.
     pub name: StrIndex,
@@ -394,12 +404,14 @@ pub struct RegisterInfo {
 }
 
 #[derive(Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum DisplaySegment {
     Literal(StrIndex),
     Field(LocalIndex),
     Subtable(LocalIndex),
 }
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum AttachmentIndex {
     Register((u32, u32), ValueSize),
     Name((u32, u32)),
@@ -407,6 +419,7 @@ pub enum AttachmentIndex {
 }
 
 #[derive(Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct RegisterAttachment {
     pub name: StrIndex,
     pub offset: u32,
@@ -418,11 +431,13 @@ pub enum AttachmentRef<'a> {
     Register(&'a [Option<RegisterAttachment>], ValueSize),
 }
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ConstructorDebugInfo {
     pub line: String,
 }
 
 #[derive(Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct DebugInfo {
     pub subtable_names: Vec<StrIndex>,
     pub constructors: Vec<ConstructorDebugInfo>,
@@ -438,6 +453,7 @@ impl DebugInfo {
 }
 
 #[derive(Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct SleighData {
     pub strings: String,
 
@@ -485,11 +501,20 @@ pub struct SleighData {
     pub debug_info: DebugInfo,
 
     pub default_space_size: u16,
+
+    /// The number of bytes at each address in the default space (e.g. 2 for AVR, where code is
+    /// addressed in words).
+    pub default_space_word_size: u16,
     pub alignment: u16,
     pub big_endian: bool,
 }
 
 impl SleighData {
+    /// Returns the number of bytes at each address of the default space.
+    pub fn word_size(&self) -> u64 {
+        self.default_space_word_size.max(1) as u64
+    }
+
     pub fn decode_into(&self, state: &mut Decoder, inst: &mut Instruction) -> Option<()> {
         state.decode_into(self, inst)
     }
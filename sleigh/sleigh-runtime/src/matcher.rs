@@ -19,6 +19,7 @@ pub type Matcher = SequentialMatcher;
 ///
 /// Note: The matcher checks constructor constraints in the order they are defined, therefore if
 /// there are multiple matching constructors the most specific one should be ordered first.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct SequentialMatcher {
     /// The set of constructor constraints that can be matched at the current position.
     pub cases: Vec<MatchCase>,
@@ -48,6 +49,7 @@ impl SequentialMatcher {
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct MatchCase {
     /// The constructor id of the matched constructor.
     pub constructor: ConstructorId,
@@ -80,6 +82,7 @@ impl MatchCase {
 }
 
 #[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Pattern {
     pub bits: u64,
     pub mask: u64,
@@ -112,6 +115,7 @@ impl Pattern {
 /// Note: Since full expressions are almost never used by real-world SLEIGH specifications we have
 /// special cases for constants, and field expressions to improve performance.
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum ConstraintOperand {
     Constant(i64),
     Field(Field),
@@ -119,6 +123,7 @@ pub enum ConstraintOperand {
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Constraint {
     Token { token: Token, field: Field, cmp: ConstraintCmp, operand: ConstraintOperand },
     Context { field: Field, cmp: ConstraintCmp, operand: ConstraintOperand },
@@ -1,6 +1,7 @@
 pub type ValueSize = u16;
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum SemanticAction {
     Op { op: pcode::Op, inputs: Vec<Value>, output: Option<Value> },
     AddressOf { output: Value, base: Local },
@@ -11,6 +12,7 @@ pub enum SemanticAction {
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Value {
     /// The underlying local variable that the value is derived from.
     pub local: Local,
@@ -49,6 +51,7 @@ impl From<Local> for Value {
 
 /// Encodes the value exported by the constructor.
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Export {
     /// A statically computed value.
     Value(Value),
@@ -71,12 +74,14 @@ impl Export {
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct PcodeTmp {
     pub name: Option<sleigh_parse::ast::Ident>,
     pub size: Option<ValueSize>,
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Local {
     /// The address of the current instruction.
     InstStart,